@@ -5,12 +5,14 @@ use tiny_skia::{Pixmap, Transform};
 use usvg::{Options, Tree};
 
 const ICON_SIZE: u32 = 64;
+#[cfg(windows)]
 const ICON_SIZES: [u32; 5] = [16, 32, 64, 96, 128];
 
 fn main() {
     // Render the icon to a bitmap and store the raw bytes so that they can be included when the binary is compiled
     let input = Path::new("assets/icon.svg");
     let output_bytes = Path::new("target/icon.bytes");
+    #[cfg(windows)]
     let output_ico = Path::new("target\\icon.ico");
 
     let tree;
@@ -1,22 +1,54 @@
 /// This module contains the objects for reading and writing backups
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::fs::{create_dir_all, File};
-use std::io::{BufWriter, Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use chrono::NaiveDateTime;
 use number_prefix::NumberPrefix;
+use path_absolutize::Absolutize;
+use path_clean::PathClean;
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
 
-use crate::compression::{CompressionDecoder, CompressionDecoderEntry, CompressionEncoder};
-use crate::config::Config;
-use crate::files::{FileAccessError, FileCrawler, FileInfo};
-use crate::lists::{FileListString, FileListVec};
-use crate::parse_date::naive_now;
-use crate::utils::extend_pathbuf;
+use crate::commands::{collect_metadata, METADATA_BATCH};
+use crate::compression::{
+    archive_exists, archive_size, delta_manifest, entry_xattrs, hard_link_target, is_duplicate,
+    is_multi_volume, remove_archive, rename_archive, rename_archive_txn, unpack_with_progress,
+    ArchiveReader, CompressionDecoder, CompressionDecoderEntry, CompressionEncoder, Retry,
+    SourceFile, StreamSource,
+};
+use crate::config::{Config, ConfigIssue};
+use crate::files::{
+    cmp_path_str, sort_paths, string_to_path, BlockSignature, ChangeDetection, DeltaManifest,
+    FileAccessError, FileCrawler, FileInfo, MetadataUpdate, DELTA_BLOCK_SIZE,
+};
+use crate::lists::{
+    display_path, Entry, FileListString, FileListVec, ParanoidReport, ScanCheckpoint,
+    SignatureList, CHECKPOINT_MAX_AGE, SIGNATURES_NAME,
+};
+use crate::options::{BackupOptions, RestoreOptions};
+use crate::parse_date::{naive_now, system_to_naive};
+use crate::read_ahead::ReadAhead;
+use crate::stats::CompressionProfile;
+use crate::txn::{Step, Transaction, TxnError};
+use crate::utils::{
+    expand_path, extend_pathbuf, first_volume_backup, format_count, format_elapsed, format_size,
+    long_path, strip_path_prefix, temporary_path, BackupIterator,
+};
+use crate::xattrs::{self, MacosXattrs, STORE_XATTRS};
 
 pub(crate) const BACKUP_FILE_EXTENSION: &str = ".tar.zst";
 pub(crate) const CONFIG_DEFAULT_NAME: &str = "config.yml";
-pub(crate) const CONFIG_FILE_EXTENSION: &str = ".yml";
+/// Other names of the embedded config in archives from old versions
+const LEGACY_CONFIG_NAMES: [&str; 1] = ["config.yaml"];
+pub(crate) const CONFIG_FILE_EXTENSIONS: [&str; 4] = [".yml", ".yaml", ".toml", ".json"];
+/// The output (or source) that means stdout (or stdin)
+pub(crate) const STREAM_PATH: &str = "-";
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -37,6 +69,17 @@ pub enum BackupError {
     GenericError(&'static str),
     Unspecified,
     FileExists(PathBuf),
+    MissingEntry(String),
+    NotInBackup(String),
+    RequiredRootEmpty(String, String),
+    InvalidConfig(String),
+    RegexError(regex::Error),
+    WorkerPanic(String),
+    CommandFailed(String, String),
+    OutputUnavailable(PathBuf),
+    OutputMoved(PathBuf, PathBuf),
+    OutsidePrefix(String, String),
+    RunAs(String, String),
 }
 
 impl Display for BackupError {
@@ -78,7 +121,7 @@ impl Display for BackupError {
                 write!(f, "Could not parse the config: {}", e)
             }
             BackupError::InvalidPath(path) => {
-                write!(f, "The path must be either a config ({}), a backup ({}), or a directory containing backups: {}", CONFIG_FILE_EXTENSION, BACKUP_FILE_EXTENSION, path)
+                write!(f, "The path must be either a config ({}), a backup ({}), or a directory containing backups: {}", CONFIG_FILE_EXTENSIONS.join(", "), BACKUP_FILE_EXTENSION, path)
             }
             BackupError::Cancel => {
                 write!(f, "The operation has been cancelled")
@@ -88,11 +131,73 @@ impl Display for BackupError {
             BackupError::GenericError(e) => e.fmt(f),
             BackupError::Unspecified => write!(f, "Unspecified error"),
             BackupError::FileExists(p) => write!(f, "Path already exists: {}", p.to_string_lossy()),
+            BackupError::MissingEntry(p) => {
+                write!(f, "The file is listed but missing from all backups: {}", p)
+            }
+            BackupError::NotInBackup(p) => {
+                write!(f, "Could not find the path in the backup: {}", p)
+            }
+            BackupError::InvalidConfig(e) => e.fmt(f),
+            BackupError::RegexError(e) => write!(f, "Could not parse regex: {}", e),
+            BackupError::WorkerPanic(e) => write!(f, "The worker thread crashed: {}", e),
+            BackupError::CommandFailed(name, reason) => {
+                write!(f, "The command '{}' failed: {}", name, reason)
+            }
+            BackupError::OutputUnavailable(p) => {
+                write!(
+                    f,
+                    "The output is not available (is the drive connected?), missing: {}",
+                    p.to_string_lossy()
+                )
+            }
+            BackupError::OutputMoved(prev, current) => {
+                write!(
+                    f,
+                    "The output location changed since the last backup (from '{}' to '{}'), \
+                     starting a fresh chain (use --chain-across-moves to continue the chain)",
+                    prev.to_string_lossy(),
+                    current.to_string_lossy()
+                )
+            }
+            BackupError::OutsidePrefix(p, prefix) => {
+                write!(
+                    f,
+                    "The file '{}' is not inside the prefix to strip: {}",
+                    p, prefix
+                )
+            }
+            BackupError::RequiredRootEmpty(p, reason) => {
+                write!(
+                    f,
+                    "The required path '{}' has no files to backup: {}",
+                    p, reason
+                )
+            }
+            BackupError::RunAs(user, reason) => {
+                write!(f, "Could not run as the user '{}': {}", user, reason)
+            }
         }
     }
 }
 
-impl std::error::Error for BackupError {}
+/// The wrapped errors are already part of the message, so only their causes are the source
+/// (otherwise the same message would be repeated when walking the chain)
+impl std::error::Error for BackupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BackupError::ArchiveError(e)
+            | BackupError::FileError(e)
+            | BackupError::WriteError(e)
+            | BackupError::IOError(e)
+            | BackupError::DeleteError(e)
+            | BackupError::RenameError(_, _, e) => e.source(),
+            BackupError::YamlError(e) => e.source(),
+            BackupError::FileAccessError(e) => e.source(),
+            BackupError::RegexError(e) => e.source(),
+            _ => None,
+        }
+    }
+}
 
 impl From<serde_yaml::Error> for BackupError {
     fn from(e: serde_yaml::Error) -> Self {
@@ -106,29 +211,196 @@ impl From<std::io::Error> for BackupError {
     }
 }
 
+impl From<TxnError> for BackupError {
+    fn from(e: TxnError) -> Self {
+        match e {
+            TxnError::Journal(e) => BackupError::WriteError(e),
+            TxnError::Step(Step::Rename { from, to }, e) => BackupError::RenameError(
+                from.to_string_lossy().to_string(),
+                to.to_string_lossy().to_string(),
+                e,
+            ),
+            TxnError::Step(Step::Delete { .. }, e) => BackupError::DeleteError(e),
+        }
+    }
+}
+
 impl From<FileAccessError> for BackupError {
     fn from(e: FileAccessError) -> Self {
         BackupError::FileAccessError(e)
     }
 }
 
+/// The number of crawled files in a required include
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequiredRoot {
+    pub path: String,
+    pub files: u64,
+}
+
+/// The time of the backup that an incremental backup is based on
+/// (a chosen base must be older than the new backup, and if the output is configured the same
+/// way as in the latest backup it must also resolve to the same directory, e.g. a symlink that
+/// has not been repointed, see [`Config::chain_across_moves`])
+fn base_time(
+    path: PathBuf,
+    chosen: bool,
+    config: &Config,
+) -> Result<Option<NaiveDateTime>, BackupError> {
+    let previous = BackupReader::read_config_only(path.clone())?;
+    if chosen && previous.time.is_none_or(|t| t >= naive_now()) {
+        return Err(BackupError::InvalidConfig(format!(
+            "The base backup '{}' is not older than the new backup",
+            path.to_string_lossy()
+        )));
+    }
+    if !chosen && !config.chain_across_moves && previous.output == config.output {
+        if let (Some(prev), Some(current)) = (previous.resolved_output, &config.resolved_output) {
+            if &prev != current {
+                return Err(BackupError::OutputMoved(prev, current.clone()));
+            }
+        }
+    }
+    Ok(previous.time)
+}
+
+/// Has the file been removed from the disk (files that cannot be accessed for other reasons, e.g.
+/// due to permissions, still exist)
+fn is_removed(path: &str) -> bool {
+    std::fs::symlink_metadata(long_path(&string_to_path(path)))
+        .is_err_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// The sizes of a written backup (see [`BackupWriter::write`] and [`BackupMerger::write`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WriteStats {
+    /// Number of files stored in the backup
+    pub files: u64,
+    /// Total size of the stored files before compression (in bytes)
+    pub size: u64,
+    /// Size of the backup file (unknown when writing to a stream)
+    pub compressed: Option<u64>,
+    pub elapsed: Duration,
+}
+
+impl Display for WriteStats {
+    /// E.g. `12,345 files, 18.30 GiB -> 9.10 GiB (49%) in 14m12s (21.99 MiB/s)`, where the
+    /// speed is of the uncompressed bytes
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}, {}",
+            format_count(self.files),
+            if self.files == 1 { "file" } else { "files" },
+            format_size(self.size)
+        )?;
+        if let Some(compressed) = self.compressed {
+            write!(f, " -> {}", format_size(compressed))?;
+            if self.size > 0 {
+                write!(f, " ({:.0}%)", compressed as f64 * 100.0 / self.size as f64)?;
+            }
+        }
+        write!(f, " in {}", format_elapsed(self.elapsed))?;
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            write!(f, " ({}/s)", format_size((self.size as f64 / secs) as u64))?;
+        }
+        Ok(())
+    }
+}
+
+/// Where [`BackupWriter`] writes the archive
+enum Destination<'a> {
+    /// A (temporary) file
+    File(&'a Path),
+    Stream(Box<dyn Write + Send>),
+}
+
+/// The block signatures while writing a backup with `delta_files` (see [`Config::delta_files`])
+struct DeltaState {
+    /// The signatures for the new backup (unchanged files keep the previous signatures)
+    current: SignatureList,
+    /// The signatures from the previous backup (empty if nothing can be stored as blocks)
+    previous: SignatureList,
+    /// The size and modification time of the files when they were hashed
+    hashed: HashMap<String, (u64, Option<SystemTime>)>,
+}
+
+impl DeltaState {
+    /// How to store a file as the changed blocks (`None` to store the whole file)
+    fn manifest(&self, name: &str) -> Option<DeltaManifest> {
+        if !self.hashed.contains_key(name) {
+            return None;
+        }
+        let (current, previous) = (self.current.get(name)?, self.previous.get(name)?);
+        let blocks = current.changed_blocks(previous);
+        if blocks.len() * 2 > current.blocks.len() {
+            log::info!(
+                "Storing the whole file '{}' ({} of {} blocks changed)",
+                name,
+                blocks.len(),
+                current.blocks.len()
+            );
+            return None;
+        }
+        Some(DeltaManifest {
+            size: current.size,
+            base: previous.digest(),
+            digest: current.digest(),
+            blocks,
+        })
+    }
+
+    /// Has the file changed since it was hashed (so the signature might not match the content)
+    fn is_stale(&self, name: &str, path: &Path) -> bool {
+        match self.hashed.get(name) {
+            None => false,
+            Some(&(size, time)) => std::fs::metadata(long_path(path))
+                .map_or(true, |m| m.len() != size || m.modified().ok() != time),
+        }
+    }
+}
+
 pub struct BackupWriter {
     pub path: PathBuf,
     pub config: Config,
     pub prev_time: Option<NaiveDateTime>,
     pub list: Option<FileListVec>,
+    pub paranoid: Option<ParanoidReport>,
+    pub required: Option<Vec<RequiredRoot>>,
+    /// Fail the backup if a command fails (instead of leaving out the output)
+    pub strict: bool,
+    skipped: Vec<String>,
+    failed_commands: Vec<String>,
+    /// The outputs of the commands (until they have been added to the backup)
+    spooled: HashMap<PathBuf, PathBuf>,
+    /// The files in the previous backup that have been deleted since (see [`Self::find_deleted`])
+    deleted: Option<Vec<String>>,
+    prev_backup: Option<PathBuf>,
+    checkpoint: Option<ScanCheckpoint>,
     time: NaiveDateTime,
+    stats: Option<WriteStats>,
 }
 
 impl BackupWriter {
     /// Create a new backup
-    pub fn new(config: Config) -> (Self, Option<BackupError>) {
+    pub fn new(mut config: Config) -> (Self, Option<BackupError>) {
+        // Only merged backups should list merged backups
+        config.merged.clear();
+        config.resolved_output = config.get_resolved_output();
+        // The previous backup is either the chosen base or the latest
+        let base = || {
+            config
+                .base
+                .clone()
+                .or_else(|| config.get_backups().get_latest())
+        };
         let (prev_time, error) = if config.incremental {
             match config.time {
                 Some(t) => (Some(t), None),
-                None => match config.get_backups().get_latest() {
-                    Some(path) => match BackupReader::read_config_only(path) {
-                        Ok(c) => (c.time, None),
+                None => match base() {
+                    Some(path) => match base_time(path, config.base.is_some(), &config) {
+                        Ok(t) => (t, None),
                         Err(e) => (None, Some(e)),
                     },
                     None => (None, None),
@@ -137,6 +409,11 @@ impl BackupWriter {
         } else {
             (None, None)
         };
+        let prev_backup = if prev_time.is_some() && config.paranoid_incremental {
+            base()
+        } else {
+            None
+        };
         let path = config.get_new_output();
         (
             Self {
@@ -144,28 +421,153 @@ impl BackupWriter {
                 path,
                 prev_time,
                 list: None,
+                paranoid: None,
+                required: None,
+                strict: false,
+                skipped: vec![],
+                failed_commands: vec![],
+                spooled: HashMap::new(),
+                deleted: None,
+                prev_backup,
+                checkpoint: None,
                 time: naive_now(),
+                stats: None,
             },
             error,
         )
     }
 
+    /// Check that the output is available (see [`Config::missing_output`])
+    pub fn check_output(&self) -> Result<(), BackupError> {
+        match self.config.missing_output() {
+            Some(path) => Err(BackupError::OutputUnavailable(path)),
+            None => Ok(()),
+        }
+    }
+
+    /// Save the progress of the crawl, and continue from an earlier interrupted crawl
+    /// (see [`ScanCheckpoint`])
+    pub fn resume_scan(&mut self) {
+        self.checkpoint = Some(ScanCheckpoint::new(
+            self.config.get_scan_checkpoint(),
+            &self.config,
+            CHECKPOINT_MAX_AGE,
+        ));
+    }
+
+    /// Remove the saved progress of the crawl (if any)
+    pub fn remove_checkpoint(&self) {
+        if let Some(cp) = &self.checkpoint {
+            cp.remove();
+        }
+    }
+
+    /// Crawl for the files in the config (after reading the exclusions from any pattern files)
+    fn crawler(&mut self) -> Result<FileCrawler, BackupError> {
+        self.config
+            .read_pattern_files()
+            .map_err(BackupError::FileError)?;
+        Ok(self.config.crawler()?)
+    }
+
     /// List all files that are added to the backup
     fn get_files(&mut self) -> Result<&mut FileListVec, BackupError> {
         if self.list.is_none() {
-            self.list = Some(FileListVec::crawl(
-                FileCrawler::new(
-                    &self.config.include,
-                    &self.config.exclude,
-                    &self.config.regex,
-                    self.config.local,
+            let crawler = self.crawler()?;
+            self.list = Some(match self.checkpoint.as_mut() {
+                Some(cp) => FileListVec::crawl_with_callback(
+                    crawler,
+                    self.prev_time,
+                    self.config.change_detection,
+                    false,
+                    Some(cp),
+                    |_| Ok(()),
                 )?,
-                self.prev_time,
-            ));
+                None => FileListVec::crawl(crawler, self.prev_time, self.config.change_detection),
+            });
+            self.mark_metadata_updates();
+            self.check_paranoid()?;
         }
+        self.check_required()?;
         Ok(self.list.as_mut().unwrap())
     }
 
+    /// Count the crawled files in the required includes (failing if any of them are empty)
+    fn check_required(&mut self) -> Result<(), BackupError> {
+        if self.required.is_none() {
+            let list = match self.list.as_ref() {
+                Some(list) => list,
+                None => return Ok(()),
+            };
+            let local = self.config.local;
+            let roots: Vec<PathBuf> = self
+                .config
+                .required
+                .iter()
+                .map(|s| required_path(s, local))
+                .collect();
+            let mut counts = vec![0; roots.len()];
+            for (_, fi) in list.iter() {
+                let path = fi.copy_path();
+                for (root, count) in roots.iter().zip(counts.iter_mut()) {
+                    if path.starts_with(root) {
+                        *count += 1;
+                    }
+                }
+            }
+            self.required = Some(
+                self.config
+                    .required
+                    .iter()
+                    .zip(counts)
+                    .map(|(path, files)| RequiredRoot {
+                        path: path.clone(),
+                        files,
+                    })
+                    .collect(),
+            );
+        }
+        for root in self.required.iter().flatten() {
+            if root.files == 0 {
+                let reason = empty_reason(&required_path(&root.path, self.config.local));
+                return Err(BackupError::RequiredRootEmpty(root.path.clone(), reason));
+            }
+        }
+        Ok(())
+    }
+
+    /// Does the backup store metadata updates (instead of the content) for some files
+    fn has_metadata_updates(&self) -> bool {
+        self.config.metadata_updates
+            && self.config.change_detection != ChangeDetection::Mtime
+            && self.prev_time.is_some()
+    }
+
+    /// Find the files where only the metadata has changed (see [`FileListVec::mark_metadata_updates`])
+    fn mark_metadata_updates(&mut self) {
+        if let (true, Some(time), Some(list)) = (
+            self.has_metadata_updates(),
+            self.prev_time,
+            self.list.as_mut(),
+        ) {
+            list.mark_metadata_updates(time);
+        }
+    }
+
+    /// Double check the files that are "unchanged" against the previous backup (if paranoid)
+    fn check_paranoid(&mut self) -> Result<(), BackupError> {
+        if let (Some(prev), Some(list)) = (self.prev_backup.take(), self.list.as_mut()) {
+            let seed = self
+                .config
+                .paranoid_seed
+                .unwrap_or_else(|| self.time.and_utc().timestamp_nanos_opt().unwrap_or(0) as u64);
+            let mut reader = BackupReader::new(prev);
+            self.paranoid =
+                Some(list.paranoid_check(reader.get_list()?, self.config.paranoid_sample, seed));
+        }
+        Ok(())
+    }
+
     /// Iterate through all files that are added to the backup
     pub fn foreach_file(
         &mut self,
@@ -174,69 +576,682 @@ impl BackupWriter {
     ) -> Result<(), BackupError> {
         let all = all || self.prev_time.is_none();
         if self.list.is_some() {
+            self.check_required()?;
             for (b, fi) in self.list.as_mut().unwrap().iter_mut() {
                 if all || *b {
                     callback(Ok(fi))?
                 }
             }
+        } else if self.prev_backup.is_some() || self.has_metadata_updates() {
+            // The paranoid check (and the metadata updates) need the full list before deciding
+            // which files to include
+            self.list = Some(FileListVec::crawl_with_callback(
+                self.crawler()?,
+                self.prev_time,
+                self.config.change_detection,
+                false,
+                self.checkpoint.as_mut(),
+                |res| match res {
+                    Ok(_) => Ok(()),
+                    Err(e) => callback(Err(e)),
+                },
+            )?);
+            self.mark_metadata_updates();
+            self.check_paranoid()?;
+            return self.foreach_file(all, callback);
         } else {
             self.list = Some(FileListVec::crawl_with_callback(
-                FileCrawler::new(
-                    &self.config.include,
-                    &self.config.exclude,
-                    &self.config.regex,
-                    self.config.local,
-                )?,
+                self.crawler()?,
                 self.prev_time,
+                self.config.change_detection,
                 all,
+                self.checkpoint.as_mut(),
                 callback,
             )?);
+            self.check_required()?;
         }
         Ok(())
     }
 
     /// Write (and compress) the backup to disk
+    /// (`on_progress` is called with the number of newly compressed bytes).
+    /// The backup is written to a temporary file that is only renamed when complete
+    /// (or to stdout if the output is `-`, see [`Self::write_to`]).
+    /// Returns the sizes of the backup (also available from [`Self::stats`]).
     pub fn write(
         &mut self,
         on_added: impl FnMut(&mut FileInfo, Result<(), BackupError>) -> Result<(), BackupError>,
+        on_progress: impl FnMut(u64),
         on_final: impl FnOnce(),
-    ) -> Result<(), BackupError> {
-        match self.write_internal(on_added, on_final) {
-            Ok(_) => Ok(()),
+    ) -> Result<WriteStats, BackupError> {
+        if self.config.is_output_stream() {
+            if self.config.incremental {
+                return Err(BackupError::InvalidConfig(
+                    ConfigIssue::IncrementalStream.to_string(),
+                ));
+            }
+            return self.write_to(std::io::stdout(), on_added, on_progress, on_final);
+        }
+        // Nothing is written (or created) if the output is missing
+        self.check_output()?;
+        let start = Instant::now();
+        log::info!(
+            "Compressing the backup '{}' (quality {}, {} threads)",
+            self.path.to_string_lossy(),
+            self.config.quality,
+            self.config.threads
+        );
+        let tmp = temporary_path(&self.path);
+        let res = self.write_internal(Destination::File(&tmp), on_added, on_progress, on_final);
+        self.remove_spooled();
+        match res {
+            Ok(mut stats) => {
+                self.rename_complete(&tmp)?;
+                self.remove_checkpoint();
+                log::info!(
+                    "Compressed the backup '{}' in {:.1?} ({} files skipped)",
+                    self.path.to_string_lossy(),
+                    start.elapsed(),
+                    self.skipped.len()
+                );
+                stats.compressed = archive_size(&self.path).ok();
+                stats.elapsed = start.elapsed();
+                self.stats = Some(stats);
+                Ok(stats)
+            }
             #[allow(unused_must_use)]
             Err(e) => {
+                match e {
+                    BackupError::Cancel => log::info!("The backup was cancelled"),
+                    _ => log::info!("The backup failed"),
+                }
+                log::info!("Removing the incomplete backup '{}'", tmp.to_string_lossy());
                 // Clean up failed backup (allowed to fail without checking)
-                std::fs::remove_file(&self.path);
+                remove_archive(&tmp);
                 Err(e)
             }
         }
     }
 
+    /// Write the backup (see [`Self::write`]) using the `strict` and `cancel` from the options
+    pub fn write_with(
+        &mut self,
+        mut on_added: impl FnMut(&mut FileInfo, Result<(), BackupError>) -> Result<(), BackupError>,
+        on_progress: impl FnMut(u64),
+        on_final: impl FnOnce(),
+        options: &BackupOptions,
+    ) -> Result<WriteStats, BackupError> {
+        self.strict = options.strict;
+        self.write(
+            |fi, res| {
+                on_added(fi, res)?;
+                check_cancel(options.cancel)
+            },
+            on_progress,
+            on_final,
+        )
+    }
+
+    /// Write (and compress) the backup to a stream, such as stdout (see [`Self::write`]).
+    /// An incomplete backup cannot be removed from the stream, and the files that are skipped
+    /// (due to errors) remain in the embedded file list.
+    pub fn write_to<W: Write + Send + 'static>(
+        &mut self,
+        writer: W,
+        on_added: impl FnMut(&mut FileInfo, Result<(), BackupError>) -> Result<(), BackupError>,
+        on_progress: impl FnMut(u64),
+        on_final: impl FnOnce(),
+    ) -> Result<WriteStats, BackupError> {
+        let start = Instant::now();
+        log::info!(
+            "Compressing the backup to a stream (quality {}, {} threads)",
+            self.config.quality,
+            self.config.threads
+        );
+        let destination = Destination::Stream(Box::new(writer));
+        let res = self.write_internal(destination, on_added, on_progress, on_final);
+        self.remove_spooled();
+        match &res {
+            Ok(_) => log::info!(
+                "Compressed the backup in {:.1?} ({} files skipped)",
+                start.elapsed(),
+                self.skipped.len()
+            ),
+            Err(BackupError::Cancel) => log::info!("The backup was cancelled"),
+            Err(_) => log::info!("The backup failed"),
+        }
+        let stats = WriteStats {
+            elapsed: start.elapsed(),
+            ..res?
+        };
+        self.stats = Some(stats);
+        Ok(stats)
+    }
+
+    /// Remove the outputs of the commands (after they have been added to the backup)
+    fn remove_spooled(&mut self) {
+        for (_, spool) in self.spooled.drain() {
+            #[allow(unused_must_use)]
+            std::fs::remove_file(spool);
+        }
+    }
+
     fn write_internal(
         &mut self,
+        destination: Destination,
         mut on_added: impl FnMut(&mut FileInfo, Result<(), BackupError>) -> Result<(), BackupError>,
+        mut on_progress: impl FnMut(u64),
         on_final: impl FnOnce(),
-    ) -> Result<(), BackupError> {
-        let list_string = FileListString::from(self.get_files()?);
-        let mut encoder =
-            CompressionEncoder::create(&self.path, self.config.quality, self.config.threads)?;
+    ) -> Result<WriteStats, BackupError> {
+        self.run_commands(&mut on_added)?;
+        let dedup = self.config.dedup;
+        if dedup {
+            // The hashes are stored in the list (so identical files have the same size and hash)
+            for (b, fi) in self.get_files()?.iter_mut() {
+                if *b && fi.size > 0 && fi.hash.is_none() {
+                    #[allow(unused_must_use)]
+                    {
+                        fi.hash_content();
+                    }
+                }
+            }
+        }
+        self.collect_user_meta()?;
+        self.find_deleted()?;
+        let deleted = self.deleted.take().unwrap_or_default();
+        let files = self.get_files()?;
+        let original_size = files
+            .iter()
+            .filter(|(b, _)| *b)
+            .map(|(_, fi)| fi.size)
+            .sum();
+        let mut list_string = FileListString::from(files);
+        list_string.add_deleted(&deleted);
+        self.deleted = Some(deleted);
+        self.config.original_size = Some(original_size);
+        let (quality, threads) = (self.config.quality, self.config.threads);
+        let (encoder, tmp) = match destination {
+            Destination::File(tmp) => (
+                CompressionEncoder::create(tmp, quality, threads, self.config.volume_size)?,
+                Some(tmp),
+            ),
+            Destination::Stream(writer) => (
+                CompressionEncoder::from_writer(writer, quality, threads)?,
+                None,
+            ),
+        };
+        let retry = Retry::new(
+            self.config.retries,
+            Duration::from_millis(self.config.retry_delay),
+        );
+        let mut encoder = encoder
+            .store_only(&self.config.store_only)
+            .macos_xattrs(self.config.macos_xattrs)
+            .tar_format(self.config.tar_format)
+            .retry(retry);
         self.config.time = Some(self.time);
+        // The output directory might have been created by the encoder
+        self.config.resolved_output = self.config.get_resolved_output();
         encoder.append_data(CONFIG_DEFAULT_NAME, self.config.as_yaml()?)?;
         encoder.append_data(list_string.filename(), list_string)?;
+        let mut deltas = self.delta_state()?;
+        // Signatures removed after they were written (so the archive is rewritten)
+        let mut stale = false;
+        if let Some(deltas) = &deltas {
+            encoder.append_data(SIGNATURES_NAME, deltas.current.to_string())?;
+        }
+        // Dropping this stops the reading (e.g. when cancelling)
+        let mut read_ahead = self.read_ahead(deltas.as_ref(), retry);
 
         let list = self.list.as_mut().unwrap();
-        for (b, fi) in list.iter_mut() {
-            if *b {
-                let res = encoder.append_file(fi.get_path());
+        // The first added file for each inode (later hard links only refer to it)
+        let mut linked: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        // The first added file for each size and hash (when deduplicating)
+        let mut stored: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        for (i, (b, fi)) in list.iter_mut().enumerate() {
+            if *b && fi.generated {
+                let mut done = 0;
+                let spool = &self.spooled[&fi.clone_path()];
+                let res = encoder.append_generated(fi.get_path(), spool, |b| {
+                    done += b;
+                    on_progress(b);
+                });
+                if done < fi.size {
+                    on_progress(fi.size - done);
+                }
+                if res.is_err() {
+                    self.skipped.push(fi.copy_string().to_string());
+                }
+                on_added(fi, res.map_err(BackupError::IOError))?;
+            } else if *b {
+                let res = if let Some(target) = fi.inode.and_then(|id| linked.get(&id)) {
+                    Some(encoder.append_hard_link(fi.get_path(), target))
+                } else {
+                    match fi
+                        .hash
+                        .filter(|_| dedup)
+                        .and_then(|h| stored.get(&(fi.size, h)))
+                    {
+                        // The hash is not trusted to be unique, so the content is also compared
+                        Some(target) if same_content(target, fi.get_path()).unwrap_or(false) => {
+                            Some(encoder.append_duplicate(fi.get_path(), target))
+                        }
+                        _ => None,
+                    }
+                };
+                if let Some(res) = res {
+                    on_progress(fi.size);
+                    if res.is_err() {
+                        self.skipped.push(fi.copy_string().to_string());
+                    }
+                    on_added(fi, res.map_err(BackupError::IOError))?;
+                    continue;
+                }
+                let mut done = 0;
+                let name = fi.copy_string().to_string();
+                // Hard links are always stored whole (the targets of later links)
+                let manifest = deltas
+                    .as_ref()
+                    .filter(|_| fi.inode.is_none())
+                    .and_then(|d| d.manifest(&name));
+                let source = match read_ahead.as_mut().and_then(|r| r.take(&i)) {
+                    Some(source) => source,
+                    None => open_with_retries(fi.get_path(), retry),
+                };
+                let mut res = source.and_then(|source| {
+                    let on_progress = |b| {
+                        done += b;
+                        on_progress(b);
+                    };
+                    match &manifest {
+                        Some(m) => encoder.append_delta(fi.get_path(), source, m, on_progress),
+                        None => encoder.append_source(fi.get_path(), source, on_progress),
+                    }
+                });
+                // Keep the progress in sync with the crawled sizes (even if the file has changed)
+                if done < fi.size {
+                    on_progress(fi.size - done);
+                }
+                if let Some(d) = deltas.as_mut().filter(|_| res.is_ok()) {
+                    if d.is_stale(&name, fi.get_path()) {
+                        if manifest.is_some() {
+                            // The stored blocks might not fit with the previous version
+                            res = Err(std::io::Error::other(
+                                "The file changed while the blocks were stored",
+                            ));
+                        } else {
+                            log::warn!("The file '{}' changed while it was stored", name);
+                            stale |= d.current.remove(&name).is_some();
+                        }
+                    }
+                }
+                match (&res, fi.inode) {
+                    (Err(_), _) => self.skipped.push(fi.copy_string().to_string()),
+                    (Ok(_), Some(id)) => {
+                        linked.insert(id, fi.clone_path());
+                    }
+                    (Ok(_), None) => {}
+                }
+                // Files stored as blocks cannot be the targets of duplicates
+                if let (Ok(_), Some(hash), true, None) = (&res, fi.hash, dedup, &manifest) {
+                    stored
+                        .entry((fi.size, hash))
+                        .or_insert_with(|| fi.clone_path());
+                }
                 on_added(fi, res.map_err(BackupError::IOError))?;
             }
         }
         on_final();
+        // Closing also syncs the archive to the disk
         encoder.close()?;
+        let skipped: HashSet<&str> = self.skipped.iter().map(|s| s.as_str()).collect();
+        let mut stats = WriteStats::default();
+        for (_, fi) in list.iter_mut().filter(|(b, _)| *b) {
+            if !skipped.contains(fi.get_string().as_str()) {
+                stats.files += 1;
+                stats.size += fi.size;
+            }
+        }
+        // The skipped files (and the files that changed while stored) get no signatures
+        let signatures = deltas.map(|mut d| {
+            for s in self.skipped.iter() {
+                d.current.remove(s);
+            }
+            d.current
+        });
+        match tmp {
+            Some(tmp) if !self.skipped.is_empty() || stale => {
+                self.remove_skipped(tmp, signatures.as_ref())?
+            }
+            None if !self.skipped.is_empty() => log::warn!(
+                "The skipped files are still listed in the backup (a stream cannot be rewritten)"
+            ),
+            _ => {}
+        }
+        Ok(stats)
+    }
+
+    /// Find the files in the previous backup (the base or the latest) that have been deleted
+    /// since, so that they can be removed when mirroring a restore (see
+    /// [`FileListString::add_deleted`]). Files that still exist (e.g. excluded or unreadable
+    /// files) are not deleted.
+    pub fn find_deleted(&mut self) -> Result<&[String], BackupError> {
+        if self.deleted.is_none() {
+            self.deleted = Some(self.diff_previous()?);
+        }
+        Ok(self.deleted.as_deref().unwrap_or_default())
+    }
+
+    fn diff_previous(&mut self) -> Result<Vec<String>, BackupError> {
+        if self.prev_time.is_none() {
+            return Ok(vec![]);
+        }
+        let previous = match self
+            .config
+            .base
+            .clone()
+            .or_else(|| self.config.get_backups().get_latest())
+        {
+            Some(previous) => previous,
+            None => return Ok(vec![]),
+        };
+        let mut reader = BackupReader::new(previous);
+        let previous = match reader.get_list() {
+            Ok(list) => list,
+            Err(e) => {
+                log::warn!(
+                    "Could not read the previous list to find the deleted files: {}",
+                    e
+                );
+                return Ok(vec![]);
+            }
+        };
+        // The paths in the lists always use `/`
+        let current: HashSet<String> = self
+            .get_files()?
+            .iter()
+            .map(|(_, fi)| fi.copy_string().replace('\\', "/"))
+            .collect();
+        let generated: HashSet<&str> = previous.iter_generated().collect();
+        let deleted: Vec<String> = previous
+            .iter()
+            .map(|(_, s)| s)
+            .filter(|s| !current.contains(*s) && !generated.contains(s))
+            .filter(|s| is_removed(s))
+            .map(String::from)
+            .collect();
+        log::info!(
+            "{} files have been deleted since the previous backup",
+            deleted.len()
+        );
+        Ok(deleted)
+    }
+
+    /// Start reading the files that are stored whole on background threads (see [`ReadAhead`]),
+    /// files larger than `read_ahead_mb` (and hard links and changed blocks) are read directly
+    fn read_ahead(&self, deltas: Option<&DeltaState>, retry: Retry) -> Option<ReadAhead<usize>> {
+        let max_bytes = self.config.read_ahead_mb << 20;
+        if max_bytes == 0 {
+            return None;
+        }
+        let dedup = self.config.dedup;
+        let mut hashes = HashSet::new();
+        let files: Vec<(usize, PathBuf, u64)> = self
+            .list
+            .as_ref()?
+            .iter()
+            .enumerate()
+            .filter(|(_, (b, fi))| *b && !fi.generated && fi.inode.is_none())
+            .filter(|(_, (_, fi))| fi.size <= max_bytes)
+            // Files with the same content are probably stored as duplicates
+            .filter(|(_, (_, fi))| match fi.hash.filter(|_| dedup) {
+                Some(hash) => hashes.insert((fi.size, hash)),
+                None => true,
+            })
+            .filter(|(_, (_, fi))| {
+                deltas.is_none_or(|d| d.manifest(&fi.copy_string()).is_none())
+            })
+            .map(|(i, (_, fi))| (i, fi.clone_path(), fi.size))
+            .collect();
+        if files.is_empty() {
+            return None;
+        }
+        let threads = self.config.threads as usize;
+        Some(ReadAhead::new(files, threads, max_bytes, retry))
+    }
+
+    /// Hash the blocks of the large files that match `delta_files` (see [`Config::delta_files`]),
+    /// and read the signatures from the previous backup (to find the changed blocks)
+    fn delta_state(&mut self) -> Result<Option<DeltaState>, BackupError> {
+        if self.config.delta_files.is_empty() {
+            return Ok(None);
+        }
+        let regex = match RegexSet::new(&self.config.delta_files) {
+            Ok(regex) => regex,
+            Err(e) => {
+                log::warn!("Storing the whole files (invalid delta_files): {}", e);
+                return Ok(None);
+            }
+        };
+        // Restoring the blocks needs the previous backup (the one found by `get_previous`)
+        let mut previous = SignatureList::default();
+        if self.prev_time.is_some() && self.config.base.is_none() {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            if let Some(prev) = self.config.get_backups_in(dir).get_previous(&self.path) {
+                match BackupReader::new(prev).read_signatures() {
+                    Ok(signatures) => previous = signatures,
+                    Err(e) => log::warn!(
+                        "Storing the whole files (could not read the previous signatures): {}",
+                        e
+                    ),
+                }
+            }
+        }
+        let min_size = self.config.delta_min_size;
+        let mut state = DeltaState {
+            current: SignatureList::default(),
+            previous,
+            hashed: HashMap::new(),
+        };
+        for (b, fi) in self.get_files()?.iter_mut() {
+            if fi.generated || fi.size < min_size || !regex.is_match(fi.get_string()) {
+                continue;
+            }
+            let name = fi.copy_string().to_string();
+            if !*b {
+                if let Some(signature) = state.previous.get(&name) {
+                    state.current.insert(&name, signature.clone());
+                }
+                continue;
+            }
+            let meta = std::fs::metadata(long_path(fi.get_path()));
+            match meta.and_then(|m| Ok((m, BlockSignature::read(fi.get_path())?))) {
+                Ok((meta, signature)) => {
+                    state.current.insert(&name, signature);
+                    state
+                        .hashed
+                        .insert(name, (meta.len(), meta.modified().ok()));
+                }
+                // The error is reported when the file is stored
+                Err(e) => log::info!("Could not hash the blocks of '{}': {}", name, e),
+            }
+        }
+        Ok(Some(state))
+    }
+
+    /// Rename the complete (temporary) archive to the name of the backup
+    fn rename_complete(&self, tmp: &Path) -> Result<(), BackupError> {
+        // With a journal a failed rename is instead recovered later
+        let journal = self.config.journal.then(|| self.config.get_journal_dir());
+        rename_archive_txn(tmp, &self.path)
+            .commit(journal.as_deref())
+            .map_err(|e| {
+                log::error!("Could not rename the complete backup: {}", e);
+                if journal.is_none() {
+                    remove_archive(tmp).unwrap_or_default();
+                }
+                e.into()
+            })
+    }
+
+    /// Run the commands from the config and add the outputs to the list of files.
+    /// The outputs are spooled to temporary files, since the sizes are written before the content.
+    fn run_commands(
+        &mut self,
+        on_added: &mut impl FnMut(&mut FileInfo, Result<(), BackupError>) -> Result<(), BackupError>,
+    ) -> Result<(), BackupError> {
+        let mut list = std::mem::take(self.get_files()?);
+        // The commands are always run again (and never copied from a previous backup)
+        list.retain_mut(|_, fi| !fi.generated);
+        self.failed_commands.clear();
+        let mut res = Ok(());
+        let spool_base = if self.config.is_output_stream() {
+            std::env::temp_dir().join(format!("simple_backup_{}", std::process::id()))
+        } else {
+            self.path.clone()
+        };
+        for (i, command) in self.config.commands.iter().enumerate() {
+            let spool = extend_pathbuf(spool_base.clone(), format!(".cmd{}.tmp", i));
+            let mut fi = FileInfo::from(command.path());
+            log::info!("Running the command '{}': {}", command.name, command.cmd);
+            match command.run(&spool) {
+                Ok(size) => {
+                    fi.size = size;
+                    fi.generated = true;
+                    self.spooled.insert(fi.clone_path(), spool);
+                    list.push(true, fi);
+                }
+                Err(reason) => {
+                    #[allow(unused_must_use)]
+                    std::fs::remove_file(&spool);
+                    let e = BackupError::CommandFailed(command.name.clone(), reason);
+                    log::warn!("{}", e);
+                    self.failed_commands.push(command.name.clone());
+                    res = if self.strict {
+                        Err(e)
+                    } else {
+                        on_added(&mut fi, Err(e))
+                    };
+                    if res.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        list.sort_unstable();
+        self.list = Some(list);
+        res
+    }
+
+    /// Run the `metadata_command` (if any) for all crawled files (also the unchanged files, so
+    /// that the metadata in the latest backup is always complete)
+    fn collect_user_meta(&mut self) -> Result<(), BackupError> {
+        let cmd = match self.config.metadata_command.clone() {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+        let list = self.get_files()?;
+        // The paths are given one per line, so paths with line breaks get no metadata
+        let paths: Vec<String> = list
+            .iter()
+            .filter(|(_, fi)| !fi.generated)
+            .map(|(_, fi)| fi.copy_string().to_string())
+            .filter(|p| !p.contains(['\n', '\r']))
+            .collect();
+        log::info!(
+            "Running the metadata command for {} files: {}",
+            paths.len(),
+            cmd
+        );
+        let mut meta = collect_metadata(&cmd, &paths, METADATA_BATCH);
+        for (_, fi) in list.iter_mut() {
+            fi.user_meta = meta.remove(fi.copy_string().as_ref());
+        }
         Ok(())
     }
 
-    #[allow(unused)]
+    /// Files that could not be read (and are missing from the backup)
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
+
+    /// Commands that failed (and whose outputs are missing from the backup)
+    pub fn failed_commands(&self) -> &[String] {
+        &self.failed_commands
+    }
+
+    /// The sizes of the written backup (after [`Self::write`])
+    pub fn stats(&self) -> Option<WriteStats> {
+        self.stats
+    }
+
+    /// The stored bytes by how they were compressed (after [`Self::write`] to a file,
+    /// see [`crate::stats::quality_advice`])
+    pub fn compression_profile(&self) -> Option<CompressionProfile> {
+        let stats = self.stats?;
+        let skipped: HashSet<&str> = self.skipped.iter().map(|s| s.as_str()).collect();
+        let files = self
+            .list
+            .as_ref()?
+            .iter()
+            .filter(|(b, _)| *b)
+            .map(|(_, fi)| (fi.copy_string(), fi.size))
+            .filter(|(s, _)| !skipped.contains(s.as_ref()));
+        Some(CompressionProfile::new(
+            self.config.quality,
+            &self.config.store_only,
+            files,
+            stats.compressed?,
+            stats.elapsed,
+        ))
+    }
+
+    /// Rewrite the (temporary) archive with the skipped files removed from the embedded list
+    /// (and with the updated block signatures, if any)
+    fn remove_skipped(
+        &mut self,
+        archive: &Path,
+        signatures: Option<&SignatureList>,
+    ) -> Result<(), BackupError> {
+        let skipped: HashSet<&str> = self.skipped.iter().map(|s| s.as_str()).collect();
+        let list = self.list.as_mut().unwrap();
+        list.retain_mut(|b, fi| !*b || !skipped.contains(fi.get_string().as_str()));
+        let mut list_string = FileListString::from(list);
+        list_string.add_deleted(self.deleted.as_deref().unwrap_or_default());
+        let tmp = temporary_path(archive);
+        let res = (|| -> std::io::Result<()> {
+            let mut decoder = CompressionDecoder::read(archive, self.config.threads)?;
+            let mut encoder = CompressionEncoder::create(
+                &tmp,
+                self.config.quality,
+                self.config.threads,
+                self.config.volume_size,
+            )?
+            .store_only(&self.config.store_only)
+            .tar_format(self.config.tar_format);
+            for (i, entry) in decoder.entries()?.enumerate() {
+                let signatures = signatures.filter(|_| is_signatures(&entry));
+                let (_, entry) = entry?;
+                if i == 1 {
+                    encoder.append_data(list_string.filename(), &list_string)?;
+                } else if let Some(signatures) = signatures {
+                    encoder.append_data(SIGNATURES_NAME, signatures.to_string())?;
+                } else {
+                    encoder.append_entry(entry, |_| ())?;
+                }
+            }
+            encoder.close()
+        })();
+        if res.is_ok() {
+            return Ok(rename_archive(&tmp, archive)?);
+        }
+        remove_archive(&tmp).unwrap_or_default();
+        res.map_err(BackupError::WriteError)
+    }
+
     pub fn export_list<P: AsRef<Path>>(&mut self, path: P, all: bool) -> Result<(), BackupError> {
         let f = File::create(path).map_err(BackupError::FileError)?;
         let mut f = BufWriter::new(f);
@@ -249,7 +1264,7 @@ impl BackupWriter {
                         "\n{}, {:>6.2} KiB, {}",
                         fi.time.unwrap().format("%Y-%m-%d %H:%M:%S"),
                         number / 1024.0,
-                        &fi.get_string()
+                        display_path(fi.get_string())
                     )
                 }
                 NumberPrefix::Prefixed(prefix, number) => {
@@ -259,7 +1274,7 @@ impl BackupWriter {
                         fi.time.unwrap().format("%Y-%m-%d %H:%M:%S"),
                         number,
                         prefix,
-                        &fi.get_string()
+                        display_path(fi.get_string())
                     )
                 }
             }
@@ -274,33 +1289,185 @@ impl BackupWriter {
         Ok(())
     }
 
-    #[allow(unused)]
     pub fn delete_file(&self) -> Result<(), std::io::Error> {
-        if self.path.exists() {
-            std::fs::remove_file(&self.path)
+        if archive_exists(&self.path) {
+            remove_archive(&self.path)
         } else {
             Ok(())
         }
     }
 }
 
+/// Do the files have the same content
+fn same_content(a: &Path, b: &Path) -> std::io::Result<bool> {
+    let (mut a, mut b) = (
+        BufReader::new(File::open(a)?),
+        BufReader::new(File::open(b)?),
+    );
+    loop {
+        let (ba, bb) = (a.fill_buf()?, b.fill_buf()?);
+        let n = ba.len().min(bb.len());
+        if n == 0 {
+            return Ok(ba.len() == bb.len());
+        }
+        if ba[..n] != bb[..n] {
+            return Ok(false);
+        }
+        a.consume(n);
+        b.consume(n);
+    }
+}
+
+/// Open a file for adding to a backup, retrying a few times (e.g. if the file is temporarily locked)
+pub(crate) fn open_with_retries(path: &Path, retry: Retry) -> std::io::Result<SourceFile> {
+    let mut attempt = 0;
+    loop {
+        match SourceFile::open(path) {
+            // Deleted files will not come back, so only retry other errors
+            Err(e) if attempt < retry.retries && e.kind() != std::io::ErrorKind::NotFound => {
+                std::thread::sleep(retry.backoff(attempt));
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Resolve a required include the same way as the crawler resolves includes
+pub(crate) fn required_path(path: &str, local: bool) -> PathBuf {
+    let path = PathBuf::from(expand_path(path));
+    if local {
+        path.clean()
+    } else {
+        match path.absolutize() {
+            Ok(p) => p.to_path_buf(),
+            Err(_) => path,
+        }
+    }
+}
+
+/// Explain why a required include has no files
+fn empty_reason(path: &Path) -> String {
+    match path.metadata() {
+        Err(e) => e.to_string(),
+        Ok(md) if md.is_dir() => match path.read_dir() {
+            Err(e) => e.to_string(),
+            Ok(_) => String::from("no files were found (or all were excluded)"),
+        },
+        Ok(_) => String::from("the file is excluded"),
+    }
+}
+
+/// What to do with existing files when restoring
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overwrite {
+    /// Keep the existing files (they are reported as errors)
+    #[default]
+    Never,
+    /// Replace the existing files
+    Always,
+    /// Only replace the existing files that are older than the files in the backup
+    /// (based on the modification time)
+    IfNewer,
+}
+
+impl Overwrite {
+    /// The policy from the command line flags
+    pub fn from_flags(force: bool, newer: bool) -> Self {
+        match (force, newer) {
+            (true, _) => Self::Always,
+            (false, true) => Self::IfNewer,
+            (false, false) => Self::Never,
+        }
+    }
+
+    /// Why an existing file is kept instead of restored (`None` if the file can be written)
+    fn conflict(&self, path: &mut FileInfo, mtime: Option<u64>) -> Option<std::io::Error> {
+        let existing = match self {
+            Overwrite::Always => return None,
+            _ => long_path(path.get_path()).metadata().ok()?,
+        };
+        if *self == Overwrite::IfNewer {
+            let backup = UNIX_EPOCH + Duration::from_secs(mtime.unwrap_or(0));
+            // The archives only store whole seconds
+            let existing = existing
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs()));
+            if existing.is_some_and(|t| t < backup) {
+                return None;
+            }
+            return Some(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!(
+                    "Skipped '{}' (the existing file is not older than the backup).",
+                    path.get_string()
+                ),
+            ));
+        }
+        Some(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("File '{}' already exists.", path.get_string()),
+        ))
+    }
+}
+
 #[derive(Clone)]
 pub struct BackupReader {
     pub path: FileInfo,
     pub config: Option<Config>,
     list: Option<FileListString>,
+    /// Also look for previous backups in the original output directory (from the config)
+    follow_origin: bool,
+    /// The backup is read from a stream instead of the path (see [`Self::from_stream`])
+    stream: Option<StreamSource>,
+    /// The positions of the config and the file list, when they are not the first two entries
+    /// (in archives from old versions, see [`Self::is_legacy`])
+    legacy_layout: Option<[usize; 2]>,
 }
 
 impl BackupReader {
-    /// Read a backup
+    /// Read a backup (the first volume, `*.001`, can be used for multi-volume backups)
     pub fn new(path: PathBuf) -> Self {
+        let path = first_volume_backup(&path).unwrap_or(path);
         BackupReader {
             path: path.into(),
             list: None,
             config: None,
+            follow_origin: false,
+            stream: None,
+            legacy_layout: None,
         }
     }
 
+    /// Read a backup from a stream, such as stdin (the path is `-`).
+    /// The stream is only read twice: once for the config and file list, and once for the files
+    /// (so e.g. previous backups and the modification times cannot be read).
+    pub fn from_stream<R: Read + Send + 'static>(reader: R) -> Self {
+        BackupReader {
+            path: FileInfo::from(STREAM_PATH),
+            list: None,
+            config: None,
+            follow_origin: false,
+            stream: Some(StreamSource::new(reader)),
+            legacy_layout: None,
+        }
+    }
+
+    /// Is the backup read from a stream (see [`Self::from_stream`])
+    pub fn is_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Continue the chain of incremental backups in the original output directory if the
+    /// previous backup is not found next to this backup
+    pub fn with_follow_origin(mut self, follow: bool) -> Self {
+        self.follow_origin = follow;
+        self
+    }
+
     /// Read a backup from a config
     pub fn from_config(config: Config) -> Result<Self, BackupError> {
         match config.get_backups().get_latest() {
@@ -309,12 +1476,62 @@ impl BackupReader {
                 path: prev.into(),
                 config: Some(config),
                 list: None,
+                follow_origin: false,
+                stream: None,
+                legacy_layout: None,
             }),
         }
     }
 
+    /// Open the archive (decompressing in a separate thread if the config uses multiple threads)
     pub fn get_decoder<'a>(&self) -> Result<CompressionDecoder<'a>, BackupError> {
-        CompressionDecoder::read(self.path.copy_path().as_path()).map_err(BackupError::ArchiveError)
+        // The backup might have been created on a computer with more cores
+        let threads = self
+            .config
+            .as_ref()
+            .map_or(1, |c| c.threads.min(num_cpus::get() as u32));
+        match &self.stream {
+            Some(stream) => stream
+                .open()
+                .and_then(|s| CompressionDecoder::from_stream(s, threads)),
+            None => CompressionDecoder::read(self.path.copy_path().as_path(), threads),
+        }
+        .map_err(BackupError::ArchiveError)
+    }
+
+    /// Read the modification times and sizes of the files stored in the backup (from the headers
+    /// in the archive, so the whole backup is decompressed but nothing is extracted)
+    pub fn read_headers(&self) -> Result<HashMap<String, (NaiveDateTime, u64)>, BackupError> {
+        let mut decoder = self.get_decoder()?;
+        let mut headers = HashMap::new();
+        let entries = decoder.entries().map_err(BackupError::ArchiveError)?;
+        for entry in skip_meta(entries, self.meta_positions()) {
+            let (mut fi, entry) = entry.map_err(BackupError::ArchiveError)?;
+            let header = entry.header();
+            if let (Ok(time), Ok(size)) = (header.mtime(), header.size()) {
+                let time = system_to_naive(UNIX_EPOCH + Duration::from_secs(time));
+                #[cfg(target_os = "windows")]
+                headers.insert(fi.move_string().replace('\\', "/"), (time, size));
+                #[cfg(not(target_os = "windows"))]
+                headers.insert(fi.move_string(), (time, size));
+            }
+        }
+        Ok(headers)
+    }
+
+    /// The files stored in this backup with sizes and modification times
+    /// (see [`FileListString::entries`] for a faster alternative without the times)
+    #[allow(unused)]
+    pub fn entries(&mut self) -> Result<impl Iterator<Item = Entry<'_>> + '_, BackupError> {
+        let headers = self.read_headers()?;
+        Ok(self.get_list()?.entries().map(move |mut e| {
+            if let Some((time, size)) = headers.get(e.path) {
+                e.time = Some(*time);
+                // Links (e.g. deduplicated files) have no size in the archive
+                e.size = e.size.or(Some(*size));
+            }
+            e
+        }))
     }
 
     /// Read a backup, but only return the embedded config
@@ -326,6 +1543,11 @@ impl BackupReader {
 
     /// Read the embedded config from the backup
     fn read_config(&mut self) -> Result<&mut Config, BackupError> {
+        if self.is_stream() {
+            // The stream is only read once for the metadata
+            self.read_meta()?;
+            return Ok(self.config.as_mut().unwrap());
+        }
         let mut decoder = self.get_decoder()?;
         let entry = decoder.entries().map_err(BackupError::ArchiveError)?.next();
         let entry = match entry {
@@ -333,12 +1555,16 @@ impl BackupReader {
             Some(Err(e)) => return Err(BackupError::ArchiveError(e)),
             None => return Err(BackupError::NoConfig(self.path.clone_path())),
         };
-        self.parse_config(entry)?;
+        match self.parse_config(entry) {
+            Err(BackupError::NoConfig(_)) => self.scan_meta()?,
+            res => res?,
+        }
         Ok(self.config.as_mut().unwrap())
     }
 
     fn parse_config(&mut self, mut entry: CompressionDecoderEntry) -> Result<(), BackupError> {
-        if entry.0.get_string() != CONFIG_DEFAULT_NAME {
+        let name = entry.0.get_string();
+        if name != CONFIG_DEFAULT_NAME && !LEGACY_CONFIG_NAMES.contains(&name.as_str()) {
             return Err(BackupError::NoConfig(self.path.clone_path()));
         }
         let mut s = String::new();
@@ -352,26 +1578,89 @@ impl BackupReader {
         Ok(())
     }
 
+    /// Look through the whole archive for the config and the file list (archives from old versions
+    /// might have them in another order, or after other entries)
+    fn scan_meta(&mut self) -> Result<(), BackupError> {
+        log::info!(
+            "Looking for the config and the file list in '{}' (the backup might be from an old version)",
+            self.path.get_string()
+        );
+        let mut decoder = self.get_decoder()?;
+        let (mut config, mut list) = (None, None);
+        for (i, entry) in decoder
+            .entries()
+            .map_err(BackupError::ArchiveError)?
+            .enumerate()
+        {
+            let entry = entry.map_err(BackupError::ArchiveError)?;
+            // The stored name (the files in the backup have a prefix, see `path_to_archive`)
+            let name = entry.1.path().map_err(BackupError::ArchiveError)?;
+            let name = name.to_string_lossy().to_string();
+            if config.is_none()
+                && (name == CONFIG_DEFAULT_NAME || LEGACY_CONFIG_NAMES.contains(&name.as_str()))
+            {
+                self.parse_config(entry)?;
+                config = Some(i);
+            } else if list.is_none() && FileListString::version_of(&name).is_some() {
+                self.parse_list(entry)?;
+                list = Some(i);
+            }
+            if let (Some(config), Some(list)) = (config, list) {
+                self.legacy_layout = Some([config, list]);
+                return Ok(());
+            }
+        }
+        match config {
+            None => Err(BackupError::NoConfig(self.path.clone_path())),
+            Some(_) => Err(BackupError::NoList(self.path.clone_path())),
+        }
+    }
+
+    /// Is the backup from an old version (the config had to be migrated, see
+    /// [`Config::from_legacy_yaml`], or the config and the file list are not the first entries)
+    pub fn is_legacy(&self) -> bool {
+        self.legacy_layout.is_some()
+            || self
+                .config
+                .as_ref()
+                .is_some_and(|c| !c.migrations.is_empty())
+    }
+
+    /// Are the files in the archive not sorted (as in the lists from the oldest versions)
+    pub fn is_unsorted(&self) -> bool {
+        self.list
+            .as_ref()
+            .is_some_and(|l| l.filename() == "files.csv")
+    }
+
+    /// The positions of the config and the file list in the archive
+    fn meta_positions(&self) -> [usize; 2] {
+        self.legacy_layout.unwrap_or([0, 1])
+    }
+
     /// Get the config
     pub fn get_config(&mut self) -> Result<&mut Config, BackupError> {
         if self.config.is_none() {
-            self.read_config()
-        } else {
-            Ok(self.config.as_mut().unwrap())
+            self.read_config()?;
         }
+        Ok(self.config.as_mut().unwrap())
     }
 
     /// Read the embedded list of files from the backup
     fn read_list(&mut self) -> Result<&FileListString, BackupError> {
+        if self.is_stream() {
+            return Ok(self.read_meta()?.1);
+        }
         let mut decoder = self.get_decoder()?;
-        let mut entries = decoder
-            .entries()
-            .map_err(BackupError::ArchiveError)?
-            .skip(1);
-        match entries.next() {
+        let mut entries = decoder.entries().map_err(BackupError::ArchiveError)?;
+        let res = match entries.nth(self.meta_positions()[1]) {
             Some(entry) => self.parse_list(entry.map_err(BackupError::ArchiveError)?),
             None => Err(BackupError::NoList(self.path.clone_path())),
-        }?;
+        };
+        match res {
+            Err(BackupError::NoList(_)) if self.legacy_layout.is_none() => self.scan_meta()?,
+            res => res?,
+        }
         Ok(self.list.as_ref().unwrap())
     }
 
@@ -390,13 +1679,24 @@ impl BackupReader {
     }
 
     /// Get the embedded list of files
-    #[allow(unused)]
     pub fn get_list(&mut self) -> Result<&FileListString, BackupError> {
         if self.list.is_none() {
-            self.read_list()
-        } else {
-            Ok(self.list.as_ref().unwrap())
+            self.read_list()?;
         }
+        Ok(self.list.as_ref().unwrap())
+    }
+
+    /// The files in the list of the backup that no longer exist on disk (the files generated by
+    /// commands have no source file, so they are skipped)
+    pub fn find_missing(&mut self) -> Result<Vec<String>, BackupError> {
+        let list = self.get_list()?;
+        let generated: HashSet<&str> = list.iter_generated().collect();
+        Ok(list
+            .iter()
+            .map(|(_, s)| s)
+            .filter(|s| !generated.contains(s) && is_removed(s))
+            .map(String::from)
+            .collect())
     }
 
     /// move the list of files out of the backup
@@ -409,6 +1709,15 @@ impl BackupReader {
 
     /// Read the embedded config and file list
     pub fn read_meta(&mut self) -> Result<(&Config, &FileListString), BackupError> {
+        match self.read_meta_entries() {
+            Err(BackupError::NoConfig(_) | BackupError::NoList(_)) => self.scan_meta()?,
+            res => res?,
+        }
+        Ok((self.config.as_ref().unwrap(), self.list.as_ref().unwrap()))
+    }
+
+    /// Read the config and the file list from the first two entries
+    fn read_meta_entries(&mut self) -> Result<(), BackupError> {
         let mut decoder = self.get_decoder()?;
         let mut entries = decoder.entries().map_err(BackupError::ArchiveError)?;
         // Read Config
@@ -420,43 +1729,162 @@ impl BackupReader {
         match entries.next() {
             Some(entry) => self.parse_list(entry.map_err(BackupError::ArchiveError)?),
             None => Err(BackupError::NoList(self.path.clone_path())),
-        }?;
-        // Rest
-        Ok((self.config.as_ref().unwrap(), self.list.as_ref().unwrap()))
+        }
     }
 
     /// Get the embedded list of files
     pub fn get_meta(&mut self) -> Result<(&Config, &FileListString), BackupError> {
         if self.config.is_none() || self.list.is_none() {
-            self.read_meta()
-        } else {
-            Ok((self.config.as_mut().unwrap(), self.list.as_ref().unwrap()))
+            self.read_meta()?;
+        }
+        Ok((self.config.as_ref().unwrap(), self.list.as_ref().unwrap()))
+    }
+
+    /// Read the block signatures (see [`SignatureList`]) from the third entry
+    /// (the list is empty if the backup was made without `delta_files` in the config)
+    pub fn read_signatures(&self) -> Result<SignatureList, BackupError> {
+        let mut decoder = self.get_decoder()?;
+        let mut entries = decoder.entries().map_err(BackupError::ArchiveError)?;
+        match entries.nth(2) {
+            Some(entry) if is_signatures(&entry) => {
+                let (_, mut entry) = entry.map_err(BackupError::ArchiveError)?;
+                let mut content = String::new();
+                entry
+                    .read_to_string(&mut content)
+                    .map_err(BackupError::ArchiveError)?;
+                Ok(SignatureList::parse(&content))
+            }
+            Some(Err(e)) => Err(BackupError::ArchiveError(e)),
+            _ => Ok(SignatureList::default()),
         }
     }
 
+    /// Cheaply check that the backup is readable: the config and file list can be parsed
+    /// and the first stored file is the first included file in the list
+    pub fn quick_check(&mut self) -> Result<(), BackupError> {
+        let mut decoder = self.get_decoder()?;
+        let mut entries = decoder.entries().map_err(BackupError::ArchiveError)?;
+        let res = match entries.next() {
+            Some(entry) => self.parse_config(entry.map_err(BackupError::ArchiveError)?),
+            None => Err(BackupError::NoConfig(self.path.clone_path())),
+        }
+        .and_then(|_| match entries.next() {
+            Some(entry) => self.parse_list(entry.map_err(BackupError::ArchiveError)?),
+            None => Err(BackupError::NoList(self.path.clone_path())),
+        });
+        match res {
+            Ok(_) => self.check_first_entry(entries.filter(|e| !is_signatures(e))),
+            Err(BackupError::NoConfig(_) | BackupError::NoList(_)) => {
+                std::mem::drop(entries);
+                self.scan_meta()?;
+                let mut decoder = self.get_decoder()?;
+                let entries = decoder.entries().map_err(BackupError::ArchiveError)?;
+                self.check_first_entry(skip_meta(entries, self.meta_positions()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check that the first of the files is the first included file in the list
+    fn check_first_entry<'a, 'b: 'a>(
+        &self,
+        mut entries: impl Iterator<Item = std::io::Result<CompressionDecoderEntry<'a, 'b>>>,
+    ) -> Result<(), BackupError> {
+        let first = self.list.as_ref().unwrap().iter_included().next();
+        if let Some(first) = first {
+            let (mut fi, _) = entries
+                .next()
+                .ok_or_else(|| BackupError::MissingEntry(first.to_string()))?
+                .map_err(BackupError::ArchiveError)?;
+            if fi.get_string() != first {
+                return Err(BackupError::MissingEntry(first.to_string()));
+            }
+        }
+        Ok(())
+    }
+
     /// Is this an incemental backup
     pub fn check_incremental(&mut self) -> Result<bool, BackupError> {
         Ok(self.get_config()?.incremental)
     }
 
-    /// Try to find the previous backup
+    /// The files that have been deleted before this backup (see [`FileListString::iter_deleted`]),
+    /// also in the previous backups of the chain (if `chain`), except the files in this backup
+    pub fn get_deleted(&mut self, chain: bool) -> Result<Vec<String>, BackupError> {
+        let mut deleted: Vec<String> = self.get_list()?.iter_deleted().map(String::from).collect();
+        let mut previous = if chain { self.get_previous()? } else { None };
+        while let Some(mut reader) = previous {
+            deleted.extend(reader.get_list()?.iter_deleted().map(String::from));
+            previous = reader.get_previous()?;
+        }
+        let current: HashSet<&str> = self.get_list()?.iter().map(|(_, s)| s).collect();
+        deleted.retain(|s| !current.contains(s.as_str()));
+        sort_paths(&mut deleted);
+        deleted.dedup();
+        Ok(deleted)
+    }
+
+    /// Try to find the previous backup (in the same directory as this backup, unless following
+    /// the origin, see [`Self::with_follow_origin`])
     pub fn get_previous(&mut self) -> Result<Option<Self>, BackupError> {
         if !self.check_incremental()? {
             return Ok(None);
         }
-        match self
-            .config
-            .as_ref()
-            .unwrap()
-            .get_backups()
-            .get_previous(self.path.get_path())
-        {
-            None => Ok(None),
-            Some(path) => Ok(Some(BackupReader::new(path))),
+        if self.is_stream() {
+            log::warn!("The previous backups cannot be found for a backup from a stream");
+            return Ok(None);
         }
+        let path = self.path.get_path();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let config = self.config.as_ref().unwrap();
+        let previous = match config.get_backups_in(dir).get_previous(path) {
+            Some(prev) => {
+                log::info!(
+                    "Continuing with the previous backup '{}' (from '{}')",
+                    prev.to_string_lossy(),
+                    dir.to_string_lossy()
+                );
+                Some(prev)
+            }
+            None if self.follow_origin => {
+                // A symlinked output might point elsewhere now, so the directory that the backup
+                // was actually written to is searched first
+                let prev = config
+                    .resolved_output
+                    .as_ref()
+                    .filter(|d| d.is_dir())
+                    .and_then(|d| config.get_backups_in(d).get_previous(path))
+                    .or_else(|| {
+                        let current = config.get_resolved_output();
+                        if let (Some(recorded), Some(current)) = (&config.resolved_output, current)
+                        {
+                            if *recorded != current {
+                                log::warn!(
+                                    "The output location changed since the backup (from '{}' to '{}')",
+                                    recorded.to_string_lossy(),
+                                    current.to_string_lossy()
+                                );
+                            }
+                        }
+                        config.get_backups().get_previous(path)
+                    });
+                if let Some(prev) = &prev {
+                    log::warn!(
+                        "Continuing with the previous backup '{}' from the original directory '{}'",
+                        prev.to_string_lossy(),
+                        prev.parent().unwrap_or(Path::new("")).to_string_lossy()
+                    );
+                }
+                prev
+            }
+            None => None,
+        };
+        Ok(previous.map(|p| BackupReader::new(p).with_follow_origin(self.follow_origin)))
     }
 
-    #[allow(unused)]
     pub fn export_list<P: AsRef<Path>>(&mut self, path: P) -> Result<(), BackupError> {
         let mut f = File::create(path).map_err(BackupError::FileError)?;
         f.write_all(self.get_list()?.as_ref())
@@ -464,6 +1892,11 @@ impl BackupReader {
         Ok(())
     }
 
+    /// Restore all files stored in this backup
+    #[deprecated(
+        since = "2.3.0",
+        note = "use `restore_with` and `RestoreOptions` instead"
+    )]
     #[allow(unused)]
     pub fn restore_this(
         &mut self,
@@ -471,13 +1904,20 @@ impl BackupReader {
         callback: impl FnMut(std::io::Result<FileInfo>) -> Result<(), BackupError>,
         overwrite: bool,
     ) -> Result<(), BackupError> {
-        let list = self.move_list()?;
-        let selection = list.iter().map(|v| v.1).collect();
-        let res = self.restore(selection, path_transform, callback, overwrite, false);
-        self.list = Some(list);
-        res
+        let selection: Vec<String> = self.get_list()?.iter().map(|v| v.1.to_string()).collect();
+        let options = RestoreOptions {
+            overwrite: Overwrite::from_flags(overwrite, false),
+            only_this: true,
+            ..Default::default()
+        };
+        self.restore_with(selection, path_transform, callback, |_| (), &options)
     }
 
+    /// Restore all files (also the unchanged files from the previous backups)
+    #[deprecated(
+        since = "2.3.0",
+        note = "use `restore_with` and `RestoreOptions` instead"
+    )]
     #[allow(unused)]
     pub fn restore_all(
         &mut self,
@@ -485,40 +1925,259 @@ impl BackupReader {
         callback: impl FnMut(std::io::Result<FileInfo>) -> Result<(), BackupError>,
         overwrite: bool,
     ) -> Result<(), BackupError> {
-        let list = self.move_list()?;
-        let selection = list.iter().map(|v| v.1).collect();
-        let res = self.restore(selection, path_transform, callback, overwrite, true);
-        self.list = Some(list);
-        res
+        let selection: Vec<String> = self.get_list()?.iter().map(|v| v.1.to_string()).collect();
+        let options = RestoreOptions {
+            overwrite: Overwrite::from_flags(overwrite, false),
+            ..Default::default()
+        };
+        self.restore_with(selection, path_transform, callback, |_| (), &options)
+    }
+
+    /// Replace the selected paths that are not files in the list with the files under them
+    /// (as directories, `None` if every path is in the list). With `only_this` the directories
+    /// only contain the files stored in this backup.
+    pub fn expand_selection<S: AsRef<str>>(
+        &mut self,
+        selection: &[S],
+        only_this: bool,
+    ) -> Result<Option<Vec<String>>, BackupError> {
+        let list = self.get_list()?;
+        let listed: HashSet<&str> = list.iter().map(|(_, s)| s).collect();
+        let dirs: Vec<String> = selection
+            .iter()
+            .map(|s| s.as_ref())
+            .filter(|s| !listed.contains(s))
+            .map(|s| s.replace('\\', "/"))
+            .filter(|s| !listed.contains(s.as_str()))
+            .collect();
+        if dirs.is_empty() {
+            return Ok(None);
+        }
+        let mut expanded: Vec<String> = selection
+            .iter()
+            .map(|s| s.as_ref())
+            .filter(|s| listed.contains(s))
+            .map(String::from)
+            .collect();
+        for dir in dirs {
+            let files = if only_this {
+                list.iter_included()
+            } else {
+                Box::new(list.iter().map(|(_, s)| s))
+            };
+            let len = expanded.len();
+            expanded.extend(
+                files
+                    .filter(|f| strip_path_prefix(f, &dir).is_some())
+                    .map(String::from),
+            );
+            // Nothing matches, so the path is reported as missing
+            if expanded.len() == len {
+                expanded.push(dir);
+            }
+        }
+        sort_paths(&mut expanded);
+        Ok(Some(expanded))
     }
 
-    /// Restore specific files
+    /// Restore specific files, or directories (see [`Self::expand_selection`])
     pub fn restore<S: AsRef<str>>(
+        &mut self,
+        selection: Vec<S>,
+        path_transform: impl FnMut(FileInfo) -> FileInfo,
+        callback: impl FnMut(std::io::Result<FileInfo>) -> Result<(), BackupError>,
+        overwrite: Overwrite,
+        recursive: bool,
+    ) -> Result<(), BackupError> {
+        self.restore_expanded(
+            selection,
+            path_transform,
+            callback,
+            |_| (),
+            overwrite,
+            recursive,
+        )
+    }
+
+    fn restore_expanded<S: AsRef<str>>(
+        &mut self,
+        selection: Vec<S>,
+        path_transform: impl FnMut(FileInfo) -> FileInfo,
+        callback: impl FnMut(std::io::Result<FileInfo>) -> Result<(), BackupError>,
+        on_progress: impl FnMut(u64),
+        overwrite: Overwrite,
+        recursive: bool,
+    ) -> Result<(), BackupError> {
+        if self.is_stream() && self.list.is_none() {
+            // The list of a stream cannot be read again (after it has been moved out)
+            return self.restore_files(
+                selection,
+                path_transform,
+                callback,
+                on_progress,
+                overwrite,
+                recursive,
+            );
+        }
+        match self.expand_selection(&selection, !recursive)? {
+            Some(expanded) => self.restore_files(
+                expanded,
+                path_transform,
+                callback,
+                on_progress,
+                overwrite,
+                recursive,
+            ),
+            None => self.restore_files(
+                selection,
+                path_transform,
+                callback,
+                on_progress,
+                overwrite,
+                recursive,
+            ),
+        }
+    }
+
+    /// Restore specific files (without expanding directories)
+    fn restore_files<S: AsRef<str>>(
         &mut self,
         selection: Vec<S>,
         mut path_transform: impl FnMut(FileInfo) -> FileInfo,
         mut callback: impl FnMut(std::io::Result<FileInfo>) -> Result<(), BackupError>,
-        overwrite: bool,
+        mut on_progress: impl FnMut(u64),
+        overwrite: Overwrite,
+        recursive: bool,
+    ) -> Result<(), BackupError> {
+        let start = Instant::now();
+        log::info!(
+            "Restoring {} paths from '{}'",
+            selection.len(),
+            self.path.get_string()
+        );
+        self.restore_selection(
+            selection,
+            &mut path_transform,
+            &mut callback,
+            &mut on_progress,
+            overwrite,
+            recursive,
+        )
+        .inspect(|_| log::info!("Restored the files in {:.1?}", start.elapsed()))
+        .inspect_err(|e| match e {
+            BackupError::Cancel => log::info!("The restore was cancelled"),
+            e => log::info!("The restore failed: {}", e),
+        })
+    }
+
+    /// Restore specific files, or directories (using the `overwrite`, `only_this` and `cancel`
+    /// from the options, see [`Self::restore`]).
+    /// `on_progress` is called with the number of newly unpacked bytes (while the files are
+    /// written). Hard links and duplicates unpack nothing, and files stored as changed blocks
+    /// only unpack the blocks (see [`DeltaManifest`]).
+    pub fn restore_with<S: AsRef<str>>(
+        &mut self,
+        selection: Vec<S>,
+        path_transform: impl FnMut(FileInfo) -> FileInfo,
+        mut callback: impl FnMut(std::io::Result<FileInfo>) -> Result<(), BackupError>,
+        on_progress: impl FnMut(u64),
+        options: &RestoreOptions,
+    ) -> Result<(), BackupError> {
+        self.restore_expanded(
+            selection,
+            path_transform,
+            |res| {
+                callback(res)?;
+                check_cancel(options.cancel)
+            },
+            on_progress,
+            options.overwrite,
+            !options.only_this,
+        )
+    }
+
+    fn restore_selection<S: AsRef<str>>(
+        &mut self,
+        selection: Vec<S>,
+        path_transform: &mut dyn FnMut(FileInfo) -> FileInfo,
+        callback: &mut dyn FnMut(std::io::Result<FileInfo>) -> Result<(), BackupError>,
+        on_progress: &mut dyn FnMut(u64),
+        overwrite: Overwrite,
         recursive: bool,
     ) -> Result<(), BackupError> {
         if selection.is_empty() {
             return Ok(());
         }
+        // Selections from other platforms might use `\` (but the archives always use `/`)
+        let normalise = selection.iter().any(|v| v.as_ref().contains('\\'));
+        let normalised: Vec<String> = if normalise {
+            selection
+                .iter()
+                .map(|v| v.as_ref().replace('\\', "/"))
+                .collect()
+        } else {
+            vec![]
+        };
+        let mut selection: Vec<&str> = if normalise {
+            normalised.iter().map(String::as_str).collect()
+        } else {
+            selection.iter().map(|v| v.as_ref()).collect()
+        };
+        // The archive is only read forward, so the selection must be in the same order
+        sort_paths(&mut selection);
         let mut not_found: Vec<&str> = vec![];
+        let mut links: Vec<DeferredLink> = vec![];
+        let mut deltas: Vec<PendingDelta> = vec![];
+        let mut failed: HashSet<PathBuf> = HashSet::new();
+        let xattrs = self.restored_xattrs();
+        let mut rescanned = None;
         let mut decoder = self.get_decoder()?;
         let mut entries = decoder.entries().map_err(BackupError::ArchiveError)?;
-        let unsorted = match entries.nth(1) {
-            Some(r) => r?.0.get_string() == "files.csv",
-            None => return Err(BackupError::NoList(self.path.clone_path())),
+        // The file list is the second entry (except in some archives from old versions)
+        let list_entry = match self.legacy_layout {
+            Some(_) => None,
+            None => match entries.nth(1) {
+                Some(r) => Some(r?).filter(|e| {
+                    e.1.path()
+                        .is_ok_and(|p| FileListString::version_of(&p.to_string_lossy()).is_some())
+                }),
+                None => return Err(BackupError::NoList(self.path.clone_path())),
+            },
+        };
+        // The list is elsewhere, so the archive is scanned for it (and read again)
+        let rescan = self.legacy_layout.is_none() && list_entry.is_none();
+        let (unsorted, updates) = match list_entry {
+            // Windows backups from before the case insensitive ordering might not be sorted as expected
+            Some(mut entry) => {
+                let unsorted =
+                    entry.0.get_string() == "files.csv" || cfg!(target_os = "windows") || normalise;
+                (unsorted, selected_updates(entry, &selection)?)
+            }
+            // The files in archives from old versions are found by name (in any order)
+            None => (true, vec![]),
         };
-        let mut list = selection.iter().map(|v| v.as_ref());
+        let entries: Box<dyn Iterator<Item = std::io::Result<CompressionDecoderEntry>> + '_> =
+            match self.legacy_layout {
+                Some(meta) => Box::new(skip_meta(entries, meta)),
+                None if rescan => {
+                    self.scan_meta()?;
+                    let decoder = rescanned.insert(self.get_decoder()?);
+                    Box::new(skip_meta(
+                        decoder.entries().map_err(BackupError::ArchiveError)?,
+                        self.meta_positions(),
+                    ))
+                }
+                None => Box::new(entries.filter(|e| !is_signatures(e))),
+            };
+        let mut list = selection.iter().copied();
         let mut current = if unsorted {
-            not_found.extend(selection.iter().map(|v| v.as_ref()));
+            not_found.extend(selection.iter().copied());
             not_found.sort_unstable();
             ""
         } else {
             list.next().unwrap_or("")
         };
+        let mut finished = false;
         'decoder: for res in entries {
             match res {
                 Ok((mut fi, mut entry)) => {
@@ -526,7 +2185,12 @@ impl BackupReader {
                         // Unsorted is needed to be able to extract files from some old
                         // simple_backup backups, where the files were not properly sorted.
                         let fis = fi.get_string().as_str();
-                        if let Ok(i) = not_found.binary_search(&fis) {
+                        let fis = if normalise {
+                            Cow::Owned(fis.replace('\\', "/"))
+                        } else {
+                            Cow::Borrowed(fis)
+                        };
+                        if let Ok(i) = not_found.binary_search(&fis.as_ref()) {
                             not_found.remove(i);
                             true
                         } else {
@@ -551,25 +2215,70 @@ impl BackupReader {
                             }
                             current = match list.next() {
                                 Some(f) => f,
-                                None => break 'decoder,
+                                None => {
+                                    finished = true;
+                                    break 'decoder;
+                                }
                             };
                         }
                         fi.get_string() == current
                     };
                     if restore {
+                        let delta = delta_manifest(&mut entry).map(|m| (fi.copy_string(), m));
+                        let delta = delta.map(|(name, m)| (name.to_string(), m));
                         let mut path = path_transform(fi);
-                        if !overwrite && path.get_path().exists() {
-                            callback(Err(std::io::Error::new(
-                                std::io::ErrorKind::AlreadyExists,
-                                format!("File '{}' already exists.", path.get_string()),
-                            )))?;
-                        } else if let Some(dir) = path.get_path().parent() {
-                            callback(
-                                create_dir_all(dir)
-                                    .and_then(|_| entry.unpack(path.get_path()).and(Ok(path))),
-                            )?;
+                        // The restored file gets the modification time from the backup
+                        path.time = entry
+                            .header()
+                            .mtime()
+                            .ok()
+                            .map(|t| system_to_naive(UNIX_EPOCH + Duration::from_secs(t)));
+                        if entry.header().entry_type().is_dir() {
+                            // Empty directories (an existing directory is not a conflict)
+                            callback(create_dir_all(long_path(path.get_path())).and(Ok(path)))?;
+                        } else if let Some(err) =
+                            overwrite.conflict(&mut path, entry.header().mtime().ok())
+                        {
+                            failed.insert(path.consume_path());
+                            callback(Err(err))?;
+                        } else if let Some((name, manifest)) = delta {
+                            // The blocks are written over the previous version (after this archive)
+                            let blocks = extend_pathbuf(
+                                path.clone_path(),
+                                format!(".{:x}.tmp", manifest.digest),
+                            );
+                            match unpack_entry(&mut entry, &blocks, None, on_progress) {
+                                Ok(_) => deltas.push(PendingDelta {
+                                    name,
+                                    path,
+                                    blocks,
+                                    manifest,
+                                }),
+                                Err(e) => {
+                                    failed.insert(path.consume_path());
+                                    callback(Err(e))?;
+                                }
+                            }
+                        } else if let Some(target) = hard_link_target(&entry) {
+                            // Hard links are restored last (when the targets have been restored)
+                            let copy = is_duplicate(&mut entry).then(|| {
+                                UNIX_EPOCH
+                                    + Duration::from_secs(entry.header().mtime().unwrap_or(0))
+                            });
+                            links.push((target, path, copy));
                         } else {
-                            callback(entry.unpack(path.get_path()).and(Ok(path)))?;
+                            match unpack_entry(
+                                &mut entry,
+                                path.get_path(),
+                                xattrs.as_ref(),
+                                on_progress,
+                            ) {
+                                Ok(_) => callback(Ok(path))?,
+                                Err(e) => {
+                                    failed.insert(path.consume_path());
+                                    callback(Err(e))?;
+                                }
+                            }
                         }
                         if unsorted {
                             if not_found.is_empty() {
@@ -578,7 +2287,10 @@ impl BackupReader {
                         } else {
                             current = match list.next() {
                                 Some(s) => s,
-                                None => break 'decoder,
+                                None => {
+                                    finished = true;
+                                    break 'decoder;
+                                }
                             };
                         }
                     }
@@ -586,10 +2298,64 @@ impl BackupReader {
                 Err(e) => callback(Err(e))?,
             }
         }
+        if !unsorted && !finished {
+            // The rest of the selection comes after the last file in the backup
+            not_found.push(current);
+            not_found.extend(list);
+        }
+        if !deltas.is_empty() {
+            self.apply_deltas(deltas, &mut failed, callback, on_progress)?;
+        }
+        if !links.is_empty() {
+            let selected: HashSet<&str> = selection.iter().copied().collect();
+            let mut unresolved: Vec<DeferredLink> = vec![];
+            for (mut target, mut path, copy) in links {
+                let name = target.get_string().as_str();
+                let name = if normalise {
+                    Cow::Owned(name.replace('\\', "/"))
+                } else {
+                    Cow::Borrowed(name)
+                };
+                if selected.contains(name.as_ref()) {
+                    let mut restored = path_transform(target.clone());
+                    if !failed.contains(restored.get_path())
+                        && link_file(restored.get_path(), path.get_path(), copy).is_ok()
+                    {
+                        callback(Ok(path))?;
+                        continue;
+                    }
+                }
+                unresolved.push((target, path, copy));
+            }
+            if !unresolved.is_empty() {
+                self.restore_link_targets(unresolved, callback, on_progress)?;
+            }
+        }
         if !not_found.is_empty() {
             if recursive {
                 if let Some(mut bw) = self.get_previous()? {
-                    return bw.restore(not_found, path_transform, callback, overwrite, recursive);
+                    // The files with updated metadata are restored from the previous backups first
+                    let updates: Vec<(FileInfo, MetadataUpdate)> = updates
+                        .into_iter()
+                        .map(|(fi, update)| (path_transform(fi), update))
+                        .collect();
+                    bw.restore_selection(
+                        not_found,
+                        path_transform,
+                        callback,
+                        on_progress,
+                        overwrite,
+                        recursive,
+                    )?;
+                    for (mut path, update) in updates {
+                        let path = long_path(path.get_path());
+                        if path.exists() {
+                            if let Err(e) = update.apply(&path) {
+                                callback(Err(e))?;
+                            }
+                        }
+                    }
+                    return Ok(());
                 }
             }
             for f in not_found.iter() {
@@ -605,6 +2371,318 @@ impl BackupReader {
         }
         Ok(())
     }
+
+    /// Restore the files that are stored as changed blocks (see [`DeltaManifest`]), by restoring
+    /// the previous versions (from the previous backups) and writing the blocks over them
+    fn apply_deltas(
+        &mut self,
+        deltas: Vec<PendingDelta>,
+        failed: &mut HashSet<PathBuf>,
+        callback: &mut dyn FnMut(std::io::Result<FileInfo>) -> Result<(), BackupError>,
+        on_progress: &mut dyn FnMut(u64),
+    ) -> Result<(), BackupError> {
+        match self.get_previous()? {
+            Some(mut prev) => {
+                let targets: HashMap<&str, &FileInfo> =
+                    deltas.iter().map(|d| (d.name.as_str(), &d.path)).collect();
+                let names: Vec<&str> = targets.keys().copied().collect();
+                // Any problems are noticed (and reported) when the blocks are applied
+                prev.restore_files(
+                    names,
+                    |mut fi| {
+                        let path = targets.get(fi.get_string().as_str());
+                        FileInfo::from(path.map(|p| p.clone_path()).unwrap_or_default())
+                    },
+                    |res| {
+                        if let Err(e) = res {
+                            log::warn!("Could not restore a previous version: {}", e);
+                        }
+                        Ok(())
+                    },
+                    &mut *on_progress,
+                    Overwrite::Always,
+                    true,
+                )?;
+            }
+            None => log::warn!(
+                "The previous backup is missing (for the files stored as changed blocks in '{}')",
+                self.path.get_string()
+            ),
+        }
+        for mut delta in deltas {
+            let res = apply_delta(delta.path.get_path(), &delta.blocks, &delta.manifest);
+            #[allow(unused_must_use)]
+            {
+                std::fs::remove_file(long_path(&delta.blocks));
+            }
+            match res {
+                Ok(_) => callback(Ok(delta.path))?,
+                Err(e) => {
+                    log::warn!("Could not restore the file '{}': {}", delta.name, e);
+                    failed.insert(delta.path.consume_path());
+                    callback(Err(e))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The policy for restoring extended attributes (`None` when they are not restored)
+    fn restored_xattrs(&self) -> Option<MacosXattrs> {
+        STORE_XATTRS.then(|| {
+            self.config
+                .as_ref()
+                .map(|c| c.macos_xattrs)
+                .unwrap_or_default()
+        })
+    }
+
+    /// Restore hard links whose targets have not been restored, by extracting the targets again
+    fn restore_link_targets(
+        &self,
+        links: Vec<DeferredLink>,
+        callback: &mut dyn FnMut(std::io::Result<FileInfo>) -> Result<(), BackupError>,
+        on_progress: &mut dyn FnMut(u64),
+    ) -> Result<(), BackupError> {
+        let mut targets: HashMap<String, Vec<(FileInfo, Option<SystemTime>)>> = HashMap::new();
+        for (mut target, path, copy) in links {
+            targets
+                .entry(target.move_string())
+                .or_default()
+                .push((path, copy));
+        }
+        let xattrs = self.restored_xattrs();
+        let mut decoder = self.get_decoder()?;
+        let entries = decoder.entries().map_err(BackupError::ArchiveError)?;
+        for res in skip_meta(entries, self.meta_positions()) {
+            let (mut fi, mut entry) = res?;
+            let paths = match targets.remove(fi.get_string()) {
+                Some(paths) => paths,
+                None => continue,
+            };
+            let mut paths = paths.into_iter();
+            let (mut first, copy) = paths.next().unwrap();
+            let res = unpack_entry(&mut entry, first.get_path(), xattrs.as_ref(), on_progress)
+                .and_then(|_| match copy {
+                    Some(time) => set_modified(first.get_path(), time),
+                    None => Ok(()),
+                });
+            let linked = match res {
+                Ok(_) => {
+                    let linked = first.clone_path();
+                    callback(Ok(first))?;
+                    Some(linked)
+                }
+                Err(e) => {
+                    callback(Err(e))?;
+                    None
+                }
+            };
+            for (mut path, copy) in paths {
+                match &linked {
+                    Some(l) => {
+                        callback(link_file(l, path.get_path(), copy).and(Ok(path)))?;
+                    }
+                    None => callback(Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("Could not restore the hard link '{}'.", path.get_string()),
+                    )))?,
+                }
+            }
+            if targets.is_empty() {
+                break;
+            }
+        }
+        for (target, paths) in targets {
+            for (mut path, _) in paths {
+                callback(Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!(
+                        "Could not find '{}' (the target of the hard link '{}') in backup '{}'.",
+                        target,
+                        path.get_string(),
+                        self.path.copy_string()
+                    ),
+                )))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A hard link to restore: the target, the path, and the modification time if it is a duplicate
+/// (duplicates are restored as copies instead of links)
+type DeferredLink = (FileInfo, FileInfo, Option<SystemTime>);
+
+/// A file stored as changed blocks (unpacked to `blocks`) that is restored after the other files
+struct PendingDelta {
+    /// The name in the archive
+    name: String,
+    path: FileInfo,
+    blocks: PathBuf,
+    manifest: DeltaManifest,
+}
+
+/// Read the metadata updates for the selected files from the list entry
+fn selected_updates(
+    mut entry: CompressionDecoderEntry,
+    selection: &[&str],
+) -> Result<Vec<(FileInfo, MetadataUpdate)>, BackupError> {
+    let mut content = String::new();
+    entry
+        .1
+        .read_to_string(&mut content)
+        .map_err(BackupError::ArchiveError)?;
+    if !content.starts_with('m') && !content.contains("\nm") {
+        return Ok(vec![]);
+    }
+    let list = match FileListString::new(entry.0.get_string(), content) {
+        Ok(list) => list,
+        Err(_) => return Ok(vec![]),
+    };
+    let selected: HashSet<&str> = selection.iter().copied().collect();
+    Ok(list
+        .iter_updates()
+        .filter(|(_, s)| selected.contains(s))
+        .map(|(update, s)| (FileInfo::from(s), update))
+        .collect())
+}
+
+/// Skip the config and the file list (at the given positions), and the block signatures, among
+/// the entries of an archive
+fn skip_meta<'a, 'b: 'a>(
+    entries: impl Iterator<Item = std::io::Result<CompressionDecoderEntry<'a, 'b>>>,
+    meta: [usize; 2],
+) -> impl Iterator<Item = std::io::Result<CompressionDecoderEntry<'a, 'b>>> {
+    entries
+        .enumerate()
+        .filter(move |(i, e)| !meta.contains(i) && !is_signatures(e))
+        .map(|(_, e)| e)
+}
+
+/// Is the entry the block signatures (see [`SIGNATURES_NAME`]), which is recognised by the stored
+/// name (the stored files always have a prefix, see [`crate::compression`])
+fn is_signatures(entry: &std::io::Result<CompressionDecoderEntry>) -> bool {
+    entry
+        .as_ref()
+        .is_ok_and(|(_, e)| e.path().is_ok_and(|p| p.as_os_str() == SIGNATURES_NAME))
+}
+
+/// Stop with [`BackupError::Cancel`] if the flag has been set
+fn check_cancel(cancel: Option<&AtomicBool>) -> Result<(), BackupError> {
+    match cancel {
+        Some(c) if c.load(Ordering::Relaxed) => Err(BackupError::Cancel),
+        _ => Ok(()),
+    }
+}
+
+/// Unpack an entry from an archive (creating the parent directories)
+/// and restore the extended attributes allowed by the policy
+/// (`on_progress` is called with the number of newly unpacked bytes)
+fn unpack_entry<R: Read>(
+    entry: &mut tar::Entry<'_, R>,
+    path: &Path,
+    xattrs: Option<&MacosXattrs>,
+    on_progress: &mut dyn FnMut(u64),
+) -> std::io::Result<()> {
+    let path = long_path(path);
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    let attrs = xattrs
+        .map(|policy| policy.restored(entry_xattrs(entry)))
+        .unwrap_or_default();
+    unpack_with_progress(entry, &path, on_progress)?;
+    if !attrs.is_empty() {
+        // The content is restored even if the file system does not support the attributes
+        if let Err(e) = xattrs::write(&path, &attrs) {
+            log::warn!(
+                "Could not restore the extended attributes of '{}': {}",
+                path.to_string_lossy(),
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Write the changed blocks (unpacked to `blocks`) over the previous version of the file at `path`,
+/// checking that both the previous and the restored versions match the [`DeltaManifest`]
+fn apply_delta(path: &Path, blocks: &Path, manifest: &DeltaManifest) -> std::io::Result<()> {
+    let path = long_path(path);
+    let base = BlockSignature::read(&path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "The previous version of '{}' is missing (only the changed blocks are in the backup): {}",
+                path.to_string_lossy(),
+                e
+            ),
+        )
+    })?;
+    if base.digest() != manifest.base {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "The previous version of '{}' does not match the changed blocks in the backup",
+                path.to_string_lossy()
+            ),
+        ));
+    }
+    let blocks = long_path(blocks);
+    let meta = std::fs::metadata(&blocks)?;
+    let mut source = File::open(&blocks)?;
+    let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    for &i in manifest.blocks.iter() {
+        let len = manifest
+            .size
+            .saturating_sub(i * DELTA_BLOCK_SIZE)
+            .min(DELTA_BLOCK_SIZE);
+        file.seek(SeekFrom::Start(i * DELTA_BLOCK_SIZE))?;
+        if std::io::copy(&mut (&mut source).take(len), &mut file)? != len {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    file.set_len(manifest.size)?;
+    std::mem::drop(file);
+    if BlockSignature::read(&path)?.digest() != manifest.digest {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "The restored '{}' does not match the backup",
+                path.to_string_lossy()
+            ),
+        ));
+    }
+    // The blocks were unpacked with the permissions and modification time from the backup
+    std::fs::set_permissions(&path, meta.permissions())?;
+    set_modified(&path, meta.modified()?)
+}
+
+/// Create a hard link, or a copy for a duplicate (replacing any existing file)
+fn link_file(target: &Path, path: &Path, copy: Option<SystemTime>) -> std::io::Result<()> {
+    let (target, path) = (long_path(target), long_path(path));
+    let (target, path) = (target.as_ref(), path.as_ref());
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    match copy {
+        None => std::fs::hard_link(target, path),
+        Some(time) => {
+            std::fs::copy(target, path)?;
+            set_modified(path, time)
+        }
+    }
+}
+
+fn set_modified(path: &Path, time: SystemTime) -> std::io::Result<()> {
+    File::options()
+        .write(true)
+        .open(long_path(path))?
+        .set_modified(time)
 }
 
 impl std::fmt::Debug for BackupReader {
@@ -615,20 +2693,59 @@ impl std::fmt::Debug for BackupReader {
     }
 }
 
+/// The merged backup must replace the newest merged backup if there are newer incremental backups
+/// that depend on it (otherwise there would be a gap in the chain of incremental backups).
+/// The readers should be sorted with the newest first.
+fn check_merge_output(readers: &[BackupReader], path: &Path) -> Result<(), BackupError> {
+    let newest = readers.first().unwrap().path.clone_path();
+    if newest == path {
+        return Ok(());
+    }
+    let dir = match newest.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    if let Some(next) = BackupIterator::dir(dir).get_next(&newest) {
+        if BackupReader::read_config_only(next.clone()).is_ok_and(|c| c.incremental) {
+            return Err(BackupError::InvalidConfig(format!(
+                "The incremental backup '{}' depends on '{}', so the merged backup must replace it \
+                 (without a different output path)",
+                next.to_string_lossy(),
+                newest.to_string_lossy()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// A file copied by the merger (and the result of writing it)
+type CopiedEntry = (FileInfo, Result<(), BackupError>);
+
 pub struct BackupMerger {
     pub path: PathBuf,
     tmp_path: PathBuf,
+    /// Where the merged backups are moved to (unless they are deleted)
+    old_paths: Vec<PathBuf>,
     readers: Vec<BackupReader>,
     pub files: FileListVec,
+    /// The files that were deleted in any of the merged backups (and not added again)
+    deleted: Vec<String>,
     delete: bool,
     overwrite: bool,
     quality: Option<i32>,
     threads: Option<u32>,
+    strict: bool,
+    /// Write a journal before renaming the backups
+    pub journal: bool,
 }
 
 impl BackupMerger {
     /// Create a new backup merger.
     /// The merged backup can either contain only files mentioned in the latest backup, or all files from all backups.
+    /// In strict mode files that are listed, but missing from all the archives, are dropped from the merged list.
+    /// If newer incremental backups depend on the newest merged backup, then the merged backup must replace it
+    /// (the `path` must be `None` or the path of the newest backup).
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         path: Option<PathBuf>,
         mut readers: Vec<BackupReader>,
@@ -637,6 +2754,7 @@ impl BackupMerger {
         overwrite: bool,
         quality: Option<i32>,
         threads: Option<u32>,
+        strict: bool,
     ) -> Result<Self, (Vec<BackupReader>, BackupError)> {
         if readers.len() < 2 {
             return Err((
@@ -646,6 +2764,15 @@ impl BackupMerger {
         }
         readers.sort_by(|a, b| a.path.cmp(&b.path));
         readers.dedup_by(|a, b| b.path == a.path);
+        if readers
+            .iter()
+            .any(|r| is_multi_volume(r.path.copy_path().as_path()))
+        {
+            return Err((
+                readers,
+                BackupError::GenericError("Merging multi-volume backups is not yet supported"),
+            ));
+        }
         let err = readers
             .iter_mut()
             .try_for_each(|r| r.get_meta().map(|_| ()));
@@ -661,6 +2788,11 @@ impl BackupMerger {
         });
         readers.reverse();
 
+        if let Some(path) = &path {
+            if let Err(e) = check_merge_output(&readers, path) {
+                return Err((readers, e));
+            }
+        }
         let path = match path {
             Some(path) => path,
             None => readers.first().unwrap().path.clone_path(),
@@ -668,50 +2800,113 @@ impl BackupMerger {
 
         let mut files = FileListVec::default();
         {
+            let updates: Vec<HashMap<&str, MetadataUpdate>> = readers
+                .iter()
+                .map(|r| {
+                    r.list
+                        .as_ref()
+                        .unwrap()
+                        .iter_updates()
+                        .map(|(u, s)| (s, u))
+                        .collect()
+                })
+                .collect();
+            let generated: HashSet<&str> = readers
+                .iter()
+                .flat_map(|r| r.list.as_ref().unwrap().iter_generated())
+                .collect();
+            let user_meta: Vec<HashMap<&str, Cow<str>>> = readers
+                .iter()
+                .map(|r| r.list.as_ref().unwrap().iter_user_meta().collect())
+                .collect();
             let mut lists = readers
                 .iter()
-                .map(|r| Box::new(r.list.as_ref().unwrap().iter().peekable()))
+                .map(|r| {
+                    let list = r.list.as_ref().unwrap();
+                    if r.is_unsorted() {
+                        let mut files = list.iter_meta().collect::<Vec<_>>();
+                        files.sort_by(|a, b| cmp_path_str(a.3, b.3));
+                        Box::new(files.into_iter()) as Box<dyn Iterator<Item = _>>
+                    } else {
+                        list.iter_meta()
+                    }
+                    .peekable()
+                })
                 .collect::<Vec<_>>();
             loop {
                 let s = if all {
                     lists
                         .iter_mut()
                         .filter_map(|p| p.peek())
-                        .min()
-                        .map(|(_, s)| String::from(*s))
+                        .map(|(_, _, _, s)| *s)
+                        .min_by(|a, b| cmp_path_str(a, b))
+                        .map(String::from)
                 } else {
                     lists
                         .first_mut()
                         .unwrap()
                         .peek()
-                        .map(|(_, s)| String::from(*s))
+                        .map(|(_, _, _, s)| String::from(*s))
                 };
                 let mut inc = false;
                 match s {
                     None => break,
                     Some(s) => {
-                        for p in lists.iter_mut() {
-                            if let Some((b, s2)) = p.peek() {
-                                inc = inc || *b;
-                                if s.as_str() >= *s2 {
-                                    p.next();
+                        let mut fi = FileInfo::from(s);
+                        fi.generated = generated.contains(fi.copy_string().as_ref());
+                        let mut meta = None;
+                        for ((p, updates), user_meta) in
+                            lists.iter_mut().zip(updates.iter()).zip(user_meta.iter())
+                        {
+                            while let Some((b, size, hash, s2)) = p.peek() {
+                                match cmp_path_str(fi.copy_string().as_ref(), s2) {
+                                    std::cmp::Ordering::Less => break,
+                                    std::cmp::Ordering::Equal => {
+                                        // Keep the newest metadata (even if the content is older)
+                                        if !inc && fi.update.is_none() {
+                                            fi.update = updates.get(s2).copied();
+                                        }
+                                        if meta.is_none() {
+                                            fi.user_meta = user_meta.get(s2).map(|m| m.to_string());
+                                        }
+                                        inc = inc || *b;
+                                        meta = meta.or(Some((*size, *hash)));
+                                    }
+                                    std::cmp::Ordering::Greater => {}
                                 }
+                                p.next();
                             }
                         }
-                        files.push(inc, FileInfo::from(s));
+                        if let Some((size, hash)) = meta {
+                            fi.size = size.unwrap_or_default();
+                            fi.hash = hash;
+                        }
+                        files.push(inc, fi);
                     }
                 };
             }
         }
+        let mut deleted: Vec<String> = readers
+            .iter()
+            .flat_map(|r| r.list.as_ref().unwrap().iter_deleted())
+            .filter(|s| files.find(s).is_none())
+            .map(String::from)
+            .collect();
+        sort_paths(&mut deleted);
+        deleted.dedup();
         Ok(Self {
             path,
             tmp_path: PathBuf::new(),
+            old_paths: vec![],
             readers,
             files,
+            deleted,
             delete,
             overwrite,
             quality,
             threads,
+            strict,
+            journal: false,
         })
     }
 
@@ -720,29 +2915,62 @@ impl BackupMerger {
         self.readers
     }
 
-    /// Write (and compress) the backup to disk
+    /// Write the merged backup to another path (see [`Self::new`] for the restrictions)
+    #[allow(unused)]
+    pub fn set_output(&mut self, path: PathBuf) -> Result<(), BackupError> {
+        check_merge_output(&self.readers, &path)?;
+        self.path = path;
+        Ok(())
+    }
+
+    /// Write (and compress) the merged backup to disk, returning the sizes of the merged backup
+    /// (`on_progress` is called with the number of newly copied bytes)
     pub fn write(
         &mut self,
         on_added: impl FnMut(&mut FileInfo, Result<(), BackupError>) -> Result<(), BackupError>,
+        on_progress: impl FnMut(u64),
         on_final: impl FnOnce(),
-    ) -> Result<(), BackupError> {
+    ) -> Result<WriteStats, BackupError> {
         self.tmp_path = self.get_tmp_output();
-        self.write_internal(on_added, on_final).inspect_err(|_| {
-            // Clean up failed merge (allowed to fail without checking)
-            #[allow(unused_must_use)]
-            {
-                std::fs::remove_file(&self.tmp_path);
-                self.tmp_path.clear();
-            }
-        })?;
+        self.old_paths = self.get_old_paths();
+        let start = Instant::now();
+        log::info!(
+            "Merging {} backups into '{}'",
+            self.readers.len(),
+            self.tmp_path.to_string_lossy()
+        );
+        let mut stats = self
+            .write_internal(on_added, on_progress, on_final)
+            .inspect_err(|e| {
+                match e {
+                    BackupError::Cancel => log::info!("The merge was cancelled"),
+                    _ => log::info!("The merge failed"),
+                }
+                log::info!(
+                    "Removing the incomplete merge '{}'",
+                    self.tmp_path.to_string_lossy()
+                );
+                // Clean up failed merge (allowed to fail without checking)
+                #[allow(unused_must_use)]
+                {
+                    std::fs::remove_file(&self.tmp_path);
+                    self.tmp_path.clear();
+                }
+            })?;
+        log::info!("Compressed the merged backup in {:.1?}", start.elapsed());
         self.cleanup()
+            .inspect_err(|e| log::error!("Could not replace the merged backups: {}", e))?;
+        stats.compressed = archive_size(&self.path).ok();
+        stats.elapsed = start.elapsed();
+        Ok(stats)
     }
 
     fn write_internal(
         &mut self,
         mut on_added: impl FnMut(&mut FileInfo, Result<(), BackupError>) -> Result<(), BackupError>,
+        mut on_progress: impl FnMut(u64),
         on_final: impl FnOnce(),
-    ) -> Result<(), BackupError> {
+    ) -> Result<WriteStats, BackupError> {
         let config = self
             .readers
             .first_mut()
@@ -752,54 +2980,156 @@ impl BackupMerger {
             .expect("The config should already be read!");
         let quality = self.quality.unwrap_or(config.quality);
         let threads = self.threads.unwrap_or(config.threads);
+        let store_only = config.store_only.clone();
+        let tar_format = config.tar_format;
+        let delta_files = !config.delta_files.is_empty();
+        // Record the renamed backups (so that they can be attributed to this merge)
+        let mut config = config.clone();
+        config.merged = self
+            .old_paths
+            .iter()
+            .filter_map(|p| p.file_name())
+            .map(|n| n.to_string_lossy().to_string())
+            .collect();
+
+        if self.strict {
+            self.remove_missing(&mut on_added)?;
+        }
+        config.original_size = Some(
+            self.files
+                .iter()
+                .filter(|(b, _)| *b)
+                .map(|(_, fi)| fi.size)
+                .sum(),
+        );
         let config = config.as_yaml()?;
 
         let mut decoders = self
             .readers
             .iter_mut()
-            .map(|r| r.get_decoder())
+            .map(|r| Ok((r.get_decoder()?, r.meta_positions())))
             .collect::<Result<Vec<_>, BackupError>>()?;
         let mut entries = decoders
             .iter_mut()
-            .map(|d| {
-                Ok(d.entries()
-                    .map_err(BackupError::ArchiveError)?
-                    .skip(2)
-                    .peekable())
+            .map(|(d, meta)| {
+                let entries = d.entries().map_err(BackupError::ArchiveError)?;
+                Ok(skip_meta(entries, *meta).peekable())
             })
             .collect::<Result<Vec<_>, BackupError>>()?;
 
         if let Some(p) = self.tmp_path.parent() {
             std::fs::create_dir_all(p)?;
         }
-        let list = FileListString::from(&mut self.files);
-        let mut encoder = CompressionEncoder::create(&self.tmp_path, quality, threads)
-            .map_err(BackupError::WriteError)?;
+        let mut list = FileListString::from(&mut self.files);
+        list.add_deleted(&self.deleted);
+        // The merged backup is never split (renaming volumes during cleanup is not supported)
+        let mut encoder = CompressionEncoder::create(&self.tmp_path, quality, threads, None)
+            .map_err(BackupError::WriteError)?
+            .store_only(&store_only)
+            .tar_format(tar_format);
         encoder
             .append_data(CONFIG_DEFAULT_NAME, config)
             .map_err(BackupError::WriteError)?;
         encoder
             .append_data(list.filename(), list)
             .map_err(BackupError::WriteError)?;
+        // The latest signatures are kept (for the next incremental backup)
+        if delta_files {
+            let signatures = self.readers[0].read_signatures()?;
+            encoder
+                .append_data(SIGNATURES_NAME, signatures.to_string())
+                .map_err(BackupError::WriteError)?;
+        }
 
-        for (_, file) in self.files.iter_mut() {
-            let file = file.get_string();
-            'outer: for p in entries.iter_mut() {
+        // The archive that each file is copied from (hard links must refer to the same archive)
+        let mut sources: Vec<Option<usize>> = vec![None; self.files.len()];
+        // The files stored in the archives that are not sorted (these are looked up by name)
+        let unsorted: Vec<Option<HashSet<String>>> = self
+            .readers
+            .iter()
+            .map(|r| {
+                r.is_unsorted().then(|| {
+                    let list = r.list.as_ref().unwrap();
+                    list.iter_included().map(String::from).collect()
+                })
+            })
+            .collect();
+        let mut stats = WriteStats::default();
+        for i in 0..self.files.len() {
+            let mut found = false;
+            let name = self.files.get_mut(i).unwrap().1.get_string().clone();
+            'outer: for (r, p) in entries.iter_mut().enumerate() {
+                if let Some(included) = &unsorted[r] {
+                    if !included.contains(&name) {
+                        continue;
+                    }
+                    let update = self.files.get_mut(i).unwrap().1.update;
+                    if let Some((mut fi, res)) =
+                        self.copy_unsorted(r, &name, update, &mut encoder, &mut on_progress)?
+                    {
+                        if res.is_ok() {
+                            stats.files += 1;
+                            stats.size += self.files.get_mut(i).unwrap().1.size;
+                        }
+                        on_added(&mut fi, res)?;
+                        sources[i] = Some(r);
+                        found = true;
+                        break 'outer;
+                    }
+                    continue;
+                }
                 while let Some(e) = p.peek_mut() {
                     match e {
                         Err(_) => {
                             p.next().unwrap()?;
                         }
-                        Ok((fi, _)) => match fi.get_string().cmp(file) {
+                        Ok((fi, _)) => match cmp_path_str(fi.get_string(), &name) {
                             std::cmp::Ordering::Less => {
                                 p.next();
                             }
                             std::cmp::Ordering::Equal => {
-                                let (mut fi, entry) = p.next().unwrap()?;
-                                on_added(
-                                    &mut fi,
-                                    encoder.append_entry(entry).map_err(BackupError::WriteError),
-                                )?;
+                                let (mut fi, mut entry) = p.next().unwrap()?;
+                                let res = match hard_link_target(&entry) {
+                                    // The merged backup has the whole file (not only the blocks)
+                                    _ if delta_manifest(&mut entry).is_some() => self.copy_delta(
+                                        r,
+                                        &name,
+                                        entry,
+                                        &mut encoder,
+                                        &mut on_progress,
+                                    ),
+                                    // Copy the content if the target is taken from another archive
+                                    Some(target)
+                                        if self
+                                            .files
+                                            .find(&target.copy_string())
+                                            .is_none_or(|j| sources[j] != Some(r)) =>
+                                    {
+                                        self.copy_link_target(
+                                            r,
+                                            target,
+                                            fi.get_path(),
+                                            &mut encoder,
+                                            &mut on_progress,
+                                        )
+                                    }
+                                    _ => match self.files.get_mut(i).unwrap().1.update {
+                                        Some(update) => encoder.append_entry_updated(
+                                            entry,
+                                            &update,
+                                            &mut on_progress,
+                                        ),
+                                        None => encoder.append_entry(entry, &mut on_progress),
+                                    }
+                                    .map_err(BackupError::WriteError),
+                                };
+                                if res.is_ok() {
+                                    stats.files += 1;
+                                    stats.size += self.files.get_mut(i).unwrap().1.size;
+                                }
+                                on_added(&mut fi, res)?;
+                                sources[i] = Some(r);
+                                found = true;
                                 break 'outer;
                             }
                             std::cmp::Ordering::Greater => break,
@@ -807,46 +3137,186 @@ impl BackupMerger {
                     }
                 }
             }
+            let (inc, file) = self.files.get_mut(i).unwrap();
+            if *inc && !found {
+                let name = file.copy_string().to_string();
+                on_added(file, Err(BackupError::MissingEntry(name)))?;
+            }
         }
         on_final();
         encoder.close()?;
+        Ok(stats)
+    }
+
+    /// Copy the content of a hard link target (from the same archive as the link) as `file`,
+    /// for when the target is not copied from that archive
+    fn copy_link_target(
+        &self,
+        reader: usize,
+        mut target: FileInfo,
+        file: &PathBuf,
+        encoder: &mut CompressionEncoder,
+        on_progress: &mut impl FnMut(u64),
+    ) -> Result<(), BackupError> {
+        let mut decoder = self.readers[reader].get_decoder()?;
+        let entries = decoder.entries().map_err(BackupError::ArchiveError)?;
+        for res in skip_meta(entries, self.readers[reader].meta_positions()) {
+            let (mut fi, entry) = res.map_err(BackupError::ArchiveError)?;
+            if cmp_path_str(fi.get_string(), target.get_string()).is_eq() {
+                return encoder
+                    .append_entry_renamed(entry, file, on_progress)
+                    .map_err(BackupError::WriteError);
+            }
+        }
+        Err(BackupError::MissingEntry(target.move_string()))
+    }
+
+    /// Copy a file that is stored as changed blocks as the whole file, which is restored from the
+    /// archive (and the previous backups) to a temporary file first
+    fn copy_delta(
+        &self,
+        reader: usize,
+        name: &str,
+        entry: tar::Entry<'_, ArchiveReader<'_>>,
+        encoder: &mut CompressionEncoder,
+        on_progress: &mut impl FnMut(u64),
+    ) -> Result<(), BackupError> {
+        let content = extend_pathbuf(self.tmp_path.clone(), ".delta.tmp");
+        let mut source = BackupReader::new(self.readers[reader].path.clone_path())
+            .with_follow_origin(self.readers[reader].follow_origin);
+        let mut error = None;
+        let res = source
+            .restore_files(
+                vec![name],
+                |_| FileInfo::from(content.clone()),
+                |res| {
+                    if let Err(e) = res {
+                        error.get_or_insert(e);
+                    }
+                    Ok(())
+                },
+                |_| (),
+                Overwrite::Always,
+                false,
+            )
+            .and_then(|_| match error {
+                Some(e) => Err(BackupError::FileError(e)),
+                None => encoder
+                    .append_entry_with_content(entry, &content, on_progress)
+                    .map_err(BackupError::WriteError),
+            });
+        #[allow(unused_must_use)]
+        {
+            std::fs::remove_file(&content);
+        }
+        res
+    }
+
+    /// Copy a file from an archive that is not sorted (see [`BackupReader::is_unsorted`]) by
+    /// looking through the whole archive (`None` if the file is not found)
+    fn copy_unsorted(
+        &self,
+        reader: usize,
+        name: &str,
+        update: Option<MetadataUpdate>,
+        encoder: &mut CompressionEncoder,
+        on_progress: &mut impl FnMut(u64),
+    ) -> Result<Option<CopiedEntry>, BackupError> {
+        let mut decoder = self.readers[reader].get_decoder()?;
+        let entries = decoder.entries().map_err(BackupError::ArchiveError)?;
+        for res in skip_meta(entries, self.readers[reader].meta_positions()) {
+            let (mut fi, entry) = res.map_err(BackupError::ArchiveError)?;
+            if cmp_path_str(fi.get_string(), name).is_eq() {
+                let res = match update {
+                    Some(update) => encoder.append_entry_updated(entry, &update, on_progress),
+                    None => encoder.append_entry(entry, on_progress),
+                };
+                return Ok(Some((fi, res.map_err(BackupError::WriteError))));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Drop (and report) files that are listed as included but are not found in any of the archives
+    fn remove_missing(
+        &mut self,
+        on_added: &mut impl FnMut(&mut FileInfo, Result<(), BackupError>) -> Result<(), BackupError>,
+    ) -> Result<(), BackupError> {
+        let mut found = HashSet::new();
+        for r in self.readers.iter_mut() {
+            let mut decoder = r.get_decoder()?;
+            let entries = decoder.entries().map_err(BackupError::ArchiveError)?;
+            for entry in skip_meta(entries, r.meta_positions()) {
+                let (mut fi, _) = entry.map_err(BackupError::ArchiveError)?;
+                found.insert(fi.move_string());
+            }
+        }
+        let mut missing = vec![];
+        self.files.retain_mut(|inc, fi| {
+            if *inc && !found.contains(fi.get_string()) {
+                missing.push(fi.clone());
+                false
+            } else {
+                true
+            }
+        });
+        for mut fi in missing {
+            let name = fi.copy_string().to_string();
+            on_added(&mut fi, Err(BackupError::MissingEntry(name)))?;
+        }
         Ok(())
     }
 
     fn get_tmp_output(&self) -> PathBuf {
-        let mut path = self.path.clone();
-        while path.exists() {
-            path = extend_pathbuf(path, ".tmp");
+        if self.path.exists() {
+            temporary_path(&self.path)
+        } else {
+            self.path.clone()
         }
-        path
     }
 
-    fn cleanup(&mut self) -> Result<(), BackupError> {
+    fn get_old_paths(&self) -> Vec<PathBuf> {
         if self.delete {
-            for r in self.readers.iter_mut() {
-                std::fs::remove_file(r.path.get_path()).map_err(BackupError::DeleteError)?;
-            }
-        } else {
-            for r in self.readers.iter_mut() {
-                let mut path = r.path.clone_path();
-                path = extend_pathbuf(path, ".old");
+            return vec![];
+        }
+        self.readers
+            .iter()
+            .map(|r| {
+                let mut path = extend_pathbuf(r.path.clone_path(), ".old");
                 while path.exists() {
                     path = extend_pathbuf(path, ".old");
                 }
-                std::fs::rename(r.path.get_path(), &path).map_err(|e| {
-                    BackupError::RenameError(
-                        r.path.get_string().to_string(),
-                        path.to_string_lossy().to_string(),
-                        e,
-                    )
-                })?;
-                r.path = path.into();
+                path
+            })
+            .collect()
+    }
+
+    fn cleanup(&mut self) -> Result<(), BackupError> {
+        let sources: Vec<PathBuf> = self.readers.iter().map(|r| r.path.clone_path()).collect();
+        let mut txn = Transaction::new();
+        if self.delete {
+            for p in sources.iter() {
+                log::info!("Deleting the merged backup '{}'", p.to_string_lossy());
+                txn.delete(p);
+            }
+        } else {
+            for (p, old) in sources.iter().zip(self.old_paths.iter()) {
+                log::info!(
+                    "Renaming the merged backup '{}' to '{}'",
+                    p.to_string_lossy(),
+                    old.to_string_lossy()
+                );
+                txn.rename(p, old);
             }
         }
         if self.path != self.tmp_path {
-            if self.path.exists() {
+            // The destination is free if it is one of the merged backups
+            let absolute = |p: &Path| p.absolutize().map(|p| p.to_path_buf()).ok();
+            let dest = absolute(&self.path);
+            if self.path.exists() && !sources.iter().any(|p| absolute(p) == dest) {
                 if self.overwrite {
-                    std::fs::remove_file(&self.path).map_err(BackupError::DeleteError)?;
+                    log::info!("Overwriting '{}'", self.path.to_string_lossy());
+                    txn.delete(&self.path);
                 } else {
                     return Err(BackupError::FileExists(self.path.to_path_buf()));
                 }
@@ -854,13 +3324,22 @@ impl BackupMerger {
             if let Some(p) = self.path.parent() {
                 std::fs::create_dir_all(p)?;
             }
-            std::fs::rename(&self.tmp_path, &self.path).map_err(|e| {
-                BackupError::RenameError(
-                    self.tmp_path.to_string_lossy().to_string(),
-                    self.path.to_string_lossy().to_string(),
-                    e,
-                )
-            })?;
+            log::info!(
+                "Renaming the merged backup '{}' to '{}'",
+                self.tmp_path.to_string_lossy(),
+                self.path.to_string_lossy()
+            );
+            txn.rename(&self.tmp_path, &self.path);
+        }
+        let dir = match self.path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        txn.commit(self.journal.then_some(dir.as_path()))?;
+        if !self.delete {
+            for (r, old) in self.readers.iter_mut().zip(self.old_paths.iter()) {
+                r.path = old.clone().into();
+            }
         }
         self.tmp_path.clear();
         Ok(())
@@ -0,0 +1,330 @@
+/// This module contains the interactive browsing of a backup (see [`crate::cli::browse`])
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::backup::{BackupError, BackupReader, Overwrite};
+use crate::files::{string_to_path, FileInfo};
+use crate::stats::common_root;
+use crate::utils::format_size;
+
+/// The commands of the browser (with the arguments and a description)
+pub const BROWSE_COMMANDS: [(&str, &str); 8] = [
+    ("ls [PATH]", "List a directory (or a file)"),
+    (
+        "cd [PATH]",
+        "Change the directory (to the starting directory without a path)",
+    ),
+    ("pwd", "Print the current directory"),
+    ("cat FILE", "Print the content of a file"),
+    (
+        "get FILE [DEST]",
+        "Restore a file (to the working directory without a destination)",
+    ),
+    (
+        "find REGEX",
+        "List the files under the current directory that match a regex",
+    ),
+    ("help", "Show the commands"),
+    ("quit", "Stop browsing (also `exit`)"),
+];
+
+/// A virtual directory tree over the files in a backup (and the previous backups in the chain)
+pub struct Browser {
+    reader: BackupReader,
+    /// All files in the backup, with the sizes (when known)
+    files: Vec<(String, Option<u64>)>,
+    /// The starting directory (the common root of all files)
+    home: String,
+    cwd: String,
+}
+
+impl Browser {
+    pub fn new(mut reader: BackupReader) -> Result<Self, BackupError> {
+        let files: Vec<(String, Option<u64>)> = reader
+            .get_list()?
+            .iter_meta()
+            .map(|(_, size, _, path)| (path.to_string(), size))
+            .collect();
+        let home = common_root(files.iter().map(|(f, _)| Path::new(f.as_str())))
+            .to_string_lossy()
+            .replace('\\', "/");
+        Ok(Self {
+            reader,
+            files,
+            cwd: home.clone(),
+            home,
+        })
+    }
+
+    /// The current directory (empty at the top, above all roots)
+    pub fn cwd(&self) -> &str {
+        &self.cwd
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Resolve an (absolute or relative) path against the current directory
+    pub fn resolve(&self, path: &str) -> String {
+        resolve_path(&self.cwd, path)
+    }
+
+    fn size(&self, path: &str) -> Option<Option<u64>> {
+        self.files.iter().find(|(f, _)| f == path).map(|(_, s)| *s)
+    }
+
+    fn is_dir(&self, path: &str) -> bool {
+        self.files.iter().any(|(f, _)| child_of(path, f).is_some())
+    }
+
+    /// Run a command, returns false when the browsing should stop
+    pub fn run<S: AsRef<str>>(
+        &mut self,
+        args: &[S],
+        out: &mut impl Write,
+    ) -> Result<bool, BackupError> {
+        let args: Vec<&str> = args.iter().map(|a| a.as_ref()).collect();
+        match args.as_slice() {
+            [] => {}
+            ["ls"] => self.ls(&self.cwd.clone(), out)?,
+            ["ls", path] => self.ls(&self.resolve(path), out)?,
+            ["cd"] => self.cwd = self.home.clone(),
+            ["cd", path] => {
+                let path = self.resolve(path);
+                if self.size(&path).is_some() {
+                    return Err(BackupError::GenericError("Not a directory"));
+                }
+                if !path.is_empty() && !self.is_dir(&path) {
+                    return Err(BackupError::NotInBackup(path));
+                }
+                self.cwd = path;
+            }
+            ["pwd"] => writeln!(out, "{}", self.cwd)?,
+            ["cat", path] => {
+                let path = self.resolve(path);
+                let tmp =
+                    std::env::temp_dir().join(format!("simple_backup_{}.cat", std::process::id()));
+                let res = self
+                    .get(&path, &tmp, Overwrite::Always)
+                    .and_then(|_| Ok(std::io::copy(&mut File::open(&tmp)?, out)?));
+                std::fs::remove_file(&tmp).ok();
+                res?;
+            }
+            ["get", path] => {
+                let path = self.resolve(path);
+                let dest = string_to_path(path.rsplit('/').next().unwrap_or_default());
+                self.get(&path, &dest, Overwrite::Never)?;
+                writeln!(out, "{}", dest.to_string_lossy())?;
+            }
+            ["get", path, dest] => {
+                let path = self.resolve(path);
+                let mut dest = PathBuf::from(dest);
+                if dest.is_dir() {
+                    dest.push(string_to_path(path.rsplit('/').next().unwrap_or_default()));
+                }
+                self.get(&path, &dest, Overwrite::Never)?;
+                writeln!(out, "{}", dest.to_string_lossy())?;
+            }
+            ["find", regex] => {
+                let regex = Regex::new(regex).map_err(BackupError::RegexError)?;
+                for (f, _) in self.files.iter() {
+                    if child_of(&self.cwd, f).is_some() && regex.is_match(f) {
+                        writeln!(out, "{}", f)?;
+                    }
+                }
+            }
+            ["help"] => {
+                for (cmd, desc) in BROWSE_COMMANDS {
+                    writeln!(out, "{:<16}  {}", cmd, desc)?;
+                }
+            }
+            ["quit"] | ["exit"] => return Ok(false),
+            _ => {
+                return Err(BackupError::GenericError(
+                    "Unknown command or wrong number of arguments (see `help`)",
+                ))
+            }
+        }
+        Ok(true)
+    }
+
+    /// List the content of a directory (or a single file)
+    fn ls(&self, path: &str, out: &mut impl Write) -> Result<(), BackupError> {
+        if let Some(size) = self.size(path) {
+            writeln!(
+                out,
+                "{:>10}  {}",
+                size.map(format_size).unwrap_or_default(),
+                path
+            )?;
+            return Ok(());
+        }
+        let mut entries = BTreeMap::new();
+        for (f, size) in self.files.iter() {
+            if let Some((name, rest)) = child_of(path, f) {
+                // Directories have no size
+                entries.insert(name, rest.is_empty().then_some(*size));
+            }
+        }
+        if entries.is_empty() {
+            return Err(BackupError::NotInBackup(path.to_string()));
+        }
+        for (name, size) in entries {
+            match size {
+                Some(size) => writeln!(
+                    out,
+                    "{:>10}  {}",
+                    size.map(format_size).unwrap_or_default(),
+                    name
+                )?,
+                None => writeln!(out, "{:>10}  {}/", "", name)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore a file from the backup (or the previous backup that stores it) to a path
+    fn get(&mut self, path: &str, dest: &Path, overwrite: Overwrite) -> Result<(), BackupError> {
+        if self.size(path).is_none() {
+            return Err(BackupError::NotInBackup(path.to_string()));
+        }
+        let mut reader = self.reader.clone();
+        while !reader.get_list()?.iter_included().any(|f| f == path) {
+            reader = match reader.get_previous()? {
+                Some(prev) => prev,
+                None => return Err(BackupError::MissingEntry(path.to_string())),
+            };
+        }
+        eprintln!("(from '{}')", reader.path.get_string());
+        let mut restored = false;
+        reader.restore(
+            vec![path],
+            |_| FileInfo::from(dest),
+            |res| {
+                res?;
+                restored = true;
+                Ok(())
+            },
+            overwrite,
+            false,
+        )?;
+        if restored {
+            Ok(())
+        } else {
+            Err(BackupError::MissingEntry(path.to_string()))
+        }
+    }
+}
+
+/// If the path is under the directory, get the name of the first component and the rest
+/// (`""` is the top, where absolute paths are under `/`)
+fn child_of<'a>(dir: &str, path: &'a str) -> Option<(&'a str, &'a str)> {
+    let rest = match dir {
+        "" if path.starts_with('/') => return Some(("/", &path[1..])),
+        "" => path,
+        "/" => path.strip_prefix('/')?,
+        _ => path.strip_prefix(dir)?.strip_prefix('/')?,
+    };
+    match rest.split_once('/') {
+        Some((name, rest)) => Some((name, rest)),
+        None if rest.is_empty() => None,
+        None => Some((rest, "")),
+    }
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    match dir {
+        "" => name.to_string(),
+        "/" => format!("/{}", name),
+        _ => format!("{}/{}", dir, name),
+    }
+}
+
+fn parent_path(dir: &str) -> &str {
+    match dir.rfind('/') {
+        _ if dir == "/" => "",
+        Some(0) => "/",
+        Some(i) => &dir[..i],
+        None => "",
+    }
+}
+
+/// Resolve a path (handling `.` and `..`) relative to a directory
+fn resolve_path(cwd: &str, path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let (mut dir, rest) = if let Some(rest) = path.strip_prefix('/') {
+        (String::from("/"), rest)
+    } else if path.split('/').next().is_some_and(|c| c.ends_with(':')) {
+        // A Windows drive
+        (String::new(), path.as_str())
+    } else {
+        (cwd.to_string(), path.as_str())
+    };
+    for c in rest.split('/') {
+        match c {
+            "" | "." => {}
+            ".." => dir = parent_path(&dir).to_string(),
+            c => dir = join_path(&dir, c),
+        }
+    }
+    dir
+}
+
+/// Split a command line into arguments (with support for quotes)
+pub fn split_command(line: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut current: Option<String> = None;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (Some(q), c) if q == c => quote = None,
+            (None, c) if c.is_whitespace() => args.extend(current.take()),
+            (_, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(current);
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        assert_eq!(child_of("", "/a/b"), Some(("/", "a/b")));
+        assert_eq!(child_of("", "C:/a"), Some(("C:", "a")));
+        assert_eq!(child_of("/", "/a/b"), Some(("a", "b")));
+        assert_eq!(child_of("/a", "/a/b"), Some(("b", "")));
+        assert_eq!(child_of("/a", "/ab/c"), None);
+        assert_eq!(child_of("/a", "/a"), None);
+        assert_eq!(parent_path("/a/b"), "/a");
+        assert_eq!(parent_path("/a"), "/");
+        assert_eq!(parent_path("/"), "");
+        assert_eq!(parent_path("C:"), "");
+        assert_eq!(resolve_path("/a/b", "c/./d"), "/a/b/c/d");
+        assert_eq!(resolve_path("/a/b", "../../.."), "");
+        assert_eq!(resolve_path("/a/b", "/x/../y"), "/y");
+        assert_eq!(resolve_path("/a", "C:\\data"), "C:/data");
+        assert_eq!(resolve_path("", ".."), "");
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(split_command("  ls  a/b "), vec!["ls", "a/b"]);
+        assert_eq!(split_command("get \"a b/c\" 'd e'"), vec![
+            "get", "a b/c", "d e"
+        ]);
+        assert_eq!(split_command("cat ''"), vec!["cat", ""]);
+        assert!(split_command("").is_empty());
+    }
+}
@@ -1,44 +1,357 @@
 /// This module contains the logic for running the program from a command line
-use core::panic;
-use std::io::Read;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use indicatif::{ProgressBar, ProgressStyle};
+use chrono::NaiveDateTime;
 use number_prefix::NumberPrefix;
 use regex::RegexSet;
 
-use crate::backup::{BackupMerger, BackupReader, BackupWriter};
-use crate::config::Config;
-use crate::files::{FileAccessError, FileInfo};
-use crate::lists::FileListString;
-use crate::utils::{strip_absolute_from_path, BackupIterator};
+use crate::backup::{
+    BackupError, BackupMerger, BackupReader, BackupWriter, Overwrite, BACKUP_FILE_EXTENSION,
+};
+use crate::browse::{split_command, Browser};
+use crate::commands::{format_metadata, METADATA_SIDECAR};
+use crate::compression::{archive_exists, archive_size};
+use crate::config::{Config, ConfigFormat};
+use crate::file_types::selection_for_types;
+use crate::files::{cmp_path_str, sort_paths, string_to_path, FileAccessError, FileInfo};
+use crate::lists::{display_path, FileListString};
+use crate::logging::{Progress, ProgressFormat};
+use crate::manifest::{
+    manifest_path, read_manifest, restore_sources, RestoreOutcome, RestoreParameters,
+    RestoreRecorder,
+};
+use crate::options::{BackupOptions, MergeOptions, RestoreOptions};
+use crate::overlap::{find_config_overlaps, OverlapKind};
+use crate::parse_date::naive_now;
+#[cfg(unix)]
+use crate::privileges::{self, OsPrivileges};
+use crate::query::Query;
+use crate::stats::{quality_advice, BackupInfo, BackupStats, ChainUsage, OutputFormat, StatsGroup};
+use crate::txn::recover;
+use crate::utils::{
+    extend_pathbuf, find_superseding, format_count, format_relative, format_size,
+    get_superseded_backups, is_superseded_backup, remap_path, sample_config_diff,
+    strip_absolute_from_path, strip_path_prefix, Background, BackupIterator, ConfigPathType,
+    OsPriority,
+};
 
-/// Backup files
+/// How often the watch mode checks for Ctrl-C while waiting
+const WATCH_POLL: Duration = Duration::from_millis(200);
+
+/// Print the warnings for a config (and return the errors, see [`Config::check`])
+pub fn check_config(config: &Config, quiet: bool) -> Result<(), BackupError> {
+    for issue in config.check()? {
+        if !quiet {
+            eprintln!("Warning: {}", issue);
+        }
+    }
+    Ok(())
+}
+
+/// The directory of a file (or the current directory)
+fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Complete (or revert) the operations that were interrupted while renaming files in the directory
+fn recover_journals(dir: &Path) {
+    let recovered = match recover(dir) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!(
+                "Could not recover an interrupted operation in '{}': {}",
+                dir.to_string_lossy(),
+                e
+            );
+            return;
+        }
+    };
+    for r in recovered {
+        if r.forward {
+            eprintln!(
+                "Completed an interrupted operation in '{}' ({}, {} files renamed or deleted)",
+                dir.to_string_lossy(),
+                r.id,
+                r.changed
+            );
+        } else {
+            eprintln!(
+                "Reverted an interrupted operation in '{}' ({}, {} files restored)",
+                dir.to_string_lossy(),
+                r.id,
+                r.changed
+            );
+        }
+        for p in r.lost {
+            eprintln!("  '{}' was already deleted", p.to_string_lossy());
+        }
+    }
+}
+
+/// Backup files (see [`BackupOptions`] for the options)
+pub fn backup_with(config: Config, options: &BackupOptions) -> Result<(), BackupError> {
+    check_config(&config, options.quiet)?;
+    let not_cancelled = AtomicBool::new(false);
+    let cancel = options.cancel.unwrap_or(&not_cancelled);
+    let mut background = Background::new(OsPriority, options.background);
+    if let (Some(timeout), false) = (options.wait_for_output, options.dry) {
+        wait_for_output(&config, timeout, options.quiet, cancel)?;
+    }
+    if let Some(user) = options.run_as {
+        run_as(&config, user, options.dry, options.quiet)?;
+    }
+    backup_cycle(
+        config,
+        options.verbose,
+        options.force,
+        options.dry,
+        options.quiet,
+        options.progress,
+        options.resume_scan,
+        options.report,
+        options.summary,
+        options.write_errors,
+        options.strict,
+        cancel,
+        &mut background,
+    )?;
+    Ok(())
+}
+
+/// Backup files (panics on errors, like before [`backup_with`] was added)
+#[deprecated(
+    since = "2.3.0",
+    note = "use `backup_with` and `BackupOptions` instead"
+)]
+#[allow(unused)]
 pub fn backup(config: Config, verbose: bool, force: bool, dry: bool, quiet: bool) {
+    let options = BackupOptions {
+        verbose,
+        force,
+        dry,
+        quiet,
+        ..Default::default()
+    };
+    if let Err(e) = backup_with(config, &options) {
+        panic!("{}", e);
+    }
+}
+
+/// Wait for the output to become available (see [`Config::missing_output`]).
+/// The output directory must also exist, since otherwise there would be nothing to wait for.
+fn wait_for_output(
+    config: &Config,
+    timeout: Duration,
+    quiet: bool,
+    cancel: &AtomicBool,
+) -> Result<(), BackupError> {
+    let start = Instant::now();
+    let missing = || {
+        config.missing_output().or_else(|| {
+            let dir = config.get_output_dir();
+            (!dir.is_dir()).then_some(dir)
+        })
+    };
+    let mut waiting = false;
+    while let Some(path) = missing() {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(BackupError::Cancel);
+        }
+        if start.elapsed() >= timeout {
+            return Err(BackupError::OutputUnavailable(path));
+        }
+        if !waiting {
+            log::info!("Waiting for the output '{}'", path.to_string_lossy());
+            if !quiet {
+                println!(
+                    "Waiting for the output to appear: {}",
+                    path.to_string_lossy()
+                );
+            }
+            waiting = true;
+        }
+        std::thread::sleep(WATCH_POLL.min(timeout.saturating_sub(start.elapsed())));
+    }
+    Ok(())
+}
+
+/// Drop the root privileges after the preparations (see [`crate::privileges::run_as`])
+#[cfg(unix)]
+fn run_as(config: &Config, user: &str, dry: bool, quiet: bool) -> Result<(), BackupError> {
+    for root in privileges::run_as(&mut OsPrivileges, user, config, dry)? {
+        log::warn!("'{}' cannot be read by the user '{}'", root, user);
+        if !quiet {
+            eprintln!(
+                "Warning: The included path '{}' cannot be read by the user '{}'",
+                root, user
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn run_as(_config: &Config, user: &str, _dry: bool, _quiet: bool) -> Result<(), BackupError> {
+    Err(BackupError::RunAs(
+        user.to_string(),
+        String::from("only supported on Unix"),
+    ))
+}
+
+/// Set a flag (instead of exiting) when Ctrl-C is pressed, so that partial files can be cleaned up.
+/// Pressing Ctrl-C a second time exits immediately.
+pub fn cancel_on_ctrlc() -> Result<Arc<AtomicBool>, BackupError> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let cancel2 = cancel.clone();
+    ctrlc::set_handler(move || {
+        if cancel2.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+    })
+    .map_err(|_| BackupError::GenericError("Could not listen for Ctrl-C"))?;
+    Ok(cancel)
+}
+
+/// Repeatedly backup files (until cancelled with Ctrl-C)
+pub fn watch(
+    mut config: Config,
+    interval: Duration,
+    verbose: bool,
+    force: bool,
+    quiet: bool,
+    background: bool,
+) -> Result<(), BackupError> {
+    check_config(&config, quiet)?;
+    let cancel = cancel_on_ctrlc()?;
+    let mut background = Background::new(OsPriority, background);
+    loop {
+        let start = Instant::now();
+        let time = naive_now().format("%Y-%m-%d %H:%M:%S");
+        match backup_cycle(
+            config.clone(),
+            verbose,
+            force,
+            false,
+            quiet,
+            ProgressFormat::Bar,
+            false,
+            None,
+            None,
+            false,
+            false,
+            &cancel,
+            &mut background,
+        ) {
+            Ok(Some((num_files, path))) => {
+                let size = archive_size(&path).unwrap_or(0);
+                eprintln!(
+                    "[{}] Backed up {} files to '{}' ({})",
+                    time,
+                    num_files,
+                    path.to_string_lossy(),
+                    format_size(size)
+                );
+            }
+            Ok(None) => eprintln!("[{}] Nothing to backup", time),
+            Err(e) if cancel.load(Ordering::Relaxed) => {
+                eprintln!("[{}] Backup cancelled: {}", time, e);
+            }
+            Err(e) => eprintln!("[{}] Backup failed: {}", time, e),
+        }
+        // Following backups use the previous backup for incrementality
+        config.time = None;
+        config.base = None;
+        while start.elapsed() < interval {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            std::thread::sleep(WATCH_POLL.min(interval.saturating_sub(start.elapsed())));
+        }
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+    }
+}
+
+/// Perform one backup (returning the number of files and the path to the backup)
+#[allow(clippy::too_many_arguments)]
+fn backup_cycle(
+    config: Config,
+    verbose: bool,
+    force: bool,
+    dry: bool,
+    quiet: bool,
+    progress: ProgressFormat,
+    resume_scan: bool,
+    report: Option<&Path>,
+    summary: Option<usize>,
+    write_errors: bool,
+    strict: bool,
+    cancel: &AtomicBool,
+    background: &mut Background<OsPriority>,
+) -> Result<Option<(u64, PathBuf)>, BackupError> {
+    let check_cancel = || {
+        if cancel.load(Ordering::Relaxed) {
+            Err(BackupError::Cancel)
+        } else {
+            Ok(())
+        }
+    };
+    // Nothing else is printed to stdout when the backup is written there
+    let stream = config.is_output_stream();
+    macro_rules! out {
+        ($($arg:tt)*) => {
+            if stream {
+                eprintln!($($arg)*)
+            } else {
+                println!($($arg)*)
+            }
+        };
+    }
+    if !dry && !stream {
+        // Fail before anything is created on the wrong drive
+        if let Some(path) = config.missing_output() {
+            return Err(BackupError::OutputUnavailable(path));
+        }
+        recover_journals(&config.get_journal_dir());
+    }
     let (mut bw, error) = BackupWriter::new(config);
-    if error.is_some() {
-        eprintln!(
-            "Could not get time from previous backup: {}",
-            error.unwrap()
-        );
+    if let Some(error) = error {
+        if bw.config.base.is_some() {
+            // A chosen base must be usable (instead of silently doing a full backup)
+            return Err(error);
+        }
+        match error {
+            BackupError::OutputMoved(..) => log::warn!("{}", error),
+            _ => log::warn!("Could not get time from previous backup: {}", error),
+        }
     }
-    if bw.path.exists() && !force {
-        panic!(
-            "Backup already exists at '{}' (use --force to overwrite)",
-            bw.path.to_string_lossy()
-        );
+    if !stream && archive_exists(&bw.path) && !force {
+        return Err(BackupError::FileExists(bw.path));
+    }
+    if resume_scan {
+        bw.resume_scan();
     }
 
     // Crawl for files
     let mut num_files = 0;
     let mut total_size = 0;
+    let mut errors = ErrorSummary::default();
     if verbose {
-        if bw.config.time.is_some() {
-            eprintln!(
-                "Updated files to backup (since {}):",
-                bw.config.time.unwrap()
-            );
+        if let Some(time) = bw.config.time {
+            eprintln!("Updated files to backup (since {}):", time);
         } else {
             eprintln!("Files to backup:");
         }
@@ -49,21 +362,32 @@ pub fn backup(config: Config, verbose: bool, force: bool, dry: bool, quiet: bool
                     total_size += fi.size;
                     match NumberPrefix::binary(fi.size as f64) {
                         NumberPrefix::Standalone(number) => {
-                            println!("{:>6.2} KiB  {}", number / 1024.0, &fi.get_string());
+                            out!(
+                                "{:>6.2} KiB  {}",
+                                number / 1024.0,
+                                display_path(fi.get_string())
+                            );
                         }
                         NumberPrefix::Prefixed(prefix, number) => {
-                            println!("{:>6.2} {}B  {}", number, prefix, &fi.get_string());
+                            out!(
+                                "{:>6.2} {}B  {}",
+                                number,
+                                prefix,
+                                display_path(fi.get_string())
+                            );
                         }
                     }
                 }
-                Err(e) => eprintln!("{}", e),
+                Err(e) => {
+                    log::warn!("{}", e);
+                    errors.add(e.path(), e.error());
+                }
             }
-            Ok(())
-        })
-        .expect("Could not crawl for files");
+            check_cancel()
+        })?;
     } else {
         if !quiet {
-            println!("Crawling for files...");
+            out!("Crawling for files...");
         }
         bw.foreach_file(false, |res: Result<&mut FileInfo, FileAccessError>| {
             match res {
@@ -71,16 +395,82 @@ pub fn backup(config: Config, verbose: bool, force: bool, dry: bool, quiet: bool
                     num_files += 1;
                     total_size += fi.size;
                 }
-                Err(e) => eprintln!("{}", e),
+                Err(e) => {
+                    log::warn!("{}", e);
+                    errors.add(e.path(), e.error());
+                }
             }
-            Ok(())
-        })
-        .expect("Could not crawl for files");
+            check_cancel()
+        })?;
+    }
+
+    if let Some(report) = &bw.paranoid {
+        for f in report.mismatched.iter() {
+            log::warn!("'{}' has changed without an updated modification time", f);
+        }
+        if !quiet {
+            eprintln!(
+                "Double checked unchanged files: {} hashed, {} with a changed size, {} with changed content",
+                report.sampled,
+                report.resized,
+                report.mismatched.len()
+            );
+            if !report.escalated.is_empty() {
+                eprintln!("Fully including these directories due to changed content:");
+                for d in report.escalated.iter() {
+                    eprintln!("  {}", d.to_string_lossy());
+                }
+            }
+        }
+    }
+
+    if !quiet {
+        for root in bw.required.iter().flatten() {
+            eprintln!("Required '{}': {} files", root.path, root.files);
+        }
+    }
+
+    // The commands are run when writing the backup
+    if !bw.config.commands.is_empty() {
+        num_files += bw.config.commands.len() as u64;
+        if verbose || dry {
+            eprintln!("Commands to run:");
+            for c in bw.config.commands.iter() {
+                out!("{}  {}", c.path().to_string_lossy(), c.cmd);
+            }
+        }
+    }
+
+    if let Some(report) = report {
+        bw.export_list(report, false)?;
+        if !quiet {
+            eprintln!("Wrote the list of files to '{}'", report.to_string_lossy());
+        }
     }
 
-    if num_files == 0 {
+    if let (Some(depth), Some(list)) = (summary, bw.list.as_ref()) {
+        let groups = list.size_by_prefix(depth);
+        if !groups.is_empty() {
+            eprintln!("Size by directory:");
+        }
+        for g in groups {
+            out!(
+                "{:>12}  {:>8} files  {}",
+                format_size(g.size),
+                g.files,
+                g.name
+            );
+        }
+    }
+
+    // A backup that only records deleted files is still written
+    if num_files == 0 && bw.find_deleted()?.is_empty() {
+        if !dry {
+            bw.remove_checkpoint();
+        }
+        errors.print();
         eprintln!("Nothing to backup!");
-        return;
+        return Ok(None);
     }
 
     // Perform the backup
@@ -91,186 +481,625 @@ pub fn backup(config: Config, verbose: bool, force: bool, dry: bool, quiet: bool
         if !quiet {
             eprintln!("Backing up files...");
         }
-        let bar = if quiet {
-            ProgressBar::hidden()
-        } else {
-            ProgressBar::new(total_size + num_files)
-        };
-        bar.set_style(ProgressStyle::default_bar().template(
+        let bar = Progress::new(
+            progress,
+            quiet,
+            "backup",
+            num_files,
+            total_size,
             "{wide_msg} {bytes:>8} / {total_bytes:<8}\n{wide_bar} {elapsed_precise:>8} / {duration_precise:<8}",
-        ).expect("The progressbar template is wrong!"));
-        bar.set_message("Compressing file list");
-        bar.tick();
-        bar.enable_steady_tick(Duration::from_secs(1));
-        bw.write(
-            |fi: &mut FileInfo, err| {
-                bar.set_message(fi.move_string());
-                bar.inc(fi.size + 1);
-                if let Err(e) = err {
-                    bar.println(format!(
-                        "Could not add '{}' to the backup: {}",
-                        fi.get_string(),
-                        e
-                    ));
-                }
-                Ok(())
-            },
-            || bar.set_message("Waiting for the compression to complete..."),
-        )
-        .expect("Could not create backup file");
-        bar.disable_steady_tick();
-        bar.set_message("Backup completed!");
-        bar.finish();
+        );
+        let _guard = bar.guard();
+        bar.start("Compressing file list");
+        let options = BackupOptions {
+            strict,
+            cancel: Some(cancel),
+            ..Default::default()
+        };
+        let stats = bw
+            .write_with(
+                |fi: &mut FileInfo, err| {
+                    bar.set_message(fi.move_string());
+                    bar.inc_files(1);
+                    if let Err(e) = err {
+                        log::warn!("Could not add '{}' to the backup: {}", fi.get_string(), e);
+                        errors.add(fi.get_string(), e);
+                    }
+                    background.pace();
+                    Ok(())
+                },
+                |bytes| bar.inc_bytes(bytes),
+                || bar.set_phase("finalize", "Waiting for the compression to complete..."),
+                &options,
+            )
+            .inspect_err(|_| bar.abandon())?;
+        bar.finish("Backup completed!");
+        if !quiet {
+            eprintln!("Backed up {}", stats);
+            if let Some(advice) = bw.compression_profile().as_ref().and_then(quality_advice) {
+                eprintln!("Suggestion: {}", advice);
+            }
+        }
+        num_files -= (bw.skipped().len() + bw.failed_commands().len()) as u64;
+        if write_errors && !errors.is_empty() {
+            let path = errors_path(&bw.path);
+            errors.write(&path).map_err(BackupError::WriteError)?;
+            if !quiet {
+                eprintln!("Wrote the skipped files to '{}'", path.to_string_lossy());
+            }
+        }
     }
+    errors.print();
+    Ok(Some((num_files, bw.path)))
 }
 
-/// Restore files from a backup
-#[allow(clippy::too_many_arguments)]
-pub fn restore<P: AsRef<Path>>(
-    mut source: BackupReader,
-    output: Option<P>,
-    #[allow(unused_mut)] mut include: Vec<String>,
-    regex: Vec<String>,
-    flatten: bool,
-    only_this: bool,
-    force: bool,
-    verbose: bool,
-    dry: bool,
-    quiet: bool,
-) {
-    source.get_meta().expect("Could not read the backup");
+/// The files that were skipped due to errors (the reasons are printed after the backup)
+#[derive(Default)]
+struct ErrorSummary(Vec<(String, String)>);
+
+impl ErrorSummary {
+    fn add(&mut self, path: &str, error: impl Display) {
+        self.0.push((path.to_string(), error.to_string()));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn print(&self) {
+        if !self.0.is_empty() {
+            eprintln!(
+                "{} {} skipped due to errors (they are not in the backup):",
+                self.0.len(),
+                if self.0.len() == 1 { "file" } else { "files" }
+            );
+            for (path, error) in self.0.iter() {
+                eprintln!("  {}: {}", display_path(path), error);
+            }
+        }
+    }
+
+    fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut f = BufWriter::new(File::create(path)?);
+        for (file, error) in self.0.iter() {
+            writeln!(f, "{}: {}", display_path(file), error)?;
+        }
+        f.flush()
+    }
+}
+
+/// The path of the list of skipped files (`backup_TIME.errors.txt` next to `backup_TIME.tar.zst`)
+pub fn errors_path(backup: &Path) -> PathBuf {
+    let name = backup.file_name().unwrap_or_default().to_string_lossy();
+    let stem = name.strip_suffix(BACKUP_FILE_EXTENSION).unwrap_or(&name);
+    backup.with_file_name(format!("{}.errors.txt", stem))
+}
+
+/// Restore files from a backup (see [`RestoreOptions`] for the options)
+pub fn restore_with(mut source: BackupReader, options: &RestoreOptions) -> Result<(), BackupError> {
+    #[allow(unused_mut)]
+    let RestoreOptions {
+        output,
+        include,
+        regex,
+        mut map,
+        strip_prefix,
+        types,
+        query,
+        flatten,
+        only_this,
+        overwrite,
+        mirror,
+        verbose,
+        dry,
+        quiet,
+        progress,
+        background,
+        record,
+        metadata_sidecar,
+        cancel,
+    } = options.clone();
+    if mirror && flatten {
+        return Err(BackupError::GenericError(
+            "Mirroring cannot be combined with flatten=true",
+        ));
+    }
+    let mut background = Background::new(OsPriority, background);
+    source.get_meta()?;
+    // The matches are found before the list is moved out of the reader
+    let matching: Option<HashSet<String>> = match query {
+        Some(query) => {
+            let mask = query.filter_list(&mut source)?;
+            let list = source.get_list()?;
+            Some(
+                list.iter()
+                    .zip(mask)
+                    .filter(|(_, m)| *m)
+                    .map(|((_, f), _)| f.to_string())
+                    .collect(),
+            )
+        }
+        None => None,
+    };
+    let stream = source.is_stream();
     let only_this = {
-        let conf = source.get_config().expect("Could not read the backup");
+        let conf = source.get_config()?;
         if conf.incremental {
-            if only_this {
+            if stream && !only_this && !quiet {
+                eprintln!(
+                    "The previous backups cannot be found for a backup from stdin (only restoring the files stored in it)"
+                );
+            }
+            if only_this || stream {
                 conf.incremental = false;
             }
-            only_this
+            only_this || stream
         } else {
             true
         }
     };
+    let recorder = match record {
+        Some(_) if !dry => {
+            let sources = if only_this {
+                HashMap::new()
+            } else {
+                restore_sources(&mut source)?
+            };
+            let parameters = RestoreParameters {
+                output: output.map(Path::to_path_buf),
+                include: include.clone(),
+                regex: regex.clone(),
+                map: map.clone(),
+                strip_prefix: strip_prefix.map(String::from),
+                query: query.map(|q| q.to_string()),
+                flatten,
+                only_this,
+                overwrite,
+            };
+            let backup = source.path.copy_string().to_string();
+            Some(RefCell::new(RestoreRecorder::new(
+                backup, parameters, sources,
+            )))
+        }
+        _ => None,
+    };
+    if metadata_sidecar && output.is_none() {
+        return Err(BackupError::GenericError(
+            "The output must be given if metadata_sidecar is set",
+        ));
+    }
+    // The deleted files are found before the list is moved out of the reader
+    let mut deleted = if mirror {
+        source.get_deleted(!only_this)?
+    } else {
+        vec![]
+    };
+    // The latest list has the metadata of all files (also those from previous backups)
+    let user_meta: HashMap<String, String> = if metadata_sidecar && !dry {
+        source
+            .get_list()?
+            .iter_user_meta()
+            .map(|(path, meta)| (path.to_string(), meta.into_owned()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    // The sizes are also found before the list is moved out (for the progress)
+    let sizes: HashMap<String, u64> = if !dry && !quiet {
+        source
+            .get_list()?
+            .iter_meta()
+            .filter_map(|(_, size, _, path)| Some((path.to_string(), size?)))
+            .collect()
+    } else {
+        HashMap::new()
+    };
 
     let tmp1: FileListString;
-    let mut list: Vec<&str> = if !regex.is_empty() {
-        let regex = RegexSet::new(regex).expect("Could not parse regex");
-        tmp1 = source
-            .move_list()
-            .expect("Could not get list of files from backup");
+    let mut list: Vec<Cow<str>> = if !regex.is_empty() {
+        let regex = RegexSet::new(&regex).map_err(BackupError::RegexError)?;
+        tmp1 = source.move_list()?;
         if only_this {
-            tmp1.iter_included().filter(|f| regex.is_match(f)).collect()
+            tmp1.iter_included()
+                .filter(|f| regex.is_match(f))
+                .map(Cow::Borrowed)
+                .collect()
         } else {
             tmp1.iter()
                 .map(|v| v.1)
                 .filter(|f| regex.is_match(f))
+                .map(Cow::Borrowed)
                 .collect()
         }
     } else if include.is_empty() {
-        tmp1 = source
-            .move_list()
-            .expect("Could not get list of files from backup");
+        tmp1 = source.move_list()?;
         if only_this {
-            tmp1.iter_included().collect()
+            tmp1.iter_included().map(Cow::Borrowed).collect()
         } else {
-            tmp1.iter().map(|v| v.1).collect()
+            tmp1.iter().map(|v| Cow::Borrowed(v.1)).collect()
         }
     } else {
         vec![]
     };
+    // Directories are restored with all the files under them
     if !include.is_empty() {
-        list.reserve(include.len());
-        #[cfg(target_os = "windows")]
-        include.iter_mut().for_each(|s| *s = s.replace('\\', "/"));
-        list.extend(include.iter().map(|s| s.as_str()));
-        list.sort_unstable();
+        match source.expand_selection(&include, only_this)? {
+            Some(expanded) => list.extend(expanded.into_iter().map(Cow::Owned)),
+            None => list.extend(include.iter().map(|s| Cow::Borrowed(s.as_str()))),
+        }
     }
+    // Both the includes and the matches from the list might use `\` on Windows
+    #[cfg(target_os = "windows")]
+    list.iter_mut()
+        .filter(|s| s.contains('\\'))
+        .for_each(|s| *s = Cow::Owned(s.replace('\\', "/")));
+    sort_paths(&mut list);
+    if !types.is_empty() {
+        let selection = selection_for_types(&types);
+        list.retain(|f| selection.is_match(f));
+    }
+    if let Some(matching) = &matching {
+        list.retain(|f| matching.contains(f.as_ref()));
+    }
+    let mirrored: Vec<PathBuf> = if !deleted.is_empty() {
+        // The deleted files are selected like the restored files (but cannot match a query)
+        let regex = RegexSet::new(regex).map_err(BackupError::RegexError)?;
+        let selection = selection_for_types(&types);
+        deleted.retain(|f| {
+            (regex.is_empty() || regex.is_match(f))
+                && (include.is_empty() || include.iter().any(|i| is_path_under(f, i)))
+                && (types.is_empty() || selection.is_match(f))
+                && query.is_none()
+        });
+        deleted
+            .iter()
+            .filter_map(|f| {
+                let mapped = remap_path(f, &map);
+                let mapped = mapped.as_deref().unwrap_or(f);
+                match (output, strip_prefix) {
+                    (Some(o), Some(p)) => Some(o.join(string_to_path(&strip_absolute_from_path(
+                        strip_path_prefix(mapped, p)?,
+                    )))),
+                    (Some(o), None) => {
+                        Some(o.join(string_to_path(&strip_absolute_from_path(mapped))))
+                    }
+                    (None, _) => Some(string_to_path(mapped)),
+                }
+            })
+            .collect()
+    } else {
+        vec![]
+    };
 
     if list.is_empty() {
         if !quiet {
             eprintln!("No files to backup");
         }
-        return;
+        delete_mirrored(mirrored, verbose, dry, quiet);
+        return Ok(());
+    }
+    #[cfg(target_os = "windows")]
+    map.iter_mut().for_each(|(from, to)| {
+        *from = from.replace('\\', "/");
+        *to = to.replace('\\', "/");
+    });
+    #[cfg(target_os = "windows")]
+    let strip_prefix = strip_prefix.map(|p| p.replace('\\', "/"));
+    #[cfg(target_os = "windows")]
+    let strip_prefix = strip_prefix.as_deref();
+    if let Some(prefix) = strip_prefix {
+        if output.is_none() {
+            return Err(BackupError::GenericError(
+                "The output must be given if strip_prefix is set",
+            ));
+        }
+        // Check all files before restoring anything
+        for f in list.iter() {
+            let mapped = remap_path(f, &map);
+            if strip_path_prefix(mapped.as_deref().unwrap_or(f), prefix).is_none() {
+                return Err(BackupError::OutsidePrefix(
+                    f.to_string(),
+                    prefix.to_string(),
+                ));
+            }
+        }
     }
     if verbose {
         eprintln!("Files to restore:");
         for f in list.iter() {
-            println!("{}", f);
+            println!("{}", display_path(f));
         }
         eprintln!();
     }
 
     if !dry {
-        let bar = if quiet {
-            ProgressBar::hidden()
+        let total_size: u64 = list.iter().filter_map(|f| sizes.get(f.as_ref())).sum();
+        // Lists from old versions have no sizes (then only the files are counted)
+        let bar = if total_size > 0 {
+            Progress::new(
+                progress,
+                quiet,
+                "restore",
+                list.len() as u64,
+                total_size,
+                "{wide_msg} {bytes:>8} / {total_bytes:<8}\n{wide_bar} {elapsed_precise:>8} (ETA {eta_precise:<8})",
+            )
+            .bytes_only()
         } else {
-            ProgressBar::new(list.len() as u64)
+            Progress::new(
+                progress,
+                quiet,
+                "restore",
+                list.len() as u64,
+                0,
+                "{wide_msg} {pos:>8} / {len:<8}\n{wide_bar} {elapsed_precise:>8} / {duration_precise:<8}",
+            )
         };
-        bar.set_style(ProgressStyle::default_bar().template(
-            "{wide_msg} {pos:>8} / {len:<8}\n{wide_bar} {elapsed_precise:>8} / {duration_precise:<8}"
-        ).expect("The progressbar template is wrong!"));
-        bar.set_message("Restoring files");
-        bar.tick();
-        bar.enable_steady_tick(Duration::from_secs(1));
-
-        let callback = |res| {
+        let _guard = bar.guard();
+        bar.start("Restoring files");
+
+        let sidecar: RefCell<Vec<(PathBuf, &str)>> = RefCell::new(vec![]);
+        let record_path = |entry: &str, path: &Path| {
+            if let Some(r) = &recorder {
+                r.borrow_mut().transformed(entry, path);
+            }
+            if let Some(meta) = user_meta.get(entry) {
+                sidecar.borrow_mut().push((path.to_path_buf(), meta));
+            }
+        };
+        // Hard links, duplicates, and changed blocks unpack less than the listed sizes, so the
+        // total is the unpacked bytes plus the listed sizes of the files not yet restored
+        let mut restored_size = 0;
+        let callback = |mut res: std::io::Result<FileInfo>| {
+            if let Some(r) = &recorder {
+                r.borrow_mut().outcome(&mut res);
+            }
             match res {
-                Ok(_) => bar.inc(1),
+                Ok(fi) => {
+                    bar.inc_files(1);
+                    restored_size += fi.size;
+                    bar.set_total_bytes(bar.bytes() + total_size.saturating_sub(restored_size));
+                }
                 Err(e) => {
-                    bar.inc(1);
-                    bar.println(format!("Could not restore from backup: {}", e));
+                    bar.inc_files(1);
+                    log::warn!("Could not restore from backup: {}", e);
                 }
             }
+            background.pace();
             Ok(())
         };
+        // The `only_this` might have changed (e.g. for a stream)
+        let restore_options = RestoreOptions {
+            overwrite,
+            only_this,
+            cancel,
+            ..Default::default()
+        };
 
-        if flatten {
-            let output = output.expect("Output directory required for flattening!");
-            let output = output.as_ref();
+        // The selection is kept for the manifest (the files that are never found)
+        let selection = recorder.as_ref().map(|_| list.clone()).unwrap_or_default();
+        let res = if flatten {
+            let output = output.ok_or(BackupError::GenericError(
+                "The output must be given if flatten=true",
+            ))?;
             let path_transform = |mut fi: FileInfo| {
-                bar.set_message(fi.move_string());
-                FileInfo::from(output.join(fi.consume_path().file_name().unwrap()))
+                let s = fi.move_string();
+                let path = output.join(fi.consume_path().file_name().unwrap());
+                record_path(&s, &path);
+                let mut fi = FileInfo::from(path);
+                fi.size = sizes.get(&s).copied().unwrap_or(0);
+                bar.set_message(s);
+                fi
             };
-            source.restore(list, path_transform, callback, force, !only_this)
+            source.restore_with(
+                list,
+                path_transform,
+                callback,
+                |bytes| bar.inc_bytes(bytes),
+                &restore_options,
+            )
         } else if let Some(o) = &output {
             let path_transform = |mut fi: FileInfo| {
                 let s = fi.move_string();
-                let path = strip_absolute_from_path(&s);
+                let mapped = remap_path(&s, &map);
+                let mapped = mapped.as_deref().unwrap_or(&s);
+                let path = match strip_prefix.and_then(|p| strip_path_prefix(mapped, p)) {
+                    Some(rest) => strip_absolute_from_path(rest),
+                    None => strip_absolute_from_path(mapped),
+                };
+                let path = o.join(string_to_path(&path));
+                record_path(&s, &path);
+                let mut fi = FileInfo::from(path);
+                fi.size = sizes.get(&s).copied().unwrap_or(0);
                 bar.set_message(s);
-                FileInfo::from(o.as_ref().join(path))
+                fi
             };
-            source.restore(list, path_transform, callback, force, !only_this)
+            source.restore_with(
+                list,
+                path_transform,
+                callback,
+                |bytes| bar.inc_bytes(bytes),
+                &restore_options,
+            )
         } else {
             let path_transform = |mut fi: FileInfo| {
-                bar.set_message(fi.move_string());
+                let s = fi.move_string();
+                let mapped = remap_path(&s, &map);
+                let mut fi = match mapped {
+                    Some(p) => FileInfo::from(p),
+                    None => FileInfo::from(s.as_str()),
+                };
+                record_path(&s, fi.get_path());
+                fi.size = sizes.get(&s).copied().unwrap_or(0);
+                bar.set_message(s);
                 fi
             };
-            source.restore(list, path_transform, callback, force, !only_this)
+            source.restore_with(
+                list,
+                path_transform,
+                callback,
+                |bytes| bar.inc_bytes(bytes),
+                &restore_options,
+            )
+        };
+        if let (Some(recorder), Some(dir)) = (recorder, record) {
+            // The manifest is also written for interrupted restorations
+            let manifest = recorder.into_inner().finish(&selection, res.is_err());
+            manifest
+                .append(manifest_path(dir))
+                .map_err(BackupError::WriteError)?;
+        }
+        res.inspect_err(|_| bar.abandon())?;
+        if let (Some(o), false) = (output, user_meta.is_empty()) {
+            write_metadata_sidecar(o, sidecar.into_inner())?;
         }
-        .expect("Could not restore from backup");
 
-        bar.disable_steady_tick();
-        bar.set_message("Restoration Completed!");
-        bar.finish();
+        bar.finish("Restoration Completed!");
     }
+    // The files are only deleted after a successful restore
+    delete_mirrored(mirrored, verbose, dry, quiet);
+    Ok(())
+}
+
+/// Is the path (from a list) the same as, or inside, the directory
+fn is_path_under(path: &str, dir: &str) -> bool {
+    let dir = dir.trim_end_matches(['/', '\\']);
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['/', '\\']))
+}
+
+/// Delete the files (and empty directories) that were deleted before the backup was made,
+/// when mirroring a restore (see [`RestoreOptions::mirror`])
+fn delete_mirrored(paths: Vec<PathBuf>, verbose: bool, dry: bool, quiet: bool) {
+    let mut count = 0;
+    for path in paths {
+        let meta = match std::fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            Err(_) => continue,
+        };
+        if verbose || dry {
+            println!("Deleting: {}", display_path(&path.to_string_lossy()));
+        }
+        if dry {
+            continue;
+        }
+        let res = if meta.is_dir() {
+            // Directories with other files are kept
+            std::fs::remove_dir(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        match res {
+            Ok(_) => count += 1,
+            Err(e) => log::warn!("Could not delete '{}': {}", path.to_string_lossy(), e),
+        }
+    }
+    if count > 0 && !quiet {
+        eprintln!(
+            "Deleted {} {} that had been deleted before the backup",
+            format_count(count),
+            if count == 1 { "file" } else { "files" }
+        );
+    }
+}
+
+/// Write the metadata of the restored files (with the paths relative to the output directory)
+fn write_metadata_sidecar(output: &Path, files: Vec<(PathBuf, &str)>) -> Result<(), BackupError> {
+    let mut files: Vec<(String, &str)> = files
+        .into_iter()
+        .map(|(path, meta)| {
+            let path = path.strip_prefix(output).unwrap_or(&path);
+            (display_path(&path.to_string_lossy()).into_owned(), meta)
+        })
+        .collect();
+    files.sort_unstable_by(|a, b| cmp_path_str(&a.0, &b.0));
+    let content = format_metadata(files.iter().map(|(path, meta)| (path.as_str(), *meta)));
+    std::fs::write(output.join(METADATA_SIDECAR), content).map_err(BackupError::WriteError)
+}
+
+/// Restore files from a backup (panics on errors, like before [`restore_with`] was added)
+#[deprecated(
+    since = "2.3.0",
+    note = "use `restore_with` and `RestoreOptions` instead"
+)]
+#[allow(unused, clippy::too_many_arguments)]
+pub fn restore<P: AsRef<Path>>(
+    source: BackupReader,
+    output: Option<P>,
+    include: Vec<String>,
+    regex: Vec<String>,
+    flatten: bool,
+    only_this: bool,
+    force: bool,
+    verbose: bool,
+    dry: bool,
+    quiet: bool,
+) {
+    let options = RestoreOptions {
+        output: output.as_ref().map(|o| o.as_ref()),
+        include,
+        regex,
+        flatten,
+        only_this,
+        overwrite: Overwrite::from_flags(force, false),
+        verbose,
+        dry,
+        quiet,
+        ..Default::default()
+    };
+    if let Err(e) = restore_with(source, &options) {
+        panic!("{}", e);
+    }
+}
+
+/// Extract files from a backup into a directory, ignoring the original absolute paths.
+/// Unlike [`restore_with`] this never writes to the original locations (the `output` must be
+/// given, and the `map` is ignored).
+pub fn extract_with(source: BackupReader, options: &RestoreOptions) -> Result<(), BackupError> {
+    let output = options.output.ok_or(BackupError::GenericError(
+        "The output must be given when extracting",
+    ))?;
+    if output.exists() && !output.is_dir() {
+        return Err(BackupError::GenericError("The output must be a directory"));
+    }
+    restore_with(source, &RestoreOptions {
+        map: vec![],
+        ..options.clone()
+    })
 }
 
 /// Inspect backup metadata
-pub fn inspect(mut source: BackupReader, config: bool, list: bool, quiet: bool) {
+/// (with a `query` only the matching lines of the list are shown)
+pub fn inspect(
+    mut source: BackupReader,
+    config: bool,
+    list: bool,
+    query: Option<&Query>,
+    quiet: bool,
+) -> Result<(), BackupError> {
+    let mask = match query {
+        Some(query) if list => Some(query.filter_list(&mut source)?),
+        _ => None,
+    };
+    let path = source.path.clone_path();
     let backup = source.path.move_string();
-    let mut decoder = source.get_decoder().expect("Could not open the backup");
-    let mut entries = decoder.entries().expect("Could not read the backup");
+    if !quiet && is_superseded_backup(&path) {
+        eprintln!("{}", describe_superseded(&path));
+    }
+    let mut decoder = source.get_decoder()?;
+    let mut entries = decoder.entries().map_err(BackupError::ArchiveError)?;
     if config {
         let (mut fi, mut entry) = entries
             .next()
-            .expect("No config found")
-            .expect("Could not read the backup");
+            .ok_or_else(|| BackupError::NoConfig(path.clone()))?
+            .map_err(BackupError::ArchiveError)?;
         if !quiet {
             eprintln!("{} > {}:", backup, fi.move_string());
         }
         let mut conf = String::new();
         entry
             .read_to_string(&mut conf)
-            .expect("Could not read the backup");
+            .map_err(BackupError::ArchiveError)?;
         if !quiet {
             print!("{}", conf);
         }
@@ -280,8 +1109,8 @@ pub fn inspect(mut source: BackupReader, config: bool, list: bool, quiet: bool)
     if list {
         let (mut fi, mut entry) = entries
             .next()
-            .expect("No file list found")
-            .expect("Could not read the backup");
+            .ok_or(BackupError::NoList(path))?
+            .map_err(BackupError::ArchiveError)?;
         if config && !quiet {
             eprint!("{} > {}:", backup, fi.move_string());
             println!();
@@ -291,35 +1120,561 @@ pub fn inspect(mut source: BackupReader, config: bool, list: bool, quiet: bool)
         let mut conf = String::new();
         entry
             .read_to_string(&mut conf)
-            .expect("Could not read the backup");
+            .map_err(BackupError::ArchiveError)?;
+        if let Some(mask) = mask {
+            // The mask has one value per line in the list
+            conf = conf
+                .split('\n')
+                .zip(mask)
+                .filter_map(|(line, m)| m.then_some(line))
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
         if !quiet {
             println!("{}", conf);
         }
     }
+    Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-pub fn merge(
-    backups: Vec<PathBuf>,
-    path: Option<PathBuf>,
-    all: bool,
-    delete: bool,
-    quality: Option<i32>,
-    threads: Option<u32>,
-    verbose: bool,
-    force: bool,
-    dry: bool,
+/// Explain where a superseded backup comes from
+fn describe_superseded(path: &Path) -> String {
+    match find_superseding(path) {
+        Some((merged, time)) => format!(
+            "'{}' has been superseded by merge on {} (into '{}')",
+            path.to_string_lossy(),
+            time.format("%Y-%m-%d %H:%M:%S"),
+            merged.to_string_lossy()
+        ),
+        None => format!(
+            "'{}' has been superseded (the merged backup could not be found)",
+            path.to_string_lossy()
+        ),
+    }
+}
+
+/// Summarise the contents of a backup
+pub fn stats(
+    mut source: BackupReader,
+    by: StatsGroup,
+    top: usize,
+    json: bool,
     quiet: bool,
-) {
-    let backups = backups
+) -> Result<(), BackupError> {
+    let stats = BackupStats::new(&mut source, by, top)?;
+    if quiet {
+        return Ok(());
+    }
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&stats)
+                .map_err(|_| BackupError::GenericError("Could not serialise the statistics"))?
+        );
+        return Ok(());
+    }
+    println!("Backup:            {}", stats.path);
+    if stats.listed > stats.files {
+        println!(
+            "Files:             {} ({} listed including previous backups)",
+            stats.files, stats.listed
+        );
+    } else {
+        println!("Files:             {}", stats.files);
+    }
+    println!("Uncompressed size: {}", format_size(stats.size));
+    println!("Compressed size:   {}", format_size(stats.compressed));
+    if let Some(ratio) = stats.ratio {
+        println!("Compression ratio: {:.2}", ratio);
+    }
+    if stats.unknown_size > 0 {
+        println!(
+            "({} files are missing sizes in the file list of this older backup)",
+            stats.unknown_size
+        );
+    }
+    if !stats.largest.is_empty() {
+        println!("\nLargest files:");
+        for f in stats.largest.iter() {
+            println!("{:>12}  {}", format_size(f.size), display_path(&f.path));
+        }
+    }
+    if !stats.groups.is_empty() {
+        match by {
+            StatsGroup::Extension => println!("\nSize by extension:"),
+            StatsGroup::Directory => println!("\nSize by directory:"),
+        }
+        for g in stats.groups.iter() {
+            println!(
+                "{:>12}  {} ({} files)",
+                format_size(g.size),
+                g.name,
+                g.files
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Show the chain of backups (a backup file shows the chain leading up to it)
+/// (the times are shown relative to now, unless `absolute`, except in the json)
+pub fn info(path: PathBuf, json: bool, absolute: bool, quiet: bool) -> Result<(), BackupError> {
+    let paths = match ConfigPathType::parse_read_only(path)? {
+        ConfigPathType::Backup(path) => {
+            let mut reader = BackupReader::new(path);
+            let mut paths = vec![reader.path.clone_path()];
+            while let Some(prev) = reader.get_previous()? {
+                reader = prev;
+                paths.push(reader.path.clone_path());
+            }
+            paths.reverse();
+            paths
+        }
+        ConfigPathType::Dir(path) => {
+            let paths = BackupIterator::dir(&path).get_all()?;
+            if paths.is_empty() {
+                return Err(BackupError::NoBackup(path));
+            }
+            paths
+        }
+        ConfigPathType::Config(path) => {
+            let config = Config::read_file(path)?;
+            let paths = config.get_backups().get_all()?;
+            if paths.is_empty() {
+                return Err(BackupError::NoBackup(config.get_dir()));
+            }
+            paths
+        }
+    };
+    let infos = paths
         .into_iter()
-        .flat_map(|p| BackupIterator::path(p).expect("Could not find backup"))
-        .map(|r| r.map(BackupReader::new))
-        .collect::<std::io::Result<Vec<BackupReader>>>()
-        .expect("Could not find backup");
-    let mut merger = BackupMerger::new(path, backups, all, delete, force, quality, threads)
-        .map_err(|(_, e)| e)
-        .expect("Could not read the backups");
+        .map(|p| BackupInfo::new(&mut BackupReader::new(p)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if quiet {
+        return Ok(());
+    }
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&infos)
+                .map_err(|_| BackupError::GenericError("Could not serialise the backup chain"))?
+        );
+        return Ok(());
+    }
+
+    let name = |p: &str| {
+        Path::new(p)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| p.to_string())
+    };
+    let label = |i: &BackupInfo| {
+        if i.legacy {
+            format!("{} (legacy)", name(&i.path))
+        } else {
+            name(&i.path)
+        }
+    };
+    let time = |i: &BackupInfo| match &i.time {
+        Some(t) if !absolute => NaiveDateTime::parse_from_str(t, "%Y-%m-%d %H:%M:%S")
+            .map_or_else(|_| t.clone(), format_relative),
+        Some(t) => t.clone(),
+        None => String::from("-"),
+    };
+    let width = infos
+        .iter()
+        .map(|i| label(i).len())
+        .max()
+        .unwrap_or_default()
+        .max(6);
+    let time_width = infos
+        .iter()
+        .map(|i| time(i).len())
+        .max()
+        .unwrap_or_default()
+        .max(4);
+    println!(
+        "{:<width$}  {:<time_width$}  {:<11}  {:>8}  {:>8}  {:>12}  {:>12}  {:>5}  Previous",
+        "Backup", "Time", "Type", "Files", "New", "Original", "Size", "Ratio"
+    );
+    for i in infos.iter() {
+        let previous = match (&i.previous, i.broken) {
+            (Some(p), false) => name(p),
+            (Some(p), true) => format!("{} (BROKEN: files are missing from the chain)", name(p)),
+            (None, false) => String::from("-"),
+            (None, true) => String::from("BROKEN: the previous backup is missing"),
+        };
+        let (original, ratio) = match i.size {
+            Some(0) => (format_size(0), String::from("-")),
+            Some(size) => (
+                format_size(size),
+                format!("{:.0}%", i.compressed as f64 * 100.0 / size as f64),
+            ),
+            None => (String::from("-"), String::from("-")),
+        };
+        println!(
+            "{:<width$}  {:<time_width$}  {:<11}  {:>8}  {:>8}  {:>12}  {:>12}  {:>5}  {}",
+            label(i),
+            time(i),
+            if i.incremental { "incremental" } else { "full" },
+            i.files,
+            i.new,
+            original,
+            format_size(i.compressed),
+            ratio,
+            previous
+        );
+    }
+    let broken = infos.iter().filter(|i| i.broken).count();
+    if broken > 0 {
+        eprintln!(
+            "\n{} backup(s) depend on files from a previous backup that is missing (these files cannot be restored)",
+            broken
+        );
+    }
+    Ok(())
+}
+
+/// Show the restorations recorded in a manifest (see [`crate::manifest`])
+pub fn restore_log(path: PathBuf, verbose: bool) -> Result<(), BackupError> {
+    let path = manifest_path(&path);
+    let records = read_manifest(&path)?;
+    for (i, r) in records.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        println!(
+            "{}  {}{}",
+            r.time
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            r.backup,
+            if r.partial { "  (INTERRUPTED)" } else { "" }
+        );
+        println!(
+            "  Restored: {}, Skipped: {}, Failed: {}",
+            r.count(RestoreOutcome::Restored),
+            r.count(RestoreOutcome::Skipped),
+            r.count(RestoreOutcome::Failed)
+        );
+        for f in r.files.iter() {
+            match (f.outcome, &f.error) {
+                (RestoreOutcome::Restored, _) if verbose => println!("  {}", f.path),
+                (RestoreOutcome::Restored, _) => {}
+                (outcome, Some(e)) => println!("  {:?}: {} ({})", outcome, f.entry, e),
+                (outcome, None) => println!("  {:?}: {}", outcome, f.entry),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Show how much unique data each backup in a chain contributes to the newest backup
+/// (backups without unique files can be deleted without losing any files from the newest backup)
+pub fn usage(source: BackupReader, format: OutputFormat, quiet: bool) -> Result<(), BackupError> {
+    let usage = ChainUsage::new(source)?;
+    if quiet {
+        return Ok(());
+    }
+    if format == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&usage)
+                .map_err(|_| BackupError::GenericError("Could not serialise the storage usage"))?
+        );
+        return Ok(());
+    }
+
+    let name = |p: &str| {
+        Path::new(p)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| p.to_string())
+    };
+    let width = usage
+        .backups
+        .iter()
+        .map(|b| name(&b.path).len())
+        .max()
+        .unwrap_or_default()
+        .max(6);
+    println!(
+        "{:<width$}  {:<19}  {:>8}  {:>12}  {:>8}  {:>12}  {:>12}",
+        "Backup", "Time", "Stored", "Stored size", "Unique", "Unique size", "Size"
+    );
+    for b in usage.backups.iter() {
+        println!(
+            "{:<width$}  {:<19}  {:>8}  {:>12}  {:>8}  {:>12}  {:>12}{}",
+            name(&b.path),
+            b.time.as_deref().unwrap_or("-"),
+            b.stored,
+            format_size(b.stored_size),
+            b.unique,
+            format_size(b.unique_size),
+            format_size(b.compressed),
+            if b.is_redundant() {
+                "  (no unique files)"
+            } else {
+                ""
+            }
+        );
+    }
+    if usage.missing > 0 {
+        eprintln!(
+            "\n{} of {} files in the newest backup are not stored in any backup in the chain",
+            usage.missing, usage.files
+        );
+    }
+    let redundant = usage.backups.iter().filter(|b| b.is_redundant()).count();
+    if redundant > 0 {
+        println!(
+            "\n{} backup(s) only contain files that are superseded by newer backups (deleting them \
+             does not lose any files from the newest backup)",
+            redundant
+        );
+    }
+    if !usage.consolidations.is_empty() {
+        println!("\nSuggested merges (estimated sizes):");
+        for c in usage.consolidations.iter() {
+            println!(
+                "  {} .. {} ({} backups): {} -> {}",
+                name(c.backups.first().unwrap()),
+                name(c.backups.last().unwrap()),
+                c.backups.len(),
+                format_size(c.compressed),
+                format_size(c.estimated)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// List the files in a backup that no longer exist on disk (e.g. to decide whether to make a new
+/// full backup instead of the next incremental backup)
+pub fn check(mut source: BackupReader, quiet: bool) -> Result<(), BackupError> {
+    let missing = source.find_missing()?;
+    if quiet {
+        return Ok(());
+    }
+    let total = source.get_list()?.iter().count();
+    for path in missing.iter() {
+        println!("{}", display_path(path));
+    }
+    eprintln!(
+        "{} of {} {} in '{}' no longer exist",
+        format_count(missing.len() as u64),
+        format_count(total as u64),
+        if total == 1 { "file" } else { "files" },
+        source.path.copy_string()
+    );
+    Ok(())
+}
+
+/// Browse a backup with shell-like commands (see [`crate::browse::BROWSE_COMMANDS`]),
+/// either interactively (from stdin) or by running a single `command` (for scripts)
+pub fn browse(source: BackupReader, command: Vec<String>) -> Result<(), BackupError> {
+    let mut browser = Browser::new(source)?;
+    let mut out = std::io::stdout();
+    if !command.is_empty() {
+        browser.run(&command, &mut out)?;
+        return Ok(());
+    }
+    eprintln!(
+        "Browsing {} files (type `help` for the commands, or `quit` to stop)",
+        browser.num_files()
+    );
+    let stdin = std::io::stdin();
+    loop {
+        eprint!("{}> ", browser.cwd());
+        let mut line = String::new();
+        if stdin.read_line(&mut line)? == 0 {
+            break;
+        }
+        match browser.run(&split_command(&line), &mut out) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => eprintln!("{}", e),
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Report files that are backed up by more than one config
+pub fn overlap(paths: Vec<PathBuf>, quiet: bool) -> Result<(), BackupError> {
+    let configs = paths
+        .iter()
+        .map(|p| Config::read_file(p.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let overlaps = find_config_overlaps(&configs)?;
+    if quiet {
+        return Ok(());
+    }
+    if overlaps.is_empty() {
+        println!("No overlapping coverage found");
+        return Ok(());
+    }
+    for co in overlaps.iter() {
+        let first = paths[co.first].to_string_lossy();
+        let second = paths[co.second].to_string_lossy();
+        println!(
+            "'{}' and '{}' both backup {} (estimated):",
+            first,
+            second,
+            format_size(co.size)
+        );
+        for o in co.overlaps.iter() {
+            let path = o.path.to_string_lossy();
+            match o.kind {
+                OverlapKind::Shared => println!(
+                    "  {}: included as '{}' in '{}' and as '{}' in '{}'",
+                    path, o.first, first, o.second, second
+                ),
+                OverlapKind::FirstContains => println!(
+                    "  {}: included as '{}' in '{}' and inside '{}' in '{}'",
+                    path, o.second, second, o.first, first
+                ),
+                OverlapKind::SecondContains => println!(
+                    "  {}: included as '{}' in '{}' and inside '{}' in '{}'",
+                    path, o.first, first, o.second, second
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compare two configs (optionally by how they classify the files in `sample`), and optionally
+/// copy the missing excludes and regexes from the first config into the second config
+pub fn config_diff(
+    first: PathBuf,
+    second: PathBuf,
+    sample: Option<PathBuf>,
+    copy_excludes: bool,
+    quiet: bool,
+) -> Result<(), BackupError> {
+    let a = Config::read_file(first.clone())?;
+    let mut b = Config::read_file(second.clone())?;
+    if !quiet {
+        let (first, second) = (first.to_string_lossy(), second.to_string_lossy());
+        let diff = a.diff(&b)?;
+        if diff.is_empty() {
+            println!("No differences in the configs");
+        }
+        for (kind, (only_a, only_b)) in [
+            ("Include", &diff.include),
+            ("Exclude", &diff.exclude),
+            ("Regex", &diff.regex),
+        ] {
+            for s in only_a.iter() {
+                println!("{} only in '{}': {}", kind, first, s);
+            }
+            for s in only_b.iter() {
+                println!("{} only in '{}': {}", kind, second, s);
+            }
+        }
+        for (name, va, vb) in diff.settings.iter() {
+            println!("Setting '{}' differs: {} vs {}", name, va, vb);
+        }
+        if let Some(dir) = sample {
+            let paths = sample_config_diff(&a, &b, &dir)?;
+            if paths.is_empty() {
+                println!("The configs treat the files in the sample the same");
+            }
+            for (path, in_a) in paths.iter() {
+                println!(
+                    "Backed up only by '{}': {}",
+                    if *in_a { &first } else { &second },
+                    path.to_string_lossy()
+                );
+            }
+        }
+    }
+    if copy_excludes {
+        let added = b.copy_excludes(&a)?;
+        if added == 0 {
+            if !quiet {
+                println!("No excludes to copy");
+            }
+            return Ok(());
+        }
+        check_config(&b, quiet)?;
+        // Write to a temporary file first, so that the config is never partially written
+        let tmp = extend_pathbuf(second.clone(), ".tmp");
+        std::fs::write(&tmp, b.as_format(ConfigFormat::from_path(&second))?)
+            .map_err(BackupError::WriteError)?;
+        std::fs::rename(&tmp, &second).map_err(|e| {
+            BackupError::RenameError(
+                tmp.to_string_lossy().to_string(),
+                second.to_string_lossy().to_string(),
+                e,
+            )
+        })?;
+        if !quiet {
+            println!(
+                "Copied {} excludes to '{}'",
+                added,
+                second.to_string_lossy()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Merge backups (see [`MergeOptions`] for the options)
+pub fn merge(backups: Vec<PathBuf>, options: &MergeOptions) -> Result<(), BackupError> {
+    let MergeOptions {
+        output,
+        all,
+        delete,
+        quality,
+        threads,
+        strict,
+        journal,
+        after,
+        before,
+        verbose,
+        force,
+        dry,
+        quiet,
+        progress,
+        cancel,
+    } = options.clone();
+    let path = output.map(Path::to_path_buf);
+    let mut dirs: Vec<PathBuf> = backups
+        .iter()
+        .chain(path.iter())
+        .map(|p| if p.is_dir() { p.clone() } else { parent_dir(p) })
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    if !dry {
+        dirs.iter().for_each(|d| recover_journals(d));
+    }
+    let mut selected = vec![];
+    let mut excluded = vec![];
+    for p in backups {
+        let (inside, outside) = BackupIterator::path(p)?.split_range(after, before)?;
+        selected.extend(inside);
+        excluded.extend(outside);
+    }
+    if verbose {
+        eprintln!("Backups to merge:");
+        for p in selected.iter() {
+            eprintln!("  {}", p.to_string_lossy());
+        }
+        if !excluded.is_empty() {
+            eprintln!("Backups outside the time range:");
+            for p in excluded.iter() {
+                eprintln!("  {}", p.to_string_lossy());
+            }
+        }
+        eprintln!();
+    }
+    let readers = selected.into_iter().map(BackupReader::new).collect();
+    let mut merger = BackupMerger::new(path, readers, all, delete, force, quality, threads, strict)
+        .map_err(|(_, e)| e)?;
+    merger.journal = journal;
     let count;
     if verbose {
         eprintln!("Files in the merged backup:");
@@ -327,7 +1682,7 @@ pub fn merge(
             .files
             .iter()
             .filter(|(b, f)| {
-                println!("{}", f.copy_string());
+                println!("{}", display_path(&f.copy_string()));
                 *b
             })
             .count();
@@ -340,39 +1695,155 @@ pub fn merge(
         count = merger.files.iter().filter(|(b, _)| *b).count();
     }
     if dry {
-        return;
+        return Ok(());
     }
 
-    let bar = if quiet {
-        ProgressBar::hidden()
-    } else {
-        ProgressBar::new(count as u64 + 1)
-    };
-    bar.set_style(ProgressStyle::default_bar().template(
+    let bar = Progress::new(
+        progress,
+        quiet,
+        "merge",
+        count as u64 + 1,
+        0,
         "{wide_msg} {pos:>8} / {len:<8}\n{wide_bar} {elapsed_precise:>8} / {duration_precise:<8}",
-    ).expect("The progressbar template is wrong!"));
-    bar.set_message("Merging backups...");
-    bar.tick();
-    bar.enable_steady_tick(Duration::from_secs(1));
+    );
+    let _guard = bar.guard();
+    bar.start("Merging backups...");
 
-    merger
+    let mut missing = vec![];
+    let stats = merger
         .write(
             |fi: &mut FileInfo, err| {
                 bar.set_message(fi.move_string());
-                bar.inc(1);
-                if let Err(e) = err {
-                    bar.println(format!(
-                        "Could not add '{}' to the backup: {}",
-                        fi.get_string(),
-                        e
-                    ));
+                bar.inc_files(1);
+                match err {
+                    Err(BackupError::MissingEntry(s)) => missing.push(s),
+                    Err(e) => {
+                        log::warn!("Could not add '{}' to the backup: {}", fi.get_string(), e)
+                    }
+                    Ok(_) => {}
+                }
+                match cancel {
+                    Some(c) if c.load(Ordering::Relaxed) => Err(BackupError::Cancel),
+                    _ => Ok(()),
                 }
-                Ok(())
             },
-            || bar.set_message("Waiting for the compression to complete..."),
+            |_| (),
+            || bar.set_phase("finalize", "Waiting for the compression to complete..."),
         )
-        .expect("Could not merge the backups");
-    bar.disable_steady_tick();
-    bar.set_message("Merge complete!");
-    bar.finish();
+        .inspect_err(|_| bar.abandon())?;
+    bar.finish("Merge complete!");
+    if !quiet {
+        eprintln!("Merged {}", stats);
+    }
+
+    if !missing.is_empty() {
+        if strict {
+            eprintln!(
+                "{} listed files were missing from all backups (and removed from the list):",
+                missing.len()
+            );
+        } else {
+            eprintln!(
+                "{} listed files were missing from all backups:",
+                missing.len()
+            );
+        }
+        for s in missing {
+            eprintln!("  {}", s);
+        }
+    }
+    Ok(())
+}
+
+/// Delete superseded (`.old`) backups that are older than `age`, but only if the backup that
+/// replaced them can be found (following merges of merges) and passes a quick check
+pub fn prune_old_files(
+    source: PathBuf,
+    age: Duration,
+    verbose: bool,
+    dry: bool,
+    quiet: bool,
+) -> Result<(), BackupError> {
+    let dir = match ConfigPathType::parse_read_only(source)? {
+        ConfigPathType::Dir(p) => p,
+        ConfigPathType::Config(p) => Config::read_file(p)?.get_dir(),
+        ConfigPathType::Backup(p) => parent_dir(&p),
+    };
+    if !dry {
+        recover_journals(&dir);
+    }
+    let now = std::time::SystemTime::now();
+    let mut deleted = 0;
+    for old in get_superseded_backups(&dir)? {
+        let name = old.to_string_lossy();
+        let modified = old.metadata().and_then(|md| md.modified())?;
+        if now.duration_since(modified).unwrap_or_default() < age {
+            if verbose {
+                eprintln!("Keeping '{}': it is not old enough", name);
+            }
+            continue;
+        }
+        // Follow the merges until reaching a backup that has not been superseded
+        let mut current = old.clone();
+        let mut merged = None;
+        for _ in 0..100 {
+            match find_superseding(&current) {
+                Some((m, time)) => {
+                    merged.get_or_insert(time);
+                    current = m;
+                    if !is_superseded_backup(&current) {
+                        break;
+                    }
+                }
+                None => {
+                    merged = None;
+                    break;
+                }
+            }
+        }
+        let time = match merged {
+            Some(t) if current != old && !is_superseded_backup(&current) => t,
+            _ => {
+                if !quiet {
+                    eprintln!(
+                        "Keeping '{}': could not find the backup it was merged into",
+                        name
+                    );
+                }
+                continue;
+            }
+        };
+        if let Err(e) = BackupReader::new(current.clone()).quick_check() {
+            if !quiet {
+                eprintln!(
+                    "Keeping '{}': the merged backup '{}' failed the check: {}",
+                    name,
+                    current.to_string_lossy(),
+                    e
+                );
+            }
+            continue;
+        }
+        if !dry {
+            std::fs::remove_file(&old).map_err(BackupError::DeleteError)?;
+        }
+        deleted += 1;
+        if verbose || dry {
+            println!(
+                "{} '{}' (superseded by merge on {}, now in '{}')",
+                if dry { "Would delete" } else { "Deleted" },
+                name,
+                time.format("%Y-%m-%d %H:%M:%S"),
+                current.to_string_lossy()
+            );
+        }
+    }
+    if !quiet {
+        eprintln!(
+            "{} {} superseded backups",
+            if dry { "Would delete" } else { "Deleted" },
+            deleted
+        );
+    }
+    Ok(())
 }
@@ -0,0 +1,317 @@
+/// This module contains the commands whose output is stored in the backup ("virtual includes"),
+/// and the `metadata_command` that adds user-defined metadata to the files in the list
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// The directory (in the backup) where the outputs of the commands are stored
+pub const COMMANDS_DIR: &str = "_commands";
+
+/// How often a running command is checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How many paths are given to the `metadata_command` at once
+pub const METADATA_BATCH: usize = 1000;
+/// The metadata of a file is limited to this many bytes (the pairs that do not fit are dropped)
+pub const METADATA_MAX_LEN: usize = 1024;
+/// The file (in the output directory) where the metadata of the restored files is written
+pub const METADATA_SIDECAR: &str = "simple_backup_metadata.tsv";
+
+/// A command whose output (stdout) is stored in the backup as `_commands/<name>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupCommand {
+    pub name: String,
+    /// The command is run with `sh -c` (or `cmd /C` on Windows)
+    pub cmd: String,
+    /// Kill the command after this many seconds (zero means no limit)
+    #[serde(default)]
+    pub timeout_s: u64,
+}
+
+impl BackupCommand {
+    pub fn new<S1: Into<String>, S2: Into<String>>(name: S1, cmd: S2, timeout_s: u64) -> Self {
+        Self {
+            name: name.into(),
+            cmd: cmd.into(),
+            timeout_s,
+        }
+    }
+
+    /// The path of the output in the backup (and when restoring)
+    pub fn path(&self) -> PathBuf {
+        Path::new(COMMANDS_DIR).join(&self.name)
+    }
+
+    /// The name must be a plain file name (since it is used as a path)
+    pub fn has_valid_name(&self) -> bool {
+        !self.name.is_empty()
+            && self.name != "."
+            && self.name != ".."
+            && !self.name.contains(['/', '\\'])
+    }
+
+    /// Run the command, writing the output to `output` and returning the size of the output.
+    /// The error describes why the command failed (e.g. the exit status or a timeout).
+    pub fn run(&self, output: &Path) -> Result<u64, String> {
+        let file = File::create(output).map_err(|e| e.to_string())?;
+        let mut child = shell(&self.cmd)
+            .stdin(Stdio::null())
+            .stdout(file)
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("could not start the command: {}", e))?;
+        let start = Instant::now();
+        let timeout = Duration::from_secs(self.timeout_s);
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if self.timeout_s > 0 && start.elapsed() >= timeout => {
+                    #[allow(unused_must_use)]
+                    {
+                        child.kill();
+                        child.wait();
+                    }
+                    return Err(format!("timed out after {} seconds", self.timeout_s));
+                }
+                Ok(None) => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => return Err(e.to_string()),
+            }
+        };
+        if !status.success() {
+            return Err(format!("exited with {}", status));
+        }
+        std::fs::metadata(output)
+            .map(|m| m.len())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Run the `metadata_command` for the paths (`batch` paths at a time), returning the
+/// `key=value;key=value` pairs of the paths that have metadata. The command reads the paths from
+/// stdin (one per line) and prints `path<TAB>key=value;key=value` lines. Failures are only logged
+/// (the files in a failed batch get no metadata).
+pub fn collect_metadata<S: AsRef<str>>(
+    cmd: &str,
+    paths: &[S],
+    batch: usize,
+) -> HashMap<String, String> {
+    let mut meta = HashMap::new();
+    for chunk in paths.chunks(batch.max(1)) {
+        match run_with_input(cmd, chunk) {
+            Ok(output) => {
+                let expected: HashSet<&str> = chunk.iter().map(|s| s.as_ref()).collect();
+                let (batch_meta, problems) = parse_metadata(&output, Some(&expected));
+                for problem in problems {
+                    log::warn!("Metadata command: {}", problem);
+                }
+                meta.extend(batch_meta);
+            }
+            Err(reason) => log::warn!(
+                "The metadata command failed ({}), {} files have no metadata",
+                reason,
+                chunk.len()
+            ),
+        }
+    }
+    meta
+}
+
+/// Run a command with the paths on stdin (one per line), returning the output
+fn run_with_input<S: AsRef<str>>(cmd: &str, paths: &[S]) -> Result<String, String> {
+    let mut child = shell(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("could not start the command: {}", e))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input: String = paths.iter().map(|p| format!("{}\n", p.as_ref())).collect();
+    // Written from another thread, so that a command printing while reading cannot block
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    // The command is allowed to exit without reading all paths
+    #[allow(unused_must_use)]
+    writer.join();
+    if !output.status.success() {
+        return Err(format!("exited with {}", output.status));
+    }
+    String::from_utf8(output.stdout).map_err(|_| "the output is not valid UTF-8".to_string())
+}
+
+/// Parse `path<TAB>key=value;key=value` lines (the output of the `metadata_command` and the
+/// [`METADATA_SIDECAR`]), returning the metadata of each path and the problems found. With
+/// `expected` the other paths are skipped. Lines for the same path are combined, and pairs beyond
+/// [`METADATA_MAX_LEN`] are dropped.
+pub fn parse_metadata(
+    output: &str,
+    expected: Option<&HashSet<&str>>,
+) -> (HashMap<String, String>, Vec<String>) {
+    let mut meta: HashMap<String, String> = HashMap::new();
+    let mut problems = vec![];
+    for (i, line) in output.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Paths may contain tabs, but the pairs cannot
+        let (path, pairs) = match line.rsplit_once('\t') {
+            Some(split) => split,
+            None => {
+                problems.push(format!("line {}: expected `path<TAB>key=value;...`", i + 1));
+                continue;
+            }
+        };
+        if expected.is_some_and(|e| !e.contains(path)) {
+            problems.push(format!(
+                "line {}: '{}' was not given to the command",
+                i + 1,
+                path
+            ));
+            continue;
+        }
+        let entry = meta.entry(path.to_string()).or_default();
+        for pair in pairs.split(';').filter(|p| !p.is_empty()) {
+            match pair.split_once('=') {
+                Some((key, _)) if !key.is_empty() => {
+                    if entry.len() + pair.len() + usize::from(!entry.is_empty()) > METADATA_MAX_LEN
+                    {
+                        problems.push(format!(
+                            "line {}: the metadata of '{}' is longer than {} bytes (the rest is dropped)",
+                            i + 1,
+                            path,
+                            METADATA_MAX_LEN
+                        ));
+                        break;
+                    }
+                    if !entry.is_empty() {
+                        entry.push(';');
+                    }
+                    entry.push_str(pair);
+                }
+                _ => problems.push(format!(
+                    "line {}: '{}' is not a key=value pair",
+                    i + 1,
+                    pair
+                )),
+            }
+        }
+    }
+    meta.retain(|_, m| !m.is_empty());
+    (meta, problems)
+}
+
+/// Get a value from `key=value;key=value` pairs (the last one if the key is repeated)
+pub fn metadata_value<'a>(meta: &'a str, key: &str) -> Option<&'a str> {
+    meta.split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .filter(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+        .next_back()
+}
+
+/// Format the metadata as `path<TAB>key=value;key=value` lines (see [`parse_metadata`])
+pub fn format_metadata<'a>(entries: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    entries
+        .into_iter()
+        .map(|(path, meta)| format!("{}\t{}\n", path, meta))
+        .collect()
+}
+
+#[cfg(target_os = "windows")]
+fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(cmd);
+    command
+}
+
+#[cfg(not(target_os = "windows"))]
+fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tempfile::tempdir;
+
+    use super::{
+        collect_metadata, format_metadata, metadata_value, parse_metadata, BackupCommand,
+        METADATA_MAX_LEN,
+    };
+
+    #[test]
+    fn run() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out");
+        let command = BackupCommand::new("hello", "echo hello", 0);
+        assert!(command.has_valid_name());
+        assert!(command.run(&out).unwrap() >= 5);
+        assert!(std::fs::read_to_string(&out).unwrap().starts_with("hello"));
+        assert!(BackupCommand::new("fail", "exit 3", 0).run(&out).is_err());
+        assert!(!BackupCommand::new("../x", "echo", 0).has_valid_name());
+        assert!(!BackupCommand::new("", "echo", 0).has_valid_name());
+    }
+
+    #[test]
+    fn parse_lines() {
+        let expected: HashSet<&str> = ["a", "b\tc", "d"].into_iter().collect();
+        let output =
+            "a\towner=anna;review=done\r\n\nb\tc\tk=v=w;;bad;=x\nz\tk=v\nno tab\na\textra=1\n";
+        let (meta, problems) = parse_metadata(output, Some(&expected));
+        assert_eq!(meta["a"], "owner=anna;review=done;extra=1");
+        assert_eq!(meta["b\tc"], "k=v=w");
+        assert_eq!(meta.len(), 2);
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].contains("'bad'"));
+        assert!(problems[2].contains("'z'"));
+        assert!(problems[3].starts_with("line 5"));
+
+        // The pairs that do not fit are dropped
+        let long = format!("a\tk={};x=1\n", "v".repeat(METADATA_MAX_LEN - 2));
+        let (meta, problems) = parse_metadata(&long, None);
+        assert_eq!(meta["a"].len(), METADATA_MAX_LEN);
+        assert_eq!(problems.len(), 1);
+        let (meta, _) = parse_metadata(&format!("a\tk={}\n", "v".repeat(METADATA_MAX_LEN)), None);
+        assert!(meta.is_empty());
+
+        assert_eq!(metadata_value("a=1;b=2;a=3", "a"), Some("3"));
+        assert_eq!(metadata_value("a=1;b=x=y", "b"), Some("x=y"));
+        assert_eq!(metadata_value("a=1", "c"), None);
+        let formatted = format_metadata([("x/y", "a=1"), ("z", "b=2;c=3")]);
+        assert_eq!(formatted, "x/y\ta=1\nz\tb=2;c=3\n");
+        assert_eq!(parse_metadata(&formatted, None).0["z"], "b=2;c=3");
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn metadata_batches() {
+        let dir = tempdir().unwrap();
+        let calls = dir.path().join("calls");
+        let cmd = format!(
+            "echo x >> '{}'; while IFS= read -r p; do printf '%s\\tn=%s\\n' \"$p\" \"$p\"; done",
+            calls.to_string_lossy()
+        );
+        let paths: Vec<String> = (0..7).map(|i| format!("/f{}", i)).collect();
+        let meta = collect_metadata(&cmd, &paths, 3);
+        assert_eq!(meta.len(), 7);
+        assert_eq!(meta["/f5"], "n=/f5");
+        assert_eq!(std::fs::read_to_string(&calls).unwrap().lines().count(), 3);
+
+        // Failing batches are skipped (without failing the others)
+        let cmd = "while IFS= read -r p; do [ \"$p\" = /f4 ] && exit 3; printf '%s\\tk=v\\n' \"$p\"; done";
+        let meta = collect_metadata(cmd, &paths, 3);
+        let mut keys: Vec<_> = meta.keys().map(|k| k.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, ["/f0", "/f1", "/f2", "/f6"]);
+        assert!(collect_metadata("exit 1", &paths, 100).is_empty());
+        assert!(collect_metadata("true", &paths, 100).is_empty());
+        assert!(collect_metadata("printf 'x\\0\\377\\n'", &paths, 100).is_empty());
+    }
+}
@@ -1,186 +1,1700 @@
-/// This module contains the objects for handling compressed archive files
-use std::fmt::Debug;
-use std::fs::{create_dir_all, remove_file, File};
-use std::io::BufReader;
-use std::path::{Path, PathBuf};
-
-use path_clean::PathClean;
-use tar::{Archive, Builder, Entry, Header};
-use zstd::{Decoder, Encoder};
-
-use crate::files::FileInfo;
-
-pub struct CompressionEncoder<'a>(Builder<Encoder<'a, File>>);
-
-impl CompressionEncoder<'_> {
-    /// Create a compressed archive
-    pub fn create<P: AsRef<Path>>(path: P, quality: i32, threads: u32) -> std::io::Result<Self> {
-        if let Some(p) = path.as_ref().parent() {
-            create_dir_all(p)?;
-        }
-        let file = File::create(&path)?;
-        let cleanup = |err| {
-            remove_file(&path).unwrap_or_default();
-            err
-        };
-        let mut encoder = Encoder::new(file, quality).map_err(cleanup)?;
-        encoder.multithread(threads).map_err(cleanup)?;
-        let archive = Builder::new(encoder);
-        Ok(CompressionEncoder(archive))
-    }
-
-    /// Finnish compressing the archive and close the file
-    pub fn close(self) -> std::io::Result<()> {
-        self.0.into_inner()?.finish()?.sync_all()?;
-        Ok(())
-    }
-
-    /// Add a file to the compressed archive
-    pub fn append_file(&mut self, file: &PathBuf) -> std::io::Result<()> {
-        let name = path_to_archive(file);
-        self.0.append_path_with_name(file, name)
-    }
-
-    /// Add raw data as a file to the compressed archive
-    pub fn append_data<P: AsRef<Path>, B: AsRef<[u8]>>(
-        &mut self,
-        name: P,
-        content: B,
-    ) -> std::io::Result<()> {
-        let content = content.as_ref();
-        let mut header = Header::new_gnu();
-        header.set_size(content.len() as u64);
-        self.0.append_data(&mut header, &name, content)
-    }
-
-    pub fn append_entry(
-        &mut self,
-        entry: Entry<'_, Decoder<'_, BufReader<File>>>,
-    ) -> std::io::Result<()> {
-        let mut head = entry.header().clone();
-        let path = entry.path()?.to_path_buf();
-        self.0.append_data(&mut head, path, entry)
-    }
-}
-
-pub type CompressionDecoderEntry<'dummy, 'a> =
-    (FileInfo, Entry<'dummy, Decoder<'a, BufReader<File>>>);
-pub struct CompressionDecoder<'a>(Archive<Decoder<'a, BufReader<File>>>);
-
-impl Debug for CompressionDecoder<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("CompressionDecoder").finish()
-    }
-}
-
-impl<'a> CompressionDecoder<'a> {
-    /// Read a compressed archive
-    pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
-        let file = File::open(&path)?;
-        let decoder = Decoder::new(file)?;
-        let mut archive = Archive::new(decoder);
-        archive.set_unpack_xattrs(true);
-        archive.set_preserve_permissions(true);
-        archive.set_preserve_mtime(true);
-        archive.set_overwrite(true);
-        Ok(Self(archive))
-    }
-
-    /// Iterate over the files in the compressed archive
-    pub fn entries(
-        &mut self,
-    ) -> std::io::Result<impl Iterator<Item = std::io::Result<CompressionDecoderEntry<'_, 'a>>>>
-    {
-        Ok(self.0.entries()?.map(|entry| {
-            let entry = entry?;
-            let path = entry.path()?;
-            Ok((path_from_archive(&path), entry))
-        }))
-    }
-}
-
-/// Encode a path for adding to a tar archive
-#[cfg(target_os = "windows")]
-fn path_to_archive(path: &PathBuf) -> String {
-    if path.has_root() {
-        "abs".to_string() + &path.to_string_lossy().replace('\\', "/")
-    } else {
-        "rel/".to_string() + &path.clean().to_string_lossy().replace('\\', "/")
-    }
-}
-
-/// Encode a path for adding to a tar archive
-#[cfg(not(target_os = "windows"))]
-fn path_to_archive(path: &PathBuf) -> String {
-    if path.has_root() {
-        "abs".to_string() + &path.to_string_lossy()
-    } else {
-        "rel/".to_string() + &path.clean().to_string_lossy()
-    }
-}
-
-/// Decode a path from a tar archive
-fn path_from_archive<P: AsRef<Path>>(path: P) -> FileInfo {
-    let path = path.as_ref();
-    let string = path.to_string_lossy();
-    if let Some(s) = string.strip_prefix("rel/") {
-        FileInfo::from(s.to_string())
-    } else if let Some(s) = string.strip_prefix("abs") {
-        FileInfo::from(s.to_string())
-    } else if string == "rel" {
-        FileInfo::from(".")
-    } else {
-        FileInfo::from(path)
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::io::Cursor;
-    use std::path::PathBuf;
-
-    use path_absolutize::Absolutize;
-    use tar::{Archive, Builder, Header};
-
-    use super::{path_from_archive, path_to_archive};
-
-    #[test]
-    fn paths_abs() {
-        let dir = PathBuf::from(".").absolutize().unwrap().to_path_buf();
-        let pta = path_to_archive(&dir);
-        let out = path_from_archive(PathBuf::from(&pta)).consume_path();
-        assert_eq!(dir, out);
-
-        let tmp: Vec<u8> = vec![];
-        let mut tar = Builder::new(tmp);
-        let mut header = Header::new_gnu();
-        header.set_size(2);
-        tar.append_data(&mut header, pta, "ab".as_bytes()).unwrap();
-        let tmp = tar.into_inner().unwrap();
-        let mut tar = Archive::new(Cursor::new(tmp));
-        let entry = tar.entries().unwrap().next().unwrap().unwrap();
-        let pia = entry.header().path().unwrap();
-        let out = path_from_archive(&pia).consume_path();
-        assert_eq!(dir, out);
-    }
-
-    #[test]
-    fn paths_rel() {
-        let dir = PathBuf::from(".");
-        let pta = path_to_archive(&dir);
-        let out = path_from_archive(PathBuf::from(&pta)).consume_path();
-        assert_eq!(dir, out);
-
-        let tmp: Vec<u8> = vec![];
-        let mut tar = Builder::new(tmp);
-        let mut header = Header::new_gnu();
-        header.set_size(2);
-        tar.append_data(&mut header, pta, "ab".as_bytes()).unwrap();
-        let tmp = tar.into_inner().unwrap();
-        let mut tar = Archive::new(Cursor::new(tmp));
-        let entry = tar.entries().unwrap().next().unwrap().unwrap();
-        let pia = entry.header().path().unwrap();
-        let out = path_from_archive(&pia).consume_path();
-        assert_eq!(dir, out);
-    }
-}
+use std::collections::HashSet;
+use std::ffi::OsString;
+/// This module contains the objects for handling compressed archive files
+use std::fmt::Debug;
+use std::fs::{create_dir_all, remove_file, File, Metadata};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use path_clean::PathClean;
+use serde::{Deserialize, Serialize};
+use tar::{Archive, Builder, Entry, EntryType, Header};
+use zstd::{Decoder, Encoder};
+
+use crate::files::{
+    path_to_string, string_to_path, DeltaManifest, FileInfo, MetadataUpdate, DELTA_BLOCK_SIZE,
+};
+use crate::txn::Transaction;
+use crate::utils::long_path;
+use crate::xattrs::{self, from_pax_key, pax_key, MacosXattrs, STORE_XATTRS};
+
+/// How many bytes to read between the progress callbacks
+const PROGRESS_INTERVAL: u64 = 1 << 20;
+
+/// The size of the chunks that are decompressed ahead of the reading (when using threads)
+const READ_AHEAD_CHUNK: u64 = 1 << 20;
+
+/// The compression level for already compressed files (higher levels would not shrink them further)
+pub const STORE_LEVEL: i32 = 1;
+
+/// Already compressed files smaller than this do not switch the compression level
+pub(crate) const STORE_MIN_SIZE: u64 = 1 << 16;
+
+/// The prefix of the (pax) extensions of this program (these are kept when copying entries)
+const OWN_PAX_PREFIX: &str = "SIMPLE_BACKUP.";
+
+/// The (pax) extension that marks a hard link entry as a duplicate
+const DUPLICATE_KEY: &str = "SIMPLE_BACKUP.duplicate";
+
+/// The (pax) extension that marks an entry as only the changed blocks of a file (the value is
+/// the [`DeltaManifest`])
+const DELTA_KEY: &str = "SIMPLE_BACKUP.delta";
+
+/// The largest size that fits in the (octal) size field of a ustar header
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+/// How many (compressed) bytes of a stream are kept for reading it again (see [`StreamSource`])
+const STREAM_REPLAY_LIMIT: usize = 256 << 20;
+
+/// The format of the tar headers in new archives (archives in both formats can be read)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TarFormat {
+    /// POSIX (ustar) headers, with pax extensions for long paths and large files
+    #[default]
+    Pax,
+    /// GNU headers, with extra GNU entries for long paths (the format of older backups)
+    Gnu,
+}
+
+impl TarFormat {
+    fn header(self) -> Header {
+        match self {
+            TarFormat::Pax => Header::new_ustar(),
+            TarFormat::Gnu => Header::new_gnu(),
+        }
+    }
+}
+
+/// Reader that reports the number of read bytes (in batches) to a callback
+struct ProgressReader<R: Read, F: FnMut(u64)> {
+    inner: R,
+    pending: u64,
+    on_progress: F,
+}
+
+impl<R: Read, F: FnMut(u64)> ProgressReader<R, F> {
+    fn new(inner: R, on_progress: F) -> Self {
+        Self {
+            inner,
+            pending: 0,
+            on_progress,
+        }
+    }
+}
+
+impl<R: Read, F: FnMut(u64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pending += n as u64;
+        if (n == 0 && self.pending > 0) || self.pending >= PROGRESS_INTERVAL {
+            (self.on_progress)(self.pending);
+            self.pending = 0;
+        }
+        Ok(n)
+    }
+}
+
+/// How many times to retry (after waiting) when reading a file fails (the wait doubles after every retry)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Retry {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl Retry {
+    pub fn new(retries: u32, delay: Duration) -> Self {
+        Self { retries, delay }
+    }
+
+    /// How long to wait before a retry (the first retry is `attempt = 0`)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.delay.saturating_mul(1 << attempt.min(16))
+    }
+}
+
+/// Errors that might go away when trying again (e.g. a timeout on a network drive)
+pub fn is_transient(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::Interrupted
+            | std::io::ErrorKind::TimedOut
+            | std::io::ErrorKind::WouldBlock
+    )
+}
+
+/// Reader that retries reads that fail with transient errors (see [`is_transient`]),
+/// a failed read does not advance the file, so the archive stays consistent
+struct RetryReader<R: Read> {
+    inner: R,
+    retry: Retry,
+}
+
+impl<R: Read> Read for RetryReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut attempt = 0;
+        loop {
+            match self.inner.read(buf) {
+                Err(e) if attempt < self.retry.retries && is_transient(&e) => {
+                    log::info!("Retrying a read that failed: {}", e);
+                    std::thread::sleep(self.retry.backoff(attempt));
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for RetryReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// The path to a volume of a multi-volume archive (volumes are numbered from 1)
+pub fn volume_path<P: AsRef<Path>>(path: P, index: usize) -> PathBuf {
+    let mut p: OsString = path.as_ref().into();
+    p.push(format!(".{:03}", index));
+    p.into()
+}
+
+/// Get the archive path from the path to the first volume (`*.001`)
+pub fn volume_base<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    let path = path.as_ref();
+    let name = path.file_name()?.to_str()?;
+    let base = name.strip_suffix(".001")?;
+    if base.is_empty() {
+        None
+    } else {
+        Some(path.with_file_name(base))
+    }
+}
+
+/// The (consecutively numbered) volumes of an archive that exist on disk
+pub fn volume_parts<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
+    (1..)
+        .map(|i| volume_path(&path, i))
+        .take_while(|p| p.is_file())
+        .collect()
+}
+
+/// Is the archive split into multiple volumes (instead of being a single file)
+pub fn is_multi_volume<P: AsRef<Path>>(path: P) -> bool {
+    !path.as_ref().is_file() && volume_path(path, 1).is_file()
+}
+
+/// The files that make up an archive (either the file itself or the volumes)
+fn archive_parts<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    if !path.is_file() {
+        let parts = volume_parts(path);
+        if !parts.is_empty() {
+            return Ok(parts);
+        }
+    }
+    path.metadata()?;
+    Ok(vec![path.to_path_buf()])
+}
+
+/// Does the archive exist (as a single file or as volumes)
+pub fn archive_exists<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().exists() || volume_path(path, 1).is_file()
+}
+
+/// The metadata of the archive (or of the first volume)
+pub fn archive_metadata<P: AsRef<Path>>(path: P) -> std::io::Result<Metadata> {
+    archive_parts(path)?[0].metadata()
+}
+
+/// The total size of the archive (including all volumes)
+pub fn archive_size<P: AsRef<Path>>(path: P) -> std::io::Result<u64> {
+    archive_parts(path)?
+        .iter()
+        .try_fold(0, |sum, p| Ok(sum + p.metadata()?.len()))
+}
+
+/// Remove the volumes of an archive (except for the first `keep`)
+fn remove_volumes<P: AsRef<Path>>(path: P, keep: usize) -> std::io::Result<()> {
+    volume_parts(path)
+        .iter()
+        .skip(keep)
+        .try_for_each(remove_file)
+}
+
+/// Remove an archive (including all volumes)
+pub fn remove_archive<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    let path = path.as_ref();
+    if path.is_file() {
+        remove_file(path)?;
+    }
+    remove_volumes(path, 0)
+}
+
+/// Rename an archive (including all volumes), replacing any archive at the destination
+#[allow(unused)]
+pub fn rename_archive<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> std::io::Result<()> {
+    Ok(rename_archive_txn(from, to).commit(None)?)
+}
+
+/// The renames (and deletes) for renaming an archive, see [`rename_archive`]
+pub fn rename_archive_txn<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Transaction {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let mut txn = Transaction::new();
+    if from.is_file() {
+        txn.rename(from, to);
+        for p in volume_parts(to) {
+            txn.delete(p);
+        }
+    } else {
+        let parts = volume_parts(from);
+        if parts.is_empty() {
+            txn.rename(from, to);
+            return txn;
+        }
+        for (i, p) in parts.iter().enumerate() {
+            txn.rename(p, volume_path(to, i + 1));
+        }
+        if to.is_file() {
+            txn.delete(to);
+        }
+        for p in volume_parts(to).into_iter().skip(parts.len()) {
+            txn.delete(p);
+        }
+    }
+    txn
+}
+
+/// Writer that splits the output into volumes (`*.001`, `*.002`, ...) when it grows beyond the limit.
+/// Output that fits within the limit is kept as a single (unnumbered) file.
+pub struct VolumeWriter {
+    path: PathBuf,
+    limit: Option<u64>,
+    file: File,
+    written: u64,
+    volumes: usize,
+}
+
+impl VolumeWriter {
+    pub fn create<P: AsRef<Path>>(path: P, limit: Option<u64>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        // Stale volumes would otherwise be read as part of the new archive
+        remove_volumes(&path, 0)?;
+        let file = File::create(&path)?;
+        Ok(Self {
+            path,
+            limit: limit.filter(|l| *l > 0),
+            file,
+            written: 0,
+            volumes: 0,
+        })
+    }
+
+    fn next_volume(&mut self) -> std::io::Result<()> {
+        self.file.sync_all()?;
+        if self.volumes == 0 {
+            self.volumes = 1;
+        }
+        let next = File::create(volume_path(&self.path, self.volumes + 1))?;
+        // The file has to be closed before renaming (on Windows)
+        std::mem::drop(std::mem::replace(&mut self.file, next));
+        if self.volumes == 1 {
+            std::fs::rename(&self.path, volume_path(&self.path, 1))?;
+        }
+        self.volumes += 1;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Flush and sync the last volume
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.limit {
+            None => self.file.write(buf),
+            Some(limit) => {
+                if self.written >= limit {
+                    self.next_volume()?;
+                }
+                let len = buf.len().min((limit - self.written) as usize);
+                let n = self.file.write(&buf[..len])?;
+                self.written += n as u64;
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Reader that concatenates the volumes of an archive
+pub struct VolumeReader {
+    parts: Vec<PathBuf>,
+    index: usize,
+    file: File,
+}
+
+impl VolumeReader {
+    /// Open an archive (either a single file or the volumes)
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let parts = archive_parts(path)?;
+        let file = File::open(&parts[0])?;
+        Ok(Self {
+            parts,
+            index: 0,
+            file,
+        })
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.file.read(buf)?;
+            if n > 0 || buf.is_empty() || self.index + 1 >= self.parts.len() {
+                return Ok(n);
+            }
+            self.index += 1;
+            self.file = File::open(&self.parts[self.index])?;
+        }
+    }
+}
+
+/// The output of an archive (files on disk, or a stream such as stdout)
+pub enum ArchiveWriter {
+    Volumes(VolumeWriter),
+    Stream(Box<dyn Write + Send>),
+}
+
+impl ArchiveWriter {
+    /// Flush (and sync) the output
+    pub fn finish(self) -> std::io::Result<()> {
+        match self {
+            Self::Volumes(volumes) => volumes.finish(),
+            Self::Stream(mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Volumes(volumes) => volumes.write(buf),
+            Self::Stream(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Volumes(volumes) => volumes.flush(),
+            Self::Stream(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A stream (such as stdin) that can be read from the start twice: the bytes read by the first
+/// reader (normally only the config and the file list) are kept and replayed to the second reader,
+/// which then continues with the rest of the stream
+#[derive(Clone)]
+pub struct StreamSource(Arc<Mutex<SharedStream>>);
+
+struct SharedStream {
+    inner: Box<dyn Read + Send>,
+    kept: Vec<u8>,
+    /// The first reader read more than [`STREAM_REPLAY_LIMIT`] (so the stream cannot be replayed)
+    overflow: bool,
+    /// The number of opened readers (only the latest can read)
+    readers: usize,
+}
+
+impl StreamSource {
+    pub fn new<R: Read + Send + 'static>(inner: R) -> Self {
+        Self(Arc::new(Mutex::new(SharedStream {
+            inner: Box::new(inner),
+            kept: vec![],
+            overflow: false,
+            readers: 0,
+        })))
+    }
+
+    /// Read the stream from the start (this fails for the third reader, and for the second if
+    /// the first read too much to be kept)
+    pub fn open(&self) -> std::io::Result<StreamReader> {
+        let mut shared = self.0.lock().map_err(|_| poisoned_stream())?;
+        if shared.readers >= 2 || (shared.readers == 1 && shared.overflow) {
+            return Err(std::io::Error::other(
+                "A backup from a stream can only be read once (after the config and file list)",
+            ));
+        }
+        shared.readers += 1;
+        Ok(StreamReader {
+            source: self.0.clone(),
+            id: shared.readers,
+            pos: 0,
+        })
+    }
+}
+
+fn poisoned_stream() -> std::io::Error {
+    std::io::Error::other("The stream was poisoned by a crashed reader")
+}
+
+/// A reader from a [`StreamSource`]
+pub struct StreamReader {
+    source: Arc<Mutex<SharedStream>>,
+    id: usize,
+    pos: usize,
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut shared = self.source.lock().map_err(|_| poisoned_stream())?;
+        if shared.readers != self.id {
+            return Err(std::io::Error::other("The stream has been opened again"));
+        }
+        if self.id > 1 && self.pos < shared.kept.len() {
+            let len = buf.len().min(shared.kept.len() - self.pos);
+            buf[..len].copy_from_slice(&shared.kept[self.pos..self.pos + len]);
+            self.pos += len;
+            if self.pos == shared.kept.len() {
+                // The replay is complete (and the stream is never read again)
+                shared.kept = vec![];
+                self.pos = 0;
+            }
+            return Ok(len);
+        }
+        let len = shared.inner.read(buf)?;
+        if self.id == 1 && !shared.overflow {
+            if shared.kept.len() + len > STREAM_REPLAY_LIMIT {
+                shared.overflow = true;
+                shared.kept = vec![];
+            } else {
+                shared.kept.extend_from_slice(&buf[..len]);
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// A file that has been opened for adding to an archive
+/// (nothing is written to the archive if opening fails, so it is safe to retry)
+pub struct SourceFile {
+    meta: Metadata,
+    file: Option<File>,
+    /// The content, if the file has been read into memory (see [`Self::preload`])
+    pub(crate) content: Option<Vec<u8>>,
+}
+
+impl SourceFile {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let path = long_path(path);
+        let meta = std::fs::metadata(&path)?;
+        let file = if meta.is_file() {
+            Some(File::open(&path)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            meta,
+            file,
+            content: None,
+        })
+    }
+
+    /// Read the content of the file into memory (see [`crate::read_ahead`])
+    pub fn preload(mut self, retry: Retry) -> std::io::Result<Self> {
+        if let Some(f) = self.file.take() {
+            let mut content = Vec::with_capacity(self.meta.len() as usize);
+            RetryReader { inner: f, retry }
+                .take(self.meta.len())
+                .read_to_end(&mut content)?;
+            self.content = Some(content);
+        }
+        Ok(self)
+    }
+}
+
+/// Reader for the listed blocks of a file (in order), see [`CompressionEncoder::append_delta`]
+struct BlockReader<R: Read + Seek> {
+    file: R,
+    blocks: std::vec::IntoIter<u64>,
+    size: u64,
+    /// The bytes left in the current block
+    remaining: u64,
+}
+
+impl<R: Read + Seek> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.remaining == 0 {
+            let block = match self.blocks.next() {
+                Some(block) => block,
+                None => return Ok(0),
+            };
+            let start = block * DELTA_BLOCK_SIZE;
+            self.remaining = self.size.saturating_sub(start).min(DELTA_BLOCK_SIZE);
+            self.file.seek(SeekFrom::Start(start))?;
+        }
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.file.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "The file was shortened while reading the changed blocks",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Writer that compresses into consecutive zstd frames, so that the level can change between files
+/// (the decoder reads the concatenated frames as one stream)
+struct FrameEncoder<'a> {
+    encoder: Option<Encoder<'a, ArchiveWriter>>,
+    level: i32,
+    threads: u32,
+}
+
+impl<'a> FrameEncoder<'a> {
+    fn new(file: ArchiveWriter, level: i32, threads: u32) -> std::io::Result<Self> {
+        Ok(Self {
+            encoder: Some(Self::start(file, level, threads)?),
+            level,
+            threads,
+        })
+    }
+
+    fn start(
+        file: ArchiveWriter,
+        level: i32,
+        threads: u32,
+    ) -> std::io::Result<Encoder<'a, ArchiveWriter>> {
+        let mut encoder = Encoder::new(file, level)?;
+        encoder.multithread(threads)?;
+        Ok(encoder)
+    }
+
+    fn encoder(&mut self) -> std::io::Result<&mut Encoder<'a, ArchiveWriter>> {
+        self.encoder
+            .as_mut()
+            .ok_or_else(|| std::io::Error::other("The compression could not be restarted"))
+    }
+
+    /// Finish the current frame and continue with a new level (if it differs)
+    fn set_level(&mut self, level: i32) -> std::io::Result<()> {
+        if level != self.level {
+            if let Some(encoder) = self.encoder.take() {
+                self.encoder = Some(Self::start(encoder.finish()?, level, self.threads)?);
+                self.level = level;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<ArchiveWriter> {
+        self.encoder.take().map_or_else(
+            || {
+                Err(std::io::Error::other(
+                    "The compression could not be restarted",
+                ))
+            },
+            |e| e.finish(),
+        )
+    }
+}
+
+impl Write for FrameEncoder<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.encoder()?.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.encoder()?.flush()
+    }
+}
+
+/// Writer for compressed archives
+/// (already compressed files, see [`CompressionEncoder::store_only`], use a fast level)
+pub struct CompressionEncoder<'a> {
+    archive: Builder<FrameEncoder<'a>>,
+    quality: i32,
+    store_only: HashSet<String>,
+    xattrs: Option<MacosXattrs>,
+    format: TarFormat,
+    /// The pax extensions for the next entry (an entry can only have one pax header)
+    extensions: Vec<(String, Vec<u8>)>,
+    retry: Retry,
+}
+
+impl CompressionEncoder<'_> {
+    /// Create a compressed archive
+    /// (split into volumes of `volume_size` bytes, see [`VolumeWriter`])
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        quality: i32,
+        threads: u32,
+        volume_size: Option<u64>,
+    ) -> std::io::Result<Self> {
+        if let Some(p) = path.as_ref().parent() {
+            create_dir_all(p)?;
+        }
+        let file = VolumeWriter::create(&path, volume_size)?;
+        Self::new(ArchiveWriter::Volumes(file), quality, threads)
+            .inspect_err(|_| remove_file(&path).unwrap_or_default())
+    }
+
+    /// Compress an archive into a stream (e.g. stdout)
+    pub fn from_writer<W: Write + Send + 'static>(
+        writer: W,
+        quality: i32,
+        threads: u32,
+    ) -> std::io::Result<Self> {
+        Self::new(ArchiveWriter::Stream(Box::new(writer)), quality, threads)
+    }
+
+    fn new(output: ArchiveWriter, quality: i32, threads: u32) -> std::io::Result<Self> {
+        Ok(CompressionEncoder {
+            archive: Builder::new(FrameEncoder::new(output, quality, threads)?),
+            quality,
+            store_only: HashSet::new(),
+            xattrs: None,
+            format: TarFormat::default(),
+            extensions: vec![],
+            retry: Retry::default(),
+        })
+    }
+
+    /// Compress files with these extensions (case insensitive) with [`STORE_LEVEL`]
+    pub fn store_only<I: IntoIterator<Item = S>, S: AsRef<str>>(mut self, extensions: I) -> Self {
+        self.store_only = extensions
+            .into_iter()
+            .map(|e| e.as_ref().trim_start_matches('.').to_lowercase())
+            .collect();
+        self
+    }
+
+    /// Store the extended attributes of added files (according to the policy, only on macOS)
+    pub fn macos_xattrs(mut self, policy: MacosXattrs) -> Self {
+        self.xattrs = STORE_XATTRS.then_some(policy);
+        self
+    }
+
+    /// Write the headers in this format (see [`TarFormat`])
+    pub fn tar_format(mut self, format: TarFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Retry the reads of added files that fail with transient errors (see [`Retry`])
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Add the extended attributes of a file (as pax extensions for the next entry)
+    fn append_xattrs(&mut self, file: &Path) {
+        if let Some(policy) = self.xattrs.as_ref() {
+            // Files with unreadable attributes are still backed up
+            let attrs = policy.stored(xattrs::read(file).unwrap_or_default());
+            self.extensions
+                .extend(attrs.into_iter().map(|(n, v)| (pax_key(&n), v)));
+        }
+    }
+
+    /// Add an entry (after the pending pax extensions)
+    fn append_header<R: Read>(
+        &mut self,
+        header: &mut Header,
+        path: &Path,
+        link: Option<&Path>,
+        data: R,
+    ) -> std::io::Result<()> {
+        let extensions = std::mem::take(&mut self.extensions);
+        append_with_format(
+            &mut self.archive,
+            self.format,
+            header,
+            path,
+            link,
+            extensions,
+            data,
+        )
+    }
+
+    fn is_store_only(&self, path: &Path) -> bool {
+        !self.store_only.is_empty()
+            && path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| self.store_only.contains(&e.to_lowercase()))
+    }
+
+    /// Switch the level before adding a file (small already compressed files keep the current level,
+    /// since every switch ends a zstd frame)
+    fn select_level(&mut self, path: &Path, size: u64) -> std::io::Result<()> {
+        if !self.is_store_only(path) {
+            self.archive.get_mut().set_level(self.quality)
+        } else if size >= STORE_MIN_SIZE {
+            self.archive
+                .get_mut()
+                .set_level(STORE_LEVEL.min(self.quality))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Finnish compressing the archive and close the file
+    pub fn close(self) -> std::io::Result<()> {
+        self.archive.into_inner()?.finish()?.finish()?;
+        Ok(())
+    }
+
+    /// Add a file to the compressed archive (reporting the number of compressed bytes along the way)
+    #[allow(unused)]
+    pub fn append_file(
+        &mut self,
+        file: &PathBuf,
+        on_progress: impl FnMut(u64),
+    ) -> std::io::Result<()> {
+        self.append_source(file, SourceFile::open(file)?, on_progress)
+    }
+
+    /// Add an already opened file to the compressed archive (reporting the number of compressed bytes along the way)
+    pub fn append_source(
+        &mut self,
+        file: &PathBuf,
+        source: SourceFile,
+        on_progress: impl FnMut(u64),
+    ) -> std::io::Result<()> {
+        let name = path_to_archive(file);
+        self.append_xattrs(file);
+        match (source.file, source.content) {
+            (None, None) => {
+                let meta = std::fs::symlink_metadata(long_path(file))?;
+                let link = if meta.file_type().is_symlink() {
+                    Some(std::fs::read_link(long_path(file))?)
+                } else {
+                    None
+                };
+                if self.format == TarFormat::Gnu || !(meta.is_dir() || link.is_some()) {
+                    // Special files (and the GNU format) are handled by the tar crate
+                    let extensions = std::mem::take(&mut self.extensions);
+                    if !extensions.is_empty() {
+                        self.archive.append_pax_extensions(
+                            extensions.iter().map(|(k, v)| (k.as_str(), v.as_slice())),
+                        )?;
+                    }
+                    self.archive.append_path_with_name(file, name)
+                } else {
+                    let mut header = self.format.header();
+                    header.set_metadata(&meta);
+                    self.append_header(
+                        &mut header,
+                        Path::new(&name),
+                        link.as_deref(),
+                        std::io::empty(),
+                    )
+                }
+            }
+            (Some(f), _) => {
+                self.select_level(file, source.meta.len())?;
+                let mut header = self.format.header();
+                header.set_metadata(&source.meta);
+                let reader = RetryReader {
+                    inner: f,
+                    retry: self.retry,
+                }
+                .take(source.meta.len());
+                self.append_header(
+                    &mut header,
+                    Path::new(&name),
+                    None,
+                    ProgressReader::new(reader, on_progress),
+                )
+            }
+            (None, Some(content)) => {
+                self.select_level(file, source.meta.len())?;
+                let mut header = self.format.header();
+                header.set_metadata(&source.meta);
+                self.append_header(
+                    &mut header,
+                    Path::new(&name),
+                    None,
+                    ProgressReader::new(content.as_slice(), on_progress),
+                )
+            }
+        }
+    }
+
+    /// Add the content of `content` as `file` (e.g. the output of a command), with the current
+    /// time and default permissions (reporting the number of compressed bytes along the way)
+    pub fn append_generated(
+        &mut self,
+        file: &PathBuf,
+        content: &Path,
+        on_progress: impl FnMut(u64),
+    ) -> std::io::Result<()> {
+        let source = File::open(content)?;
+        let size = source.metadata()?.len();
+        self.select_level(file, size)?;
+        let mut header = self.format.header();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+        self.append_header(
+            &mut header,
+            &path_to_archive(file),
+            None,
+            ProgressReader::new(source.take(size), on_progress),
+        )
+    }
+
+    /// Add raw data as a file to the compressed archive
+    pub fn append_data<P: AsRef<Path>, B: AsRef<[u8]>>(
+        &mut self,
+        name: P,
+        content: B,
+    ) -> std::io::Result<()> {
+        let content = content.as_ref();
+        let mut header = self.format.header();
+        header.set_size(content.len() as u64);
+        self.append_header(&mut header, name.as_ref(), None, content)
+    }
+
+    /// Add a hard link to a file that has already been added to the compressed archive
+    pub fn append_hard_link(&mut self, file: &PathBuf, target: &PathBuf) -> std::io::Result<()> {
+        let mut header = self.format.header();
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        self.append_header(
+            &mut header,
+            &path_to_archive(file),
+            Some(&path_to_archive(target)),
+            std::io::empty(),
+        )
+    }
+
+    /// Add a file with the same content as an already added file (`target`).
+    /// The file is stored as a hard link that is marked as a duplicate (so that it is restored as a copy).
+    pub fn append_duplicate(&mut self, file: &PathBuf, target: &PathBuf) -> std::io::Result<()> {
+        let mut header = self.format.header();
+        header.set_metadata(&file.metadata()?);
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        self.extensions
+            .push((DUPLICATE_KEY.to_string(), b"1".to_vec()));
+        self.append_header(
+            &mut header,
+            &path_to_archive(file),
+            Some(&path_to_archive(target)),
+            std::io::empty(),
+        )
+    }
+
+    /// Add the changed blocks of a file (listed in the `manifest`) instead of the whole file
+    /// (reporting the number of compressed bytes along the way)
+    pub fn append_delta(
+        &mut self,
+        file: &PathBuf,
+        source: SourceFile,
+        manifest: &DeltaManifest,
+        on_progress: impl FnMut(u64),
+    ) -> std::io::Result<()> {
+        let f = source.file.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Only files can be stored as blocks",
+            )
+        })?;
+        let size = manifest.stored_size();
+        self.select_level(file, size)?;
+        self.append_xattrs(file);
+        self.extensions
+            .push((DELTA_KEY.to_string(), manifest.to_string().into_bytes()));
+        let mut header = self.format.header();
+        header.set_metadata(&source.meta);
+        header.set_size(size);
+        let reader = BlockReader {
+            file: RetryReader {
+                inner: f,
+                retry: self.retry,
+            },
+            blocks: manifest.blocks.clone().into_iter(),
+            size: manifest.size,
+            remaining: 0,
+        };
+        self.append_header(
+            &mut header,
+            &path_to_archive(file),
+            None,
+            ProgressReader::new(reader.take(size), on_progress),
+        )
+    }
+
+    /// Copy an entry from another archive, but with the content of `content` (e.g. a file that
+    /// was stored as changed blocks, after restoring the whole file)
+    pub fn append_entry_with_content(
+        &mut self,
+        mut entry: Entry<'_, ArchiveReader<'_>>,
+        content: &Path,
+        on_progress: impl FnMut(u64),
+    ) -> std::io::Result<()> {
+        let path = entry.path()?.to_path_buf();
+        let source = File::open(content)?;
+        let size = source.metadata()?.len();
+        let mut head = entry.header().clone();
+        head.set_size(size);
+        self.extensions.extend(
+            own_extensions(&mut entry)
+                .into_iter()
+                .filter(|(k, _)| k != DELTA_KEY),
+        );
+        self.select_level(&path, size)?;
+        self.append_header(
+            &mut head,
+            &path,
+            None,
+            ProgressReader::new(source.take(size), on_progress),
+        )
+    }
+
+    /// Copy an entry from another archive (reporting the number of copied bytes along the way)
+    pub fn append_entry(
+        &mut self,
+        entry: Entry<'_, ArchiveReader<'_>>,
+        on_progress: impl FnMut(u64),
+    ) -> std::io::Result<()> {
+        let path = entry.path()?.to_path_buf();
+        self.append_entry_as(entry, path, None, on_progress)
+    }
+
+    /// Copy an entry from another archive, but with newer permissions and owner
+    pub fn append_entry_updated(
+        &mut self,
+        entry: Entry<'_, ArchiveReader<'_>>,
+        update: &MetadataUpdate,
+        on_progress: impl FnMut(u64),
+    ) -> std::io::Result<()> {
+        let path = entry.path()?.to_path_buf();
+        self.append_entry_as(entry, path, Some(update), on_progress)
+    }
+
+    /// Copy an entry from another archive with a new (already encoded) name
+    fn append_entry_as(
+        &mut self,
+        mut entry: Entry<'_, ArchiveReader<'_>>,
+        path: PathBuf,
+        update: Option<&MetadataUpdate>,
+        on_progress: impl FnMut(u64),
+    ) -> std::io::Result<()> {
+        let mut head = entry.header().clone();
+        if let Some(update) = update {
+            head.set_mode(update.mode);
+            head.set_uid(update.uid as u64);
+            head.set_gid(update.gid as u64);
+        }
+        self.extensions.extend(own_extensions(&mut entry));
+        if let Some(target) = entry.link_name()? {
+            // Long link names are not stored in the header (so they have to be added again)
+            let target = target.to_path_buf();
+            return self.append_header(&mut head, &path, Some(&target), std::io::empty());
+        }
+        // The size might only be stored in a pax extension
+        head.set_size(entry.size());
+        self.select_level(&path, entry.size())?;
+        self.append_header(
+            &mut head,
+            &path,
+            None,
+            ProgressReader::new(entry, on_progress),
+        )
+    }
+
+    /// Copy the content of an entry from another archive, but store it as `file`
+    /// (e.g. to replace a hard link whose target is not copied)
+    pub fn append_entry_renamed(
+        &mut self,
+        entry: Entry<'_, ArchiveReader<'_>>,
+        file: &PathBuf,
+        on_progress: impl FnMut(u64),
+    ) -> std::io::Result<()> {
+        self.append_entry_as(entry, path_to_archive(file), None, on_progress)
+    }
+}
+
+/// Add an entry to an archive. In the [`TarFormat::Pax`] format the paths and sizes that do not
+/// fit in the header are stored as pax extensions (together with the other `extensions`), while
+/// the [`TarFormat::Gnu`] format uses the GNU extensions of the tar crate.
+fn append_with_format<W: Write, R: Read>(
+    archive: &mut Builder<W>,
+    format: TarFormat,
+    header: &mut Header,
+    path: &Path,
+    link: Option<&Path>,
+    mut extensions: Vec<(String, Vec<u8>)>,
+    data: R,
+) -> std::io::Result<()> {
+    if format == TarFormat::Pax {
+        if header.set_path(path).is_err() {
+            extensions.push(("path".to_string(), path_bytes(path)));
+            header.set_path(truncate_path(path))?;
+        }
+        if let Some(link) = link {
+            if header.set_link_name(link).is_err() {
+                extensions.push(("linkpath".to_string(), path_bytes(link)));
+                header.set_link_name(truncate_path(link))?;
+            }
+        }
+        let size = header.size()?;
+        if size > USTAR_MAX_SIZE {
+            extensions.push(("size".to_string(), size.to_string().into_bytes()));
+        }
+    }
+    if !extensions.is_empty() {
+        archive
+            .append_pax_extensions(extensions.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+    }
+    match (format, link) {
+        (TarFormat::Gnu, Some(link)) => archive.append_link(header, path, link),
+        (TarFormat::Gnu, None) => archive.append_data(header, path, data),
+        (TarFormat::Pax, _) => {
+            header.set_cksum();
+            archive.append(header, data)
+        }
+    }
+}
+
+/// The path as stored in a pax extension
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+/// The path as stored in a pax extension
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().replace('\\', "/").into_bytes()
+}
+
+/// The start of a long path (for the header, the full path is in a pax extension)
+fn truncate_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let mut end = path.len().min(100);
+    while !path.is_char_boundary(end) {
+        end -= 1;
+    }
+    path[..end].to_string()
+}
+
+/// Is the (hard link) entry a duplicate of another file, see [`CompressionEncoder::append_duplicate`]
+pub fn is_duplicate<R: Read>(entry: &mut Entry<'_, R>) -> bool {
+    match entry.pax_extensions() {
+        Ok(Some(mut extensions)) => {
+            extensions.any(|e| e.is_ok_and(|e| e.key() == Ok(DUPLICATE_KEY)))
+        }
+        _ => false,
+    }
+}
+
+/// How the entry stores the changed blocks of a file, see [`CompressionEncoder::append_delta`]
+/// (`None` if the entry is the whole file)
+pub fn delta_manifest<R: Read>(entry: &mut Entry<'_, R>) -> Option<DeltaManifest> {
+    let mut extensions = entry.pax_extensions().ok()??;
+    let value = extensions.find_map(|e| e.ok().filter(|e| e.key() == Ok(DELTA_KEY)))?;
+    DeltaManifest::parse(value.value().ok()?)
+}
+
+/// The (pax) extensions of this program on an entry, e.g. [`DUPLICATE_KEY`]
+fn own_extensions<R: Read>(entry: &mut Entry<'_, R>) -> Vec<(String, Vec<u8>)> {
+    match entry.pax_extensions() {
+        Ok(Some(extensions)) => extensions
+            .filter_map(|e| e.ok())
+            .filter_map(|e| Some((e.key().ok()?.to_string(), e.value_bytes().to_vec())))
+            .filter(|(k, _)| k.starts_with(OWN_PAX_PREFIX))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// The extended attributes that are stored with an entry, see [`CompressionEncoder::macos_xattrs`]
+pub fn entry_xattrs<R: Read>(entry: &mut Entry<'_, R>) -> Vec<(String, Vec<u8>)> {
+    own_extensions(entry)
+        .into_iter()
+        .filter_map(|(k, v)| Some((from_pax_key(&k)?.to_string(), v)))
+        .collect()
+}
+
+/// Unpack an entry like [`Entry::unpack`], but the content of regular files is written while
+/// reporting the progress (`on_progress` is called with the number of newly unpacked bytes).
+/// Like the tar crate, an existing file is replaced (instead of written over, since it might be a
+/// link) and the modification time and permissions are restored.
+pub fn unpack_with_progress<R: Read>(
+    entry: &mut Entry<'_, R>,
+    path: &Path,
+    on_progress: impl FnMut(u64),
+) -> std::io::Result<()> {
+    if entry.header().entry_type() != EntryType::Regular {
+        entry.unpack(path)?;
+        return Ok(());
+    }
+    let (mode, mtime) = (entry.header().mode(), entry.header().mtime());
+    let open = || {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+    };
+    let mut file = match open() {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            remove_file(path)?;
+            open()?
+        }
+        res => res?,
+    };
+    std::io::copy(&mut ProgressReader::new(entry, on_progress), &mut file)?;
+    if let Ok(mtime) = mtime {
+        // The tar crate avoids files with a zero time (since some tools have problems with them)
+        let time = std::time::UNIX_EPOCH + Duration::from_secs(mtime.max(1));
+        file.set_modified(time)?;
+    }
+    if let Ok(mode) = mode {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // Like the tar crate (without `preserve_permissions`) the special bits are dropped
+            file.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+        #[cfg(not(unix))]
+        {
+            let mut permissions = file.metadata()?.permissions();
+            permissions.set_readonly(mode & 0o200 != 0o200);
+            file.set_permissions(permissions)?;
+        }
+    }
+    Ok(())
+}
+
+/// The file that a hard link entry refers to (or `None` if the entry is not a hard link)
+pub fn hard_link_target<R: Read>(entry: &Entry<'_, R>) -> Option<FileInfo> {
+    if entry.header().entry_type().is_hard_link() {
+        entry
+            .link_name()
+            .ok()
+            .flatten()
+            .map(|p| path_from_archive(p.as_ref()))
+    } else {
+        None
+    }
+}
+
+/// Reader that decompresses in a separate thread, so that the decompression is not waiting for
+/// the restored files to be written (zstd itself can only decompress a frame on one thread)
+pub struct ReadAheadReader {
+    queue: Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadAheadReader {
+    /// Decompress up to `chunks` chunks ahead of the reading
+    fn new<R: Read + Send + 'static>(mut inner: R, chunks: usize) -> Self {
+        let (send, queue) = sync_channel(chunks);
+        std::thread::spawn(move || loop {
+            let mut chunk = Vec::with_capacity(READ_AHEAD_CHUNK as usize);
+            match inner
+                .by_ref()
+                .take(READ_AHEAD_CHUNK)
+                .read_to_end(&mut chunk)
+            {
+                Ok(0) => break,
+                res => {
+                    let failed = res.is_err();
+                    // Stop when the reader is dropped (or after an error)
+                    if send.send(res.map(|_| chunk)).is_err() || failed {
+                        break;
+                    }
+                }
+            }
+        });
+        Self {
+            queue,
+            chunk: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ReadAheadReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos >= self.chunk.len() {
+            match self.queue.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                // The decompression thread has reached the end
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// The compressed input of an archive (files on disk, or a stream such as stdin)
+pub enum ArchiveInput {
+    Volumes(VolumeReader),
+    Stream(StreamReader),
+}
+
+impl Read for ArchiveInput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Volumes(volumes) => volumes.read(buf),
+            Self::Stream(stream) => stream.read(buf),
+        }
+    }
+}
+
+/// The decompressed stream of the archive (optionally decompressed in a separate thread)
+pub enum ArchiveReader<'a> {
+    Direct(Decoder<'a, BufReader<ArchiveInput>>),
+    ReadAhead(ReadAheadReader),
+}
+
+impl Read for ArchiveReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Direct(decoder) => decoder.read(buf),
+            Self::ReadAhead(reader) => reader.read(buf),
+        }
+    }
+}
+
+pub type CompressionDecoderEntry<'dummy, 'a> = (FileInfo, Entry<'dummy, ArchiveReader<'a>>);
+pub struct CompressionDecoder<'a>(Archive<ArchiveReader<'a>>);
+
+impl Debug for CompressionDecoder<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompressionDecoder").finish()
+    }
+}
+
+impl<'a> CompressionDecoder<'a> {
+    /// Read a compressed archive (the volumes are read in order if the archive is split)
+    /// (with more than one thread the archive is decompressed in a separate thread)
+    pub fn read<P: AsRef<Path>>(path: P, threads: u32) -> std::io::Result<Self> {
+        Self::new(ArchiveInput::Volumes(VolumeReader::open(&path)?), threads)
+    }
+
+    /// Read a compressed archive from a stream (see [`StreamSource`])
+    pub fn from_stream(stream: StreamReader, threads: u32) -> std::io::Result<Self> {
+        Self::new(ArchiveInput::Stream(stream), threads)
+    }
+
+    fn new(input: ArchiveInput, threads: u32) -> std::io::Result<Self> {
+        let decoder = Decoder::new(input)?;
+        let reader = if threads > 1 {
+            ArchiveReader::ReadAhead(ReadAheadReader::new(decoder, threads as usize))
+        } else {
+            ArchiveReader::Direct(decoder)
+        };
+        let mut archive = Archive::new(reader);
+        archive.set_unpack_xattrs(true);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.set_overwrite(true);
+        Ok(Self(archive))
+    }
+
+    /// Iterate over the files in the compressed archive
+    pub fn entries(
+        &mut self,
+    ) -> std::io::Result<impl Iterator<Item = std::io::Result<CompressionDecoderEntry<'_, 'a>>>>
+    {
+        Ok(self.0.entries()?.map(|entry| {
+            let entry = entry?;
+            let path = entry.path()?;
+            Ok((path_from_archive(&path), entry))
+        }))
+    }
+}
+
+/// Encode a path for adding to a tar archive
+#[cfg(target_os = "windows")]
+fn path_to_archive(path: &PathBuf) -> PathBuf {
+    if path.has_root() {
+        string_to_path(&("abs".to_string() + &path.to_string_lossy().replace('\\', "/")))
+    } else {
+        string_to_path(&("rel/".to_string() + &path.clean().to_string_lossy().replace('\\', "/")))
+    }
+}
+
+/// Encode a path for adding to a tar archive (file names that are not valid UTF-8 keep the bytes)
+#[cfg(not(target_os = "windows"))]
+fn path_to_archive(path: &PathBuf) -> PathBuf {
+    if path.has_root() {
+        string_to_path(&("abs".to_string() + &path_to_string(path)))
+    } else {
+        string_to_path(&("rel/".to_string() + &path_to_string(&path.clean())))
+    }
+}
+
+/// Decode a path from a tar archive
+fn path_from_archive<P: AsRef<Path>>(path: P) -> FileInfo {
+    let path = path.as_ref();
+    let string = path_to_string(path);
+    if let Some(s) = string.strip_prefix("rel/") {
+        FileInfo::from(s.to_string())
+    } else if let Some(s) = string.strip_prefix("abs") {
+        FileInfo::from(s.to_string())
+    } else if string == "rel" {
+        FileInfo::from(".")
+    } else {
+        FileInfo::from(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, ErrorKind, Read};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use path_absolutize::Absolutize;
+    use tar::{Archive, Builder, Header};
+
+    use super::{
+        append_with_format, archive_size, hard_link_target, is_multi_volume, path_from_archive,
+        path_to_archive, remove_archive, rename_archive, volume_base, volume_parts, volume_path,
+        CompressionEncoder, ReadAheadReader, Retry, RetryReader, StreamSource, TarFormat,
+        PROGRESS_INTERVAL, READ_AHEAD_CHUNK, STORE_MIN_SIZE,
+    };
+    use crate::compression::CompressionDecoder;
+
+    #[test]
+    fn paths_abs() {
+        let dir = PathBuf::from(".").absolutize().unwrap().to_path_buf();
+        let pta = path_to_archive(&dir);
+        let out = path_from_archive(PathBuf::from(&pta)).consume_path();
+        assert_eq!(dir, out);
+
+        let tmp: Vec<u8> = vec![];
+        let mut tar = Builder::new(tmp);
+        let mut header = Header::new_gnu();
+        header.set_size(2);
+        tar.append_data(&mut header, pta, "ab".as_bytes()).unwrap();
+        let tmp = tar.into_inner().unwrap();
+        let mut tar = Archive::new(Cursor::new(tmp));
+        let entry = tar.entries().unwrap().next().unwrap().unwrap();
+        let pia = entry.header().path().unwrap();
+        let out = path_from_archive(&pia).consume_path();
+        assert_eq!(dir, out);
+    }
+
+    #[test]
+    fn paths_rel() {
+        let dir = PathBuf::from(".");
+        let pta = path_to_archive(&dir);
+        let out = path_from_archive(PathBuf::from(&pta)).consume_path();
+        assert_eq!(dir, out);
+
+        let tmp: Vec<u8> = vec![];
+        let mut tar = Builder::new(tmp);
+        let mut header = Header::new_gnu();
+        header.set_size(2);
+        tar.append_data(&mut header, pta, "ab".as_bytes()).unwrap();
+        let tmp = tar.into_inner().unwrap();
+        let mut tar = Archive::new(Cursor::new(tmp));
+        let entry = tar.entries().unwrap().next().unwrap().unwrap();
+        let pia = entry.header().path().unwrap();
+        let out = path_from_archive(&pia).consume_path();
+        assert_eq!(dir, out);
+    }
+
+    #[test]
+    fn progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file.bin");
+        let size = PROGRESS_INTERVAL * 2 + 123;
+        std::fs::write(&file, vec![7u8; size as usize]).unwrap();
+        let archive = dir.path().join("archive.tar.zst");
+        let mut encoder = CompressionEncoder::create(&archive, 1, 1, None).unwrap();
+        let mut calls = vec![];
+        encoder.append_file(&file, |b| calls.push(b)).unwrap();
+        encoder.close().unwrap();
+        assert_eq!(calls.iter().sum::<u64>(), size);
+        assert!(calls.len() >= 3);
+
+        let mut decoder = CompressionDecoder::read(&archive, 1).unwrap();
+        let (mut fi, entry) = decoder.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(fi.get_path(), &file);
+        assert_eq!(entry.header().size().unwrap(), size);
+    }
+
+    #[test]
+    fn volumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file.bin");
+        // Pseudo-random content so that the archive does not compress well
+        let mut x = 1u32;
+        let content: Vec<u8> = (0..50_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        std::fs::write(&file, &content).unwrap();
+        let archive = dir.path().join("archive.tar.zst");
+        let mut encoder = CompressionEncoder::create(&archive, 1, 1, Some(10_000)).unwrap();
+        encoder.append_file(&file, |_| ()).unwrap();
+        encoder.close().unwrap();
+
+        assert!(!archive.exists());
+        assert!(is_multi_volume(&archive));
+        let parts = volume_parts(&archive);
+        assert!(parts.len() >= 5);
+        assert_eq!(parts[0], volume_path(&archive, 1));
+        assert_eq!(volume_base(&parts[0]), Some(archive.clone()));
+        assert_eq!(volume_base(&parts[1]), None);
+        for p in parts[..parts.len() - 1].iter() {
+            assert_eq!(p.metadata().unwrap().len(), 10_000);
+        }
+        assert!(archive_size(&archive).unwrap() > content.len() as u64);
+
+        let mut decoder = CompressionDecoder::read(&archive, 1).unwrap();
+        let (mut fi, mut entry) = decoder.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(fi.get_path(), &file);
+        let mut out = vec![];
+        std::io::Read::read_to_end(&mut entry, &mut out).unwrap();
+        assert_eq!(out, content);
+        std::mem::drop(decoder);
+
+        // Small archives are not split
+        let small = dir.path().join("small.tar.zst");
+        let mut encoder = CompressionEncoder::create(&small, 1, 1, Some(1_000_000)).unwrap();
+        encoder.append_file(&file, |_| ()).unwrap();
+        encoder.close().unwrap();
+        assert!(small.is_file());
+        assert!(!is_multi_volume(&small));
+
+        // Replacing a single file with volumes leaves no stale files
+        rename_archive(&archive, &small).unwrap();
+        assert!(!small.exists());
+        assert_eq!(volume_parts(&small).len(), parts.len());
+        assert!(volume_parts(&archive).is_empty());
+        remove_archive(&small).unwrap();
+        assert!(volume_parts(&small).is_empty());
+    }
+
+    #[test]
+    fn read_ahead() {
+        let content: Vec<u8> = (0..READ_AHEAD_CHUNK * 5 / 2).map(|i| i as u8).collect();
+        let mut reader = ReadAheadReader::new(Cursor::new(content.clone()), 2);
+        let mut out = vec![];
+        std::io::Read::read_to_end(&mut reader, &mut out).unwrap();
+        assert_eq!(out, content);
+    }
+
+    #[test]
+    fn store_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut x = 1u32;
+        let noise: Vec<u8> = (0..STORE_MIN_SIZE * 2)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let files = [
+            (dir.path().join("a.txt"), vec![b'a'; 100_000]),
+            (dir.path().join("b.JPG"), noise.clone()),
+            (dir.path().join("c.jpg"), noise[..1000].to_vec()),
+            (dir.path().join("d.txt"), vec![b'd'; 100_000]),
+        ];
+        let archive = dir.path().join("archive.tar.zst");
+        let mut encoder = CompressionEncoder::create(&archive, 19, 1, None)
+            .unwrap()
+            .store_only([".jpg"]);
+        for (path, content) in files.iter() {
+            std::fs::write(path, content).unwrap();
+            encoder.append_file(path, |_| ()).unwrap();
+        }
+        encoder.close().unwrap();
+
+        // The level changes for the large jpg (but not for the small one)
+        let data = std::fs::read(&archive).unwrap();
+        let mut frames = 0;
+        let mut offset = 0;
+        while offset < data.len() {
+            offset += zstd::zstd_safe::find_frame_compressed_size(&data[offset..]).unwrap();
+            frames += 1;
+        }
+        assert_eq!(frames, 3);
+
+        let mut decoder = CompressionDecoder::read(&archive, 1).unwrap();
+        for (entry, (path, content)) in decoder.entries().unwrap().zip(files.iter()) {
+            let (mut fi, mut entry) = entry.unwrap();
+            assert_eq!(fi.get_path(), path);
+            let mut out = vec![];
+            std::io::Read::read_to_end(&mut entry, &mut out).unwrap();
+            assert_eq!(&out, content);
+        }
+    }
+
+    #[test]
+    fn long_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut long = dir.path().to_path_buf();
+        for i in 0..4 {
+            long.push(format!("{}_{}", i, "directory".repeat(10)));
+        }
+        std::fs::create_dir_all(&long).unwrap();
+        let file = long.join("file.txt");
+        let link = long.join("link.txt");
+        std::fs::write(&file, "content").unwrap();
+        assert!(path_to_archive(&file).as_os_str().len() > 300);
+
+        for format in [TarFormat::Pax, TarFormat::Gnu] {
+            let archive = dir.path().join(format!("{:?}.tar.zst", format));
+            let mut encoder = CompressionEncoder::create(&archive, 1, 1, None)
+                .unwrap()
+                .tar_format(format);
+            encoder.append_file(&file, |_| ()).unwrap();
+            encoder.append_hard_link(&link, &file).unwrap();
+            encoder.close().unwrap();
+
+            // Only the pax format avoids the GNU long name entries
+            let data = zstd::decode_all(std::fs::File::open(&archive).unwrap()).unwrap();
+            let mut tar = Archive::new(Cursor::new(data));
+            let gnu = tar
+                .entries()
+                .unwrap()
+                .raw(true)
+                .map(|e| e.unwrap().header().entry_type())
+                .any(|t| t.is_gnu_longname() || t.is_gnu_longlink());
+            assert_eq!(gnu, format == TarFormat::Gnu);
+
+            let mut decoder = CompressionDecoder::read(&archive, 1).unwrap();
+            let mut entries = decoder.entries().unwrap();
+            let (mut fi, mut entry) = entries.next().unwrap().unwrap();
+            assert_eq!(fi.get_path(), &file);
+            let mut out = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut out).unwrap();
+            assert_eq!(out, "content");
+            std::mem::drop(entry);
+            let (mut fi, entry) = entries.next().unwrap().unwrap();
+            assert_eq!(fi.get_path(), &link);
+            assert_eq!(
+                hard_link_target(&entry)
+                    .as_mut()
+                    .map(|t| t.get_path().clone()),
+                Some(file.clone())
+            );
+        }
+    }
+
+    /// Fails with the errors before reading the content
+    struct FlakyReader(Vec<ErrorKind>, Cursor<&'static [u8]>);
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.pop() {
+                Some(kind) => Err(kind.into()),
+                None => self.1.read(buf),
+            }
+        }
+    }
+
+    #[test]
+    fn retry_reads() {
+        let retry = Retry::new(2, Duration::from_millis(1));
+        assert_eq!(retry.backoff(0), Duration::from_millis(1));
+        assert_eq!(retry.backoff(2), Duration::from_millis(4));
+        let read = |errors: Vec<ErrorKind>| {
+            let mut reader = RetryReader {
+                inner: FlakyReader(errors, Cursor::new(b"content")),
+                retry,
+            };
+            let mut out = String::new();
+            reader.read_to_string(&mut out).map(|_| out)
+        };
+        assert_eq!(read(vec![]).unwrap(), "content");
+        assert_eq!(
+            read(vec![ErrorKind::TimedOut, ErrorKind::WouldBlock]).unwrap(),
+            "content"
+        );
+        // Too many transient errors, or errors that are not transient, are returned
+        let errors = vec![ErrorKind::TimedOut; 3];
+        assert_eq!(read(errors).unwrap_err().kind(), ErrorKind::TimedOut);
+        let errors = vec![ErrorKind::PermissionDenied];
+        assert_eq!(
+            read(errors).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn large_size() {
+        // Only the header is written (the data would not fit in the test)
+        let size = 9 << 30;
+        let path = path_to_archive(&PathBuf::from("large.bin"));
+        let mut tar = Builder::new(vec![]);
+        let mut header = TarFormat::Pax.header();
+        header.set_size(size);
+        append_with_format(
+            &mut tar,
+            TarFormat::Pax,
+            &mut header,
+            path.as_ref(),
+            None,
+            vec![],
+            std::io::empty(),
+        )
+        .unwrap();
+        let data = tar.into_inner().unwrap();
+        let mut tar = Archive::new(Cursor::new(data));
+        let mut entry = tar.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.size(), size);
+        assert_eq!(entry.path().unwrap(), path);
+        let extensions = entry.pax_extensions().unwrap().unwrap();
+        let extensions: Vec<_> = extensions
+            .map(|e| e.unwrap().key().unwrap().to_string())
+            .collect();
+        assert_eq!(extensions, vec!["size"]);
+    }
+
+    #[test]
+    fn stream_replay() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        let source = StreamSource::new(Cursor::new(data.clone()));
+        let mut first = source.open().unwrap();
+        let mut head = vec![0u8; 1000];
+        first.read_exact(&mut head).unwrap();
+        assert_eq!(head, data[..1000]);
+
+        let mut second = source.open().unwrap();
+        let mut all = vec![];
+        second.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+        assert!(first.read(&mut head).is_err());
+        assert!(source.open().is_err());
+    }
+}
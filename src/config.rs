@@ -1,20 +1,259 @@
 /// This module contains the config object (including serialisation, deserialisation, and parsing command line arguments)
+use std::borrow::Cow;
+use std::fmt::Display;
 use std::fs::File;
-use std::io::{Error, ErrorKind};
+use std::io::{Error, ErrorKind, Read};
 use std::path::{Path, PathBuf};
 
 use chrono::NaiveDateTime;
 use path_absolutize::Absolutize;
 use serde::{Deserialize, Serialize};
 
-use crate::backup::BACKUP_FILE_EXTENSION;
+use crate::backup::{BackupError, BACKUP_FILE_EXTENSION, STREAM_PATH};
+use crate::commands::BackupCommand;
+use crate::compression::TarFormat;
+use crate::file_types::COMPRESSED_EXTENSIONS;
+use crate::files::{
+    cmp_path_str, nested_includes, normalise_crawl_path, sort_paths, ChangeDetection, FileCrawler,
+};
 use crate::parse_date;
 use crate::parse_date::{create_backup_file_name, naive_now};
-use crate::utils::{clamp, default_dir, BackupIterator};
+use crate::utils::{clamp, default_dir, expand_path, extend_pathbuf, BackupIterator};
+use crate::xattrs::{MacosXattrs, APPLE_DOUBLE_REGEX};
+
+/// The file formats that are supported for (standalone) config files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Get the format based on the file extension (defaults to yaml)
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+}
+
+/// The differences between two configs (see [`Config::diff`])
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    /// The (normalised) includes that are only in the first, and only in the second, config
+    pub include: (Vec<String>, Vec<String>),
+    /// The (normalised) excludes that are only in the first, and only in the second, config
+    pub exclude: (Vec<String>, Vec<String>),
+    /// The regexes that are only in the first, and only in the second, config
+    pub regex: (Vec<String>, Vec<String>),
+    /// The other settings that differ: the name, and the values (as json) in both configs
+    pub settings: Vec<(String, String, String)>,
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.include.0.is_empty()
+            && self.include.1.is_empty()
+            && self.exclude.0.is_empty()
+            && self.exclude.1.is_empty()
+            && self.regex.0.is_empty()
+            && self.regex.1.is_empty()
+            && self.settings.is_empty()
+    }
+}
+
+/// Changes to an existing config (see [`Config::merge_patch`]): the paths and regexes are added
+/// (unless already in the config) or removed, and the other settings are only changed if given
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigPatch {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub regex: Vec<String>,
+    pub remove_include: Vec<String>,
+    pub remove_exclude: Vec<String>,
+    pub remove_regex: Vec<String>,
+    pub output: Option<PathBuf>,
+    pub quality: Option<i32>,
+    pub threads: Option<u32>,
+    pub incremental: Option<bool>,
+    pub local: Option<bool>,
+}
+
+impl ConfigPatch {
+    /// Apply the changes to a config, returning the removed values that were not in the config
+    pub fn apply(&self, config: &mut Config) -> Vec<String> {
+        let mut missing = vec![];
+        let mut update = |list: &mut Vec<String>, add: &[String], remove: &[String]| {
+            for r in remove {
+                match list.iter().position(|s| s == r) {
+                    Some(i) => {
+                        list.remove(i);
+                    }
+                    None => missing.push(r.clone()),
+                }
+            }
+            for a in add {
+                if !list.contains(a) {
+                    list.push(a.clone());
+                }
+            }
+        };
+        update(&mut config.include, &self.include, &self.remove_include);
+        update(&mut config.exclude, &self.exclude, &self.remove_exclude);
+        update(&mut config.regex, &self.regex, &self.remove_regex);
+        if let Some(output) = &self.output {
+            config.output = output.clone();
+        }
+        if let Some(quality) = self.quality {
+            config.quality = quality;
+        }
+        if let Some(threads) = self.threads {
+            config.threads = threads;
+        }
+        if let Some(incremental) = self.incremental {
+            config.incremental = incremental;
+        }
+        if let Some(local) = self.local {
+            config.local = local;
+        }
+        missing
+    }
+}
+
+/// Problems found when validating a config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigIssue {
+    NoIncludes,
+    MissingInclude(String),
+    DuplicateInclude(String),
+    ShadowedInclude(String),
+    /// The include is inside another include (possibly through a symlink), so the files are backed
+    /// up through the other include (with its paths)
+    NestedInclude(String, String),
+    OutputInInclude(String),
+    InvalidRegex(String),
+    QualityOutOfRange(i32),
+    NoThreads,
+    TooManyThreads(u32),
+    RequiredNotIncluded(String),
+    /// A file with exclusions (or regexes) does not exist
+    MissingPatternFile(String),
+    /// The change time is not available on this platform (the modification time is used instead)
+    ChangeTimeUnavailable,
+    /// The name of a command is not a valid file name
+    InvalidCommandName(String),
+    DuplicateCommandName(String),
+    /// Incremental backups cannot be written to stdout (the previous backup cannot be found)
+    IncrementalStream,
+    /// The output directory does not exist and cannot be created (a parent is not a directory)
+    OutputNotCreatable(String),
+    /// The output is inside an include, and is excluded automatically
+    OutputExcluded(String),
+    /// No files can be both newer and older than the limits (`newer_than` and `older_than`)
+    EmptyAgeRange,
+}
+
+impl ConfigIssue {
+    /// Errors prevent backups, the rest are only warnings
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            ConfigIssue::NoIncludes
+                | ConfigIssue::InvalidRegex(_)
+                | ConfigIssue::QualityOutOfRange(_)
+                | ConfigIssue::NoThreads
+                | ConfigIssue::RequiredNotIncluded(_)
+                | ConfigIssue::MissingPatternFile(_)
+                | ConfigIssue::InvalidCommandName(_)
+                | ConfigIssue::DuplicateCommandName(_)
+                | ConfigIssue::IncrementalStream
+                | ConfigIssue::OutputNotCreatable(_)
+                | ConfigIssue::EmptyAgeRange
+        )
+    }
+}
+
+impl Display for ConfigIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigIssue::NoIncludes => write!(f, "No files or directories are included"),
+            ConfigIssue::MissingInclude(s) => write!(f, "The included path '{}' does not exist", s),
+            ConfigIssue::DuplicateInclude(s) => write!(f, "The path '{}' is included twice", s),
+            ConfigIssue::ShadowedInclude(s) => write!(
+                f,
+                "The path '{}' is both included and excluded (the include takes precedence)",
+                s
+            ),
+            ConfigIssue::NestedInclude(s, outer) => write!(
+                f,
+                "The path '{}' is already backed up through the include '{}'",
+                s, outer
+            ),
+            ConfigIssue::OutputInInclude(s) => write!(
+                f,
+                "The output is inside the included directory '{}' (old backups will be included in new backups unless excluded)",
+                s
+            ),
+            ConfigIssue::EmptyAgeRange => write!(
+                f,
+                "No files can be both newer and older than the limits (`newer_than` must be before `older_than`)"
+            ),
+            ConfigIssue::OutputExcluded(s) => write!(
+                f,
+                "The output is inside the included directory '{}' (it is excluded automatically, unless `allow_output_inside` is set)",
+                s
+            ),
+            ConfigIssue::InvalidRegex(s) => write!(f, "Invalid regex: '{}'", s),
+            ConfigIssue::QualityOutOfRange(q) => write!(
+                f,
+                "The compression quality must be between 1 and 22 (not {})",
+                q
+            ),
+            ConfigIssue::NoThreads => write!(f, "At least one thread is required"),
+            ConfigIssue::TooManyThreads(t) => write!(
+                f,
+                "Using {} threads, but only {} are available",
+                t,
+                num_cpus::get()
+            ),
+            ConfigIssue::RequiredNotIncluded(s) => {
+                write!(f, "The required path '{}' is not included", s)
+            }
+            ConfigIssue::MissingPatternFile(s) => {
+                write!(f, "The file with exclusions '{}' does not exist", s)
+            }
+            ConfigIssue::ChangeTimeUnavailable => write!(
+                f,
+                "The change time is not available on this platform (using the modification time)"
+            ),
+            ConfigIssue::InvalidCommandName(s) => {
+                write!(f, "The command name '{}' is not a valid file name", s)
+            }
+            ConfigIssue::DuplicateCommandName(s) => {
+                write!(f, "There are multiple commands named '{}'", s)
+            }
+            ConfigIssue::IncrementalStream => write!(
+                f,
+                "Incremental backups cannot be written to stdout (the previous backup cannot be found)"
+            ),
+            ConfigIssue::OutputNotCreatable(s) => write!(
+                f,
+                "The output directory '{}' does not exist and cannot be created",
+                s
+            ),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Config {
     pub include: Vec<String>,
+    /// Includes that must contribute files (otherwise the backup fails)
+    #[serde(default)]
+    pub required: Vec<String>,
     pub exclude: Vec<String>,
     pub regex: Vec<String>,
     pub output: PathBuf,
@@ -24,8 +263,152 @@ pub struct Config {
     pub threads: u32,
     #[serde(with = "parse_date")]
     pub time: Option<NaiveDateTime>,
+    #[serde(default)]
+    pub paranoid_incremental: bool,
+    #[serde(default = "default_paranoid_sample")]
+    pub paranoid_sample: f32,
+    #[serde(default)]
+    pub paranoid_seed: Option<u64>,
+    /// How many times to retry reading a file before skipping it
+    /// (when opening it, or if reading it times out)
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Milliseconds to wait before the first retry (doubled for every following retry)
+    #[serde(default = "default_retry_delay")]
+    pub retry_delay: u64,
+    /// Read files into memory (at most this many MiB) on separate threads while the previous
+    /// files are compressed (`0` reads each file when it is compressed)
+    #[serde(default = "default_read_ahead_mb")]
+    pub read_ahead_mb: u64,
+    /// Split the backup into volumes of this many bytes (e.g. for file systems with a file size limit)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume_size: Option<u64>,
+    /// Store empty directories (so that they are recreated when restoring)
+    #[serde(default)]
+    pub include_empty_dirs: bool,
+    /// Skip files that are ignored by `.gitignore` files (unless explicitly included)
+    #[serde(default)]
+    pub use_gitignore: bool,
+    /// Do not descend more than this many directories below each include
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<usize>,
+    /// Only backup files modified at or after this time
+    #[serde(default, with = "parse_date", skip_serializing_if = "Option::is_none")]
+    pub newer_than: Option<NaiveDateTime>,
+    /// Only backup files modified before this time
+    #[serde(default, with = "parse_date", skip_serializing_if = "Option::is_none")]
+    pub older_than: Option<NaiveDateTime>,
+    /// Write a journal before renaming the files of a backup (so that an interrupted rename can be recovered)
+    #[serde(default)]
+    pub journal: bool,
+    /// Store files with identical content only once (the duplicates refer to the first copy)
+    #[serde(default)]
+    pub dedup: bool,
+    /// Which timestamp decides if a file has been modified (in incremental backups)
+    #[serde(default)]
+    pub change_detection: ChangeDetection,
+    /// Only store the permissions and owner of files that are included due to the change time
+    /// (but have an unchanged modification time)
+    #[serde(default)]
+    pub metadata_updates: bool,
+    /// Regexes for large files (e.g. disk images) that are stored as the blocks that changed
+    /// since the previous backup (see [`crate::files::BlockSignature`])
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delta_files: Vec<String>,
+    /// Only files of at least this many bytes are stored as changed blocks
+    #[serde(default = "default_delta_min_size")]
+    pub delta_min_size: u64,
+    /// File names of the backups that were merged into this backup (after being renamed)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged: Vec<String>,
+    /// Extensions of already compressed files (these are compressed with a fast level instead)
+    #[serde(default = "default_store_only")]
+    pub store_only: Vec<String>,
+    /// The format of the tar headers (pax stores long paths and large files in extensions)
+    #[serde(default)]
+    pub tar_format: TarFormat,
+    /// Which extended attributes (Finder tags, the quarantine flag, ...) are stored and restored on macOS
+    #[serde(default, skip_serializing_if = "MacosXattrs::is_default")]
+    pub macos_xattrs: MacosXattrs,
+    /// A file with more paths to exclude (one per line, relative to the config file)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exclude_from: Option<PathBuf>,
+    /// A file with more exclusion regexes (one per line, relative to the config file)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex_from: Option<PathBuf>,
+    /// Commands whose output is stored in the backup (as `_commands/<name>`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<BackupCommand>,
+    /// A command that gets the paths of the files (on stdin) and prints user-defined metadata for
+    /// them, which is stored in the list of files (see [`crate::commands::collect_metadata`])
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_command: Option<String>,
+    /// The output directory must already exist (instead of being created), e.g. on an external drive
+    #[serde(default)]
+    pub require_output: bool,
+    /// A file (relative to the output directory) that must exist before anything is written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker_file: Option<PathBuf>,
+    /// Allow the output directory to be backed up when it is inside an include
+    /// (otherwise it is excluded automatically, see [`Self::output_exclusion`])
+    #[serde(default)]
+    pub allow_output_inside: bool,
+    /// The output directory with the symlinks resolved (recorded in the backups, so that an
+    /// incremental backup is not based on a backup from another location)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved_output: Option<PathBuf>,
+    /// Total size of the files stored in the backup before compression (recorded in the
+    /// backups, so that the compression ratio can be shown later)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_size: Option<u64>,
     #[serde(skip)]
     pub origin: PathBuf,
+    /// The changes made when reading a config written by an old version (not stored,
+    /// see [`Self::from_legacy_yaml`])
+    #[serde(skip)]
+    pub migrations: Vec<String>,
+    /// Base an incremental backup on this backup instead of the latest one (not stored)
+    #[serde(skip)]
+    pub base: Option<PathBuf>,
+    /// Continue the chain of incremental backups even if the output has moved (not stored)
+    #[serde(skip)]
+    pub chain_across_moves: bool,
+}
+
+/// Fields that have been renamed since the first releases (old name, current name)
+const LEGACY_RENAMED: [(&str, &str); 5] = [
+    ("includes", "include"),
+    ("excludes", "exclude"),
+    ("regexes", "regex"),
+    ("compression", "quality"),
+    ("paranoid", "paranoid_incremental"),
+];
+
+fn default_paranoid_sample() -> f32 {
+    1.0
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_retry_delay() -> u64 {
+    500
+}
+
+fn default_read_ahead_mb() -> u64 {
+    32
+}
+
+fn default_delta_min_size() -> u64 {
+    64 << 20
+}
+
+pub fn default_store_only() -> Vec<String> {
+    COMPRESSED_EXTENSIONS
+        .iter()
+        .map(|e| e.to_string())
+        .collect()
 }
 
 impl Config {
@@ -34,6 +417,7 @@ impl Config {
     pub fn new() -> Self {
         Config {
             include: vec![],
+            required: vec![],
             exclude: vec![],
             regex: vec![],
             output: PathBuf::new(),
@@ -42,7 +426,41 @@ impl Config {
             local: false,
             threads: 4,
             time: None,
+            paranoid_incremental: false,
+            paranoid_sample: default_paranoid_sample(),
+            paranoid_seed: None,
+            retries: default_retries(),
+            retry_delay: default_retry_delay(),
+            read_ahead_mb: default_read_ahead_mb(),
+            volume_size: None,
+            include_empty_dirs: false,
+            use_gitignore: false,
+            max_depth: None,
+            newer_than: None,
+            older_than: None,
+            journal: false,
+            dedup: false,
+            change_detection: ChangeDetection::Mtime,
+            metadata_updates: false,
+            delta_files: vec![],
+            delta_min_size: default_delta_min_size(),
+            merged: vec![],
+            store_only: default_store_only(),
+            tar_format: TarFormat::default(),
+            macos_xattrs: MacosXattrs::default(),
+            exclude_from: None,
+            regex_from: None,
+            commands: vec![],
+            metadata_command: None,
+            require_output: false,
+            marker_file: None,
+            allow_output_inside: false,
+            resolved_output: None,
+            original_size: None,
             origin: PathBuf::new(),
+            migrations: vec![],
+            base: None,
+            chain_across_moves: false,
         }
     }
 
@@ -58,7 +476,10 @@ impl Config {
 
     pub fn get_output(&self, home: bool) -> PathBuf {
         if !self.output.as_os_str().is_empty() {
-            self.output.clone()
+            match self.output.to_str() {
+                Some(s) => PathBuf::from(expand_path(s)),
+                None => self.output.clone(),
+            }
         } else if !self.origin.as_os_str().is_empty() {
             self.origin.clone()
         } else if home {
@@ -68,7 +489,32 @@ impl Config {
         }
     }
 
+    /// Check that the output is available (see `require_output` and `marker_file`) before
+    /// anything is written, returning the path that is missing otherwise
+    pub fn missing_output(&self) -> Option<PathBuf> {
+        if (!self.require_output && self.marker_file.is_none()) || self.is_output_stream() {
+            return None;
+        }
+        let dir = self.get_output_dir();
+        if !dir.is_dir() {
+            return Some(dir);
+        }
+        self.marker_file
+            .as_ref()
+            .map(|m| dir.join(m))
+            .filter(|m| !m.exists())
+    }
+
+    /// The output directory with the symlinks resolved (if it exists)
+    pub fn get_resolved_output(&self) -> Option<PathBuf> {
+        if self.is_output_stream() {
+            return None;
+        }
+        self.get_output_dir().canonicalize().ok()
+    }
+
     /// Read a config from a yaml file
+    #[allow(unused)]
     pub fn read_yaml(path: PathBuf) -> std::io::Result<Self> {
         let reader = File::open(&path)?;
         let mut conf: Config =
@@ -77,7 +523,60 @@ impl Config {
         Ok(conf)
     }
 
+    /// Read a config from a file (the format is based on the file extension)
+    pub fn read_file(path: PathBuf) -> std::io::Result<Self> {
+        let mut content = String::new();
+        File::open(&path)?.read_to_string(&mut content)?;
+        let mut conf = Self::from_format(content, ConfigFormat::from_path(&path))?;
+        conf.origin = path;
+        Ok(conf)
+    }
+
+    /// Write the config to a file (the format is based on the file extension)
+    pub fn write_file<P: AsRef<Path>>(&mut self, path: P, time: bool) -> std::io::Result<()> {
+        let t = self.time;
+        if !time {
+            self.time = None;
+        }
+        let res = self.as_format(ConfigFormat::from_path(&path));
+        if !time {
+            self.time = t;
+        }
+        std::fs::write(path, res?)
+    }
+
+    /// Parse a config in the given format
+    pub fn from_format<S: AsRef<str>>(content: S, format: ConfigFormat) -> std::io::Result<Self> {
+        match format {
+            ConfigFormat::Yaml => {
+                Self::from_yaml(content).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            }
+            ConfigFormat::Toml => {
+                Self::from_toml(content).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            }
+            ConfigFormat::Json => {
+                Self::from_json(content).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    /// Serialise the config in the given format
+    pub fn as_format(&mut self, format: ConfigFormat) -> std::io::Result<String> {
+        match format {
+            ConfigFormat::Yaml => self
+                .as_yaml()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            ConfigFormat::Toml => self
+                .as_toml()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            ConfigFormat::Json => self
+                .as_json()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+
     /// Write the config to a yaml file
+    #[allow(unused)]
     pub fn write_yaml<P: AsRef<Path>>(&mut self, path: P, time: bool) -> std::io::Result<()> {
         self.sort();
         let t = self.time;
@@ -94,8 +593,62 @@ impl Config {
     }
 
     /// Parse a yaml string to a config
+    /// (configs from old versions are migrated if the strict parsing fails, see [`Self::from_legacy_yaml`])
     pub fn from_yaml<S: AsRef<str>>(yaml: S) -> Result<Self, serde_yaml::Error> {
         serde_yaml::from_str(yaml.as_ref())
+            .or_else(|e| Self::from_legacy_yaml(yaml.as_ref()).ok_or(e))
+    }
+
+    /// Parse a yaml config written by an old version: renamed fields get their current names,
+    /// missing fields get the current defaults, and fields that are no longer used are ignored.
+    /// The changes are listed in [`Self::migrations`] (`None` if nothing could be migrated).
+    pub fn from_legacy_yaml(yaml: &str) -> Option<Self> {
+        let mut fields = match serde_yaml::from_str(yaml).ok()? {
+            serde_yaml::Value::Mapping(m) => m,
+            _ => return None,
+        };
+        let mut migrations = vec![];
+        for (old, new) in LEGACY_RENAMED {
+            if !fields.contains_key(new) {
+                if let Some(v) = fields.remove(old) {
+                    fields.insert(new.into(), v);
+                    migrations.push(format!("Renamed '{}' to '{}'", old, new));
+                }
+            }
+        }
+        if let serde_yaml::Value::Mapping(defaults) = serde_yaml::to_value(Config::new()).ok()? {
+            for (k, v) in defaults {
+                if !fields.contains_key(&k) {
+                    migrations.push(format!(
+                        "Using the default for the missing '{}'",
+                        k.as_str().unwrap_or_default()
+                    ));
+                    fields.insert(k, v);
+                }
+            }
+        }
+        if migrations.is_empty() {
+            return None;
+        }
+        let mut config: Config =
+            serde_yaml::from_value(serde_yaml::Value::Mapping(fields.clone())).ok()?;
+        // Fields that are not written back are not used anymore (unless they are empty)
+        if let serde_yaml::Value::Mapping(known) = serde_yaml::to_value(&config).ok()? {
+            for (k, v) in fields.iter() {
+                let empty = v.is_null() || v.as_sequence().is_some_and(|s| s.is_empty());
+                if !empty && !known.contains_key(k) {
+                    migrations.push(format!(
+                        "Ignoring '{}' (no longer used)",
+                        k.as_str().unwrap_or_default()
+                    ));
+                }
+            }
+        }
+        for m in migrations.iter() {
+            log::info!("Reading a config from an old version: {}", m);
+        }
+        config.migrations = migrations;
+        Some(config)
     }
 
     /// serialise the config as a yaml string
@@ -104,12 +657,39 @@ impl Config {
         serde_yaml::to_string(&self)
     }
 
+    /// Parse a toml string to a config
+    pub fn from_toml<S: AsRef<str>>(toml: S) -> Result<Self, toml::de::Error> {
+        toml::from_str(toml.as_ref())
+    }
+
+    /// serialise the config as a toml string
+    pub fn as_toml(&mut self) -> Result<String, toml::ser::Error> {
+        self.sort();
+        toml::to_string(&self)
+    }
+
+    /// Parse a json string to a config
+    pub fn from_json<S: AsRef<str>>(json: S) -> serde_json::Result<Self> {
+        serde_json::from_str(json.as_ref())
+    }
+
+    /// serialise the config as a json string
+    pub fn as_json(&mut self) -> serde_json::Result<String> {
+        self.sort();
+        serde_json::to_string_pretty(&self)
+    }
+
     pub fn sort(&mut self) {
         self.include.sort_unstable();
         self.exclude.sort_unstable();
         self.regex.retain(|s| !s.is_empty());
     }
 
+    /// Is the backup written to stdout (the output is `-`)
+    pub fn is_output_stream(&self) -> bool {
+        self.output.as_os_str() == STREAM_PATH
+    }
+
     fn is_output_file(&self) -> bool {
         if let Some(n) = self.output.file_name() {
             return n.to_string_lossy().ends_with(BACKUP_FILE_EXTENSION);
@@ -119,13 +699,41 @@ impl Config {
 
     /// Get the path for a new backup
     pub fn get_new_output(&self) -> PathBuf {
-        if self.is_output_file() {
-            self.output.clone()
+        if self.is_output_stream() {
+            PathBuf::from(STREAM_PATH)
+        } else if self.is_output_file() {
+            self.get_output(false)
         } else {
             self.get_dir().join(create_backup_file_name(naive_now()))
         }
     }
 
+    /// Where the progress of a crawl is saved (see [`crate::lists::ScanCheckpoint`])
+    pub fn get_scan_checkpoint(&self) -> PathBuf {
+        if self.is_output_file() {
+            extend_pathbuf(self.get_output(false), ".scan.json")
+        } else {
+            self.get_dir().join("backup_scan.json")
+        }
+    }
+
+    /// The directory for the journals of interrupted operations (next to the backups)
+    pub fn get_journal_dir(&self) -> PathBuf {
+        self.get_output_dir()
+    }
+
+    /// The directory where the backups are written
+    pub fn get_output_dir(&self) -> PathBuf {
+        if self.is_output_file() {
+            match self.get_output(false).parent() {
+                Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+                _ => PathBuf::from("."),
+            }
+        } else {
+            self.get_dir()
+        }
+    }
+
     pub fn get_dir(&self) -> PathBuf {
         let mut path = self.get_output(false);
         if path.is_file() {
@@ -147,12 +755,401 @@ impl Config {
     /// Iterate over old backups
     pub fn get_backups(&self) -> BackupIterator {
         if self.is_output_file() {
-            BackupIterator::file(self.output.clone())
+            BackupIterator::file(self.get_output(false))
         } else {
             BackupIterator::dir(self.get_dir())
         }
     }
 
+    /// Iterate over old backups, but look for them in another directory (e.g. a copy of the backups)
+    pub fn get_backups_in<P: AsRef<Path>>(&self, dir: P) -> BackupIterator {
+        match self.output.file_name() {
+            Some(name) if self.is_output_file() => BackupIterator::file(dir.as_ref().join(name)),
+            _ => BackupIterator::dir(dir),
+        }
+    }
+
+    /// Resolve a path relative to the config file (or the current directory)
+    fn relative_to_origin(&self, path: &Path) -> PathBuf {
+        let path = match path.to_str() {
+            Some(s) => PathBuf::from(expand_path(s)),
+            None => path.to_path_buf(),
+        };
+        match self.origin.parent() {
+            Some(dir) if path.is_relative() => dir.join(path),
+            _ => path,
+        }
+    }
+
+    /// The files with more exclusions and regexes (resolved relative to the config file)
+    pub fn get_pattern_files(&self) -> (Option<PathBuf>, Option<PathBuf>) {
+        (
+            self.exclude_from
+                .as_deref()
+                .map(|p| self.relative_to_origin(p)),
+            self.regex_from
+                .as_deref()
+                .map(|p| self.relative_to_origin(p)),
+        )
+    }
+
+    /// Append the lines of [`Config::exclude_from`] and [`Config::regex_from`] to the exclusions
+    /// and regexes (lines that are already there are not added again)
+    pub fn read_pattern_files(&mut self) -> std::io::Result<()> {
+        let (exclude, regex) = self.get_pattern_files();
+        for (file, patterns) in [(exclude, &mut self.exclude), (regex, &mut self.regex)] {
+            if let Some(file) = file {
+                for line in read_pattern_file(&file)? {
+                    if !patterns.contains(&line) {
+                        patterns.push(line);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A crawler for the files in the config (with the same classification of paths as backups).
+    /// The exclusions from files are only used if they are already read (see [`Self::read_pattern_files`]).
+    pub fn crawler(&self) -> std::io::Result<FileCrawler> {
+        let mut regex = Cow::Borrowed(&self.regex);
+        if let Some(output) = self.output_exclusion()? {
+            regex.to_mut().push(output);
+        }
+        Ok(
+            FileCrawler::new(&self.include, &self.exclude, regex.as_ref(), self.local)?
+                .with_empty_dirs(self.include_empty_dirs)
+                .with_gitignore(self.use_gitignore)
+                .with_max_depth(self.max_depth)
+                .with_age(self.newer_than, self.older_than)
+                .with_separate(&self.required),
+        )
+    }
+
+    /// A regex that excludes the output directory if it is inside an include (so that old
+    /// backups are not stored in new backups), unless [`Self::allow_output_inside`] is set.
+    /// The output is resolved the same way as the includes (absolute or cleaned, see `local`).
+    pub fn output_exclusion(&self) -> std::io::Result<Option<String>> {
+        if self.allow_output_inside || self.is_output_stream() || self.include.is_empty() {
+            return Ok(None);
+        }
+        let cwd = if self.local {
+            PathBuf::new()
+        } else {
+            std::env::current_dir()?
+        };
+        let output =
+            normalise_crawl_path(&self.get_output_dir().to_string_lossy(), self.local, &cwd)?;
+        for include in self.include.iter() {
+            if output.starts_with(normalise_crawl_path(include, self.local, &cwd)?) {
+                let output = output.to_string_lossy();
+                return Ok(Some(format!("^{}$", regex::escape(&output))));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Apply a patch to the content of a config file (see [`ConfigPatch`]), returning the updated
+    /// config and content. Fields that this version does not know (e.g. from a newer version) are
+    /// kept as they are, while the old names of renamed fields are dropped.
+    pub fn merge_patch(
+        content: &str,
+        format: ConfigFormat,
+        patch: &ConfigPatch,
+    ) -> std::io::Result<(Self, String)> {
+        let invalid = |e: &dyn Display| Error::new(ErrorKind::InvalidData, e.to_string());
+        let mut config = Self::from_format(content, format)?;
+        for m in patch.apply(&mut config) {
+            log::warn!("'{}' is not in the config (nothing to remove)", m);
+        }
+        config.sort();
+        let original: serde_yaml::Value = match format {
+            ConfigFormat::Yaml => serde_yaml::from_str(content).map_err(|e| invalid(&e))?,
+            ConfigFormat::Toml => toml::from_str(content).map_err(|e| invalid(&e))?,
+            ConfigFormat::Json => serde_json::from_str(content).map_err(|e| invalid(&e))?,
+        };
+        let mut fields = match serde_yaml::to_value(&config).map_err(|e| invalid(&e))? {
+            serde_yaml::Value::Mapping(m) => m,
+            _ => return Err(invalid(&"Not a config")),
+        };
+        if let serde_yaml::Value::Mapping(original) = original {
+            for (k, v) in original {
+                let legacy = LEGACY_RENAMED
+                    .iter()
+                    .any(|(old, _)| k.as_str() == Some(old));
+                if !legacy && !fields.contains_key(&k) {
+                    fields.insert(k, v);
+                }
+            }
+        }
+        let content = match format {
+            ConfigFormat::Yaml => serde_yaml::to_string(&fields).map_err(|e| invalid(&e))?,
+            ConfigFormat::Toml => {
+                // Toml has no null (the missing values are `None` when reading)
+                fields.retain(|_, v| !v.is_null());
+                toml::to_string(&fields).map_err(|e| invalid(&e))?
+            }
+            ConfigFormat::Json => serde_json::to_string_pretty(&fields)?,
+        };
+        Ok((config, content))
+    }
+
+    /// Compare how two configs behave: the includes and excludes are compared after the same
+    /// normalisation as the crawler does (including the exclusions from files), and the other
+    /// settings by value
+    pub fn diff(&self, other: &Config) -> std::io::Result<ConfigDiff> {
+        let first = self.normalised()?;
+        let second = other.normalised()?;
+        let paths = |a: &[String], b: &[String]| -> (Vec<String>, Vec<String>) {
+            let missing = |a: &[String], b: &[String]| -> Vec<String> {
+                a.iter()
+                    .filter(|s| !b.iter().any(|s2| cmp_path_str(s, s2).is_eq()))
+                    .cloned()
+                    .collect()
+            };
+            (missing(a, b), missing(b, a))
+        };
+        let missing = |a: &[String], b: &[String]| -> Vec<String> {
+            a.iter().filter(|s| !b.contains(s)).cloned().collect()
+        };
+        let mut diff = ConfigDiff {
+            include: paths(&first.include, &second.include),
+            exclude: paths(&first.exclude, &second.exclude),
+            regex: (
+                missing(&first.regex, &second.regex),
+                missing(&second.regex, &first.regex),
+            ),
+            settings: vec![],
+        };
+        let as_map = |c: &Config| -> std::io::Result<serde_json::Map<String, serde_json::Value>> {
+            match serde_json::to_value(c)? {
+                serde_json::Value::Object(map) => Ok(map),
+                _ => Err(Error::new(ErrorKind::InvalidData, "Not a config")),
+            }
+        };
+        let (first, second) = (as_map(&first)?, as_map(&second)?);
+        // The paths are already compared (with the exclusions from files merged into them),
+        // and the time, merged backups, resolved output, and original size describe previous
+        // backups rather than the config
+        let skip = [
+            "include",
+            "exclude",
+            "regex",
+            "exclude_from",
+            "regex_from",
+            "time",
+            "merged",
+            "resolved_output",
+            "original_size",
+        ];
+        let null = serde_json::Value::Null;
+        let mut keys: Vec<&String> = first.keys().chain(second.keys()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        for key in keys.into_iter().filter(|k| !skip.contains(&k.as_str())) {
+            let a = first.get(key).unwrap_or(&null);
+            let b = second.get(key).unwrap_or(&null);
+            if a != b {
+                diff.settings
+                    .push((key.clone(), a.to_string(), b.to_string()));
+            }
+        }
+        Ok(diff)
+    }
+
+    /// A copy of the config with the exclusions from files read, and the includes, excludes, and
+    /// regexes normalised (see [`normalise_crawl_path`])
+    fn normalised(&self) -> std::io::Result<Config> {
+        let mut config = self.clone();
+        config.read_pattern_files()?;
+        let cwd = std::env::current_dir()?;
+        let normalise = |paths: &[String]| -> std::io::Result<Vec<String>> {
+            let mut paths = paths
+                .iter()
+                .map(|s| {
+                    normalise_crawl_path(s, self.local, &cwd)
+                        .map(|p| p.to_string_lossy().to_string())
+                })
+                .collect::<std::io::Result<Vec<_>>>()?;
+            sort_paths(&mut paths);
+            Ok(paths)
+        };
+        config.include = normalise(&config.include)?;
+        config.exclude = normalise(&config.exclude)?;
+        config.regex.retain(|s| !s.is_empty());
+        config.regex.sort_unstable();
+        config.regex.dedup();
+        Ok(config)
+    }
+
+    /// Add the excludes and regexes of `other` that are missing from this config (as written in
+    /// `other`), returning the number of added lines
+    pub fn copy_excludes(&mut self, other: &Config) -> std::io::Result<usize> {
+        let diff = other.diff(self)?;
+        let mut missing = diff.exclude.0;
+        let mut other = other.clone();
+        other.read_pattern_files()?;
+        let cwd = std::env::current_dir()?;
+        let mut added = 0;
+        for e in other.exclude.into_iter() {
+            let p = normalise_crawl_path(&e, other.local, &cwd)?;
+            let p = p.to_string_lossy();
+            // Removing the match avoids adding duplicates (that only differ before normalisation)
+            if let Some(i) = missing.iter().position(|s| cmp_path_str(s, &p).is_eq()) {
+                missing.swap_remove(i);
+                self.exclude.push(e);
+                added += 1;
+            }
+        }
+        for r in diff.regex.0.into_iter() {
+            self.regex.push(r);
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Check the config for problems (without modifying anything)
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = vec![];
+        if self.include.is_empty() && self.commands.is_empty() {
+            issues.push(ConfigIssue::NoIncludes);
+        }
+        let absolute = |s: &str| -> PathBuf {
+            let path = PathBuf::from(expand_path(s));
+            match path.absolutize() {
+                Ok(p) => p.to_path_buf(),
+                Err(_) => path,
+            }
+        };
+        let includes: Vec<PathBuf> = self.include.iter().map(|s| absolute(s)).collect();
+        let excludes: Vec<PathBuf> = self.exclude.iter().map(|s| absolute(s)).collect();
+        for (i, (s, p)) in self.include.iter().zip(includes.iter()).enumerate() {
+            if !p.exists() {
+                issues.push(ConfigIssue::MissingInclude(s.clone()));
+            }
+            if includes[..i].contains(p) {
+                issues.push(ConfigIssue::DuplicateInclude(s.clone()));
+            }
+            if excludes.contains(p) {
+                issues.push(ConfigIssue::ShadowedInclude(s.clone()));
+            }
+        }
+        let regex = match regex::RegexSet::new(self.regex.iter().filter(|s| !s.is_empty())) {
+            Ok(r) => Some(r),
+            Err(_) => {
+                for r in self.regex.iter() {
+                    if regex::Regex::new(r).is_err() {
+                        issues.push(ConfigIssue::InvalidRegex(r.clone()));
+                    }
+                }
+                None
+            }
+        };
+        let excluded = |p: &Path| {
+            excludes.iter().any(|e| e == p)
+                || regex
+                    .as_ref()
+                    .is_some_and(|r| r.is_match(&p.to_string_lossy()))
+        };
+        for (i, j, _) in nested_includes(&includes, excluded) {
+            // The required paths are crawled on their own (see `FileCrawler::with_separate`)
+            if includes[i] != includes[j] && !self.required.contains(&self.include[i]) {
+                issues.push(ConfigIssue::NestedInclude(
+                    self.include[i].clone(),
+                    self.include[j].clone(),
+                ));
+            }
+        }
+        for r in self.delta_files.iter() {
+            if regex::Regex::new(r).is_err() {
+                issues.push(ConfigIssue::InvalidRegex(r.clone()));
+            }
+        }
+        let output = absolute(&self.get_dir().to_string_lossy());
+        for (s, p) in self.include.iter().zip(includes.iter()) {
+            if p.is_dir()
+                && !self.is_output_stream()
+                && output.starts_with(p)
+                && !excludes.iter().any(|e| output.starts_with(e))
+                && !regex
+                    .as_ref()
+                    .is_some_and(|r| r.is_match(&output.to_string_lossy()))
+            {
+                issues.push(if self.allow_output_inside {
+                    ConfigIssue::OutputInInclude(s.clone())
+                } else {
+                    ConfigIssue::OutputExcluded(s.clone())
+                });
+            }
+        }
+        // A required output (e.g. an external drive) is allowed to be missing until the backup
+        if !self.is_output_stream()
+            && !self.require_output
+            && self.marker_file.is_none()
+            && !is_creatable_dir(&output)
+        {
+            issues.push(ConfigIssue::OutputNotCreatable(
+                output.to_string_lossy().to_string(),
+            ));
+        }
+        for s in self.required.iter() {
+            if !self.include.contains(s) {
+                issues.push(ConfigIssue::RequiredNotIncluded(s.clone()));
+            }
+        }
+        let (exclude_from, regex_from) = self.get_pattern_files();
+        for file in [exclude_from, regex_from].into_iter().flatten() {
+            if !file.is_file() {
+                issues.push(ConfigIssue::MissingPatternFile(
+                    file.to_string_lossy().to_string(),
+                ));
+            }
+        }
+        if let (Some(newer), Some(older)) = (self.newer_than, self.older_than) {
+            if newer >= older {
+                issues.push(ConfigIssue::EmptyAgeRange);
+            }
+        }
+        if !(1..=22).contains(&self.quality) {
+            issues.push(ConfigIssue::QualityOutOfRange(self.quality));
+        }
+        if self.threads == 0 {
+            issues.push(ConfigIssue::NoThreads);
+        } else if self.threads > num_cpus::get() as u32 {
+            // Only a warning, since the configs (and backups) are moved between computers
+            issues.push(ConfigIssue::TooManyThreads(self.threads));
+        }
+        if self.incremental && self.is_output_stream() {
+            issues.push(ConfigIssue::IncrementalStream);
+        }
+        if cfg!(windows) && self.change_detection != ChangeDetection::Mtime {
+            issues.push(ConfigIssue::ChangeTimeUnavailable);
+        }
+        for (i, c) in self.commands.iter().enumerate() {
+            if !c.has_valid_name() {
+                issues.push(ConfigIssue::InvalidCommandName(c.name.clone()));
+            } else if self.commands[..i].iter().any(|c2| c2.name == c.name) {
+                issues.push(ConfigIssue::DuplicateCommandName(c.name.clone()));
+            }
+        }
+        issues
+    }
+
+    /// Reject the config if [`Self::validate`] finds any errors (otherwise return the warnings)
+    pub fn check(&self) -> Result<Vec<ConfigIssue>, BackupError> {
+        let (errors, warnings): (Vec<ConfigIssue>, Vec<ConfigIssue>) =
+            self.validate().into_iter().partition(|i| i.is_error());
+        if errors.is_empty() {
+            Ok(warnings)
+        } else {
+            let errors: Vec<String> = errors.iter().map(|i| i.to_string()).collect();
+            Err(BackupError::InvalidConfig(format!(
+                "Invalid config:\n  {}",
+                errors.join("\n  ")
+            )))
+        }
+    }
+
     pub fn add_default_ignores(&mut self) {
         let regexes = [
             r"\\\$RECYCLE.BIN$",
@@ -174,13 +1171,365 @@ impl Config {
         ];
         self.regex.extend(regexes.iter().map(|s| s.to_string()));
     }
+
+    /// Add an ignore-pattern for the AppleDouble (`._*`) files that macOS creates on other file systems
+    pub fn add_apple_double_ignores(&mut self) {
+        if !self.regex.iter().any(|r| r == APPLE_DOUBLE_REGEX) {
+            self.regex.push(APPLE_DOUBLE_REGEX.to_string());
+        }
+    }
+}
+
+/// Does the directory exist, or can it be created (the closest existing parent is a directory)
+fn is_creatable_dir(dir: &Path) -> bool {
+    dir.ancestors()
+        .find(|p| p.exists())
+        .is_some_and(|p| p.is_dir())
+}
+
+/// Read the lines of a file with exclusions (or regexes), skipping blank lines and `#` comments
+fn read_pattern_file(path: &Path) -> std::io::Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.to_string_lossy(), e)))?;
+    Ok(content
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/// Fluent construction of a [`Config`] (starting from the defaults of [`Config::new`])
+///
+/// ```
+/// use simple_backup::config::ConfigBuilder;
+///
+/// let config = ConfigBuilder::new()
+///     .include(["documents", "pictures"])
+///     .exclude(["documents/tmp"])
+///     .output("backups")
+///     .quality(11)
+///     .build();
+/// assert_eq!(config.include.len(), 2);
+/// ```
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct ConfigBuilder(Config);
+
+#[allow(unused)]
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self(Config::new())
+    }
+
+    /// Add files and directories to the backup
+    pub fn include<I: IntoIterator<Item = S>, S: Into<String>>(mut self, paths: I) -> Self {
+        self.0.include.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add files and directories that must contain files to backup (these are also included)
+    pub fn required<I: IntoIterator<Item = S>, S: Into<String>>(mut self, paths: I) -> Self {
+        for p in paths.into_iter().map(Into::into) {
+            if !self.0.include.contains(&p) {
+                self.0.include.push(p.clone());
+            }
+            self.0.required.push(p);
+        }
+        self
+    }
+
+    /// Add files and directories to skip
+    pub fn exclude<I: IntoIterator<Item = S>, S: Into<String>>(mut self, paths: I) -> Self {
+        self.0.exclude.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add regex filters for paths to skip
+    pub fn regex<I: IntoIterator<Item = S>, S: Into<String>>(mut self, regex: I) -> Self {
+        self.0.regex.extend(regex.into_iter().map(Into::into));
+        self
+    }
+
+    /// The directory where the backups are stored
+    pub fn output<P: Into<PathBuf>>(mut self, output: P) -> Self {
+        self.0.output = output.into();
+        self
+    }
+
+    /// Only backup files that have been modified since the previous backup
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.0.incremental = incremental;
+        self
+    }
+
+    /// Compression quality (clamped to 1-22)
+    pub fn quality(mut self, quality: i32) -> Self {
+        self.0.set_quality(quality);
+        self
+    }
+
+    /// Number of compression threads (clamped to the number of cpus)
+    pub fn threads(mut self, threads: u32) -> Self {
+        self.0.set_threads(threads);
+        self
+    }
+
+    /// Preserve relative (local) paths instead of converting to absolute paths
+    pub fn local(mut self, local: bool) -> Self {
+        self.0.local = local;
+        self
+    }
+
+    /// Store empty directories in the backup
+    pub fn include_empty_dirs(mut self, enabled: bool) -> Self {
+        self.0.include_empty_dirs = enabled;
+        self
+    }
+
+    /// Skip files that are ignored by `.gitignore` files
+    pub fn use_gitignore(mut self, enabled: bool) -> Self {
+        self.0.use_gitignore = enabled;
+        self
+    }
+
+    /// Limit how many directories the crawler descends below each include
+    pub fn max_depth(mut self, depth: Option<usize>) -> Self {
+        self.0.max_depth = depth;
+        self
+    }
+
+    /// Store files with identical content only once
+    pub fn dedup(mut self, enabled: bool) -> Self {
+        self.0.dedup = enabled;
+        self
+    }
+
+    /// Which timestamp decides if a file has been modified
+    pub fn change_detection(mut self, detection: ChangeDetection) -> Self {
+        self.0.change_detection = detection;
+        self
+    }
+
+    /// Only store the permissions and owner of files where only those have changed
+    pub fn metadata_updates(mut self, enabled: bool) -> Self {
+        self.0.metadata_updates = enabled;
+        self
+    }
+
+    /// Write a journal before renaming the files of a backup
+    pub fn journal(mut self, enabled: bool) -> Self {
+        self.0.journal = enabled;
+        self
+    }
+
+    /// Store the files matching these regexes (and at least `min_size` bytes) as the changed
+    /// blocks in incremental backups
+    pub fn delta_files<I: IntoIterator<Item = S>, S: Into<String>>(
+        mut self,
+        patterns: I,
+        min_size: u64,
+    ) -> Self {
+        self.0.delta_files = patterns.into_iter().map(Into::into).collect();
+        self.0.delta_min_size = min_size;
+        self
+    }
+
+    /// Extensions of already compressed files (that are not worth compressing further)
+    pub fn store_only<I: IntoIterator<Item = S>, S: Into<String>>(mut self, extensions: I) -> Self {
+        self.0.store_only = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Read more exclusions from a file (one path per line)
+    pub fn exclude_from<P: Into<PathBuf>>(mut self, path: Option<P>) -> Self {
+        self.0.exclude_from = path.map(|p| p.into());
+        self
+    }
+
+    /// Only write backups if the output directory exists and contains the marker file (if any)
+    pub fn require_output<P: Into<PathBuf>>(
+        mut self,
+        enabled: bool,
+        marker_file: Option<P>,
+    ) -> Self {
+        self.0.require_output = enabled;
+        self.0.marker_file = marker_file.map(|p| p.into());
+        self
+    }
+
+    /// Only backup the files modified at or after `newer_than` and before `older_than`
+    pub fn age(
+        mut self,
+        newer_than: Option<NaiveDateTime>,
+        older_than: Option<NaiveDateTime>,
+    ) -> Self {
+        self.0.newer_than = newer_than;
+        self.0.older_than = older_than;
+        self
+    }
+
+    /// Allow the output directory to be backed up when it is inside an include
+    pub fn allow_output_inside(mut self, enabled: bool) -> Self {
+        self.0.allow_output_inside = enabled;
+        self
+    }
+
+    /// Read more exclusion regexes from a file (one regex per line)
+    pub fn regex_from<P: Into<PathBuf>>(mut self, path: Option<P>) -> Self {
+        self.0.regex_from = path.map(|p| p.into());
+        self
+    }
+
+    /// How the extended attributes are handled on macOS
+    pub fn macos_xattrs(mut self, policy: MacosXattrs) -> Self {
+        self.0.macos_xattrs = policy;
+        self
+    }
+
+    /// Skip the AppleDouble (`._*`) files
+    pub fn skip_apple_double(mut self) -> Self {
+        self.0.add_apple_double_ignores();
+        self
+    }
+
+    /// Base an incremental backup on this backup (instead of the latest)
+    pub fn base<P: Into<PathBuf>>(mut self, path: Option<P>) -> Self {
+        self.0.base = path.map(|p| p.into());
+        self
+    }
+
+    /// How many times (and how many milliseconds apart) to retry reading a file before skipping it
+    pub fn retries(mut self, retries: u32, delay: u64) -> Self {
+        self.0.retries = retries;
+        self.0.retry_delay = delay;
+        self
+    }
+
+    /// How many MiB of files to read ahead of the compression (`0` to disable)
+    pub fn read_ahead_mb(mut self, mb: u64) -> Self {
+        self.0.read_ahead_mb = mb;
+        self
+    }
+
+    /// Store the output of a command in the backup (as `_commands/<name>`)
+    pub fn command<S1: Into<String>, S2: Into<String>>(
+        mut self,
+        name: S1,
+        cmd: S2,
+        timeout_s: u64,
+    ) -> Self {
+        self.0
+            .commands
+            .push(BackupCommand::new(name, cmd, timeout_s));
+        self
+    }
+
+    /// Store user-defined metadata from this command in the list of files
+    /// (see [`crate::commands::collect_metadata`])
+    pub fn metadata_command<S: Into<String>>(mut self, cmd: Option<S>) -> Self {
+        self.0.metadata_command = cmd.map(|c| c.into());
+        self
+    }
+
+    /// Make the backup at a fixed time (instead of the last file modification)
+    pub fn time(mut self, time: Option<NaiveDateTime>) -> Self {
+        self.0.time = time;
+        self
+    }
+
+    /// Double check unchanged files in incremental backups (hashing `sample` percent of them)
+    pub fn paranoid(mut self, enabled: bool, sample: f32, seed: Option<u64>) -> Self {
+        self.0.paranoid_incremental = enabled;
+        self.0.paranoid_sample = sample.clamp(0.0, 100.0);
+        self.0.paranoid_seed = seed;
+        self
+    }
+
+    /// Split the backup into volumes of this many bytes
+    pub fn volume_size(mut self, size: Option<u64>) -> Self {
+        self.0.volume_size = size;
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.0
+    }
+
+    /// Build the config, but only if [`Config::check`] finds no errors (warnings are ignored)
+    pub fn try_build(self) -> Result<Config, BackupError> {
+        self.0.check()?;
+        Ok(self.0)
+    }
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Config;
+    use tempfile::tempdir;
+
+    use super::{Config, ConfigBuilder, ConfigFormat, ConfigIssue, ConfigPatch};
     use crate::files::{FileCrawler, FileInfo};
 
+    #[test]
+    fn builder() {
+        let config = ConfigBuilder::new()
+            .include(["a", "b"])
+            .include(vec![String::from("c")])
+            .required(["c", "d"])
+            .exclude(["a/x"])
+            .regex([r"\.tmp$"])
+            .output("out")
+            .incremental(false)
+            .quality(100)
+            .threads(0)
+            .local(true)
+            .retries(5, 10)
+            .build();
+        assert_eq!(config.include, ["a", "b", "c", "d"]);
+        assert_eq!(config.required, ["c", "d"]);
+        assert_eq!(config.exclude, ["a/x"]);
+        assert_eq!(config.regex, [r"\.tmp$"]);
+        assert_eq!(config.output.to_string_lossy(), "out");
+        assert!(!config.incremental);
+        assert!(config.local);
+        assert_eq!(config.quality, 22);
+        assert_eq!(config.threads, 1);
+        assert_eq!((config.retries, config.retry_delay), (5, 10));
+        assert!(config.validate().iter().all(|i| !i.is_error()));
+
+        let config = ConfigBuilder::default().build();
+        let default = Config::new();
+        assert_eq!(config.quality, default.quality);
+        assert_eq!(config.threads, default.threads);
+        assert_eq!(config.incremental, default.incremental);
+        assert!(config.include.is_empty());
+
+        let error = ConfigBuilder::new()
+            .regex(["("])
+            .try_build()
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(&ConfigIssue::NoIncludes.to_string()));
+        assert!(error.contains(&ConfigIssue::InvalidRegex(String::from("(")).to_string()));
+        let dir = tempdir().unwrap();
+        let config = ConfigBuilder::new()
+            .include([dir.path().to_string_lossy()])
+            .include(["missing/file"])
+            .paranoid(true, 200.0, Some(3))
+            .volume_size(Some(1024))
+            .try_build()
+            .unwrap();
+        assert!(config.paranoid_incremental);
+        assert_eq!(config.paranoid_sample, 100.0);
+        assert_eq!(config.volume_size, Some(1024));
+    }
+
     #[test]
     fn yaml() {
         let mut config = Config::new();
@@ -196,9 +1545,31 @@ mod tests {
         assert_eq!(config.quality, config2.quality);
         assert_eq!(config.local, config2.local);
         assert_eq!(config.time, config2.time);
+        assert_eq!(config.paranoid_incremental, config2.paranoid_incremental);
+        assert_eq!(config.paranoid_sample, config2.paranoid_sample);
+        assert_eq!(config.paranoid_seed, config2.paranoid_seed);
         assert_eq!(yaml, yaml2);
     }
 
+    #[test]
+    fn formats() {
+        let mut config = Config::new();
+        config.include.push("a.txt".to_string());
+        config.add_default_ignores();
+        let yaml = config.as_yaml().unwrap();
+        for format in [ConfigFormat::Toml, ConfigFormat::Json] {
+            let s = config.as_format(format).unwrap();
+            let mut config2 = Config::from_format(&s, format).unwrap();
+            assert_eq!(config.include, config2.include);
+            assert_eq!(config.regex, config2.regex);
+            assert_eq!(config.time, config2.time);
+            assert_eq!(yaml, config2.as_yaml().unwrap());
+        }
+        assert_eq!(ConfigFormat::from_path("a/b.toml"), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::from_path("a/b.json"), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_path("a/b.yaml"), ConfigFormat::Yaml);
+    }
+
     #[test]
     fn default_ignores() -> std::io::Result<()> {
         let mut config = Config::new();
@@ -208,4 +1579,274 @@ mod tests {
         assert!(!fc.check_path(&mut FileInfo::from("src/cache"), Some(true)));
         Ok(())
     }
+
+    #[test]
+    fn validate() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let missing = dir.path().join("missing").to_string_lossy().to_string();
+        let mut config = Config::new();
+        config.threads = 1;
+        assert_eq!(config.validate(), vec![ConfigIssue::NoIncludes]);
+
+        config.include = vec![root.clone(), missing.clone(), root.clone()];
+        config.exclude = vec![missing.clone()];
+        config.output = dir.path().join("backups");
+        config.quality = 0;
+        config.threads = 0;
+        let issues = config.validate();
+        assert!(issues.contains(&ConfigIssue::MissingInclude(missing.clone())));
+        assert!(issues.contains(&ConfigIssue::DuplicateInclude(root.clone())));
+        assert!(issues.contains(&ConfigIssue::ShadowedInclude(missing.clone())));
+        assert!(issues.contains(&ConfigIssue::OutputExcluded(root.clone())));
+        assert!(issues.contains(&ConfigIssue::QualityOutOfRange(0)));
+        assert!(issues.contains(&ConfigIssue::NoThreads));
+        assert!(issues.iter().any(|i| i.is_error()));
+        issues
+            .iter()
+            .for_each(|i| assert!(!i.to_string().is_empty()));
+
+        config.include = vec![root.clone()];
+        config.exclude = vec![config.output.to_string_lossy().to_string()];
+        config.quality = 1;
+        config.threads = 1;
+        assert_eq!(config.validate(), vec![]);
+        config.exclude = vec![];
+        config.regex = vec!["backups$".to_string()];
+        assert_eq!(config.validate(), vec![]);
+        config.regex = vec!["(".to_string()];
+        assert_eq!(config.validate(), vec![
+            ConfigIssue::InvalidRegex("(".to_string()),
+            ConfigIssue::OutputExcluded(root.clone())
+        ]);
+        config.allow_output_inside = true;
+        assert!(config
+            .validate()
+            .contains(&ConfigIssue::OutputInInclude(root.clone())));
+        config.newer_than = crate::parse_date::try_parse("2024-01-01").unwrap();
+        config.older_than = crate::parse_date::try_parse("2023-01-01").unwrap();
+        assert!(config.validate().contains(&ConfigIssue::EmptyAgeRange));
+        config.required = vec![missing.clone()];
+        assert!(config
+            .validate()
+            .contains(&ConfigIssue::RequiredNotIncluded(missing)));
+        let docs = dir.path().join("docs");
+        std::fs::create_dir(&docs).unwrap();
+        let docs = docs.to_string_lossy().to_string();
+        config.include = vec![root.clone(), docs.clone()];
+        assert!(config
+            .validate()
+            .contains(&ConfigIssue::NestedInclude(docs.clone(), root)));
+        // Required paths are backed up with their own paths
+        config.required = vec![docs];
+        assert!(!config
+            .validate()
+            .iter()
+            .any(|i| matches!(i, ConfigIssue::NestedInclude(..))));
+    }
+
+    #[test]
+    fn output_exclusion() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().to_string_lossy().to_string();
+        let mut config = ConfigBuilder::new()
+            .include([&root])
+            .output(dir.path().join("backups"))
+            .build();
+        let regex = config.output_exclusion().unwrap().unwrap();
+        let regex = regex::Regex::new(&regex).unwrap();
+        let output = dir.path().join("backups").to_string_lossy().to_string();
+        assert!(regex.is_match(&output));
+        assert!(!regex.is_match(&format!("{}2", output)));
+        assert!(!regex.is_match(&root));
+
+        config.allow_output_inside = true;
+        assert_eq!(config.output_exclusion().unwrap(), None);
+        config.allow_output_inside = false;
+        config.output = std::path::PathBuf::from("-");
+        assert_eq!(config.output_exclusion().unwrap(), None);
+        config.output = std::env::temp_dir().join("elsewhere");
+        config.include = vec![dir.path().join("docs").to_string_lossy().to_string()];
+        assert_eq!(config.output_exclusion().unwrap(), None);
+    }
+
+    #[test]
+    fn check_output() {
+        let dir = tempdir().unwrap();
+        let out = tempdir().unwrap();
+        let file = out.path().join("file.txt");
+        std::fs::write(&file, "").unwrap();
+        let mut config = ConfigBuilder::new()
+            .include([dir.path().to_string_lossy()])
+            .output(out.path().join("new").join("backups"))
+            .threads(1)
+            .build();
+        assert!(config.check().is_ok());
+        config.output = file.join("backups");
+        let output = file.join("backups").to_string_lossy().to_string();
+        assert_eq!(config.validate(), vec![ConfigIssue::OutputNotCreatable(
+            output.clone()
+        )]);
+        let error = config.check().unwrap_err().to_string();
+        assert!(error.contains(&output), "{}", error);
+        // An output that is required to exist is only checked before the backup
+        config.require_output = true;
+        assert!(config.check().is_ok());
+        config.require_output = false;
+        config.output = out.path().to_path_buf();
+        config.quality = 99;
+        let error = config.check().unwrap_err().to_string();
+        assert!(error.contains("between 1 and 22 (not 99)"), "{}", error);
+    }
+
+    #[test]
+    fn pattern_files() {
+        let dir = tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("excludes.txt"),
+            "# Build output\n/src/target\n\n  /src/tmp  \n/src/target\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("regex.txt"), "\\.log$\n#\\.tmp$\n").unwrap();
+        let config_path = dir.path().join("config.yml");
+        ConfigBuilder::new()
+            .include(["/src"])
+            .exclude(["/src/tmp"])
+            .exclude_from(Some("excludes.txt"))
+            .regex_from(Some("regex.txt"))
+            .build()
+            .write_yaml(&config_path, false)
+            .unwrap();
+        let mut config = Config::read_yaml(config_path).unwrap();
+        assert_eq!(
+            config.get_pattern_files(),
+            (
+                Some(dir.path().join("excludes.txt")),
+                Some(dir.path().join("regex.txt"))
+            )
+        );
+        config.read_pattern_files().unwrap();
+        config.read_pattern_files().unwrap();
+        assert_eq!(config.exclude, ["/src/tmp", "/src/target"]);
+        assert_eq!(config.regex, ["\\.log$"]);
+
+        config.regex_from = Some("missing.txt".into());
+        assert!(config.read_pattern_files().is_err());
+        assert!(config.validate().contains(&ConfigIssue::MissingPatternFile(
+            dir.path().join("missing.txt").to_string_lossy().to_string()
+        )));
+    }
+
+    #[test]
+    fn diff() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("excludes.txt"), "/src/target\n").unwrap();
+        let a = ConfigBuilder::new()
+            .include(["/src", "/docs"])
+            .exclude(["/src/tmp/", "/src/cache"])
+            .regex([r"\.log$"])
+            .quality(10)
+            .build();
+        let mut b = ConfigBuilder::new()
+            .include(["/docs/../src", "/photos"])
+            .exclude(["/src/cache"])
+            .exclude_from(Some(dir.path().join("excludes.txt")))
+            .quality(10)
+            .dedup(true)
+            .build();
+        let diff = a.diff(&b).unwrap();
+        assert!(!diff.is_empty());
+        assert_eq!(diff.include, (vec!["/docs".into()], vec!["/photos".into()]));
+        assert_eq!(
+            diff.exclude,
+            (vec!["/src/tmp".into()], vec!["/src/target".into()])
+        );
+        assert_eq!(diff.regex, (vec![r"\.log$".into()], vec![]));
+        assert_eq!(diff.settings, vec![(
+            "dedup".into(),
+            "false".into(),
+            "true".into()
+        )]);
+        assert!(a.diff(&a).unwrap().is_empty());
+
+        assert_eq!(b.copy_excludes(&a).unwrap(), 2);
+        assert_eq!(b.exclude, ["/src/cache", "/src/tmp/"]);
+        assert_eq!(b.regex.last().unwrap(), r"\.log$");
+        assert_eq!(b.copy_excludes(&a).unwrap(), 0);
+    }
+
+    #[test]
+    fn legacy() {
+        let yaml = "---\nincludes:\n  - /home/user/notes\nexcludes: []\nregexes: []\noutput: /home/user/backups\nincremental: true\ncompression: 21\nparanoid: false\nverbose: true\n";
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.include, ["/home/user/notes"]);
+        assert_eq!(config.quality, 21);
+        assert!(config.incremental);
+        assert!(!config.paranoid_incremental);
+        assert!(config
+            .migrations
+            .contains(&"Renamed 'includes' to 'include'".to_string()));
+        assert!(config
+            .migrations
+            .contains(&"Ignoring 'verbose' (no longer used)".to_string()));
+        assert!(config.migrations.iter().any(|m| m.contains("'threads'")));
+
+        let mut config = Config::new();
+        let config = Config::from_yaml(config.as_yaml().unwrap()).unwrap();
+        assert!(config.migrations.is_empty());
+        assert!(Config::from_legacy_yaml("- not a config").is_none());
+        assert!(Config::from_yaml("compression: many").is_err());
+    }
+
+    #[test]
+    fn merge_patch() {
+        let mut config = ConfigBuilder::new()
+            .include(["a", "b"])
+            .exclude(["a/x"])
+            .regex(["y$"])
+            .quality(10)
+            .threads(2)
+            .build();
+        let patch = ConfigPatch {
+            include: vec!["b".into(), "c".into()],
+            remove_include: vec!["a".into(), "missing".into()],
+            remove_exclude: vec!["a/x".into()],
+            regex: vec!["z$".into()],
+            quality: Some(3),
+            incremental: Some(true),
+            ..Default::default()
+        };
+        for format in [ConfigFormat::Yaml, ConfigFormat::Toml, ConfigFormat::Json] {
+            let mut content = config.as_format(format).unwrap();
+            // A field from a newer version
+            match format {
+                ConfigFormat::Yaml => content.push_str("future: [1, 2]\n"),
+                ConfigFormat::Toml => content.insert_str(0, "future = [1, 2]\n"),
+                ConfigFormat::Json => content = content.replacen('{', "{\"future\": [1, 2],", 1),
+            }
+            let (mut merged, content) = Config::merge_patch(&content, format, &patch).unwrap();
+            assert_eq!(merged.include, ["b", "c"]);
+            assert!(merged.exclude.is_empty());
+            assert_eq!(merged.regex, ["y$", "z$"]);
+            assert_eq!(merged.quality, 3);
+            assert_eq!(merged.threads, config.threads);
+            assert!(merged.incremental);
+            assert!(!merged.local);
+            assert_eq!(
+                Config::from_format(&content, format)
+                    .unwrap()
+                    .as_yaml()
+                    .unwrap(),
+                merged.as_yaml().unwrap()
+            );
+            assert!(content.contains("future"), "{}", content);
+        }
+
+        // Without changes nothing is changed
+        let content = config.as_yaml().unwrap();
+        let (mut merged, after) =
+            Config::merge_patch(&content, ConfigFormat::Yaml, &ConfigPatch::default()).unwrap();
+        assert_eq!(merged.as_yaml().unwrap(), content);
+        assert_eq!(after, content);
+    }
 }
@@ -0,0 +1,126 @@
+/// This module contains the named file types (based on extensions) used for selecting files when restoring
+use std::fmt::Display;
+
+use regex::RegexSet;
+
+/// The (lowercase) extensions of formats that are already compressed (and would not shrink further)
+pub const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "avif", "mp4", "m4v", "mkv", "mov", "avi",
+    "webm", "mp3", "ogg", "opus", "flac", "m4a", "aac", "zip", "7z", "rar", "gz", "tgz", "bz2",
+    "xz", "zst", "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub", "jar", "apk",
+];
+
+/// Named groups of file extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FileType {
+    Photos,
+    Documents,
+    Videos,
+    Music,
+    Archives,
+}
+
+impl FileType {
+    #[allow(unused)]
+    pub const ALL: [FileType; 5] = [
+        FileType::Photos,
+        FileType::Documents,
+        FileType::Videos,
+        FileType::Music,
+        FileType::Archives,
+    ];
+
+    /// The (lowercase) extensions that belong to the file type
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            FileType::Photos => &[
+                "jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "webp", "heic", "heif", "avif",
+                "raw", "cr2", "nef", "arw", "dng", "orf", "rw2", "svg",
+            ],
+            FileType::Documents => &[
+                "pdf", "doc", "docx", "odt", "rtf", "txt", "md", "xls", "xlsx", "ods", "csv",
+                "ppt", "pptx", "odp", "epub", "tex",
+            ],
+            FileType::Videos => &[
+                "mp4", "m4v", "mkv", "mov", "avi", "wmv", "webm", "flv", "mpg", "mpeg", "3gp",
+                "mts", "m2ts",
+            ],
+            FileType::Music => &[
+                "mp3", "flac", "ogg", "opus", "wav", "m4a", "aac", "wma", "aiff", "alac", "mid",
+                "midi",
+            ],
+            FileType::Archives => &[
+                "zip", "7z", "rar", "tar", "gz", "tgz", "bz2", "xz", "zst", "iso",
+            ],
+        }
+    }
+}
+
+impl FileType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileType::Photos => "Photos",
+            FileType::Documents => "Documents",
+            FileType::Videos => "Videos",
+            FileType::Music => "Music",
+            FileType::Archives => "Archives",
+        }
+    }
+}
+
+impl Display for FileType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Create a (case insensitive) selection filter for a file type
+#[allow(unused)]
+pub fn selection_for_type(t: FileType) -> RegexSet {
+    selection_for_types(&[t])
+}
+
+/// Create a (case insensitive) selection filter that matches any of the file types
+pub fn selection_for_types(types: &[FileType]) -> RegexSet {
+    RegexSet::new(
+        types
+            .iter()
+            .flat_map(|t| t.extensions())
+            .map(|e| format!(r"(?i)\.{}$", regex::escape(e))),
+    )
+    .expect("The file type extensions should be valid regex")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{selection_for_type, selection_for_types, FileType};
+
+    #[test]
+    fn photos() {
+        let set = selection_for_type(FileType::Photos);
+        assert!(set.is_match("/home/user/Pictures/IMG_0001.jpg"));
+        assert!(set.is_match("/home/user/Pictures/IMG_0002.JPEG"));
+        assert!(set.is_match("holiday.HeIc"));
+        assert!(!set.is_match("/home/user/notes.txt"));
+        assert!(!set.is_match("/home/user/jpg"));
+        assert!(!set.is_match("/home/user/png.d/README"));
+        assert!(!set.is_match("/home/user/photo.jpg.part"));
+    }
+
+    #[test]
+    fn types() {
+        for t in FileType::ALL {
+            let set = selection_for_type(t);
+            for e in t.extensions() {
+                assert!(set.is_match(&format!("a/b.{}", e)));
+                assert!(set.is_match(&format!("a/B.{}", e.to_uppercase())));
+            }
+            assert!(!set.is_match("a/Makefile"));
+        }
+        let set = selection_for_types(&[FileType::Music, FileType::Videos]);
+        assert!(set.is_match("song.FLAC"));
+        assert!(set.is_match("movie.mkv"));
+        assert!(!set.is_match("report.pdf"));
+        assert!(!selection_for_types(&[]).is_match("anything.jpg"));
+    }
+}
@@ -1,15 +1,22 @@
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 /// This module contains the FileInfo struct and a file crawler
+use std::ffi::OsString;
 use std::fmt::Display;
-use std::fs::DirEntry;
+use std::fs::{DirEntry, File, Metadata};
+use std::io::{ErrorKind, Read};
 use std::path::{Path, PathBuf};
 
 use chrono::NaiveDateTime;
 use path_absolutize::Absolutize;
 use path_clean::PathClean;
 use regex::RegexSet;
+use serde::{Deserialize, Serialize};
 
+use crate::gitignore::IgnoreStack;
 use crate::parse_date;
+use crate::utils::{expand_path, long_path};
 
 /// A struct that contains both the PathBuf and String versions of a path
 #[derive(Debug, Eq, Clone)]
@@ -18,6 +25,17 @@ pub struct FileInfo {
     path: Option<PathBuf>,
     pub time: Option<NaiveDateTime>,
     pub size: u64,
+    pub hash: Option<u64>,
+    /// Device and inode of files with multiple hard links (only detected on Unix)
+    pub inode: Option<(u64, u64)>,
+    /// Time of the last status change (only available on Unix)
+    pub ctime: Option<NaiveDateTime>,
+    /// New permissions and owner, for files where only the metadata is stored
+    pub update: Option<MetadataUpdate>,
+    /// The output of a command (see [`crate::commands::BackupCommand`]) instead of a crawled file
+    pub generated: bool,
+    /// User-defined `key=value;key=value` pairs (see [`crate::commands::collect_metadata`])
+    pub user_meta: Option<String>,
 }
 
 impl From<PathBuf> for FileInfo {
@@ -27,6 +45,12 @@ impl From<PathBuf> for FileInfo {
             string: None,
             time: None,
             size: 0,
+            hash: None,
+            inode: None,
+            ctime: None,
+            update: None,
+            generated: false,
+            user_meta: None,
         }
     }
 }
@@ -37,6 +61,12 @@ impl From<&Path> for FileInfo {
             string: None,
             time: None,
             size: 0,
+            hash: None,
+            inode: None,
+            ctime: None,
+            update: None,
+            generated: false,
+            user_meta: None,
         }
     }
 }
@@ -47,6 +77,12 @@ impl From<&DirEntry> for FileInfo {
             string: None,
             time: None,
             size: 0,
+            hash: None,
+            inode: None,
+            ctime: None,
+            update: None,
+            generated: false,
+            user_meta: None,
         }
     }
 }
@@ -58,6 +94,12 @@ impl From<String> for FileInfo {
             string: Some(path),
             time: None,
             size: 0,
+            hash: None,
+            inode: None,
+            ctime: None,
+            update: None,
+            generated: false,
+            user_meta: None,
         }
     }
 }
@@ -69,6 +111,12 @@ impl From<&str> for FileInfo {
             string: Some(path.to_string()),
             time: None,
             size: 0,
+            hash: None,
+            inode: None,
+            ctime: None,
+            update: None,
+            generated: false,
+            user_meta: None,
         }
     }
 }
@@ -77,42 +125,243 @@ impl PartialEq for FileInfo {
     fn eq(&self, other: &Self) -> bool {
         if let Some(s1) = self.string.as_ref() {
             if let Some(s2) = other.string.as_ref() {
-                return s1 == s2;
+                return cmp_path_str(s1, s2).is_eq();
             }
         }
         if let Some(p1) = self.path.as_ref() {
             if let Some(p2) = other.path.as_ref() {
-                return p1 == p2;
+                return cmp_path(p1, p2).is_eq();
             }
         }
         false
     }
 }
 
-#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd for FileInfo {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        if let Some(s1) = self.string.as_ref() {
-            if let Some(s2) = other.string.as_ref() {
-                return Some(s1.cmp(s2));
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FileInfo {
+    /// Always compare the strings, so that crawled files are sorted the same way as the backups
+    /// (component-wise sorting would put `a/b` before `a b`)
+    fn cmp(&self, other: &Self) -> Ordering {
+        cmp_path_str(&self.copy_string(), &other.copy_string())
+    }
+}
+
+/// Compare path strings the way the file system does (case insensitive on Windows)
+pub fn cmp_path_str(a: &str, b: &str) -> Ordering {
+    #[cfg(target_os = "windows")]
+    return a
+        .chars()
+        .flat_map(char::to_lowercase)
+        .cmp(b.chars().flat_map(char::to_lowercase));
+    #[cfg(not(target_os = "windows"))]
+    a.cmp(b)
+}
+
+/// Compare paths component-wise the way the file system does (case insensitive on Windows)
+pub fn cmp_path(a: &Path, b: &Path) -> Ordering {
+    #[cfg(target_os = "windows")]
+    return a
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_lowercase())
+        .cmp(
+            b.components()
+                .map(|c| c.as_os_str().to_string_lossy().to_lowercase()),
+        );
+    #[cfg(not(target_os = "windows"))]
+    a.cmp(b)
+}
+
+/// Sort paths in the order that they are stored in backups (see [`cmp_path_str`]), removing duplicates
+pub fn sort_paths<S: AsRef<str>>(paths: &mut Vec<S>) {
+    paths.sort_unstable_by(|a, b| cmp_path_str(a.as_ref(), b.as_ref()));
+    paths.dedup_by(|a, b| a.as_ref() == b.as_ref());
+}
+
+/// The private use characters `U+EF80..=U+EFFF` stand for the bytes `0x80..=0xFF` of file names
+/// that are not valid UTF-8 (Unix only), so that such names survive the conversion to a string.
+/// A name that really contains these characters (or the escape `U+EF00`) has them escaped with
+/// `U+EF00`, so that the conversion back is unambiguous.
+const RAW_BYTE_CHARS: u32 = 0xEF00;
+const RAW_ESCAPE: char = '\u{EF00}';
+
+/// The byte that a character stands for (see [`bytes_to_string`])
+fn raw_byte(c: char) -> Option<u8> {
+    match c as u32 {
+        c @ 0xEF80..=0xEFFF => Some((c - RAW_BYTE_CHARS) as u8),
+        _ => None,
+    }
+}
+
+/// Is the character a byte or an escape (see [`bytes_to_string`])
+pub fn is_raw_char(c: char) -> bool {
+    c == RAW_ESCAPE || raw_byte(c).is_some()
+}
+
+/// A character of a string from [`bytes_to_string`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RawChar {
+    Char(char),
+    /// A byte that is not valid UTF-8
+    Byte(u8),
+}
+
+/// The characters of a string from [`bytes_to_string`] (with the escapes removed)
+pub fn raw_chars(string: &str) -> impl Iterator<Item = RawChar> + '_ {
+    let mut chars = string.chars();
+    std::iter::from_fn(move || {
+        let c = chars.next()?;
+        Some(match raw_byte(c) {
+            Some(b) => RawChar::Byte(b),
+            None if c == RAW_ESCAPE => RawChar::Char(chars.next().unwrap_or(c)),
+            None => RawChar::Char(c),
+        })
+    })
+}
+
+/// Convert bytes to a string, with the bytes that are not valid UTF-8 as private use characters
+/// (that [`string_to_path`] converts back)
+pub fn bytes_to_string(bytes: &[u8]) -> Cow<'_, str> {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.contains(is_raw_char) => return Cow::Borrowed(s),
+        _ => {}
+    }
+    let mut string = String::with_capacity(bytes.len() + 8);
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if is_raw_char(c) {
+                string.push(RAW_ESCAPE);
             }
+            string.push(c);
         }
-        if let Some(p1) = self.path.as_ref() {
-            if let Some(p2) = other.path.as_ref() {
-                return Some(p1.cmp(p2));
+        string.extend(
+            chunk
+                .invalid()
+                .iter()
+                .filter_map(|b| char::from_u32(RAW_BYTE_CHARS + *b as u32)),
+        );
+    }
+    Cow::Owned(string)
+}
+
+/// Convert a path to a string without losing the bytes that are not valid UTF-8 (on Unix, see
+/// [`bytes_to_string`])
+pub fn path_to_string(path: &Path) -> Cow<'_, str> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        bytes_to_string(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    match path.to_string_lossy() {
+        Cow::Borrowed(s) => bytes_to_string(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(bytes_to_string(s.as_bytes()).into_owned()),
+    }
+}
+
+/// Convert a string from [`path_to_string`] back to the path
+pub fn string_to_path(string: &str) -> PathBuf {
+    if !string.contains(is_raw_char) {
+        return PathBuf::from(string);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        let mut bytes = Vec::with_capacity(string.len());
+        let mut buf = [0; 4];
+        for c in raw_chars(string) {
+            match c {
+                RawChar::Byte(b) => bytes.push(b),
+                RawChar::Char(c) => bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes()),
             }
         }
-        None
+        PathBuf::from(OsString::from_vec(bytes))
     }
+    // Names from Unix with bytes that are not valid UTF-8 keep the private use characters
+    #[cfg(not(unix))]
+    PathBuf::from(
+        raw_chars(string)
+            .filter_map(|c| match c {
+                RawChar::Byte(b) => char::from_u32(RAW_BYTE_CHARS + b as u32),
+                RawChar::Char(c) => Some(c),
+            })
+            .collect::<String>(),
+    )
 }
 
-impl Ord for FileInfo {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        match self.partial_cmp(other) {
-            Some(o) => o,
-            None => self.copy_string().cmp(&other.copy_string()),
+/// Normalise an include or exclude the way the crawler does: expand `~` and environment variables,
+/// and make the path absolute (from `cwd`) unless the paths are `local`
+pub fn normalise_crawl_path(path: &str, local: bool, cwd: &Path) -> std::io::Result<PathBuf> {
+    let path = PathBuf::from(expand_path(path));
+    if local {
+        Ok(normalise_drive(path.clean()))
+    } else {
+        path.absolutize_from(cwd)
+            .map(|p| normalise_drive(p.to_path_buf()))
+    }
+}
+
+/// Find the includes that are inside another include (also through symlinks), so that crawling the
+/// other include reaches them (unless a path between them is excluded). Returns the index of each
+/// such include, the index of the outermost include containing it, and the levels between them.
+pub fn nested_includes(
+    includes: &[PathBuf],
+    is_excluded: impl Fn(&Path) -> bool,
+) -> Vec<(usize, usize, usize)> {
+    let canonical: Vec<PathBuf> = includes
+        .iter()
+        .map(|p| long_path(p).canonicalize().unwrap_or_else(|_| p.clone()))
+        .collect();
+    let mut first = HashMap::with_capacity(canonical.len());
+    for (i, c) in canonical.iter().enumerate() {
+        first.entry(c.as_path()).or_insert(i);
+    }
+    let mut nested = vec![];
+    for (i, c) in canonical.iter().enumerate() {
+        // The outermost include containing this one (of the same paths, the first is kept)
+        let outer = c
+            .ancestors()
+            .enumerate()
+            .filter_map(|(depth, a)| Some((depth, *first.get(a)?)))
+            .filter(|(_, j)| *j != i)
+            .last();
+        if let Some((depth, j)) = outer {
+            let through = includes[j].join(c.strip_prefix(&canonical[j]).unwrap_or(c));
+            if !through.ancestors().take(depth).any(&is_excluded) {
+                nested.push((i, j, depth));
+            }
+        }
+    }
+    nested
+}
+
+/// Use an uppercase drive letter on Windows (`absolutize` keeps whatever casing was typed)
+fn normalise_drive(path: PathBuf) -> PathBuf {
+    #[cfg(target_os = "windows")]
+    {
+        use std::path::{Component, Prefix};
+        let offset = match path.components().next() {
+            Some(Component::Prefix(p)) => match p.kind() {
+                Prefix::Disk(d) if d.is_ascii_lowercase() => 0,
+                Prefix::VerbatimDisk(d) if d.is_ascii_lowercase() => 4,
+                _ => return path,
+            },
+            _ => return path,
+        };
+        match path.into_os_string().into_string() {
+            Ok(mut s) => {
+                s[offset..=offset].make_ascii_uppercase();
+                PathBuf::from(s)
+            }
+            Err(s) => PathBuf::from(s),
         }
     }
+    #[cfg(not(target_os = "windows"))]
+    path
 }
 
 impl FileInfo {
@@ -123,6 +372,12 @@ impl FileInfo {
             string: Some(string),
             time: None,
             size: 0,
+            hash: None,
+            inode: None,
+            ctime: None,
+            update: None,
+            generated: false,
+            user_meta: None,
         }
     }
 
@@ -134,32 +389,33 @@ impl FileInfo {
     /// Returns the String version (with lazy conversion)
     pub fn get_string(&mut self) -> &String {
         if self.string.is_none() {
-            self.string = Some(self.path.as_ref().unwrap().to_string_lossy().to_string())
+            self.string = Some(path_to_string(self.path.as_ref().unwrap()).into_owned())
         }
         self.string.as_ref().unwrap()
     }
 
     /// Returns the String version (with lazy conversion) without mutation
-    pub fn copy_string(&self) -> Cow<str> {
+    pub fn copy_string(&self) -> Cow<'_, str> {
         match self.string.as_ref() {
             Some(s) => Cow::Borrowed(s),
-            None => self.path.as_ref().unwrap().to_string_lossy(),
+            None => path_to_string(self.path.as_ref().unwrap()),
         }
     }
 
     /// Returns the PathBuf version (with lazy conversion)
     pub fn get_path(&mut self) -> &PathBuf {
         if self.path.is_none() {
-            self.path = Some(PathBuf::from(self.string.as_ref().unwrap()))
+            self.path = Some(string_to_path(self.string.as_ref().unwrap()))
         }
         self.path.as_ref().unwrap()
     }
 
     /// Returns the Pathbuf version (with lazy conversion) without mutation
-    pub fn copy_path(&self) -> Cow<PathBuf> {
+    #[allow(clippy::owned_cow)]
+    pub fn copy_path(&self) -> Cow<'_, PathBuf> {
         match self.path.as_ref() {
             Some(s) => Cow::Borrowed(s),
-            None => Cow::Owned(PathBuf::from(self.string.as_ref().unwrap())),
+            None => Cow::Owned(string_to_path(self.string.as_ref().unwrap())),
         }
     }
 
@@ -167,7 +423,7 @@ impl FileInfo {
     pub fn clone_path(&self) -> PathBuf {
         match self.path.as_ref() {
             Some(s) => s.to_path_buf(),
-            None => PathBuf::from(self.string.as_ref().unwrap()),
+            None => string_to_path(self.string.as_ref().unwrap()),
         }
     }
 
@@ -175,20 +431,298 @@ impl FileInfo {
     pub fn consume_path(self) -> PathBuf {
         match self.path {
             Some(path) => path,
-            None => PathBuf::from(&self.string.unwrap()),
+            None => string_to_path(&self.string.unwrap()),
         }
     }
 
     /// Move the String version out (with minimal allocation)
     pub fn move_string(&mut self) -> String {
         if self.string.is_none() {
-            self.path.as_ref().unwrap().to_string_lossy().to_string()
+            path_to_string(self.path.as_ref().unwrap()).into_owned()
         } else if self.path.is_none() {
             self.string.as_ref().unwrap().to_string()
         } else {
             std::mem::take(&mut self.string).unwrap()
         }
     }
+
+    /// Hash the content of the file (64-bit FNV-1a) and store the result
+    pub fn hash_content(&mut self) -> std::io::Result<u64> {
+        let mut file = File::open(self.get_path())?;
+        let mut buffer = vec![0u8; 1 << 16];
+        let mut hash = FNV_OFFSET;
+        loop {
+            let n = match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            hash = fnv1a(hash, &buffer[..n]);
+        }
+        self.hash = Some(hash);
+        Ok(hash)
+    }
+
+    /// Has the file been modified since `time` (the change time falls back to the modification time)
+    pub fn is_changed(&self, time: NaiveDateTime, detection: ChangeDetection) -> bool {
+        let modified = self.time.is_some_and(|t| t >= time);
+        let changed = self.ctime.or(self.time).is_some_and(|t| t >= time);
+        match detection {
+            ChangeDetection::Mtime => modified,
+            ChangeDetection::Ctime => changed,
+            ChangeDetection::Either => modified || changed,
+        }
+    }
+}
+
+/// Which timestamp decides if a file has been modified (for incremental backups)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeDetection {
+    /// The modification time (changes when the content is written)
+    #[default]
+    Mtime,
+    /// The status change time (also changes with permissions, owner, and renames)
+    Ctime,
+    /// Either of the timestamps
+    Either,
+}
+
+/// The permissions and owner of a file (stored instead of the content when only these have changed)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataUpdate {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl MetadataUpdate {
+    /// Read the current permissions and owner of a file
+    #[cfg(unix)]
+    pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let md = path.as_ref().metadata()?;
+        Ok(Self {
+            mode: md.mode() & 0o7777,
+            uid: md.uid(),
+            gid: md.gid(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn read<P: AsRef<Path>>(_path: P) -> std::io::Result<Self> {
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "Metadata updates are only supported on Unix",
+        ))
+    }
+
+    /// Apply the permissions (and the owner, if allowed) to a file
+    #[cfg(unix)]
+    pub fn apply<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let path = path.as_ref();
+        // Like when unpacking, changing the owner is only attempted (it requires privileges)
+        #[allow(unused_must_use)]
+        std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid));
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(self.mode))
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply<P: AsRef<Path>>(&self, _path: P) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Parse the `mode:uid:gid` form (with an octal mode)
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.splitn(3, ':');
+        Some(Self {
+            mode: u32::from_str_radix(parts.next()?, 8).ok()?,
+            uid: parts.next()?.parse().ok()?,
+            gid: parts.next()?.parse().ok()?,
+        })
+    }
+}
+
+impl Display for MetadataUpdate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:o}:{}:{}", self.mode, self.uid, self.gid)
+    }
+}
+
+/// The size of the blocks in a [`BlockSignature`] (the unit of the delta storage)
+pub const DELTA_BLOCK_SIZE: u64 = 1 << 20;
+
+/// The hashes of the fixed-size blocks of a file (see [`DELTA_BLOCK_SIZE`]), so that only the
+/// changed blocks of large files need to be stored in incremental backups
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BlockSignature {
+    pub size: u64,
+    /// 64-bit FNV-1a hashes of the blocks (the last block might be shorter)
+    pub blocks: Vec<u64>,
+}
+
+impl BlockSignature {
+    /// Hash the blocks of a file
+    pub fn read<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::from_reader(File::open(long_path(path.as_ref()))?)
+    }
+
+    /// Hash the blocks of the content from a reader
+    pub fn from_reader<R: Read>(mut reader: R) -> std::io::Result<Self> {
+        let mut signature = Self::default();
+        let mut buffer = vec![0u8; 1 << 16];
+        let (mut hash, mut filled) = (FNV_OFFSET, 0);
+        loop {
+            let want = (DELTA_BLOCK_SIZE - filled).min(buffer.len() as u64) as usize;
+            let n = match reader.read(&mut buffer[..want]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            hash = fnv1a(hash, &buffer[..n]);
+            filled += n as u64;
+            signature.size += n as u64;
+            if filled == DELTA_BLOCK_SIZE {
+                signature.blocks.push(hash);
+                (hash, filled) = (FNV_OFFSET, 0);
+            }
+        }
+        if filled > 0 {
+            signature.blocks.push(hash);
+        }
+        Ok(signature)
+    }
+
+    /// A hash of the whole signature (to check that a file has the expected content)
+    pub fn digest(&self) -> u64 {
+        self.blocks
+            .iter()
+            .fold(fnv1a(FNV_OFFSET, &self.size.to_le_bytes()), |h, b| {
+                fnv1a(h, &b.to_le_bytes())
+            })
+    }
+
+    /// The indices of the blocks that differ from a previous version of the file
+    /// (including the blocks after the end of the previous version)
+    pub fn changed_blocks(&self, previous: &Self) -> Vec<u64> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(|(i, b)| {
+                previous.blocks.get(*i) != Some(b)
+                    || self.block_len(*i as u64) != previous.block_len(*i as u64)
+            })
+            .map(|(i, _)| i as u64)
+            .collect()
+    }
+
+    /// The length of a block (the last block might be shorter)
+    pub fn block_len(&self, index: u64) -> u64 {
+        self.size
+            .saturating_sub(index * DELTA_BLOCK_SIZE)
+            .min(DELTA_BLOCK_SIZE)
+    }
+
+    /// Parse the `size,hash hash ...` form (with hexadecimal hashes)
+    pub fn parse(s: &str) -> Option<Self> {
+        let (size, blocks) = s.split_once(',')?;
+        let signature = Self {
+            size: size.parse().ok()?,
+            blocks: blocks
+                .split_whitespace()
+                .map(|b| u64::from_str_radix(b, 16).ok())
+                .collect::<Option<_>>()?,
+        };
+        (signature.blocks.len() as u64 == signature.size.div_ceil(DELTA_BLOCK_SIZE))
+            .then_some(signature)
+    }
+}
+
+impl Display for BlockSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},", self.size)?;
+        for (i, b) in self.blocks.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// A file that is stored as the blocks that changed since the previous backup (the other blocks
+/// are taken from the previous version of the file when restoring)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaManifest {
+    pub size: u64,
+    /// The [`BlockSignature::digest`] of the previous version
+    pub base: u64,
+    /// The [`BlockSignature::digest`] of this version
+    pub digest: u64,
+    /// The indices of the stored blocks (in the order they are stored)
+    pub blocks: Vec<u64>,
+}
+
+impl DeltaManifest {
+    /// The number of stored bytes
+    pub fn stored_size(&self) -> u64 {
+        self.blocks
+            .iter()
+            .map(|i| {
+                self.size
+                    .saturating_sub(i * DELTA_BLOCK_SIZE)
+                    .min(DELTA_BLOCK_SIZE)
+            })
+            .sum()
+    }
+
+    /// Parse the `size base digest blocks` form (with hexadecimal digests and comma separated
+    /// block indices)
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split(' ');
+        let manifest = Self {
+            size: parts.next()?.parse().ok()?,
+            base: u64::from_str_radix(parts.next()?, 16).ok()?,
+            digest: u64::from_str_radix(parts.next()?, 16).ok()?,
+            blocks: match parts.next()? {
+                "" => vec![],
+                blocks => blocks
+                    .split(',')
+                    .map(|b| b.parse().ok())
+                    .collect::<Option<_>>()?,
+            },
+        };
+        parts.next().is_none().then_some(manifest)
+    }
+}
+
+impl Display for DeltaManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:x} {:x} ", self.size, self.base, self.digest)?;
+        for (i, b) in self.blocks.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// The initial state of a 64-bit FNV-1a hash
+pub(crate) const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Continue a 64-bit FNV-1a hash with more bytes
+pub(crate) fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
 }
 
 impl Display for FileInfo {
@@ -198,7 +732,7 @@ impl Display for FileInfo {
             None => write!(
                 f,
                 "FileInfo({})",
-                self.path.as_ref().unwrap().to_string_lossy()
+                path_to_string(self.path.as_ref().unwrap())
             ),
         }
     }
@@ -216,20 +750,186 @@ impl std::fmt::Display for FileAccessError {
     }
 }
 
-impl std::error::Error for FileAccessError {}
+/// The error is part of the message, so only the cause of it is the source
+impl std::error::Error for FileAccessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
 
 impl FileAccessError {
     fn new(error: std::io::Error, path: String) -> Self {
         Self { error, path }
     }
+
+    /// The file (or directory) that could not be accessed
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn error(&self) -> &std::io::Error {
+        &self.error
+    }
+
+    /// A directory (e.g. a symlink or junction) that leads back to one of its ancestors
+    fn filesystem_loop(path: String, ancestor: &Path) -> Self {
+        let error = std::io::Error::other(format!(
+            "Skipping filesystem loop back to '{}'",
+            ancestor.to_string_lossy()
+        ));
+        Self { error, path }
+    }
+}
+
+#[cfg(unix)]
+type DirId = (u64, u64);
+
+/// Identify a directory (by device and inode)
+#[cfg(unix)]
+fn dir_id(_path: &Path, md: &Metadata) -> Option<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((md.dev(), md.ino()))
+}
+
+#[cfg(windows)]
+type DirId = same_file::Handle;
+
+/// Identify a directory (by volume serial number and file index)
+#[cfg(windows)]
+fn dir_id(path: &Path, _md: &Metadata) -> Option<DirId> {
+    same_file::Handle::from_path(path).ok()
+}
+
+#[cfg(not(any(unix, windows)))]
+type DirId = ();
+
+#[cfg(not(any(unix, windows)))]
+fn dir_id(_path: &Path, _md: &Metadata) -> Option<DirId> {
+    None
+}
+
+/// The time of the last status change
+#[cfg(unix)]
+fn change_time(md: &Metadata) -> Option<NaiveDateTime> {
+    use std::os::unix::fs::MetadataExt;
+    let secs = u64::try_from(md.ctime()).ok()?;
+    let time = std::time::UNIX_EPOCH + std::time::Duration::new(secs, md.ctime_nsec() as u32);
+    Some(parse_date::system_to_naive(time))
+}
+
+#[cfg(not(unix))]
+fn change_time(_md: &Metadata) -> Option<NaiveDateTime> {
+    None
+}
+
+/// Identify a file with multiple hard links (by device and inode)
+#[cfg(unix)]
+fn hard_link_id(md: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    if md.nlink() > 1 {
+        Some((md.dev(), md.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn hard_link_id(_md: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The directories on the path from the include root to the current directory
+#[derive(Default)]
+struct Ancestry(Vec<(PathBuf, DirId)>);
+
+impl Ancestry {
+    /// Enter a directory, returning the ancestor with the same identity if this is a loop
+    fn enter(&mut self, path: &Path, id: Option<DirId>) -> Option<&Path> {
+        while let Some((p, _)) = self.0.last() {
+            if path.starts_with(p) || (p == Path::new(".") && path.is_relative()) {
+                break;
+            }
+            self.0.pop();
+        }
+        let id = id?;
+        match self.0.iter().position(|(_, i)| *i == id) {
+            Some(i) => Some(&self.0[i].0),
+            None => {
+                self.0.push((path.to_path_buf(), id));
+                None
+            }
+        }
+    }
+}
+
+/// A normalised form of a path, such that equal keys means equal paths (as with [`cmp_path`])
+fn path_key(path: &Path) -> OsString {
+    let key = path.components().collect::<PathBuf>().into_os_string();
+    #[cfg(target_os = "windows")]
+    let key = OsString::from(key.to_string_lossy().to_lowercase());
+    key
+}
+
+/// The unfinished part of a crawl (see [`FileCrawler::frontier`] and [`FileCrawler::resume`])
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrawlFrontier {
+    /// Paths that have not been looked at yet
+    pub pending: Vec<String>,
+    /// Directories that have been read (their content is either crawled or pending)
+    pub visited: Vec<String>,
+}
+
+impl CrawlFrontier {
+    /// The visited directories that have been modified (or removed) since `since`,
+    /// without the subdirectories of other stale directories
+    pub fn stale_dirs(&self, since: NaiveDateTime) -> Vec<String> {
+        let mut stale: Vec<&String> = self
+            .visited
+            .iter()
+            .filter(
+                |d| match Path::new(d).metadata().and_then(|md| md.modified()) {
+                    Ok(time) => parse_date::system_to_naive(time) >= since,
+                    Err(_) => true,
+                },
+            )
+            .collect();
+        stale.sort_unstable();
+        let mut top: Vec<String> = vec![];
+        for d in stale {
+            if !top.iter().any(|t| Path::new(d).starts_with(t)) {
+                top.push(d.clone());
+            }
+        }
+        top
+    }
 }
 
 /// Iterator for crawling through files to backup
 pub struct FileCrawler {
     temp: Vec<(FileInfo, DirEntry)>,
     stack: Vec<FileInfo>,
+    /// The included paths (as comparable keys, see [`path_key`])
+    include: Vec<OsString>,
+    /// Excluded paths (exact matches, sorted)
+    exclude: Vec<String>,
     regex: RegexSet,
     local: bool,
+    /// Also yield directories that are empty (after exclusions)
+    empty_dirs: bool,
+    /// The directories that have been read (only tracked for [`FileCrawler::frontier`])
+    visited: Option<Vec<String>>,
+    ancestry: Ancestry,
+    /// The active `.gitignore` patterns (if they are honoured)
+    gitignore: Option<IgnoreStack>,
+    /// Do not read directories this many levels below an include
+    max_depth: Option<usize>,
+    /// Skip files modified before this time
+    newer_than: Option<NaiveDateTime>,
+    /// Skip files modified at or after this time
+    older_than: Option<NaiveDateTime>,
+    /// Includes inside other includes that are crawled from those instead (with the levels
+    /// between them, see [`nested_includes`])
+    nested: Vec<(FileInfo, usize)>,
 }
 
 impl FileCrawler {
@@ -247,64 +947,257 @@ impl FileCrawler {
         filter: VS3,
         local: bool,
     ) -> Result<Self, std::io::Error> {
-        let mut stack: Vec<FileInfo>;
-        let exc: Vec<String>;
-        if local {
-            stack = include
-                .as_ref()
-                .iter()
-                .map(|s| FileInfo::from(PathBuf::from(s.as_ref()).clean()))
-                .collect();
-            exc = exclude
-                .as_ref()
-                .iter()
-                .map(|s| {
-                    format!(
-                        "^{}$",
-                        regex::escape(&PathBuf::from(s.as_ref()).clean().to_string_lossy())
-                    )
-                })
-                .collect::<Vec<String>>();
+        // Only look up the working directory once (instead of once per path)
+        let cwd = if local || (include.as_ref().is_empty() && exclude.as_ref().is_empty()) {
+            PathBuf::new()
         } else {
-            stack = include
-                .as_ref()
-                .iter()
-                .map(|s| {
-                    PathBuf::from(s.as_ref())
-                        .absolutize()
-                        .map(|p| FileInfo::from(p.to_path_buf()))
-                })
-                .collect::<std::io::Result<Vec<FileInfo>>>()?;
-            exc = exclude
-                .as_ref()
-                .iter()
-                .map(|s| {
-                    PathBuf::from(s.as_ref())
-                        .absolutize()
-                        .map(|p| format!("^{}$", regex::escape(&p.to_string_lossy())))
-                })
-                .collect::<std::io::Result<Vec<String>>>()?;
-        }
-        stack.sort_unstable_by(|a, b| b.path.as_ref().unwrap().cmp(a.path.as_ref().unwrap()));
+            std::env::current_dir()?
+        };
+        let mut stack = include
+            .as_ref()
+            .iter()
+            .map(|s| normalise_crawl_path(s.as_ref(), local, &cwd).map(FileInfo::from))
+            .collect::<std::io::Result<Vec<FileInfo>>>()?;
+        let mut exc = exclude
+            .as_ref()
+            .iter()
+            .map(|s| {
+                normalise_crawl_path(s.as_ref(), local, &cwd)
+                    .map(|p| path_to_string(&p).into_owned())
+            })
+            .collect::<std::io::Result<Vec<String>>>()?;
+        stack.sort_unstable_by(|a, b| cmp_path(b.path.as_ref().unwrap(), a.path.as_ref().unwrap()));
+        stack.dedup_by(|a, b| cmp_path(a.path.as_ref().unwrap(), b.path.as_ref().unwrap()).is_eq());
+        exc.sort_unstable_by(|a, b| cmp_path_str(a, b));
+        exc.dedup_by(|a, b| cmp_path_str(a, b).is_eq());
+        let mut include: Vec<OsString> = stack
+            .iter()
+            .map(|fi| path_key(fi.path.as_ref().unwrap()))
+            .collect();
+        include.sort_unstable();
 
         let regex = RegexSet::new(
             filter
                 .as_ref()
                 .iter()
                 .filter(|s| !s.as_ref().is_empty())
-                .map(|s| s.as_ref())
-                .chain(exc.iter().map(|s| s.as_str())),
+                .map(|s| s.as_ref()),
         )
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
 
-        Ok(Self {
+        let mut crawler = Self {
             stack,
+            include,
+            exclude: exc,
             regex,
             temp: vec![],
             local,
+            empty_dirs: false,
+            visited: None,
+            ancestry: Ancestry::default(),
+            gitignore: None,
+            max_depth: None,
+            newer_than: None,
+            older_than: None,
+            nested: vec![],
+        };
+        crawler.skip_nested();
+        Ok(crawler)
+    }
+
+    /// Only crawl the includes that are inside other includes from those (so that the files are
+    /// not found twice, e.g. through a symlink)
+    fn skip_nested(&mut self) {
+        // In the order of the paths (the stack is reversed), so that the first of aliases is kept
+        let paths: Vec<PathBuf> = self.stack.iter().rev().map(|fi| fi.clone_path()).collect();
+        let mut nested = nested_includes(&paths, |p| self.is_excluded(&path_to_string(p)));
+        nested.sort_unstable_by_key(|(i, _, _)| *i);
+        for (i, j, depth) in nested {
+            log::warn!(
+                "The include '{}' is inside '{}' (it is crawled from there)",
+                path_to_string(&paths[i]),
+                path_to_string(&paths[j])
+            );
+            let fi = self.stack.remove(paths.len() - 1 - i);
+            self.nested.push((fi, depth));
+        }
+    }
+
+    /// Crawl the nested includes (see [`Self::skip_nested`]) on their own after all, e.g. when
+    /// crawling the outer include would not reach them
+    fn crawl_nested(&mut self, separately: impl Fn(&FileInfo, usize) -> bool) {
+        let (separate, nested): (Vec<_>, Vec<_>) = std::mem::take(&mut self.nested)
+            .into_iter()
+            .partition(|(fi, depth)| separately(fi, *depth));
+        self.nested = nested;
+        if !separate.is_empty() {
+            self.stack.extend(separate.into_iter().map(|(fi, _)| fi));
+            self.stack.sort_unstable_by(|a, b| {
+                cmp_path(b.path.as_ref().unwrap(), a.path.as_ref().unwrap())
+            });
+        }
+    }
+
+    /// Crawl these includes on their own even if they are inside another include (so that the
+    /// files are found under these paths, e.g. for [`crate::config::Config::required`])
+    pub fn with_separate<S: AsRef<str>, VS: AsRef<[S]>>(mut self, paths: VS) -> Self {
+        if self.nested.is_empty() || paths.as_ref().is_empty() {
+            return self;
+        }
+        let cwd = if self.local {
+            PathBuf::new()
+        } else {
+            std::env::current_dir().unwrap_or_default()
+        };
+        let paths: Vec<PathBuf> = paths
+            .as_ref()
+            .iter()
+            .filter_map(|s| normalise_crawl_path(s.as_ref(), self.local, &cwd).ok())
+            .collect();
+        self.crawl_nested(|fi, _| {
+            paths
+                .iter()
+                .any(|p| cmp_path(p, fi.path.as_ref().unwrap()).is_eq())
+        });
+        self
+    }
+
+    /// Also yield directories that contain nothing to backup (so that they can be recreated)
+    pub fn with_empty_dirs(mut self, enabled: bool) -> Self {
+        self.empty_dirs = enabled;
+        self
+    }
+
+    /// Skip the files that are ignored by `.gitignore` files (and the global git ignore file)
+    pub fn with_gitignore(mut self, enabled: bool) -> Self {
+        if enabled {
+            // The directories between the includes might be ignored
+            self.crawl_nested(|_, _| true);
+        }
+        self.gitignore = if enabled {
+            Some(IgnoreStack::new(
+                self.stack.iter().map(|fi| fi.clone_path()).collect(),
+            ))
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Limit how deep the crawler descends below each include (`Some(1)` only reads the includes)
+    pub fn with_max_depth(mut self, depth: Option<usize>) -> Self {
+        if let Some(max) = depth {
+            self.crawl_nested(|_, d| d >= max);
+        }
+        self.max_depth = depth;
+        self
+    }
+
+    /// Only yield the files modified at or after `newer_than` and before `older_than`
+    /// (based on the modification time, unlike incremental backups this is not relative to the
+    /// previous backup)
+    pub fn with_age(
+        mut self,
+        newer_than: Option<NaiveDateTime>,
+        older_than: Option<NaiveDateTime>,
+    ) -> Self {
+        self.newer_than = newer_than;
+        self.older_than = older_than;
+        self
+    }
+
+    /// Is the modification time outside the range of [`Self::with_age`]
+    fn is_wrong_age(&self, time: NaiveDateTime) -> bool {
+        self.newer_than.is_some_and(|t| time < t) || self.older_than.is_some_and(|t| time >= t)
+    }
+
+    /// Keep track of the read directories (so that the crawl can be resumed later)
+    pub fn track_visited(mut self) -> Self {
+        if self.visited.is_none() {
+            self.visited = Some(vec![]);
+        }
+        self
+    }
+
+    /// The paths that remain to be crawled (and the directories that already have been)
+    pub fn frontier(&self) -> CrawlFrontier {
+        CrawlFrontier {
+            pending: self
+                .stack
+                .iter()
+                .rev()
+                .map(|fi| fi.copy_string().to_string())
+                .collect(),
+            visited: self.visited.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Continue an earlier crawl from the frontier instead of the includes.
+    /// The `stale` directories (see [`CrawlFrontier::stale_dirs`]) are crawled again from scratch.
+    pub fn resume(mut self, frontier: CrawlFrontier, stale: &[String]) -> Self {
+        let is_stale = |s: &str| stale.iter().any(|d| Path::new(s).starts_with(d));
+        self.stack = frontier
+            .pending
+            .into_iter()
+            .filter(|s| !is_stale(s))
+            .chain(stale.iter().filter(|d| Path::new(d).exists()).cloned())
+            .map(|s| FileInfo::from_both(string_to_path(&s), s))
+            .collect();
+        self.stack
+            .sort_unstable_by(|a, b| cmp_path(b.path.as_ref().unwrap(), a.path.as_ref().unwrap()));
+        self.stack
+            .dedup_by(|a, b| cmp_path(a.path.as_ref().unwrap(), b.path.as_ref().unwrap()).is_eq());
+        if let Some(visited) = self.visited.as_mut() {
+            *visited = frontier
+                .visited
+                .into_iter()
+                .filter(|d| !is_stale(d))
+                .collect();
+        }
+        self.ancestry = Ancestry::default();
+        self
+    }
+
+    /// Check if a path is explicitly excluded or matches a filter
+    fn is_excluded(&self, path: &str) -> bool {
+        self.exclude
+            .binary_search_by(|e| cmp_path_str(e, path))
+            .is_ok()
+            || self.regex.is_match(path)
+    }
+
+    /// Check if a path in the current directory is ignored by a `.gitignore`
+    /// (explicitly included paths are never ignored)
+    fn is_gitignored(&self, path: &Path, is_dir: impl FnOnce() -> bool) -> bool {
+        match self.gitignore.as_ref() {
+            Some(ignores) => {
+                ignores.is_ignored(path, is_dir())
+                    && self.include.binary_search(&path_key(path)).is_err()
+            }
+            None => false,
+        }
+    }
+
+    /// The number of levels between the path and the (innermost) include that contains it
+    fn depth(&self, path: &Path) -> Option<usize> {
+        path.ancestors().position(|p| {
+            let p = if p.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                p
+            };
+            self.include.binary_search(&path_key(p)).is_ok()
         })
     }
 
+    /// Is the directory at (or beyond) the depth limit, so that it should not be read
+    fn is_too_deep(&self, dir: &Path) -> bool {
+        match self.max_depth {
+            Some(max) => self.depth(dir).is_some_and(|d| d >= max),
+            None => false,
+        }
+    }
+
     #[allow(unused)]
     pub fn check_path(&self, path: &mut FileInfo, parent_included: Option<bool>) -> bool {
         let p = path.get_path();
@@ -320,14 +1213,22 @@ impl FileCrawler {
         } else {
             p
         };
-        if self
-            .stack
-            .binary_search_by(|fi| p.cmp(fi.path.as_ref().unwrap()))
-            .is_ok()
-        {
+        if self.include.binary_search(&path_key(p)).is_ok() {
             return true;
         }
-        if self.regex.is_match(path.get_string()) {
+        if p.parent().is_some_and(|d| self.is_too_deep(d)) {
+            return false;
+        }
+        if let Some(ignores) = self.gitignore.as_ref() {
+            let mut ignores = ignores.clone();
+            if let Some(dir) = p.parent() {
+                ignores.enter(dir);
+            }
+            if ignores.is_ignored(p, p.is_dir()) {
+                return false;
+            }
+        }
+        if self.is_excluded(path.get_string()) {
             return false;
         }
         match parent_included {
@@ -351,8 +1252,10 @@ fn dir_read<P: AsRef<Path>>(
     dir.as_ref().read_dir()
 }
 
-fn dir_path(d: &DirEntry, local: bool) -> PathBuf {
-    let path = d.path();
+/// The path of a directory entry (joined with the directory as given, so that any extended-length
+/// prefix from [`long_path`] does not leak into the path)
+fn dir_path(dir: &Path, d: &DirEntry, local: bool) -> PathBuf {
+    let path = dir.join(d.file_name());
     if local && path.is_relative() {
         path.clean()
     } else {
@@ -365,35 +1268,70 @@ impl Iterator for FileCrawler {
 
     fn next(&mut self) -> Option<Self::Item> {
         while let Some(mut item) = self.stack.pop() {
-            let md = try_some!(item
-                .get_path()
+            let md = try_some!(long_path(item.get_path())
                 .metadata()
                 .map_err(|e| FileAccessError::new(e, item.move_string())));
             if md.is_file() {
-                item.time = Some(parse_date::system_to_naive(try_some!(md
+                let time = parse_date::system_to_naive(try_some!(md
                     .modified()
-                    .map_err(|e| FileAccessError::new(e, item.move_string())))));
+                    .map_err(|e| FileAccessError::new(e, item.move_string()))));
+                if self.is_wrong_age(time) {
+                    continue;
+                }
+                item.time = Some(time);
+                item.ctime = change_time(&md);
                 item.size = md.len();
+                item.inode = hard_link_id(&md);
                 return Some(Ok(item));
             } else {
                 let string = item.move_string();
                 let path = item.consume_path();
+                if self.is_too_deep(&path) {
+                    continue;
+                }
+                if let Some(ancestor) = self.ancestry.enter(&path, dir_id(&path, &md)) {
+                    return Some(Err(FileAccessError::filesystem_loop(string, ancestor)));
+                }
                 let dir =
-                    try_some!(dir_read(path).map_err(|e| FileAccessError::new(e, string.clone())));
+                    try_some!(dir_read(long_path(&path))
+                        .map_err(|e| FileAccessError::new(e, string.clone())));
+                if let Some(ignores) = self.gitignore.as_mut() {
+                    ignores.enter(&path);
+                }
                 for f in dir {
                     let entry = try_some!(f.map_err(|e| FileAccessError::new(e, string.clone())));
-                    let path = dir_path(&entry, self.local);
-                    let string = path.to_string_lossy();
-                    if !self.regex.is_match(&string) {
+                    let path = dir_path(&path, &entry, self.local);
+                    let string = path_to_string(&path);
+                    let is_dir = || {
+                        entry
+                            .file_type()
+                            .is_ok_and(|t| t.is_dir() || (t.is_symlink() && path.is_dir()))
+                    };
+                    if !self.is_excluded(&string) && !self.is_gitignored(&path, is_dir) {
                         let string = string.to_string();
                         let fi = FileInfo::from_both(path, string);
                         self.temp.push((fi, entry));
                     }
                 }
-                if !self.temp.is_empty() {
+                if let Some(visited) = self.visited.as_mut() {
+                    visited.push(string.clone());
+                }
+                if self.temp.is_empty() {
+                    if self.empty_dirs {
+                        let mut fi = FileInfo::from_both(path, string);
+                        fi.time = Some(parse_date::system_to_naive(try_some!(md
+                            .modified()
+                            .map_err(|e| FileAccessError::new(e, fi.move_string())))));
+                        fi.ctime = change_time(&md);
+                        return Some(Ok(fi));
+                    }
+                } else {
                     // Sort the added items to preserve lexicographic ordering
+                    #[cfg(not(target_os = "windows"))]
+                    self.temp.sort_unstable_by_key(|a| a.1.file_name());
+                    #[cfg(target_os = "windows")]
                     self.temp
-                        .sort_unstable_by(|a, b| a.1.file_name().cmp(&b.1.file_name()));
+                        .sort_unstable_by_key(|a| a.1.file_name().to_string_lossy().to_lowercase());
                     // Check for items already on the stack
                     let mut count = self.stack.len();
                     let mut needs_sorting = false;
@@ -402,9 +1340,9 @@ impl Iterator for FileCrawler {
                         for (fi1, _) in self.temp.iter() {
                             // SAFETY: count is guaranteed to be between zero and self.stack.len()
                             let fi2 = unsafe { self.stack.get_unchecked(count) };
-                            match fi1.path.as_ref().unwrap().cmp(fi2.path.as_ref().unwrap()) {
-                                std::cmp::Ordering::Less => {}
-                                std::cmp::Ordering::Equal => {
+                            match cmp_path(fi1.path.as_ref().unwrap(), fi2.path.as_ref().unwrap()) {
+                                Ordering::Less => {}
+                                Ordering::Equal => {
                                     self.stack.remove(count);
                                     if count == 0 {
                                         break;
@@ -412,7 +1350,7 @@ impl Iterator for FileCrawler {
                                         count -= 1;
                                     }
                                 }
-                                std::cmp::Ordering::Greater => {
+                                Ordering::Greater => {
                                     needs_sorting = true;
                                     if count == 0 {
                                         break;
@@ -430,7 +1368,7 @@ impl Iterator for FileCrawler {
                     // If the top of the stack is not sorted
                     if needs_sorting {
                         self.stack[count..].sort_unstable_by(|a, b| {
-                            b.path.as_ref().unwrap().cmp(a.path.as_ref().unwrap())
+                            cmp_path(b.path.as_ref().unwrap(), a.path.as_ref().unwrap())
                         });
                     }
                 }
@@ -442,11 +1380,61 @@ impl Iterator for FileCrawler {
 
 #[cfg(test)]
 mod tests {
+    use std::fs::File;
+    use std::io::Write;
     use std::path::{Path, PathBuf};
 
     use path_absolutize::Absolutize;
+    use path_clean::PathClean;
+    use regex::RegexSet;
+    use tempfile::tempdir;
+
+    use super::{
+        bytes_to_string, path_to_string, string_to_path, BlockSignature, ChangeDetection,
+        DeltaManifest, FileCrawler, FileInfo, DELTA_BLOCK_SIZE,
+    };
+    use crate::lists::FileListVec;
+    use crate::parse_date::naive_now;
 
-    use super::{FileCrawler, FileInfo};
+    #[test]
+    #[cfg(unix)]
+    fn raw_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let bytes = b"/a\xff/b\xc3\xa9\xc3";
+        let string = bytes_to_string(bytes);
+        assert_eq!(string, "/a\u{efff}/b\u{e9}\u{efc3}");
+        assert_eq!(string_to_path(&string).as_os_str().as_bytes(), bytes);
+        let mut fi = FileInfo::from(string_to_path(&string));
+        assert_eq!(fi.get_string(), &string);
+        assert_eq!(bytes_to_string(b"plain"), "plain");
+        assert_eq!(string_to_path("plain"), PathBuf::from("plain"));
+
+        // Names that really contain the private use characters are escaped
+        let path = PathBuf::from("/a\u{ef80}/b\u{ef00}c");
+        let string = path_to_string(&path);
+        assert_eq!(string, "/a\u{ef00}\u{ef80}/b\u{ef00}\u{ef00}c");
+        assert_eq!(string_to_path(&string), path);
+        assert_ne!(string_to_path(&bytes_to_string(b"\x80")), path);
+        let mut fi = FileInfo::from(path.as_path());
+        assert_eq!(fi.get_string(), &string);
+    }
+
+    #[test]
+    fn hash_content() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let f1 = dir.path().join("a.txt");
+        let f2 = dir.path().join("b.txt");
+        File::create(&f1)?.write_all(b"content")?;
+        File::create(&f2)?.write_all(b"content")?;
+        let mut fi = FileInfo::from(f1.as_path());
+        let hash = fi.hash_content()?;
+        assert_eq!(fi.hash, Some(hash));
+        assert_eq!(FileInfo::from(f2.as_path()).hash_content()?, hash);
+        File::create(&f2)?.write_all(b"Content")?;
+        assert_ne!(FileInfo::from(f2).hash_content()?, hash);
+        Ok(())
+    }
 
     #[test]
     fn file_crawler_abs() {
@@ -479,6 +1467,75 @@ mod tests {
         );
     }
 
+    #[test]
+    fn file_crawler_expand() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        std::fs::create_dir(dir.path().join("a"))?;
+        File::create(dir.path().join("a").join("x.txt"))?;
+        File::create(dir.path().join("a").join("y.txt"))?;
+        std::env::set_var("SIMPLE_BACKUP_CRAWL", dir.path());
+        for local in [false, true] {
+            let files: Vec<PathBuf> = FileCrawler::new(
+                ["${SIMPLE_BACKUP_CRAWL}/a"],
+                ["$SIMPLE_BACKUP_CRAWL/a/y.txt"],
+                [""],
+                local,
+            )?
+            .map(|fi| fi.unwrap().consume_path())
+            .collect();
+            assert_eq!(files, vec![dir.path().join("a").join("x.txt")]);
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_crawler_symlink_loop() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a");
+        std::fs::create_dir(&a)?;
+        File::create(a.join("x.txt"))?;
+        std::os::unix::fs::symlink(&a, a.join("loop"))?;
+        let (files, errors): (Vec<_>, Vec<_>) =
+            FileCrawler::new([dir.path().to_string_lossy()], [""; 0], [""], false)?
+                .partition(|r| r.is_ok());
+        assert_eq!(
+            files
+                .into_iter()
+                .map(|f| f.unwrap().consume_path())
+                .collect::<Vec<_>>(),
+            vec![a.join("x.txt")]
+        );
+        assert_eq!(errors.len(), 1);
+        let error = errors[0].as_ref().unwrap_err().to_string();
+        assert!(error.contains(&a.join("loop").to_string_lossy().to_string()));
+        assert!(error.contains(&format!("'{}'", a.to_string_lossy())));
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn file_crawler_junction_loop() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let a = dir.path().join("a");
+        std::fs::create_dir(&a)?;
+        File::create(a.join("x.txt"))?;
+        let status = std::process::Command::new("cmd")
+            .arg("/C")
+            .arg("mklink")
+            .arg("/J")
+            .arg(a.join("loop"))
+            .arg(&a)
+            .status()?;
+        assert!(status.success());
+        let (files, errors): (Vec<_>, Vec<_>) =
+            FileCrawler::new([dir.path().to_string_lossy()], [""; 0], [""], false)?
+                .partition(|r| r.is_ok());
+        assert_eq!(files.len(), 1);
+        assert_eq!(errors.len(), 1);
+        Ok(())
+    }
+
     #[test]
     fn file_crawler_rel() {
         let main_path = Path::new("src").join("main.rs");
@@ -550,6 +1607,457 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn file_crawler_case() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let docs = dir.path().join("Docs");
+        std::fs::create_dir_all(docs.join("tmp"))?;
+        File::create(docs.join("tmp").join("x.txt"))?;
+        File::create(docs.join("y.txt"))?;
+        let lower = dir.path().join("docs");
+        let fc = FileCrawler::new(
+            [docs.to_string_lossy(), lower.to_string_lossy()],
+            [lower.join("tmp").to_string_lossy()],
+            [""; 0],
+            false,
+        )?;
+        let files: Vec<PathBuf> = FileListVec::crawl(fc, None, ChangeDetection::Mtime)
+            .iter()
+            .map(|(_, fi)| fi.clone().consume_path())
+            .collect();
+        if cfg!(target_os = "windows") {
+            assert_eq!(files, vec![docs.join("y.txt")]);
+        } else {
+            assert_eq!(files, vec![
+                docs.join("tmp").join("x.txt"),
+                docs.join("y.txt")
+            ]);
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    #[test]
+    fn case_insensitive_paths() {
+        use std::cmp::Ordering;
+
+        use super::{cmp_path, cmp_path_str, normalise_drive};
+
+        assert_eq!(
+            FileInfo::from("C:/Users/Me/a.txt"),
+            FileInfo::from("c:/users/me/A.TXT")
+        );
+        assert_eq!(
+            FileInfo::from(PathBuf::from(r"C:\Users\Me")),
+            FileInfo::from(PathBuf::from(r"c:\users\me"))
+        );
+        assert_ne!(FileInfo::from("C:/Users/a"), FileInfo::from("C:/Users/b"));
+        assert_eq!(cmp_path_str("C:/a", "C:/B"), Ordering::Less);
+        assert_eq!(
+            cmp_path(Path::new(r"C:\a"), Path::new(r"c:\B")),
+            Ordering::Less
+        );
+        assert_eq!(
+            normalise_drive(PathBuf::from(r"c:\Users")),
+            PathBuf::from(r"C:\Users")
+        );
+        assert_eq!(
+            normalise_drive(PathBuf::from(r"\\?\d:\x")),
+            PathBuf::from(r"\\?\D:\x")
+        );
+        assert_eq!(normalise_drive(PathBuf::from("x")), PathBuf::from("x"));
+
+        let fc = FileCrawler::new(
+            [r"C:\Users\Me\Docs"],
+            [r"c:\users\me\docs\tmp"],
+            [""; 0],
+            false,
+        )
+        .unwrap();
+        assert!(fc.check_path(&mut FileInfo::from(r"c:\users\me\docs\a.txt"), None));
+        assert!(!fc.check_path(&mut FileInfo::from(r"C:\Users\Me\Docs\Tmp"), None));
+        assert!(!fc.check_path(&mut FileInfo::from(r"C:\Users\Me\Docs\TMP\b.txt"), None));
+    }
+
+    /// The crawler as it was before the exact excludes (everything in the regex)
+    fn legacy_crawler(
+        include: &[String],
+        exclude: &[String],
+        filter: &[String],
+        local: bool,
+    ) -> FileCrawler {
+        let exc = exclude.iter().map(|s| {
+            let p = if local {
+                PathBuf::from(s).clean()
+            } else {
+                PathBuf::from(s).absolutize().unwrap().to_path_buf()
+            };
+            format!("^{}$", regex::escape(&p.to_string_lossy()))
+        });
+        let mut fc = FileCrawler::new(include, [""; 0], [""; 0], local).unwrap();
+        // The legacy check looks for all the includes on the stack
+        fc.crawl_nested(|_, _| true);
+        fc.regex = RegexSet::new(filter.iter().cloned().chain(exc)).unwrap();
+        fc
+    }
+
+    /// The check as it was before the include keys (binary search on the paths)
+    fn legacy_check_path(fc: &FileCrawler, path: &mut FileInfo, parent: Option<bool>) -> bool {
+        let p = path.get_path();
+        let p = if !fc.local && !p.is_absolute() {
+            p.absolutize().unwrap().to_path_buf()
+        } else {
+            p.clone()
+        };
+        if fc
+            .stack
+            .binary_search_by(|fi| p.cmp(fi.path.as_ref().unwrap()))
+            .is_ok()
+        {
+            return true;
+        }
+        if fc.regex.is_match(path.get_string()) {
+            return false;
+        }
+        if parent.is_none() && (path.get_string().is_empty() || path.get_string() == ".") {
+            return false;
+        }
+        match parent {
+            Some(parent) => parent,
+            None => match path.get_path().parent() {
+                Some(parent) => legacy_check_path(fc, &mut FileInfo::from(parent), None),
+                None => false,
+            },
+        }
+    }
+
+    #[test]
+    fn file_crawler_equivalence() -> std::io::Result<()> {
+        // Names chosen so that string ordering and path ordering differ
+        let names = ["a", "a-b", "a.b", "ab", "b", "a b"];
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let mut random = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        let mut crawled = 0;
+        for _ in 0..5 {
+            let dir = tempdir()?;
+            let mut dirs = vec![dir.path().to_path_buf()];
+            let mut paths = vec![];
+            for _ in 0..60 {
+                let parent = dirs[random(dirs.len())].clone();
+                let path = parent.join(names[random(names.len())]);
+                if path.exists() {
+                    continue;
+                }
+                if random(3) == 0 {
+                    File::create(&path)?;
+                } else {
+                    std::fs::create_dir(&path)?;
+                    dirs.push(path.clone());
+                }
+                paths.push(path);
+            }
+            let mut pick = |n: usize| -> Vec<String> {
+                (0..n)
+                    .map(|_| paths[random(paths.len())].to_string_lossy().to_string())
+                    .collect()
+            };
+            let include = pick(4);
+            let exclude = pick(6);
+            let filter = vec![String::from("a\\.b$")];
+            for local in [false, true] {
+                let fc = FileCrawler::new(&include, &exclude, &filter, local)?;
+                let legacy = legacy_crawler(&include, &exclude, &filter, local);
+                for p in paths.iter().chain([dir.path().to_path_buf()].iter()) {
+                    for parent in [None, Some(true), Some(false)] {
+                        assert_eq!(
+                            fc.check_path(&mut FileInfo::from(p.as_path()), parent),
+                            legacy_check_path(&legacy, &mut FileInfo::from(p.as_path()), parent),
+                            "{:?} {:?}",
+                            p,
+                            parent,
+                        );
+                    }
+                }
+                let files: Vec<PathBuf> = fc.map(|f| f.unwrap().consume_path()).collect();
+                let files2: Vec<PathBuf> = legacy.map(|f| f.unwrap().consume_path()).collect();
+                assert_eq!(files, files2);
+                crawled += files.len();
+            }
+        }
+        assert!(crawled > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn file_crawler_gitignore() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        for d in ["src", "target", "sub/build", "sub/keep"] {
+            std::fs::create_dir_all(root.join(d))?;
+        }
+        for f in [
+            "src/a.rs",
+            "src/a.o",
+            "target/b",
+            "sub/build/c",
+            "sub/keep/d.o",
+            "sub/e.log",
+        ] {
+            File::create(root.join(f))?;
+        }
+        std::fs::write(root.join(".gitignore"), "/target\n*.o\n")?;
+        std::fs::write(root.join("sub").join(".gitignore"), "build/\n*.log\n!d.o\n")?;
+        let crawl = |include: &[PathBuf]| -> std::io::Result<Vec<PathBuf>> {
+            Ok(FileCrawler::new(
+                include
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                [""; 0],
+                [""; 0],
+                false,
+            )?
+            .with_gitignore(true)
+            .map(|f| f.unwrap().consume_path())
+            .collect())
+        };
+        assert_eq!(crawl(&[root.to_path_buf()])?, vec![
+            root.join(".gitignore"),
+            root.join("src").join("a.rs"),
+            root.join("sub").join(".gitignore"),
+            root.join("sub").join("keep").join("d.o"),
+        ]);
+        // Explicit includes are not ignored
+        let include = [
+            root.to_path_buf(),
+            root.join("target"),
+            root.join("src/a.o"),
+        ];
+        assert_eq!(crawl(&include)?, vec![
+            root.join(".gitignore"),
+            root.join("src").join("a.o"),
+            root.join("src").join("a.rs"),
+            root.join("sub").join(".gitignore"),
+            root.join("sub").join("keep").join("d.o"),
+            root.join("target").join("b"),
+        ]);
+
+        let fc = FileCrawler::new([root.to_string_lossy()], [""; 0], [""; 0], false)?
+            .with_gitignore(true);
+        let check = |p: &str| fc.check_path(&mut FileInfo::from(root.join(p)), None);
+        assert!(check("src/a.rs"));
+        assert!(!check("src/a.o"));
+        assert!(!check("target/b"));
+        assert!(!check("sub/build/c"));
+        assert!(check("sub/keep/d.o"));
+        assert!(!check("sub/e.log"));
+        Ok(())
+    }
+
+    #[test]
+    fn file_crawler_max_depth() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("a/b/c"))?;
+        for f in ["x", "a/y", "a/b/z", "a/b/c/w"] {
+            File::create(root.join(f))?;
+        }
+        let crawl = |include: &[PathBuf], depth: Option<usize>| -> std::io::Result<Vec<PathBuf>> {
+            Ok(FileCrawler::new(
+                include
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                [""; 0],
+                [""; 0],
+                false,
+            )?
+            .with_max_depth(depth)
+            .map(|f| f.unwrap().consume_path())
+            .collect())
+        };
+        assert_eq!(crawl(&[root.to_path_buf()], None)?.len(), 4);
+        assert_eq!(
+            crawl(&[root.to_path_buf()], Some(0))?,
+            Vec::<PathBuf>::new()
+        );
+        assert_eq!(crawl(&[root.to_path_buf()], Some(1))?, vec![root.join("x")]);
+        assert_eq!(crawl(&[root.to_path_buf()], Some(2))?, vec![
+            root.join("a").join("y"),
+            root.join("x"),
+        ]);
+        // The depth is relative to the innermost include
+        assert_eq!(
+            crawl(&[root.to_path_buf(), root.join("a/b")], Some(1))?,
+            vec![root.join("a").join("b").join("z"), root.join("x")]
+        );
+
+        let fc = FileCrawler::new([root.to_string_lossy()], [""; 0], [""; 0], false)?
+            .with_max_depth(Some(2));
+        let check = |p: &str| fc.check_path(&mut FileInfo::from(root.join(p)), None);
+        assert!(check("a/y"));
+        assert!(!check("a/b/z"));
+        assert!(!check("a/b/c/w"));
+        Ok(())
+    }
+
+    #[test]
+    fn file_crawler_nested() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path().canonicalize()?;
+        std::fs::create_dir_all(root.join("a/b/c"))?;
+        for f in ["a/x", "a/b/y", "a/b/c/z"] {
+            File::create(root.join(f))?;
+        }
+        let s = |p: PathBuf| p.to_string_lossy().to_string();
+        let crawl = |fc: FileCrawler| fc.map(|f| f.unwrap().consume_path()).collect::<Vec<_>>();
+        let all = vec![root.join("a/b/c/z"), root.join("a/b/y"), root.join("a/x")];
+        let include = [
+            s(root.join("a")),
+            s(root.join("a/b/c")),
+            s(root.join("a/b")),
+        ];
+        let fc = FileCrawler::new(&include, [""; 0], [""; 0], false)?;
+        assert_eq!(fc.stack.len(), 1);
+        assert_eq!(crawl(fc), all);
+        // Excluded between the includes
+        let fc = FileCrawler::new(&include, [s(root.join("a/b"))], [""; 0], false)?;
+        assert_eq!(fc.stack.len(), 3);
+        assert_eq!(crawl(fc), all);
+        // Deeper than the limit
+        let fc = FileCrawler::new(&include, [""; 0], [""; 0], false)?.with_max_depth(Some(2));
+        assert_eq!(fc.stack.len(), 2);
+        assert_eq!(crawl(fc), all);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("a/b"), root.join("link"))?;
+            let include = [s(root.join("a")), s(root.join("link"))];
+            assert_eq!(
+                crawl(FileCrawler::new(&include, [""; 0], [""; 0], false)?),
+                all
+            );
+            let fc = FileCrawler::new(&include, [""; 0], [""; 0], false)?
+                .with_separate([s(root.join("link"))]);
+            assert_eq!(crawl(fc).len(), 5);
+            // An alias of the same directory
+            let include = [s(root.join("link")), s(root.join("a/b"))];
+            assert_eq!(
+                crawl(FileCrawler::new(&include, [""; 0], [""; 0], false)?),
+                vec![root.join("a/b/c/z"), root.join("a/b/y")]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn file_crawler_age() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let root = dir.path();
+        let day = std::time::Duration::from_secs(24 * 60 * 60);
+        let now = std::time::SystemTime::now();
+        for (f, age) in [("new", 0), ("month", 30), ("old", 400)] {
+            File::create(root.join(f))?.set_modified(now - day * age)?;
+        }
+        let crawl = |newer: Option<u32>, older: Option<u32>| -> std::io::Result<Vec<PathBuf>> {
+            let ago = |d: u32| crate::parse_date::system_to_naive(now - day * d);
+            Ok(
+                FileCrawler::new([root.to_string_lossy()], [""; 0], [""; 0], false)?
+                    .with_age(newer.map(ago), older.map(ago))
+                    .map(|f| f.unwrap().consume_path())
+                    .collect(),
+            )
+        };
+        assert_eq!(crawl(None, None)?.len(), 3);
+        assert_eq!(crawl(Some(365), None)?, vec![
+            root.join("month"),
+            root.join("new")
+        ]);
+        assert_eq!(crawl(None, Some(365))?, vec![root.join("old")]);
+        assert_eq!(crawl(Some(365), Some(7))?, vec![root.join("month")]);
+        assert_eq!(crawl(Some(7), Some(365))?, Vec::<PathBuf>::new());
+        Ok(())
+    }
+
+    #[test]
+    fn file_crawler_many_includes() -> std::io::Result<()> {
+        let include: Vec<String> = (0..10000).map(|i| format!("project_{}/src", i)).collect();
+        let exclude: Vec<String> = (0..10000)
+            .step_by(3)
+            .map(|i| format!("project_{}/src/target", i))
+            .collect();
+        let start = std::time::Instant::now();
+        let fc = FileCrawler::new(&include, &exclude, [r"\.tmp$"], false)?;
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        let cwd = std::env::current_dir()?;
+        let path = cwd.join("project_9999").join("src");
+        assert!(fc.check_path(&mut FileInfo::from(path.join("lib.rs")), None));
+        assert!(!fc.check_path(&mut FileInfo::from(path.join("lib.tmp")), None));
+        assert!(!fc.check_path(&mut FileInfo::from(path.join("target")), None));
+        assert!(fc.check_path(&mut FileInfo::from(cwd.join("project_1/src/target")), None));
+        assert!(!fc.check_path(&mut FileInfo::from(cwd.join("project_10000/src")), None));
+        Ok(())
+    }
+
+    #[test]
+    fn file_crawler_resume() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        for d in ["a", "a/b", "c"] {
+            std::fs::create_dir(dir.path().join(d))?;
+        }
+        for f in ["a/x.txt", "a/b/y.txt", "c/z.txt", "w.txt"] {
+            File::create(dir.path().join(f))?;
+        }
+        let include = [dir.path().to_string_lossy().to_string()];
+        let crawler = || FileCrawler::new(&include, [""; 0], [""; 0], false);
+        let full: Vec<PathBuf> = crawler()?.map(|fi| fi.unwrap().consume_path()).collect();
+
+        let mut fc = crawler()?.track_visited();
+        let mut files: Vec<PathBuf> = fc
+            .by_ref()
+            .take(2)
+            .map(|fi| fi.unwrap().consume_path())
+            .collect();
+        let frontier = fc.frontier();
+        assert_eq!(frontier.visited.len(), 3);
+        assert_eq!(frontier.pending.len(), 2);
+        assert_eq!(files[1], dir.path().join("a").join("x.txt"));
+        files.extend(
+            crawler()?
+                .resume(frontier.clone(), &[])
+                .map(|fi| fi.unwrap().consume_path()),
+        );
+        assert_eq!(files, full);
+
+        // Unmodified directories are not stale, removed directories are
+        let future = naive_now() + chrono::TimeDelta::hours(1);
+        assert!(frontier.stale_dirs(future).is_empty());
+        let past = naive_now() - chrono::TimeDelta::hours(1);
+        assert_eq!(frontier.stale_dirs(past), include.to_vec());
+        std::fs::remove_dir_all(dir.path().join("a").join("b"))?;
+        let stale = frontier.stale_dirs(future);
+        assert_eq!(stale, vec![dir
+            .path()
+            .join("a")
+            .join("b")
+            .to_string_lossy()
+            .to_string()]);
+
+        // Stale directories are crawled again (removing the files within from the frontier)
+        let rest: Vec<PathBuf> = crawler()?
+            .track_visited()
+            .resume(frontier, &stale)
+            .map(|fi| fi.unwrap().consume_path())
+            .collect();
+        assert_eq!(rest, full[2..].to_vec());
+        Ok(())
+    }
+
     #[test]
     fn fileinfo_from() {
         let mut fi1 = FileInfo::from(PathBuf::from("cargo.toml"));
@@ -571,4 +2079,67 @@ mod tests {
         assert_eq!(fi5.get_path(), fi4.get_path());
         assert_eq!(fi5.get_path(), fi6.get_path());
     }
+
+    #[test]
+    fn fileinfo_order() {
+        // Paths are sorted as strings (not component-wise), regardless of the representation
+        let mut list = vec![
+            FileInfo::from(PathBuf::from("a b")),
+            FileInfo::from(PathBuf::from("a/b")),
+            FileInfo::from("a c"),
+            FileInfo::from(Path::new("a")),
+        ];
+        list.sort();
+        let strings: Vec<String> = list.into_iter().map(|mut fi| fi.move_string()).collect();
+        assert_eq!(strings, vec!["a", "a b", "a c", "a/b"]);
+    }
+
+    #[test]
+    fn block_signature() {
+        let block = DELTA_BLOCK_SIZE as usize;
+        let mut content = vec![7u8; block * 2 + 100];
+        let signature = BlockSignature::from_reader(content.as_slice()).unwrap();
+        assert_eq!(signature.size, content.len() as u64);
+        assert_eq!(signature.blocks.len(), 3);
+        assert_eq!(signature.blocks[0], signature.blocks[1]);
+        assert_eq!(signature.block_len(2), 100);
+        assert_eq!(
+            BlockSignature::parse(&signature.to_string()),
+            Some(signature.clone())
+        );
+        assert_eq!(BlockSignature::parse("10,1 2"), None);
+
+        // Changes at the block boundaries only affect the blocks with the changed bytes
+        content[block - 1] = 1;
+        content[block * 2] = 1;
+        let changed = BlockSignature::from_reader(content.as_slice()).unwrap();
+        assert_eq!(changed.changed_blocks(&signature), vec![0, 2]);
+        assert_ne!(changed.digest(), signature.digest());
+        content.truncate(block + 10);
+        let shorter = BlockSignature::from_reader(content.as_slice()).unwrap();
+        assert_eq!(shorter.changed_blocks(&signature), vec![0, 1]);
+        content.extend(vec![7u8; block * 2]);
+        let longer = BlockSignature::from_reader(content.as_slice()).unwrap();
+        assert_eq!(longer.changed_blocks(&changed), vec![2, 3]);
+    }
+
+    #[test]
+    fn delta_manifest() {
+        let manifest = DeltaManifest {
+            size: DELTA_BLOCK_SIZE * 3 + 5,
+            base: 0xabc,
+            digest: 0x123,
+            blocks: vec![1, 3],
+        };
+        assert_eq!(
+            manifest.to_string(),
+            format!("{} abc 123 1,3", manifest.size)
+        );
+        assert_eq!(manifest.stored_size(), DELTA_BLOCK_SIZE + 5);
+        assert_eq!(DeltaManifest::parse(&manifest.to_string()), Some(manifest));
+        let empty = DeltaManifest::parse("5 1 2 ").unwrap();
+        assert!(empty.blocks.is_empty());
+        assert_eq!(DeltaManifest::parse(&empty.to_string()), Some(empty));
+        assert_eq!(DeltaManifest::parse("5 1 2 x"), None);
+    }
 }
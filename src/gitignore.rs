@@ -0,0 +1,281 @@
+/// This module contains the (subset of) `.gitignore` patterns that the file crawler can honour
+use std::path::{Path, PathBuf};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::utils::expand_path;
+
+/// The name of the ignore files that are read from the crawled directories
+pub const GITIGNORE_NAME: &str = ".gitignore";
+
+/// A single line from an ignore file
+#[derive(Debug, Clone)]
+struct Pattern {
+    regex: Regex,
+    /// Re-include files that an earlier pattern has ignored (`!pattern`)
+    negated: bool,
+    /// Only match directories (`pattern/`)
+    dir_only: bool,
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        // Trailing spaces are ignored (unless escaped)
+        let trimmed = line.trim_end_matches(' ');
+        let line = if trimmed.ends_with('\\') && trimmed.len() < line.len() {
+            &line[..trimmed.len() + 1]
+        } else {
+            trimmed
+        };
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        if line.is_empty() {
+            return None;
+        }
+        // Patterns with a slash (except at the end) are relative to the directory of the file
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        let mut regex = String::from(if anchored { "^" } else { "(?:^|/)" });
+        regex.push_str(&glob_to_regex(line));
+        regex.push('$');
+        Some(Self {
+            regex: RegexBuilder::new(&regex)
+                .case_insensitive(cfg!(target_os = "windows"))
+                .build()
+                .ok()?,
+            negated,
+            dir_only,
+        })
+    }
+}
+
+/// Translate a glob (`*`, `?`, `**`, and `[...]`) into a regex
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut out = String::with_capacity(glob.len() * 2);
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                let start = i == 0 || chars[i - 1] == '/';
+                let end = i + 2 == chars.len();
+                if start && chars.get(i + 2) == Some(&'/') {
+                    // `**/` matches zero or more directories
+                    out.push_str("(?:.*/)?");
+                    i += 3;
+                    continue;
+                } else if start && end {
+                    out.push_str(".*");
+                } else {
+                    out.push_str("[^/]*");
+                }
+                i += 2;
+                continue;
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            '[' => match chars[i + 1..].iter().position(|c| *c == ']') {
+                Some(len) if len > 0 => {
+                    let class: String = chars[i + 1..i + 1 + len].iter().collect();
+                    let class = match class.strip_prefix('!') {
+                        Some(rest) => format!("^{}", rest),
+                        None => class,
+                    };
+                    out.push('[');
+                    out.push_str(&class.replace('\\', "\\\\").replace('[', "\\["));
+                    out.push(']');
+                    i += len + 2;
+                    continue;
+                }
+                _ => out.push_str("\\["),
+            },
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                out.push_str(&regex::escape(&chars[i].to_string()));
+            }
+            c => out.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// The patterns from one ignore file (relative to `dir`)
+#[derive(Debug, Clone)]
+pub struct GitIgnore {
+    dir: PathBuf,
+    patterns: Vec<Pattern>,
+}
+
+impl GitIgnore {
+    pub fn parse<P: Into<PathBuf>>(dir: P, content: &str) -> Self {
+        Self {
+            dir: dir.into(),
+            patterns: content.lines().filter_map(Pattern::parse).collect(),
+        }
+    }
+
+    /// Read the ignore file in a directory (a missing file has no patterns)
+    pub fn load<P: Into<PathBuf>>(dir: P) -> Self {
+        let dir = dir.into();
+        let content = std::fs::read_to_string(dir.join(GITIGNORE_NAME)).unwrap_or_default();
+        Self::parse(dir, &content)
+    }
+
+    /// Is the path ignored (`Some(true)`), re-included (`Some(false)`), or not matched (`None`)
+    pub fn matches(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        if self.patterns.is_empty() {
+            return None;
+        }
+        let relative = path.strip_prefix(&self.dir).ok()?;
+        let relative = relative.to_string_lossy();
+        #[cfg(target_os = "windows")]
+        let relative = relative.replace('\\', "/");
+        self.patterns
+            .iter()
+            .rev()
+            .find(|p| (is_dir || !p.dir_only) && p.regex.is_match(&relative))
+            .map(|p| !p.negated)
+    }
+}
+
+/// The global ignore file (`$XDG_CONFIG_HOME/git/ignore` or `~/.config/git/ignore`)
+fn global_ignore_path() -> PathBuf {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("git").join("ignore"),
+        _ => PathBuf::from(expand_path("~/.config/git/ignore")),
+    }
+}
+
+/// Use an empty path for the current directory (so that relative paths start with it)
+fn current_to_empty(path: &Path) -> &Path {
+    if path == Path::new(".") {
+        Path::new("")
+    } else {
+        path
+    }
+}
+
+/// The ignore files that apply to the directory being crawled, from the include root and down
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreStack {
+    /// The include roots (the global patterns are relative to these)
+    roots: Vec<PathBuf>,
+    global: Option<GitIgnore>,
+    /// One entry per directory between the root and the current directory
+    levels: Vec<GitIgnore>,
+}
+
+impl IgnoreStack {
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        let roots = roots
+            .into_iter()
+            .map(|r| current_to_empty(&r).to_path_buf())
+            .collect();
+        let global = std::fs::read_to_string(global_ignore_path())
+            .ok()
+            .map(|content| GitIgnore::parse(PathBuf::new(), &content));
+        Self {
+            roots,
+            global,
+            levels: vec![],
+        }
+    }
+
+    /// The (innermost) include root that contains the path
+    fn root(&self, path: &Path) -> Option<&PathBuf> {
+        self.roots
+            .iter()
+            .filter(|r| path.starts_with(r))
+            .max_by_key(|r| r.components().count())
+    }
+
+    /// Move to a directory, reading the ignore files of it (and of any skipped parents)
+    pub fn enter(&mut self, dir: &Path) {
+        let dir = current_to_empty(dir);
+        while self.levels.last().is_some_and(|l| !dir.starts_with(&l.dir)) {
+            self.levels.pop();
+        }
+        let mut current = match self.levels.last() {
+            Some(l) => l.dir.clone(),
+            None => match self.root(dir) {
+                Some(root) => {
+                    let root = root.clone();
+                    self.levels.push(GitIgnore::load(&root));
+                    root
+                }
+                None => {
+                    self.levels.push(GitIgnore::load(dir));
+                    return;
+                }
+            },
+        };
+        if let Ok(rest) = dir.strip_prefix(&current) {
+            let rest = rest.to_path_buf();
+            for c in rest.components() {
+                current.push(c);
+                self.levels.push(GitIgnore::load(&current));
+            }
+        }
+    }
+
+    /// Is the path (in the current directory) ignored, the later (deeper) patterns take precedence
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        if let Some(global) = self.global.as_ref() {
+            let relative = match self.root(path) {
+                Some(root) => path.strip_prefix(root).unwrap_or(path),
+                None => Path::new(path.file_name().unwrap_or_default()),
+            };
+            if let Some(i) = global.matches(relative, is_dir) {
+                ignored = i;
+            }
+        }
+        for level in self.levels.iter() {
+            if let Some(i) = level.matches(path, is_dir) {
+                ignored = i;
+            }
+        }
+        ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::GitIgnore;
+
+    #[test]
+    fn patterns() {
+        let ignore = GitIgnore::parse(
+            "/repo",
+            "# comment\n*.o\n/build\ndocs/*.html\n!keep.o\nlogs/\n**/tmp/**\n\\#hash\nfile[0-9].txt\n",
+        );
+        let m = |p: &str, dir: bool| ignore.matches(Path::new(p), dir);
+        assert_eq!(m("/repo/a.o", false), Some(true));
+        assert_eq!(m("/repo/src/b.o", false), Some(true));
+        assert_eq!(m("/repo/src/keep.o", false), Some(false));
+        assert_eq!(m("/repo/build", true), Some(true));
+        assert_eq!(m("/repo/src/build", true), None);
+        assert_eq!(m("/repo/docs/index.html", false), Some(true));
+        assert_eq!(m("/repo/docs/api/index.html", false), None);
+        assert_eq!(m("/repo/logs", true), Some(true));
+        assert_eq!(m("/repo/logs", false), None);
+        assert_eq!(m("/repo/a/tmp/b/c", false), Some(true));
+        assert_eq!(m("/repo/#hash", false), Some(true));
+        assert_eq!(m("/repo/file1.txt", false), Some(true));
+        assert_eq!(m("/repo/fileA.txt", false), None);
+        assert_eq!(m("/other/a.o", false), None);
+    }
+}
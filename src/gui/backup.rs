@@ -5,17 +5,20 @@ use iced::{Element, Length, Subscription};
 use rfd::FileDialog;
 
 use super::threads::ThreadWrapper;
-use super::{paginated, presets, Message};
-use crate::backup::{BackupError, BackupWriter};
-use crate::config::Config;
+use super::{paginated, presets, LastBackup, Message};
+use crate::backup::{BackupError, BackupWriter, WriteStats};
+use crate::config::{Config, ConfigIssue};
 use crate::files::FileInfo;
-use crate::utils::format_size;
+use crate::stats::{quality_advice, GroupSize, QualityAdvice};
+use crate::utils::{format_size, get_config_from_path};
 
 #[derive(PartialEq, Eq)]
 enum ListSort {
     Name,
     Size,
     Time,
+    /// The sizes of the directories (instead of the files)
+    Dir,
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -25,13 +28,16 @@ enum BackupStage {
     Viewing(BackupWriter),
     Performing(ThreadWrapper<Result<FileInfo, BackupError>, BackupWriter>),
     Cancelling(ThreadWrapper<Result<FileInfo, BackupError>, BackupWriter>),
-    Completed,
+    /// The completed stage holds the files that were skipped (and the sizes of the backup,
+    /// with a suggestion for the compression quality)
+    Completed(Vec<String>, Option<WriteStats>, Option<QualityAdvice>),
     Cancelled,
 }
 
 pub(crate) struct BackupState {
     pub config: Config,
     list_sort: ListSort,
+    dirs: Vec<GroupSize>,
     error: String,
     total_count: usize,
     total_size: u64,
@@ -39,25 +45,45 @@ pub(crate) struct BackupState {
     current_size: u64,
     stage: BackupStage,
     pagination: paginated::State,
+    background: bool,
+    last_backup: LastBackup,
 }
 
 impl BackupState {
+    pub fn from_path(path: std::path::PathBuf) -> Result<Self, BackupError> {
+        get_config_from_path(path).map(Self::new)
+    }
+
     pub fn new(config: Config) -> Self {
         let crawler = ThreadWrapper::crawl_for_files(config.clone(), 1000);
+        // Tell that the output is left out of the backup
+        let error = config
+            .validate()
+            .into_iter()
+            .filter(|i| matches!(i, ConfigIssue::OutputExcluded(_)))
+            .map(|i| format!("\n{}", i))
+            .collect();
+        let last_backup = LastBackup::lookup(&config);
         Self {
             config,
             list_sort: ListSort::Name,
-            error: String::new(),
+            dirs: vec![],
+            error,
             total_count: 0,
             total_size: 0,
             current_count: 0,
             current_size: 0,
             stage: BackupStage::Scanning(crawler),
             pagination: paginated::State::new(100, 0),
+            background: false,
+            last_backup,
         }
     }
 
     pub fn update(&mut self, message: Message) {
+        if let Message::Tick = message {
+            self.last_backup.poll();
+        }
         match message {
             Message::Tick => match &mut self.stage {
                 BackupStage::Scanning(crawler) => {
@@ -102,9 +128,12 @@ impl BackupState {
                                                 self.pagination.set_total(self.total_count);
                                                 self.stage = BackupStage::Viewing(bw);
                                             }
-                                            Err(_) => self.error.push_str(
-                                                "\nFailure when finalising the list of files",
-                                            ),
+                                            Err(e) => {
+                                                self.error.push_str(
+                                                    "\nFailure when finalising the list of files: ",
+                                                );
+                                                self.error.push_str(&e.to_string());
+                                            }
                                         }
                                     }
                                     break;
@@ -114,12 +143,12 @@ impl BackupState {
                     }
                 }
                 BackupStage::Performing(wrapper) => {
+                    self.current_size = wrapper.bytes();
                     for recv in wrapper {
                         match recv {
                             Ok(res) => match res {
-                                Ok(fi) => {
+                                Ok(_) => {
                                     self.current_count += 1;
-                                    self.current_size += fi.size;
                                 }
                                 Err(e) => {
                                     self.error.push('\n');
@@ -134,14 +163,24 @@ impl BackupState {
                                     if let BackupStage::Performing(wrapper) =
                                         std::mem::replace(&mut self.stage, BackupStage::Failed)
                                     {
+                                        self.current_size = wrapper.bytes();
                                         match wrapper.join() {
-                                            Ok(_) => {
+                                            Ok(bw) => {
                                                 self.current_count = 0;
-                                                self.stage = BackupStage::Completed
+                                                self.stage = BackupStage::Completed(
+                                                    bw.skipped().to_vec(),
+                                                    bw.stats(),
+                                                    bw.compression_profile()
+                                                        .as_ref()
+                                                        .and_then(quality_advice),
+                                                )
+                                            }
+                                            Err(e) => {
+                                                self.error.push_str(
+                                                    "\nFailure when finalising the backup: ",
+                                                );
+                                                self.error.push_str(&e.to_string());
                                             }
-                                            Err(_) => self
-                                                .error
-                                                .push_str("\nFailure when finalising the backup"),
                                         }
                                     }
                                     break;
@@ -150,6 +189,7 @@ impl BackupState {
                         }
                     }
                 }
+                #[allow(clippy::collapsible_match)]
                 BackupStage::Cancelling(wrapper) => {
                     if wrapper.try_cancel() {
                         if let BackupStage::Cancelling(wrapper) =
@@ -164,8 +204,10 @@ impl BackupState {
                                     self.current_count = 0;
                                     self.stage = BackupStage::Cancelled
                                 }
-                                Err(_) => {
-                                    self.error.push_str("\nFailure when cancelling the backup")
+                                Err(e) => {
+                                    self.error
+                                        .push_str("\nFailure when cancelling the backup: ");
+                                    self.error.push_str(&e.to_string());
                                 }
                             };
                         }
@@ -186,7 +228,7 @@ impl BackupState {
                         .list
                         .as_mut()
                         .unwrap()
-                        .sort_unstable_by(|a, b| b.size.cmp(&a.size));
+                        .sort_unstable_by(|a, b| b.size.cmp(&a.size).then_with(|| a.cmp(b)));
                 }
             }
             Message::SortTime => {
@@ -196,18 +238,35 @@ impl BackupState {
                         .list
                         .as_mut()
                         .unwrap()
-                        .sort_unstable_by(|a, b| b.time.unwrap().cmp(&a.time.unwrap()));
+                        .sort_unstable_by(|a, b| b.time.cmp(&a.time).then_with(|| a.cmp(b)));
+                }
+            }
+            Message::SortDir => {
+                if let BackupStage::Viewing(writer) = &self.stage {
+                    self.list_sort = ListSort::Dir;
+                    self.dirs = writer.list.as_ref().unwrap().size_by_prefix(1);
                 }
             }
             Message::Backup => {
-                if let BackupStage::Viewing(_) = &self.stage {
+                if let BackupStage::Viewing(writer) = &self.stage {
+                    // Stay in the list (so that the backup can be retried after connecting the drive)
+                    if let Err(e) = writer.check_output() {
+                        let e = format!("\n{}", e);
+                        if !self.error.ends_with(&e) {
+                            self.error.push_str(&e);
+                        }
+                        return;
+                    }
                     self.list_sort = ListSort::Name;
                     if let BackupStage::Viewing(mut writer) =
                         std::mem::replace(&mut self.stage, BackupStage::Failed)
                     {
                         writer.list.as_mut().unwrap().sort_unstable();
-                        self.stage =
-                            BackupStage::Performing(ThreadWrapper::backup_files(writer, 1000));
+                        self.stage = BackupStage::Performing(ThreadWrapper::backup_files(
+                            writer,
+                            1000,
+                            self.background,
+                        ));
                         self.current_count = 0;
                         self.current_size = 0;
                     }
@@ -222,6 +281,12 @@ impl BackupState {
                     }
                 }
             }
+            Message::Background(b) => {
+                self.background = b;
+                if let BackupStage::Performing(wrapper) = &self.stage {
+                    wrapper.set_background(b);
+                }
+            }
             Message::Export => {
                 if let BackupStage::Viewing(writer) = &mut self.stage {
                     if let Some(file) = FileDialog::new()
@@ -244,13 +309,17 @@ impl BackupState {
                     self.pagination.goto(index)
                 }
             }
-            Message::Repeat => *self = BackupState::new(std::mem::take(&mut self.config)),
-            _ => eprintln!("Unexpected GUI message: {:?}", message),
+            Message::Repeat => {
+                let background = self.background;
+                *self = BackupState::new(std::mem::take(&mut self.config));
+                self.background = background;
+            }
+            _ => log::warn!("Unexpected GUI message: {:?}", message),
         }
     }
 
     pub fn subscription(&self) -> Subscription<Message> {
-        match self.stage {
+        let stage = match self.stage {
             BackupStage::Scanning(_) => {
                 iced::time::every(std::time::Duration::from_millis(100)).map(|_| Message::Tick)
             }
@@ -261,10 +330,11 @@ impl BackupState {
                 iced::time::every(std::time::Duration::from_millis(500)).map(|_| Message::Tick)
             }
             _ => Subscription::none(),
-        }
+        };
+        Subscription::batch([stage, self.last_backup.subscription()])
     }
 
-    pub fn view(&self) -> Element<Message> {
+    pub fn view(&self) -> Element<'_, Message> {
         let mut scroll = presets::column_list();
         if !self.error.is_empty() {
             scroll = scroll.push(presets::text_error(&self.error[1..]));
@@ -273,6 +343,7 @@ impl BackupState {
             BackupStage::Scanning(_) => {
                 let brow = presets::row_bar(vec![
                     presets::button_nav("Edit", Message::EditConfig, false),
+                    self.last_backup.view(),
                     presets::text_center(format!(
                         "Scanning for files to backup: {} with total size {}\n",
                         self.total_count,
@@ -307,30 +378,62 @@ impl BackupState {
                     .width(Length::Fixed(182.0))
                     .into(),
                 ]);
-                scroll = self.pagination.push_to(
-                    scroll,
-                    writer
-                        .list
-                        .as_ref()
-                        .expect("The files should already be crawled at this point!")
-                        .iter()
-                        .filter_map(|(b, fi)| if *b { Some(fi) } else { None }),
-                    |f| {
-                        presets::row_list2(vec![
-                            presets::text(f.copy_string()).width(Length::Fill).into(),
-                            presets::text(format_size(f.size))
+                // The commands are run when the backup starts, so show them before the files
+                if !self.config.commands.is_empty() {
+                    scroll = scroll.push(presets::text_error(
+                        "These commands will be run and their output stored in the backup:",
+                    ));
+                    for c in self.config.commands.iter() {
+                        scroll = scroll.push(presets::text(format!(
+                            "{}:  {}",
+                            c.path().to_string_lossy(),
+                            c.cmd
+                        )));
+                    }
+                }
+                if self.list_sort == ListSort::Dir {
+                    for g in self.dirs.iter() {
+                        scroll = scroll.push(presets::row_list2(vec![
+                            presets::text(&g.name).width(Length::Fill).into(),
+                            presets::text(format_size(g.size))
                                 .width(Length::Fixed(102.0))
                                 .align_x(Horizontal::Right)
                                 .into(),
-                            presets::text(f.time.unwrap().format("%Y-%m-%d %H:%M:%S").to_string())
+                            presets::text(format!("{} files", g.files))
                                 .width(Length::Fixed(182.0))
                                 .align_x(Horizontal::Right)
                                 .into(),
                             presets::space_scroll(),
-                        ])
-                        .into()
-                    },
-                );
+                        ]));
+                    }
+                } else {
+                    scroll = self.pagination.push_to(
+                        scroll,
+                        writer
+                            .list
+                            .as_ref()
+                            .expect("The files should already be crawled at this point!")
+                            .iter()
+                            .filter_map(|(b, fi)| if *b { Some(fi) } else { None }),
+                        |f| {
+                            presets::row_list2(vec![
+                                presets::text(f.copy_string()).width(Length::Fill).into(),
+                                presets::text(format_size(f.size))
+                                    .width(Length::Fixed(102.0))
+                                    .align_x(Horizontal::Right)
+                                    .into(),
+                                presets::text(
+                                    f.time.unwrap().format("%Y-%m-%d %H:%M:%S").to_string(),
+                                )
+                                .width(Length::Fixed(182.0))
+                                .align_x(Horizontal::Right)
+                                .into(),
+                                presets::space_scroll(),
+                            ])
+                            .into()
+                        },
+                    );
+                }
                 let diff = writer.list.as_ref().unwrap().len() - self.total_count;
                 let status = if diff > 0 {
                     if let Some(time) = writer.prev_time {
@@ -356,11 +459,32 @@ impl BackupState {
                         format_size(self.total_size)
                     )
                 };
+                let required = writer.required.as_deref().unwrap_or_default();
+                let status = if required.is_empty() {
+                    status
+                } else {
+                    let roots: Vec<String> = required
+                        .iter()
+                        .map(|r| format!("'{}' ({} files)", r.path, r.files))
+                        .collect();
+                    format!("{}\nRequired: {}", status, roots.join(", "))
+                };
+                let backup = if required.iter().any(|r| r.files == 0) {
+                    Message::None
+                } else {
+                    Message::Backup
+                };
                 let brow = presets::row_bar(vec![
                     presets::button_nav("Edit", Message::EditConfig, false),
+                    self.last_backup.view(),
                     presets::text_center(status),
+                    presets::button("Sizes by folder", Message::SortDir),
+                    presets::space_large(),
                     presets::button("Export list", Message::Export),
-                    presets::button_nav("Backup", Message::Backup, true),
+                    presets::space_large(),
+                    presets::toggler(self.background, "Background mode", Message::Background),
+                    presets::space_large(),
+                    presets::button_nav("Backup", backup, true),
                 ]);
                 let scroll = presets::scroll_border(scroll.into());
                 presets::column_root(vec![trow.into(), scroll, brow.into()]).into()
@@ -385,6 +509,8 @@ impl BackupState {
                 let brow = presets::row_bar(vec![
                     presets::button_nav("Edit", Message::None, false),
                     status,
+                    presets::toggler(self.background, "Background mode", Message::Background),
+                    presets::space_large(),
                     presets::button_nav(
                         "Cancel",
                         if let BackupStage::Cancelling(_) = self.stage {
@@ -407,10 +533,29 @@ impl BackupState {
                 let scroll = presets::scroll_border(scroll.into());
                 presets::column_root(vec![scroll, brow.into()]).into()
             }
-            BackupStage::Completed => {
+            BackupStage::Completed(skipped, stats, advice) => {
+                if let Some(advice) = advice {
+                    scroll = scroll.push(presets::text(format!("Suggestion: {}", advice)));
+                }
+                let status = match (skipped.is_empty(), stats) {
+                    (true, Some(stats)) => presets::text_center(format!("Backed up {}", stats)),
+                    (true, None) => presets::text_center("Backup completed"),
+                    (false, _) => {
+                        scroll = scroll.push(presets::text_error(
+                            "These files could not be read and are not in the backup:",
+                        ));
+                        for f in skipped.iter() {
+                            scroll = scroll.push(presets::text(f));
+                        }
+                        presets::text_center_error(format!(
+                            "Backup completed ({} files skipped)",
+                            skipped.len()
+                        ))
+                    }
+                };
                 let brow = presets::row_bar(vec![
                     presets::button_nav("Edit", Message::EditConfig, false),
-                    presets::text_center("Backup completed"),
+                    status,
                     presets::button_nav("Repeat", Message::Repeat, true),
                 ]);
                 let scroll = presets::scroll_border(scroll.into());
@@ -1,17 +1,22 @@
 #![cfg(feature = "gui")]
 
+use std::collections::HashMap;
 use std::path::PathBuf;
 
+use chrono::NaiveDateTime;
 use iced::widget::pane_grid;
-use iced::{Element, Length};
+use iced::{event, window, Element, Event, Length, Subscription};
 use regex::Regex;
 use rfd::{FileDialog, MessageDialog};
 
-use super::{presets, Message};
-use crate::backup::{CONFIG_DEFAULT_NAME, CONFIG_FILE_EXTENSION};
-use crate::config::Config;
-use crate::files::{FileCrawler, FileInfo};
-use crate::utils::{default_dir, home_dir};
+use super::settings::{LayoutAxis, PaneLayout};
+use super::{presets, LastBackup, Message, RowId};
+use crate::backup::{BackupError, CONFIG_DEFAULT_NAME, CONFIG_FILE_EXTENSIONS};
+use crate::config::{Config, ConfigIssue};
+use crate::files::FileInfo;
+use crate::parse_date::try_parse;
+use crate::recent::{remember, RecentKind};
+use crate::utils::{default_dir, home_dir, read_config_from_path};
 
 pub(crate) struct ConfigState {
     pub config: Config,
@@ -23,32 +28,70 @@ pub(crate) struct ConfigState {
     excludes: pane_grid::Pane,
     filters: pane_grid::Pane,
     current_dir: FileInfo,
+    filter_ids: usize,
+    hovered: Option<pane_grid::Pane>,
+    issues: Vec<ConfigIssue>,
+    /// The text of the age limits (kept while the time is being typed)
+    newer_than: String,
+    older_than: String,
+    /// The text of the incremental reference time (empty for the time of the last backup)
+    incremental_time: String,
+    last_backup: LastBackup,
+}
+
+/// The key of the config layout in the settings
+pub(crate) const CONFIG_LAYOUT: &str = "config";
+
+/// The built-in layout of the config panes (files to the left and the lists to the right)
+fn default_layout() -> PaneLayout {
+    let split = |axis, a: &str, b| PaneLayout::Split {
+        axis,
+        ratio: 0.5,
+        a: Box::new(PaneLayout::Pane(a.to_string())),
+        b: Box::new(b),
+    };
+    split(
+        LayoutAxis::Vertical,
+        ConfigPane::Files.name(),
+        split(
+            LayoutAxis::Horizontal,
+            ConfigPane::Includes.name(),
+            split(
+                LayoutAxis::Horizontal,
+                ConfigPane::Excludes.name(),
+                PaneLayout::Pane(ConfigPane::Filters.name().to_string()),
+            ),
+        ),
+    )
+}
+
+/// Find the position of a path in a list based on the row id
+fn find_row(list: &[String], id: RowId) -> Option<usize> {
+    list.iter().position(|s| RowId::of(s) == id)
 }
 
 impl ConfigState {
-    pub fn new(open_home: bool, default_ignores: bool) -> Self {
-        let (mut panes, files) = pane_grid::State::new(Pane::new(ConfigPane::Files));
-        let (includes, _) = panes
-            .split(
-                pane_grid::Axis::Vertical,
-                files,
-                Pane::new(ConfigPane::Includes),
-            )
-            .unwrap();
-        let (excludes, _) = panes
-            .split(
-                pane_grid::Axis::Horizontal,
-                includes,
-                Pane::new(ConfigPane::Excludes),
-            )
-            .unwrap();
-        let (filters, _) = panes
-            .split(
-                pane_grid::Axis::Horizontal,
-                excludes,
-                Pane::new(ConfigPane::Filters),
-            )
-            .unwrap();
+    /// Create the config editor (with the stored `layout` of the panes, if it is still valid)
+    pub fn new(open_home: bool, default_ignores: bool, layout: Option<&PaneLayout>) -> Self {
+        let panes = default_layout()
+            .rebuild(&ConfigPane::NAMES, |n| {
+                ConfigPane::from_name(n).map(Pane::new)
+            })
+            .map(pane_grid::State::with_configuration)
+            .expect("The default layout should have all panes");
+        let find = |content| {
+            panes
+                .iter()
+                .find(|(_, p)| p.content == content)
+                .map(|(id, _)| *id)
+                .unwrap()
+        };
+        let (files, includes, excludes, filters) = (
+            find(ConfigPane::Files),
+            find(ConfigPane::Includes),
+            find(ConfigPane::Excludes),
+            find(ConfigPane::Filters),
+        );
         let mut config = Config::new();
         if default_ignores {
             config.add_default_ignores();
@@ -63,30 +106,99 @@ impl ConfigState {
             excludes,
             filters,
             current_dir: FileInfo::from(if open_home { home_dir() } else { default_dir() }),
+            filter_ids: 0,
+            hovered: None,
+            issues: vec![],
+            newer_than: String::new(),
+            older_than: String::new(),
+            incremental_time: String::new(),
+            last_backup: LastBackup::Missing,
         };
+        if let Some(layout) = layout {
+            if !state.set_layout(layout) {
+                log::warn!("Could not restore the stored layout, using the default layout");
+            }
+        }
         if open_home | default_ignores {
             state.refresh_filters();
             state.refresh_files();
         }
+        state.issues = state.config.validate();
         state
     }
 
-    pub fn from(mut config: Config) -> Self {
+    pub fn from(mut config: Config, layout: Option<&PaneLayout>) -> Self {
         config.sort();
-        let mut state = Self::new(false, false);
+        let mut state = Self::new(false, false, layout);
         state.current_dir = FileInfo::from(config.get_dir());
+        let format = |t: Option<NaiveDateTime>| {
+            t.map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default()
+        };
+        state.newer_than = format(config.newer_than);
+        state.older_than = format(config.older_than);
+        state.incremental_time = format(config.time);
+        state.last_backup = LastBackup::lookup(&config);
         state.config = config;
         state.refresh_includes();
         state.refresh_excludes();
         state.refresh_filters();
         state.refresh_files();
+        state.issues = state.config.validate();
         state
     }
 
-    pub fn view(&self) -> Element<Message> {
-        let pane_grid = presets::pane_grid(&self.panes, |_, pane, _| pane.content());
+    /// Open the config for editing (configs with errors are also opened, to show the issues)
+    pub fn from_path(path: PathBuf, layout: Option<&PaneLayout>) -> Result<Self, BackupError> {
+        read_config_from_path(path).map(|c| Self::from(c, layout))
+    }
+
+    /// The current layout of the panes (for storing in the settings)
+    pub fn layout(&self) -> PaneLayout {
+        PaneLayout::capture(&self.panes, |p| p.content.name())
+    }
+
+    /// Rearrange the panes (keeping the content), returns false if the layout is invalid
+    fn set_layout(&mut self, layout: &PaneLayout) -> bool {
+        let mut items: HashMap<&str, Vec<ListItem>> = self
+            .panes
+            .iter_mut()
+            .map(|(_, p)| (p.content.name(), std::mem::take(&mut p.items)))
+            .collect();
+        let config = layout.rebuild(&ConfigPane::NAMES, |n| {
+            Some(Pane {
+                content: ConfigPane::from_name(n)?,
+                items: items.remove(n).unwrap_or_default(),
+            })
+        });
+        let Some(config) = config else {
+            // Put the content back
+            for (_, p) in self.panes.iter_mut() {
+                p.items = items.remove(p.content.name()).unwrap_or_default();
+            }
+            return false;
+        };
+        self.panes = pane_grid::State::with_configuration(config);
+        for (id, p) in self.panes.iter() {
+            match p.content {
+                ConfigPane::Files => self.files = *id,
+                ConfigPane::Includes => self.includes = *id,
+                ConfigPane::Excludes => self.excludes = *id,
+                ConfigPane::Filters => self.filters = *id,
+            }
+        }
+        self.hovered = None;
+        true
+    }
+
+    pub fn view(&self) -> Element<'_, Message> {
+        let pane_grid = presets::pane_grid(&self.panes, |id, pane, _| pane.content(id));
         let bar = presets::row_bar(vec![
             presets::button_nav("Back", Message::MainView, false),
+            presets::space_large(),
+            presets::button_grey("Reset layout", Message::ResetLayout).into(),
+            presets::space_large(),
+            self.last_backup.view(),
             presets::space_hfill(),
             presets::text("Compression:").into(),
             presets::pick_list(
@@ -102,16 +214,59 @@ impl ConfigState {
                 Message::ThreadCount,
             ),
             presets::space_large(),
+            presets::text("Max depth:").into(),
+            presets::number_field(
+                &self
+                    .config
+                    .max_depth
+                    .map(|d| d.to_string())
+                    .unwrap_or_default(),
+                "None",
+                Message::MaxDepth,
+            ),
+            presets::space_large(),
+            presets::text("Newer than:").into(),
+            presets::number_field(&self.newer_than, "Any time", Message::NewerThan),
+            presets::space_large(),
+            presets::text("Older than:").into(),
+            presets::number_field(&self.older_than, "Any time", Message::OlderThan),
+            presets::space_large(),
             presets::toggler(
                 self.config.incremental,
                 "Incremental backups:",
                 Message::Incremental,
             ),
+            presets::space_large(),
+            presets::text("Since:").into(),
+            presets::number_field(
+                &self.incremental_time,
+                "Last backup",
+                Message::IncrementalTime,
+            ),
             presets::space_hfill(),
             presets::button_nav("Save", Message::Save, true),
             presets::button_nav("Backup", Message::BackupView, true),
         ]);
-        presets::column_root(vec![pane_grid.into(), bar.into()]).into()
+        let mut root = vec![pane_grid.into()];
+        if !self.issues.is_empty() {
+            root.push(
+                presets::column_list2(
+                    self.issues
+                        .iter()
+                        .map(|i| {
+                            if i.is_error() {
+                                presets::text_error(format!("Error: {}", i)).into()
+                            } else {
+                                presets::text(format!("Warning: {}", i)).into()
+                            }
+                        })
+                        .collect(),
+                )
+                .into(),
+            );
+        }
+        root.push(bar.into());
+        presets::column_root(root).into()
     }
 
     pub fn update(&mut self, message: Message) {
@@ -123,32 +278,65 @@ impl ConfigState {
                 self.panes.drop(pane, target)
             }
             Message::PaneDragged(_) => {}
+            Message::ResetLayout => {
+                self.set_layout(&default_layout());
+            }
+            Message::PaneHovered(pane) => self.hovered = Some(pane),
+            Message::FileDropped(path) => {
+                let s = path.to_string_lossy().to_string();
+                if self.hovered == Some(self.excludes) {
+                    self.add_exclude(s);
+                } else {
+                    self.add_include(s);
+                }
+            }
+            Message::Tick => self.last_backup.poll(),
             Message::Incremental(t) => self.config.incremental = t,
             Message::ThreadCount(text) => self.config.set_threads(text),
             Message::CompressionQuality(text) => self.config.set_quality(text),
-            Message::IncludeAdd(i) => {
+            Message::MaxDepth(text) => {
+                let depth = text.parse().ok();
+                if depth.is_some() || text.is_empty() {
+                    self.config.max_depth = depth;
+                    self.refresh_files();
+                }
+            }
+            Message::IncrementalTime(text) => {
+                if let Ok(time) = try_parse(&text) {
+                    self.config.time = time;
+                }
+                self.incremental_time = text;
+            }
+            Message::NewerThan(text) => {
+                if let Ok(time) = try_parse(&text) {
+                    self.config.newer_than = time;
+                }
+                self.newer_than = text;
+            }
+            Message::OlderThan(text) => {
+                if let Ok(time) = try_parse(&text) {
+                    self.config.older_than = time;
+                }
+                self.older_than = text;
+            }
+            Message::IncludeAdd(id) => {
                 let pane = self.panes.get_mut(self.files).unwrap();
-                if let Some(li) = pane.items.get_mut(i) {
+                if let Some(li) = pane.items.iter_mut().find(|li| li.id == id) {
                     let s = std::mem::take(&mut li.text);
-                    if let Ok(i) = self.config.exclude.binary_search(&s) {
-                        self.config.exclude.remove(i);
-                        self.refresh_excludes();
-                    }
-                    self.config.include.push(s);
-                    self.config.include.sort_unstable();
-                    self.refresh_includes();
-                    self.refresh_files();
+                    self.add_include(s);
                 }
             }
-            Message::IncludeRemove(i) => {
-                if i < self.config.include.len() {
-                    self.config.include.remove(i);
+            Message::IncludeRemove(id) => {
+                if let Some(i) = find_row(&self.config.include, id) {
+                    let s = self.config.include.remove(i);
+                    self.config.required.retain(|r| *r != s);
                     self.refresh_includes();
                     self.refresh_files();
                 }
             }
-            Message::IncludeOpen(i) => {
-                if let Some(s) = self.config.include.get(i) {
+            Message::IncludeOpen(id) => {
+                if let Some(s) = find_row(&self.config.include, id).map(|i| &self.config.include[i])
+                {
                     let p = PathBuf::from(s);
                     if let Ok(m) = p.metadata() {
                         if m.is_dir() {
@@ -159,29 +347,35 @@ impl ConfigState {
                     }
                 }
             }
-            Message::ExcludeAdd(i) => {
+            Message::IncludeRequired(id) => {
+                if let Some(i) = find_row(&self.config.include, id) {
+                    let s = &self.config.include[i];
+                    match self.config.required.iter().position(|r| r == s) {
+                        Some(j) => {
+                            self.config.required.remove(j);
+                        }
+                        None => self.config.required.push(s.clone()),
+                    }
+                    self.refresh_includes();
+                }
+            }
+            Message::ExcludeAdd(id) => {
                 let pane = self.panes.get_mut(self.files).unwrap();
-                if let Some(li) = pane.items.get_mut(i) {
+                if let Some(li) = pane.items.iter_mut().find(|li| li.id == id) {
                     let s = std::mem::take(&mut li.text);
-                    if let Ok(i) = self.config.include.binary_search(&s) {
-                        self.config.include.remove(i);
-                        self.refresh_includes();
-                    }
-                    self.config.exclude.push(s);
-                    self.config.exclude.sort_unstable();
-                    self.refresh_excludes();
-                    self.refresh_files();
+                    self.add_exclude(s);
                 }
             }
-            Message::ExcludeRemove(i) => {
-                if i < self.config.exclude.len() {
+            Message::ExcludeRemove(id) => {
+                if let Some(i) = find_row(&self.config.exclude, id) {
                     self.config.exclude.remove(i);
                     self.refresh_excludes();
                     self.refresh_files();
                 }
             }
-            Message::ExcludeOpen(i) => {
-                if let Some(s) = self.config.exclude.get(i) {
+            Message::ExcludeOpen(id) => {
+                if let Some(s) = find_row(&self.config.exclude, id).map(|i| &self.config.exclude[i])
+                {
                     let p = PathBuf::from(s);
                     if let Ok(m) = p.metadata() {
                         if m.is_dir() {
@@ -196,17 +390,23 @@ impl ConfigState {
                 self.config.regex.push(String::new());
                 self.refresh_filters();
             }
-            Message::FilterRemove(i) => {
-                if i < self.config.regex.len() {
+            Message::FilterRemove(id) => {
+                let pane = self.panes.get(self.filters).unwrap();
+                if let Some(i) = pane.items.iter().position(|li| li.id == id) {
                     self.config.regex.remove(i);
                     self.refresh_filters();
                     self.refresh_files();
                 }
             }
-            Message::FilterEdit(i, s) => {
+            Message::FilterEdit(id, s) => {
                 let pane = self.panes.get_mut(self.filters).unwrap();
                 let mut refresh = false;
-                if let Some(item) = pane.items.get_mut(i) {
+                if let Some((i, item)) = pane
+                    .items
+                    .iter_mut()
+                    .enumerate()
+                    .find(|(_, li)| li.id == id)
+                {
                     if !item.text.eq(&s) {
                         if Regex::new(&s).is_ok() {
                             item.status = true;
@@ -223,9 +423,9 @@ impl ConfigState {
                     self.refresh_files();
                 }
             }
-            Message::FolderOpen(i) => {
+            Message::FolderOpen(id) => {
                 let pane = self.panes.get_mut(self.files).unwrap();
-                if let Some(li) = pane.items.get_mut(i) {
+                if let Some(li) = pane.items.iter_mut().find(|li| li.id == id) {
                     let dir: FileInfo = std::mem::take(&mut li.text).into();
                     self.open_dir(dir);
                 }
@@ -250,11 +450,11 @@ impl ConfigState {
                     .set_directory(self.config.get_output(true))
                     .set_title("Save config file")
                     .set_file_name(CONFIG_DEFAULT_NAME)
-                    .add_filter("Config file", &[&CONFIG_FILE_EXTENSION[1..]])
+                    .add_filter("Config file", &CONFIG_FILE_EXTENSIONS.map(|e| &e[1..]))
                     .save_file()
                 {
-                    match self.config.write_yaml(file, false) {
-                        Ok(_) => {}
+                    match self.config.write_file(file.clone(), false) {
+                        Ok(_) => remember(RecentKind::Config, file),
                         Err(e) => {
                             MessageDialog::new()
                                 .set_description(e.to_string())
@@ -266,7 +466,43 @@ impl ConfigState {
                     };
                 }
             }
-            _ => eprintln!("Unexpected GUI message: {:?}", message),
+            _ => log::warn!("Unexpected GUI message: {:?}", message),
+        }
+        self.issues = self.config.validate();
+    }
+
+    pub fn subscription(&self) -> Subscription<Message> {
+        Subscription::batch([
+            event::listen_with(|event, _, _| match event {
+                Event::Window(window::Event::FileDropped(path)) => Some(Message::FileDropped(path)),
+                _ => None,
+            }),
+            self.last_backup.subscription(),
+        ])
+    }
+
+    fn add_include(&mut self, s: String) {
+        if let Ok(i) = self.config.exclude.binary_search(&s) {
+            self.config.exclude.remove(i);
+            self.refresh_excludes();
+        }
+        if let Err(i) = self.config.include.binary_search(&s) {
+            self.config.include.insert(i, s);
+            self.refresh_includes();
+            self.refresh_files();
+        }
+    }
+
+    fn add_exclude(&mut self, s: String) {
+        if let Ok(i) = self.config.include.binary_search(&s) {
+            self.config.include.remove(i);
+            self.config.required.retain(|r| *r != s);
+            self.refresh_includes();
+        }
+        if let Err(i) = self.config.exclude.binary_search(&s) {
+            self.config.exclude.insert(i, s);
+            self.refresh_excludes();
+            self.refresh_files();
         }
     }
 
@@ -278,35 +514,29 @@ impl ConfigState {
     fn refresh_files(&mut self) {
         let pane = self.panes.get_mut(self.files).unwrap();
         pane.items.clear();
-        match FileCrawler::new(
-            &self.config.include,
-            &self.config.exclude,
-            &self.config.regex,
-            self.config.local,
-        ) {
+        match self.config.crawler() {
             Ok(fc) => {
                 let parent = fc.check_path(&mut self.current_dir, None);
+                let text = self.current_dir.get_string().to_string();
                 pane.items.push(ListItem::new(
                     ListState::ParentFolder(self.current_dir.get_path().parent().is_some()),
-                    self.current_dir.get_string().to_string(),
-                    0,
+                    RowId::of(&text),
+                    text,
                     parent,
                 ));
                 match self.current_dir.get_path().read_dir() {
                     Ok(rd) => {
-                        for (i, de) in rd.into_iter().enumerate() {
+                        // Sort the entries to get a deterministic order
+                        let mut entries: Vec<_> = rd.collect();
+                        entries.sort_by_key(|de| de.as_ref().ok().map(|de| de.file_name()));
+                        for de in entries {
                             match de {
                                 Ok(de) => match de.metadata() {
                                     Ok(md) => {
                                         let dir = md.is_dir();
                                         let mut fi = FileInfo::from(&de);
                                         let inc = fc.check_path(&mut fi, Some(parent));
-                                        pane.items.push(ListItem::file(
-                                            fi.move_string(),
-                                            inc,
-                                            dir,
-                                            i + 1,
-                                        ));
+                                        pane.items.push(ListItem::file(fi.move_string(), inc, dir));
                                     }
                                     Err(e) => pane.items.push(ListItem::error(format!("{}", e))),
                                 },
@@ -324,13 +554,15 @@ impl ConfigState {
     fn refresh_includes(&mut self) {
         let pane = self.panes.get_mut(self.includes).unwrap();
         pane.items.clear();
-        pane.items.extend(
-            self.config
-                .include
-                .iter()
-                .enumerate()
-                .map(|(i, s)| ListItem::new(ListState::Include, s.to_string(), i, false)),
-        );
+        let required = &self.config.required;
+        pane.items.extend(self.config.include.iter().map(|s| {
+            ListItem::new(
+                ListState::Include,
+                RowId::of(s),
+                s.to_string(),
+                required.contains(s),
+            )
+        }));
     }
 
     fn refresh_excludes(&mut self) {
@@ -340,24 +572,23 @@ impl ConfigState {
             self.config
                 .exclude
                 .iter()
-                .enumerate()
-                .map(|(i, s)| ListItem::new(ListState::Exclude, s.to_string(), i, false)),
+                .map(|s| ListItem::new(ListState::Exclude, RowId::of(s), s.to_string(), false)),
         );
     }
 
     fn refresh_filters(&mut self) {
+        // The filters might not be unique, so they get new ids on every refresh
         let pane = self.panes.get_mut(self.filters).unwrap();
         pane.items.clear();
-        pane.items.extend(
-            self.config
-                .regex
-                .iter()
-                .enumerate()
-                .map(|(i, s)| ListItem::edit(s.to_string(), i)),
-        );
+        for s in self.config.regex.iter() {
+            pane.items
+                .push(ListItem::edit(s.to_string(), RowId::from(self.filter_ids)));
+            self.filter_ids += 1;
+        }
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum ConfigPane {
     Files,
     Includes,
@@ -365,6 +596,25 @@ enum ConfigPane {
     Filters,
 }
 
+impl ConfigPane {
+    /// The names of the panes in the stored layouts
+    const NAMES: [&'static str; 4] = ["files", "includes", "excludes", "filters"];
+
+    fn name(self) -> &'static str {
+        Self::NAMES[self as usize]
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "files" => Some(ConfigPane::Files),
+            "includes" => Some(ConfigPane::Includes),
+            "excludes" => Some(ConfigPane::Excludes),
+            "filters" => Some(ConfigPane::Filters),
+            _ => None,
+        }
+    }
+}
+
 struct Pane {
     content: ConfigPane,
     items: Vec<ListItem>,
@@ -378,19 +628,24 @@ impl Pane {
         }
     }
 
-    fn content(&self) -> pane_grid::Content<Message> {
+    fn content(&self, id: pane_grid::Pane) -> pane_grid::Content<'_, Message> {
         let content = presets::column_list2(self.items.iter().map(|i| i.view()).collect());
+        let hover = Message::PaneHovered(id);
         match self.content {
             ConfigPane::Files => presets::scroll_pane(
                 "Files",
                 Some(("Open", Message::FolderDialog)),
                 content.into(),
+                hover,
+            ),
+            ConfigPane::Includes => presets::scroll_pane("Includes", None, content.into(), hover),
+            ConfigPane::Excludes => presets::scroll_pane("Excludes", None, content.into(), hover),
+            ConfigPane::Filters => presets::scroll_pane(
+                "Filters",
+                Some(("Add", Message::FilterAdd)),
+                content.into(),
+                hover,
             ),
-            ConfigPane::Includes => presets::scroll_pane("Includes", None, content.into()),
-            ConfigPane::Excludes => presets::scroll_pane("Excludes", None, content.into()),
-            ConfigPane::Filters => {
-                presets::scroll_pane("Filters", Some(("Add", Message::FilterAdd)), content.into())
-            }
         }
     }
 }
@@ -407,44 +662,45 @@ enum ListState {
 
 struct ListItem {
     state: ListState,
-    index: usize,
+    id: RowId,
     status: bool,
     text: String,
 }
 
 impl ListItem {
-    fn new(state: ListState, text: String, index: usize, status: bool) -> Self {
+    fn new(state: ListState, id: RowId, text: String, status: bool) -> Self {
         Self {
             state,
-            index,
+            id,
             status,
             text,
         }
     }
 
     fn error(text: String) -> Self {
-        Self::new(ListState::Error, text, 0, false)
+        Self::new(ListState::Error, RowId::of(&text), text, false)
     }
 
-    fn file(text: String, included: bool, is_dir: bool, index: usize) -> Self {
+    fn file(text: String, included: bool, is_dir: bool) -> Self {
+        let id = RowId::of(&text);
         if is_dir {
-            Self::new(ListState::Folder, text, index, included)
+            Self::new(ListState::Folder, id, text, included)
         } else {
-            Self::new(ListState::File, text, index, included)
+            Self::new(ListState::File, id, text, included)
         }
     }
 
-    fn edit(text: String, index: usize) -> Self {
+    fn edit(text: String, id: RowId) -> Self {
         let valid = text.is_empty() || Regex::new(&text).is_ok();
-        Self::new(ListState::Filter, text, index, valid)
+        Self::new(ListState::Filter, id, text, valid)
     }
 
-    fn view(&self) -> Element<Message> {
+    fn view(&self) -> Element<'_, Message> {
         let row = presets::row_list();
         let row = match self.state {
             ListState::File => row.push(presets::space_icon()),
             ListState::Folder => row.push(presets::tooltip_right(
-                presets::button_icon(">", Message::FolderOpen(self.index), false),
+                presets::button_icon(">", Message::FolderOpen(self.id), false),
                 "Open",
             )),
             ListState::ParentFolder(up) => row.push(presets::tooltip_right(
@@ -456,11 +712,11 @@ impl ListItem {
                 "Go Up",
             )),
             ListState::Include => row.push(presets::tooltip_right(
-                presets::button_icon("O", Message::IncludeOpen(self.index), false),
+                presets::button_icon("O", Message::IncludeOpen(self.id), false),
                 "Go To",
             )),
             ListState::Exclude => row.push(presets::tooltip_right(
-                presets::button_icon("O", Message::ExcludeOpen(self.index), false),
+                presets::button_icon("O", Message::ExcludeOpen(self.id), false),
                 "Go To",
             )),
             ListState::Error | ListState::Filter => row,
@@ -478,7 +734,7 @@ impl ListItem {
                         if self.status {
                             Message::None
                         } else {
-                            Message::IncludeAdd(self.index)
+                            Message::IncludeAdd(self.id)
                         },
                         false,
                     ),
@@ -488,7 +744,7 @@ impl ListItem {
                     presets::button_icon(
                         "-",
                         if self.status {
-                            Message::ExcludeAdd(self.index)
+                            Message::ExcludeAdd(self.id)
                         } else {
                             Message::None
                         },
@@ -496,17 +752,30 @@ impl ListItem {
                     ),
                     "Exclude",
                 )),
-            ListState::Include => row.push(presets::tooltip_left(
-                presets::button_icon("-", Message::IncludeRemove(self.index), true),
+            ListState::Include => if self.status {
+                row.push(presets::text_badge("Required"))
+            } else {
+                row
+            }
+            .push(presets::tooltip_left(
+                presets::button_icon("!", Message::IncludeRequired(self.id), false),
+                if self.status {
+                    "Make optional"
+                } else {
+                    "Make required (fail the backup if it has no files)"
+                },
+            ))
+            .push(presets::tooltip_left(
+                presets::button_icon("-", Message::IncludeRemove(self.id), true),
                 "Remove",
             )),
             ListState::Exclude => row.push(presets::tooltip_left(
-                presets::button_icon("-", Message::ExcludeRemove(self.index), true),
+                presets::button_icon("-", Message::ExcludeRemove(self.id), true),
                 "Remove",
             )),
             ListState::Filter => {
-                let i = self.index;
-                let mess = move |t| Message::FilterEdit(i, t);
+                let id = self.id;
+                let mess = move |t| Message::FilterEdit(id, t);
                 let row = row.push(presets::regex_field(
                     &self.text,
                     "Regex filter",
@@ -519,7 +788,7 @@ impl ListItem {
                     row
                 }
                 .push(presets::tooltip_left(
-                    presets::button_icon("-", Message::FilterRemove(self.index), true),
+                    presets::button_icon("-", Message::FilterRemove(self.id), true),
                     "Remove",
                 ))
             }
@@ -529,3 +798,193 @@ impl ListItem {
         row.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use tempfile::tempdir;
+
+    use super::{default_layout, ConfigState};
+    use crate::config::{Config, ConfigIssue};
+    use crate::gui::settings::{LayoutAxis, PaneLayout};
+    use crate::gui::{Message, RowId};
+    use crate::utils::get_config_from_path;
+
+    #[test]
+    fn toggle_required() {
+        let dir = tempdir().unwrap();
+        let paths: Vec<String> = ["a", "b"]
+            .iter()
+            .map(|f| dir.path().join(f).to_string_lossy().to_string())
+            .collect();
+        let mut config = Config::new();
+        config.include = paths.clone();
+        config.output = dir.path().to_path_buf();
+        let mut state = ConfigState::from(config, None);
+
+        state.update(Message::IncludeRequired(RowId::of(&paths[1])));
+        assert_eq!(state.config.required, vec![paths[1].clone()]);
+        let items = &state.panes.get(state.includes).unwrap().items;
+        assert!(!items[0].status);
+        assert!(items[1].status);
+        state.update(Message::IncludeRequired(RowId::of(&paths[0])));
+        state.update(Message::IncludeRequired(RowId::of(&paths[1])));
+        assert_eq!(state.config.required, vec![paths[0].clone()]);
+        state.update(Message::IncludeRemove(RowId::of(&paths[0])));
+        assert!(state.config.required.is_empty());
+    }
+
+    #[test]
+    fn remove_include_after_filter() {
+        let dir = tempdir().unwrap();
+        let paths: Vec<String> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|f| {
+                let path = dir.path().join(f);
+                File::create(&path).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let mut config = Config::new();
+        config.include = paths.clone();
+        config.output = dir.path().to_path_buf();
+        let mut state = ConfigState::from(config, None);
+
+        state.update(Message::FilterAdd);
+        let id = state.panes.get(state.filters).unwrap().items[0].id;
+        state.update(Message::FilterEdit(id, "c\\.txt$".to_string()));
+        assert_eq!(state.config.regex, vec!["c\\.txt$".to_string()]);
+
+        state.update(Message::IncludeRemove(RowId::of(&paths[1])));
+        assert_eq!(state.config.include, vec![
+            paths[0].clone(),
+            paths[2].clone()
+        ]);
+        // Stale ids should not affect other rows
+        state.update(Message::IncludeRemove(RowId::of(&paths[1])));
+        assert_eq!(state.config.include.len(), 2);
+        state.update(Message::FilterAdd);
+        state.update(Message::FilterRemove(id));
+        assert_eq!(state.config.regex.len(), 2);
+    }
+
+    #[test]
+    fn include_from_files() {
+        let dir = tempdir().unwrap();
+        for f in ["c.txt", "a.txt", "b.txt"] {
+            File::create(dir.path().join(f)).unwrap();
+        }
+        let mut config = Config::new();
+        config.output = dir.path().to_path_buf();
+        let mut state = ConfigState::from(config, None);
+
+        let files: Vec<String> = state.panes.get(state.files).unwrap().items[1..]
+            .iter()
+            .map(|li| li.text.clone())
+            .collect();
+        assert_eq!(files.len(), 3);
+        assert!(files.is_sorted());
+
+        state.update(Message::IncludeAdd(RowId::of(&files[1])));
+        assert_eq!(state.config.include, vec![files[1].clone()]);
+        state.update(Message::ExcludeAdd(RowId::of(&files[2])));
+        assert_eq!(state.config.exclude, vec![files[2].clone()]);
+    }
+
+    #[test]
+    fn drop_files() {
+        let dir = tempdir().unwrap();
+        let mut config = Config::new();
+        config.output = dir.path().to_path_buf();
+        let mut state = ConfigState::from(config, None);
+        let f1 = dir.path().join("a.txt");
+        let f2 = dir.path().join("b");
+
+        state.update(Message::FileDropped(f1.clone()));
+        state.update(Message::FileDropped(f1.clone()));
+        assert_eq!(state.config.include, vec![f1.to_string_lossy().to_string()]);
+        assert_eq!(state.panes.get(state.includes).unwrap().items.len(), 1);
+
+        state.update(Message::PaneHovered(state.excludes));
+        state.update(Message::FileDropped(f2.clone()));
+        state.update(Message::FileDropped(f1.clone()));
+        assert!(state.config.include.is_empty());
+        assert_eq!(state.config.exclude, vec![
+            f1.to_string_lossy().to_string(),
+            f2.to_string_lossy().to_string()
+        ]);
+
+        state.update(Message::PaneHovered(state.files));
+        state.update(Message::FileDropped(f2.clone()));
+        assert_eq!(state.config.include, vec![f2.to_string_lossy().to_string()]);
+        assert_eq!(state.config.exclude, vec![f1.to_string_lossy().to_string()]);
+    }
+
+    #[test]
+    fn open_invalid() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config.yml");
+        let missing = dir.path().join("missing.txt");
+        let mut config = Config::new();
+        config.include = vec![dir.path().to_string_lossy().to_string()];
+        config.output = dir.path().to_path_buf();
+        config.exclude_from = Some(missing.clone());
+        config.write_file(&path, false).unwrap();
+        assert!(get_config_from_path(path.clone()).is_err());
+
+        // Configs with errors can still be opened for editing
+        let state = ConfigState::from_path(path, None).unwrap();
+        assert_eq!(state.config.exclude_from, Some(missing.clone()));
+        assert!(state.issues.contains(&ConfigIssue::MissingPatternFile(
+            missing.to_string_lossy().to_string()
+        )));
+    }
+
+    #[test]
+    fn stored_layout() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a").to_string_lossy().to_string();
+        let mut config = Config::new();
+        config.include = vec![path.clone()];
+        config.output = dir.path().to_path_buf();
+        let pane = |name: &str| Box::new(PaneLayout::Pane(name.to_string()));
+        let layout = PaneLayout::Split {
+            axis: LayoutAxis::Horizontal,
+            ratio: 0.7,
+            a: Box::new(PaneLayout::Split {
+                axis: LayoutAxis::Vertical,
+                ratio: 0.2,
+                a: pane("filters"),
+                b: pane("includes"),
+            }),
+            b: Box::new(PaneLayout::Split {
+                axis: LayoutAxis::Vertical,
+                ratio: 0.4,
+                a: pane("excludes"),
+                b: pane("files"),
+            }),
+        };
+        let mut state = ConfigState::from(config.clone(), Some(&layout));
+        assert_eq!(state.layout(), layout);
+        let items = &state.panes.get(state.includes).unwrap().items;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].text, path);
+
+        // The content is kept when resetting
+        state.update(Message::ResetLayout);
+        assert_eq!(state.layout(), default_layout());
+        assert_eq!(state.panes.get(state.includes).unwrap().items.len(), 1);
+
+        // Layouts from other versions fall back to the default
+        let old = PaneLayout::Split {
+            axis: LayoutAxis::Vertical,
+            ratio: 0.3,
+            a: pane("files"),
+            b: pane("includes"),
+        };
+        let state = ConfigState::from(config, Some(&old));
+        assert_eq!(state.layout(), default_layout());
+        assert_eq!(state.panes.len(), 4);
+    }
+}
@@ -8,10 +8,10 @@ use iced::{Element, Length, Subscription};
 use rfd::FileDialog;
 
 use super::threads::ThreadWrapper;
-use super::{presets, Message};
+use super::{presets, Message, RowId};
 use crate::backup::{BackupError, BackupMerger, BackupReader, BACKUP_FILE_EXTENSION};
 use crate::files::FileInfo;
-use crate::utils::{default_dir, default_dir_opt};
+use crate::utils::{default_dir, default_dir_opt, format_relative, BackupIterator};
 
 fn open_backups<P: AsRef<Path>>(dir: Option<P>) -> Option<Vec<PathBuf>> {
     if let Some(dir) = dir {
@@ -79,6 +79,48 @@ impl MergeState {
         }
     }
 
+    pub fn from_path(path: PathBuf) -> Result<Self, BackupError> {
+        let paths = BackupIterator::path(path)?.collect::<std::io::Result<Vec<_>>>()?;
+        let mut state = Self::new();
+        state.add_backups(paths);
+        match &state.stage {
+            MergeStage::Selecting(list) if list.is_empty() => {
+                Err(BackupError::GenericError("No backups could be loaded"))
+            }
+            _ => Ok(state),
+        }
+    }
+
+    fn add_backups(&mut self, paths: Vec<PathBuf>) {
+        if let MergeStage::Selecting(list) = &mut self.stage {
+            for p in paths.into_iter() {
+                let mut reader = BackupReader::new(p);
+                if let Err(e) = reader.get_meta() {
+                    self.error.push('\n');
+                    self.error.push_str(&e.to_string());
+                } else if !list.iter().any(|r| r.path == reader.path) {
+                    match reader.get_meta() {
+                        Ok((config, _)) => {
+                            if list.is_empty() {
+                                if self.quality.is_none() {
+                                    self.quality = Some(config.quality);
+                                }
+                                if self.threads.is_none() {
+                                    self.threads = Some(config.threads);
+                                }
+                            }
+                            list.push(reader);
+                        }
+                        Err(e) => {
+                            self.error.push('\n');
+                            self.error.push_str(&e.to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     pub fn update(&mut self, message: Message) {
         match message {
             Message::Tick => match &mut self.stage {
@@ -107,10 +149,12 @@ impl MergeState {
                                                 self.current_count = 0;
                                                 self.stage = MergeStage::Completed;
                                             }
-                                            Err(_) => {
+                                            Err(e) => {
                                                 self.error.push_str(
-                                                    "\nFailure when finalising the backup",
+                                                    "\nFailure when finalising the backup: ",
                                                 );
+                                                self.error.push_str(&e.to_string());
+                                                self.stage = MergeStage::Error;
                                             }
                                         }
                                     }
@@ -120,6 +164,7 @@ impl MergeState {
                         }
                     }
                 }
+                #[allow(clippy::collapsible_match)]
                 MergeStage::Cancelling(wrapper) => {
                     if wrapper.try_cancel() {
                         if let MergeStage::Cancelling(wrapper) =
@@ -134,8 +179,11 @@ impl MergeState {
                                     self.current_count = 0;
                                     self.stage = MergeStage::Cancelled;
                                 }
-                                Err(_) => {
-                                    self.error.push_str("\nFailure when cancelling the backup");
+                                Err(e) => {
+                                    self.error
+                                        .push_str("\nFailure when cancelling the backup: ");
+                                    self.error.push_str(&e.to_string());
+                                    self.stage = MergeStage::Error;
                                 }
                             };
                         }
@@ -156,18 +204,26 @@ impl MergeState {
                             true,
                             self.quality,
                             self.threads,
+                            false,
                         ) {
-                            Ok(mut merger) => {
-                                if let Some(path) = select_output(&merger.path) {
-                                    merger.path = path;
+                            Ok(mut merger) => match select_output(&merger.path)
+                                .map(|path| merger.set_output(path))
+                            {
+                                Some(Ok(())) => {
                                     self.current_count = merger.files.len();
                                     self.stage = MergeStage::Performing(
                                         ThreadWrapper::merge_backups(merger, 1000),
                                     );
-                                } else {
+                                }
+                                Some(Err(e)) => {
+                                    self.error.push('\n');
+                                    self.error.push_str(&e.to_string());
                                     self.stage = MergeStage::Selecting(merger.deconstruct());
                                 }
-                            }
+                                None => {
+                                    self.stage = MergeStage::Selecting(merger.deconstruct());
+                                }
+                            },
                             Err((_, e)) => {
                                 self.error.push('\n');
                                 self.error.push_str(&e.to_string());
@@ -186,42 +242,17 @@ impl MergeState {
                     }
                 }
             }
-            Message::IncludeRemove(i) => {
+            Message::IncludeRemove(id) => {
                 if let MergeStage::Selecting(list) = &mut self.stage {
-                    list.remove(i);
+                    list.retain(|r| RowId::of(&r.path.copy_string()) != id);
                 }
             }
-            Message::IncludeAdd(_) => {
+            Message::AddBackup => {
                 if let MergeStage::Selecting(list) = &mut self.stage {
                     let dir = list.iter_mut().next().map(|r| r.path.get_path());
-                    let open = open_backups(dir);
-                    if let Some(list2) = open {
-                        for p in list2.into_iter() {
-                            let mut reader = BackupReader::new(p);
-                            if let Err(e) = reader.get_meta() {
-                                self.error.push('\n');
-                                self.error.push_str(&e.to_string());
-                            } else if !list.iter().any(|r| r.path == reader.path) {
-                                match reader.get_meta() {
-                                    Ok((config, _)) => {
-                                        if list.is_empty() {
-                                            if self.quality.is_none() {
-                                                self.quality = Some(config.quality);
-                                            }
-                                            if self.threads.is_none() {
-                                                self.threads = Some(config.threads);
-                                            }
-                                        }
-                                        list.push(reader);
-                                    }
-                                    Err(e) => {
-                                        self.error.push('\n');
-                                        self.error.push_str(&e.to_string());
-                                    }
-                                }
-                            }
-                        }
-                    };
+                    if let Some(paths) = open_backups(dir) {
+                        self.add_backups(paths);
+                    }
                 }
             }
             Message::All(b) => {
@@ -239,7 +270,7 @@ impl MergeState {
             Message::Repeat => {
                 *self = Self::new();
             }
-            _ => eprintln!("Unexpected GUI message: {:?}", message),
+            _ => log::warn!("Unexpected GUI message: {:?}", message),
         }
     }
 
@@ -255,26 +286,38 @@ impl MergeState {
         }
     }
 
-    pub fn view(&self) -> Element<Message> {
+    pub fn view(&self) -> Element<'_, Message> {
         let mut scroll = presets::column_list();
         if !self.error.is_empty() {
             scroll = scroll.push(presets::text_error(&self.error[1..]));
         }
         match &self.stage {
             MergeStage::Selecting(list) => {
-                scroll = list.iter().enumerate().fold(scroll, |s, (i, r)| {
+                scroll = list.iter().fold(scroll, |s, r| {
                     s.push(presets::row_list2(vec![
-                        presets::button_icon("-", Message::IncludeRemove(i), true),
+                        presets::button_icon(
+                            "-",
+                            Message::IncludeRemove(RowId::of(&r.path.copy_string())),
+                            true,
+                        ),
                         presets::text(r.path.copy_string())
                             .width(Length::Fill)
                             .align_x(Horizontal::Left)
                             .into(),
+                        match r.config.as_ref().and_then(|c| c.time) {
+                            Some(t) => presets::tooltip_left(
+                                presets::text(format_relative(t)).into(),
+                                t.format("%Y-%m-%d %H:%M:%S").to_string(),
+                            )
+                            .into(),
+                            None => presets::text("unknown time").into(),
+                        },
                     ]))
                 });
                 scroll = scroll.push(presets::space_large());
                 scroll = scroll.push(presets::row_list2(vec![
                     presets::space_hfill(),
-                    presets::button("  Add backup  ", Message::IncludeAdd(0)),
+                    presets::button("  Add backup  ", Message::AddBackup),
                     presets::space_hfill(),
                 ]));
                 let mess = if list.len() < 2 {
@@ -1,17 +1,27 @@
 #![cfg(feature = "gui")]
 /// This module contains the logic for running the program through a GUI
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::PathBuf;
+
+use clap::ValueEnum;
 use iced::widget::{column, pane_grid, row, Space};
-use iced::{Element, Length, Subscription};
+use iced::{Element, Length, Subscription, Task};
 use rfd::{FileDialog, MessageDialog};
 use theme::theme;
 
 use self::backup::BackupState;
-use self::config::ConfigState;
+use self::config::{ConfigState, CONFIG_LAYOUT};
 use self::merge::MergeState;
 use self::restore::RestoreState;
-use crate::backup::{BackupReader, BACKUP_FILE_EXTENSION, CONFIG_FILE_EXTENSION};
+use self::settings::{Settings, ThemeChoice};
+use self::threads::{PreviousBackup, ThreadWrapper};
+use crate::backup::{BackupError, BackupReader, BACKUP_FILE_EXTENSION, CONFIG_FILE_EXTENSIONS};
 use crate::config::Config;
-use crate::utils::{default_dir, get_config_from_path};
+use crate::file_types::FileType;
+use crate::recent::{remember, RecentKind, RecentList};
+use crate::utils::{
+    default_dir, format_count, get_config_from_path, read_config_from_path, ConfigPathType,
+};
 
 mod backup;
 mod config;
@@ -19,6 +29,7 @@ mod merge;
 mod paginated;
 mod presets;
 mod restore;
+mod settings;
 mod theme;
 mod threads;
 
@@ -28,7 +39,46 @@ extern "system" {
     fn FreeConsole() -> i32;
 }
 
-pub fn gui(_hide_terminal: bool) {
+/// The screens that the GUI can start on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Screen {
+    Main,
+    Config,
+    Backup,
+    Merge,
+    Restore,
+}
+
+/// The initial screen of the GUI (with a preloaded path)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum InitialScreen {
+    #[default]
+    Main,
+    Config(Option<PathBuf>),
+    Backup(PathBuf),
+    Merge(PathBuf),
+    Restore(PathBuf),
+}
+
+impl InitialScreen {
+    /// Combine and validate the command line arguments
+    pub fn from_args(screen: Option<Screen>, path: Option<PathBuf>) -> Result<Self, String> {
+        if let Some(p) = &path {
+            ConfigPathType::parse(p).map_err(|e| e.to_string())?;
+        }
+        match (screen.unwrap_or(Screen::Main), path) {
+            (Screen::Main, None) => Ok(Self::Main),
+            (Screen::Main, Some(_)) => Err("The main screen does not use a path".to_string()),
+            (Screen::Config, path) => Ok(Self::Config(path)),
+            (Screen::Backup, Some(path)) => Ok(Self::Backup(path)),
+            (Screen::Merge, Some(path)) => Ok(Self::Merge(path)),
+            (Screen::Restore, Some(path)) => Ok(Self::Restore(path)),
+            (_, None) => Err("A path is required for this screen".to_string()),
+        }
+    }
+}
+
+pub fn gui(_hide_terminal: bool, initial: InitialScreen) {
     #[cfg(all(target_os = "windows", not(debug_assertions)))]
     if _hide_terminal {
         unsafe {
@@ -49,10 +99,26 @@ pub fn gui(_hide_terminal: bool) {
         .theme(theme)
         .window(settings)
         .subscription(subscription)
-        .run()
+        .run_with(move || (Application::new(initial), Task::none()))
         .expect("Failed to run application");
 }
 
+pub(crate) struct Application {
+    state: ApplicationState,
+    settings: Settings,
+}
+
+impl Application {
+    fn new(initial: InitialScreen) -> Self {
+        let settings = Settings::load();
+        Self {
+            state: ApplicationState::from_initial(initial, &settings),
+            settings,
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
 enum ApplicationState {
     Main(MainState),
     Config(ConfigState),
@@ -61,11 +127,38 @@ enum ApplicationState {
     Restore(RestoreState),
 }
 
+/// A stable identifier for a row in a list (unaffected by sorting, filtering, and pagination)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub(crate) struct RowId(u64);
+
+impl RowId {
+    /// Derive the id from the content of the row (such as a path)
+    pub fn of<H: Hash + ?Sized>(value: &H) -> Self {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        Self(hasher.finish())
+    }
+
+    /// The index of the row (for ids assigned from positions at load)
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl From<usize> for RowId {
+    fn from(index: usize) -> Self {
+        Self(index as u64)
+    }
+}
+
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub(crate) enum Message {
     PaneResized(pane_grid::ResizeEvent),
     PaneDragged(pane_grid::DragEvent),
+    PaneHovered(pane_grid::Pane),
+    ResetLayout,
+    FileDropped(PathBuf),
     MainView,
     CreateConfig,
     EditConfig,
@@ -75,36 +168,49 @@ pub(crate) enum Message {
     Incremental(bool),
     ThreadCount(u32),
     CompressionQuality(i32),
-    IncludeAdd(usize),
-    IncludeRemove(usize),
-    IncludeOpen(usize),
-    ExcludeAdd(usize),
-    ExcludeRemove(usize),
-    ExcludeOpen(usize),
+    MaxDepth(String),
+    IncrementalTime(String),
+    NewerThan(String),
+    OlderThan(String),
+    IncludeAdd(RowId),
+    IncludeRemove(RowId),
+    IncludeOpen(RowId),
+    IncludeRequired(RowId),
+    ExcludeAdd(RowId),
+    ExcludeRemove(RowId),
+    ExcludeOpen(RowId),
     FilterAdd,
-    FilterRemove(usize),
-    FilterEdit(usize, String),
-    FolderOpen(usize),
+    FilterRemove(RowId),
+    FilterEdit(RowId, String),
+    FolderOpen(RowId),
     FolderUp,
     FolderDialog,
     Save,
     SortName,
     SortSize,
     SortTime,
+    SortDir,
     GoTo(usize),
     Backup,
     Cancel,
     Export,
     Tick,
-    Toggle(usize),
+    Toggle(RowId),
     ToggleAll,
     Restore,
     Extract,
     Merge,
+    AddBackup,
     Flat(bool),
     All(bool),
+    SelectedOnly(bool),
     Delete(bool),
+    Background(bool),
     Repeat,
+    ThemeSelect(ThemeChoice),
+    TypeAdd(FileType),
+    TypeRemove(FileType),
+    OpenRecent(usize),
     None,
 }
 
@@ -114,8 +220,28 @@ impl Default for ApplicationState {
     }
 }
 
-fn title(state: &ApplicationState) -> String {
-    match state {
+impl ApplicationState {
+    /// Open the initial screen (falling back to the main screen if the path cannot be loaded)
+    fn from_initial(initial: InitialScreen, settings: &Settings) -> Self {
+        let layout = settings.layouts.get(CONFIG_LAYOUT);
+        let state = match initial {
+            InitialScreen::Main => return Self::default(),
+            InitialScreen::Config(None) => {
+                return Self::Config(ConfigState::new(true, true, layout))
+            }
+            InitialScreen::Config(Some(path)) => {
+                ConfigState::from_path(path, layout).map(Self::Config)
+            }
+            InitialScreen::Backup(path) => BackupState::from_path(path).map(Self::Backup),
+            InitialScreen::Merge(path) => MergeState::from_path(path).map(Self::Merge),
+            InitialScreen::Restore(path) => RestoreState::from_path(path).map(Self::Restore),
+        };
+        state.unwrap_or_else(|e| Self::Main(MainState::with_error(e.to_string())))
+    }
+}
+
+fn title(app: &Application) -> String {
+    match &app.state {
         ApplicationState::Main(_) => String::from("simple_backup"),
         ApplicationState::Config(_) => String::from("simple_backup - Config"),
         ApplicationState::Backup(_) => String::from("simple_backup - Backup"),
@@ -124,15 +250,39 @@ fn title(state: &ApplicationState) -> String {
     }
 }
 
-fn update(state: &mut ApplicationState, message: Message) {
+fn update(app: &mut Application, message: Message) {
+    let state = &mut app.state;
+    let layout = app.settings.layouts.get(CONFIG_LAYOUT);
     match message {
-        Message::CreateConfig => *state = ApplicationState::Config(ConfigState::new(true, true)),
+        Message::CreateConfig => {
+            *state = ApplicationState::Config(ConfigState::new(true, true, layout))
+        }
         Message::EditConfig => {
             if let ApplicationState::Backup(state2) = state {
-                *state =
-                    ApplicationState::Config(ConfigState::from(std::mem::take(&mut state2.config)))
-            } else if let Some(config) = open_config() {
-                *state = ApplicationState::Config(ConfigState::from(config))
+                let config = std::mem::take(&mut state2.config);
+                *state = ApplicationState::Config(ConfigState::from(config, layout))
+            } else if let Some(config) = open_config(false) {
+                *state = ApplicationState::Config(ConfigState::from(config, layout))
+            }
+        }
+        Message::PaneResized(_) | Message::PaneDragged(_) | Message::ResetLayout => {
+            if let ApplicationState::Config(state) = state {
+                let reset = matches!(message, Message::ResetLayout);
+                state.update(message);
+                // The layout is stored when it changes (and forgotten when reset)
+                let layout = (!reset).then(|| state.layout());
+                if app.settings.layouts.get(CONFIG_LAYOUT) != layout.as_ref() {
+                    match layout {
+                        Some(layout) => app
+                            .settings
+                            .layouts
+                            .insert(CONFIG_LAYOUT.to_string(), layout),
+                        None => app.settings.layouts.remove(CONFIG_LAYOUT),
+                    };
+                    if let Err(e) = app.settings.save() {
+                        log::error!("Could not save the settings: {}", e);
+                    }
+                }
             }
         }
         Message::BackupView => {
@@ -146,7 +296,7 @@ fn update(state: &mut ApplicationState, message: Message) {
                     config.output = path;
                     *state = ApplicationState::Backup(BackupState::new(config))
                 }
-            } else if let Some(config) = open_config() {
+            } else if let Some(config) = open_config(true) {
                 *state = ApplicationState::Backup(BackupState::new(config))
             };
         }
@@ -156,7 +306,7 @@ fn update(state: &mut ApplicationState, message: Message) {
             }
         }
         Message::None => {
-            eprintln!("Unspecified GUI message");
+            log::warn!("Unspecified GUI message");
         }
         Message::MainView => {
             *state = ApplicationState::Main(MainState::new());
@@ -164,6 +314,32 @@ fn update(state: &mut ApplicationState, message: Message) {
         Message::MergeView => {
             *state = ApplicationState::Merge(MergeState::new());
         }
+        Message::OpenRecent(index) => {
+            if let ApplicationState::Main(main) = state {
+                if let Some(entry) = main.recent.entries.get(index).cloned() {
+                    let opened = match entry.kind {
+                        RecentKind::Config => {
+                            BackupState::from_path(entry.path.clone()).map(ApplicationState::Backup)
+                        }
+                        RecentKind::Backup => RestoreState::from_path(entry.path.clone())
+                            .map(ApplicationState::Restore),
+                    };
+                    *state = match opened {
+                        Ok(opened) => {
+                            remember(entry.kind, &entry.path);
+                            opened
+                        }
+                        Err(e) => ApplicationState::Main(MainState::with_error(e.to_string())),
+                    };
+                }
+            }
+        }
+        Message::ThemeSelect(theme) => {
+            app.settings.theme = theme;
+            if let Err(e) = app.settings.save() {
+                log::error!("Could not save the settings: {}", e);
+            }
+        }
         _ => match state {
             ApplicationState::Main(_) => {}
             ApplicationState::Config(state) => state.update(message),
@@ -174,9 +350,9 @@ fn update(state: &mut ApplicationState, message: Message) {
     }
 }
 
-fn view(state: &ApplicationState) -> Element<Message> {
-    match state {
-        ApplicationState::Main(state) => state.view(),
+fn view(app: &Application) -> Element<'_, Message> {
+    match &app.state {
+        ApplicationState::Main(state) => state.view(&app.settings),
         ApplicationState::Config(state) => state.view(),
         ApplicationState::Backup(state) => state.view(),
         ApplicationState::Merge(state) => state.view(),
@@ -184,27 +360,42 @@ fn view(state: &ApplicationState) -> Element<Message> {
     }
 }
 
-fn open_config() -> Option<Config> {
+/// Ask for a config (with `check` configs with errors are rejected, see [`Config::check`])
+fn open_config(check: bool) -> Option<Config> {
     FileDialog::new()
         .set_directory(default_dir())
         .set_title("Open existing config or backup file")
-        .add_filter("Config and backup files", &[
-            &CONFIG_FILE_EXTENSION[1..],
-            &BACKUP_FILE_EXTENSION[1..],
-        ])
-        .add_filter("Config files", &[&CONFIG_FILE_EXTENSION[1..]])
+        .add_filter(
+            "Config and backup files",
+            &CONFIG_FILE_EXTENSIONS
+                .iter()
+                .chain([&BACKUP_FILE_EXTENSION])
+                .map(|e| &e[1..])
+                .collect::<Vec<_>>(),
+        )
+        .add_filter("Config files", &CONFIG_FILE_EXTENSIONS.map(|e| &e[1..]))
         .add_filter("Backup files", &[&BACKUP_FILE_EXTENSION[1..]])
         .pick_file()
-        .and_then(|file| match get_config_from_path(file) {
-            Ok(config) => Some(config),
-            Err(e) => {
-                MessageDialog::new()
-                    .set_description(e.to_string())
-                    .set_level(rfd::MessageLevel::Error)
-                    .set_buttons(rfd::MessageButtons::Ok)
-                    .set_title("Problem with reading config")
-                    .show();
-                None
+        .and_then(|file| {
+            let config = if check {
+                get_config_from_path(file.clone())
+            } else {
+                read_config_from_path(file.clone())
+            };
+            match config {
+                Ok(config) => {
+                    remember(RecentKind::Config, file);
+                    Some(config)
+                }
+                Err(e) => {
+                    MessageDialog::new()
+                        .set_description(e.to_string())
+                        .set_level(rfd::MessageLevel::Error)
+                        .set_buttons(rfd::MessageButtons::Ok)
+                        .set_title("Problem with reading config")
+                        .show();
+                    None
+                }
             }
         })
 }
@@ -214,11 +405,15 @@ fn open_backup() -> Option<BackupReader> {
         .set_title("Open backup file")
         .add_filter("Backup files", &[&BACKUP_FILE_EXTENSION[1..]])
         .pick_file()
-        .map(BackupReader::new)
+        .map(|file| {
+            remember(RecentKind::Backup, &file);
+            BackupReader::new(file)
+        })
 }
 
-fn subscription(state: &ApplicationState) -> iced::Subscription<Message> {
-    match state {
+fn subscription(app: &Application) -> iced::Subscription<Message> {
+    match &app.state {
+        ApplicationState::Config(state) => state.subscription(),
         ApplicationState::Backup(state) => state.subscription(),
         ApplicationState::Merge(state) => state.subscription(),
         ApplicationState::Restore(state) => state.subscription(),
@@ -226,24 +421,129 @@ fn subscription(state: &ApplicationState) -> iced::Subscription<Message> {
     }
 }
 
-struct MainState {}
+/// The previous backup of a config (looked up in the background when the config is opened)
+pub(crate) enum LastBackup {
+    Loading(ThreadWrapper<(), Result<Option<PreviousBackup>, BackupError>>),
+    Found(PreviousBackup),
+    Missing,
+    Failed(String),
+}
+
+impl LastBackup {
+    pub fn lookup(config: &Config) -> Self {
+        Self::Loading(ThreadWrapper::previous_backup(config.clone()))
+    }
+
+    /// Check if the lookup has finished (called on every tick)
+    pub fn poll(&mut self) {
+        if matches!(self, Self::Loading(wrapper) if wrapper.is_finished()) {
+            if let Self::Loading(wrapper) = std::mem::replace(self, Self::Missing) {
+                *self = match wrapper.join_previous() {
+                    Ok(Some(previous)) => Self::Found(previous),
+                    Ok(None) => Self::Missing,
+                    Err(e) => {
+                        log::warn!("Could not read the previous backup: {}", e);
+                        Self::Failed(e.to_string())
+                    }
+                };
+            }
+        }
+    }
+
+    pub fn subscription(&self) -> Subscription<Message> {
+        match self {
+            Self::Loading(_) => {
+                iced::time::every(std::time::Duration::from_millis(200)).map(|_| Message::Tick)
+            }
+            _ => Subscription::none(),
+        }
+    }
+
+    /// The time and size of the previous backup (or a warning if it cannot be read)
+    pub fn view(&self) -> Element<'_, Message> {
+        match self {
+            Self::Loading(_) => presets::text_vcenter("Last backup: ..."),
+            Self::Found((time, files)) => presets::text_vcenter(format!(
+                "Last backup: {} ({} files)",
+                time.map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_else(|| String::from("unknown time")),
+                format_count(*files as u64)
+            )),
+            Self::Missing => presets::text_vcenter("No previous backup"),
+            Self::Failed(e) => presets::tooltip_left(
+                presets::text_error("! Last backup").into(),
+                format!("Could not read the previous backup: {}", e),
+            )
+            .into(),
+        }
+    }
+}
+
+struct MainState {
+    error: Option<String>,
+    /// The recently opened configs and backups (that still exist)
+    recent: RecentList,
+}
 
 impl MainState {
     fn new() -> Self {
-        Self {}
+        Self {
+            error: None,
+            recent: RecentList::load(),
+        }
+    }
+
+    fn with_error(error: String) -> Self {
+        Self {
+            error: Some(error),
+            recent: RecentList::load(),
+        }
     }
 
-    fn view(&self) -> Element<Message> {
+    /// Buttons for opening the recent configs (for backups) and backups (for restoring)
+    fn view_recent(&self) -> Element<'_, Message> {
+        if self.recent.entries.is_empty() {
+            return Space::with_height(Length::Shrink).into();
+        }
+        let mut list = presets::column_list().push(presets::text("Recent:"));
+        for (i, entry) in self.recent.entries.iter().enumerate() {
+            let action = match entry.kind {
+                RecentKind::Config => "Backup",
+                RecentKind::Backup => "Restore",
+            };
+            let button = presets::button_recent(
+                format!("{}: {}", action, entry.name()),
+                Message::OpenRecent(i),
+            );
+            list = list.push(presets::tooltip_right(button, entry.path.to_string_lossy()));
+        }
+        list.into()
+    }
+
+    fn view(&self, settings: &Settings) -> Element<'_, Message> {
         let column = presets::column_main(column![
             Space::with_height(Length::Fill),
             presets::text_title("simple_backup"),
-            Space::with_height(Length::Shrink),
+            match &self.error {
+                Some(e) => presets::text_error(e).into(),
+                None => Element::from(Space::with_height(Length::Shrink)),
+            },
             presets::button_main("Create", false, Message::CreateConfig),
             presets::button_main("Edit", false, Message::EditConfig),
             presets::button_main("Backup", false, Message::BackupView),
             presets::button_main("Merge", true, Message::MergeView),
             presets::button_main("Restore", true, Message::RestoreView),
+            self.view_recent(),
             Space::with_height(Length::Fill),
+            presets::row_bar(vec![
+                presets::text("Theme:").into(),
+                presets::pick_list(
+                    &ThemeChoice::ALL,
+                    Some(settings.theme),
+                    Message::ThemeSelect
+                ),
+            ]),
+            Space::with_height(Length::Shrink),
         ]);
         row![
             Space::with_width(Length::Fill),
@@ -253,3 +553,64 @@ impl MainState {
         .into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    use super::{ApplicationState, InitialScreen, Screen, Settings};
+
+    #[test]
+    fn initial_screen() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_path_buf();
+        assert_eq!(
+            InitialScreen::from_args(None, None),
+            Ok(InitialScreen::Main)
+        );
+        assert_eq!(
+            InitialScreen::from_args(Some(Screen::Config), None),
+            Ok(InitialScreen::Config(None))
+        );
+        assert_eq!(
+            InitialScreen::from_args(Some(Screen::Merge), Some(path.clone())),
+            Ok(InitialScreen::Merge(path.clone()))
+        );
+        assert!(InitialScreen::from_args(Some(Screen::Restore), None).is_err());
+        assert!(InitialScreen::from_args(Some(Screen::Main), Some(path.clone())).is_err());
+        assert!(
+            InitialScreen::from_args(Some(Screen::Backup), Some(path.join("missing.yml"))).is_err()
+        );
+    }
+
+    #[test]
+    fn initial_screen_fallback() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config.yml");
+        File::create(&path)
+            .unwrap()
+            .write_all(b"not: [a config")
+            .unwrap();
+        match ApplicationState::from_initial(
+            InitialScreen::Config(Some(path.clone())),
+            &Settings::default(),
+        ) {
+            ApplicationState::Main(state) => assert!(state.error.is_some()),
+            _ => panic!("Expected the main screen"),
+        }
+        match ApplicationState::from_initial(
+            InitialScreen::Merge(dir.path().to_path_buf()),
+            &Settings::default(),
+        ) {
+            ApplicationState::Main(state) => assert!(state.error.is_some()),
+            _ => panic!("Expected the main screen"),
+        }
+        assert!(matches!(
+            ApplicationState::from_initial(InitialScreen::Config(None), &Settings::default()),
+            ApplicationState::Config(_)
+        ));
+    }
+}
@@ -35,8 +35,8 @@ impl State {
     }
 
     pub fn goto(&mut self, index: usize) {
-        self.index = if index > self.total {
-            self.total - self.length
+        self.index = if index >= self.total {
+            self.total.saturating_sub(self.length)
         } else {
             index
         };
@@ -114,3 +114,23 @@ impl State {
         scroll
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::State;
+
+    #[test]
+    fn goto_short_list() {
+        let mut state = State::new(100, 5);
+        state.goto(usize::MAX);
+        assert_eq!(state.index, 0);
+        state.set_total(250);
+        state.goto(usize::MAX);
+        assert_eq!(state.index, 150);
+        state.goto(100);
+        assert_eq!(state.index, 100);
+        state.set_total(0);
+        state.goto(usize::MAX);
+        assert_eq!(state.index, 0);
+    }
+}
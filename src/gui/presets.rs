@@ -7,8 +7,8 @@ use iced::font::Weight;
 use iced::widget::pane_grid::Pane;
 use iced::widget::text::Fragment;
 use iced::widget::{
-    tooltip, Button, Checkbox, Column, Container, PaneGrid, PickList, ProgressBar, Row, Scrollable,
-    Space, Text, TextInput, Toggler, Tooltip,
+    tooltip, Button, Checkbox, Column, Container, MouseArea, PaneGrid, PickList, ProgressBar, Row,
+    Scrollable, Space, Text, TextInput, Toggler, Tooltip,
 };
 use iced::{Element, Font, Length};
 
@@ -23,7 +23,7 @@ const NAV_BUTTON_WIDTH: f32 = 64.0;
 const MAIN_BUTTON_WIDTH: f32 = 200.0;
 const MAIN_BUTTON_HEIGHT: f32 = 40.0;
 
-pub(crate) fn button(text: &str, action: Message) -> Element<Message> {
+pub(crate) fn button(text: &str, action: Message) -> Element<'_, Message> {
     let label = Text::new(text)
         .align_x(Horizontal::Center)
         .align_y(Vertical::Center);
@@ -34,7 +34,7 @@ pub(crate) fn button(text: &str, action: Message) -> Element<Message> {
         but.on_press(action).into()
     }
 }
-pub(crate) fn button_grey(text: &str, action: Message) -> Button<Message> {
+pub(crate) fn button_grey(text: &str, action: Message) -> Button<'_, Message> {
     let label = Text::new(text)
         .align_x(Horizontal::Center)
         .align_y(Vertical::Center);
@@ -46,7 +46,7 @@ pub(crate) fn button_grey(text: &str, action: Message) -> Button<Message> {
     }
 }
 
-pub(crate) fn button_group(text: &str, action: Message, selected: bool) -> Button<Message> {
+pub(crate) fn button_group(text: &str, action: Message, selected: bool) -> Button<'_, Message> {
     let label = Text::new(text)
         .align_x(Horizontal::Center)
         .align_y(Vertical::Center);
@@ -63,7 +63,7 @@ pub(crate) fn button_group(text: &str, action: Message, selected: bool) -> Butto
     }
 }
 
-pub(crate) fn button_nav(text: &str, action: Message, forward: bool) -> Element<Message> {
+pub(crate) fn button_nav(text: &str, action: Message, forward: bool) -> Element<'_, Message> {
     let label = Text::new(text)
         .width(Length::Fixed(NAV_BUTTON_WIDTH))
         .align_x(Horizontal::Center)
@@ -80,7 +80,7 @@ pub(crate) fn button_nav(text: &str, action: Message, forward: bool) -> Element<
     }
 }
 
-pub(crate) fn button_icon(text: &str, action: Message, negative: bool) -> Element<Message> {
+pub(crate) fn button_icon(text: &str, action: Message, negative: bool) -> Element<'_, Message> {
     let label = Text::new(text)
         .align_x(Horizontal::Center)
         .align_y(Vertical::Center);
@@ -118,7 +118,7 @@ pub(crate) fn space_hfill<'a>() -> Element<'a, Message> {
     Space::with_width(Length::Fill).into()
 }
 
-pub(crate) fn button_main(text: &str, alt: bool, action: Message) -> Element<Message> {
+pub(crate) fn button_main(text: &str, alt: bool, action: Message) -> Element<'_, Message> {
     let label = Text::new(text)
         .align_x(Horizontal::Center)
         .align_y(Vertical::Center);
@@ -137,6 +137,17 @@ pub(crate) fn button_main(text: &str, alt: bool, action: Message) -> Element<Mes
     }
 }
 
+pub(crate) fn button_recent<'a>(text: String, action: Message) -> Element<'a, Message> {
+    let label = Text::new(text)
+        .align_x(Horizontal::Left)
+        .align_y(Vertical::Center);
+    Button::new(label)
+        .width(Length::Fixed(MAIN_BUTTON_WIDTH))
+        .style(theme::button_grey)
+        .on_press(action)
+        .into()
+}
+
 pub(crate) fn row_list<'a>() -> Row<'a, Message> {
     Row::new()
         .width(Length::Fill)
@@ -201,6 +212,13 @@ pub(crate) fn text_error<'a, S: Into<Fragment<'a>>>(text: S) -> Text<'a> {
     Text::new(text.into()).style(iced::widget::text::danger)
 }
 
+pub(crate) fn text_badge<'a, S: Into<Fragment<'a>>>(text: S) -> Element<'a, Message> {
+    Text::new(text.into())
+        .style(iced::widget::text::primary)
+        .align_y(Vertical::Center)
+        .into()
+}
+
 pub(crate) fn text_center<'a, S: Into<Fragment<'a>>>(text: S) -> Element<'a, Message> {
     Text::new(text.into())
         .align_x(Horizontal::Center)
@@ -221,7 +239,7 @@ pub(crate) fn text_center_error<'a, S: Into<Fragment<'a>>>(text: S) -> Element<'
 pub(crate) fn pane_grid<T, F>(
     state: &iced::widget::pane_grid::State<T>,
     view: F,
-) -> PaneGrid<Message>
+) -> PaneGrid<'_, Message>
 where
     F: Fn(Pane, &T, bool) -> iced::widget::pane_grid::Content<Message>,
 {
@@ -258,11 +276,14 @@ pub(crate) fn scroll_pane<'a, S: Into<Cow<'a, str>>>(
     title: S,
     button: Option<(&'a str, Message)>,
     content: Element<'a, Message>,
+    on_hover: Message,
 ) -> iced::widget::pane_grid::Content<'a, Message> {
     pane_border(
         title,
         button,
-        Scrollable::new(content).style(theme::scrollbar).into(),
+        MouseArea::new(Scrollable::new(content).style(theme::scrollbar))
+            .on_enter(on_hover)
+            .into(),
     )
 }
 
@@ -315,19 +336,24 @@ where
     }
 }
 
-pub(crate) fn progress_bar<'a>(current: f32, max: f32) -> ProgressBar<'a> {
-    ProgressBar::new(0.0..=max, current)
-        .width(Length::Fill)
-        .style(theme::progressbar)
+pub(crate) fn number_field<'a, F>(value: &str, placeholder: &str, mess: F) -> Element<'a, Message>
+where
+    F: 'static + Fn(String) -> Message,
+{
+    TextInput::new(placeholder, value)
+        .width(Length::Fixed(NAV_BUTTON_WIDTH))
+        .on_input(mess)
+        .style(theme::input_primary)
+        .into()
 }
 
-pub(crate) fn progress_bar2<'a>(current: usize, max: usize) -> ProgressBar<'a> {
-    ProgressBar::new(0.0..=max as f32, current as f32)
+pub(crate) fn progress_bar<'a>(current: f32, max: f32) -> ProgressBar<'a> {
+    ProgressBar::new(0.0..=max, current)
         .width(Length::Fill)
         .style(theme::progressbar)
 }
 
-pub(crate) fn toggler<F>(state: bool, label: &str, on_change: F) -> Element<Message>
+pub(crate) fn toggler<F>(state: bool, label: &str, on_change: F) -> Element<'_, Message>
 where
     F: 'static + Fn(bool) -> Message,
 {
@@ -336,7 +362,7 @@ where
         .into()
 }
 
-pub(crate) fn toggler_comp<F>(state: bool, label: &str, on_change: F) -> Element<Message>
+pub(crate) fn toggler_comp<F>(state: bool, label: &str, on_change: F) -> Element<'_, Message>
 where
     F: 'static + Fn(bool) -> Message,
 {
@@ -345,7 +371,7 @@ where
         .into()
 }
 
-fn _toggler<F>(state: bool, label: &str, on_change: F) -> Toggler<Message>
+fn _toggler<F>(state: bool, label: &str, on_change: F) -> Toggler<'_, Message>
 where
     F: 'static + Fn(bool) -> Message,
 {
@@ -358,7 +384,7 @@ where
         .size(TOGGLER_SIZE)
 }
 
-pub(crate) fn checkbox<F>(state: bool, label: &str, on_change: F) -> Checkbox<Message>
+pub(crate) fn checkbox<F>(state: bool, label: &str, on_change: F) -> Checkbox<'_, Message>
 where
     F: 'static + Fn(bool) -> Message,
 {
@@ -367,11 +393,27 @@ where
         .style(theme::checkbox_color)
 }
 
+pub(crate) fn pick_list_hint<'a, T, F>(
+    options: &'a [T],
+    placeholder: &str,
+    on_change: F,
+) -> Element<'a, Message>
+where
+    T: ToString + Eq + Clone,
+    F: 'static + Fn(T) -> Message,
+{
+    PickList::new(options, None::<T>, on_change)
+        .placeholder(placeholder)
+        .width(Length::Shrink)
+        .style(theme::dropdown)
+        .into()
+}
+
 pub(crate) fn pick_list<T, F>(
     options: &'_ [T],
     selected: Option<T>,
     on_change: F,
-) -> Element<Message>
+) -> Element<'_, Message>
 where
     T: ToString + Eq + Clone,
     F: 'static + Fn(T) -> Message,
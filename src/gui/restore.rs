@@ -1,14 +1,18 @@
 #![cfg(feature = "gui")]
 
+use std::path::PathBuf;
+
 use iced::widget::Space;
 use iced::{Element, Length, Subscription};
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
 use rfd::FileDialog;
 
 use super::threads::ThreadWrapper;
-use super::{paginated, presets, Message};
-use crate::backup::{BackupError, BackupReader};
+use super::{paginated, presets, Message, RowId};
+use crate::backup::{BackupError, BackupReader, Overwrite};
+use crate::file_types::{selection_for_types, FileType};
 use crate::files::FileInfo;
+use crate::utils::{format_relative, format_size, get_backup_from_path};
 
 pub(crate) enum RestoreStage {
     Failed,
@@ -23,12 +27,24 @@ pub(crate) enum RestoreStage {
 pub(crate) struct RestoreState {
     filter: String,
     filter_ok: bool,
+    types: Vec<FileType>,
     error: String,
     stage: RestoreStage,
     all: bool,
+    /// Only show the selected files
+    selected_only: bool,
     flat: bool,
     pagination: paginated::State,
     extract: bool,
+    visible: Vec<usize>,
+    background: bool,
+    /// The bytes unpacked so far
+    current_size: u64,
+    /// The listed sizes of the restored files (and of all the selected files)
+    restored_size: u64,
+    total_size: u64,
+    /// The path of the backup, for retrying if the reader is lost (when the worker thread crashes)
+    source: PathBuf,
 }
 
 impl RestoreState {
@@ -37,16 +53,30 @@ impl RestoreState {
             error: String::new(),
             stage: RestoreStage::Failed,
             all: true,
+            selected_only: false,
             filter: String::new(),
             filter_ok: true,
+            types: vec![],
             flat: false,
             pagination: paginated::State::new(100, 0),
             extract: false,
+            visible: vec![],
+            background: false,
+            current_size: 0,
+            restored_size: 0,
+            total_size: 0,
+            source: reader.path.clone_path(),
         };
         state.view_list(reader);
         state
     }
 
+    pub fn from_path(path: PathBuf) -> Result<Self, BackupError> {
+        let mut reader = get_backup_from_path(path)?;
+        reader.get_meta()?;
+        Ok(Self::new(reader))
+    }
+
     fn extract_reader(&mut self) -> Option<Box<BackupReader>> {
         self.stage = match std::mem::replace(&mut self.stage, RestoreStage::Failed) {
             RestoreStage::Error(br) => return Some(br),
@@ -68,8 +98,11 @@ impl RestoreState {
             Ok((_, list)) => {
                 let list: Vec<_> = list.iter().map(|(_, s)| (true, String::from(s))).collect();
                 self.pagination.set_total(list.len());
+                self.visible = (0..list.len()).collect();
                 self.all = true;
                 self.stage = RestoreStage::Viewing(Box::new(reader), list);
+                // Keep the filters when the list is viewed again
+                self.filter_list();
             }
         }
     }
@@ -84,6 +117,13 @@ impl RestoreState {
         self.stage = RestoreStage::Error(Box::new(reader))
     }
 
+    /// Show the error of the worker thread (with a new reader, since the old one is lost)
+    fn view_crash(&mut self, error: BackupError) {
+        self.error.push('\n');
+        self.error.push_str(&error.to_string());
+        self.view_error(BackupReader::new(self.source.clone()));
+    }
+
     fn try_view_error(&mut self) {
         if let Some(br) = self.extract_reader() {
             self.view_error(*br);
@@ -102,38 +142,104 @@ impl RestoreState {
         }
     }
 
+    /// The filter is a case insensitive substring, unless it is prefixed with `re:` (for a regex)
+    fn filter_regex(filter: &str) -> Result<Option<Regex>, regex::Error> {
+        if filter.is_empty() {
+            Ok(None)
+        } else if let Some(re) = filter.strip_prefix("re:") {
+            Regex::new(re).map(Some)
+        } else {
+            RegexBuilder::new(&regex::escape(filter))
+                .case_insensitive(true)
+                .build()
+                .map(Some)
+        }
+    }
+
+    /// The total size of the selected files (zero for lists from old versions without sizes)
+    fn selected_size(reader: &mut BackupReader, list: &[(bool, String)]) -> u64 {
+        reader.get_list().map_or(0, |l| {
+            l.iter_meta()
+                .zip(list)
+                .filter(|(_, (b, _))| *b)
+                .filter_map(|((_, size, _, _), _)| size)
+                .sum()
+        })
+    }
+
+    /// The bytes to unpack: hard links, duplicates, and changed blocks unpack less than the listed
+    /// sizes, so this is the unpacked bytes plus the listed sizes of the files not yet restored
+    fn estimated_size(&self) -> u64 {
+        self.current_size + self.total_size.saturating_sub(self.restored_size)
+    }
+
+    /// The progress in bytes (or in files for lists from old versions without sizes)
+    fn progress(&self) -> iced::widget::ProgressBar<'_> {
+        if self.total_size > 0 {
+            presets::progress_bar(self.current_size as f32, self.estimated_size() as f32)
+        } else {
+            presets::progress_bar(
+                self.pagination.index as f32,
+                self.pagination.get_total() as f32,
+            )
+        }
+    }
+
+    fn is_filtered(&self) -> bool {
+        !self.filter.is_empty() || !self.types.is_empty() || self.selected_only
+    }
+
+    /// The select-all checkbox is checked if all visible files are selected
+    fn update_all(&mut self) {
+        if let RestoreStage::Viewing(_, list) = &self.stage {
+            self.all = !self.visible.is_empty() && self.visible.iter().all(|i| list[*i].0);
+        }
+    }
+
     fn filter_list(&mut self) {
-        if let RestoreStage::Viewing(_, list) = &mut self.stage {
-            let mut total = 0;
-            let mut changed = false;
-            if !self.filter.is_empty() {
-                match Regex::new(&self.filter) {
-                    Ok(regex) => {
-                        self.filter_ok = true;
-                        for i in 0..list.len() {
-                            if regex.is_match(&list[i].1) {
-                                if total != i {
-                                    changed = true;
-                                    list.swap(total, i);
-                                }
-                                total += 1;
-                            }
-                        }
-                    }
-                    Err(_) => {
-                        self.filter_ok = false;
-                        return;
-                    }
+        if let RestoreStage::Viewing(_, list) = &self.stage {
+            // The list keeps the original order, so that the row ids (indices) remain stable
+            let regex = match Self::filter_regex(&self.filter) {
+                Ok(regex) => regex,
+                Err(_) => {
+                    self.filter_ok = false;
+                    return;
                 }
+            };
+            let types = if self.types.is_empty() {
+                None
             } else {
-                self.filter_ok = true;
-                total = list.len();
+                Some(selection_for_types(&self.types))
+            };
+            let visible: Vec<usize> = list
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, s))| regex.as_ref().is_none_or(|r| r.is_match(s)))
+                .filter(|(_, (_, s))| types.as_ref().is_none_or(|t| t.is_match(s)))
+                .filter(|(_, (b, _))| !self.selected_only || *b)
+                .map(|(i, _)| i)
+                .collect();
+            self.filter_ok = true;
+            if visible != self.visible {
+                self.pagination.set_total(visible.len());
+                self.visible = visible;
             }
-            if changed || self.pagination.get_total() != total {
-                self.all = false;
-                list[..total].sort_unstable_by(|(_, s1), (_, s2)| s1.cmp(s2));
-                self.pagination.set_total(total);
+            self.update_all();
+        }
+    }
+
+    /// "N of M files selected (filtered: K shown)"
+    fn status(&self) -> String {
+        match &self.stage {
+            RestoreStage::Viewing(_, list) => {
+                let selected = list.iter().filter(|(b, _)| *b).count();
+                let mut status = format!("{} of {} files selected", selected, list.len());
+                if self.is_filtered() {
+                    status.push_str(&format!(" (filtered: {} shown)", self.visible.len()));
+                }
+                status
             }
+            _ => String::new(),
         }
     }
 
@@ -141,11 +247,13 @@ impl RestoreState {
         match message {
             Message::Tick => match &mut self.stage {
                 RestoreStage::Performing(wrapper) => {
+                    self.current_size = wrapper.bytes();
                     for recv in wrapper {
                         match recv {
                             Ok(res) => match res {
-                                Ok(_) => {
+                                Ok(fi) => {
                                     self.pagination.index += 1;
+                                    self.restored_size += fi.size;
                                 }
                                 Err(e) => {
                                     self.error.push('\n');
@@ -164,11 +272,14 @@ impl RestoreState {
                                             Ok(br) => {
                                                 self.stage = RestoreStage::Completed(Box::new(br))
                                             }
-                                            Err(_) => self.error.push_str(if self.extract {
-                                                "\nFailure when finalising the extraction"
-                                            } else {
-                                                "\nFailure when finalising the restoration"
-                                            }),
+                                            Err(e) => {
+                                                self.error.push_str(if self.extract {
+                                                    "\nFailure when finalising the extraction:"
+                                                } else {
+                                                    "\nFailure when finalising the restoration:"
+                                                });
+                                                self.view_crash(e);
+                                            }
                                         }
                                     }
                                     break;
@@ -177,6 +288,7 @@ impl RestoreState {
                         }
                     }
                 }
+                #[allow(clippy::collapsible_match)]
                 RestoreStage::Cancelling(wrapper) => {
                     if wrapper.try_cancel() {
                         if let RestoreStage::Cancelling(wrapper) =
@@ -186,11 +298,14 @@ impl RestoreState {
                                 Ok(reader) => {
                                     self.stage = RestoreStage::Cancelled(Box::new(reader))
                                 }
-                                Err(_) => self.error.push_str(if self.extract {
-                                    "\nFailure when cancelling the extraction"
-                                } else {
-                                    "\nFailure when cancelling the restoration"
-                                }),
+                                Err(e) => {
+                                    self.error.push_str(if self.extract {
+                                        "\nFailure when cancelling the extraction:"
+                                    } else {
+                                        "\nFailure when cancelling the restoration:"
+                                    });
+                                    self.view_crash(e);
+                                }
                             };
                         }
                     }
@@ -210,10 +325,14 @@ impl RestoreState {
                         .set_title("Select directory to extract to")
                         .pick_folder()
                     {
-                        if let RestoreStage::Viewing(reader, list) =
+                        if let RestoreStage::Viewing(mut reader, list) =
                             std::mem::replace(&mut self.stage, RestoreStage::Failed)
                         {
+                            self.pagination.set_total(list.len());
                             self.extract = true;
+                            self.current_size = 0;
+                            self.restored_size = 0;
+                            self.total_size = Self::selected_size(&mut reader, &list);
                             self.stage = match ThreadWrapper::restore_files(
                                 *reader,
                                 list.into_iter()
@@ -221,7 +340,9 @@ impl RestoreState {
                                     .collect(),
                                 self.flat,
                                 Some(output),
+                                Overwrite::Always,
                                 1000,
+                                self.background,
                             ) {
                                 Ok(w) => RestoreStage::Performing(w),
                                 Err((br, e)) => {
@@ -236,11 +357,14 @@ impl RestoreState {
             }
             Message::Restore => {
                 if let RestoreStage::Viewing(..) = &self.stage {
-                    if let RestoreStage::Viewing(reader, list) =
+                    if let RestoreStage::Viewing(mut reader, list) =
                         std::mem::replace(&mut self.stage, RestoreStage::Failed)
                     {
                         self.pagination.set_total(list.len());
                         self.extract = false;
+                        self.current_size = 0;
+                        self.restored_size = 0;
+                        self.total_size = Self::selected_size(&mut reader, &list);
                         self.stage = match ThreadWrapper::restore_files(
                             *reader,
                             list.into_iter()
@@ -248,7 +372,9 @@ impl RestoreState {
                                 .collect(),
                             false,
                             None,
+                            Overwrite::Always,
                             1000,
+                            self.background,
                         ) {
                             Ok(w) => RestoreStage::Performing(w),
                             Err((br, e)) => {
@@ -269,15 +395,26 @@ impl RestoreState {
                     }
                 }
             }
-            Message::Toggle(i) => {
+            Message::Toggle(id) => {
                 if let RestoreStage::Viewing(_, list) = &mut self.stage {
-                    if let Some((b, _)) = list.get_mut(i) {
+                    if let Some((b, _)) = list.get_mut(id.index()) {
                         *b = !*b;
                     }
-                    self.all = false;
+                    // Deselected files remain visible (with `selected_only`) until the filter changes
+                    self.update_all();
                 }
             }
+            Message::SelectedOnly(b) => {
+                self.selected_only = b;
+                self.filter_list();
+            }
             Message::Flat(b) => self.flat = b,
+            Message::Background(b) => {
+                self.background = b;
+                if let RestoreStage::Performing(wrapper) = &self.stage {
+                    wrapper.set_background(b);
+                }
+            }
             Message::Export => {
                 if let RestoreStage::Viewing(reader, _) = &mut self.stage {
                     if let Some(file) = FileDialog::new()
@@ -300,15 +437,25 @@ impl RestoreState {
             Message::ToggleAll => {
                 if let RestoreStage::Viewing(_, list) = &mut self.stage {
                     self.all = !self.all;
-                    list[..self.pagination.get_total()]
-                        .iter_mut()
-                        .for_each(|(b, _)| *b = self.all);
+                    for i in self.visible.iter() {
+                        list[*i].0 = self.all;
+                    }
                 }
             }
             Message::FilterEdit(_, s) => {
                 self.filter = s;
                 self.filter_list();
             }
+            Message::TypeAdd(t) => {
+                if !self.types.contains(&t) {
+                    self.types.push(t);
+                    self.filter_list();
+                }
+            }
+            Message::TypeRemove(t) => {
+                self.types.retain(|t2| *t2 != t);
+                self.filter_list();
+            }
             Message::GoTo(index) => {
                 if let RestoreStage::Viewing(_, _) = &mut self.stage {
                     self.pagination.goto(index)
@@ -318,55 +465,87 @@ impl RestoreState {
                 self.error.clear();
                 self.try_view_list();
             }
-            _ => eprintln!("Unexpected GUI message: {:?}", message),
+            _ => log::warn!("Unexpected GUI message: {:?}", message),
         }
     }
 
-    pub fn view(&self) -> Element<Message> {
+    pub fn view(&self) -> Element<'_, Message> {
         let mut scroll = presets::column_list();
         if !self.error.is_empty() {
             scroll = scroll.push(presets::text_error(&self.error[1..]))
         }
         match &self.stage {
             RestoreStage::Viewing(reader, list) => {
-                scroll =
-                    self.pagination
-                        .push_to(scroll, list.iter().enumerate(), |(i, (sel, file))| {
-                            presets::checkbox(*sel, file, move |_| Message::Toggle(i))
-                                .width(Length::Fill)
-                                .into()
-                        });
-                let trow = presets::row_list2(vec![
+                scroll = self.pagination.push_to(
+                    scroll,
+                    self.visible.iter().map(|i| (*i, &list[*i])),
+                    |(i, (sel, file))| {
+                        presets::checkbox(*sel, file, move |_| Message::Toggle(RowId::from(i)))
+                            .width(Length::Fill)
+                            .into()
+                    },
+                );
+                let mut trow = vec![
                     presets::space_inner(),
                     presets::checkbox(self.all, "", |_| Message::ToggleAll).into(),
                     presets::space_large(),
-                    presets::regex_field(&self.filter, "Search", self.filter_ok, |s| {
-                        Message::FilterEdit(0, s)
-                    })
+                    presets::regex_field(
+                        &self.filter,
+                        "Search (re: for regex)",
+                        self.filter_ok,
+                        |s| Message::FilterEdit(RowId::default(), s),
+                    )
                     .width(Length::Fill)
                     .on_submit(Message::FilterAdd)
                     .into(),
-                ]);
-                let status = match reader
+                ];
+                trow.push(presets::toggler(
+                    self.selected_only,
+                    "Selected only",
+                    Message::SelectedOnly,
+                ));
+                for t in self.types.iter() {
+                    trow.push(
+                        presets::tooltip_left(
+                            presets::button_grey(t.name(), Message::TypeRemove(*t)).into(),
+                            "Click to remove",
+                        )
+                        .into(),
+                    );
+                }
+                trow.push(presets::pick_list_hint(
+                    &FileType::ALL,
+                    "File type",
+                    Message::TypeAdd,
+                ));
+                let trow = presets::row_list2(trow);
+                let mut status = self.status();
+                let time = reader
                     .config
                     .as_ref()
                     .expect("The config should already be read at this point!")
-                    .time
-                {
-                    Some(t) => format!(
-                        "{} files from {}",
-                        list.len(),
-                        t.format("%Y-%m-%d %H:%M:%S")
-                    ),
-                    None => format!("{} files", list.len(),),
+                    .time;
+                // The exact time is shown on hover
+                let status = match time {
+                    Some(t) => {
+                        status.push_str(&format!(", from {}", format_relative(t)));
+                        presets::tooltip_left(
+                            presets::text_center(status),
+                            t.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        )
+                        .into()
+                    }
+                    None => presets::text_center(status),
                 };
                 let brow = presets::row_bar(vec![
                     presets::button_nav("Back", Message::MainView, false),
-                    presets::text_center(status),
+                    status,
                     presets::button("Export list", Message::Export),
                     presets::space_large(),
                     presets::toggler(self.flat, "Flat", Message::Flat),
                     presets::space_large(),
+                    presets::toggler(self.background, "Background mode", Message::Background),
+                    presets::space_large(),
                     presets::button("Extract", Message::Extract),
                     presets::button("Restore", Message::Restore),
                 ]);
@@ -389,21 +568,28 @@ impl RestoreState {
             RestoreStage::Performing(_) => {
                 let brow = presets::row_bar(vec![
                     presets::button_nav("Cancel", Message::Cancel, false),
-                    presets::text_center(if self.extract {
-                        format!(
-                            "Extracting files: {} / {}",
-                            self.pagination.index,
-                            self.pagination.get_total(),
-                        )
-                    } else {
-                        format!(
-                            "Restoring files: {} / {}",
-                            self.pagination.index,
-                            self.pagination.get_total(),
-                        )
-                    }),
+                    presets::text_center(format!(
+                        "{} files: {} / {}{}",
+                        if self.extract {
+                            "Extracting"
+                        } else {
+                            "Restoring"
+                        },
+                        self.pagination.index,
+                        self.pagination.get_total(),
+                        if self.total_size > 0 {
+                            format!(
+                                " ({} of {})",
+                                format_size(self.current_size),
+                                format_size(self.estimated_size())
+                            )
+                        } else {
+                            String::new()
+                        },
+                    )),
+                    presets::toggler(self.background, "Background mode", Message::Background),
                 ]);
-                let pb = presets::progress_bar2(self.pagination.index, self.pagination.get_total());
+                let pb = self.progress();
                 let scroll = presets::scroll_border(scroll.into());
                 presets::column_root(vec![scroll, pb.into(), brow.into()]).into()
             }
@@ -416,7 +602,7 @@ impl RestoreState {
                         presets::text_center_error("Cancelling the restoration")
                     },
                 ]);
-                let pb = presets::progress_bar2(self.pagination.index, self.pagination.get_total());
+                let pb = self.progress();
                 let scroll = presets::scroll_border(scroll.into());
                 presets::column_root(vec![scroll, pb.into(), brow.into()]).into()
             }
@@ -462,3 +648,219 @@ impl RestoreState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use super::{RestoreStage, RestoreState};
+    use crate::backup::{BackupReader, BackupWriter};
+    use crate::config::Config;
+    use crate::file_types::FileType;
+    use crate::gui::{Message, RowId};
+
+    fn create_backup(dir: &Path, output: &Path, files: usize) -> BackupReader {
+        for i in 0..files {
+            File::create(dir.join(format!("{:03}.txt", i))).unwrap();
+        }
+        let mut config = Config::new();
+        config.include.push(dir.to_string_lossy().to_string());
+        config.output = output.join("backup.tar.zst");
+        config.incremental = false;
+        config.quality = 1;
+        config.threads = 1;
+        let mut bw = BackupWriter::new(config).0;
+        bw.write(|_, _| Ok(()), |_| (), || ()).unwrap();
+        BackupReader::new(bw.path)
+    }
+
+    fn selected(state: &RestoreState) -> Vec<String> {
+        match &state.stage {
+            RestoreStage::Viewing(_, list) => list
+                .iter()
+                .filter_map(|(b, s)| if *b { Some(s.clone()) } else { None })
+                .collect(),
+            _ => panic!("The state should be viewing the list"),
+        }
+    }
+
+    #[test]
+    fn toggle_after_filter() {
+        let dir = tempdir().unwrap();
+        let out = tempdir().unwrap();
+        let mut state = RestoreState::new(create_backup(dir.path(), out.path(), 5));
+        assert_eq!(selected(&state).len(), 5);
+
+        state.update(Message::FilterEdit(
+            RowId::default(),
+            "re:00[12]\\.txt$".to_string(),
+        ));
+        assert_eq!(state.pagination.get_total(), 2);
+        let id = RowId::from(state.visible[1]);
+        state.update(Message::Toggle(id));
+        state.update(Message::FilterEdit(RowId::default(), String::new()));
+        assert_eq!(state.pagination.get_total(), 5);
+
+        let sel = selected(&state);
+        assert_eq!(sel.len(), 4);
+        assert!(sel.iter().all(|s| !s.ends_with("002.txt")));
+        assert!(sel.is_sorted());
+
+        state.update(Message::FilterEdit(
+            RowId::default(),
+            "re:00[34]\\.txt$".to_string(),
+        ));
+        // Both visible files are selected, so the checkbox deselects them
+        state.update(Message::ToggleAll);
+        let sel = selected(&state);
+        assert_eq!(sel.len(), 2);
+        assert!(sel[0].ends_with("000.txt"));
+        assert!(sel[1].ends_with("001.txt"));
+    }
+
+    #[test]
+    fn pagination_with_filter() {
+        let dir = tempdir().unwrap();
+        let out = tempdir().unwrap();
+        let mut state = RestoreState::new(create_backup(dir.path(), out.path(), 250));
+        state.update(Message::GoTo(100));
+        assert_eq!(state.pagination.index, 100);
+
+        state.update(Message::FilterEdit(
+            RowId::default(),
+            "re:00[0-4]\\.txt$".to_string(),
+        ));
+        assert_eq!(state.pagination.get_total(), 5);
+        assert_eq!(state.pagination.index, 0);
+        state.update(Message::GoTo(usize::MAX));
+        assert_eq!(state.pagination.index, 0);
+
+        state.update(Message::FilterEdit(RowId::default(), String::new()));
+        assert_eq!(state.pagination.get_total(), 250);
+        state.update(Message::GoTo(usize::MAX));
+        assert_eq!(state.pagination.index, 150);
+    }
+
+    #[test]
+    fn type_with_filter() {
+        let dir = tempdir().unwrap();
+        let out = tempdir().unwrap();
+        File::create(dir.path().join("001.jpg")).unwrap();
+        File::create(dir.path().join("002.PNG")).unwrap();
+        let mut state = RestoreState::new(create_backup(dir.path(), out.path(), 3));
+        assert_eq!(state.pagination.get_total(), 5);
+
+        state.update(Message::TypeAdd(FileType::Photos));
+        assert_eq!(state.pagination.get_total(), 2);
+        state.update(Message::FilterEdit(RowId::default(), "001".to_string()));
+        assert_eq!(state.pagination.get_total(), 1);
+        state.update(Message::TypeAdd(FileType::Documents));
+        assert_eq!(state.types.len(), 2);
+        assert_eq!(state.pagination.get_total(), 2);
+        state.update(Message::TypeRemove(FileType::Photos));
+        state.update(Message::TypeRemove(FileType::Documents));
+        assert_eq!(state.pagination.get_total(), 2);
+        state.update(Message::FilterEdit(RowId::default(), String::new()));
+        assert_eq!(state.pagination.get_total(), 5);
+    }
+
+    #[test]
+    fn substring_filter() {
+        let dir = tempdir().unwrap();
+        let out = tempdir().unwrap();
+        File::create(dir.path().join("C++ projects.md")).unwrap();
+        let mut state = RestoreState::new(create_backup(dir.path(), out.path(), 3));
+        assert_eq!(state.pagination.get_total(), 4);
+
+        state.update(Message::FilterEdit(RowId::default(), "c++ Pro".to_string()));
+        assert!(state.filter_ok);
+        assert_eq!(state.pagination.get_total(), 1);
+        state.update(Message::FilterEdit(
+            RowId::default(),
+            "re:(C++ pro".to_string(),
+        ));
+        assert!(!state.filter_ok);
+        assert_eq!(state.pagination.get_total(), 1);
+        state.update(Message::FilterEdit(
+            RowId::default(),
+            "re:\\d\\.txt$".to_string(),
+        ));
+        assert!(state.filter_ok);
+        assert_eq!(state.pagination.get_total(), 3);
+        assert_eq!(state.status(), "4 of 4 files selected (filtered: 3 shown)");
+    }
+
+    #[test]
+    fn selected_only() {
+        let dir = tempdir().unwrap();
+        let out = tempdir().unwrap();
+        let mut state = RestoreState::new(create_backup(dir.path(), out.path(), 5));
+        assert_eq!(state.status(), "5 of 5 files selected");
+        state.update(Message::Toggle(RowId::from(1)));
+        state.update(Message::Toggle(RowId::from(3)));
+        assert!(!state.all);
+
+        state.update(Message::SelectedOnly(true));
+        assert_eq!(state.visible, vec![0, 2, 4]);
+        assert!(state.all);
+        assert_eq!(state.status(), "3 of 5 files selected (filtered: 3 shown)");
+        // Deselected files remain visible until the filter changes
+        state.update(Message::Toggle(RowId::from(2)));
+        assert_eq!(state.visible, vec![0, 2, 4]);
+        assert!(!state.all);
+        state.update(Message::FilterEdit(RowId::default(), "0".to_string()));
+        assert_eq!(state.visible, vec![0, 4]);
+        assert!(state.all);
+
+        state.update(Message::SelectedOnly(false));
+        state.update(Message::FilterEdit(RowId::default(), String::new()));
+        assert_eq!(state.visible, vec![0, 1, 2, 3, 4]);
+        assert!(!state.all);
+        let sel = selected(&state);
+        assert_eq!(sel.len(), 2);
+        assert!(sel[0].ends_with("000.txt"));
+        assert!(sel[1].ends_with("004.txt"));
+    }
+
+    #[test]
+    fn toggle_all_with_filter() {
+        let dir = tempdir().unwrap();
+        let out = tempdir().unwrap();
+        let mut state = RestoreState::new(create_backup(dir.path(), out.path(), 5));
+        state.update(Message::FilterEdit(
+            RowId::default(),
+            "re:00[01]".to_string(),
+        ));
+        assert!(state.all);
+        state.update(Message::ToggleAll);
+        assert!(!state.all);
+        assert_eq!(selected(&state).len(), 3);
+
+        // The checkbox follows the visible files
+        state.update(Message::FilterEdit(
+            RowId::default(),
+            "re:00[12]".to_string(),
+        ));
+        assert!(!state.all);
+        state.update(Message::ToggleAll);
+        assert!(state.all);
+        assert_eq!(selected(&state).len(), 4);
+        state.update(Message::FilterEdit(
+            RowId::default(),
+            "re:00[34]".to_string(),
+        ));
+        assert!(state.all);
+        state.update(Message::FilterEdit(RowId::default(), "nothing".to_string()));
+        assert!(!state.all);
+        state.update(Message::ToggleAll);
+        assert_eq!(selected(&state).len(), 4);
+
+        state.update(Message::FilterEdit(RowId::default(), String::new()));
+        assert!(!state.all);
+        state.update(Message::Toggle(RowId::from(0)));
+        assert!(state.all);
+    }
+}
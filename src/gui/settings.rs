@@ -0,0 +1,256 @@
+#![cfg(feature = "gui")]
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{create_dir_all, File};
+use std::path::PathBuf;
+
+use iced::widget::pane_grid::{self, Axis, Configuration, Node};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::settings_dir;
+
+const SETTINGS_FILE_NAME: &str = "settings.yml";
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ThemeChoice {
+    /// Follow the preference of the operating system (detected when the GUI starts)
+    #[default]
+    System,
+    Light,
+    Dark,
+}
+
+impl ThemeChoice {
+    pub const ALL: [ThemeChoice; 3] = [ThemeChoice::System, ThemeChoice::Light, ThemeChoice::Dark];
+}
+
+impl Display for ThemeChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThemeChoice::System => write!(f, "System"),
+            ThemeChoice::Light => write!(f, "Light"),
+            ThemeChoice::Dark => write!(f, "Dark"),
+        }
+    }
+}
+
+/// The direction of a split in a [`PaneLayout`]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LayoutAxis {
+    Horizontal,
+    Vertical,
+}
+
+impl From<Axis> for LayoutAxis {
+    fn from(axis: Axis) -> Self {
+        match axis {
+            Axis::Horizontal => LayoutAxis::Horizontal,
+            Axis::Vertical => LayoutAxis::Vertical,
+        }
+    }
+}
+
+impl From<LayoutAxis> for Axis {
+    fn from(axis: LayoutAxis) -> Self {
+        match axis {
+            LayoutAxis::Horizontal => Axis::Horizontal,
+            LayoutAxis::Vertical => Axis::Vertical,
+        }
+    }
+}
+
+/// The arrangement of a pane grid that can be stored in the settings (the panes are named)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PaneLayout {
+    Pane(String),
+    Split {
+        axis: LayoutAxis,
+        ratio: f32,
+        a: Box<PaneLayout>,
+        b: Box<PaneLayout>,
+    },
+}
+
+impl PaneLayout {
+    /// Capture the layout of a pane grid (`name` gives the name of each pane)
+    pub fn capture<T>(state: &pane_grid::State<T>, name: impl Fn(&T) -> &str) -> Self {
+        fn capture_node<T>(
+            state: &pane_grid::State<T>,
+            node: &Node,
+            name: &impl Fn(&T) -> &str,
+        ) -> PaneLayout {
+            match node {
+                Node::Split {
+                    axis, ratio, a, b, ..
+                } => PaneLayout::Split {
+                    axis: (*axis).into(),
+                    ratio: *ratio,
+                    a: Box::new(capture_node(state, a, name)),
+                    b: Box::new(capture_node(state, b, name)),
+                },
+                Node::Pane(pane) => {
+                    PaneLayout::Pane(state.get(*pane).map(name).unwrap_or_default().to_string())
+                }
+            }
+        }
+        capture_node(state, state.layout(), &name)
+    }
+
+    /// Rebuild the configuration of a pane grid (`new` creates the pane with the name).
+    /// Layouts that do not have exactly the `names` (e.g. stored by another version), or that
+    /// have invalid ratios, give `None`.
+    pub fn rebuild<T>(
+        &self,
+        names: &[&str],
+        mut new: impl FnMut(&str) -> Option<T>,
+    ) -> Option<Configuration<T>> {
+        let mut panes = self.panes()?;
+        let mut names = names.to_vec();
+        panes.sort_unstable();
+        names.sort_unstable();
+        if panes != names {
+            return None;
+        }
+        self.configuration(&mut new)
+    }
+
+    /// The names of the panes (or `None` if a ratio is invalid)
+    fn panes(&self) -> Option<Vec<&str>> {
+        match self {
+            PaneLayout::Pane(name) => Some(vec![name.as_str()]),
+            PaneLayout::Split { ratio, a, b, .. } => {
+                if !(0.0..=1.0).contains(ratio) {
+                    return None;
+                }
+                let mut panes = a.panes()?;
+                panes.extend(b.panes()?);
+                Some(panes)
+            }
+        }
+    }
+
+    fn configuration<T>(
+        &self,
+        new: &mut impl FnMut(&str) -> Option<T>,
+    ) -> Option<Configuration<T>> {
+        Some(match self {
+            PaneLayout::Pane(name) => Configuration::Pane(new(name)?),
+            PaneLayout::Split { axis, ratio, a, b } => Configuration::Split {
+                axis: (*axis).into(),
+                ratio: *ratio,
+                a: Box::new(a.configuration(new)?),
+                b: Box::new(b.configuration(new)?),
+            },
+        })
+    }
+}
+
+/// Persistent settings for the GUI (stored next to other user configuration)
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub(crate) struct Settings {
+    pub theme: ThemeChoice,
+    /// The pane layouts of the screens (the built-in layouts are used for missing screens)
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub layouts: BTreeMap<String, PaneLayout>,
+}
+
+impl Settings {
+    fn path() -> Option<PathBuf> {
+        settings_dir().map(|p| p.join(SETTINGS_FILE_NAME))
+    }
+
+    /// Read the settings (falling back to the defaults if there are no stored settings)
+    pub fn load() -> Self {
+        Self::path()
+            .and_then(|p| File::open(p).ok())
+            .and_then(|f| serde_yaml::from_reader(f).ok())
+            .unwrap_or_default()
+    }
+
+    /// Store the settings
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        serde_yaml::to_writer(File::create(path)?, self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iced::widget::pane_grid::{self, Axis};
+
+    use super::{LayoutAxis, PaneLayout, Settings, ThemeChoice};
+
+    fn capture(state: &pane_grid::State<&'static str>) -> PaneLayout {
+        PaneLayout::capture(state, |p| p)
+    }
+
+    #[test]
+    fn layout_round_trip() {
+        let (mut state, a) = pane_grid::State::new("a");
+        let (b, split) = state.split(Axis::Vertical, a, "b").unwrap();
+        state.split(Axis::Horizontal, b, "c").unwrap();
+        state.resize(split, 0.3);
+        let layout = capture(&state);
+        match &layout {
+            PaneLayout::Split { axis, ratio, .. } => {
+                assert_eq!(*axis, LayoutAxis::Vertical);
+                assert_eq!(*ratio, 0.3);
+            }
+            PaneLayout::Pane(_) => panic!("Expected a split"),
+        }
+
+        let yaml = serde_yaml::to_string(&layout).unwrap();
+        let stored: PaneLayout = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(stored, layout);
+        let names = ["c", "b", "a"];
+        let config = stored
+            .rebuild(&names, |n| names.iter().find(|m| **m == n).copied())
+            .unwrap();
+        let rebuilt = pane_grid::State::with_configuration(config);
+        assert_eq!(rebuilt.len(), 3);
+        assert_eq!(capture(&rebuilt), layout);
+    }
+
+    #[test]
+    fn layout_fallback() {
+        // Stored by a version without the pane "c"
+        let old = PaneLayout::Split {
+            axis: LayoutAxis::Horizontal,
+            ratio: 0.5,
+            a: Box::new(PaneLayout::Pane("a".to_string())),
+            b: Box::new(PaneLayout::Pane("b".to_string())),
+        };
+        let new = |n: &str| Some(n.to_string());
+        assert!(old.rebuild(&["a", "b", "c"], new).is_none());
+        assert!(old.rebuild(&["a", "b"], new).is_some());
+        // Unknown panes and invalid ratios
+        assert!(old
+            .rebuild(&["a", "b"], |n| (n == "a").then_some(()))
+            .is_none());
+        let mut invalid = old.clone();
+        if let PaneLayout::Split { ratio, .. } = &mut invalid {
+            *ratio = f32::NAN;
+        }
+        assert!(invalid.rebuild(&["a", "b"], new).is_none());
+    }
+
+    #[test]
+    fn theme_default() {
+        // Settings stored before the system theme keep the chosen theme
+        let stored: Settings = serde_yaml::from_str("theme: Dark").unwrap();
+        assert_eq!(stored.theme, ThemeChoice::Dark);
+        let stored: Settings = serde_yaml::from_str("layouts: {}").unwrap();
+        assert_eq!(stored.theme, ThemeChoice::System);
+    }
+}
@@ -7,19 +7,34 @@ use iced::widget::{
 };
 use iced::{border, Border, Color, Shadow, Theme, Vector};
 
-use super::ApplicationState;
+use super::settings::ThemeChoice;
+use super::Application;
 
 const COLOR_APP: Color = Color::from_rgb(78.0 / 255.0, 155.0 / 255.0, 71.0 / 255.0); //#4E9B47
 const COLOR_COMP: Color = Color::from_rgb(148.0 / 255.0, 71.0 / 255.0, 155.0 / 255.0); //#94479b
+/// A lighter [`COLOR_COMP`] so that the error texts are readable on the dark background
+const COLOR_COMP_LIGHT: Color = Color::from_rgb(197.0 / 255.0, 139.0 / 255.0, 203.0 / 255.0); //#C58BCB
 const COLOR_GREY: Color = Color::from_rgb(0.6, 0.6, 0.6);
 const COLOR_LIGHT: Color = Color::from_rgb(0.9, 0.9, 0.9);
+const COLOR_DARK_BG: Color = Color::from_rgb(0.13, 0.13, 0.14);
+const COLOR_DARK_GREY: Color = Color::from_rgb(0.45, 0.45, 0.47);
+const COLOR_DARK: Color = Color::from_rgb(0.25, 0.25, 0.27);
 const RADIUS_SMALL: f32 = 4.0;
 const RADIUS_LARGE: f32 = 8.0;
 const SHADOW_OFFSET: Vector<f32> = Vector::new(1.3, 2.0);
 const BORDER_WIDTH: f32 = 3.0;
 const BORDER_SMALL: f32 = 1.5;
 
-pub fn theme(_state: &ApplicationState) -> Theme {
+pub fn theme(app: &Application) -> Theme {
+    match app.settings.theme {
+        // The default theme of iced follows the system (detected once)
+        ThemeChoice::System if Theme::default().extended_palette().is_dark => theme_dark(),
+        ThemeChoice::System | ThemeChoice::Light => theme_light(),
+        ThemeChoice::Dark => theme_dark(),
+    }
+}
+
+fn theme_light() -> Theme {
     Theme::custom_with_fn(
         "white_green_pruple".to_string(),
         Palette {
@@ -40,6 +55,27 @@ pub fn theme(_state: &ApplicationState) -> Theme {
     )
 }
 
+fn theme_dark() -> Theme {
+    Theme::custom_with_fn(
+        "dark_green_purple".to_string(),
+        Palette {
+            background: COLOR_DARK_BG,
+            text: COLOR_LIGHT,
+            primary: COLOR_DARK,
+            success: COLOR_APP,
+            danger: COLOR_COMP_LIGHT,
+        },
+        |p| Extended {
+            background: Background::new(p.background, p.text),
+            primary: Primary::generate(p.success, p.background, p.text),
+            secondary: Secondary::generate(COLOR_DARK_GREY, p.text),
+            success: Success::generate(p.success, p.background, p.text),
+            danger: Danger::generate(p.danger, p.background, p.text),
+            is_dark: true,
+        },
+    )
+}
+
 pub fn button_normal(theme: &Theme, status: button::Status) -> button::Style {
     let pal = theme.palette();
     let ext = theme.extended_palette();
@@ -186,14 +222,11 @@ pub fn toggle_comp(theme: &Theme, status: toggler::Status) -> toggler::Style {
 fn _toggle(theme: &Theme, status: toggler::Status, color: Color) -> toggler::Style {
     let palette = theme.extended_palette();
     let mut style = toggler::default(theme, status);
-    match status {
-        toggler::Status::Active { is_toggled } | toggler::Status::Hovered { is_toggled } => {
-            if is_toggled {
-                style.background = color;
-                style.foreground = palette.background.base.color;
-            }
-        }
-        _ => {}
+    if let toggler::Status::Active { is_toggled: true }
+    | toggler::Status::Hovered { is_toggled: true } = status
+    {
+        style.background = color;
+        style.foreground = palette.background.base.color;
     }
     style.foreground_border_width = BORDER_SMALL;
     style
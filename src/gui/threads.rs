@@ -1,20 +1,69 @@
 #![cfg(feature = "gui")]
 
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
-use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::Arc;
 use std::thread::JoinHandle;
 
-use crate::backup::{BackupError, BackupMerger, BackupReader, BackupWriter};
+use chrono::NaiveDateTime;
+
+use crate::backup::{BackupError, BackupMerger, BackupReader, BackupWriter, Overwrite};
 use crate::config::Config;
 use crate::files::FileInfo;
-use crate::utils::strip_absolute_from_path;
+use crate::options::RestoreOptions;
+use crate::utils::{strip_absolute_from_path, Background, OsPriority};
 
 pub(crate) struct ThreadWrapper<T1, T2> {
     batch_size: usize,
     batch_mult: usize,
     index: usize,
     queue: Option<Receiver<T1>>,
-    handle: JoinHandle<T2>,
+    handle: JoinHandle<Result<T2, BackupError>>,
+    bytes: Arc<AtomicU64>,
+    background: Arc<AtomicBool>,
+}
+
+/// The message of a panic (the payload is usually a `&str` or a `String`)
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(s) => *s,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(s) => s.to_string(),
+            Err(_) => String::from("Unknown panic"),
+        },
+    }
+}
+
+impl<T1: Send + 'static, T2: Send + 'static> ThreadWrapper<T1, T2> {
+    /// Run the task on a new thread, a panic in the task is turned into a [`BackupError::WorkerPanic`]
+    fn spawn(
+        batch_size: usize,
+        bytes: Arc<AtomicU64>,
+        background: Arc<AtomicBool>,
+        task: impl FnOnce(Sender<T1>) -> T2 + Send + 'static,
+    ) -> Self {
+        let (send, queue) = std::sync::mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            std::panic::catch_unwind(AssertUnwindSafe(|| task(send))).map_err(|payload| {
+                let msg = panic_message(payload);
+                log::error!("The worker thread panicked: {}", msg);
+                BackupError::WorkerPanic(msg)
+            })
+        });
+        Self {
+            batch_size,
+            batch_mult: 1,
+            index: 0,
+            queue: Some(queue),
+            handle,
+            bytes,
+            background,
+        }
+    }
 }
 
 impl<T1, T2> ThreadWrapper<T1, T2> {
@@ -31,118 +80,155 @@ impl<T1, T2> ThreadWrapper<T1, T2> {
         self.handle.is_finished()
     }
 
-    pub fn cancel(self) -> std::thread::Result<T2> {
-        std::mem::drop(self.queue);
-        self.handle.join()
+    pub fn cancel(mut self) -> Result<T2, BackupError> {
+        std::mem::drop(self.queue.take());
+        self.join()
+    }
+
+    pub fn join(self) -> Result<T2, BackupError> {
+        self.handle
+            .join()
+            .unwrap_or_else(|payload| Err(BackupError::WorkerPanic(panic_message(payload))))
+    }
+
+    /// Has the thread finished (so that [`Self::join`] does not block)
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// The number of bytes processed so far (if the thread reports it)
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
     }
 
-    pub fn join(self) -> std::thread::Result<T2> {
-        self.handle.join()
+    /// Switch [`Background`] mode on or off (applied before the next file)
+    pub fn set_background(&self, enabled: bool) {
+        self.background.store(enabled, Ordering::Relaxed);
     }
 }
 
 impl ThreadWrapper<Result<FileInfo, BackupError>, BackupWriter> {
     pub fn crawl_for_files(config: Config, batch_size: usize) -> Self {
-        let (send, queue) = std::sync::mpsc::channel();
-        let handle = std::thread::spawn(move || {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let background = Arc::new(AtomicBool::new(false));
+        Self::spawn(batch_size, bytes, background, move |send| {
             let (mut writer, error) = BackupWriter::new(config);
             #[allow(unused_must_use)]
             if let Some(e) = error {
+                match e {
+                    BackupError::OutputMoved(..) => log::warn!("{}", e),
+                    _ => log::warn!("Could not get time from previous backup: {}", e),
+                }
                 send.send(Err(e));
             }
             let error = writer.foreach_file(true, |res| {
                 send.send(match res {
                     Ok(fi) => Ok(fi.clone()),
-                    Err(e) => Err(BackupError::FileAccessError(e)),
+                    Err(e) => {
+                        log::warn!("{}", e);
+                        Err(BackupError::FileAccessError(e))
+                    }
                 })
                 .map_err(|_| BackupError::Cancel)
             });
             #[allow(unused_must_use)]
             if let Err(e) = error {
+                match e {
+                    BackupError::Cancel => log::warn!("Cancelled"),
+                    _ => log::error!("{}", e),
+                }
                 send.send(Err(e));
             }
             std::mem::drop(send);
             writer
-        });
-        Self {
-            batch_size,
-            batch_mult: 1,
-            index: 0,
-            queue: Some(queue),
-            handle,
-        }
+        })
     }
 
-    pub fn backup_files(writer: BackupWriter, batch_size: usize) -> Self {
-        let (send, queue) = std::sync::mpsc::channel();
-        let handle = std::thread::spawn(move || {
+    pub fn backup_files(writer: BackupWriter, batch_size: usize, background: bool) -> Self {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let counter = bytes.clone();
+        let background = Arc::new(AtomicBool::new(background));
+        let flag = background.clone();
+        Self::spawn(batch_size, bytes, background, move |send| {
             let mut writer = writer;
+            let mut priority = Background::new(OsPriority, flag.load(Ordering::Relaxed));
             let error = writer.write(
                 #[allow(unused_must_use)]
                 |fi, res| {
+                    priority.set(flag.load(Ordering::Relaxed));
+                    priority.pace();
                     if let Err(e) = res {
+                        log::warn!("Could not add '{}' to the backup: {}", fi.get_string(), e);
                         send.send(Err(e));
                     }
                     send.send(Ok(fi.clone())).map_err(|_| BackupError::Cancel)
                 },
+                |b| {
+                    counter.fetch_add(b, Ordering::Relaxed);
+                },
                 || {},
             );
             #[allow(unused_must_use)]
             if let Err(e) = error {
+                match e {
+                    BackupError::Cancel => log::warn!("Cancelled"),
+                    _ => log::error!("{}", e),
+                }
                 send.send(Err(e));
             }
             std::mem::drop(send);
             writer
-        });
-        Self {
-            batch_size,
-            batch_mult: 1,
-            index: 0,
-            queue: Some(queue),
-            handle,
-        }
+        })
     }
 }
 
 impl ThreadWrapper<Result<FileInfo, BackupError>, BackupMerger> {
     pub fn merge_backups(merger: BackupMerger, batch_size: usize) -> Self {
-        let (send, queue) = std::sync::mpsc::channel();
-        let handle = std::thread::spawn(move || {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let counter = bytes.clone();
+        let background = Arc::new(AtomicBool::new(false));
+        Self::spawn(batch_size, bytes, background, move |send| {
             let mut merger = merger;
             let error = merger.write(
                 #[allow(unused_must_use)]
                 |fi, res| {
                     if let Err(e) = res {
+                        log::warn!("Could not add '{}' to the backup: {}", fi.get_string(), e);
                         send.send(Err(e));
                     }
                     send.send(Ok(fi.clone())).map_err(|_| BackupError::Cancel)
                 },
+                |b| {
+                    counter.fetch_add(b, Ordering::Relaxed);
+                },
                 || {},
             );
             #[allow(unused_must_use)]
             if let Err(e) = error {
+                match e {
+                    BackupError::Cancel => log::warn!("Cancelled"),
+                    _ => log::error!("{}", e),
+                }
                 send.send(Err(e));
             }
             std::mem::drop(send);
             merger
-        });
-        Self {
-            batch_size,
-            batch_mult: 1,
-            index: 0,
-            queue: Some(queue),
-            handle,
-        }
+        })
     }
 }
 
 impl ThreadWrapper<Result<FileInfo, BackupError>, BackupReader> {
+    /// Restore the selected files, the restored files are sent with the listed sizes and the
+    /// unpacked bytes are counted (for the progress)
+    #[allow(clippy::result_large_err)]
     pub fn restore_files(
         reader: BackupReader,
         selection: Vec<String>,
         flatten: bool,
         output: Option<PathBuf>,
+        overwrite: Overwrite,
         batch_size: usize,
+        background: bool,
     ) -> Result<Self, (BackupReader, BackupError)> {
         if flatten && output.is_none() {
             return Err((
@@ -151,49 +237,110 @@ impl ThreadWrapper<Result<FileInfo, BackupError>, BackupReader> {
             ));
         }
 
-        let (send, queue) = std::sync::mpsc::channel();
-        let handle = std::thread::spawn(move || {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let counter = bytes.clone();
+        let background = Arc::new(AtomicBool::new(background));
+        let flag = background.clone();
+        Ok(Self::spawn(batch_size, bytes, background, move |send| {
             let mut reader = reader;
+            let options = RestoreOptions {
+                overwrite,
+                ..Default::default()
+            };
+            let sizes: HashMap<String, u64> = reader
+                .get_list()
+                .map(|list| {
+                    list.iter_meta()
+                        .filter_map(|(_, size, _, path)| Some((path.to_string(), size?)))
+                        .collect()
+                })
+                .unwrap_or_default();
+            let size_of = |fi: &mut FileInfo| sizes.get(fi.get_string().as_str()).copied();
+            let on_progress = |b| {
+                counter.fetch_add(b, Ordering::Relaxed);
+            };
+            let mut priority = Background::new(OsPriority, flag.load(Ordering::Relaxed));
 
             let callback = |res: std::io::Result<FileInfo>| {
+                priority.set(flag.load(Ordering::Relaxed));
+                priority.pace();
                 match res {
                     Ok(fi) => send.send(Ok(fi)),
-                    Err(e) => send.send(Err(BackupError::IOError(e))),
+                    Err(e) => {
+                        log::warn!("Could not restore from backup: {}", e);
+                        send.send(Err(BackupError::IOError(e)))
+                    }
                 }
                 .map_err(|_| BackupError::Cancel)
             };
 
             let error = if flatten {
                 let output = output.unwrap();
-                let path_transform = |fi: FileInfo| {
-                    FileInfo::from(output.join(fi.consume_path().file_name().unwrap()))
+                let path_transform = |mut fi: FileInfo| {
+                    let size = size_of(&mut fi);
+                    let mut fi =
+                        FileInfo::from(output.join(fi.consume_path().file_name().unwrap()));
+                    fi.size = size.unwrap_or(0);
+                    fi
                 };
-                reader.restore(selection, path_transform, callback, true, true)
+                reader.restore_with(selection, path_transform, callback, on_progress, &options)
             } else {
-                let path_transform = |mut fi: FileInfo| match &output {
-                    Some(output) => {
-                        FileInfo::from(output.join(strip_absolute_from_path(&fi.move_string())))
-                    }
-                    None => fi,
+                let path_transform = |mut fi: FileInfo| {
+                    let size = size_of(&mut fi);
+                    let mut fi = match &output {
+                        Some(output) => {
+                            FileInfo::from(output.join(strip_absolute_from_path(&fi.move_string())))
+                        }
+                        None => fi,
+                    };
+                    fi.size = size.unwrap_or(0);
+                    fi
                 };
-                reader.restore(selection, path_transform, callback, true, true)
+                reader.restore_with(selection, path_transform, callback, on_progress, &options)
             };
 
             #[allow(unused_must_use)]
             if let Err(e) = error {
+                match e {
+                    BackupError::Cancel => log::warn!("Cancelled"),
+                    _ => log::error!("{}", e),
+                }
                 send.send(Err(e));
             }
             std::mem::drop(send);
             reader
-        });
-        Ok(Self {
-            batch_size,
-            batch_mult: 1,
-            index: 0,
-            queue: Some(queue),
-            handle,
+        }))
+    }
+}
+
+/// The time and the number of files of the previous backup
+pub(crate) type PreviousBackup = (Option<NaiveDateTime>, usize);
+
+impl ThreadWrapper<(), Result<Option<PreviousBackup>, BackupError>> {
+    /// Find the backup that the next incremental backup is based on (the chosen base or the latest)
+    /// and read the time and the list of files from it (`None` if there is no previous backup)
+    pub fn previous_backup(config: Config) -> Self {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let background = Arc::new(AtomicBool::new(false));
+        Self::spawn(1, bytes, background, move |_| {
+            let path = match config
+                .base
+                .clone()
+                .or_else(|| config.get_backups().get_latest())
+            {
+                Some(path) => path,
+                None => return Ok(None),
+            };
+            let mut reader = BackupReader::new(path);
+            let (config, list) = reader.get_meta()?;
+            Ok(Some((config.time, list.iter().count())))
         })
     }
+
+    /// Wait for the lookup of the previous backup (a panic is returned as an error)
+    pub fn join_previous(self) -> Result<Option<PreviousBackup>, BackupError> {
+        self.join().and_then(|res| res)
+    }
 }
 
 /// Iterator over batches that tries to scale the batch size to match the queue size
@@ -225,3 +372,75 @@ impl<T1, T2> Iterator for ThreadWrapper<T1, T2> {
         (0, Some(self.batch_size))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::sync::atomic::{AtomicBool, AtomicU64};
+    use std::sync::mpsc::TryRecvError;
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+
+    use super::ThreadWrapper;
+    use crate::backup::{BackupError, BackupWriter};
+    use crate::config::Config;
+
+    #[test]
+    fn panic_is_delivered() {
+        let wrapper: ThreadWrapper<Result<u32, BackupError>, u32> = ThreadWrapper::spawn(
+            10,
+            Arc::new(AtomicU64::new(0)),
+            Arc::new(AtomicBool::new(false)),
+            |send| {
+                send.send(Ok(1)).unwrap();
+                panic!("Deliberate panic in the worker");
+            },
+        );
+        let mut received = vec![];
+        loop {
+            match wrapper.try_recv() {
+                Ok(v) => received.push(v.unwrap()),
+                Err(TryRecvError::Empty) => std::thread::yield_now(),
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+        assert_eq!(received, vec![1]);
+        match wrapper.join() {
+            Err(BackupError::WorkerPanic(msg)) => assert_eq!(msg, "Deliberate panic in the worker"),
+            _ => panic!("The panic should be returned from join"),
+        }
+    }
+
+    #[test]
+    fn previous_backup() {
+        let dir = tempdir().unwrap();
+        let out = tempdir().unwrap();
+        for i in 0..3 {
+            File::create(dir.path().join(format!("{}.txt", i))).unwrap();
+        }
+        let mut config = Config::new();
+        config
+            .include
+            .push(dir.path().to_string_lossy().to_string());
+        config.output = out.path().to_path_buf();
+        config.quality = 1;
+        config.threads = 1;
+        let previous = ThreadWrapper::previous_backup(config.clone()).join_previous();
+        assert!(matches!(previous, Ok(None)));
+
+        let mut bw = BackupWriter::new(config.clone()).0;
+        bw.write(|_, _| Ok(()), |_| (), || ()).unwrap();
+        let (time, files) = ThreadWrapper::previous_backup(config.clone())
+            .join_previous()
+            .unwrap()
+            .unwrap();
+        assert!(time.is_some());
+        assert_eq!(files, 3);
+
+        std::fs::write(&bw.path, b"not a backup").unwrap();
+        assert!(ThreadWrapper::previous_backup(config)
+            .join_previous()
+            .is_err());
+    }
+}
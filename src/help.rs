@@ -0,0 +1,1348 @@
+/// This module contains the extended help (`simple_backup help <COMMAND>`) and the man pages
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, Command};
+use clap_mangen::Man;
+use roff::{bold, roman, Roff};
+
+/// The name of the binary (used in the usage lines and man pages)
+pub const BIN_NAME: &str = "simple_backup";
+
+/// An example invocation (the arguments are separated by whitespace)
+#[derive(Debug, Clone, Copy)]
+pub struct Example {
+    /// The arguments after the binary name
+    pub args: &'static str,
+    /// What happens when the example is run
+    pub outcome: &'static str,
+}
+
+impl Example {
+    /// The command line arguments (including the binary name, double quotes group words as in a
+    /// shell)
+    #[allow(unused)]
+    pub fn argv(&self) -> Vec<&'static str> {
+        let mut argv = vec![BIN_NAME];
+        let mut rest = self.args.trim_start();
+        while !rest.is_empty() {
+            let (arg, tail) = match rest.strip_prefix('"') {
+                Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+                None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+            };
+            argv.push(arg);
+            rest = tail.trim_start();
+        }
+        argv
+    }
+}
+
+/// The extended help for a subcommand
+#[derive(Debug, Clone, Copy)]
+pub struct CommandHelp {
+    pub name: &'static str,
+    /// Long descriptions of the arguments (by their id)
+    pub args: &'static [(&'static str, &'static str)],
+    /// Also use the descriptions of the backup options (see [`CONFIG_ARGS`])
+    pub config_args: bool,
+    /// How the command behaves (beyond what the arguments do)
+    pub notes: &'static [&'static str],
+    pub examples: &'static [Example],
+}
+
+impl CommandHelp {
+    /// The long description of an argument
+    pub fn arg(&self, id: &str) -> Option<&'static str> {
+        let config: &[(&str, &str)] = if self.config_args { CONFIG_ARGS } else { &[] };
+        self.args
+            .iter()
+            .chain(config.iter())
+            .chain(COMMON_ARGS.iter())
+            .find(|(i, _)| *i == id)
+            .map(|(_, text)| *text)
+    }
+}
+
+/// Arguments that are added by clap (and do not need a long description)
+pub const BUILTIN_ARGS: [&str; 2] = ["help", "version"];
+
+/// Descriptions of the arguments that are shared by most commands
+pub const COMMON_ARGS: &[(&str, &str)] = &[
+    (
+        "verbose",
+        "Print the files that are processed (before doing anything).",
+    ),
+    (
+        "force",
+        "Overwrite files that already exist. Without this the command fails (or skips the file) \
+         instead of replacing anything.",
+    ),
+    (
+        "dry",
+        "Go through the motions (reading configs, backups, and crawling the files) without \
+         writing, renaming, or deleting anything. Combine with --verbose to see what would happen.",
+    ),
+    (
+        "background",
+        "Lower the cpu and io priority of the process and pause briefly between files, so that \
+         the computer stays responsive. The command takes longer, but the result is the same.",
+    ),
+    (
+        "progress",
+        "How to print the progress: `bar` draws a progress bar in the terminal, and `json` writes \
+         newline-delimited events to stderr (for other programs). Every event has the `phase`, \
+         `current_files`, `total_files`, `current_bytes`, `total_bytes`, and `message` fields.",
+    ),
+    (
+        "log_file",
+        "Append log messages (with timestamps) to this file, e.g. for scheduled backups. Warnings \
+         and errors are still shown on the console. The GUI logs to `gui.log` in the settings \
+         directory unless another file is given.",
+    ),
+    (
+        "log_level",
+        "How much to log: `info` adds the crawling, compression, and renaming steps, and `debug` \
+         or `trace` add even more details.",
+    ),
+    (
+        "help_long",
+        "Print this extended help (with notes and examples) instead of running the command.",
+    ),
+];
+
+/// Descriptions of the options that make up a backup config (shared by `config` and `direct`)
+pub const CONFIG_ARGS: &[(&str, &str)] = &[
+    (
+        "include",
+        "A file or directory to back up (repeat the option for more paths). Directories are \
+         crawled recursively. Paths may start with `~` or contain environment variables, which \
+         are expanded when the backup is made. An explicit include is backed up even if it is \
+         inside an excluded directory or matches a filter.",
+    ),
+    (
+        "required",
+        "Like --include, but the backup fails if the path contains nothing to back up (e.g. an \
+         external drive that is not mounted), instead of silently creating an incomplete backup.",
+    ),
+    (
+        "exclude",
+        "A file or directory to leave out (repeat the option for more paths). Only applies to the \
+         paths below the includes.",
+    ),
+    (
+        "regex",
+        "Leave out the files whose (full) path matches the regular expression, e.g. `\\.tmp$` \
+         (repeat the option for more filters).",
+    ),
+    (
+        "exclude_from",
+        "Read more paths to exclude from a file, one per line (blank lines and lines starting \
+         with `#` are skipped). A relative path is resolved from the directory of the config \
+         file, so the config and the list can be moved together.",
+    ),
+    (
+        "regex_from",
+        "Read more exclusion regexes from a file, one per line (like --exclude-from).",
+    ),
+    (
+        "output",
+        "Where the backups are written. A directory gets a new `<name>_<time>.tar.zst` for every \
+         backup, while a path ending in `.tar.zst` is used as is. With `-` the backup is written to \
+         stdout (e.g. to pipe it to remote storage), which requires a non-incremental backup.",
+    ),
+    (
+        "incremental",
+        "Only store the files that have been modified since the previous backup (the time of each \
+         backup is stored in its config). Restoring an incremental backup also looks in the previous \
+         backups for the unchanged files.",
+    ),
+    (
+        "local",
+        "Store the paths as given (relative to the working directory) instead of converting them \
+         to absolute paths, so that the backup can be restored relative to another directory.",
+    ),
+    (
+        "default",
+        "Add exclusion filters for files that are rarely worth backing up (caches, temporary \
+         files, thumbnails, and similar).",
+    ),
+    (
+        "quality",
+        "The zstd compression level, where higher compresses better but slower. Levels above 19 \
+         also use more memory.",
+    ),
+    (
+        "threads",
+        "The number of threads used for compressing. More threads are faster, but use more memory.",
+    ),
+    (
+        "paranoid",
+        "In incremental backups, double check the files that seem unchanged (by comparing the \
+         sizes and hashing a sample of them) so that modifications that kept the timestamp are \
+         also backed up.",
+    ),
+    (
+        "sample",
+        "The percentage of the unchanged files that are hashed by --paranoid.",
+    ),
+    (
+        "seed",
+        "The seed for selecting the files that --paranoid hashes (for reproducible checks).",
+    ),
+    (
+        "retries",
+        "How many times to retry reading a file (e.g. one that is locked by another program) \
+         before skipping it. Reads that are interrupted or time out (e.g. on a network drive) \
+         are also retried, without restarting the file.",
+    ),
+    (
+        "retry_delay",
+        "How long to wait (in milliseconds) before the first retry, the wait is doubled for \
+         every following retry (with the defaults: 500 ms and then 1 s).",
+    ),
+    (
+        "read_ahead_mb",
+        "Read the next files into memory (at most this many MiB, 32 by default) on separate \
+         threads (one per worker thread) while the previous files are compressed, so that slow \
+         disks and the compression do not wait for each other. Larger files are read while they \
+         are compressed. The backup is identical regardless of this, use `0` to read every file \
+         only when it is compressed (as older versions did), e.g. to compare the speed.",
+    ),
+    (
+        "volume_size",
+        "Split the backup into volumes of at most this size (named `*.tar.zst.001`, \
+         `*.tar.zst.002`, ...), e.g. `4000M` for FAT32 drives.",
+    ),
+    (
+        "empty_dirs",
+        "Also store the directories that contain nothing to back up, so that they are recreated \
+         when restoring.",
+    ),
+    (
+        "gitignore",
+        "Skip the files that are ignored by `.gitignore` files (and the global git ignore file). \
+         Paths that are explicitly included are still backed up.",
+    ),
+    (
+        "max_depth",
+        "Only descend this many directories below each include, with `1` only the files directly \
+         inside the included directories are backed up.",
+    ),
+    (
+        "newer_than",
+        "Only backup the files that were modified at or after this time, e.g. to only archive \
+         recent work. Unlike incremental backups this does not depend on the previous backup.",
+    ),
+    (
+        "older_than",
+        "Only backup the files that were modified before this time, e.g. to archive files that \
+         have not been touched in a year.",
+    ),
+    (
+        "journal",
+        "Write a journal before renaming backups, so that a rename that is interrupted by a crash \
+         is completed (or reverted) by the next command that uses the directory.",
+    ),
+    (
+        "dedup",
+        "Store files with identical content only once in a backup (they are still restored as \
+         separate copies).",
+    ),
+    (
+        "change_detection",
+        "Which timestamp decides if a file has been modified in incremental backups. The change \
+         time (ctime) is also updated when the permissions or owner change (on Unix, Windows always \
+         uses the modification time).",
+    ),
+    (
+        "metadata_updates",
+        "When only the permissions or owner of a file have changed, store just those (the content \
+         is taken from the previous backup when restoring).",
+    ),
+    (
+        "delta_files",
+        "Store the files whose path matches this regex (repeat the option for more patterns) as \
+         the blocks that changed since the previous backup, e.g. disk images or mailbox files \
+         that change a little every day. The files are compared in blocks of 1 MiB (the hashes \
+         are stored in the backup), and restoring such a file needs the previous backups. \
+         Merging stores the whole files again.",
+    ),
+    (
+        "delta_min_size",
+        "Only store the files of at least this size (e.g. `64M`, the default) as changed blocks, \
+         smaller files are always stored whole.",
+    ),
+    (
+        "store_only",
+        "Comma separated extensions of already compressed files (photos, videos, archives, ...) \
+         that are compressed with a fast level, since a high level would only waste time on them. \
+         By default a list of common formats is used, and `\"\"` compresses every file with \
+         the chosen quality.",
+    ),
+    (
+        "tar_format",
+        "The format of the tar headers. With `pax` (the default) long paths and large files are \
+         stored as pax extensions, which are supported by all modern tar implementations. `gnu` \
+         uses the GNU extensions instead (as older versions did). Backups in either format can \
+         be restored.",
+    ),
+    (
+        "skip_apple_double",
+        "Skip the AppleDouble files (`._*`) that macOS writes next to files on file systems \
+         without extended attributes (such as FAT32 drives). On macOS the Finder tags (and other \
+         extended attributes) are stored according to `macos_xattrs` in the config, by default \
+         the quarantine flag is stored but not restored.",
+    ),
+    (
+        "require_output",
+        "Fail the backup if the output directory does not already exist, instead of creating it. \
+         Prevents writing the backup to the internal drive when an external drive is not mounted \
+         (under the empty mount point).",
+    ),
+    (
+        "marker_file",
+        "Fail the backup unless this file exists in the output directory, e.g. an empty file \
+         that is created once on the external drive (implies --require-output).",
+    ),
+    (
+        "allow_output_inside",
+        "Backup the output directory if it is inside one of the includes. By default the output \
+         directory is excluded (with a warning), so that every backup does not also store all \
+         the previous backups.",
+    ),
+];
+
+/// The extended help for all subcommands
+pub const COMMANDS: &[CommandHelp] = &[
+    CommandHelp {
+        name: "config",
+        args: &[
+            (
+                "path",
+                "The config file to create, the format is decided by the extension (yaml, toml, \
+                 or json). If the file already exists it is updated instead (see the notes).",
+            ),
+            (
+                "dry",
+                "Print the config instead of writing it to disk (or the changes to an existing \
+                 config, as a unified diff).",
+            ),
+            ("remove_include", "Remove an include from an existing config."),
+            ("remove_exclude", "Remove an exclude from an existing config."),
+            ("remove_regex", "Remove a regex from an existing config."),
+            (
+                "no_incremental",
+                "Switch an existing config to full backups (the opposite of `--incremental`).",
+            ),
+            (
+                "no_local",
+                "Switch an existing config to absolute paths (the opposite of `--local`).",
+            ),
+        ],
+        config_args: true,
+        notes: &[
+            "The config stores the options, so that repeated (incremental) backups are made with \
+             `simple_backup backup <CONFIG>`.",
+            "When a backup is made, the config is also stored inside the backup, so a backup (or \
+             directory of backups) can be used instead of the config file later.",
+            "When the config already exists, the given includes, excludes, and regexes are added \
+             to it (unless already there), and `--output`, `--quality`, `--threads`, \
+             `--incremental`, and `--local` are only changed if given. Other options cannot be \
+             changed this way, and fields unknown to this version are kept.",
+        ],
+        examples: &[
+            Example {
+                args: "config docs.yml --include ~/Documents --exclude ~/Documents/tmp --output /mnt/backups",
+                outcome: "Creates `docs.yml` for backing up the documents (except `tmp`) to `/mnt/backups`.",
+            },
+            Example {
+                args: "config docs.yml -i ~/Notes --remove-exclude ~/Documents/tmp --dry",
+                outcome: "Shows how adding the notes and no longer excluding `tmp` would change `docs.yml`.",
+            },
+            Example {
+                args: "config photos.toml -i ~/Pictures -I -P --sample 5 -o /mnt/backups",
+                outcome: "Creates a config for incremental backups that double checks 5% of the unchanged files.",
+            },
+        ],
+    },
+    CommandHelp {
+        name: "backup",
+        args: &[
+            (
+                "config",
+                "A config file, a previous backup, or a directory with previous backups (the config \
+                 of the latest backup is used).",
+            ),
+            (
+                "time",
+                "Only used for incremental backups: back up the files modified after this time \
+                 (instead of after the previous backup).",
+            ),
+            (
+                "base",
+                "Only used for incremental backups: back up the files modified after this backup \
+                 (instead of after the latest backup), e.g. to start a second branch from an older \
+                 backup. The base must be older than the new backup.",
+            ),
+            (
+                "watch",
+                "Keep running and make a new backup with this interval (e.g. `30m`, `12h`, or \
+                 `1d`), until the process is stopped.",
+            ),
+            (
+                "resume_scan",
+                "Save the progress of the file scan next to the backups, and resume an interrupted \
+                 scan that is less than a day old (directories modified since are scanned again).",
+            ),
+            (
+                "report",
+                "Only with --dry: write the files that would be backed up (with their modification \
+                 times and sizes) to a csv file, e.g. to compare what two runs would capture.",
+            ),
+            (
+                "summary",
+                "Only with --dry: print the number of files and their total size for every \
+                 directory DEPTH levels below the common directory of the files (1 if no DEPTH is \
+                 given), largest first, to find what to exclude before a long backup.",
+            ),
+            (
+                "write_errors",
+                "Also write the files that were skipped due to errors (with the reasons) to \
+                 `backup_<TIME>.errors.txt` next to the backup. A summary of the skipped files is \
+                 always printed after the backup.",
+            ),
+            (
+                "strict",
+                "Fail the backup if one of the `commands` in the config fails or times out \
+                 (by default the output of the failed command is left out and the failure is \
+                 listed in the summary).",
+            ),
+            (
+                "include",
+                "Add a path to include (repeat the option for more paths), only for this backup.",
+            ),
+            (
+                "exclude",
+                "Add a path to exclude (repeat the option for more paths), only for this backup.",
+            ),
+            (
+                "regex",
+                "Add an exclusion regex (repeat the option for more filters), only for this backup.",
+            ),
+            ("output", "Write the backup here instead, only for this backup."),
+            ("quality", "Use this compression quality (1-22) instead, only for this backup."),
+            ("threads", "Use this number of threads instead, only for this backup."),
+            (
+                "incremental",
+                "Only store the files that have been modified since the previous backup, even if \
+                 the config is not incremental.",
+            ),
+            (
+                "no_incremental",
+                "Store all files (a full backup), even if the config is incremental.",
+            ),
+            (
+                "wait_for_output",
+                "Wait (at most this many seconds) for the output directory, and the `marker_file` \
+                 from the config, to appear before failing. Useful for scheduled backups to an \
+                 external drive that is mounted after boot.",
+            ),
+            (
+                "chain_across_moves",
+                "Base an incremental backup on the latest backup even if it was written to another \
+                 location (e.g. the output is a symlink that now points to another drive). Without \
+                 this a full backup is made (with a warning) when the resolved output directory \
+                 differs from the one recorded in the latest backup.",
+            ),
+            (
+                "run_as",
+                "When started as root (e.g. from a cron job), switch to this user after the output \
+                 has been found, so that the files are crawled and the backup is written (and owned) \
+                 by the user. Fails if the user is root or cannot write to the output, and required \
+                 includes that the user cannot read are errors (other includes are warnings). Only \
+                 available on Unix.",
+            ),
+        ],
+        config_args: false,
+        notes: &[
+            "For incremental configs the previous time is the time of the latest backup in the \
+             output directory (unless --time or --base is given), and only files modified after \
+             it are stored. Everything is backed up when there is no previous backup.",
+            "The output directory is also recorded with the symlinks resolved. If the output \
+             resolves to another directory than in the latest backup (e.g. a symlink that has been \
+             repointed to another drive), a new chain is started with a full backup unless \
+             --chain-across-moves is given.",
+            "The config is also stored inside the backup, so the backup directory can be used \
+             instead of the config file for the next backup.",
+            "The config can also list `commands` (with a `name`, the `cmd` to run in the shell, and \
+             an optional `timeout_s`), whose output is stored as `_commands/<name>` in every backup \
+             (e.g. a database dump). The commands are run when the backup is written, --dry only \
+             lists them.",
+            "A `metadata_command` in the config adds user-defined metadata to the list of files. It \
+             is run (when the backup is written) with the paths of the files on stdin, one per line \
+             and 1000 at a time, and prints `path<TAB>key=value;key=value` lines. The metadata of \
+             a file is limited to 1024 bytes, and a failing command only leaves out the metadata \
+             (with a warning). See `inspect --list`, `meta.<key>` in queries, and \
+             `restore --metadata-sidecar`.",
+            "The overrides (--include, --exclude, --regex, --output, --quality, --threads, \
+             --incremental, and --no-incremental) change the config only for this backup. The \
+             config file is not modified, but the config stored inside the backup contains the \
+             changes (so a later backup from the backup directory uses them).",
+            "With --progress json the phase is `backup` while the files are added, `finalize` \
+             while the compression completes, and finally `done` (or `failed`). Events are written \
+             at most five times a second, except when the phase changes.",
+            "After backups larger than 256 MiB a suggestion is printed if most of the data barely \
+             shrank (a lower --quality, or adding the extensions to `store_only`, would be faster), \
+             or if the data shrank a lot while the compression kept up with reading the files (a \
+             higher --quality would be almost free).",
+        ],
+        examples: &[
+            Example {
+                args: "backup docs.yml",
+                outcome: "Backs up the files in `docs.yml` (incrementally if the config is incremental).",
+            },
+            Example {
+                args: "backup /mnt/backups --time 2024-01-01",
+                outcome: "Repeats the latest backup in `/mnt/backups`, including everything modified after 2024-01-01.",
+            },
+            Example {
+                args: "backup docs.yml --no-incremental --output /mnt/usb",
+                outcome: "Makes a full backup of the files in `docs.yml` to `/mnt/usb` (without changing `docs.yml`).",
+            },
+            Example {
+                args: "backup docs.yml --dry --report planned.csv",
+                outcome: "Writes the files that the next backup would contain to `planned.csv` (without backing up).",
+            },
+            Example {
+                args: "backup docs.yml --dry --summary 2",
+                outcome: "Shows which directories (two levels deep) the next backup would spend the most space on.",
+            },
+            Example {
+                args: "backup docs.yml --watch 12h --background",
+                outcome: "Backs up twice a day (with low priority) until stopped.",
+            },
+        ],
+    },
+    CommandHelp {
+        name: "restore",
+        args: &[
+            (
+                "source",
+                "The backup, a directory with backups (the latest is used), a config file, or `-` to \
+                 read the backup from stdin.",
+            ),
+            (
+                "output",
+                "Restore into this directory instead of the original locations (the absolute paths \
+                 are recreated inside it).",
+            ),
+            (
+                "newer",
+                "Only overwrite the existing files that are older than the copies in the backup \
+                 (comparing the modification times), the other files are kept and reported as \
+                 skipped.",
+            ),
+            (
+                "mirror",
+                "Also delete the files that had been deleted before the backup was made (recorded \
+                 by incremental backups, for the whole chain unless --this is given), so that the \
+                 restored files match the backup. Only the deleted files that match --include, \
+                 --regex, and --type are deleted (none with --where), directories are only deleted \
+                 if they are empty, and --dry lists the files instead.",
+            ),
+            (
+                "include",
+                "Only restore this file (repeat the option for more files). The path must be written \
+                 as it is stored in the backup (see `simple_backup inspect --list`). A directory \
+                 restores all the files under it (`/home/me/photos` does not match \
+                 `/home/me/photos2`).",
+            ),
+            (
+                "regex",
+                "Only restore the files whose (stored) path matches the regular expression (repeat \
+                 the option for more patterns). Can be combined with --include.",
+            ),
+            (
+                "map",
+                "Restore the paths starting with FROM under TO instead (e.g. `/home/old=/home/new`). \
+                 When several mappings match, the longest FROM is used.",
+            ),
+            (
+                "strip_prefix",
+                "Remove a leading path from the files and restore the rest under the output, e.g. \
+                 `--strip-prefix /home/me` restores `/home/me/projects/a.txt` as `<OUTPUT>/projects/a.txt`. \
+                 The prefix is removed after the --map, and nothing is restored if any of the \
+                 selected files is outside the prefix.",
+            ),
+            ("types", "Only restore files of this type (decided by the file extension)."),
+            (
+                "query",
+                "Only restore the files that match the query (in addition to the other filters). \
+                 Compare `size`, `mtime`, `path` (`~` for regex, `^=` for prefix), `included`, and \
+                 `status` (stored, unchanged, or metadata), or the user-defined metadata with \
+                 `meta.<key>` (e.g. `meta.owner = anna`, a bare `meta.owner` checks that the key is \
+                 set), and combine with and, or, not, and parentheses. Quote values with spaces, \
+                 e.g. `mtime < '2024-01-01 12:00'`.",
+            ),
+            (
+                "flatten",
+                "Restore all files directly into the output directory (without their directories). \
+                 Files with the same name overwrite each other (if --force is given).",
+            ),
+            (
+                "this",
+                "Only restore from the selected backup. Without this, the files that are listed \
+                 (but unchanged) in an incremental backup are restored from the previous backups.",
+            ),
+            (
+                "follow_origin",
+                "The previous backups are looked for in the directory of the restored backup. With \
+                 this the original output directory (from the config) is also searched, for when \
+                 only some of the backups have been copied elsewhere.",
+            ),
+            (
+                "record_restore",
+                "Append a record of the restoration to `simple_backup.restore-manifest.yml` in the \
+                 given directory (or the output directory, or the current directory). The record \
+                 lists every selected file with where it was restored, which backup stored it, its \
+                 modification time, and whether it was restored, skipped, or failed. Nothing is \
+                 recorded with --dry. See `simple_backup restore-log`.",
+            ),
+            (
+                "metadata_sidecar",
+                "Write the user-defined metadata (from the `metadata_command` in the config) of the \
+                 restored files to `simple_backup_metadata.tsv` in the output directory, as \
+                 `path<TAB>key=value;key=value` lines with the paths relative to the output.",
+            ),
+        ],
+        config_args: false,
+        notes: &[
+            "Without --include or --regex all files are restored: for incremental backups this is \
+             the state at the time of the backup (files deleted before it are not restored, but \
+             existing copies of them are only removed with --mirror).",
+            "Existing files are not overwritten unless --force is given (they are reported as \
+             errors instead).",
+            "A backup from stdin (`-`) is read once from start to end, so the previous backups of \
+             an incremental chain cannot be found: only the files stored in the backup itself are \
+             restored (as with --this).",
+        ],
+        examples: &[
+            Example {
+                args: "restore /mnt/backups",
+                outcome: "Restores all files from the latest backup to their original locations.",
+            },
+            Example {
+                args: "restore backup.tar.zst --where \"path ^= /home/user/ and mtime >= 2024-01-01\"",
+                outcome: "Restores the files in `/home/user` that were modified this year (and stored in the backup).",
+            },
+            Example {
+                args: "restore docs.yml --output /tmp/restored --regex \\.pdf$",
+                outcome: "Restores the pdf files into `/tmp/restored`.",
+            },
+            Example {
+                args: "restore backup.tar.zst --this --type photos --output /tmp/photos --flatten",
+                outcome: "Copies the photos stored in this backup (only) into `/tmp/photos`.",
+            },
+            Example {
+                args: "restore backup.tar.zst --map /home/old=/home/new --force",
+                outcome: "Restores the files from `/home/old` to `/home/new`, overwriting files that exist.",
+            },
+            Example {
+                args: "restore backup.tar.zst --output /tmp/restored --record-restore",
+                outcome: "Restores into `/tmp/restored` and records the restored files there.",
+            },
+        ],
+    },
+    CommandHelp {
+        name: "extract",
+        args: &[
+            (
+                "source",
+                "The backup, a directory with backups (the latest is used), a config file, or `-` to \
+                 read the backup from stdin.",
+            ),
+            ("output", "The directory to extract the files into (created if missing)."),
+            (
+                "include",
+                "Only extract this file or directory (repeat the option for more files), written as \
+                 it is stored in the backup.",
+            ),
+            (
+                "regex",
+                "Only extract the files whose (stored) path matches the regular expression.",
+            ),
+            ("flatten", "Extract all files directly into the output directory (without their directories)."),
+            (
+                "newer",
+                "Only overwrite the existing files that are older than the extracted copies.",
+            ),
+            (
+                "recursive",
+                "Also extract the unchanged files of an incremental backup from the previous backups.",
+            ),
+            (
+                "follow_origin",
+                "Also look for the previous backups in the original output directory (not only next \
+                 to the extracted backup).",
+            ),
+            (
+                "record_restore",
+                "Append a record of the extracted files to the manifest in the given directory (or \
+                 the output directory). See `simple_backup restore-log`.",
+            ),
+            (
+                "metadata_sidecar",
+                "Write the user-defined metadata of the extracted files to \
+                 `simple_backup_metadata.tsv` in the output directory (see `help restore`).",
+            ),
+        ],
+        config_args: false,
+        notes: &[
+            "Unlike restore this never writes to the original locations, so it is safe to use for \
+             looking at old versions of files.",
+        ],
+        examples: &[
+            Example {
+                args: "extract backup.tar.zst /tmp/out",
+                outcome: "Copies the files stored in the backup into `/tmp/out`.",
+            },
+            Example {
+                args: "extract /mnt/backups /tmp/out --recursive --include /home/user/notes.txt",
+                outcome: "Extracts the latest version of `notes.txt`, even if it is stored in an earlier backup.",
+            },
+        ],
+    },
+    CommandHelp {
+        name: "direct",
+        args: &[
+            (
+                "time",
+                "Back up the files modified after this time (requires --incremental).",
+            ),
+            (
+                "base",
+                "Back up the files modified after this backup instead of the latest one (requires \
+                 --incremental).",
+            ),
+            (
+                "resume_scan",
+                "Save the progress of the file scan next to the backups, and resume an interrupted \
+                 scan that is less than a day old.",
+            ),
+            (
+                "report",
+                "Only with --dry: write the files that would be backed up to a csv file.",
+            ),
+            (
+                "summary",
+                "Only with --dry: print the sizes of the directories (DEPTH levels below the common \
+                 directory of the files).",
+            ),
+            (
+                "write_errors",
+                "Write the files that were skipped due to errors to `backup_<TIME>.errors.txt` \
+                 next to the backup.",
+            ),
+            (
+                "save_config",
+                "Also write the config to a file (the format is decided by the extension), so that \
+                 the backup can be repeated with `backup <CONFIG>`. Together with --dry only the \
+                 config is written.",
+            ),
+        ],
+        config_args: true,
+        notes: &[
+            "Works like creating a config and backing it up, but nothing but the backup is written \
+             (the config is still stored inside the backup) unless --save-config is given.",
+        ],
+        examples: &[
+            Example {
+                args: "direct --include ~/Documents --output /mnt/backups",
+                outcome: "Backs up the documents into a new backup in `/mnt/backups`.",
+            },
+            Example {
+                args: "direct -i src -i Cargo.toml --local -o backup.tar.zst --gitignore",
+                outcome: "Backs up the (not ignored) source files with relative paths into `backup.tar.zst`.",
+            },
+            Example {
+                args: "direct -i ~/Documents -o /mnt/backups -I --dry --save-config docs.yml",
+                outcome: "Writes the equivalent config to `docs.yml` (without backing up).",
+            },
+        ],
+    },
+    CommandHelp {
+        name: "merge",
+        args: &[
+            (
+                "backups",
+                "The backups to merge, or directories with backups (all backups inside are merged).",
+            ),
+            (
+                "output",
+                "Write the merged backup here instead of replacing the most recent of the merged \
+                 backups.",
+            ),
+            (
+                "all",
+                "Keep every file from the merged backups. By default only the files that are listed \
+                 in the newest backup are kept (so files deleted before it are dropped).",
+            ),
+            (
+                "delete",
+                "Delete the merged backups, instead of renaming them to `*.tar.zst.old`.",
+            ),
+            ("quality", "The compression level of the merged backup (by default that of the newest)."),
+            ("threads", "The number of compression threads (by default that of the newest backup)."),
+            (
+                "strict",
+                "Drop the files that are listed, but not stored in any of the merged backups (e.g. \
+                 because the oldest backups are not part of the merge).",
+            ),
+            (
+                "journal",
+                "Write a journal before renaming, so that an interrupted merge is completed (or \
+                 reverted) by the next command.",
+            ),
+            ("after", "Only merge the backups made at or after this time."),
+            (
+                "before",
+                "Only merge the backups made before this time (the newer backups are left as they are).",
+            ),
+        ],
+        config_args: false,
+        notes: &[
+            "The newest version of every file is kept, and the merged backup replaces the newest \
+             backup (unless --output is given).",
+            "If newer incremental backups depend on the newest merged backup (e.g. when using \
+             --before), then the merged backup must replace it and --output can not be used.",
+            "The replaced backups can be inspected until they are removed with `simple_backup \
+             prune <DIR> --old-files`.",
+        ],
+        examples: &[
+            Example {
+                args: "merge /mnt/backups",
+                outcome: "Merges all backups in the directory into the newest one.",
+            },
+            Example {
+                args: "merge old.tar.zst new.tar.zst --all --delete",
+                outcome: "Merges two backups (keeping deleted files) and deletes the originals.",
+            },
+            Example {
+                args: "merge /mnt/backups --before 2024-01-01 --verbose",
+                outcome: "Merges the backups made before 2024 into one (keeping the newer backups separate).",
+            },
+        ],
+    },
+    CommandHelp {
+        name: "prune",
+        args: &[
+            ("source", "The backup directory (or a config file or backup in it)."),
+            (
+                "old_files",
+                "Delete the `*.tar.zst.old` files left by merges (only if the merged backup still \
+                 exists).",
+            ),
+            ("age", "Only delete files older than this (e.g. `30d` or `12h`)."),
+            ("dry", "Print what would be deleted without deleting anything."),
+        ],
+        config_args: false,
+        notes: &[],
+        examples: &[Example {
+            args: "prune /mnt/backups --old-files --age 7d",
+            outcome: "Deletes the replaced backups that are more than a week old.",
+        }],
+    },
+    CommandHelp {
+        name: "inspect",
+        args: &[
+            (
+                "source",
+                "The backup, a directory with backups (the latest is used), a config file, or `-` to \
+                 read the backup from stdin.",
+            ),
+            ("config", "Print the config that is stored in the backup."),
+            ("list", "Print the list of files (as they are stored in the backup)."),
+            (
+                "query",
+                "Only print the files in the list that match the query (see `help restore` for \
+                 the syntax).",
+            ),
+        ],
+        config_args: false,
+        notes: &["The modification times are read from the files stored in the backup, so `mtime` \
+                  only matches files stored in the backup itself (not the unchanged files of an \
+                  incremental backup), and using it reads the whole backup.",
+                 "Paths with control characters (e.g. a newline), or with bytes that are not valid \
+                  UTF-8, are percent-encoded in the printed list (`%0A`, with `%` as `%25`), and \
+                  other commands print them with escapes (e.g. `\\n` or `\\xff`)."],
+        examples: &[
+            Example {
+                args: "inspect backup.tar.zst --list",
+                outcome: "Prints the paths of the files in the backup (usable with restore --include).",
+            },
+            Example {
+                args: "inspect backup.tar.zst --list --where \"size > 10M and path ~ '\\.log$'\"",
+                outcome: "Prints the log files larger than 10 MiB.",
+            },
+        ],
+    },
+    CommandHelp {
+        name: "stats",
+        args: &[
+            ("source", "The backup, a directory with backups (the latest is used), or a config file."),
+            ("by", "Sum the sizes by file extension or by top-level directory."),
+            ("top", "How many of the largest files to show."),
+            ("json", "Print the statistics as json (for scripts)."),
+        ],
+        config_args: false,
+        notes: &[],
+        examples: &[Example {
+            args: "stats backup.tar.zst --by directory --top 20",
+            outcome: "Shows the size per directory and the 20 largest files.",
+        }],
+    },
+    CommandHelp {
+        name: "overlap",
+        args: &[(
+            "configs",
+            "The config files to compare (at least two).",
+        )],
+        config_args: false,
+        notes: &[
+            "Reports the directories that are included by more than one config, and estimates how \
+             much is backed up twice.",
+        ],
+        examples: &[Example {
+            args: "overlap docs.yml photos.yml",
+            outcome: "Lists the paths that both configs back up.",
+        }],
+    },
+    CommandHelp {
+        name: "config-diff",
+        args: &[
+            ("first", "The first config file."),
+            ("second", "The second config file (modified by --copy-excludes)."),
+            (
+                "sample",
+                "Walk this directory and list the paths that only one of the configs would \
+                 backup (the output is capped for very large directories).",
+            ),
+            (
+                "copy_excludes",
+                "Append the excludes and regexes that are missing from the second config. The \
+                 result is validated before the second config is (atomically) replaced.",
+            ),
+        ],
+        config_args: false,
+        notes: &[
+            "The includes and excludes are compared after the same normalisation as during a \
+             backup, so `./docs` and `docs` are the same. The exclusions from `exclude_from` and \
+             `regex_from` are also compared.",
+            "The time of the last backup is not compared.",
+        ],
+        examples: &[
+            Example {
+                args: "config-diff laptop.yml desktop.yml --sample ~/Documents",
+                outcome: "Lists the differences and the documents only one config backs up.",
+            },
+            Example {
+                args: "config-diff laptop.yml desktop.yml --copy-excludes",
+                outcome: "Adds the excludes from laptop.yml to desktop.yml.",
+            },
+        ],
+    },
+    CommandHelp {
+        name: "restore-log",
+        args: &[
+            (
+                "path",
+                "The directory that was restored into (with `simple_backup.restore-manifest.yml`), \
+                 or the manifest itself.",
+            ),
+            ("verbose", "Also list the restored files (not only the skipped and failed files)."),
+        ],
+        config_args: false,
+        notes: &[
+            "The manifest is written by restore and extract with --record-restore, every \
+             restoration is appended as a new yaml document (so the file is also readable by \
+             other tools).",
+            "An interrupted restoration is marked as such, and only lists the files that were \
+             reached.",
+        ],
+        examples: &[Example {
+            args: "restore-log /tmp/restored --verbose",
+            outcome: "Lists all restorations into `/tmp/restored` with every restored file.",
+        }],
+    },
+    CommandHelp {
+        name: "info",
+        args: &[
+            ("source", "The backup, a directory with backups, or a config file."),
+            ("json", "Print the chain as json (for scripts)."),
+            (
+                "absolute",
+                "Show the times of the backups as dates, instead of relative to now \
+                 (e.g. \"2 days ago\").",
+            ),
+        ],
+        config_args: false,
+        notes: &[
+            "Shows which backups an incremental backup depends on (and which are missing).",
+            "The original size (before compression) and the compression ratio are only shown for \
+             backups made by newer versions, which record the original size in the embedded config.",
+        ],
+        examples: &[Example {
+            args: "info /mnt/backups",
+            outcome: "Shows the chain of backups in the directory.",
+        }],
+    },
+    CommandHelp {
+        name: "usage",
+        args: &[
+            (
+                "source",
+                "The newest backup of the chain, a directory with backups (the latest backup is \
+                 used), or a config file.",
+            ),
+            ("format", "Print a table, or json for scripts."),
+        ],
+        config_args: false,
+        notes: &[
+            "Every file in the newest backup is attributed to the newest backup in the chain that \
+             stores it (the copy that a restore uses). The unique files of a backup are lost if it \
+             is deleted, while a backup without unique files only holds older versions of files.",
+            "The suggested merges are estimated from the unique part of each backup (assuming the \
+             same compression for all files), the sizes come from the file lists (or the archives \
+             for older backups without sizes in the lists).",
+        ],
+        examples: &[Example {
+            args: "usage /mnt/backups --format json",
+            outcome: "Prints the unique files and sizes of the latest chain in the directory as json.",
+        }],
+    },
+    CommandHelp {
+        name: "check",
+        args: &[(
+            "source",
+            "The backup, a directory with backups (the latest backup is used), or a config file.",
+        )],
+        config_args: false,
+        notes: &[
+            "The missing files are printed one per line (followed by a count on stderr). All files \
+             in the list of the backup are checked, also the unchanged files of an incremental \
+             backup, but not the output of commands.",
+            "Relative paths (from configs with `local`) are checked from the working directory.",
+            "The next incremental backup records the missing files as deleted (see `restore \
+             --mirror`), while a new full backup starts a chain without them.",
+        ],
+        examples: &[Example {
+            args: "check /mnt/backups",
+            outcome: "Lists the files in the latest backup that have been deleted (or moved) since.",
+        }],
+    },
+    CommandHelp {
+        name: "browse",
+        args: &[
+            (
+                "source",
+                "The backup, a directory with backups (the latest backup is used), or a config file.",
+            ),
+            (
+                "command",
+                "Run a single command and exit, instead of reading the commands interactively.",
+            ),
+        ],
+        config_args: false,
+        notes: &[
+            "The commands are `ls [PATH]`, `cd [PATH]`, `pwd`, `cat FILE`, `get FILE [DEST]`, \
+             `find REGEX`, `help`, and `quit`. Paths can be absolute or relative to the current \
+             directory, which starts at the common root of the files.",
+            "Files that are unchanged in an incremental backup are read from the previous backup \
+             that stores them, and the backup that served the file is printed.",
+            "`get` never overwrites existing files.",
+        ],
+        examples: &[
+            Example {
+                args: "browse /mnt/backups",
+                outcome: "Browses the latest backup in the directory interactively.",
+            },
+            Example {
+                args: "browse backup.tar.zst ls /home/user/documents",
+                outcome: "Lists the directory in the backup and exits.",
+            },
+        ],
+    },
+    CommandHelp {
+        name: "gui",
+        args: &[
+            ("screen", "Open this screen instead of the main menu."),
+            ("path", "The config, backup, or backup directory to open on the screen."),
+        ],
+        config_args: false,
+        notes: &[],
+        examples: &[Example {
+            args: "gui --screen restore --path /mnt/backups",
+            outcome: "Opens the restore screen with the latest backup in `/mnt/backups`.",
+        }],
+    },
+    CommandHelp {
+        name: "help",
+        args: &[(
+            "command",
+            "The command to show the extended help for (all commands are listed without it).",
+        )],
+        config_args: false,
+        notes: &[],
+        examples: &[Example {
+            args: "help restore",
+            outcome: "Prints the extended help for restore.",
+        }],
+    },
+    CommandHelp {
+        name: "generate-man",
+        args: &[("dir", "The directory to write the man pages to.")],
+        config_args: false,
+        notes: &["Writes one page for the program and one for every command (for packaging)."],
+        examples: &[Example {
+            args: "generate-man target/man",
+            outcome: "Writes `simple_backup.1`, `simple_backup-backup.1`, ... into `target/man`.",
+        }],
+    },
+];
+
+/// Find the extended help for a subcommand
+pub fn find(name: &str) -> Option<&'static CommandHelp> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// The arguments that are documented (skipping the ones added by clap)
+fn documented_args(cmd: &Command) -> impl Iterator<Item = &Arg> {
+    cmd.get_arguments()
+        .filter(|a| !a.is_hide_set() && !BUILTIN_ARGS.contains(&a.get_id().as_str()))
+}
+
+/// How the argument is written on the command line (e.g. `-o, --output <PATH>`)
+fn arg_syntax(arg: &Arg) -> String {
+    let value = arg
+        .get_value_names()
+        .and_then(|v| v.first())
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| arg.get_id().as_str().to_uppercase());
+    if arg.is_positional() {
+        return format!("<{}>", value);
+    }
+    let mut out = String::new();
+    if let Some(s) = arg.get_short() {
+        write!(out, "-{}", s).unwrap();
+    }
+    if let Some(l) = arg.get_long() {
+        if !out.is_empty() {
+            out.push_str(", ");
+        }
+        write!(out, "--{}", l).unwrap();
+    }
+    if arg.get_action().takes_values() {
+        write!(out, " <{}>", value).unwrap();
+    }
+    out
+}
+
+/// The allowed and default values of an argument (e.g. `[default: 20]`)
+fn arg_values(arg: &Arg) -> String {
+    let mut out = vec![];
+    let possible: Vec<String> = arg
+        .get_possible_values()
+        .iter()
+        .filter(|p| !p.is_hide_set())
+        .map(|p| p.get_name().to_string())
+        .collect();
+    if !possible.is_empty() && arg.get_action().takes_values() {
+        out.push(format!("[possible values: {}]", possible.join(", ")));
+    }
+    let defaults: Vec<String> = arg
+        .get_default_values()
+        .iter()
+        .map(|v| v.to_string_lossy().to_string())
+        .collect();
+    if !defaults.is_empty() && arg.get_action().takes_values() {
+        out.push(format!("[default: {}]", defaults.join(", ")));
+    }
+    out.join(" ")
+}
+
+/// Wrap the text into indented lines (at word boundaries)
+fn wrap(out: &mut String, text: &str, indent: usize, width: usize) {
+    let mut len = 0;
+    for word in text.split_whitespace() {
+        if len > 0 && len + 1 + word.len() > width {
+            out.push('\n');
+            len = 0;
+        }
+        if len == 0 {
+            out.extend(std::iter::repeat_n(' ', indent));
+            len = indent;
+        } else {
+            out.push(' ');
+            len += 1;
+        }
+        out.push_str(word);
+        len += word.len();
+    }
+    out.push('\n');
+}
+
+const WIDTH: usize = 100;
+
+/// The extended help for a subcommand (or an overview of all commands if `name` is `None`).
+/// Returns `None` for unknown commands.
+pub fn long_help(cli: &Command, name: Option<&str>) -> Option<String> {
+    let mut cli = cli.clone().bin_name(BIN_NAME);
+    cli.build();
+    let mut out = String::new();
+    let name = match name {
+        Some(name) => name,
+        None => {
+            if let Some(about) = cli.get_about() {
+                writeln!(out, "{} - {}\n", BIN_NAME, about).unwrap();
+            }
+            writeln!(out, "{}\n\nCommands:", cli.render_usage()).unwrap();
+            for sub in cli.get_subcommands().filter(|s| !s.is_hide_set()) {
+                let about = sub.get_about().map(|a| a.to_string()).unwrap_or_default();
+                writeln!(out, "  {:<10}  {}", sub.get_name(), about).unwrap();
+            }
+            out.push('\n');
+            wrap(
+                &mut out,
+                "Run `simple_backup help <COMMAND>` (or add `--help-long` to a command) for the \
+                 details and examples of a command.",
+                0,
+                WIDTH,
+            );
+            return Some(out);
+        }
+    };
+    let sub = cli.find_subcommand_mut(name)?;
+    let help = find(sub.get_name());
+    let about = sub.get_about().map(|a| a.to_string()).unwrap_or_default();
+    writeln!(out, "{} {} - {}\n", BIN_NAME, sub.get_name(), about).unwrap();
+    writeln!(out, "{}", sub.render_usage()).unwrap();
+    let sub = &*sub;
+    for (title, positional) in [("Arguments", true), ("Options", false)] {
+        let mut args = documented_args(sub)
+            .filter(|a| a.is_positional() == positional)
+            .peekable();
+        if args.peek().is_none() {
+            continue;
+        }
+        writeln!(out, "\n{}:", title).unwrap();
+        for arg in args {
+            let values = arg_values(arg);
+            if values.is_empty() {
+                writeln!(out, "  {}", arg_syntax(arg)).unwrap();
+            } else {
+                writeln!(out, "  {}  {}", arg_syntax(arg), values).unwrap();
+            }
+            let short = arg.get_help().map(|h| h.to_string()).unwrap_or_default();
+            match help.and_then(|h| h.arg(arg.get_id().as_str())) {
+                Some(long) => wrap(&mut out, long, 6, WIDTH),
+                None => wrap(&mut out, &short, 6, WIDTH),
+            }
+        }
+    }
+    if let Some(help) = help {
+        if !help.notes.is_empty() {
+            out.push_str("\nNotes:\n");
+            for note in help.notes {
+                let mut text = String::new();
+                wrap(&mut text, note, 4, WIDTH);
+                out.push_str("  -");
+                out.push_str(&text[3..]);
+            }
+        }
+        if !help.examples.is_empty() {
+            out.push_str("\nExamples:\n");
+            for ex in help.examples {
+                writeln!(out, "  {} {}", BIN_NAME, ex.args).unwrap();
+                wrap(&mut out, ex.outcome, 6, WIDTH);
+            }
+        }
+    }
+    Some(out)
+}
+
+/// A man page for the program (with `name: None`) or a subcommand. The page is rendered by
+/// clap_mangen (so it follows the arguments), with the long descriptions, notes, and examples of
+/// the extended help.
+fn man_page(cli: &Command, name: Option<&str>) -> Option<Vec<u8>> {
+    let mut cli = cli.clone().bin_name(BIN_NAME);
+    cli.build();
+    let version = cli.get_version().unwrap_or_default().to_string();
+    let mut cmd = match name {
+        Some(name) => cli.find_subcommand(name)?.clone(),
+        None => cli.clone(),
+    };
+    let help = name.and_then(find);
+    if let Some(help) = help {
+        let ids: Vec<String> = documented_args(&cmd)
+            .map(|a| a.get_id().to_string())
+            .collect();
+        for id in ids {
+            if let Some(text) = help.arg(&id) {
+                cmd = cmd.mut_arg(id, |a| a.long_help(text));
+            }
+        }
+    }
+    let title = cmd
+        .get_display_name()
+        .unwrap_or(cmd.get_name())
+        .to_uppercase();
+    let mut out = vec![];
+    Man::new(cmd)
+        .title(title)
+        .source(format!("{} {}", BIN_NAME, version))
+        .render(&mut out)
+        .expect("Could not render the man page");
+    let Some(help) = help else {
+        return Some(out);
+    };
+    let mut roff = Roff::new();
+    if !help.notes.is_empty() {
+        roff.control("SH", ["NOTES"]);
+        for note in help.notes {
+            roff.control("PP", []).text([roman(*note)]);
+        }
+    }
+    if !help.examples.is_empty() {
+        roff.control("SH", ["EXAMPLES"]);
+        for ex in help.examples {
+            roff.control("TP", [])
+                .text([bold(format!("{} {}", BIN_NAME, ex.args))])
+                .text([roman(ex.outcome)]);
+        }
+    }
+    roff.control("SH", ["SEE ALSO"])
+        .text([bold(BIN_NAME), roman("(1)")]);
+    out.extend_from_slice(roff.render().as_bytes());
+    Some(out)
+}
+
+/// Write the man pages for the program and all (visible) subcommands into a directory
+pub fn write_man_pages(cli: &Command, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    let mut written = vec![];
+    let names = std::iter::once(None).chain(
+        cli.get_subcommands()
+            .filter(|s| !s.is_hide_set())
+            .map(|s| Some(s.get_name())),
+    );
+    for name in names {
+        let page = man_page(cli, name).expect("The subcommand should exist");
+        let path = match name {
+            Some(name) => dir.join(format!("{}-{}.1", BIN_NAME, name)),
+            None => dir.join(format!("{}.1", BIN_NAME)),
+        };
+        std::fs::write(&path, page)?;
+        written.push(path);
+    }
+    Ok(written)
+}
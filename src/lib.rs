@@ -2,13 +2,29 @@
 #[macro_use]
 pub mod utils;
 pub mod backup;
+pub mod browse;
 pub mod cli;
+pub mod commands;
 pub mod compression;
 pub mod config;
+pub mod file_types;
 pub mod files;
+pub mod gitignore;
 pub mod gui;
+pub mod help;
 pub mod lists;
+pub mod logging;
+pub mod manifest;
+pub mod options;
+pub mod overlap;
 pub mod parse_date;
+pub mod privileges;
+pub mod query;
+pub mod read_ahead;
+pub mod recent;
+pub mod stats;
+pub mod txn;
+pub mod xattrs;
 
 #[allow(unused_imports)]
 use crate::backup::BackupReader;
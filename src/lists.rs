@@ -1,66 +1,227 @@
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime};
+use serde::{Deserialize, Serialize};
 
 use crate::backup::BackupError;
-use crate::files::{FileAccessError, FileCrawler, FileInfo};
+use crate::config::Config;
+use crate::files::{
+    bytes_to_string, cmp_path_str, fnv1a, is_raw_char, raw_chars, sort_paths, string_to_path,
+    BlockSignature, ChangeDetection, CrawlFrontier, FileAccessError, FileCrawler, FileInfo,
+    MetadataUpdate, RawChar, FNV_OFFSET,
+};
+use crate::parse_date::naive_now;
+use crate::stats::{common_root, directory_prefix, GroupSize};
+use crate::utils::extend_pathbuf;
 
 #[derive(Default)]
 pub struct FileListVec(Vec<(bool, FileInfo)>);
 
+/// Summary of the extra checks done by a paranoid incremental backup
+#[derive(Debug, Default)]
+pub struct ParanoidReport {
+    /// Number of unchanged files that were hashed
+    pub sampled: usize,
+    /// Number of unchanged files with a different size than in the previous backup
+    pub resized: usize,
+    /// Sampled files whose content differed from the previous backup
+    pub mismatched: Vec<String>,
+    /// Directories that have been fully included due to mismatches
+    pub escalated: Vec<PathBuf>,
+}
+
+fn log_crawl_start(time: Option<NaiveDateTime>) -> Instant {
+    match time {
+        Some(t) => log::info!("Crawling for files modified since {}", t),
+        None => log::info!("Crawling for files"),
+    }
+    Instant::now()
+}
+
+fn log_crawl_end(list: &[(bool, FileInfo)], start: Instant) {
+    log::info!(
+        "Crawled {} files ({} to backup) in {:.1?}",
+        list.len(),
+        list.iter().filter(|(inc, _)| *inc).count(),
+        start.elapsed()
+    );
+}
+
 impl FileListVec {
     pub fn push(&mut self, included: bool, file: FileInfo) {
         self.0.push((included, file))
     }
 
-    pub fn crawl(crawler: FileCrawler, time: Option<NaiveDateTime>) -> Self {
-        let mut list: Vec<(bool, FileInfo)> = match time {
-            Some(prev) => crawler
-                .into_iter()
-                .filter_map(|fi| match fi {
-                    Ok(fi) => Some((fi.time.unwrap() >= prev, fi)),
-                    Err(_) => None,
-                })
-                .collect(),
-            None => crawler
-                .into_iter()
-                .filter_map(|fi| match fi {
-                    Ok(fi) => Some((true, fi)),
-                    Err(_) => None,
-                })
-                .collect(),
-        };
+    pub fn crawl(
+        crawler: FileCrawler,
+        time: Option<NaiveDateTime>,
+        detection: ChangeDetection,
+    ) -> Self {
+        let start = log_crawl_start(time);
+        let mut list: Vec<(bool, FileInfo)> = crawler
+            .into_iter()
+            .filter_map(|fi| match fi {
+                Ok(fi) => Some((time.is_none_or(|t| fi.is_changed(t, detection)), fi)),
+                Err(e) => {
+                    log::warn!("{}", e);
+                    None
+                }
+            })
+            .collect();
         list.sort_unstable_by(|a, b| a.1.cmp(&b.1));
+        // Overlapping includes (e.g. differing in case on Windows) could yield a file twice
+        list.dedup_by(|a, b| a.1 == b.1);
+        log_crawl_end(&list, start);
         Self(list)
     }
 
+    /// Crawl for files, calling `callback` for errors and the included files (or all files if `all`).
+    /// With a `checkpoint` an interrupted crawl is continued, and the progress is saved along the way.
     pub fn crawl_with_callback(
-        crawler: FileCrawler,
+        mut crawler: FileCrawler,
         time: Option<NaiveDateTime>,
+        detection: ChangeDetection,
         all: bool,
+        mut checkpoint: Option<&mut ScanCheckpoint>,
         mut callback: impl FnMut(Result<&mut FileInfo, FileAccessError>) -> Result<(), BackupError>,
     ) -> Result<Self, BackupError> {
+        let start = log_crawl_start(time);
         let all = all || time.is_none();
+        let is_inc = |fi: &FileInfo| match time {
+            Some(t) => fi.is_changed(t, detection),
+            None => true,
+        };
         let mut list: Vec<(bool, FileInfo)> = vec![];
-        for f in crawler {
-            match f {
-                Ok(mut fi) => {
-                    let inc = match time {
-                        Some(t) => fi.time.unwrap() >= t,
-                        None => true,
-                    };
-                    if all || inc {
-                        callback(Ok(&mut fi))?;
+        if let Some(cp) = checkpoint.as_mut() {
+            let resumed;
+            (crawler, resumed) = cp.resume(crawler);
+            list.extend(resumed.into_iter().map(|fi| (is_inc(&fi), fi)));
+        }
+        let res = (|| -> Result<(), BackupError> {
+            for (inc, fi) in list.iter_mut() {
+                if all || *inc {
+                    callback(Ok(fi))?;
+                }
+            }
+            while let Some(f) = crawler.next() {
+                match f {
+                    Ok(mut fi) => {
+                        let inc = is_inc(&fi);
+                        let res = if all || inc {
+                            callback(Ok(&mut fi))
+                        } else {
+                            Ok(())
+                        };
+                        // Keep the file even if the callback fails (it is already crawled)
+                        list.push((inc, fi));
+                        res?;
+                        if let Some(cp) = checkpoint.as_mut() {
+                            cp.tick(&crawler, &list);
+                        }
                     }
-                    list.push((inc, fi));
+                    Err(e) => callback(Err(e))?,
                 }
-                Err(e) => callback(Err(e))?,
             }
+            Ok(())
+        })();
+        if let Some(cp) = checkpoint {
+            // Also save completed crawls (in case the backup is interrupted)
+            #[allow(unused_must_use)]
+            cp.save(&crawler, &list);
         }
+        res?;
         list.sort_unstable_by(|a, b| a.1.cmp(&b.1));
+        list.dedup_by(|a, b| a.1 == b.1);
+        log_crawl_end(&list, start);
         Ok(Self(list))
     }
 
+    /// Only store the metadata (permissions and owner) of the files that are included due to the
+    /// change time, but have not been modified since `time` (the content is in a previous backup)
+    pub fn mark_metadata_updates(&mut self, time: NaiveDateTime) -> usize {
+        let mut count = 0;
+        for (inc, fi) in self.0.iter_mut() {
+            if *inc && fi.time.is_some_and(|t| t < time) {
+                if let Ok(update) = MetadataUpdate::read(fi.get_path()) {
+                    *inc = false;
+                    fi.update = Some(update);
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    /// Double check the files that are "unchanged" according to the modification time.
+    /// Files with a different size than in the previous backup are included, and a sample
+    /// (`sample` percent) of the rest are hashed and compared to the previous hashes.
+    /// A hash mismatch causes the whole directory (and subdirectories) to be included.
+    pub fn paranoid_check(
+        &mut self,
+        previous: &FileListString,
+        sample: f32,
+        seed: u64,
+    ) -> ParanoidReport {
+        let previous: HashMap<&str, (Option<u64>, Option<u64>)> = previous
+            .iter_meta()
+            .map(|(_, size, hash, s)| (s, (size, hash)))
+            .collect();
+        let mut report = ParanoidReport::default();
+        let mut rng = seed;
+        for (inc, fi) in self.0.iter_mut() {
+            if *inc {
+                continue;
+            }
+            #[cfg(target_os = "windows")]
+            let prev = previous.get(fi.get_string().replace('\\', "/").as_str());
+            #[cfg(not(target_os = "windows"))]
+            let prev = previous.get(fi.get_string().as_str());
+            let (size, hash) = match prev {
+                Some(v) => *v,
+                None => continue,
+            };
+            if size.is_some_and(|s| s != fi.size) {
+                *inc = true;
+                report.resized += 1;
+                continue;
+            }
+            if (splitmix64(&mut rng) % 10000) as f32 >= sample * 100.0 {
+                fi.hash = hash;
+                continue;
+            }
+            report.sampled += 1;
+            if let Ok(h) = fi.hash_content() {
+                if hash.is_some_and(|prev| prev != h) {
+                    report.mismatched.push(fi.get_string().to_string());
+                    if let Some(dir) = fi.get_path().parent() {
+                        report.escalated.push(dir.to_path_buf());
+                    }
+                }
+            }
+        }
+        if !report.escalated.is_empty() {
+            report.escalated.sort_unstable();
+            report.escalated.dedup();
+            for (inc, fi) in self.0.iter_mut() {
+                if !*inc {
+                    let path = fi.get_path();
+                    if report.escalated.iter().any(|d| path.starts_with(d)) {
+                        *inc = true;
+                        fi.hash = None;
+                    }
+                }
+            }
+        }
+        report
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &(bool, FileInfo)> {
         self.0.iter()
     }
@@ -69,6 +230,48 @@ impl FileListVec {
         self.0.iter_mut()
     }
 
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut (bool, FileInfo)> {
+        self.0.get_mut(index)
+    }
+
+    /// Find the index of a file (the list must be sorted, see [`cmp_path_str`])
+    pub fn find(&self, path: &str) -> Option<usize> {
+        self.0
+            .binary_search_by(|(_, fi)| cmp_path_str(fi.copy_string().as_ref(), path))
+            .ok()
+    }
+
+    pub fn retain_mut(&mut self, mut f: impl FnMut(&mut bool, &mut FileInfo) -> bool) {
+        self.0.retain_mut(|(b, fi)| f(b, fi));
+    }
+
+    /// Sum the included files by their directory, `depth` levels below the common root of the
+    /// files (the largest first)
+    pub fn size_by_prefix(&self, depth: usize) -> Vec<GroupSize> {
+        let files: Vec<(Cow<PathBuf>, u64)> = self
+            .0
+            .iter()
+            .filter(|(b, _)| *b)
+            .map(|(_, fi)| (fi.copy_path(), fi.size))
+            .collect();
+        let root = common_root(files.iter().map(|(p, _)| p.as_path()));
+        let mut groups: HashMap<String, GroupSize> = HashMap::new();
+        for (path, size) in files {
+            let group = groups
+                .entry(directory_prefix(&path, &root, depth))
+                .or_insert_with_key(|k| GroupSize {
+                    name: k.clone(),
+                    files: 0,
+                    size: 0,
+                });
+            group.files += 1;
+            group.size += size;
+        }
+        let mut groups: Vec<GroupSize> = groups.into_values().collect();
+        groups.sort_unstable_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+        groups
+    }
+
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         self.0.len()
@@ -88,77 +291,1012 @@ impl FileListVec {
     }
 }
 
+/// A file in the list: included, size, hash (if available), and path
+pub type FileListMeta<'a> = (bool, Option<u64>, Option<u64>, &'a str);
+
+/// A file that is stored in a backup (see [`FileListString::entries`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub path: &'a str,
+    /// Only lists from newer versions contain the sizes
+    pub size: Option<u64>,
+    /// The modification times are not in the list (see [`crate::backup::BackupReader::entries`])
+    pub time: Option<NaiveDateTime>,
+}
+
 #[derive(Debug, Clone)]
 pub struct FileListString {
     list: String,
     version: u8,
+    /// Version 5 lists escape the paths (see [`escape_list_path`]). Then `list` contains the
+    /// unescaped lines separated by `\0`, and this is the list as it is stored in the backup.
+    escaped: Option<String>,
 }
 
 impl AsRef<[u8]> for FileListString {
     fn as_ref(&self) -> &[u8] {
-        self.list.as_ref()
+        self.escaped.as_deref().unwrap_or(&self.list).as_ref()
     }
 }
 
 impl FileListString {
     pub fn new<S: AsRef<str>>(filename: S, content: String) -> Result<Self, BackupError> {
-        let version = match filename.as_ref() {
-            "files.csv" => 1,
-            "files_v2.csv" => 2,
-            _ => return Err(BackupError::Unspecified),
-        };
-        Ok(Self {
+        let version = Self::version_of(filename.as_ref()).ok_or(BackupError::Unspecified)?;
+        if version == 5 {
+            let list = content
+                .split('\n')
+                .map(unescape_line)
+                .collect::<Vec<_>>()
+                .join("\0");
+            return Ok(Self {
+                list,
+                version,
+                escaped: Some(content),
+            });
+        }
+        let mut list = Self {
             list: content,
             version,
-        })
+            escaped: None,
+        };
+        // Lists from older Windows versions use `\` (but the archives always use `/`)
+        if list.list.contains('\\') && list.iter().next().is_some_and(|(_, s)| is_windows_path(s)) {
+            list.list = list.list.replace('\\', "/");
+        }
+        Ok(list)
     }
 
-    /// Convert a FileListVec to a FileListString
+    /// Convert a FileListVec to a FileListString (the metadata column of version 4 is only
+    /// added if some file has user-defined metadata, and the paths are only escaped in version 5
+    /// if some path contains control characters, so that older versions can read the rest)
     pub fn from(files: &mut FileListVec) -> Self {
         let mut list = String::with_capacity(files.len() * 200);
+        let version = if files
+            .iter_mut()
+            .any(|(_, fi)| needs_escape(fi.get_string()))
+        {
+            5
+        } else if files.iter().any(|(_, fi)| fi.user_meta.is_some()) {
+            4
+        } else {
+            3
+        };
         files.iter_mut().for_each(|(b, fi)| {
-            list.push(if *b { '1' } else { '0' });
+            match (*b, fi.update) {
+                // Older versions skip the generated files (they are not crawled)
+                (true, _) if fi.generated => list.push('c'),
+                (true, _) => list.push('1'),
+                // Older versions ignore the update (and restore the previous metadata)
+                (false, Some(update)) => list.push_str(&format!("m{}", update)),
+                (false, None) => list.push('0'),
+            }
+            list.push(',');
+            list.push_str(&fi.size.to_string());
             list.push(',');
+            if let Some(hash) = fi.hash {
+                list.push_str(&format!("{:016x}", hash));
+            }
+            list.push(',');
+            if version >= 4 {
+                if let Some(meta) = &fi.user_meta {
+                    list.push_str(&encode_user_meta(meta));
+                }
+                list.push(',');
+            }
             #[cfg(target_os = "windows")]
-            list.push_str(&fi.get_string().replace('\\', "/"));
+            let path = Cow::<str>::Owned(fi.get_string().replace('\\', "/"));
             #[cfg(not(target_os = "windows"))]
-            list.push_str(fi.get_string());
+            let path = Cow::Borrowed(fi.get_string().as_str());
+            if version == 5 {
+                list.push_str(&escape_list_path(&path));
+            } else {
+                list.push_str(&path);
+            }
             list.push('\n');
         });
         list.pop();
-        Self { list, version: 2 }
+        if version == 5 {
+            return Self::new("files_v5.csv", list).expect("The list version is known");
+        }
+        Self {
+            list,
+            version,
+            escaped: None,
+        }
     }
 
     /// Get an iterator over all the files in the list with a flag
     pub fn iter(&'_ self) -> Box<dyn Iterator<Item = (bool, &str)> + '_> {
         match self.version {
-            2 => Box::new(
-                self.list
-                    .split('\n')
-                    .map(|s: &str| (s.starts_with('1'), &s[2..])),
-            ),
-            _ => Box::new(self.list.split('\n').map(|s| (true, s))),
+            3..=5 => Box::new(self.lines().map(|s| {
+                let (b, _, _, s) = self.split(s);
+                (b, s)
+            })),
+            2 => Box::new(self.lines().map(|s: &str| (s.starts_with('1'), &s[2..]))),
+            _ => Box::new(self.lines().map(|s| (true, s))),
         }
     }
 
     /// Get an iterator over all the files that are included
     pub fn iter_included(&'_ self) -> Box<dyn Iterator<Item = &str> + '_> {
         match self.version {
-            2 => Box::new(self.list.split('\n').filter_map(|s: &str| {
+            3..=5 => Box::new(self.lines().filter_map(|s| match self.split(s) {
+                (true, _, _, s) => Some(s),
+                _ => None,
+            })),
+            2 => Box::new(self.lines().filter_map(|s: &str| {
                 if s.starts_with('1') {
                     Some(&s[2..])
                 } else {
                     None
                 }
             })),
-            _ => Box::new(self.list.split('\n')),
+            _ => Box::new(self.lines()),
+        }
+    }
+
+    /// Get an iterator over all the files in the list with a flag, size, and hash (if available)
+    pub fn iter_meta(&'_ self) -> Box<dyn Iterator<Item = FileListMeta<'_>> + '_> {
+        match self.version {
+            3..=5 => Box::new(self.lines().map(|s| self.split(s))),
+            _ => Box::new(self.iter().map(|(b, s)| (b, None, None, s))),
+        }
+    }
+
+    /// Get an iterator over the files that are included (stored in this backup) with sizes
+    pub fn entries(&'_ self) -> impl Iterator<Item = Entry<'_>> + '_ {
+        self.iter_meta()
+            .filter(|(inc, _, _, _)| *inc)
+            .map(|(_, size, _, path)| Entry {
+                path,
+                size,
+                time: None,
+            })
+    }
+
+    /// Get an iterator over the files where only the metadata (permissions and owner) is stored
+    pub fn iter_updates(&'_ self) -> Box<dyn Iterator<Item = (MetadataUpdate, &str)> + '_> {
+        match self.version {
+            3..=5 => Box::new(self.lines().filter_map(|s| {
+                let (flag, _) = s.split_once(',')?;
+                let update = MetadataUpdate::parse(flag.strip_prefix('m')?)?;
+                Some((update, self.split(s).3))
+            })),
+            _ => Box::new(std::iter::empty()),
+        }
+    }
+
+    /// Get an iterator over the files that are generated by commands (see [`crate::commands`])
+    pub fn iter_generated(&'_ self) -> Box<dyn Iterator<Item = &str> + '_> {
+        match self.version {
+            3..=5 => Box::new(
+                self.lines()
+                    .filter(|s| s.starts_with("c,"))
+                    .map(|s| self.split(s).3),
+            ),
+            _ => Box::new(std::iter::empty()),
+        }
+    }
+
+    /// Get an iterator over the files with user-defined metadata (`key=value;key=value`, see
+    /// [`crate::commands::collect_metadata`])
+    pub fn iter_user_meta(&'_ self) -> Box<dyn Iterator<Item = (&str, Cow<'_, str>)> + '_> {
+        match self.version {
+            4 | 5 => Box::new(self.lines().filter_map(|s| {
+                let meta = s.split(',').nth(3).filter(|m| !m.is_empty())?;
+                Some((self.split(s).3, decode_user_meta(meta)))
+            })),
+            _ => Box::new(std::iter::empty()),
+        }
+    }
+
+    /// Get an iterator over the files that have been deleted since the previous backup
+    /// (see [`Self::add_deleted`])
+    pub fn iter_deleted(&'_ self) -> Box<dyn Iterator<Item = &str> + '_> {
+        match self.version {
+            3..=5 => Box::new(
+                self.all_lines()
+                    .filter(|s| s.starts_with("d,"))
+                    .map(|s| self.split(s).3),
+            ),
+            _ => Box::new(std::iter::empty()),
+        }
+    }
+
+    /// Record files that were in the previous backup but have been deleted since (tombstones).
+    /// Older versions see the deleted files as unchanged (and restore them from the previous
+    /// backups). Paths with control characters are only recorded in version 5 lists.
+    pub fn add_deleted<S: AsRef<str>>(&mut self, deleted: &[S]) {
+        let prefix = match self.version {
+            3 => "d,,,",
+            4 | 5 => "d,,,,",
+            _ => return,
+        };
+        for path in deleted.iter().map(|p| p.as_ref()) {
+            if self.version < 5 && needs_escape(path) {
+                continue;
+            }
+            if let Some(escaped) = &mut self.escaped {
+                if !escaped.is_empty() {
+                    escaped.push('\n');
+                }
+                escaped.push_str(prefix);
+                escaped.push_str(&escape_list_path(path));
+            }
+            if !self.list.is_empty() {
+                self.list.push(if self.version == 5 { '\0' } else { '\n' });
+            }
+            self.list.push_str(prefix);
+            self.list.push_str(path);
+        }
+    }
+
+    /// The lines of the files in the list (without the deleted files)
+    fn lines(&self) -> impl Iterator<Item = &str> + '_ {
+        let deleted = self.version >= 3;
+        self.all_lines()
+            .filter(move |s| !(deleted && s.starts_with("d,")))
+    }
+
+    /// All lines of the list (version 5 lists are split at `\0`, since the paths can contain `\n`)
+    fn all_lines(&self) -> std::str::Split<'_, char> {
+        self.list.split(if self.version == 5 { '\0' } else { '\n' })
+    }
+
+    /// Split a line from a version 3, 4, or 5 list into: included, size, hash, and path
+    fn split<'a>(&self, line: &'a str) -> FileListMeta<'a> {
+        let columns = if self.version >= 4 { 5 } else { 4 };
+        let mut parts = line.splitn(columns, ',');
+        let inc = matches!(parts.next(), Some("1") | Some("c"));
+        let size = parts.next().and_then(|s| s.parse().ok());
+        let hash = parts.next().and_then(|s| u64::from_str_radix(s, 16).ok());
+        (inc, size, hash, parts.last().unwrap_or_default())
+    }
+
+    /// The version of a list based on the name of the entry in the archive
+    /// (`None` if the entry is not a list)
+    pub fn version_of(filename: &str) -> Option<u8> {
+        match filename {
+            "files.csv" => Some(1),
+            "files_v2.csv" => Some(2),
+            "files_v3.csv" => Some(3),
+            "files_v4.csv" => Some(4),
+            "files_v5.csv" => Some(5),
+            _ => None,
         }
     }
 
     pub fn filename(&self) -> &'static str {
         match self.version {
+            5 => "files_v5.csv",
+            4 => "files_v4.csv",
+            3 => "files_v3.csv",
             2 => "files_v2.csv",
             _ => "files.csv",
         }
     }
 }
+
+/// The name of the entry with the block signatures (the third entry, only in backups with
+/// `delta_files` in the config)
+pub const SIGNATURES_NAME: &str = "blocks.csv";
+
+/// The block signatures of the files that are stored as changed blocks (see
+/// [`crate::config::Config::delta_files`]). Every backup lists the signatures of all such files
+/// (also the unchanged ones), so that the next backup only needs the latest signatures.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SignatureList(HashMap<String, BlockSignature>);
+
+impl SignatureList {
+    /// Parse the lines of `size,hashes,path` (invalid lines are skipped)
+    pub fn parse(s: &str) -> Self {
+        Self(
+            s.lines()
+                .filter_map(|line| {
+                    // The path is last (since it might contain commas)
+                    let mut parts = line.splitn(3, ',');
+                    let size = parts.next()?.len();
+                    let hashes = parts.next()?.len();
+                    let path = parts.next()?;
+                    let signature = BlockSignature::parse(&line[..size + hashes + 1])?;
+                    Some((path.to_string(), signature))
+                })
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, path: &str) -> Option<&BlockSignature> {
+        self.0.get(path)
+    }
+
+    /// Add the signature of a file (paths with line breaks cannot be stored)
+    pub fn insert(&mut self, path: &str, signature: BlockSignature) {
+        if !path.contains(['\n', '\r']) {
+            self.0.insert(path.to_string(), signature);
+        }
+    }
+
+    pub fn remove(&mut self, path: &str) -> Option<BlockSignature> {
+        self.0.remove(path)
+    }
+}
+
+impl Display for SignatureList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut paths: Vec<&String> = self.0.keys().collect();
+        sort_paths(&mut paths);
+        for path in paths {
+            writeln!(f, "{},{}", self.0[path], path)?;
+        }
+        Ok(())
+    }
+}
+
+/// How often the progress of a crawl is saved (see [`ScanCheckpoint`])
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+/// How many crawled files between saving the progress (unless the interval is reached first)
+const CHECKPOINT_FILES: usize = 50_000;
+/// Directories modified this many seconds before the crawl started are also crawled again
+/// (some file systems, such as FAT, only have a two second precision for modification times)
+const CHECKPOINT_MARGIN: i64 = 2;
+/// Checkpoints older than this are not resumed
+pub const CHECKPOINT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The serialised form of a [`ScanCheckpoint`]
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointData {
+    fingerprint: u64,
+    /// When the first (interrupted) crawl started, in microseconds since the epoch
+    started: i64,
+    frontier: CrawlFrontier,
+    /// The crawled files: path, modification time (in microseconds since the epoch), size,
+    /// and change time
+    entries: Vec<(String, Option<i64>, u64, Option<i64>)>,
+}
+
+/// The periodically saved progress of a crawl, so that an interrupted crawl can be resumed.
+/// When resuming, directories that have been modified since the crawl started are crawled again.
+/// Files that are modified in place (without changing the directory) keep the state from the
+/// checkpoint, which is why old checkpoints are ignored.
+pub struct ScanCheckpoint {
+    path: PathBuf,
+    fingerprint: u64,
+    started: NaiveDateTime,
+    max_age: Duration,
+    saved: Instant,
+    unsaved: usize,
+}
+
+impl ScanCheckpoint {
+    pub fn new(path: PathBuf, config: &Config, max_age: Duration) -> Self {
+        Self {
+            path,
+            fingerprint: config_fingerprint(config),
+            started: naive_now(),
+            max_age,
+            saved: Instant::now(),
+            unsaved: 0,
+        }
+    }
+
+    /// Continue the crawl from the saved checkpoint (if it is valid), returning the crawled files
+    pub fn resume(&mut self, crawler: FileCrawler) -> (FileCrawler, Vec<FileInfo>) {
+        let crawler = crawler.track_visited();
+        let (data, started) = match self.load() {
+            Some(v) => v,
+            None => return (crawler, vec![]),
+        };
+        let stale = data
+            .frontier
+            .stale_dirs(started - chrono::TimeDelta::seconds(CHECKPOINT_MARGIN));
+        let is_stale = |s: &str| stale.iter().any(|d| Path::new(s).starts_with(d));
+        let entries = data
+            .entries
+            .into_iter()
+            .filter(|(s, _, _, _)| !is_stale(s))
+            .map(|(s, time, size, ctime)| {
+                let mut fi = FileInfo::from_both(string_to_path(&s), s);
+                fi.time = time.and_then(from_micros);
+                fi.size = size;
+                fi.ctime = ctime.and_then(from_micros);
+                fi
+            })
+            .collect();
+        self.started = started;
+        (crawler.resume(data.frontier, &stale), entries)
+    }
+
+    /// Read the checkpoint (if it exists, is for the same config, and is recent enough)
+    fn load(&self) -> Option<(CheckpointData, NaiveDateTime)> {
+        let file = File::open(&self.path).ok()?;
+        let data: CheckpointData = serde_json::from_reader(BufReader::new(file)).ok()?;
+        let started = from_micros(data.started)?;
+        let recent = (naive_now() - started)
+            .to_std()
+            .is_ok_and(|age| age <= self.max_age);
+        if data.fingerprint == self.fingerprint && recent {
+            Some((data, started))
+        } else {
+            None
+        }
+    }
+
+    /// Save the progress of the crawl (replacing the previous checkpoint)
+    pub fn save(
+        &mut self,
+        crawler: &FileCrawler,
+        list: &[(bool, FileInfo)],
+    ) -> std::io::Result<()> {
+        self.saved = Instant::now();
+        self.unsaved = 0;
+        let data = CheckpointData {
+            fingerprint: self.fingerprint,
+            started: to_micros(self.started),
+            frontier: crawler.frontier(),
+            entries: list
+                .iter()
+                .map(|(_, fi)| {
+                    (
+                        fi.copy_string().to_string(),
+                        fi.time.map(to_micros),
+                        fi.size,
+                        fi.ctime.map(to_micros),
+                    )
+                })
+                .collect(),
+        };
+        // Write to a temporary file first, so that an interruption does not corrupt the checkpoint
+        let tmp = extend_pathbuf(self.path.clone(), ".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, &data)?;
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    /// Save the progress if enough time has passed (or enough files have been crawled)
+    fn tick(&mut self, crawler: &FileCrawler, list: &[(bool, FileInfo)]) {
+        self.unsaved += 1;
+        if self.unsaved >= CHECKPOINT_FILES || self.saved.elapsed() >= CHECKPOINT_INTERVAL {
+            // The checkpoint is only an optimisation (failing to save it is not an error)
+            #[allow(unused_must_use)]
+            self.save(crawler, list);
+        }
+    }
+
+    /// Remove the saved checkpoint (e.g. after a successful backup)
+    pub fn remove(&self) {
+        #[allow(unused_must_use)]
+        std::fs::remove_file(&self.path);
+    }
+}
+
+/// Hash the parts of the config that affect a crawl
+fn config_fingerprint(config: &Config) -> u64 {
+    let cwd = if config.local {
+        None
+    } else {
+        std::env::current_dir().ok()
+    };
+    let key = serde_json::to_string(&(
+        &config.include,
+        &config.exclude,
+        &config.regex,
+        config.local,
+        config.include_empty_dirs,
+        config.use_gitignore,
+        config.max_depth,
+        cwd,
+    ))
+    .unwrap_or_default();
+    fnv1a(FNV_OFFSET, key.as_bytes())
+}
+
+fn to_micros(time: NaiveDateTime) -> i64 {
+    time.and_utc().timestamp_micros()
+}
+
+fn from_micros(micros: i64) -> Option<NaiveDateTime> {
+    DateTime::from_timestamp_micros(micros).map(|t| t.naive_utc())
+}
+
+/// Does the path start with a Windows drive (`C:\`) or network share (`\\`)
+fn is_windows_path(path: &str) -> bool {
+    let b = path.as_bytes();
+    (b.len() > 2 && b[0].is_ascii_alphabetic() && b[1] == b':' && b[2] == b'\\')
+        || path.starts_with("\\\\")
+}
+
+/// Escape the characters that would end the metadata column (`,` and newlines)
+fn encode_user_meta(meta: &str) -> Cow<'_, str> {
+    if meta.contains(['%', ',', '\n', '\r']) {
+        Cow::Owned(
+            meta.replace('%', "%25")
+                .replace(',', "%2C")
+                .replace('\n', "%0A")
+                .replace('\r', "%0D"),
+        )
+    } else {
+        Cow::Borrowed(meta)
+    }
+}
+
+/// Reverse [`encode_user_meta`]
+fn decode_user_meta(meta: &str) -> Cow<'_, str> {
+    if meta.contains('%') {
+        Cow::Owned(
+            meta.replace("%2C", ",")
+                .replace("%0A", "\n")
+                .replace("%0D", "\r")
+                .replace("%25", "%"),
+        )
+    } else {
+        Cow::Borrowed(meta)
+    }
+}
+
+/// Does the path contain control characters (that would break the lines of the list), or bytes
+/// that are not valid UTF-8 (see [`crate::files::path_to_string`])
+fn needs_escape(path: &str) -> bool {
+    path.contains(|c: char| c.is_control() || is_raw_char(c))
+}
+
+/// Percent-encode the control characters (and `%`) in a path for a version 5 list, the bytes that
+/// are not valid UTF-8 are stored as the bytes themselves
+fn escape_list_path(path: &str) -> Cow<'_, str> {
+    if !path.contains(|c: char| c == '%' || c.is_control() || is_raw_char(c)) {
+        return Cow::Borrowed(path);
+    }
+    let mut escaped = String::with_capacity(path.len() + 8);
+    let mut buf = [0; 4];
+    for c in raw_chars(path) {
+        match c {
+            RawChar::Byte(b) => escaped.push_str(&format!("%{:02X}", b)),
+            RawChar::Char(c) if c == '%' || c.is_control() => {
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    escaped.push_str(&format!("%{:02X}", b));
+                }
+            }
+            RawChar::Char(c) => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Reverse [`escape_list_path`] (invalid escapes are kept as they are)
+fn unescape_list_path(path: &str) -> Cow<'_, str> {
+    // The private use characters are stored unescaped (see [`crate::files::bytes_to_string`])
+    if !path.contains(|c: char| c == '%' || is_raw_char(c)) {
+        return Cow::Borrowed(path);
+    }
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    Cow::Owned(bytes_to_string(&out).into_owned())
+}
+
+/// Unescape the path (the last column) of a line from a version 5 list
+fn unescape_line(line: &str) -> Cow<'_, str> {
+    match line.match_indices(',').nth(3) {
+        Some((i, _)) => match unescape_list_path(&line[i + 1..]) {
+            Cow::Borrowed(_) => Cow::Borrowed(line),
+            Cow::Owned(path) => Cow::Owned(format!("{}{}", &line[..=i], path)),
+        },
+        None => Cow::Borrowed(line),
+    }
+}
+
+/// Show the control characters in a path as escapes (e.g. `\n`), so that printing the path does
+/// not break the lines of the output (and the bytes that are not valid UTF-8 as e.g. `\xff`)
+pub fn display_path(path: &str) -> Cow<'_, str> {
+    if !needs_escape(path) {
+        return Cow::Borrowed(path);
+    }
+    Cow::Owned(
+        raw_chars(path)
+            .map(|c| match c {
+                RawChar::Byte(b) => format!("\\x{:02x}", b),
+                RawChar::Char(c) if c.is_control() => c.escape_default().to_string(),
+                RawChar::Char(c) => c.to_string(),
+            })
+            .collect(),
+    )
+}
+
+/// Simple seeded pseudo random number generator
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use tempfile::tempdir;
+
+    use super::{
+        display_path, unescape_list_path, Entry, FileListString, FileListVec, ScanCheckpoint,
+        SignatureList, CHECKPOINT_MAX_AGE,
+    };
+    use crate::files::{string_to_path, BlockSignature, FileCrawler, FileInfo, MetadataUpdate};
+    use crate::stats::GroupSize;
+    use crate::Config;
+
+    #[test]
+    fn list_v3() {
+        let mut files = FileListVec::default();
+        let mut fi = FileInfo::from("a,b.txt");
+        fi.size = 12;
+        fi.hash = Some(0xabc);
+        files.push(true, fi);
+        files.push(false, FileInfo::from("c.txt"));
+        let list = FileListString::from(&mut files);
+        assert_eq!(list.filename(), "files_v3.csv");
+        let list = FileListString::new(list.filename(), list.list).unwrap();
+        assert_eq!(list.iter().collect::<Vec<_>>(), vec![
+            (true, "a,b.txt"),
+            (false, "c.txt")
+        ]);
+        assert_eq!(list.iter_included().collect::<Vec<_>>(), vec!["a,b.txt"]);
+        assert_eq!(list.iter_meta().collect::<Vec<_>>(), vec![
+            (true, Some(12), Some(0xabc), "a,b.txt"),
+            (false, Some(0), None, "c.txt")
+        ]);
+        let list = FileListString::new("files_v2.csv", "1,a.txt\n0,b.txt".to_string()).unwrap();
+        assert_eq!(list.iter_meta().collect::<Vec<_>>(), vec![
+            (true, None, None, "a.txt"),
+            (false, None, None, "b.txt")
+        ]);
+    }
+
+    #[test]
+    fn list_updates() {
+        let mut files = FileListVec::default();
+        let update = MetadataUpdate {
+            mode: 0o640,
+            uid: 1000,
+            gid: 100,
+        };
+        let mut fi = FileInfo::from("a.txt");
+        fi.update = Some(update);
+        files.push(false, fi);
+        // Included files are stored in full
+        let mut fi = FileInfo::from("b.txt");
+        fi.update = Some(update);
+        files.push(true, fi);
+        let list = FileListString::from(&mut files);
+        assert_eq!(list.list, "m640:1000:100,0,,a.txt\n1,0,,b.txt");
+        let list = FileListString::new(list.filename(), list.list).unwrap();
+        assert_eq!(list.iter().collect::<Vec<_>>(), vec![
+            (false, "a.txt"),
+            (true, "b.txt")
+        ]);
+        assert_eq!(list.iter_updates().collect::<Vec<_>>(), vec![(
+            update, "a.txt"
+        )]);
+    }
+
+    #[test]
+    fn list_generated() {
+        let mut files = FileListVec::default();
+        let mut fi = FileInfo::from("_commands/dump");
+        fi.size = 4;
+        fi.generated = true;
+        files.push(true, fi);
+        files.push(true, FileInfo::from("a.txt"));
+        let list = FileListString::from(&mut files);
+        assert_eq!(list.list, "c,4,,_commands/dump\n1,0,,a.txt");
+        let list = FileListString::new(list.filename(), list.list).unwrap();
+        assert_eq!(list.iter_included().collect::<Vec<_>>(), vec![
+            "_commands/dump",
+            "a.txt"
+        ]);
+        assert_eq!(list.iter_generated().collect::<Vec<_>>(), vec![
+            "_commands/dump"
+        ]);
+    }
+
+    #[test]
+    fn list_user_meta() {
+        let mut files = FileListVec::default();
+        let mut fi = FileInfo::from("_commands/dump");
+        fi.generated = true;
+        files.push(true, fi);
+        let mut fi = FileInfo::from("a,b.txt");
+        fi.user_meta = Some("note=1,2%;x=\n".to_string());
+        files.push(true, fi);
+        let mut fi = FileInfo::from("c.txt");
+        fi.update = Some(MetadataUpdate {
+            mode: 0o600,
+            uid: 1,
+            gid: 2,
+        });
+        files.push(false, fi);
+        let list = FileListString::from(&mut files);
+        assert_eq!(list.filename(), "files_v4.csv");
+        assert_eq!(
+            list.list,
+            "c,0,,,_commands/dump\n1,0,,note=1%2C2%25;x=%0A,a,b.txt\nm600:1:2,0,,,c.txt"
+        );
+        let list = FileListString::new(list.filename(), list.list).unwrap();
+        assert_eq!(list.iter().map(|(_, s)| s).collect::<Vec<_>>(), [
+            "_commands/dump",
+            "a,b.txt",
+            "c.txt"
+        ]);
+        assert_eq!(list.iter_generated().collect::<Vec<_>>(), [
+            "_commands/dump"
+        ]);
+        assert_eq!(list.iter_updates().map(|(_, s)| s).collect::<Vec<_>>(), [
+            "c.txt"
+        ]);
+        let meta: Vec<_> = list.iter_user_meta().collect();
+        assert_eq!(meta.len(), 1);
+        assert_eq!(meta[0].0, "a,b.txt");
+        assert_eq!(meta[0].1, "note=1,2%;x=\n");
+
+        // Lists without metadata stay readable by older versions
+        files.iter_mut().for_each(|(_, fi)| fi.user_meta = None);
+        assert_eq!(FileListString::from(&mut files).filename(), "files_v3.csv");
+    }
+
+    #[test]
+    fn list_escaped() {
+        let mut files = FileListVec::default();
+        files.push(true, FileInfo::from("a\nb.txt"));
+        let mut fi = FileInfo::from("100%\t.txt");
+        fi.user_meta = Some("x=1".to_string());
+        files.push(false, fi);
+        files.push(true, FileInfo::from("%0A.txt"));
+        let list = FileListString::from(&mut files);
+        assert_eq!(list.filename(), "files_v5.csv");
+        let stored = std::str::from_utf8(list.as_ref()).unwrap();
+        assert_eq!(
+            stored,
+            "1,0,,,a%0Ab.txt\n0,0,,x=1,100%25%09.txt\n1,0,,,%250A.txt"
+        );
+        let list = FileListString::new(list.filename(), stored.to_string()).unwrap();
+        assert_eq!(list.iter().collect::<Vec<_>>(), [
+            (true, "a\nb.txt"),
+            (false, "100%\t.txt"),
+            (true, "%0A.txt")
+        ]);
+        assert_eq!(list.iter_user_meta().next().unwrap().0, "100%\t.txt");
+        assert_eq!(std::str::from_utf8(list.as_ref()).unwrap(), stored);
+        assert_eq!(display_path("a\nb\u{1b}.txt"), "a\\nb\\u{1b}.txt");
+        assert_eq!(unescape_list_path("%zz%4"), "%zz%4");
+        // Bytes that are not valid UTF-8 are stored as they are
+        assert_eq!(unescape_list_path("a%FF%C3%A9"), "a\u{efff}\u{e9}");
+        assert_eq!(display_path("a\u{efff}.txt"), "a\\xff.txt");
+        // Names that really contain the private use characters are stored as they are
+        let mut fi = FileInfo::from(Path::new("a\u{ef80}\n.txt"));
+        let string = fi.get_string().clone();
+        let mut escaped = FileListVec::default();
+        escaped.push(true, fi);
+        let list = FileListString::from(&mut escaped);
+        assert_eq!(
+            std::str::from_utf8(list.as_ref()).unwrap(),
+            "1,0,,,a\u{ef80}%0A.txt"
+        );
+        let stored = "1,0,,,a\u{ef80}%0A.txt".to_string();
+        let list = FileListString::new(list.filename(), stored).unwrap();
+        assert_eq!(list.iter().next().unwrap().1, string);
+        assert_eq!(string_to_path(&string), Path::new("a\u{ef80}\n.txt"));
+        assert_eq!(display_path(&string), "a\u{ef80}\\n.txt");
+
+        // Lists without control characters are not escaped
+        files.retain_mut(|_, fi| !fi.get_string().contains(['\n', '\t']));
+        assert_eq!(FileListString::from(&mut files).filename(), "files_v3.csv");
+    }
+
+    #[test]
+    fn list_deleted() {
+        let mut files = FileListVec::default();
+        files.push(true, FileInfo::from("a.txt"));
+        files.push(false, FileInfo::from("b.txt"));
+        let mut list = FileListString::from(&mut files);
+        list.add_deleted(&["c.txt", "d\n.txt"]);
+        assert_eq!(
+            std::str::from_utf8(list.as_ref()).unwrap(),
+            "1,0,,a.txt\n0,0,,b.txt\nd,,,c.txt"
+        );
+        let list = FileListString::new(list.filename(), list.list).unwrap();
+        assert_eq!(list.iter().map(|(_, s)| s).collect::<Vec<_>>(), [
+            "a.txt", "b.txt"
+        ]);
+        assert_eq!(list.iter_meta().count(), 2);
+        assert_eq!(list.iter_deleted().collect::<Vec<_>>(), ["c.txt"]);
+
+        // Escaped lists also escape the deleted files
+        files.push(true, FileInfo::from("e\t.txt"));
+        let mut list = FileListString::from(&mut files);
+        list.add_deleted(&["d\n.txt"]);
+        let stored = std::str::from_utf8(list.as_ref()).unwrap().to_string();
+        assert!(stored.ends_with("\nd,,,,d%0A.txt"));
+        let list = FileListString::new(list.filename(), stored).unwrap();
+        assert_eq!(list.iter().count(), 3);
+        assert_eq!(list.iter_deleted().collect::<Vec<_>>(), ["d\n.txt"]);
+
+        // Only deleted files
+        let mut list = FileListString::from(&mut FileListVec::default());
+        list.add_deleted(&["a"]);
+        assert_eq!(list.iter().count(), 0);
+        assert_eq!(list.iter_deleted().collect::<Vec<_>>(), ["a"]);
+    }
+
+    #[test]
+    fn list_entries() {
+        let content = "1,3,,a\n0,4,,b\nc,5,,c".to_string();
+        let list = FileListString::new("files_v3.csv", content).unwrap();
+        assert_eq!(list.entries().collect::<Vec<_>>(), vec![
+            Entry {
+                path: "a",
+                size: Some(3),
+                time: None
+            },
+            Entry {
+                path: "c",
+                size: Some(5),
+                time: None
+            }
+        ]);
+        let list = FileListString::new("files_v2.csv", "1,a\n0,b".to_string()).unwrap();
+        assert_eq!(
+            list.entries().map(|e| (e.path, e.size)).collect::<Vec<_>>(),
+            vec![("a", None)]
+        );
+    }
+
+    #[test]
+    fn size_by_prefix() {
+        let root = if cfg!(windows) {
+            Path::new("C:\\data\\docs")
+        } else {
+            Path::new("/data/docs")
+        };
+        let mut files = FileListVec::default();
+        for (included, path, size) in [
+            (true, "a/x.txt", 10),
+            (true, "a/b/y.txt", 20),
+            (true, "c/z.txt", 5),
+            (true, "top.txt", 1),
+            (false, "c/big.bin", 1000),
+        ] {
+            let mut fi = FileInfo::from(root.join(path));
+            fi.size = size;
+            files.push(included, fi);
+        }
+        let group = |dir: &str, files: u64, size: u64| GroupSize {
+            name: root.join(dir).to_string_lossy().to_string(),
+            files,
+            size,
+        };
+        let top = GroupSize {
+            name: root.to_string_lossy().to_string(),
+            files: 1,
+            size: 1,
+        };
+        assert_eq!(files.size_by_prefix(1), vec![
+            group("a", 2, 30),
+            group("c", 1, 5),
+            top.clone()
+        ]);
+        assert_eq!(files.size_by_prefix(2), vec![
+            group(&format!("a{}b", std::path::MAIN_SEPARATOR), 1, 20),
+            group("a", 1, 10),
+            group("c", 1, 5),
+            top
+        ]);
+
+        // Local (relative) paths
+        let mut files = FileListVec::default();
+        files.push(true, FileInfo::from(Path::new("a").join("x.txt")));
+        files.push(true, FileInfo::from("b.txt"));
+        let names: Vec<String> = files
+            .size_by_prefix(1)
+            .into_iter()
+            .map(|g| g.name)
+            .collect();
+        assert_eq!(names, vec![String::from("."), String::from("a")]);
+    }
+
+    #[test]
+    fn list_windows() {
+        let content = "1,C:\\data\\a.txt\n0,C:\\data\\sub\\b.txt".to_string();
+        let list = FileListString::new("files_v2.csv", content).unwrap();
+        assert_eq!(list.iter().collect::<Vec<_>>(), vec![
+            (true, "C:/data/a.txt"),
+            (false, "C:/data/sub/b.txt")
+        ]);
+        let list = FileListString::new("files_v3.csv", "1,3,,\\\\nas\\x".to_string()).unwrap();
+        assert_eq!(list.iter_included().collect::<Vec<_>>(), vec!["//nas/x"]);
+        // Backslashes are valid in file names elsewhere
+        let list = FileListString::new("files_v3.csv", "1,3,,/a\\b".to_string()).unwrap();
+        assert_eq!(list.iter_included().collect::<Vec<_>>(), vec!["/a\\b"]);
+    }
+
+    #[test]
+    fn scan_checkpoint() -> std::io::Result<()> {
+        let dir = tempdir()?;
+        let out = tempdir()?;
+        let files: Vec<String> = ["a.txt", "b.txt", "c.txt"]
+            .iter()
+            .map(|f| dir.path().join(f).to_string_lossy().to_string())
+            .collect();
+        for f in files.iter() {
+            File::create(f)?;
+        }
+        let mut config = Config::new();
+        // Including files directly means that there are no directories that could be stale
+        config.include = files.clone();
+        let path = out.path().join("scan.json");
+        let crawler = |c: &Config| FileCrawler::new(&c.include, &c.exclude, &c.regex, false);
+
+        let mut cp = ScanCheckpoint::new(path.clone(), &config, CHECKPOINT_MAX_AGE);
+        let (mut fc, resumed) = cp.resume(crawler(&config)?);
+        assert!(resumed.is_empty());
+        let mut fi = fc.next().unwrap().unwrap();
+        fi.size = 12;
+        cp.save(&fc, &[(true, fi)])?;
+        assert!(path.exists());
+
+        let mut cp = ScanCheckpoint::new(path.clone(), &config, CHECKPOINT_MAX_AGE);
+        let (fc, resumed) = cp.resume(crawler(&config)?);
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(resumed[0].copy_string(), files[0]);
+        assert_eq!(resumed[0].size, 12);
+        assert!(resumed[0].time.is_some());
+        let rest: Vec<String> = fc.map(|fi| fi.unwrap().move_string()).collect();
+        assert_eq!(rest, files[1..].to_vec());
+
+        // Checkpoints for other configs (or too old checkpoints) are ignored
+        let mut other = config.clone();
+        other.exclude.push(files[2].clone());
+        let mut cp = ScanCheckpoint::new(path.clone(), &other, CHECKPOINT_MAX_AGE);
+        assert!(cp.resume(crawler(&other)?).1.is_empty());
+        std::thread::sleep(Duration::from_millis(10));
+        let mut cp = ScanCheckpoint::new(path.clone(), &config, Duration::from_millis(1));
+        assert!(cp.resume(crawler(&config)?).1.is_empty());
+
+        cp.remove();
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn signature_list() {
+        let mut list = SignatureList::default();
+        let signature = BlockSignature::from_reader([1u8, 2, 3].as_slice()).unwrap();
+        list.insert("/b,c.img", signature.clone());
+        list.insert("/a.img", BlockSignature::default());
+        list.insert("/new\nline.img", signature.clone());
+        let text = list.to_string();
+        assert!(text.starts_with("0,,/a.img\n"));
+        let parsed = SignatureList::parse(&format!("{}x,1,/invalid\n", text));
+        assert_eq!(parsed, list);
+        assert_eq!(parsed.get("/b,c.img"), Some(&signature));
+        assert_eq!(list.remove("/a.img"), Some(BlockSignature::default()));
+        assert_eq!(list.get("/a.img"), None);
+    }
+}
@@ -0,0 +1,396 @@
+/// This module contains the logger (writing to stderr and optionally a log file)
+/// and the progress output of the cli
+use std::cell::{Cell, RefCell};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+
+use crate::parse_date::naive_now;
+use crate::utils::settings_dir;
+
+/// The name of the log file that the GUI writes to (in the settings directory)
+pub const GUI_LOG_FILE_NAME: &str = "gui.log";
+
+/// How much to log
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LogLevel {
+    Off,
+    Error,
+    #[default]
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// The progress bar that is currently drawn (messages are printed above it)
+static PROGRESS: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Print log messages above the progress bar while the guard lives
+pub struct ProgressGuard;
+
+impl ProgressGuard {
+    pub fn new(bar: &ProgressBar) -> Self {
+        if let Ok(mut progress) = PROGRESS.lock() {
+            *progress = Some(bar.clone());
+        }
+        Self
+    }
+}
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        if let Ok(mut progress) = PROGRESS.lock() {
+            *progress = None;
+        }
+    }
+}
+
+/// How to show the progress of the cli commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ProgressFormat {
+    /// A progress bar in the terminal
+    #[default]
+    Bar,
+    /// Newline-delimited json events on stderr (for other programs)
+    Json,
+}
+
+/// The minimum time between json events (phase changes are always written)
+const JSON_INTERVAL: Duration = Duration::from_millis(200);
+
+/// One line of the json progress
+#[derive(Debug, Serialize)]
+pub struct ProgressEvent<'a> {
+    pub phase: &'a str,
+    pub current_files: u64,
+    pub total_files: u64,
+    pub current_bytes: u64,
+    pub total_bytes: u64,
+    pub message: &'a str,
+}
+
+/// The progress of a command, either as a bar or as json events (see [`ProgressFormat`])
+pub struct Progress {
+    bar: ProgressBar,
+    json: bool,
+    phase: Cell<&'static str>,
+    message: RefCell<String>,
+    files: Cell<u64>,
+    total_files: u64,
+    bytes: Cell<u64>,
+    total_bytes: Cell<u64>,
+    bytes_only: bool,
+    last: Cell<Option<Instant>>,
+}
+
+impl Progress {
+    /// The bar is drawn with the `template` (and the length is the sum of the files and bytes)
+    pub fn new(
+        format: ProgressFormat,
+        quiet: bool,
+        phase: &'static str,
+        total_files: u64,
+        total_bytes: u64,
+        template: &str,
+    ) -> Self {
+        let json = !quiet && format == ProgressFormat::Json;
+        let bar = if quiet || json {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(total_files + total_bytes)
+        };
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template(template)
+                .expect("The progressbar template is wrong!"),
+        );
+        Self {
+            bar,
+            json,
+            phase: Cell::new(phase),
+            message: RefCell::new(String::new()),
+            files: Cell::new(0),
+            total_files,
+            bytes: Cell::new(0),
+            total_bytes: Cell::new(total_bytes),
+            bytes_only: false,
+            last: Cell::new(None),
+        }
+    }
+
+    /// Only the bytes move the bar (the files are still counted in the json events)
+    pub fn bytes_only(mut self) -> Self {
+        self.bytes_only = true;
+        self.bar.set_length(self.total_bytes.get());
+        self
+    }
+
+    /// Print log messages above the bar while the guard lives
+    pub fn guard(&self) -> ProgressGuard {
+        ProgressGuard::new(&self.bar)
+    }
+
+    /// Start drawing the progress
+    pub fn start(&self, message: &str) {
+        self.set_phase(self.phase.get(), message);
+        self.bar.enable_steady_tick(Duration::from_secs(1));
+    }
+
+    /// Change the phase (this is always written as a json event)
+    pub fn set_phase(&self, phase: &'static str, message: &str) {
+        self.phase.set(phase);
+        self.set_message(message.to_string());
+        self.bar.tick();
+        self.emit(true);
+    }
+
+    /// Set the message (e.g. the current file)
+    pub fn set_message(&self, message: String) {
+        if self.json {
+            *self.message.borrow_mut() = message;
+        } else {
+            self.bar.set_message(message);
+        }
+    }
+
+    pub fn inc_files(&self, files: u64) {
+        self.files.set(self.files.get() + files);
+        if !self.bytes_only {
+            self.bar.inc(files);
+        }
+        self.emit(false);
+    }
+
+    pub fn inc_bytes(&self, bytes: u64) {
+        self.bytes.set(self.bytes.get() + bytes);
+        self.bar.inc(bytes);
+        self.emit(false);
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.get()
+    }
+
+    /// Change the total bytes (when the estimate changes)
+    pub fn set_total_bytes(&self, total_bytes: u64) {
+        self.total_bytes.set(total_bytes);
+        if self.bytes_only {
+            self.bar.set_length(total_bytes);
+        } else {
+            self.bar.set_length(self.total_files + total_bytes);
+        }
+    }
+
+    /// Stop with the "done" phase
+    pub fn finish(&self, message: &str) {
+        self.bar.disable_steady_tick();
+        self.set_phase("done", message);
+        self.bar.finish();
+    }
+
+    /// Stop with the "failed" phase (leaving the bar as is)
+    pub fn abandon(&self) {
+        self.bar.disable_steady_tick();
+        self.bar.abandon();
+        if self.json {
+            self.phase.set("failed");
+            self.emit(true);
+        }
+    }
+
+    /// The current progress as a line of json
+    pub fn event(&self) -> String {
+        let message = self.message.borrow();
+        let event = ProgressEvent {
+            phase: self.phase.get(),
+            current_files: self.files.get(),
+            total_files: self.total_files,
+            current_bytes: self.bytes.get(),
+            total_bytes: self.total_bytes.get(),
+            message: &message,
+        };
+        serde_json::to_string(&event).expect("Could not serialize the progress")
+    }
+
+    fn emit(&self, force: bool) {
+        if !self.json {
+            return;
+        }
+        let now = Instant::now();
+        if !force && self.last.get().is_some_and(|t| now - t < JSON_INTERVAL) {
+            return;
+        }
+        self.last.set(Some(now));
+        eprintln!("{}", self.event());
+    }
+}
+
+/// Writes warnings and errors to stderr, and everything up to the level to the log file (if any).
+/// Without a log file everything up to the level goes to stderr.
+struct Logger {
+    level: LevelFilter,
+    file: Option<Mutex<File>>,
+}
+
+impl Logger {
+    fn stderr_level(&self) -> LevelFilter {
+        if self.file.is_some() {
+            self.level.min(LevelFilter::Warn)
+        } else {
+            self.level
+        }
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "Error",
+        Level::Warn => "Warning",
+        Level::Info => "Info",
+        Level::Debug => "Debug",
+        Level::Trace => "Trace",
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= self.stderr_level() {
+            let line = format!("{}: {}", level_name(record.level()), record.args());
+            match PROGRESS.lock().ok().and_then(|p| p.clone()) {
+                Some(bar) if !bar.is_hidden() => bar.println(line),
+                _ => eprintln!("{}", line),
+            }
+        }
+        if let Some(file) = self.file.as_ref() {
+            if let Ok(mut file) = file.lock() {
+                let line = format!(
+                    "{} {:<5} [{}] {}\n",
+                    naive_now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                    record.level(),
+                    record.target(),
+                    record.args()
+                );
+                // There is nowhere to report a failing log file
+                #[allow(unused_must_use)]
+                file.write_all(line.as_bytes());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.file.as_ref() {
+            if let Ok(mut file) = file.lock() {
+                #[allow(unused_must_use)]
+                file.flush();
+            }
+        }
+    }
+}
+
+/// Start logging (appending to the log file if one is given)
+pub fn init(level: LogLevel, file: Option<&Path>) -> std::io::Result<()> {
+    let file = match file {
+        Some(path) => {
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)?;
+            }
+            Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            ))
+        }
+        None => None,
+    };
+    let level = LevelFilter::from(level);
+    // Only the first logger is used (e.g. in tests)
+    if log::set_boxed_logger(Box::new(Logger { level, file })).is_ok() {
+        log::set_max_level(level);
+    }
+    Ok(())
+}
+
+/// The log file of the GUI (in the settings directory)
+pub fn gui_log_path() -> Option<PathBuf> {
+    settings_dir().map(|p| p.join(GUI_LOG_FILE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use log::{Level, LevelFilter, Log, Record};
+    use tempfile::tempdir;
+
+    use super::{LogLevel, Logger, Progress, ProgressFormat};
+
+    #[test]
+    fn log_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.log");
+        let file = std::fs::File::create(&path).unwrap();
+        let logger = Logger {
+            level: LogLevel::Info.into(),
+            file: Some(std::sync::Mutex::new(file)),
+        };
+        assert_eq!(logger.stderr_level(), LevelFilter::Warn);
+        for (level, msg) in [(Level::Info, "first"), (Level::Debug, "second")] {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target("test")
+                    .args(format_args!("{}", msg))
+                    .build(),
+            );
+        }
+        logger.flush();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.ends_with("INFO  [test] first\n"));
+        assert!(!content.contains("second"));
+    }
+
+    #[test]
+    fn json_progress() {
+        let progress = Progress::new(ProgressFormat::Json, false, "backup", 2, 100, "{wide_msg}");
+        progress.set_message("a,\"b\"".to_string());
+        progress.inc_files(1);
+        progress.inc_bytes(40);
+        let event: serde_json::Value = serde_json::from_str(&progress.event()).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "phase": "backup",
+                "current_files": 1,
+                "total_files": 2,
+                "current_bytes": 40,
+                "total_bytes": 100,
+                "message": "a,\"b\"",
+            })
+        );
+        progress.finish("Backup completed!");
+        assert!(progress.event().starts_with("{\"phase\":\"done\""));
+    }
+}
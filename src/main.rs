@@ -2,43 +2,90 @@
 #[macro_use]
 mod utils;
 mod backup;
+mod browse;
 mod cli;
+mod commands;
 mod compression;
 mod config;
+mod file_types;
 mod files;
+mod gitignore;
 #[cfg(feature = "gui")]
 mod gui;
+mod help;
 mod lists;
+mod logging;
+mod manifest;
+mod options;
+mod overlap;
 mod parse_date;
+mod privileges;
+mod query;
+mod read_ahead;
+mod recent;
+mod stats;
+mod txn;
+mod xattrs;
 
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use backup::CONFIG_FILE_EXTENSION;
+use backup::{BackupError, Overwrite, CONFIG_FILE_EXTENSIONS};
 use chrono::NaiveDateTime;
+use clap::parser::ValueSource;
 #[allow(unused_imports)]
-use clap::{Args, CommandFactory, Parser, Subcommand};
-use config::Config;
-use utils::{get_backup_from_path, get_config_from_path};
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use compression::TarFormat;
+use config::{default_store_only, Config, ConfigFormat, ConfigPatch};
+use files::ChangeDetection;
+use logging::{LogLevel, ProgressFormat};
+use options::{BackupOptions, MergeOptions, RestoreOptions};
+use query::Query;
+use utils::{get_backup_from_path, get_backup_from_source, get_config_from_path, parse_size};
 
 #[derive(Parser)]
-#[clap(version, about, long_about = None, propagate_version = true, term_width = 0)]
+#[clap(
+    version,
+    about,
+    long_about = None,
+    propagate_version = true,
+    term_width = 0,
+    disable_help_subcommand = true
+)]
 struct Cli {
     #[clap(subcommand)]
     cmd: Option<Commands>,
+    /// Print the extended help (with notes and examples)
+    #[clap(long, global = true)]
+    #[allow(dead_code)] // Handled before parsing (so that required arguments can be left out)
+    help_long: bool,
+    /// Append the log messages to this file (the GUI logs to the settings directory by default)
+    #[clap(long, global = true, value_parser, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// How much to log (warnings and errors are also shown when logging to a file)
+    #[clap(long, global = true, value_enum, default_value_t, value_name = "LEVEL")]
+    log_level: LogLevel,
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
-    /// Create a config file (the options are added to the config file)
+    /// Create a config file, or update an existing one (the options are added to the config file)
     Config {
-        /// The path for the new config file
+        /// The path for the new config file (an existing config is updated in place)
         #[clap(value_parser = parse_config_path, value_name = "CONFIG")]
         path: PathBuf,
         #[clap(flatten)]
         config: ArgConfig,
-        /// Only display the output, don't write anything to disk
+        #[clap(flatten)]
+        patch: ArgPatch,
+        /// Only display the output (the changes to an existing config), don't write anything to disk
         #[clap(short, long)]
         dry: bool,
+        /// The arguments that were given on the command line (not defaults)
+        #[clap(skip)]
+        explicit: Vec<String>,
     },
     /// Backup using an existing config file
     Backup {
@@ -48,6 +95,9 @@ enum Commands {
         /// If doing an incremental backup, set the previous time to this
         #[clap(short, long, value_parser = parse_time, value_name = "TIME")]
         time: Option<NaiveDateTime>,
+        /// If doing an incremental backup, only include files changed since this backup (instead of the latest)
+        #[clap(long, value_parser, value_name = "PATH", conflicts_with = "time")]
+        base: Option<PathBuf>,
         /// Increase verbosity
         #[clap(short, long)]
         verbose: bool,
@@ -57,36 +107,160 @@ enum Commands {
         /// Only display the output, don't write anything to disk
         #[clap(short, long)]
         dry: bool,
+        /// Keep running and repeat the backup with this interval (e.g. "30m", "12h", or "1d")
+        #[clap(short, long, value_parser = parse_date::parse_duration, value_name = "INTERVAL", conflicts_with = "dry")]
+        watch: Option<Duration>,
+        /// Lower the cpu and io priority and pause between files (to keep the computer responsive)
+        #[clap(long)]
+        background: bool,
+        /// How to print the progress (`json` writes one event per line to stderr)
+        #[clap(
+            long,
+            value_enum,
+            default_value_t,
+            value_name = "FORMAT",
+            conflicts_with = "watch"
+        )]
+        progress: ProgressFormat,
+        /// Save the progress of the file scan, and resume an interrupted scan (less than a day old)
+        #[clap(long, conflicts_with = "watch")]
+        resume_scan: bool,
+        /// Write the list of files that would be backed up to this csv file
+        #[clap(long, value_parser, value_name = "PATH", requires = "dry")]
+        report: Option<PathBuf>,
+        /// Show the sizes of the directories (this many levels below the common root, default 1)
+        #[clap(long, value_name = "DEPTH", num_args = 0..=1, default_missing_value = "1", requires = "dry")]
+        summary: Option<usize>,
+        /// Write the files that were skipped due to errors to `<BACKUP>.errors.txt` (next to the backup)
+        #[clap(long, conflicts_with = "dry")]
+        write_errors: bool,
+        /// Fail the backup if a command from the config fails (instead of leaving out its output)
+        #[clap(long, conflicts_with = "dry")]
+        strict: bool,
+        /// Wait up to this many seconds for the output directory (or marker file) to appear
+        #[clap(long, value_parser, value_name = "SECONDS", conflicts_with = "dry")]
+        wait_for_output: Option<u64>,
+        /// Continue the chain of incremental backups even if the output directory has moved
+        #[clap(long, conflicts_with = "base")]
+        chain_across_moves: bool,
+        /// Switch to this user (when started as root) before crawling and writing the backup (Unix only)
+        #[clap(long, value_name = "USER", conflicts_with = "watch")]
+        run_as: Option<String>,
+        #[clap(flatten)]
+        overrides: ArgOverrides,
     },
     /// Restore from a backup
     Restore {
-        /// Path to the backup, backup directory, or config file
+        /// Path to the backup, backup directory, or config file (`-` for stdin)
         #[clap(value_parser, value_name = "PATH")]
         source: PathBuf,
         /// The directory to restore to (if not original)
         #[clap(short, long, value_parser, value_name = "PATH")]
         output: Option<PathBuf>,
-        /// Files to restore (if given then only these are restored)
+        /// Files or directories to restore (if given then only these are restored)
         #[clap(short, long, value_parser, value_name = "PATH")]
         include: Vec<String>,
         /// Use regex to specify which files to restore
         #[clap(short, long, value_parser, value_name = "REGEX")]
         regex: Vec<String>,
+        /// Restore paths starting with FROM to TO instead (the longest match is used)
+        #[clap(short, long, value_parser = parse_map, value_name = "FROM=TO")]
+        map: Vec<(String, String)>,
+        /// Remove this leading path from the files and restore the rest under the output
+        #[clap(
+            long,
+            value_name = "PATH",
+            requires = "output",
+            conflicts_with = "flatten"
+        )]
+        strip_prefix: Option<String>,
+        /// Only restore files of this type (based on the file extension)
+        #[clap(short = 'T', long = "type", value_enum, value_name = "TYPE")]
+        types: Vec<file_types::FileType>,
+        /// Only restore files matching this query (e.g. "size > 10M and path ~ '\.log$'")
+        #[clap(long = "where", value_name = "QUERY")]
+        query: Option<Query>,
         /// Remove the paths and restore all files to the same directory (if an output path is given)
         #[clap(short = 'F', long, value_parser, requires = "output")]
         flatten: bool,
         /// Only restore from the selected / latest backup even if it is incremental
         #[clap(short, long)]
         this: bool,
+        /// Look for previous backups in the original output directory if they are not next to the backup
+        #[clap(long, conflicts_with = "this")]
+        follow_origin: bool,
+        /// Increase verbosity
+        #[clap(short, long)]
+        verbose: bool,
+        /// Overwrite existing files
+        #[clap(short, long)]
+        force: bool,
+        /// Only overwrite existing files that are older than the files in the backup
+        #[clap(short = 'N', long, conflicts_with = "force")]
+        newer: bool,
+        /// Also delete the files that had been deleted before the backup was made
+        #[clap(long, conflicts_with = "flatten")]
+        mirror: bool,
+        /// Only display the output, don't write anything to disk
+        #[clap(short, long)]
+        dry: bool,
+        /// Lower the cpu and io priority and pause between files (to keep the computer responsive)
+        #[clap(long)]
+        background: bool,
+        /// How to print the progress (`json` writes one event per line to stderr)
+        #[clap(long, value_enum, default_value_t, value_name = "FORMAT")]
+        progress: ProgressFormat,
+        /// Append a manifest of the restored files to the output (or this) directory
+        #[clap(long, value_parser, value_name = "DIR", num_args = 0..=1)]
+        record_restore: Option<Option<PathBuf>>,
+        /// Write the user-defined metadata of the restored files to the output directory
+        #[clap(long, requires = "output")]
+        metadata_sidecar: bool,
+    },
+    /// Extract files from a backup into a directory (ignoring the original locations)
+    Extract {
+        /// Path to the backup, backup directory, or config file (`-` for stdin)
+        #[clap(value_parser, value_name = "PATH")]
+        source: PathBuf,
+        /// The directory to extract to
+        #[clap(value_parser = parse_output_dir, value_name = "DIR")]
+        output: PathBuf,
+        /// Files or directories to extract (if given then only these are extracted)
+        #[clap(short, long, value_parser, value_name = "PATH")]
+        include: Vec<String>,
+        /// Use regex to specify which files to extract
+        #[clap(short, long, value_parser, value_name = "REGEX")]
+        regex: Vec<String>,
+        /// Remove the paths and extract all files to the same directory
+        #[clap(short = 'F', long)]
+        flatten: bool,
+        /// Also extract files from previous incremental backups
+        #[clap(short = 'R', long)]
+        recursive: bool,
+        /// Look for previous backups in the original output directory if they are not next to the backup
+        #[clap(long, requires = "recursive")]
+        follow_origin: bool,
         /// Increase verbosity
         #[clap(short, long)]
         verbose: bool,
         /// Overwrite existing files
         #[clap(short, long)]
         force: bool,
+        /// Only overwrite existing files that are older than the files in the backup
+        #[clap(short = 'N', long, conflicts_with = "force")]
+        newer: bool,
         /// Only display the output, don't write anything to disk
         #[clap(short, long)]
         dry: bool,
+        /// Lower the cpu and io priority and pause between files (to keep the computer responsive)
+        #[clap(long)]
+        background: bool,
+        /// Append a manifest of the restored files to the output (or this) directory
+        #[clap(long, value_parser, value_name = "DIR", num_args = 0..=1)]
+        record_restore: Option<Option<PathBuf>>,
+        /// Write the user-defined metadata of the restored files to the output directory
+        #[clap(long, requires = "output")]
+        metadata_sidecar: bool,
     },
     /// Backup using command line arguments directly
     Direct {
@@ -95,6 +269,15 @@ enum Commands {
         /// If doing an incremental backup, use this as the previous time
         #[clap(short, long, value_parser = parse_time, value_name = "TIME", requires = "incremental")]
         time: Option<NaiveDateTime>,
+        /// If doing an incremental backup, only include files changed since this backup (instead of the latest)
+        #[clap(
+            long,
+            value_parser,
+            value_name = "PATH",
+            requires = "incremental",
+            conflicts_with = "time"
+        )]
+        base: Option<PathBuf>,
         /// Increase verbosity
         #[clap(short, long)]
         verbose: bool,
@@ -104,6 +287,24 @@ enum Commands {
         /// Only display the output, don't write anything to disk
         #[clap(short, long)]
         dry: bool,
+        /// Lower the cpu and io priority and pause between files (to keep the computer responsive)
+        #[clap(long)]
+        background: bool,
+        /// Save the progress of the file scan, and resume an interrupted scan (less than a day old)
+        #[clap(long)]
+        resume_scan: bool,
+        /// Write the list of files that would be backed up to this csv file
+        #[clap(long, value_parser, value_name = "PATH", requires = "dry")]
+        report: Option<PathBuf>,
+        /// Show the sizes of the directories (this many levels below the common root, default 1)
+        #[clap(long, value_name = "DEPTH", num_args = 0..=1, default_missing_value = "1", requires = "dry")]
+        summary: Option<usize>,
+        /// Write the files that were skipped due to errors to `<BACKUP>.errors.txt` (next to the backup)
+        #[clap(long, conflicts_with = "dry")]
+        write_errors: bool,
+        /// Also write the config to this file (combine with --dry to only write the config)
+        #[clap(long, value_parser = parse_config_path, value_name = "CONFIG")]
+        save_config: Option<PathBuf>,
     },
     /// Merge two backup archives
     Merge {
@@ -125,6 +326,18 @@ enum Commands {
         /// Number of worker threads (using threads requires more memory)
         #[clap(short='n', long, value_parser = parse_cpu, value_name = "NUM")]
         threads: Option<u32>,
+        /// Remove files that are missing from all backups from the merged list
+        #[clap(short, long)]
+        strict: bool,
+        /// Write a journal before renaming the backups (so that an interrupted merge can be recovered)
+        #[clap(long)]
+        journal: bool,
+        /// Only merge backups made at or after this time
+        #[clap(long, value_parser = parse_time, value_name = "TIME")]
+        after: Option<NaiveDateTime>,
+        /// Only merge backups made before this time
+        #[clap(long, value_parser = parse_time, value_name = "TIME")]
+        before: Option<NaiveDateTime>,
         /// Increase verbosity
         #[clap(short, long)]
         verbose: bool,
@@ -134,10 +347,31 @@ enum Commands {
         /// Only display the output, don't write anything to disk
         #[clap(short, long)]
         dry: bool,
+        /// How to print the progress (`json` writes one event per line to stderr)
+        #[clap(long, value_enum, default_value_t, value_name = "FORMAT")]
+        progress: ProgressFormat,
+    },
+    /// Delete files left behind by merges
+    Prune {
+        /// Path to the backup directory (or a config file or backup within it)
+        #[clap(value_parser, value_name = "PATH")]
+        source: PathBuf,
+        /// Delete `.old` backups that have been merged into a backup that still exists
+        #[clap(long, required = true)]
+        old_files: bool,
+        /// Only delete files older than this (e.g. "30d" or "12h")
+        #[clap(short, long, value_parser = parse_date::parse_duration, default_value = "30d", value_name = "AGE")]
+        age: Duration,
+        /// Increase verbosity
+        #[clap(short, long)]
+        verbose: bool,
+        /// Only display the output, don't delete anything
+        #[clap(short, long)]
+        dry: bool,
     },
     /// Inspect the metadata of a backup
     Inspect {
-        /// Path to the backup, backup directory, or config file
+        /// Path to the backup, backup directory, or config file (`-` for stdin)
         #[clap(value_parser, value_name = "PATH")]
         source: PathBuf,
         /// Output the config
@@ -146,24 +380,141 @@ enum Commands {
         /// Output the list of files
         #[clap(short, long)]
         list: bool,
+        /// Only output the files in the list that match this query (e.g. "size > 10M")
+        #[clap(long = "where", value_name = "QUERY", requires = "list")]
+        query: Option<Query>,
+    },
+    /// Summarise the contents of a backup
+    Stats {
+        /// Path to the backup, backup directory, or config file
+        #[clap(value_parser, value_name = "PATH")]
+        source: PathBuf,
+        /// Group the sizes by file extension or top-level directory
+        #[clap(short, long, value_enum, default_value_t)]
+        by: stats::StatsGroup,
+        /// The number of largest files to show
+        #[clap(short = 'n', long, default_value_t = 10, value_name = "N")]
+        top: usize,
+        /// Output as json
+        #[clap(short, long)]
+        json: bool,
+    },
+    /// Find files that are backed up by more than one config
+    Overlap {
+        /// Paths to the config files
+        #[clap(value_parser = parse_config_path, value_name = "CONFIG", num_args = 2.., required = true)]
+        configs: Vec<PathBuf>,
+    },
+    /// Compare two configs (and copy excludes between them)
+    ConfigDiff {
+        /// Path to the first config file
+        #[clap(value_parser = parse_config_path, value_name = "CONFIG")]
+        first: PathBuf,
+        /// Path to the second config file
+        #[clap(value_parser = parse_config_path, value_name = "CONFIG")]
+        second: PathBuf,
+        /// Also list the files in this directory that only one of the configs would backup
+        #[clap(short, long, value_parser, value_name = "DIR")]
+        sample: Option<PathBuf>,
+        /// Add the excludes and regexes that are only in the first config to the second config
+        #[clap(long)]
+        copy_excludes: bool,
+    },
+    /// Show the restorations recorded with --record-restore
+    RestoreLog {
+        /// The directory with the manifest (or the manifest file)
+        #[clap(value_parser, value_name = "PATH")]
+        path: PathBuf,
+        /// List all files (not only the skipped and failed files)
+        #[clap(short, long)]
+        verbose: bool,
+    },
+    /// Show the chain of (incremental) backups
+    Info {
+        /// Path to the backup, backup directory, or config file
+        #[clap(value_parser, value_name = "PATH")]
+        source: PathBuf,
+        /// Output as json
+        #[clap(short, long)]
+        json: bool,
+        /// Show the times as dates (instead of e.g. "2 days ago")
+        #[clap(short, long)]
+        absolute: bool,
+    },
+    /// Show how much unique data each backup in a chain contributes
+    Usage {
+        /// Path to the (newest) backup, backup directory, or config file
+        #[clap(value_parser, value_name = "PATH")]
+        source: PathBuf,
+        /// Print a table or json (for scripts)
+        #[clap(short, long, value_enum, default_value_t)]
+        format: stats::OutputFormat,
+    },
+    /// List the backed up files that no longer exist on disk
+    Check {
+        /// Path to the backup, backup directory, or config file (the latest backup is checked)
+        #[clap(value_parser, value_name = "PATH")]
+        source: PathBuf,
+    },
+    /// Browse the files in a backup with shell-like commands (`ls`, `cd`, `cat`, `get`, `find`)
+    Browse {
+        /// Path to the backup, backup directory, or config file
+        #[clap(value_parser, value_name = "PATH")]
+        source: PathBuf,
+        /// Run this command and exit (instead of reading commands interactively)
+        #[clap(
+            value_name = "COMMAND",
+            trailing_var_arg = true,
+            allow_hyphen_values = true
+        )]
+        command: Vec<String>,
     },
     #[cfg(feature = "gui")]
     /// Start a graphical user interface
-    Gui,
+    Gui {
+        /// Open this screen directly
+        #[clap(short, long, value_enum)]
+        screen: Option<gui::Screen>,
+        /// Preload a config file, backup, or backup directory (requires --screen)
+        #[clap(short, long, value_parser, value_name = "PATH", requires = "screen")]
+        path: Option<PathBuf>,
+    },
+    /// Print the extended help (with notes and examples) for a command
+    Help {
+        /// The command to describe
+        #[clap(value_name = "COMMAND")]
+        command: Option<String>,
+    },
+    /// Write man pages for all commands
+    #[clap(hide = true)]
+    GenerateMan {
+        /// The directory to write the pages to
+        #[clap(value_parser, value_name = "DIR", default_value = ".")]
+        dir: PathBuf,
+    },
 }
 
 #[derive(Args)]
 struct ArgConfig {
     /// Paths (file or directory) to include in the backup
-    #[clap(short, long, value_parser, value_name = "PATH", required = true)]
+    #[clap(short, long, value_parser, value_name = "PATH")]
     include: Vec<String>,
+    /// Paths to include that must contain files to backup (otherwise the backup fails)
+    #[clap(short = 'R', long, value_parser, value_name = "PATH")]
+    required: Vec<String>,
     /// Paths (file or directory) to exclude from the backup
     #[clap(short, long, value_parser, value_name = "PATH")]
     exclude: Vec<String>,
     /// Use regex to specify exclusion filters
     #[clap(short, long, value_parser, value_name = "REGEX")]
     regex: Vec<String>,
-    /// Where should the backup be stored (either a direcory or a file ending in `.tar.zst`)
+    /// Read more paths to exclude from a file (one per line, `#` for comments)
+    #[clap(long, value_parser, value_name = "FILE")]
+    exclude_from: Option<PathBuf>,
+    /// Read more exclusion regexes from a file (one per line, `#` for comments)
+    #[clap(long, value_parser, value_name = "FILE")]
+    regex_from: Option<PathBuf>,
+    /// Where should the backup be stored (either a direcory, a file ending in `.tar.zst`, or `-` for stdout)
     #[clap(short, long, value_parser, value_name = "PATH", default_value = ".")]
     output: PathBuf,
     /// Do an incremental backup (only backup files that have been modified)
@@ -181,12 +532,91 @@ struct ArgConfig {
     /// Number of worker threads (using threads requires more memory)
     #[clap(short='n', long, value_parser = parse_cpu, default_value_t = 1, value_name = "NUM")]
     threads: u32,
+    /// Double check unchanged files in incremental backups (compare sizes and hash a sample)
+    #[clap(short = 'P', long, requires = "incremental")]
+    paranoid: bool,
+    /// Percentage of unchanged files to hash when double checking
+    #[clap(long, value_parser = parse_percentage, default_value_t = 1.0, value_name = "PERCENT", requires = "paranoid")]
+    sample: f32,
+    /// Seed for selecting which unchanged files to hash (otherwise random)
+    #[clap(long, value_parser, value_name = "NUM", requires = "paranoid")]
+    seed: Option<u64>,
+    /// How many times to retry reading a file (e.g. locked by another program) before skipping it
+    #[clap(long, value_parser, default_value_t = 2, value_name = "NUM")]
+    retries: u32,
+    /// Milliseconds to wait before the first retry (doubled for every following retry)
+    #[clap(long, value_parser, default_value_t = 500, value_name = "MS")]
+    retry_delay: u64,
+    /// Read files (at most this many MiB) on separate threads while compressing (`0` to disable)
+    #[clap(long, value_parser, default_value_t = 32, value_name = "MB")]
+    read_ahead_mb: u64,
+    /// Split the backup into volumes of this size (e.g. "4000M" for FAT32 drives)
+    #[clap(long, value_parser = parse_volume_size, value_name = "SIZE")]
+    volume_size: Option<u64>,
+    /// Store empty directories (so that they are recreated when restoring)
+    #[clap(long)]
+    empty_dirs: bool,
+    /// Skip files that are ignored by `.gitignore` files (and the global git ignore file)
+    #[clap(long)]
+    gitignore: bool,
+    /// Only descend this many directories below each include (`1` only reads the includes)
+    #[clap(long, value_parser, value_name = "NUM")]
+    max_depth: Option<usize>,
+    /// Only backup files modified at or after this time (e.g. "2024-01-01")
+    #[clap(long, value_parser = parse_time, value_name = "TIME")]
+    newer_than: Option<NaiveDateTime>,
+    /// Only backup files modified before this time
+    #[clap(long, value_parser = parse_time, value_name = "TIME")]
+    older_than: Option<NaiveDateTime>,
+    /// Write a journal before renaming files (so that an interrupted rename can be recovered)
+    #[clap(long)]
+    journal: bool,
+    /// Store files with identical content only once (restored as separate copies)
+    #[clap(long)]
+    dedup: bool,
+    /// Which timestamp decides if a file has been modified (ctime also changes with permissions)
+    #[clap(long, value_enum, default_value_t = ChangeDetection::Mtime, value_name = "TIME")]
+    change_detection: ChangeDetection,
+    /// Only store the permissions and owner of files where only those have changed (by ctime)
+    #[clap(long)]
+    metadata_updates: bool,
+    /// Store large files matching this regex as the changed blocks in incremental backups
+    #[clap(long, value_parser, value_name = "REGEX")]
+    delta_files: Vec<String>,
+    /// Only store files of at least this size as changed blocks
+    #[clap(long, value_parser = parse_size, default_value = "64M", value_name = "SIZE")]
+    delta_min_size: u64,
+    /// Extensions of already compressed files that are compressed with a fast level (`""` for none)
+    #[clap(long, value_name = "EXT", value_delimiter = ',', default_values_t = default_store_only(), hide_default_value = true)]
+    store_only: Vec<String>,
+    /// The format of the tar headers (`gnu` is the format of older versions)
+    #[clap(long, value_enum, default_value_t = TarFormat::Pax, value_name = "FORMAT")]
+    tar_format: TarFormat,
+    /// Skip the AppleDouble `._*` files (macOS metadata that other file systems store separately)
+    #[clap(long)]
+    skip_apple_double: bool,
+    /// Only backup if the output directory already exists (e.g. on an external drive)
+    #[clap(long)]
+    require_output: bool,
+    /// Only backup if this file exists in the output directory (implies --require-output)
+    #[clap(long, value_parser, value_name = "FILE")]
+    marker_file: Option<PathBuf>,
+    /// Backup the output directory if it is inside an include (by default it is excluded)
+    #[clap(long)]
+    allow_output_inside: bool,
 }
 
 impl ArgConfig {
     fn into_config(self, time: Option<NaiveDateTime>) -> Config {
+        let mut include = self.include;
+        for r in self.required.iter() {
+            if !include.contains(r) {
+                include.push(r.clone());
+            }
+        }
         let mut conf = Config {
-            include: self.include,
+            include,
+            required: self.required,
             exclude: self.exclude,
             regex: self.regex,
             output: self.output,
@@ -195,15 +625,183 @@ impl ArgConfig {
             local: self.local,
             threads: self.threads,
             time,
+            paranoid_incremental: self.paranoid,
+            paranoid_sample: self.sample,
+            paranoid_seed: self.seed,
+            retries: self.retries,
+            retry_delay: self.retry_delay,
+            read_ahead_mb: self.read_ahead_mb,
+            volume_size: self.volume_size,
+            include_empty_dirs: self.empty_dirs,
+            use_gitignore: self.gitignore,
+            max_depth: self.max_depth,
+            newer_than: self.newer_than,
+            older_than: self.older_than,
+            journal: self.journal,
+            dedup: self.dedup,
+            change_detection: self.change_detection,
+            metadata_updates: self.metadata_updates,
+            delta_files: self.delta_files,
+            delta_min_size: self.delta_min_size,
+            merged: vec![],
+            store_only: self
+                .store_only
+                .into_iter()
+                .filter(|e| !e.is_empty())
+                .collect(),
+            tar_format: self.tar_format,
+            macos_xattrs: Default::default(),
+            exclude_from: self.exclude_from,
+            regex_from: self.regex_from,
+            commands: vec![],
+            metadata_command: None,
+            require_output: self.require_output || self.marker_file.is_some(),
+            marker_file: self.marker_file,
+            allow_output_inside: self.allow_output_inside,
+            resolved_output: None,
+            original_size: None,
             origin: PathBuf::new(),
+            migrations: vec![],
+            base: None,
+            chain_across_moves: false,
         };
         if self.default {
             conf.add_default_ignores();
         }
+        if self.skip_apple_double {
+            conf.add_apple_double_ignores();
+        }
         conf
     }
 }
 
+/// Changes to an existing config file (in addition to the includes, excludes, regexes, and
+/// settings from [`ArgConfig`] that were given on the command line)
+#[derive(Args)]
+struct ArgPatch {
+    /// Remove paths from the includes (when updating a config)
+    #[clap(long, value_parser, value_name = "PATH")]
+    remove_include: Vec<String>,
+    /// Remove paths from the excludes (when updating a config)
+    #[clap(long, value_parser, value_name = "PATH")]
+    remove_exclude: Vec<String>,
+    /// Remove regexes from the exclusion filters (when updating a config)
+    #[clap(long, value_parser, value_name = "REGEX")]
+    remove_regex: Vec<String>,
+    /// Do full backups (when updating a config)
+    #[clap(long, conflicts_with = "incremental")]
+    no_incremental: bool,
+    /// Convert to absolute paths (when updating a config)
+    #[clap(long, conflicts_with = "local")]
+    no_local: bool,
+}
+
+impl ArgPatch {
+    /// The settings from [`ArgConfig`] that can be changed in an existing config
+    const SETTINGS: [&'static str; 8] = [
+        "include",
+        "exclude",
+        "regex",
+        "output",
+        "quality",
+        "threads",
+        "incremental",
+        "local",
+    ];
+
+    /// Combine the explicitly given arguments into the changes to an existing config
+    /// (the error names an argument that cannot be used to update a config)
+    fn into_patch(self, config: ArgConfig, explicit: &[String]) -> Result<ConfigPatch, String> {
+        let given = |id: &str| explicit.iter().any(|e| e == id);
+        let settings = ArgConfig::augment_args(clap::Command::new("config"));
+        if let Some(arg) = settings
+            .get_arguments()
+            .map(|a| a.get_id().as_str())
+            .find(|id| given(id) && !Self::SETTINGS.contains(id))
+        {
+            return Err(format!(
+                "'--{}' cannot be changed in an existing config (edit the file instead)",
+                arg.replace('_', "-")
+            ));
+        }
+        let flag = |on: bool, off: bool| (on || off).then_some(on);
+        Ok(ConfigPatch {
+            include: config.include,
+            exclude: config.exclude,
+            regex: config.regex,
+            remove_include: self.remove_include,
+            remove_exclude: self.remove_exclude,
+            remove_regex: self.remove_regex,
+            output: given("output").then_some(config.output),
+            quality: given("quality").then_some(config.quality),
+            threads: given("threads").then_some(config.threads),
+            incremental: flag(config.incremental, self.no_incremental),
+            local: flag(config.local, self.no_local),
+        })
+    }
+
+    /// Are any of the arguments (that only update a config) given
+    fn is_given(&self) -> bool {
+        !self.remove_include.is_empty()
+            || !self.remove_exclude.is_empty()
+            || !self.remove_regex.is_empty()
+            || self.no_incremental
+            || self.no_local
+    }
+}
+
+/// Changes to a loaded config (only for this run, the config file is not modified)
+#[derive(Args)]
+struct ArgOverrides {
+    /// Add paths to include in the backup (for this run)
+    #[clap(long, value_parser, value_name = "PATH")]
+    include: Vec<String>,
+    /// Add paths to exclude from the backup (for this run)
+    #[clap(long, value_parser, value_name = "PATH")]
+    exclude: Vec<String>,
+    /// Add regexes for exclusion filters (for this run)
+    #[clap(long, value_parser, value_name = "REGEX")]
+    regex: Vec<String>,
+    /// Store the backup here instead (for this run)
+    #[clap(long, value_parser, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// Use this compression quality instead (for this run)
+    #[clap(long, value_parser = parse_quality, value_name = "NUM")]
+    quality: Option<i32>,
+    /// Use this number of worker threads instead (for this run)
+    #[clap(long, value_parser = parse_cpu, value_name = "NUM")]
+    threads: Option<u32>,
+    /// Do an incremental backup (for this run)
+    #[clap(long, conflicts_with = "no_incremental")]
+    incremental: bool,
+    /// Do a full backup (for this run)
+    #[clap(long, conflicts_with_all = ["time", "base"])]
+    no_incremental: bool,
+}
+
+impl ArgOverrides {
+    /// Apply the overrides (the config stored in the backup contains the changes)
+    fn apply(self, config: &mut Config) {
+        config.include.extend(self.include);
+        config.exclude.extend(self.exclude);
+        config.regex.extend(self.regex);
+        if let Some(output) = self.output {
+            config.output = output;
+        }
+        if let Some(quality) = self.quality {
+            config.quality = quality;
+        }
+        if let Some(threads) = self.threads {
+            config.threads = threads;
+        }
+        if self.incremental {
+            config.incremental = true;
+        } else if self.no_incremental {
+            config.incremental = false;
+        }
+    }
+}
+
 fn parse_cpu(s: &str) -> Result<u32, String> {
     let cpus = num_cpus::get() as u32;
     if let Ok(i) = s.parse::<u32>() {
@@ -223,6 +821,40 @@ fn parse_quality(s: &str) -> Result<i32, &'static str> {
     Err("Must be a number between 1-22!")
 }
 
+fn parse_percentage(s: &str) -> Result<f32, &'static str> {
+    if let Ok(f) = s.parse::<f32>() {
+        if (0.0..=100.0).contains(&f) {
+            return Ok(f);
+        }
+    }
+    Err("Must be a number between 0-100!")
+}
+
+fn parse_volume_size(s: &str) -> Result<u64, &'static str> {
+    match parse_size(s)? {
+        0 => Err("Must be a positive size (e.g. 4000M)"),
+        size => Ok(size),
+    }
+}
+
+fn parse_map(s: &str) -> Result<(String, String), &'static str> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err("Must be in the form FROM=TO"),
+    }
+}
+
+fn parse_output_dir(s: &str) -> Result<PathBuf, &'static str> {
+    let path = PathBuf::from(s);
+    if path.exists() && !path.is_dir() {
+        Err("The output must be a directory")
+    } else {
+        Ok(path)
+    }
+}
+
 fn parse_time(s: &str) -> Result<NaiveDateTime, &'static str> {
     parse_date::try_parse(s)?.ok_or("Missing time")
 }
@@ -232,84 +864,358 @@ fn parse_config(s: &str) -> Result<Config, String> {
 }
 
 fn parse_config_path(s: &str) -> Result<PathBuf, String> {
-    if s.ends_with(CONFIG_FILE_EXTENSION) {
+    if CONFIG_FILE_EXTENSIONS.iter().any(|e| s.ends_with(e)) {
         Ok(PathBuf::from(s))
     } else {
-        Err("The config file must end with".to_string() + CONFIG_FILE_EXTENSION)
+        Err("The config file must end with one of: ".to_string()
+            + &CONFIG_FILE_EXTENSIONS.join(", "))
+    }
+}
+
+/// The directory for the manifest from `--record-restore` (defaults to the output directory)
+fn record_dir(record: Option<Option<PathBuf>>, output: Option<&Path>) -> Option<PathBuf> {
+    record.map(|dir| {
+        dir.or_else(|| output.map(Path::to_path_buf))
+            .unwrap_or_else(|| PathBuf::from("."))
+    })
+}
+
+/// The subcommand to show the extended help for, if `--help-long` is given
+fn help_long_requested(args: &[OsString]) -> Option<Option<String>> {
+    if !args.iter().any(|a| a == "--help-long") {
+        return None;
+    }
+    let cli = Cli::command();
+    Some(
+        args.iter()
+            .skip(1)
+            .filter_map(|a| a.to_str())
+            .find(|a| cli.find_subcommand(a).is_some())
+            .map(String::from),
+    )
+}
+
+fn print_long_help(command: Option<&str>) -> Result<(), BackupError> {
+    match help::long_help(&Cli::command(), command) {
+        Some(text) => {
+            print!("{}", text);
+            Ok(())
+        }
+        None => Err(BackupError::GenericError(
+            "Unknown command (see `simple_backup help`)",
+        )),
     }
 }
 
+/// Parse the arguments (also noting which arguments to `config` were given explicitly)
+fn parse_cli<I, T>(args: I) -> Result<Cli, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let mut command = Cli::command();
+    let matches = command.try_get_matches_from_mut(args)?;
+    let mut cli = Cli::from_arg_matches(&matches).map_err(|e| e.format(&mut command))?;
+    if let (Some(Commands::Config { explicit, .. }), Some(("config", m))) =
+        (&mut cli.cmd, matches.subcommand())
+    {
+        *explicit = explicit_args(m);
+    }
+    Ok(cli)
+}
+
+/// The ids of the arguments that were given on the command line (instead of defaults)
+fn explicit_args(matches: &ArgMatches) -> Vec<String> {
+    matches
+        .ids()
+        .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+        .map(|id| id.to_string())
+        .collect()
+}
+
 fn main() {
-    let cli = Cli::parse();
+    let args: Vec<OsString> = std::env::args_os().collect();
+    if let Some(command) = help_long_requested(&args) {
+        if let Err(e) = print_long_help(command.as_deref()) {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+        return;
+    }
+    let cli = parse_cli(args).unwrap_or_else(|e| e.exit());
 
-    if cli.cmd.is_none() {
+    let gui = match &cli.cmd {
+        None => cfg!(feature = "gui"),
         #[cfg(feature = "gui")]
-        gui::gui(true);
-        #[cfg(not(feature = "gui"))]
-        Cli::command().print_help().unwrap();
-        return;
+        Some(Commands::Gui { .. }) => true,
+        Some(_) => false,
+    };
+    // The GUI has no console to show the errors in, so they are persisted by default
+    let log_file = match cli.log_file {
+        Some(path) => Some(path),
+        None if gui => logging::gui_log_path(),
+        None => None,
+    };
+    if let Err(e) = logging::init(cli.log_level, log_file.as_deref()) {
+        eprintln!("Error: Could not open the log file: {}", e);
+        #[allow(unused_must_use)]
+        logging::init(cli.log_level, None);
+    }
+
+    match cli.cmd {
+        None => {
+            #[cfg(feature = "gui")]
+            gui::gui(true, gui::InitialScreen::Main);
+            #[cfg(not(feature = "gui"))]
+            Cli::command().print_help().unwrap();
+        }
+        Some(cmd) => match run(cmd) {
+            Ok(_) => {}
+            Err(BackupError::Cancel) => {
+                log::warn!("Cancelled");
+                std::process::exit(130);
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        },
     }
+}
 
-    match cli.cmd.unwrap() {
+fn run(cmd: Commands) -> Result<(), BackupError> {
+    match cmd {
         Commands::Backup {
             mut config,
             time,
+            base,
             verbose,
             force,
             dry,
+            watch,
+            background,
+            progress,
+            resume_scan,
+            report,
+            summary,
+            write_errors,
+            strict,
+            wait_for_output,
+            chain_across_moves,
+            run_as,
+            overrides,
         } => {
+            overrides.apply(&mut config);
             if time.is_some() {
                 config.time = time;
             }
-            cli::backup(config, verbose, force, dry, false);
+            config.base = base;
+            config.chain_across_moves = chain_across_moves;
+            match watch {
+                Some(interval) => cli::watch(config, interval, verbose, force, false, background),
+                None => {
+                    let cancel = cli::cancel_on_ctrlc()?;
+                    cli::backup_with(config, &BackupOptions {
+                        verbose,
+                        force,
+                        dry,
+                        background,
+                        progress,
+                        resume_scan,
+                        report: report.as_deref(),
+                        summary,
+                        write_errors,
+                        strict,
+                        wait_for_output: wait_for_output.map(Duration::from_secs),
+                        run_as: run_as.as_deref(),
+                        cancel: Some(&cancel),
+                        ..Default::default()
+                    })
+                }
+            }
         }
         #[cfg(feature = "gui")]
-        Commands::Gui => {
-            gui::gui(false);
-        }
+        Commands::Gui { screen, path } => match gui::InitialScreen::from_args(screen, path) {
+            Ok(initial) => {
+                gui::gui(false, initial);
+                Ok(())
+            }
+            Err(e) => Cli::command()
+                .error(clap::error::ErrorKind::InvalidValue, e)
+                .exit(),
+        },
         Commands::Restore {
             source,
             output,
             include,
             regex,
+            map,
+            strip_prefix,
+            types,
+            query,
             flatten,
             this,
+            follow_origin,
             verbose,
             force,
+            newer,
+            mirror,
             dry,
+            background,
+            progress,
+            record_restore,
+            metadata_sidecar,
         } => {
-            cli::restore(
-                get_backup_from_path(source).expect("Could not find backup"),
-                output,
-                include,
-                regex,
-                flatten,
-                this,
-                force,
-                verbose,
-                dry,
-                false,
-            );
+            let record = record_dir(record_restore, output.as_deref());
+            cli::restore_with(
+                get_backup_from_source(source)?.with_follow_origin(follow_origin),
+                &RestoreOptions {
+                    output: output.as_deref(),
+                    include,
+                    regex,
+                    map,
+                    strip_prefix: strip_prefix.as_deref(),
+                    types,
+                    query: query.as_ref(),
+                    flatten,
+                    only_this: this,
+                    overwrite: Overwrite::from_flags(force, newer),
+                    mirror,
+                    verbose,
+                    dry,
+                    quiet: false,
+                    progress,
+                    background,
+                    record: record.as_deref(),
+                    metadata_sidecar,
+                    cancel: Some(&*cli::cancel_on_ctrlc()?),
+                },
+            )
+        }
+        Commands::Extract {
+            source,
+            output,
+            include,
+            regex,
+            flatten,
+            recursive,
+            follow_origin,
+            verbose,
+            force,
+            newer,
+            dry,
+            background,
+            record_restore,
+            metadata_sidecar,
+        } => {
+            let record = record_dir(record_restore, Some(&output));
+            cli::extract_with(
+                get_backup_from_source(source)?.with_follow_origin(follow_origin),
+                &RestoreOptions {
+                    output: Some(&output),
+                    include,
+                    regex,
+                    flatten,
+                    only_this: !recursive,
+                    overwrite: Overwrite::from_flags(force, newer),
+                    verbose,
+                    dry,
+                    background,
+                    record: record.as_deref(),
+                    metadata_sidecar,
+                    cancel: Some(&*cli::cancel_on_ctrlc()?),
+                    ..Default::default()
+                },
+            )
         }
-        Commands::Config { path, config, dry } => {
+        Commands::RestoreLog { path, verbose } => cli::restore_log(path, verbose),
+        Commands::Config {
+            path,
+            config,
+            patch,
+            dry,
+            explicit,
+        } => {
+            if path.exists() {
+                let patch = match patch.into_patch(config, &explicit) {
+                    Ok(p) => p,
+                    Err(e) => Cli::command()
+                        .error(clap::error::ErrorKind::ArgumentConflict, e)
+                        .exit(),
+                };
+                let before = std::fs::read_to_string(&path)?;
+                let (config, after) =
+                    Config::merge_patch(&before, ConfigFormat::from_path(&path), &patch)?;
+                if let Err(e) = cli::check_config(&config, false) {
+                    Cli::command()
+                        .error(clap::error::ErrorKind::ValueValidation, e)
+                        .exit();
+                }
+                if dry {
+                    print!("{}", utils::unified_diff(&before, &after));
+                } else {
+                    std::fs::write(&path, after)?;
+                }
+                return Ok(());
+            }
+            if patch.is_given() {
+                Cli::command()
+                    .error(
+                        clap::error::ErrorKind::ArgumentConflict,
+                        "The '--remove-*' and '--no-*' arguments only update an existing config",
+                    )
+                    .exit();
+            }
             let mut config = config.into_config(None);
+            if let Err(e) = cli::check_config(&config, false) {
+                Cli::command()
+                    .error(clap::error::ErrorKind::ValueValidation, e)
+                    .exit();
+            }
             if dry {
-                println!("{}", config.as_yaml().expect("Could not serialise config"));
+                println!("{}", config.as_format(ConfigFormat::from_path(&path))?);
             } else {
-                config
-                    .write_yaml(path, true)
-                    .expect("Could not serialise config");
+                config.write_file(path, true)?;
             }
+            Ok(())
         }
         Commands::Direct {
             config,
             time,
+            base,
             verbose,
             force,
             dry,
+            background,
+            resume_scan,
+            report,
+            summary,
+            write_errors,
+            save_config,
         } => {
-            let config = config.into_config(time);
-            cli::backup(config, verbose, force, dry, false);
+            let mut config = config.into_config(time);
+            if let Some(path) = save_config {
+                if let Err(e) = cli::check_config(&config, false) {
+                    Cli::command()
+                        .error(clap::error::ErrorKind::ValueValidation, e)
+                        .exit();
+                }
+                config.write_file(path, true)?;
+            }
+            config.base = base;
+            let cancel = cli::cancel_on_ctrlc()?;
+            cli::backup_with(config, &BackupOptions {
+                verbose,
+                force,
+                dry,
+                background,
+                resume_scan,
+                report: report.as_deref(),
+                summary,
+                write_errors,
+                cancel: Some(&cancel),
+                ..Default::default()
+            })
         }
         Commands::Merge {
             output,
@@ -321,20 +1227,254 @@ fn main() {
             delete,
             quality,
             threads,
-        } => cli::merge(
-            backups, output, all, delete, quality, threads, verbose, force, dry, false,
-        ),
+            strict,
+            journal,
+            after,
+            before,
+            progress,
+        } => cli::merge(backups, &MergeOptions {
+            output: output.as_deref(),
+            all,
+            delete,
+            quality,
+            threads,
+            strict,
+            journal,
+            after,
+            before,
+            verbose,
+            force,
+            dry,
+            quiet: false,
+            progress,
+            cancel: Some(&*cli::cancel_on_ctrlc()?),
+        }),
+        Commands::Stats {
+            source,
+            by,
+            top,
+            json,
+        } => cli::stats(get_backup_from_path(source)?, by, top, json, false),
+        Commands::Info {
+            source,
+            json,
+            absolute,
+        } => cli::info(source, json, absolute, false),
+        Commands::Usage { source, format } => {
+            cli::usage(get_backup_from_path(source)?, format, false)
+        }
+        Commands::Check { source } => cli::check(get_backup_from_path(source)?, false),
+        Commands::Browse { source, command } => cli::browse(get_backup_from_path(source)?, command),
+        Commands::Overlap { configs } => cli::overlap(configs, false),
+        Commands::ConfigDiff {
+            first,
+            second,
+            sample,
+            copy_excludes,
+        } => cli::config_diff(first, second, sample, copy_excludes, false),
         Commands::Inspect {
             source,
             config,
             list,
-        } => {
-            cli::inspect(
-                get_backup_from_path(source).expect("Could not find backup"),
-                config,
-                list,
-                false,
+            query,
+        } => cli::inspect(
+            get_backup_from_source(source)?,
+            config,
+            list,
+            query.as_ref(),
+            false,
+        ),
+        Commands::Prune {
+            source,
+            old_files: _,
+            age,
+            verbose,
+            dry,
+        } => cli::prune_old_files(source, age, verbose, dry, false),
+        Commands::Help { command } => print_long_help(command.as_deref()),
+        Commands::GenerateMan { dir } => {
+            for path in help::write_man_pages(&Cli::command(), &dir)? {
+                println!("{}", path.to_string_lossy());
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::error::ErrorKind;
+    use clap::{CommandFactory, Parser};
+    use tempfile::tempdir;
+
+    use super::{help, parse_cli, Cli, Commands, Config};
+
+    #[test]
+    fn long_help_covers_all_args() {
+        let cli = Cli::command();
+        for sub in cli.get_subcommands() {
+            let help = help::find(sub.get_name())
+                .unwrap_or_else(|| panic!("No extended help for '{}'", sub.get_name()));
+            for arg in sub.get_arguments() {
+                let id = arg.get_id().as_str();
+                if !help::BUILTIN_ARGS.contains(&id) {
+                    assert!(
+                        help.arg(id).is_some(),
+                        "No long help for '{}' in '{}'",
+                        id,
+                        sub.get_name()
+                    );
+                }
+            }
+        }
+        for help in help::COMMANDS {
+            if cfg!(feature = "gui") || help.name != "gui" {
+                assert!(
+                    help::long_help(&cli, Some(help.name)).is_some(),
+                    "Extended help for unknown command '{}'",
+                    help.name
+                );
+            }
+        }
+        assert!(help::long_help(&cli, None).is_some());
+        assert!(help::long_help(&cli, Some("unknown")).is_none());
+    }
+
+    #[test]
+    fn long_help_examples_parse() {
+        for help in help::COMMANDS {
+            if !cfg!(feature = "gui") && help.name == "gui" {
+                continue;
+            }
+            for example in help.examples {
+                let argv = example.argv();
+                assert_eq!(argv[1], help.name);
+                match Cli::command().try_get_matches_from(&argv) {
+                    Ok(_) => {}
+                    // The value parsers might look at the file system (or the number of cpus)
+                    Err(e) if e.kind() == ErrorKind::ValueValidation => {}
+                    Err(e) => panic!("Invalid example '{}': {}", example.args, e),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn man_pages() {
+        let dir = tempdir().unwrap();
+        let pages = help::write_man_pages(&Cli::command(), dir.path()).unwrap();
+        assert!(pages.len() > 10);
+        let page = std::fs::read_to_string(dir.path().join("simple_backup-restore.1")).unwrap();
+        assert!(page.contains(".TH SIMPLE_BACKUP-RESTORE 1"));
+        assert!(page.contains("\\-\\-this"));
+        // The long descriptions of the extended help replace the short ones
+        assert!(page.contains("The path must be written as it is stored in the backup"));
+        assert!(page.contains(".SH EXAMPLES"));
+        assert!(!dir.path().join("simple_backup-generate-man.1").exists());
+    }
+
+    #[test]
+    fn backup_overrides() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config.yml");
+        let mut config = Config::new();
+        config.include = vec!["a".into()];
+        config.write_file(&path, false).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let args = [
+            "simple_backup",
+            "backup",
+            &path,
+            "--include",
+            "b",
+            "--regex",
+            "x",
+            "--quality",
+            "3",
+            "--no-incremental",
+        ];
+        match Cli::try_parse_from(args).unwrap().cmd {
+            Some(Commands::Backup {
+                mut config,
+                overrides,
+                ..
+            }) => {
+                overrides.apply(&mut config);
+                assert_eq!(config.include, ["a", "b"]);
+                assert_eq!(config.regex, ["x"]);
+                assert_eq!(config.quality, 3);
+                assert_eq!(config.threads, 4);
+                assert!(!config.incremental);
+            }
+            _ => panic!("Not a backup"),
+        }
+        for conflict in [["--no-incremental", "--incremental"], [
+            "--no-incremental",
+            "--time=2024-01-01",
+        ]] {
+            let args = ["simple_backup", "backup", &path, conflict[0], conflict[1]];
+            assert_eq!(
+                Cli::try_parse_from(args).err().map(|e| e.kind()),
+                Some(ErrorKind::ArgumentConflict)
             );
         }
     }
+
+    #[test]
+    fn config_patch() {
+        let args = [
+            "simple_backup",
+            "config",
+            "config.yml",
+            "-i",
+            "b",
+            "--remove-include",
+            "a",
+            "-q",
+            "3",
+            "--no-local",
+        ];
+        match parse_cli(args).unwrap().cmd {
+            Some(Commands::Config {
+                config,
+                patch,
+                explicit,
+                ..
+            }) => {
+                let patch = patch.into_patch(config, &explicit).unwrap();
+                assert_eq!(patch.include, ["b"]);
+                assert_eq!(patch.remove_include, ["a"]);
+                assert_eq!(patch.quality, Some(3));
+                assert_eq!(patch.local, Some(false));
+                // Not given (only the defaults)
+                assert_eq!(patch.output, None);
+                assert_eq!(patch.threads, None);
+                assert_eq!(patch.incremental, None);
+            }
+            _ => panic!("Not a config"),
+        }
+        let args = ["simple_backup", "config", "config.yml", "--gitignore"];
+        match parse_cli(args).unwrap().cmd {
+            Some(Commands::Config {
+                config,
+                patch,
+                explicit,
+                ..
+            }) => {
+                assert!(patch.into_patch(config, &explicit).is_err());
+            }
+            _ => panic!("Not a config"),
+        }
+        let args = [
+            "simple_backup",
+            "config",
+            "config.yml",
+            "-I",
+            "--no-incremental",
+        ];
+        assert_eq!(
+            parse_cli(args).err().map(|e| e.kind()),
+            Some(ErrorKind::ArgumentConflict)
+        );
+    }
 }
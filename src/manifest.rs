@@ -0,0 +1,286 @@
+/// This module contains the manifests that record which files have been restored (for auditing)
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::backup::{BackupError, BackupReader, Overwrite};
+use crate::files::FileInfo;
+use crate::parse_date;
+use crate::parse_date::naive_now;
+use crate::stats::resolve_sources;
+
+/// The name of the manifest (in the output directory of the restoration)
+pub const MANIFEST_FILE_NAME: &str = "simple_backup.restore-manifest.yml";
+
+/// What happened to a file when restoring
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RestoreOutcome {
+    Restored,
+    /// An existing file was kept (see [`Overwrite`])
+    Skipped,
+    Failed,
+}
+
+/// A file in a [`RestoreRecord`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoredFile {
+    /// The path in the backup
+    pub entry: String,
+    /// Where the file was restored (empty if the file was not found)
+    pub path: String,
+    /// The backup that stores the file (in an incremental chain)
+    pub source: String,
+    /// The modification time from the backup
+    #[serde(with = "parse_date")]
+    pub mtime: Option<NaiveDateTime>,
+    pub outcome: RestoreOutcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The options that the restoration was made with
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoreParameters {
+    pub output: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub regex: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub map: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    pub flatten: bool,
+    pub only_this: bool,
+    pub overwrite: Overwrite,
+}
+
+/// One restoration (a manifest contains one record for every restoration)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoreRecord {
+    /// When the restoration was made
+    #[serde(with = "parse_date")]
+    pub time: Option<NaiveDateTime>,
+    /// The restored backup
+    pub backup: String,
+    /// Was the restoration interrupted (the files that were not reached are not listed)
+    pub partial: bool,
+    pub parameters: RestoreParameters,
+    pub files: Vec<RestoredFile>,
+}
+
+impl RestoreRecord {
+    /// Count the files with the outcome
+    pub fn count(&self, outcome: RestoreOutcome) -> usize {
+        self.files.iter().filter(|f| f.outcome == outcome).count()
+    }
+
+    /// Append the record to a manifest (as a new yaml document), and sync it to the disk
+    pub fn append<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let yaml =
+            serde_yaml::to_string(self).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(b"---\n")?;
+        file.write_all(yaml.as_bytes())?;
+        file.sync_all()
+    }
+}
+
+/// Collects the outcomes of a restoration into a [`RestoreRecord`]
+pub struct RestoreRecorder {
+    record: RestoreRecord,
+    /// The backup that stores each file (when restoring from an incremental chain)
+    sources: HashMap<String, String>,
+    /// The path in the backup for every restored path
+    entries: HashMap<PathBuf, String>,
+    /// The latest file given to the path transform (the errors do not contain the paths)
+    current: Option<(String, PathBuf)>,
+}
+
+impl RestoreRecorder {
+    pub fn new(
+        backup: String,
+        parameters: RestoreParameters,
+        sources: HashMap<String, String>,
+    ) -> Self {
+        Self {
+            record: RestoreRecord {
+                time: Some(naive_now()),
+                backup,
+                partial: false,
+                parameters,
+                files: vec![],
+            },
+            sources,
+            entries: HashMap::new(),
+            current: None,
+        }
+    }
+
+    /// Call with the path in the backup and the restored path (from the path transform)
+    pub fn transformed(&mut self, entry: &str, path: &Path) {
+        self.entries.insert(path.to_path_buf(), entry.to_string());
+        self.current = Some((entry.to_string(), path.to_path_buf()));
+    }
+
+    /// Call with the result of every file (from the restore callback)
+    pub fn outcome(&mut self, res: &mut std::io::Result<FileInfo>) {
+        let (entry, path, mtime, outcome, error) = match res {
+            Ok(fi) => match self.entries.get(fi.get_path()) {
+                Some(entry) => (
+                    entry.clone(),
+                    fi.get_path().to_path_buf(),
+                    fi.time,
+                    RestoreOutcome::Restored,
+                    None,
+                ),
+                None => return,
+            },
+            // The files that are not found are listed when finishing
+            Err(e) if e.kind() == ErrorKind::NotFound && self.current.is_none() => return,
+            Err(e) => {
+                let outcome = if e.kind() == ErrorKind::AlreadyExists {
+                    RestoreOutcome::Skipped
+                } else {
+                    RestoreOutcome::Failed
+                };
+                let (entry, path) = self.current.take().unwrap_or_default();
+                (entry, path, None, outcome, Some(e.to_string()))
+            }
+        };
+        self.current = None;
+        let source = match self.sources.get(&entry) {
+            Some(source) => source.clone(),
+            None => self.record.backup.clone(),
+        };
+        self.record.files.push(RestoredFile {
+            entry,
+            path: path.to_string_lossy().to_string(),
+            source,
+            mtime,
+            outcome,
+            error,
+        });
+    }
+
+    /// Finish the record, the selected files without an outcome were not found in the backup
+    /// (unless the restoration was interrupted)
+    pub fn finish<S: AsRef<str>>(mut self, selection: &[S], partial: bool) -> RestoreRecord {
+        self.record.partial = partial;
+        if !partial {
+            let recorded: HashSet<&str> =
+                self.record.files.iter().map(|f| f.entry.as_str()).collect();
+            let missing: Vec<RestoredFile> = selection
+                .iter()
+                .map(|s| s.as_ref())
+                .filter(|s| !recorded.contains(s))
+                .map(|s| RestoredFile {
+                    entry: s.to_string(),
+                    path: String::new(),
+                    source: String::new(),
+                    mtime: None,
+                    outcome: RestoreOutcome::Failed,
+                    error: Some("Not found in the backup".to_string()),
+                })
+                .collect();
+            self.record.files.extend(missing);
+        }
+        self.record
+    }
+}
+
+/// Which backup stores each file in the list of a backup (following the chain of incremental
+/// backups, see [`resolve_sources`])
+pub fn restore_sources(reader: &mut BackupReader) -> Result<HashMap<String, String>, BackupError> {
+    let mut readers = vec![];
+    let mut previous = reader.get_previous()?;
+    while let Some(mut prev) = previous {
+        prev.get_meta()?;
+        previous = prev.get_previous()?;
+        readers.push(prev);
+    }
+    let path = reader.path.copy_string().to_string();
+    let mut paths = vec![path];
+    paths.extend(readers.iter().map(|r| r.path.copy_string().to_string()));
+    let mut lists = vec![reader.get_list()?.iter().collect::<Vec<_>>()];
+    for r in readers.iter_mut() {
+        lists.push(r.get_list()?.iter().collect());
+    }
+    Ok(resolve_sources(&lists)
+        .into_iter()
+        .filter_map(|(s, i)| Some((s.to_string(), paths[i?].clone())))
+        .collect())
+}
+
+/// The path of the manifest, `path` is either a directory or the manifest itself
+pub fn manifest_path(path: &Path) -> PathBuf {
+    if path.is_dir() {
+        path.join(MANIFEST_FILE_NAME)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Read all records from a manifest (the oldest first)
+pub fn read_manifest<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<RestoreRecord>> {
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+    serde_yaml::Deserializer::from_str(&content)
+        .map(|doc| {
+            RestoreRecord::deserialize(doc).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use tempfile::tempdir;
+
+    use super::{
+        manifest_path, read_manifest, RestoreOutcome, RestoreParameters, RestoreRecord,
+        RestoredFile, MANIFEST_FILE_NAME,
+    };
+
+    #[test]
+    fn append() {
+        let dir = tempdir().unwrap();
+        let path = manifest_path(dir.path());
+        assert_eq!(path, dir.path().join(MANIFEST_FILE_NAME));
+        let file = |entry: &str, outcome: RestoreOutcome| RestoredFile {
+            entry: entry.to_string(),
+            path: format!("out/{}", entry),
+            source: "backup.tar.zst".to_string(),
+            mtime: None,
+            outcome,
+            error: (outcome != RestoreOutcome::Restored).then(|| "error".to_string()),
+        };
+        let mut record = RestoreRecord {
+            time: NaiveDate::from_ymd_opt(2024, 1, 2).and_then(|d| d.and_hms_opt(3, 4, 5)),
+            backup: "backup.tar.zst".to_string(),
+            partial: false,
+            parameters: RestoreParameters::default(),
+            files: vec![
+                file("a", RestoreOutcome::Restored),
+                file("b", RestoreOutcome::Skipped),
+            ],
+        };
+        record.append(&path).unwrap();
+        record.partial = true;
+        record.files.push(file("c", RestoreOutcome::Failed));
+        record.append(&path).unwrap();
+        let records = read_manifest(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(!records[0].partial);
+        assert_eq!(records[1], record);
+        assert_eq!(records[1].count(RestoreOutcome::Failed), 1);
+        assert_eq!(records[1].count(RestoreOutcome::Restored), 1);
+    }
+}
@@ -0,0 +1,131 @@
+/// This module contains the options for backing up, restoring, and merging (e.g. when embedding the library)
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+use crate::backup::Overwrite;
+use crate::file_types::FileType;
+use crate::logging::ProgressFormat;
+use crate::query::Query;
+
+/// Options for [`crate::cli::backup_with`] (the defaults do a normal backup with a progress bar)
+#[derive(Debug, Clone, Default)]
+pub struct BackupOptions<'a> {
+    /// Print the files to backup
+    pub verbose: bool,
+    /// Overwrite an existing backup with the same name
+    pub force: bool,
+    /// Only crawl for the files (without writing the backup)
+    pub dry: bool,
+    /// Do not print the progress
+    pub quiet: bool,
+    /// Print the progress as a bar or as json events
+    pub progress: ProgressFormat,
+    /// Lower the cpu and io priority and pause between files (see [`crate::utils::Background`])
+    pub background: bool,
+    /// Save the progress of the file scan, and resume an interrupted scan
+    pub resume_scan: bool,
+    /// Write the list of files to backup to this csv file
+    pub report: Option<&'a Path>,
+    /// Print the sizes of the directories this many levels below the common root of the files
+    /// (see [`crate::lists::FileListVec::size_by_prefix`])
+    pub summary: Option<usize>,
+    /// Write the files that were skipped due to errors to `<BACKUP>.errors.txt`
+    pub write_errors: bool,
+    /// Fail the backup if a command from the config fails (see [`crate::backup::BackupWriter::strict`])
+    pub strict: bool,
+    /// Wait (at most this long) for the output directory (and marker file) to appear,
+    /// e.g. an external drive that is mounted after boot (see [`crate::config::Config::missing_output`])
+    pub wait_for_output: Option<Duration>,
+    /// Switch to this user (from root) before crawling and writing the backup, Unix only
+    /// (see [`crate::privileges::drop_privileges`])
+    pub run_as: Option<&'a str>,
+    /// Setting this stops the backup with [`crate::backup::BackupError::Cancel`] (and removes the partial file)
+    pub cancel: Option<&'a AtomicBool>,
+}
+
+/// Options for [`crate::cli::restore_with`] and [`crate::cli::extract_with`]
+/// (the defaults restore all files to their original locations, without overwriting existing files)
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions<'a> {
+    /// Restore into this directory instead of the original locations
+    pub output: Option<&'a Path>,
+    /// Only restore these files, or the files in these directories (as they are written in the backup)
+    pub include: Vec<String>,
+    /// Only restore the files that match these regexes
+    pub regex: Vec<String>,
+    /// Restore the files under the first path of a pair to the second path instead
+    pub map: Vec<(String, String)>,
+    /// Remove this leading path (after the `map`) and restore the rest under the `output`
+    /// (every selected file must start with the prefix)
+    pub strip_prefix: Option<&'a str>,
+    /// Only restore these types of files
+    pub types: Vec<FileType>,
+    /// Only restore the files that match this query
+    pub query: Option<&'a Query>,
+    /// Restore all files directly into the output directory (requires the `output`)
+    pub flatten: bool,
+    /// Only restore the files stored in this backup (not the unchanged files from previous backups)
+    pub only_this: bool,
+    /// What to do with existing files
+    pub overwrite: Overwrite,
+    /// Delete the selected files that had been deleted before the backup was made
+    /// (see [`crate::backup::BackupReader::get_deleted`]), so that the restored files mirror the
+    /// backup (cannot be combined with `flatten`)
+    pub mirror: bool,
+    /// Print the files to restore
+    pub verbose: bool,
+    /// Only print the files to restore (without writing anything)
+    pub dry: bool,
+    /// Do not print the progress
+    pub quiet: bool,
+    /// Print the progress as a bar or as json events
+    pub progress: ProgressFormat,
+    /// Lower the cpu and io priority and pause between files (see [`crate::utils::Background`])
+    pub background: bool,
+    /// Append a manifest of the restored files to this directory (see [`crate::manifest`])
+    pub record: Option<&'a Path>,
+    /// Write the user-defined metadata of the restored files to
+    /// [`crate::commands::METADATA_SIDECAR`] in the `output` directory
+    pub metadata_sidecar: bool,
+    /// Setting this stops the restoration with [`crate::backup::BackupError::Cancel`]
+    pub cancel: Option<&'a AtomicBool>,
+}
+
+/// Options for [`crate::cli::merge`] (the defaults replace the most recent backup with a merged
+/// backup, containing the files in the most recent backup, and rename the old backups)
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions<'a> {
+    /// Write the merged backup to this path (instead of replacing the most recent backup)
+    pub output: Option<&'a Path>,
+    /// Keep all files (not just those in the most recent backup)
+    pub all: bool,
+    /// Delete the old backups after the merge (instead of renaming them)
+    pub delete: bool,
+    /// Compression quality (1-22)
+    pub quality: Option<i32>,
+    /// Number of worker threads (using threads requires more memory)
+    pub threads: Option<u32>,
+    /// Remove files that are missing from all backups from the merged list
+    pub strict: bool,
+    /// Write a journal before renaming the backups (see [`crate::txn`])
+    pub journal: bool,
+    /// Only merge backups made at or after this time
+    pub after: Option<NaiveDateTime>,
+    /// Only merge backups made before this time
+    pub before: Option<NaiveDateTime>,
+    /// Print the backups to merge and the files in the merged backup
+    pub verbose: bool,
+    /// Overwrite an existing backup at the output
+    pub force: bool,
+    /// Only print the backups and files to merge (without writing anything)
+    pub dry: bool,
+    /// Do not print the progress
+    pub quiet: bool,
+    /// Print the progress as a bar or as json events
+    pub progress: ProgressFormat,
+    /// Setting this stops the merge with [`crate::backup::BackupError::Cancel`]
+    pub cancel: Option<&'a AtomicBool>,
+}
@@ -0,0 +1,247 @@
+/// This module contains the detection of configs that backup the same files
+use std::path::{Path, PathBuf};
+
+use path_absolutize::Absolutize;
+use path_clean::PathClean;
+use regex::RegexSet;
+
+use crate::config::Config;
+use crate::files::FileCrawler;
+use crate::utils::expand_path;
+
+/// The files covered by a config (as normalised paths)
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    /// The include lines (as written in the config) and the normalised paths
+    pub roots: Vec<(String, PathBuf)>,
+    pub excludes: Vec<PathBuf>,
+    pub filters: Vec<String>,
+    regex: Option<RegexSet>,
+}
+
+impl Coverage {
+    pub fn new(config: &Config) -> Self {
+        let normalise = |s: &str| -> PathBuf {
+            let path = PathBuf::from(expand_path(s));
+            match path.absolutize() {
+                Ok(p) => p.to_path_buf(),
+                Err(_) => path.clean(),
+            }
+        };
+        let filters: Vec<String> = config
+            .regex
+            .iter()
+            .filter(|s| !s.is_empty())
+            .cloned()
+            .collect();
+        Self {
+            roots: config
+                .include
+                .iter()
+                .map(|s| (s.clone(), normalise(s)))
+                .collect(),
+            excludes: config.exclude.iter().map(|s| normalise(s)).collect(),
+            regex: RegexSet::new(&filters).ok(),
+            filters,
+        }
+    }
+
+    /// Is the path (or directory) backed up by the config.
+    /// Includes take precedence over excludes and filters, which only apply below the include.
+    pub fn covers(&self, path: &Path) -> bool {
+        self.roots.iter().any(|(_, root)| {
+            path.starts_with(root)
+                && !self
+                    .excludes
+                    .iter()
+                    .any(|e| e != root && e.starts_with(root) && path.starts_with(e))
+                && (path == root
+                    || !self
+                        .regex
+                        .as_ref()
+                        .is_some_and(|r| r.is_match(&path.to_string_lossy())))
+        })
+    }
+}
+
+/// How two include roots overlap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapKind {
+    /// Both configs include the same path
+    Shared,
+    /// The include from the first config contains the include from the second
+    FirstContains,
+    /// The include from the second config contains the include from the first
+    SecondContains,
+}
+
+/// A directory (or file) that is backed up by two configs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overlap {
+    pub path: PathBuf,
+    pub kind: OverlapKind,
+    /// The responsible include line in the first config
+    pub first: String,
+    /// The responsible include line in the second config
+    pub second: String,
+}
+
+/// Find the include roots that are covered by both configs
+pub fn find_overlaps(first: &Coverage, second: &Coverage) -> Vec<Overlap> {
+    let mut overlaps = vec![];
+    for (l1, r1) in first.roots.iter() {
+        for (l2, r2) in second.roots.iter() {
+            let (path, kind) = if r1 == r2 {
+                (r1, OverlapKind::Shared)
+            } else if r2.starts_with(r1) {
+                (r2, OverlapKind::FirstContains)
+            } else if r1.starts_with(r2) {
+                (r1, OverlapKind::SecondContains)
+            } else {
+                continue;
+            };
+            if first.covers(path) && second.covers(path) {
+                overlaps.push(Overlap {
+                    path: path.clone(),
+                    kind,
+                    first: l1.clone(),
+                    second: l2.clone(),
+                });
+            }
+        }
+    }
+    overlaps.sort_by(|a, b| a.path.cmp(&b.path));
+    overlaps
+}
+
+/// The overlapping paths without the paths nested inside other overlaps (so that nothing is counted twice)
+fn top_level(overlaps: &[Overlap]) -> Vec<&Path> {
+    let mut paths: Vec<&Path> = vec![];
+    for o in overlaps.iter() {
+        if !paths.iter().any(|p| o.path.starts_with(p)) {
+            paths.push(&o.path);
+        }
+    }
+    paths
+}
+
+/// Estimate the size of the files that are backed up by both configs
+/// (by crawling the overlapping paths once, skipping files excluded or filtered by either config)
+pub fn duplicated_size(
+    first: &Coverage,
+    second: &Coverage,
+    overlaps: &[Overlap],
+) -> std::io::Result<u64> {
+    let paths = top_level(overlaps);
+    if paths.is_empty() {
+        return Ok(0);
+    }
+    let include: Vec<String> = paths
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    let exclude: Vec<String> = first
+        .excludes
+        .iter()
+        .chain(second.excludes.iter())
+        .filter(|e| !paths.iter().any(|p| p == e))
+        .map(|e| e.to_string_lossy().to_string())
+        .collect();
+    let filter: Vec<&String> = first.filters.iter().chain(second.filters.iter()).collect();
+    let crawler = FileCrawler::new(include, exclude, filter, false)?;
+    Ok(crawler.filter_map(|f| f.ok()).map(|f| f.size).sum())
+}
+
+/// The overlapping coverage between two configs
+#[derive(Debug, Clone)]
+pub struct ConfigOverlap {
+    /// Index of the first config
+    pub first: usize,
+    /// Index of the second config
+    pub second: usize,
+    pub overlaps: Vec<Overlap>,
+    /// Estimated size of the files that are backed up by both configs (in bytes)
+    pub size: u64,
+}
+
+/// Find the overlapping coverage between all pairs of configs
+pub fn find_config_overlaps(configs: &[Config]) -> std::io::Result<Vec<ConfigOverlap>> {
+    let coverage: Vec<Coverage> = configs.iter().map(Coverage::new).collect();
+    let mut result = vec![];
+    for (i, c1) in coverage.iter().enumerate() {
+        for (j, c2) in coverage.iter().enumerate().skip(i + 1) {
+            let overlaps = find_overlaps(c1, c2);
+            if !overlaps.is_empty() {
+                let size = duplicated_size(c1, c2, &overlaps)?;
+                result.push(ConfigOverlap {
+                    first: i,
+                    second: j,
+                    overlaps,
+                    size,
+                });
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{find_overlaps, top_level, Coverage, OverlapKind};
+    use crate::Config;
+
+    fn coverage(include: &[&str], exclude: &[&str], regex: &[&str]) -> Coverage {
+        let mut config = Config::new();
+        config.include = include.iter().map(|s| s.to_string()).collect();
+        config.exclude = exclude.iter().map(|s| s.to_string()).collect();
+        config.regex = regex.iter().map(|s| s.to_string()).collect();
+        Coverage::new(&config)
+    }
+
+    #[test]
+    fn covers() {
+        let c = coverage(&["/home/a", "/home/a/b/c"], &["/home/a/b"], &["\\.tmp$"]);
+        assert!(c.covers(&PathBuf::from("/home/a")));
+        assert!(c.covers(&PathBuf::from("/home/a/x")));
+        assert!(!c.covers(&PathBuf::from("/home/a/b")));
+        assert!(!c.covers(&PathBuf::from("/home/a/b/d")));
+        assert!(c.covers(&PathBuf::from("/home/a/b/c/d")));
+        assert!(!c.covers(&PathBuf::from("/home/a/x.tmp")));
+        assert!(!c.covers(&PathBuf::from("/home/ab")));
+        assert!(!c.covers(&PathBuf::from("/home")));
+    }
+
+    #[test]
+    fn overlaps() {
+        let a = coverage(&["/home/u", "/etc"], &["/home/u/videos"], &[]);
+        let b = coverage(
+            &["/home/u/pictures", "/etc", "/home/u/videos/x", "/srv"],
+            &[],
+            &[],
+        );
+        let o = find_overlaps(&a, &b);
+        assert_eq!(o.len(), 2);
+        assert_eq!(o[0].path, PathBuf::from("/etc"));
+        assert_eq!(o[0].kind, OverlapKind::Shared);
+        assert_eq!(o[1].path, PathBuf::from("/home/u/pictures"));
+        assert_eq!(o[1].kind, OverlapKind::FirstContains);
+        assert_eq!(o[1].first, "/home/u");
+        assert_eq!(o[1].second, "/home/u/pictures");
+
+        let o = find_overlaps(&b, &a);
+        assert_eq!(o[1].kind, OverlapKind::SecondContains);
+
+        let c = coverage(&["/home", "/home/u/pictures/2020"], &[], &[]);
+        let o = find_overlaps(&c, &b);
+        assert_eq!(o.len(), 3);
+        assert_eq!(top_level(&o), vec![
+            PathBuf::from("/home/u/pictures"),
+            PathBuf::from("/home/u/videos/x")
+        ]);
+
+        assert!(find_overlaps(&a, &coverage(&["/home/u/videos"], &[], &[])).is_empty());
+        assert!(find_overlaps(&a, &coverage(&["/home/user"], &[], &[])).is_empty());
+    }
+}
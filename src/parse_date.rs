@@ -1,5 +1,5 @@
 /// This module contains date parsing, serialisation and deserialisation helpers
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, ParseError};
 use serde::de::Error;
@@ -80,6 +80,40 @@ pub fn try_parse(input: &str) -> Result<Option<NaiveDateTime>, &'static str> {
 }
 
 /// Try parsing a backup file name into a NaiveDateTime
+/// Parse a duration such as "30m", "12h", or "1h30m" (units: s, m, h, d)
+pub fn parse_duration(s: &str) -> Result<Duration, &'static str> {
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err("Unknown time unit (use s, m, h, or d)"),
+        };
+        let n: u64 = number
+            .parse()
+            .map_err(|_| "Missing number before the unit")?;
+        total = n
+            .checked_mul(unit)
+            .and_then(|n| n.checked_add(total))
+            .ok_or("The interval is too long")?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err("Missing unit after the number (use s, m, h, or d)");
+    }
+    if total == 0 {
+        return Err("The interval must be longer than zero");
+    }
+    Ok(Duration::from_secs(total))
+}
+
 pub fn parse_backup_file_name(filename: &str) -> Result<NaiveDateTime, ParseError> {
     const PATTERN: &str = "_%Y-%m-%d_%H-%M-%S.tar.zst";
     const LENGTH: usize = "_YYYY-mm-dd_HH-MM-SS.tar.zst".len();
@@ -98,11 +132,11 @@ pub fn naive_now() -> NaiveDateTime {
 
 #[cfg(test)]
 mod tests {
-    use std::time::SystemTime;
+    use std::time::{Duration, SystemTime};
 
     use chrono::{Datelike, Timelike};
 
-    use super::{system_to_naive, try_parse};
+    use super::{parse_duration, system_to_naive, try_parse};
     use crate::parse_date::parse_backup_file_name;
 
     #[test]
@@ -148,4 +182,16 @@ mod tests {
             2020
         );
     }
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 3600)));
+        assert_eq!(parse_duration("1d2h3m4s"), Ok(Duration::from_secs(93784)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("5y").is_err());
+        assert!(parse_duration("0m").is_err());
+    }
 }
@@ -0,0 +1,392 @@
+/// This module contains the dropping of root privileges (e.g. for backups started by a root cron job)
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::backup::{required_path, BackupError};
+use crate::config::Config;
+
+/// A user to run as (see [`drop_privileges`])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    /// The primary group
+    pub gid: u32,
+    /// The supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl User {
+    /// Do the permission bits allow the user to create files in the directory (ACLs are ignored)
+    pub fn can_write_dir(&self, mode: u32, owner: u32, group: u32) -> bool {
+        let bits = if owner == self.uid {
+            mode >> 6
+        } else if group == self.gid || self.groups.contains(&group) {
+            mode >> 3
+        } else {
+            mode
+        };
+        bits & 0o3 == 0o3
+    }
+}
+
+/// Platform specific handling of the users of the current process
+pub trait Privileges {
+    /// The effective user id of the process
+    fn euid(&self) -> u32;
+    /// Find a user (and its groups) by name
+    fn lookup(&self, name: &str) -> Option<User>;
+    fn set_groups(&mut self, groups: &[u32]) -> std::io::Result<()>;
+    fn set_gid(&mut self, gid: u32) -> std::io::Result<()>;
+    fn set_uid(&mut self, uid: u32) -> std::io::Result<()>;
+    /// Can the user create files in the directory (or in the closest existing parent)
+    fn can_write(&self, dir: &Path, user: &User) -> bool;
+    /// Can the process read the file (or list the directory)
+    fn can_read(&self, path: &Path) -> bool {
+        match path.metadata() {
+            Ok(md) if md.is_dir() => path.read_dir().is_ok(),
+            Ok(_) => File::open(path).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Drop the privileges of a root process to the user, in the order where each step is still
+/// allowed: first the supplementary groups, then the group, and last the user (which also clears
+/// the saved user id, so root cannot be regained). Returns the `roots` (a name and a path) that
+/// were readable before, but not after, dropping the privileges.
+pub fn drop_privileges<P: Privileges>(
+    privileges: &mut P,
+    user: &User,
+    output: Option<&Path>,
+    roots: &[(String, PathBuf)],
+) -> Result<Vec<String>, BackupError> {
+    let fail = |reason: String| BackupError::RunAs(user.name.clone(), reason);
+    if user.uid == 0 {
+        return Err(fail(String::from(
+            "dropping the privileges to root does nothing",
+        )));
+    }
+    let euid = privileges.euid();
+    if euid == user.uid {
+        // Already running as the user
+        return Ok(vec![]);
+    } else if euid != 0 {
+        return Err(fail(String::from("only root can switch to another user")));
+    }
+    if let Some(output) = output {
+        if !privileges.can_write(output, user) {
+            return Err(fail(format!(
+                "the user cannot write to the output '{}'",
+                output.to_string_lossy()
+            )));
+        }
+    }
+    let readable: Vec<&(String, PathBuf)> = roots
+        .iter()
+        .filter(|(_, path)| privileges.can_read(path))
+        .collect();
+    privileges
+        .set_groups(&user.groups)
+        .map_err(|e| fail(format!("could not set the groups: {}", e)))?;
+    privileges
+        .set_gid(user.gid)
+        .map_err(|e| fail(format!("could not set the group: {}", e)))?;
+    privileges
+        .set_uid(user.uid)
+        .map_err(|e| fail(format!("could not set the user: {}", e)))?;
+    if privileges.euid() != user.uid || privileges.set_uid(0).is_ok() {
+        return Err(fail(String::from("the root privileges were not dropped")));
+    }
+    Ok(readable
+        .into_iter()
+        .filter(|(_, path)| !privileges.can_read(path))
+        .map(|(name, _)| name.clone())
+        .collect())
+}
+
+/// Switch to the user before crawling and writing the backup (see [`drop_privileges`]).
+/// Required includes that cannot be read by the user are errors, and the other includes that
+/// cannot be read are returned (as warnings).
+pub fn run_as<P: Privileges>(
+    privileges: &mut P,
+    name: &str,
+    config: &Config,
+    dry: bool,
+) -> Result<Vec<String>, BackupError> {
+    let user = privileges.lookup(name).ok_or_else(|| {
+        BackupError::RunAs(name.to_string(), String::from("the user does not exist"))
+    })?;
+    let output = (!dry && !config.is_output_stream()).then(|| config.get_output_dir());
+    let roots: Vec<(String, PathBuf)> = config
+        .include
+        .iter()
+        .map(|s| (s.clone(), required_path(s, config.local)))
+        .collect();
+    let unreadable = drop_privileges(privileges, &user, output.as_deref(), &roots)?;
+    if let Some(root) = unreadable.iter().find(|r| config.required.contains(r)) {
+        return Err(BackupError::RequiredRootEmpty(
+            root.clone(),
+            format!("it cannot be read by the user '{}'", name),
+        ));
+    }
+    Ok(unreadable)
+}
+
+/// The users of the current process (through the operating system)
+#[cfg(unix)]
+pub struct OsPrivileges;
+
+#[cfg(unix)]
+impl Privileges for OsPrivileges {
+    fn euid(&self) -> u32 {
+        // SAFETY: geteuid has no memory safety requirements
+        unsafe { libc::geteuid() }
+    }
+
+    fn lookup(&self, name: &str) -> Option<User> {
+        use std::ffi::CString;
+
+        #[cfg(target_os = "macos")]
+        type GroupId = libc::c_int;
+        #[cfg(not(target_os = "macos"))]
+        type GroupId = libc::gid_t;
+
+        let cname = CString::new(name).ok()?;
+        let mut buf = vec![0 as libc::c_char; 16384];
+        // SAFETY: passwd is a plain C struct that is filled in by getpwnam_r
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        // SAFETY: All pointers are valid for the lengths given
+        let ret = unsafe {
+            libc::getpwnam_r(
+                cname.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret != 0 || result.is_null() {
+            return None;
+        }
+        let mut groups: Vec<GroupId> = vec![0; 64];
+        loop {
+            let mut len = groups.len() as libc::c_int;
+            // SAFETY: The groups buffer has room for `len` groups
+            let ret = unsafe {
+                libc::getgrouplist(
+                    cname.as_ptr(),
+                    pwd.pw_gid as _,
+                    groups.as_mut_ptr(),
+                    &mut len,
+                )
+            };
+            if ret >= 0 {
+                groups.truncate(len as usize);
+                break;
+            }
+            if groups.len() >= 65536 {
+                return None;
+            }
+            groups.resize((len as usize).max(groups.len() * 2), 0);
+        }
+        Some(User {
+            name: name.to_string(),
+            uid: pwd.pw_uid,
+            gid: pwd.pw_gid,
+            groups: groups.into_iter().map(|g| g as _).collect(),
+        })
+    }
+
+    fn set_groups(&mut self, groups: &[u32]) -> std::io::Result<()> {
+        let groups: Vec<libc::gid_t> = groups.iter().map(|&g| g as _).collect();
+        // SAFETY: The pointer is valid for the length given
+        match unsafe { libc::setgroups(groups.len() as _, groups.as_ptr()) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    fn set_gid(&mut self, gid: u32) -> std::io::Result<()> {
+        // SAFETY: setgid has no memory safety requirements
+        match unsafe { libc::setgid(gid) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    fn set_uid(&mut self, uid: u32) -> std::io::Result<()> {
+        // SAFETY: setuid has no memory safety requirements
+        match unsafe { libc::setuid(uid) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    fn can_write(&self, dir: &Path, user: &User) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        // The output directory is created if it does not exist
+        match dir.ancestors().find_map(|p| p.metadata().ok()) {
+            Some(md) => md.is_dir() && user.can_write_dir(md.mode(), md.uid(), md.gid()),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::config::ConfigBuilder;
+
+    /// Records the calls, and makes the `hidden` paths unreadable after dropping the privileges
+    struct MockPrivileges {
+        euid: u32,
+        calls: Vec<String>,
+        readable: HashSet<PathBuf>,
+        hidden: HashSet<PathBuf>,
+        writable: bool,
+        /// Pretend that root can be regained
+        sticky: bool,
+    }
+
+    impl MockPrivileges {
+        fn new(readable: &[&str], hidden: &[&str]) -> Self {
+            Self {
+                euid: 0,
+                calls: vec![],
+                readable: readable.iter().map(PathBuf::from).collect(),
+                hidden: hidden.iter().map(PathBuf::from).collect(),
+                writable: true,
+                sticky: false,
+            }
+        }
+    }
+
+    impl Privileges for MockPrivileges {
+        fn euid(&self) -> u32 {
+            self.euid
+        }
+
+        fn lookup(&self, name: &str) -> Option<User> {
+            let uid = match name {
+                "root" => 0,
+                "user" => 1000,
+                _ => return None,
+            };
+            Some(User {
+                name: name.to_string(),
+                uid,
+                gid: uid,
+                groups: vec![uid, 27],
+            })
+        }
+
+        fn set_groups(&mut self, groups: &[u32]) -> std::io::Result<()> {
+            self.calls.push(format!("groups {:?}", groups));
+            Ok(())
+        }
+
+        fn set_gid(&mut self, gid: u32) -> std::io::Result<()> {
+            self.calls.push(format!("gid {}", gid));
+            Ok(())
+        }
+
+        fn set_uid(&mut self, uid: u32) -> std::io::Result<()> {
+            if self.euid != 0 && !self.sticky {
+                return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+            }
+            self.calls.push(format!("uid {}", uid));
+            self.euid = uid;
+            Ok(())
+        }
+
+        fn can_write(&self, _dir: &Path, _user: &User) -> bool {
+            self.writable
+        }
+
+        fn can_read(&self, path: &Path) -> bool {
+            self.readable.contains(path) && (self.euid == 0 || !self.hidden.contains(path))
+        }
+    }
+
+    #[test]
+    fn drop_order() {
+        let mut mock = MockPrivileges::new(&[], &[]);
+        let user = mock.lookup("user").unwrap();
+        assert_eq!(
+            drop_privileges(&mut mock, &user, Some(Path::new("out")), &[]).unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(mock.calls, ["groups [1000, 27]", "gid 1000", "uid 1000"]);
+        assert_eq!(mock.euid, 1000);
+
+        // Already running as the user
+        mock.calls.clear();
+        drop_privileges(&mut mock, &user, None, &[]).unwrap();
+        assert!(mock.calls.is_empty());
+
+        // Root can still be regained
+        let mut mock = MockPrivileges::new(&[], &[]);
+        mock.sticky = true;
+        assert!(drop_privileges(&mut mock, &user, None, &[]).is_err());
+    }
+
+    #[test]
+    fn drop_refused() {
+        let mut mock = MockPrivileges::new(&[], &[]);
+        let root = mock.lookup("root").unwrap();
+        assert!(drop_privileges(&mut mock, &root, None, &[]).is_err());
+        let user = mock.lookup("user").unwrap();
+        mock.writable = false;
+        assert!(drop_privileges(&mut mock, &user, Some(Path::new("out")), &[]).is_err());
+        // The output is not checked for dry runs (nothing is written)
+        drop_privileges(&mut mock, &user, None, &[]).unwrap();
+        // Another user can only be reached from root
+        let mut mock = MockPrivileges::new(&[], &[]);
+        mock.euid = 1001;
+        assert!(drop_privileges(&mut mock, &user, None, &[]).is_err());
+        assert!(mock.calls.is_empty());
+        assert!(run_as(&mut mock, "missing", &Config::new(), true).is_err());
+    }
+
+    #[test]
+    fn unreadable_roots() {
+        let config = ConfigBuilder::new()
+            .include(["/a", "/b", "/c", "/missing"])
+            .required(["/a"])
+            .output("-")
+            .build();
+        let mut mock = MockPrivileges::new(&["/a", "/b", "/c"], &["/b"]);
+        assert_eq!(run_as(&mut mock, "user", &config, false).unwrap(), ["/b"]);
+
+        let mut mock = MockPrivileges::new(&["/a", "/b", "/c"], &["/a", "/b"]);
+        match run_as(&mut mock, "user", &config, false) {
+            Err(BackupError::RequiredRootEmpty(root, reason)) => {
+                assert_eq!(root, "/a");
+                assert!(reason.contains("user"));
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn write_permissions() {
+        let user = User {
+            name: String::from("user"),
+            uid: 1000,
+            gid: 1000,
+            groups: vec![1000, 27],
+        };
+        assert!(user.can_write_dir(0o700, 1000, 0));
+        assert!(!user.can_write_dir(0o500, 1000, 0));
+        assert!(!user.can_write_dir(0o077, 1000, 1000));
+        assert!(user.can_write_dir(0o770, 0, 27));
+        assert!(!user.can_write_dir(0o750, 0, 27));
+        assert!(user.can_write_dir(0o1777, 0, 0));
+        assert!(!user.can_write_dir(0o775, 0, 0));
+    }
+}
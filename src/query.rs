@@ -0,0 +1,719 @@
+/// This module contains a small query language for selecting files from the list in a backup,
+/// e.g. `size > 10M and path ~ '\.log$' and mtime < 2024-01-01` or `meta.owner = anna`
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use regex::Regex;
+
+use crate::backup::{BackupError, BackupReader};
+use crate::commands::metadata_value;
+use crate::parse_date::try_parse;
+use crate::utils::parse_size;
+
+/// How a file is stored in a backup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// The content is stored in the backup
+    Stored,
+    /// The file is unchanged (stored in a previous backup)
+    Unchanged,
+    /// Only the permissions and owner are stored (the content is in a previous backup)
+    Metadata,
+}
+
+impl FileStatus {
+    const NAMES: [(&'static str, Self); 3] = [
+        ("stored", Self::Stored),
+        ("unchanged", Self::Unchanged),
+        ("metadata", Self::Metadata),
+    ];
+}
+
+/// A file from the list in a backup (the size and time are unknown for some files)
+#[derive(Debug, Clone, Copy)]
+pub struct QueryEntry<'a> {
+    pub path: &'a str,
+    pub size: Option<u64>,
+    pub mtime: Option<NaiveDateTime>,
+    pub status: FileStatus,
+    /// User-defined `key=value;key=value` pairs (see [`crate::commands::collect_metadata`])
+    pub meta: Option<&'a str>,
+}
+
+/// A problem with a query (pointing at the offending part of it)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    pub query: String,
+    /// Byte offset of the offending token
+    pub position: usize,
+    len: usize,
+    pub message: String,
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let column = self.query[..self.position].chars().count();
+        let width = self.query[self.position..self.position + self.len]
+            .chars()
+            .count()
+            .max(1);
+        write!(
+            f,
+            "{}\n  {}\n  {}{}",
+            self.message,
+            self.query,
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tok {
+    /// A word (field name, keyword, or unquoted value)
+    Word(String),
+    /// A quoted value
+    Quoted(String),
+    Op(Cmp),
+    Regex,
+    Prefix,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    End,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    tok: Tok,
+    start: usize,
+    len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cmp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Cmp {
+    fn test<T: Ord>(self, a: T, b: T) -> bool {
+        match self {
+            Self::Lt => a < b,
+            Self::Le => a <= b,
+            Self::Gt => a > b,
+            Self::Ge => a >= b,
+            Self::Eq => a == b,
+            Self::Ne => a != b,
+        }
+    }
+
+    fn is_equality(self) -> bool {
+        matches!(self, Self::Eq | Self::Ne)
+    }
+}
+
+/// Is the character part of an operator (these end unquoted words)
+fn is_operator(c: char) -> bool {
+    matches!(c, '<' | '>' | '=' | '!' | '~' | '^' | '&' | '|' | '(' | ')')
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, (usize, usize, String)> {
+    let mut tokens = vec![];
+    let mut chars = query.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let two = query[start..].get(..2).unwrap_or_default();
+        let (tok, len) = match (c, two) {
+            (_, "<=") => (Tok::Op(Cmp::Le), 2),
+            (_, ">=") => (Tok::Op(Cmp::Ge), 2),
+            (_, "==") => (Tok::Op(Cmp::Eq), 2),
+            (_, "!=") => (Tok::Op(Cmp::Ne), 2),
+            (_, "^=") => (Tok::Prefix, 2),
+            (_, "&&") => (Tok::And, 2),
+            (_, "||") => (Tok::Or, 2),
+            ('<', _) => (Tok::Op(Cmp::Lt), 1),
+            ('>', _) => (Tok::Op(Cmp::Gt), 1),
+            ('=', _) => (Tok::Op(Cmp::Eq), 1),
+            ('~', _) => (Tok::Regex, 1),
+            ('!', _) => (Tok::Not, 1),
+            ('(', _) => (Tok::Open, 1),
+            (')', _) => (Tok::Close, 1),
+            ('\'' | '"', _) => {
+                // Quoted values can contain the quote if it is escaped (other `\` are kept)
+                chars.next();
+                let mut value = String::new();
+                let mut end = None;
+                while let Some((i, ch)) = chars.next() {
+                    if ch == c {
+                        end = Some(i);
+                        break;
+                    }
+                    match (ch, chars.peek()) {
+                        ('\\', Some(&(_, next))) if next == c => {
+                            value.push(c);
+                            chars.next();
+                        }
+                        _ => value.push(ch),
+                    }
+                }
+                match end {
+                    Some(end) => {
+                        tokens.push(Token {
+                            tok: Tok::Quoted(value),
+                            start,
+                            len: end + 1 - start,
+                        });
+                        continue;
+                    }
+                    None => return Err((start, 1, "The quote is never closed".to_string())),
+                }
+            }
+            ('&' | '|' | '^', _) => {
+                return Err((start, 1, format!("Unknown operator '{}'", c)));
+            }
+            _ => {
+                let len = query[start..]
+                    .find(|c: char| c.is_whitespace() || is_operator(c) || c == '\'' || c == '"')
+                    .unwrap_or(query.len() - start);
+                let word = &query[start..start + len];
+                let tok = match word.to_ascii_lowercase().as_str() {
+                    "and" => Tok::And,
+                    "or" => Tok::Or,
+                    "not" => Tok::Not,
+                    _ => Tok::Word(word.to_string()),
+                };
+                (tok, len)
+            }
+        };
+        tokens.push(Token { tok, start, len });
+        while chars.peek().is_some_and(|(i, _)| *i < start + len) {
+            chars.next();
+        }
+    }
+    tokens.push(Token {
+        tok: Tok::End,
+        start: query.len(),
+        len: 0,
+    });
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Size(Cmp, u64),
+    Mtime(Cmp, NaiveDateTime),
+    Path(Cmp, String),
+    PathRegex(Regex),
+    PathPrefix(String),
+    Included(bool),
+    Status(Cmp, FileStatus),
+    /// Compare the value of a metadata key (files without the key never match)
+    Meta(String, Cmp, String),
+    MetaRegex(String, Regex),
+    MetaPrefix(String, String),
+    MetaExists(String),
+}
+
+impl Expr {
+    fn matches(&self, entry: &QueryEntry) -> bool {
+        match self {
+            Self::And(a, b) => a.matches(entry) && b.matches(entry),
+            Self::Or(a, b) => a.matches(entry) || b.matches(entry),
+            Self::Not(a) => !a.matches(entry),
+            Self::Size(cmp, size) => entry.size.is_some_and(|s| cmp.test(s, *size)),
+            Self::Mtime(cmp, time) => entry.mtime.is_some_and(|t| cmp.test(t, *time)),
+            Self::Path(cmp, path) => cmp.test(entry.path, path.as_str()),
+            Self::PathRegex(regex) => regex.is_match(entry.path),
+            Self::PathPrefix(prefix) => entry.path.starts_with(prefix.as_str()),
+            Self::Included(included) => (entry.status == FileStatus::Stored) == *included,
+            Self::Status(cmp, status) => cmp.test(entry.status == *status, true),
+            Self::Meta(key, cmp, value) => entry
+                .meta
+                .and_then(|m| metadata_value(m, key))
+                .is_some_and(|v| cmp.test(v, value.as_str())),
+            Self::MetaRegex(key, regex) => entry
+                .meta
+                .and_then(|m| metadata_value(m, key))
+                .is_some_and(|v| regex.is_match(v)),
+            Self::MetaPrefix(key, prefix) => entry
+                .meta
+                .and_then(|m| metadata_value(m, key))
+                .is_some_and(|v| v.starts_with(prefix.as_str())),
+            Self::MetaExists(key) => entry.meta.and_then(|m| metadata_value(m, key)).is_some(),
+        }
+    }
+
+    fn uses_mtime(&self) -> bool {
+        match self {
+            Self::And(a, b) | Self::Or(a, b) => a.uses_mtime() || b.uses_mtime(),
+            Self::Not(a) => a.uses_mtime(),
+            Self::Mtime(..) => true,
+            _ => false,
+        }
+    }
+}
+
+/// The fields that can be used in a query
+const FIELDS: [&str; 5] = ["size", "mtime", "path", "included", "status"];
+const FIELDS_HELP: &str = "size, mtime, path, included, status, or meta.<key>";
+/// The prefix of the fields that compare the user-defined metadata (e.g. `meta.owner`)
+const META_PREFIX: &str = "meta.";
+
+struct Parser<'a> {
+    query: &'a str,
+    tokens: Vec<Token>,
+    index: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.index]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.index].clone();
+        if token.tok != Tok::End {
+            self.index += 1;
+        }
+        token
+    }
+
+    fn error<S: Into<String>>(&self, token: &Token, message: S) -> QueryError {
+        QueryError {
+            query: self.query.to_string(),
+            position: token.start,
+            len: token.len,
+            message: message.into(),
+        }
+    }
+
+    /// `or := and ("or" and)*`
+    fn or(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.and()?;
+        while self.peek().tok == Tok::Or {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    /// `and := unary ("and" unary)*`
+    fn and(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.unary()?;
+        while self.peek().tok == Tok::And {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    /// `unary := "not" unary | "(" or ")" | comparison`
+    fn unary(&mut self) -> Result<Expr, QueryError> {
+        let token = self.next();
+        match &token.tok {
+            Tok::Not => Ok(Expr::Not(Box::new(self.unary()?))),
+            Tok::Open => {
+                let expr = self.or()?;
+                let close = self.next();
+                if close.tok == Tok::Close {
+                    Ok(expr)
+                } else {
+                    Err(self.error(&close, "Expected ')'"))
+                }
+            }
+            Tok::Word(field) => self.comparison(&token, field.to_ascii_lowercase()),
+            Tok::End => Err(self.error(&token, "Expected a comparison (e.g. size > 10M)")),
+            _ => Err(self.error(&token, format!("Expected a field ({})", FIELDS_HELP))),
+        }
+    }
+
+    /// `comparison := field op value | "included" | "meta." key`
+    fn comparison(&mut self, field_token: &Token, field: String) -> Result<Expr, QueryError> {
+        if field.starts_with(META_PREFIX) {
+            // The keys are case sensitive (unlike the other fields)
+            let name = &self.query[field_token.start..field_token.start + field_token.len];
+            return self.meta_comparison(field_token, name[META_PREFIX.len()..].to_string());
+        }
+        if !FIELDS.contains(&field.as_str()) {
+            let name = &self.query[field_token.start..field_token.start + field_token.len];
+            return Err(self.error(
+                field_token,
+                format!("Unknown field '{}' (use {})", name, FIELDS_HELP),
+            ));
+        }
+        let op = match &self.peek().tok {
+            Tok::Op(cmp) => Ok(*cmp),
+            Tok::Regex | Tok::Prefix => Err(self.peek().tok.clone()),
+            // A bare `included` is the same as `included = true`
+            _ if field == "included" => return Ok(Expr::Included(true)),
+            _ => {
+                return Err(self.error(
+                    self.peek(),
+                    format!("Expected a comparison operator after '{}'", field),
+                ))
+            }
+        };
+        let op_token = self.next();
+        let value_token = self.next();
+        let value = match &value_token.tok {
+            Tok::Word(v) | Tok::Quoted(v) => v.clone(),
+            _ => return Err(self.error(&value_token, "Expected a value")),
+        };
+        let invalid_op = |allowed: &str| {
+            self.error(
+                &op_token,
+                format!(
+                    "'{}' can not be used with {} (use {})",
+                    &self.query[op_token.start..op_token.start + op_token.len],
+                    field,
+                    allowed
+                ),
+            )
+        };
+        match (field.as_str(), op) {
+            ("path", Ok(cmp)) if cmp.is_equality() => Ok(Expr::Path(cmp, value)),
+            ("path", Err(Tok::Regex)) => Regex::new(&value)
+                .map(Expr::PathRegex)
+                .map_err(|e| self.error(&value_token, format!("Invalid regex: {}", e))),
+            ("path", Err(_)) => Ok(Expr::PathPrefix(value)),
+            ("path", Ok(_)) => Err(invalid_op("=, !=, ~, or ^=")),
+            ("size", Ok(cmp)) => parse_size(&value)
+                .map(|size| Expr::Size(cmp, size))
+                .map_err(|_| self.error(&value_token, "Expected a size (e.g. 10M)")),
+            ("mtime", Ok(cmp)) => match try_parse(&value) {
+                Ok(Some(time)) => Ok(Expr::Mtime(cmp, time)),
+                _ => Err(self.error(&value_token, "Expected a date (e.g. 2024-01-01)")),
+            },
+            ("size" | "mtime", Err(_)) => Err(invalid_op("<, <=, >, >=, =, or !=")),
+            ("included", Ok(cmp)) if cmp.is_equality() => {
+                let included = match value.to_ascii_lowercase().as_str() {
+                    "true" | "yes" => true,
+                    "false" | "no" => false,
+                    _ => return Err(self.error(&value_token, "Expected true or false")),
+                };
+                Ok(Expr::Included(included == (cmp == Cmp::Eq)))
+            }
+            ("status", Ok(cmp)) if cmp.is_equality() => {
+                let lower = value.to_ascii_lowercase();
+                match FileStatus::NAMES.iter().find(|(name, _)| *name == lower) {
+                    Some((_, status)) => Ok(Expr::Status(cmp, *status)),
+                    None => Err(self.error(
+                        &value_token,
+                        "Expected a status (stored, unchanged, or metadata)",
+                    )),
+                }
+            }
+            _ => Err(invalid_op("= or !=")),
+        }
+    }
+
+    /// `meta.key op value`, where a bare `meta.key` checks that the file has the key
+    fn meta_comparison(&mut self, field_token: &Token, key: String) -> Result<Expr, QueryError> {
+        if key.is_empty() {
+            return Err(self.error(field_token, "Expected a key after 'meta.'"));
+        }
+        let regex = match &self.peek().tok {
+            Tok::Op(_) | Tok::Prefix => false,
+            Tok::Regex => true,
+            _ => return Ok(Expr::MetaExists(key)),
+        };
+        let op_token = self.next();
+        let value_token = self.next();
+        let value = match &value_token.tok {
+            Tok::Word(v) | Tok::Quoted(v) => v.clone(),
+            _ => return Err(self.error(&value_token, "Expected a value")),
+        };
+        match op_token.tok {
+            Tok::Op(cmp) => Ok(Expr::Meta(key, cmp, value)),
+            _ if regex => Regex::new(&value)
+                .map(|r| Expr::MetaRegex(key, r))
+                .map_err(|e| self.error(&value_token, format!("Invalid regex: {}", e))),
+            _ => Ok(Expr::MetaPrefix(key, value)),
+        }
+    }
+}
+
+/// A compiled query that selects files from the list in a backup
+#[derive(Debug, Clone)]
+pub struct Query {
+    expr: Expr,
+    text: String,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Self, QueryError> {
+        let tokens = tokenize(query).map_err(|(position, len, message)| QueryError {
+            query: query.to_string(),
+            position,
+            len,
+            message,
+        })?;
+        let mut parser = Parser {
+            query,
+            tokens,
+            index: 0,
+        };
+        let expr = parser.or()?;
+        let token = parser.next();
+        match token.tok {
+            Tok::End => Ok(Self {
+                expr,
+                text: query.to_string(),
+            }),
+            Tok::Close => Err(parser.error(&token, "Unmatched ')'")),
+            _ => Err(parser.error(&token, "Expected 'and' or 'or'")),
+        }
+    }
+
+    /// Does the file match the query (comparisons on unknown sizes and times never match)
+    pub fn matches(&self, entry: &QueryEntry) -> bool {
+        self.expr.matches(entry)
+    }
+
+    /// Does the query need the modification times (these are only available by reading the
+    /// whole backup)
+    pub fn uses_mtime(&self) -> bool {
+        self.expr.uses_mtime()
+    }
+
+    /// Check which files in the list of the backup match the query (in the order of the list)
+    pub fn filter_list(&self, reader: &mut BackupReader) -> Result<Vec<bool>, BackupError> {
+        let mtimes = if self.uses_mtime() {
+            reader
+                .read_headers()?
+                .into_iter()
+                .map(|(path, (time, _))| (path, time))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        let list = reader.get_list()?;
+        let updates: HashSet<&str> = list.iter_updates().map(|(_, path)| path).collect();
+        let user_meta: HashMap<&str, _> = list.iter_user_meta().collect();
+        Ok(list
+            .iter_meta()
+            .map(|(included, size, _, path)| {
+                let status = if included {
+                    FileStatus::Stored
+                } else if updates.contains(path) {
+                    FileStatus::Metadata
+                } else {
+                    FileStatus::Unchanged
+                };
+                self.matches(&QueryEntry {
+                    path,
+                    size,
+                    mtime: mtimes.get(path).copied(),
+                    status,
+                    meta: user_meta.get(path).map(|m| m.as_ref()),
+                })
+            })
+            .collect())
+    }
+}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::{FileStatus, Query, QueryEntry};
+
+    fn entry(path: &str, size: u64, status: FileStatus) -> QueryEntry<'_> {
+        QueryEntry {
+            path,
+            size: Some(size),
+            mtime: NaiveDate::from_ymd_opt(2023, 6, 1)
+                .unwrap()
+                .and_hms_opt(12, 0, 0),
+            status,
+            meta: None,
+        }
+    }
+
+    fn matches(query: &str, entry: &QueryEntry) -> bool {
+        Query::parse(query).unwrap().matches(entry)
+    }
+
+    #[test]
+    fn comparisons() {
+        let log = entry("/var/log/app.log", 20 << 20, FileStatus::Stored);
+        assert!(matches("size > 10M", &log));
+        assert!(matches("size >= 20m", &log));
+        assert!(!matches("size < 20MiB", &log));
+        assert!(matches("size = 20971520", &log));
+        assert!(matches("size != 1k", &log));
+        assert!(matches("path ~ '\\.log$'", &log));
+        assert!(!matches("path ~ \"\\.txt$\"", &log));
+        assert!(matches("path ^= /var/", &log));
+        assert!(matches("path = '/var/log/app.log'", &log));
+        assert!(matches("path != /var", &log));
+        assert!(matches("mtime < 2024-01-01", &log));
+        assert!(matches("mtime > 2023-06-01", &log));
+        assert!(!matches("mtime >= '2023-06-01 12:00:01'", &log));
+        assert!(matches("included", &log));
+        assert!(matches("included = true", &log));
+        assert!(matches("included != no", &log));
+        assert!(matches("status = stored", &log));
+        assert!(!matches("status = unchanged", &log));
+        assert!(matches("status != metadata", &log));
+        assert!(matches(
+            "size > 10M and path ~ '\\.log$' and mtime < 2024-01-01",
+            &log
+        ));
+    }
+
+    #[test]
+    fn unknown_values() {
+        let old = QueryEntry {
+            path: "a.txt",
+            size: None,
+            mtime: None,
+            status: FileStatus::Unchanged,
+            meta: None,
+        };
+        assert!(!matches("size > 0", &old));
+        assert!(!matches("size < 1T", &old));
+        assert!(!matches("mtime < 2100-01-01", &old));
+        assert!(matches("not size > 0", &old));
+        assert!(!matches("included", &old));
+        assert!(matches("status = unchanged", &old));
+        let update = QueryEntry {
+            status: FileStatus::Metadata,
+            ..old
+        };
+        assert!(matches("status = metadata and not included", &update));
+    }
+
+    #[test]
+    fn user_meta() {
+        let file = QueryEntry {
+            meta: Some("owner=anna;review=done;Team=a=b"),
+            ..entry("a.txt", 10, FileStatus::Stored)
+        };
+        assert!(matches("meta.owner == 'anna'", &file));
+        assert!(matches("meta.owner != bob", &file));
+        assert!(matches("meta.review ~ '^do'", &file));
+        assert!(matches("meta.review ^= d and size < 1k", &file));
+        assert!(matches("meta.Team = 'a=b'", &file));
+        assert!(!matches("meta.team = 'a=b'", &file));
+        assert!(matches("meta.owner", &file));
+        assert!(!matches("meta.priority", &file));
+        assert!(matches("not meta.priority and meta.owner > a", &file));
+        // Files without the key never match (also not for `!=`)
+        assert!(!matches("meta.priority != high", &file));
+        assert!(!matches(
+            "meta.owner = anna",
+            &entry("b.txt", 1, FileStatus::Stored)
+        ));
+        assert_eq!(error("meta. = a").0, 0);
+        assert_eq!(error("meta.owner ~ '('").0, 13);
+    }
+
+    #[test]
+    fn precedence() {
+        let small = entry("a.txt", 10, FileStatus::Stored);
+        // `and` binds tighter than `or`
+        assert!(matches("path = b or path = a.txt and size < 1k", &small));
+        assert!(!matches("(path = b or path = a.txt) and size > 1k", &small));
+        assert!(matches("path = a.txt or path = b and size > 1k", &small));
+        // `not` binds tighter than `and`
+        assert!(!matches("not path = a.txt and size < 1k", &small));
+        assert!(matches("not (path = b and size < 1k)", &small));
+        assert!(matches("not not included", &small));
+        assert!(matches("!(size > 1k) && (path ^= a || size > 1k)", &small));
+        assert!(matches("((size < 1k))", &small));
+        assert!(matches("size<1k AND NOT path=b", &small));
+    }
+
+    #[test]
+    fn uses_mtime() {
+        assert!(!Query::parse("size > 1 or path ~ a").unwrap().uses_mtime());
+        assert!(Query::parse("size > 1 or not (mtime < 2024-01-01)")
+            .unwrap()
+            .uses_mtime());
+    }
+
+    fn error(query: &str) -> (usize, String) {
+        let e = Query::parse(query).unwrap_err();
+        (e.position, e.message)
+    }
+
+    #[test]
+    fn type_errors() {
+        let (pos, msg) = error("path > 10");
+        assert_eq!(pos, 5);
+        assert!(msg.contains("can not be used with path"), "{}", msg);
+        let (pos, msg) = error("size = abc");
+        assert_eq!(pos, 7);
+        assert!(msg.contains("size"), "{}", msg);
+        let (pos, msg) = error("size ~ 'a'");
+        assert_eq!(pos, 5);
+        assert!(msg.contains("can not be used with size"), "{}", msg);
+        assert_eq!(error("size > 10X").0, 7);
+        assert_eq!(error("mtime < yesterday").0, 8);
+        assert_eq!(error("included = maybe").0, 11);
+        assert_eq!(error("included > true").0, 9);
+        assert_eq!(error("status = gone").0, 9);
+        assert_eq!(error("status ^= s").0, 7);
+        assert_eq!(error("path ~ '('").0, 7);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let (pos, msg) = error("name = a");
+        assert_eq!(pos, 0);
+        assert!(msg.contains("Unknown field"), "{}", msg);
+        assert_eq!(error("size > 1 and").0, 12);
+        assert_eq!(error("size > 1 size < 2").0, 9);
+        assert_eq!(error("(size > 1").0, 9);
+        assert_eq!(error("size > 1)").0, 8);
+        assert_eq!(error("size").0, 4);
+        assert_eq!(error("size >").0, 6);
+        assert_eq!(error("path = 'abc").0, 7);
+        assert_eq!(error("size > 1 & path = a").0, 9);
+        assert_eq!(error("").0, 0);
+        assert_eq!(error("and size > 1").0, 0);
+    }
+
+    #[test]
+    fn error_caret() {
+        let e = Query::parse("size > 1 and päth = a").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Unknown field 'päth' (use size, mtime, path, included, status, or meta.<key>)\n  size > 1 and päth = a\n               ^^^^"
+        );
+        let e = Query::parse("size > 1 and").unwrap_err();
+        assert!(e.to_string().ends_with("\n  size > 1 and\n              ^"));
+    }
+}
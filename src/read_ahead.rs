@@ -0,0 +1,207 @@
+/// This module contains the reading of files ahead of the compression (on separate threads),
+/// so that reading the files overlaps with compressing them (see [`crate::config::Config::read_ahead_mb`])
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+use crate::backup::open_with_retries;
+use crate::compression::{Retry, SourceFile};
+
+struct State {
+    /// The next file to read
+    next: usize,
+    /// The files before this have been taken (or skipped) by the writer
+    taken: usize,
+    /// The bytes of the files that are being read, or waiting to be taken
+    in_flight: u64,
+    done: HashMap<usize, std::io::Result<SourceFile>>,
+    stop: bool,
+}
+
+struct Shared {
+    files: Vec<(PathBuf, u64)>,
+    max_bytes: u64,
+    retry: Retry,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // A reader that panicked cannot leave the state inconsistent (the file is just missing)
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Read files (in order) until all are read or the reading is stopped
+    fn read_files(&self) {
+        loop {
+            let (index, size) = {
+                let mut state = self.lock();
+                loop {
+                    if state.stop || state.next >= self.files.len() {
+                        return;
+                    }
+                    // The writer has already skipped these files
+                    if state.next < state.taken {
+                        state.next = state.taken;
+                        continue;
+                    }
+                    let size = self.files[state.next].1;
+                    // The files are reserved in order, so the file the writer waits for always fits
+                    if state.in_flight == 0 || state.in_flight + size <= self.max_bytes {
+                        break;
+                    }
+                    state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+                let index = state.next;
+                let size = self.files[index].1;
+                state.next += 1;
+                state.in_flight += size;
+                (index, size)
+            };
+            let res = open_with_retries(&self.files[index].0, self.retry)
+                .and_then(|source| source.preload(self.retry));
+            let mut state = self.lock();
+            if index < state.taken {
+                state.in_flight -= size;
+            } else {
+                state.done.insert(index, res);
+            }
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// Files that are read into memory by background threads (in the order they are added to the
+/// archive), with a limit on the bytes in memory (files larger than the limit should be read
+/// directly instead)
+pub struct ReadAhead<K> {
+    shared: Arc<Shared>,
+    keys: HashMap<K, usize>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<K: Hash + Eq> ReadAhead<K> {
+    /// Start reading the files (identified by the keys) on `threads` threads
+    pub fn new(
+        files: Vec<(K, PathBuf, u64)>,
+        threads: usize,
+        max_bytes: u64,
+        retry: Retry,
+    ) -> Self {
+        let mut keys = HashMap::with_capacity(files.len());
+        let files = files
+            .into_iter()
+            .enumerate()
+            .map(|(i, (key, path, size))| {
+                keys.insert(key, i);
+                (path, size)
+            })
+            .collect();
+        let shared = Arc::new(Shared {
+            files,
+            max_bytes,
+            retry,
+            state: Mutex::new(State {
+                next: 0,
+                taken: 0,
+                in_flight: 0,
+                done: HashMap::new(),
+                stop: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let threads = (0..threads.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                std::thread::spawn(move || shared.read_files())
+            })
+            .collect();
+        Self {
+            shared,
+            keys,
+            threads,
+        }
+    }
+
+    /// Wait for a file to be read, `None` if the file was not added (or has already been taken).
+    /// The files before it that have not been taken are dropped.
+    pub fn take(&mut self, key: &K) -> Option<std::io::Result<SourceFile>> {
+        let index = *self.keys.get(key)?;
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        if index < state.taken {
+            return None;
+        }
+        for i in state.taken..index {
+            if state.done.remove(&i).is_some() {
+                state.in_flight -= shared.files[i].1;
+            }
+        }
+        state.taken = index;
+        shared.changed.notify_all();
+        let res = loop {
+            if let Some(res) = state.done.remove(&index) {
+                break res;
+            }
+            state = shared
+                .changed
+                .wait(state)
+                .unwrap_or_else(|e| e.into_inner());
+        };
+        state.taken = index + 1;
+        state.in_flight -= shared.files[index].1;
+        shared.changed.notify_all();
+        Some(res)
+    }
+}
+
+impl<K> Drop for ReadAhead<K> {
+    /// Stop the reading (the files that are being read are finished first)
+    fn drop(&mut self) {
+        self.shared.lock().stop = true;
+        self.shared.changed.notify_all();
+        for t in self.threads.drain(..) {
+            #[allow(unused_must_use)]
+            t.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn read_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<(usize, PathBuf, u64)> = (0..20)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.txt", i));
+                std::fs::write(&path, vec![i as u8; 100 * i]).unwrap();
+                (i, path, 100 * i as u64)
+            })
+            .collect();
+        // A limit smaller than some files (these are still read one at a time)
+        let mut read_ahead = ReadAhead::new(files, 3, 1000, Retry::default());
+        for i in (0..20).filter(|i| i % 3 != 2) {
+            let source = read_ahead.take(&i).unwrap().unwrap();
+            assert_eq!(source.content.unwrap(), vec![i as u8; 100 * i]);
+        }
+        assert!(read_ahead.take(&2).is_none());
+        assert!(read_ahead.take(&20).is_none());
+        assert_eq!(read_ahead.shared.lock().in_flight, 0);
+
+        // Missing files are errors, and dropping stops the reading
+        let missing = vec![
+            (0, dir.path().join("missing"), 0),
+            (1, dir.path().join("1.txt"), 100),
+        ];
+        let mut read_ahead = ReadAhead::new(missing, 2, 1000, Retry::new(1, Duration::ZERO));
+        assert!(read_ahead.take(&0).unwrap().is_err());
+    }
+}
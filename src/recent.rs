@@ -0,0 +1,162 @@
+/// This module contains the list of recently opened configs and backups (stored in the settings directory)
+use std::fs::{create_dir_all, File};
+use std::path::{Path, PathBuf};
+
+use path_absolutize::Absolutize;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::settings_dir;
+
+/// The name of the file with the recent paths (in the settings directory)
+pub const RECENT_FILE_NAME: &str = "recent.yml";
+
+/// How many paths are remembered
+pub const MAX_RECENT: usize = 8;
+
+/// What kind of file was opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecentKind {
+    /// A config (or a backup used as a config) for making backups
+    Config,
+    /// A backup for restoring
+    Backup,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecentEntry {
+    pub kind: RecentKind,
+    pub path: PathBuf,
+}
+
+impl RecentEntry {
+    /// The file name (for showing in a list)
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .unwrap_or(self.path.as_os_str())
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+/// The recently opened paths (the most recent first)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecentList {
+    pub entries: Vec<RecentEntry>,
+}
+
+impl RecentList {
+    /// The default location of the list (in the settings directory)
+    pub fn default_path() -> Option<PathBuf> {
+        settings_dir().map(|p| p.join(RECENT_FILE_NAME))
+    }
+
+    /// Read the list from the settings directory (see [`RecentList::read`])
+    pub fn load() -> Self {
+        Self::default_path()
+            .map(|p| Self::read(&p))
+            .unwrap_or_default()
+    }
+
+    /// Read the list, without the paths that no longer exist
+    /// (an empty list if the file is missing or broken)
+    pub fn read(path: &Path) -> Self {
+        let mut list: Self = File::open(path)
+            .ok()
+            .and_then(|f| serde_yaml::from_reader(f).ok())
+            .unwrap_or_default();
+        list.entries.retain(|e| e.path.exists());
+        list.entries.truncate(MAX_RECENT);
+        list
+    }
+
+    /// Store the list in the settings directory (nothing is stored without a settings directory)
+    pub fn save(&self) -> std::io::Result<()> {
+        match Self::default_path() {
+            Some(path) => self.write(&path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        serde_yaml::to_writer(File::create(path)?, self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Move (or add) a path to the front of the list
+    pub fn add<P: AsRef<Path>>(&mut self, kind: RecentKind, path: P) {
+        let path = path
+            .as_ref()
+            .absolutize()
+            .map(|p| p.to_path_buf())
+            .unwrap_or_else(|_| path.as_ref().to_path_buf());
+        self.entries.retain(|e| e.kind != kind || e.path != path);
+        self.entries.insert(0, RecentEntry { kind, path });
+        self.entries.truncate(MAX_RECENT);
+    }
+}
+
+/// Add a path to the stored list of recent paths (a failure is only logged)
+pub fn remember<P: AsRef<Path>>(kind: RecentKind, path: P) {
+    let mut list = RecentList::load();
+    list.add(kind, path);
+    if let Err(e) = list.save() {
+        log::warn!("Could not store the recent paths: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::{RecentKind, RecentList, MAX_RECENT};
+
+    #[test]
+    fn add() {
+        let mut list = RecentList::default();
+        list.add(RecentKind::Config, "/a.yml");
+        list.add(RecentKind::Backup, "/b.tar.zst");
+        list.add(RecentKind::Config, "/a.yml");
+        list.add(RecentKind::Backup, "/a.yml");
+        let names: Vec<(RecentKind, String)> =
+            list.entries.iter().map(|e| (e.kind, e.name())).collect();
+        assert_eq!(names, [
+            (RecentKind::Backup, "a.yml".to_string()),
+            (RecentKind::Config, "a.yml".to_string()),
+            (RecentKind::Backup, "b.tar.zst".to_string()),
+        ]);
+        for i in 0..MAX_RECENT * 2 {
+            list.add(RecentKind::Config, format!("/{}.yml", i));
+        }
+        assert_eq!(list.entries.len(), MAX_RECENT);
+        assert_eq!(
+            list.entries[0].name(),
+            format!("{}.yml", MAX_RECENT * 2 - 1)
+        );
+    }
+
+    #[test]
+    fn read_write() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("settings").join("recent.yml");
+        assert_eq!(RecentList::read(&path), RecentList::default());
+
+        let config = dir.path().join("config.yml");
+        std::fs::write(&config, "").unwrap();
+        let mut list = RecentList::default();
+        list.add(RecentKind::Backup, dir.path().join("missing.tar.zst"));
+        list.add(RecentKind::Config, &config);
+        list.write(&path).unwrap();
+        let list = RecentList::read(&path);
+        assert_eq!(list.entries.len(), 1);
+        assert_eq!(list.entries[0].path, config);
+
+        std::fs::write(&path, "not: [a list").unwrap();
+        assert_eq!(RecentList::read(&path), RecentList::default());
+    }
+}
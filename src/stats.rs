@@ -0,0 +1,820 @@
+/// This module contains the summary statistics for a backup (based on the embedded file list)
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::backup::{BackupError, BackupReader};
+use crate::compression::{archive_size, STORE_MIN_SIZE};
+
+/// Smaller backups get no advice about the compression quality (see [`quality_advice`])
+const ADVICE_MIN_SIZE: u64 = 256 << 20;
+/// The files compressed with the full quality must make up this share of the bytes
+const ADVICE_MIN_SHARE: f64 = 0.5;
+/// Data that shrinks less than this is considered already compressed
+const ADVICE_INCOMPRESSIBLE: f64 = 0.05;
+/// Data that shrinks more than this is considered highly compressible
+const ADVICE_COMPRESSIBLE: f64 = 0.5;
+/// The quality suggested for data that does not shrink
+const ADVICE_LOW_QUALITY: i32 = 6;
+/// Higher qualities are only suggested below this quality
+const ADVICE_RAISE_BELOW: i32 = 15;
+/// The highest quality that is suggested
+const ADVICE_HIGH_QUALITY: i32 = 19;
+/// An (optimistic) reading speed for estimating how long the backup would take without compression
+const ADVICE_READ_SPEED: f64 = (200 << 20) as f64;
+
+/// How to group the files when summarising the sizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum StatsGroup {
+    #[default]
+    Extension,
+    Directory,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileSize {
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GroupSize {
+    pub name: String,
+    pub files: u64,
+    pub size: u64,
+}
+
+/// Aggregated information about a backup
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupStats {
+    pub path: String,
+    /// Number of files stored in this backup
+    pub files: u64,
+    /// Number of files in the list (including files from previous incremental backups)
+    pub listed: u64,
+    /// Number of stored files without a size in the list (older backups)
+    pub unknown_size: u64,
+    /// Total uncompressed size of the stored files (in bytes)
+    pub size: u64,
+    /// Size of the backup file (in bytes)
+    pub compressed: u64,
+    /// Uncompressed size divided by the compressed size
+    pub ratio: Option<f64>,
+    pub largest: Vec<FileSize>,
+    pub groups: Vec<GroupSize>,
+}
+
+impl BackupStats {
+    /// Summarise a backup (with the `top` largest files)
+    pub fn new(
+        reader: &mut BackupReader,
+        group: StatsGroup,
+        top: usize,
+    ) -> Result<Self, BackupError> {
+        let compressed = archive_size(reader.path.get_path())?;
+        let path = reader.path.copy_string().to_string();
+        let list = reader.get_list()?;
+
+        let listed = list.iter().count() as u64;
+        let mut unknown_size = 0;
+        let mut files = vec![];
+        for entry in list.entries() {
+            match entry.size {
+                Some(size) => files.push((entry.path, size)),
+                None => {
+                    unknown_size += 1;
+                    files.push((entry.path, 0));
+                }
+            }
+        }
+        let size = files.iter().map(|(_, s)| s).sum();
+
+        let root = common_root(files.iter().map(|(p, _)| Path::new(p)));
+        let mut groups: HashMap<String, GroupSize> = HashMap::new();
+        for (p, s) in files.iter() {
+            let name = match group {
+                StatsGroup::Extension => extension_group(p),
+                StatsGroup::Directory => directory_group(p, &root),
+            };
+            let entry = groups.entry(name).or_insert_with_key(|k| GroupSize {
+                name: k.clone(),
+                files: 0,
+                size: 0,
+            });
+            entry.files += 1;
+            entry.size += s;
+        }
+        let mut groups: Vec<GroupSize> = groups.into_values().collect();
+        groups.sort_unstable_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+
+        files.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        let largest = files
+            .iter()
+            .take(top)
+            .map(|(p, s)| FileSize {
+                path: p.to_string(),
+                size: *s,
+            })
+            .collect();
+
+        Ok(Self {
+            path,
+            files: files.len() as u64,
+            listed,
+            unknown_size,
+            size,
+            compressed,
+            ratio: if compressed > 0 {
+                Some(size as f64 / compressed as f64)
+            } else {
+                None
+            },
+            largest,
+            groups,
+        })
+    }
+}
+
+/// The bytes stored in a backup by how they were compressed (see [`quality_advice`])
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CompressionProfile {
+    pub quality: i32,
+    /// Total size of the stored files (before compression)
+    pub size: u64,
+    /// Size of the backup file
+    pub compressed: u64,
+    /// Bytes in the files that were compressed with the fast level (see `store_only` in the config)
+    pub store_only: u64,
+    /// The other files by extension (largest first)
+    pub extensions: Vec<GroupSize>,
+    /// The time it took to write the backup
+    pub elapsed: Duration,
+}
+
+impl CompressionProfile {
+    /// Group the stored files (paths and sizes) by how they were compressed
+    /// (small files are not counted as `store_only`, since they keep the current level)
+    pub fn new<S: AsRef<str>>(
+        quality: i32,
+        store_only: &[String],
+        files: impl IntoIterator<Item = (S, u64)>,
+        compressed: u64,
+        elapsed: Duration,
+    ) -> Self {
+        let store_only: HashSet<String> = store_only
+            .iter()
+            .map(|e| e.trim_start_matches('.').to_lowercase())
+            .collect();
+        let mut profile = Self {
+            quality,
+            compressed,
+            elapsed,
+            ..Default::default()
+        };
+        let mut groups: HashMap<String, GroupSize> = HashMap::new();
+        for (path, size) in files {
+            let name = extension_group(path.as_ref());
+            profile.size += size;
+            if size >= STORE_MIN_SIZE && store_only.contains(&name) {
+                profile.store_only += size;
+                continue;
+            }
+            let entry = groups.entry(name).or_insert_with_key(|k| GroupSize {
+                name: k.clone(),
+                files: 0,
+                size: 0,
+            });
+            entry.files += 1;
+            entry.size += size;
+        }
+        profile.extensions = groups.into_values().collect();
+        profile
+            .extensions
+            .sort_unstable_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+        profile
+    }
+}
+
+/// A suggestion for the compression quality of future backups
+#[derive(Debug, Clone, PartialEq)]
+pub enum QualityAdvice {
+    /// Most of the data barely shrank, so a lower quality would be faster (at almost the same size)
+    Lower {
+        /// The share of the bytes that were compressed with the full quality
+        share: f64,
+        /// How much those bytes shrank
+        saved: f64,
+        quality: i32,
+        /// The largest extensions (candidates for `store_only`)
+        extensions: Vec<String>,
+    },
+    /// Most of the data shrank a lot, and the compression was not much slower than reading the files
+    Raise {
+        share: f64,
+        saved: f64,
+        quality: i32,
+    },
+}
+
+impl Display for QualityAdvice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = |x: f64| (x * 100.0).round() as u64;
+        match self {
+            QualityAdvice::Lower {
+                share,
+                saved,
+                quality,
+                extensions,
+            } => {
+                write!(
+                    f,
+                    "{}% of the data shrank by less than {}%, consider --quality {}",
+                    percent(*share),
+                    ((saved * 100.0).ceil() as u64).max(1),
+                    quality
+                )?;
+                if !extensions.is_empty() {
+                    write!(
+                        f,
+                        " or adding these extensions to `store_only`: {}",
+                        extensions.join(", ")
+                    )?;
+                }
+                Ok(())
+            }
+            QualityAdvice::Raise {
+                share,
+                saved,
+                quality,
+            } => write!(
+                f,
+                "{}% of the data shrank by {}% and the compression kept up with reading the files, \
+                 consider --quality {} for smaller backups",
+                percent(*share),
+                percent(*saved),
+                quality
+            ),
+        }
+    }
+}
+
+/// Suggest a better compression quality for the data in a backup (if any).
+/// The files compressed with the fast level (`store_only`) are assumed to not shrink, so their
+/// quality is never questioned, and a lower quality is never suggested for low qualities.
+pub fn quality_advice(profile: &CompressionProfile) -> Option<QualityAdvice> {
+    if profile.size < ADVICE_MIN_SIZE {
+        return None;
+    }
+    let full = profile.size.saturating_sub(profile.store_only);
+    let share = full as f64 / profile.size as f64;
+    if share < ADVICE_MIN_SHARE {
+        return None;
+    }
+    let saved = profile.size.saturating_sub(profile.compressed) as f64 / full as f64;
+    if profile.quality > ADVICE_LOW_QUALITY && saved < ADVICE_INCOMPRESSIBLE {
+        // The extensions that make up most of the data
+        let mut covered = 0;
+        let extensions = profile
+            .extensions
+            .iter()
+            .take_while(|g| {
+                let more = (covered as f64) < full as f64 * 0.8;
+                covered += g.size;
+                more
+            })
+            .take(5)
+            .filter(|g| g.name != "(none)")
+            .map(|g| g.name.clone())
+            .collect();
+        return Some(QualityAdvice::Lower {
+            share,
+            saved,
+            quality: ADVICE_LOW_QUALITY,
+            extensions,
+        });
+    }
+    let reading = Duration::from_secs_f64(profile.size as f64 / ADVICE_READ_SPEED);
+    if (ADVICE_LOW_QUALITY + 1..ADVICE_RAISE_BELOW).contains(&profile.quality)
+        && saved > ADVICE_COMPRESSIBLE
+        && profile.elapsed <= reading.mul_f64(1.5)
+    {
+        return Some(QualityAdvice::Raise {
+            share,
+            saved,
+            quality: (profile.quality + 6).min(ADVICE_HIGH_QUALITY),
+        });
+    }
+    None
+}
+
+/// Information about one backup in a chain of (incremental) backups
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+    pub path: String,
+    pub time: Option<String>,
+    pub incremental: bool,
+    /// Number of files in the list (including files from previous incremental backups)
+    pub files: u64,
+    /// Number of files stored in this backup
+    pub new: u64,
+    /// Size of the backup file (in bytes)
+    pub compressed: u64,
+    /// Total size of the stored files before compression (not recorded by older versions)
+    pub size: Option<u64>,
+    /// The backup that this incremental backup continues from
+    pub previous: Option<String>,
+    /// Files that are not stored in this backup are also missing from the previous backup
+    /// (e.g. because a backup in the chain has been deleted)
+    pub broken: bool,
+    /// The backup was written by an old version (see [`BackupReader::is_legacy`])
+    pub legacy: bool,
+}
+
+impl BackupInfo {
+    pub fn new(reader: &mut BackupReader) -> Result<Self, BackupError> {
+        let compressed = archive_size(reader.path.get_path())?;
+        let path = reader.path.copy_string().to_string();
+        let (config, list) = reader.get_meta()?;
+        let time = config
+            .time
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+        let incremental = config.incremental;
+        let size = config.original_size;
+        let mut files = 0;
+        let mut new = 0;
+        let mut unstored = vec![];
+        for (inc, s) in list.iter() {
+            files += 1;
+            if inc {
+                new += 1;
+            } else {
+                unstored.push(s.to_string());
+            }
+        }
+
+        let legacy = reader.is_legacy();
+        let mut previous = reader.get_previous()?;
+        let broken = if unstored.is_empty() {
+            false
+        } else if let Some(prev) = previous.as_mut() {
+            let listed: HashSet<&str> = prev.get_list()?.iter().map(|(_, s)| s).collect();
+            unstored.iter().any(|s| !listed.contains(s.as_str()))
+        } else {
+            true
+        };
+        Ok(Self {
+            path,
+            time,
+            incremental,
+            files,
+            new,
+            compressed,
+            size,
+            previous: previous.map(|mut p| p.path.move_string()),
+            broken,
+            legacy,
+        })
+    }
+}
+
+/// How to print a summary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Json,
+}
+
+/// For every file in the list of the newest backup of a chain: the backup that supplies the file
+/// when restoring (`None` if no backup in the chain stores it).
+/// The lists (stored, path) are ordered from the newest backup, and the newest stored copy wins
+/// (the same resolution as when restoring an incremental backup recursively).
+pub fn resolve_sources<'a>(lists: &[Vec<(bool, &'a str)>]) -> Vec<(&'a str, Option<usize>)> {
+    let (newest, older) = match lists.split_first() {
+        Some(l) => l,
+        None => return vec![],
+    };
+    let stored: Vec<HashSet<&str>> = older
+        .iter()
+        .map(|l| l.iter().filter(|(b, _)| *b).map(|(_, s)| *s).collect())
+        .collect();
+    newest
+        .iter()
+        .map(|(b, s)| {
+            let source = if *b {
+                Some(0)
+            } else {
+                stored.iter().position(|l| l.contains(s)).map(|i| i + 1)
+            };
+            (*s, source)
+        })
+        .collect()
+}
+
+/// How much of a backup in a chain is needed for restoring the newest backup
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveUsage {
+    pub path: String,
+    pub time: Option<String>,
+    pub incremental: bool,
+    /// Number of files stored in this backup
+    pub stored: u64,
+    /// Uncompressed size of the stored files (in bytes)
+    pub stored_size: u64,
+    /// Number of files whose newest copy is in this backup (these are lost if it is deleted)
+    pub unique: u64,
+    /// Uncompressed size of the unique files (in bytes)
+    pub unique_size: u64,
+    /// Size of the backup file (in bytes)
+    pub compressed: u64,
+}
+
+impl ArchiveUsage {
+    /// The backup is not needed for restoring the newest backup (all files are superseded)
+    pub fn is_redundant(&self) -> bool {
+        self.unique == 0
+    }
+
+    /// The size of the backup when only the unique files are kept (assuming an equal compression)
+    fn estimated_size(&self) -> u64 {
+        if self.stored_size == 0 {
+            if self.unique == 0 {
+                0
+            } else {
+                self.compressed
+            }
+        } else {
+            (self.compressed as u128 * self.unique_size as u128 / self.stored_size as u128) as u64
+        }
+    }
+}
+
+/// A suggested merge of the newest backups in a chain
+#[derive(Debug, Clone, Serialize)]
+pub struct Consolidation {
+    /// The merged backups (from the oldest)
+    pub backups: Vec<String>,
+    /// Total size of the merged backups (in bytes)
+    pub compressed: u64,
+    /// Estimated size of the merged backup (in bytes)
+    pub estimated: u64,
+}
+
+/// How the files of the newest backup in a chain are spread over the backups
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainUsage {
+    /// The backups in the chain (from the oldest)
+    pub backups: Vec<ArchiveUsage>,
+    /// Files in the newest backup (including files from previous backups)
+    pub files: u64,
+    /// Files in the newest backup that are not stored in any backup in the chain
+    pub missing: u64,
+    /// Merges that would make the chain smaller (from the largest)
+    pub consolidations: Vec<Consolidation>,
+}
+
+impl ChainUsage {
+    /// Attribute the files of a backup (and the previous backups in the chain)
+    pub fn new(newest: BackupReader) -> Result<Self, BackupError> {
+        let mut readers = vec![newest];
+        readers[0].get_meta()?;
+        while let Some(mut prev) = readers.last_mut().unwrap().get_previous()? {
+            prev.get_meta()?;
+            readers.push(prev);
+        }
+        // Older lists are missing the sizes (then the sizes are taken from the archive)
+        let header_sizes = readers
+            .iter_mut()
+            .map(|r| {
+                if r.get_list()?
+                    .iter_meta()
+                    .any(|(b, s, _, _)| b && s.is_none())
+                {
+                    entry_sizes(r).map(Some)
+                } else {
+                    Ok(None)
+                }
+            })
+            .collect::<Result<Vec<_>, BackupError>>()?;
+        let mut backups = vec![];
+        for r in readers.iter_mut() {
+            let compressed = archive_size(r.path.get_path())?;
+            let path = r.path.copy_string().to_string();
+            let (config, _) = r.get_meta()?;
+            backups.push(ArchiveUsage {
+                path,
+                time: config
+                    .time
+                    .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()),
+                incremental: config.incremental,
+                stored: 0,
+                stored_size: 0,
+                unique: 0,
+                unique_size: 0,
+                compressed,
+            });
+        }
+        let lists = readers
+            .iter_mut()
+            .map(|r| r.get_list())
+            .collect::<Result<Vec<_>, BackupError>>()?;
+        let sizes: Vec<HashMap<&str, u64>> = lists
+            .iter()
+            .zip(header_sizes.iter())
+            .map(|(l, headers)| {
+                l.iter_meta()
+                    .filter(|(b, _, _, _)| *b)
+                    .map(|(_, size, _, s)| {
+                        let size = size
+                            .or_else(|| headers.as_ref().and_then(|h| h.get(s).copied()))
+                            .unwrap_or_default();
+                        (s, size)
+                    })
+                    .collect()
+            })
+            .collect();
+        for (b, s) in backups.iter_mut().zip(sizes.iter()) {
+            b.stored = s.len() as u64;
+            b.stored_size = s.values().sum();
+        }
+        let lists: Vec<Vec<(bool, &str)>> = lists.iter().map(|l| l.iter().collect()).collect();
+        let sources = resolve_sources(&lists);
+        let mut missing = 0;
+        for (s, source) in sources.iter() {
+            match source {
+                Some(i) => {
+                    backups[*i].unique += 1;
+                    backups[*i].unique_size += sizes[*i].get(s).copied().unwrap_or_default();
+                }
+                None => missing += 1,
+            }
+        }
+
+        // Merging the newest backups keeps the unique files of those backups
+        let mut consolidations = vec![];
+        let mut compressed = 0;
+        let mut estimated = 0;
+        for (i, b) in backups.iter().enumerate() {
+            compressed += b.compressed;
+            estimated += b.estimated_size();
+            if i > 0 && estimated < compressed {
+                consolidations.push(Consolidation {
+                    backups: backups[..=i].iter().rev().map(|b| b.path.clone()).collect(),
+                    compressed,
+                    estimated,
+                });
+            }
+        }
+        consolidations.reverse();
+        backups.reverse();
+        Ok(Self {
+            backups,
+            files: sources.len() as u64,
+            missing,
+            consolidations,
+        })
+    }
+}
+
+/// The sizes of the files stored in a backup (from the headers in the archive)
+fn entry_sizes(reader: &BackupReader) -> Result<HashMap<String, u64>, BackupError> {
+    let mut decoder = reader.get_decoder()?;
+    let mut sizes = HashMap::new();
+    for res in decoder
+        .entries()
+        .map_err(BackupError::ArchiveError)?
+        .skip(2)
+    {
+        let (mut fi, entry) = res.map_err(BackupError::ArchiveError)?;
+        sizes.insert(fi.move_string(), entry.size());
+    }
+    Ok(sizes)
+}
+
+/// The (lowercase) file extension
+fn extension_group(path: &str) -> String {
+    match Path::new(path).extension() {
+        Some(e) => e.to_string_lossy().to_lowercase(),
+        None => String::from("(none)"),
+    }
+}
+
+/// The top-level directory (or file) below the common root
+fn directory_group(path: &str, root: &Path) -> String {
+    let path = Path::new(path);
+    match path
+        .strip_prefix(root)
+        .ok()
+        .and_then(|p| p.components().next())
+    {
+        Some(c) => root.join(c).to_string_lossy().to_string(),
+        None => path.to_string_lossy().to_string(),
+    }
+}
+
+/// The directory `depth` levels below the root that contains the path (or the directory of the
+/// path if it is not that deep), "." for the current directory of relative paths
+pub fn directory_prefix(path: &Path, root: &Path, depth: usize) -> String {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let prefix = match dir.strip_prefix(root) {
+        Ok(rel) => {
+            let mut prefix = root.to_path_buf();
+            prefix.extend(rel.components().take(depth));
+            prefix
+        }
+        Err(_) => dir.to_path_buf(),
+    };
+    if prefix.as_os_str().is_empty() {
+        String::from(".")
+    } else {
+        prefix.to_string_lossy().to_string()
+    }
+}
+
+/// The longest common directory of all paths
+pub fn common_root<'a>(mut paths: impl Iterator<Item = &'a Path>) -> PathBuf {
+    let first = match paths.next() {
+        Some(p) => p,
+        None => return PathBuf::new(),
+    };
+    let mut root: Vec<Component> = match first.parent() {
+        Some(p) => p.components().collect(),
+        None => vec![],
+    };
+    for p in paths {
+        let n = root
+            .iter()
+            .zip(p.components())
+            .take_while(|(a, b)| *a == b)
+            .count();
+        root.truncate(n);
+        // Keep the root a directory (even if the path is a prefix)
+        if root.len() == p.components().count() {
+            root.pop();
+        }
+    }
+    root.iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use super::{
+        common_root, directory_group, extension_group, quality_advice, resolve_sources,
+        ArchiveUsage, CompressionProfile, QualityAdvice,
+    };
+
+    #[test]
+    fn groups() {
+        let paths = ["/home/a/x.JPG", "/home/a/y/z.txt", "/home/b/Makefile"];
+        let root = common_root(paths.iter().map(Path::new));
+        assert_eq!(root, PathBuf::from("/home"));
+        assert_eq!(directory_group(paths[0], &root), "/home/a");
+        assert_eq!(directory_group(paths[1], &root), "/home/a");
+        assert_eq!(directory_group(paths[2], &root), "/home/b");
+        assert_eq!(extension_group(paths[0]), "jpg");
+        assert_eq!(extension_group(paths[2]), "(none)");
+
+        let root = common_root(["/home/a/x.txt"].iter().map(Path::new));
+        assert_eq!(root, PathBuf::from("/home/a"));
+        assert_eq!(directory_group("/home/a/x.txt", &root), "/home/a/x.txt");
+        let root = common_root(["a/b", "a"].iter().map(Path::new));
+        assert_eq!(root, PathBuf::from(""));
+    }
+
+    #[test]
+    fn sources() {
+        assert!(resolve_sources(&[]).is_empty());
+        // Newest first: an incremental, a fully superseded incremental, and a full backup
+        let lists = vec![
+            vec![(false, "/a"), (true, "/b"), (true, "/c"), (false, "/e")],
+            vec![(false, "/a"), (true, "/b"), (true, "/c")],
+            vec![(true, "/a"), (true, "/b"), (true, "/d")],
+        ];
+        assert_eq!(resolve_sources(&lists), [
+            ("/a", Some(2)),
+            ("/b", Some(0)),
+            ("/c", Some(0)),
+            ("/e", None),
+        ]);
+        let used: Vec<usize> = resolve_sources(&lists)
+            .iter()
+            .filter_map(|(_, s)| *s)
+            .collect();
+        assert!(!used.contains(&1));
+
+        // Without the newest copy the middle backup supplies `/c`
+        let lists = vec![
+            vec![(false, "/a"), (false, "/c")],
+            vec![(false, "/a"), (true, "/c")],
+            vec![(true, "/a"), (true, "/c")],
+        ];
+        assert_eq!(resolve_sources(&lists), [("/a", Some(2)), ("/c", Some(1))]);
+    }
+
+    #[test]
+    fn estimated_size() {
+        let usage = |stored_size, unique, unique_size| ArchiveUsage {
+            path: String::new(),
+            time: None,
+            incremental: true,
+            stored: 4,
+            stored_size,
+            unique,
+            unique_size,
+            compressed: 1000,
+        };
+        assert_eq!(usage(400, 1, 100).estimated_size(), 250);
+        assert_eq!(usage(400, 4, 400).estimated_size(), 1000);
+        assert!(usage(400, 0, 0).is_redundant());
+        assert_eq!(usage(400, 0, 0).estimated_size(), 0);
+        assert_eq!(usage(0, 2, 0).estimated_size(), 1000);
+    }
+
+    #[test]
+    fn compression_advice() {
+        const GIB: u64 = 1 << 30;
+        let minute = Duration::from_secs(60);
+        let profile = |store_only: &[&str], files: &[(&str, u64)], compressed, quality, elapsed| {
+            let store_only: Vec<String> = store_only.iter().map(|s| s.to_string()).collect();
+            CompressionProfile::new(
+                quality,
+                &store_only,
+                files.iter().copied(),
+                compressed,
+                elapsed,
+            )
+        };
+        let media = [
+            ("a.mp4", 60 * GIB),
+            ("b.jpg", 27 * GIB),
+            ("c.txt", 13 * GIB),
+        ];
+        let text = [
+            ("a.txt", 70 * GIB),
+            ("b.csv", 20 * GIB),
+            ("c.jpg", 10 * GIB),
+        ];
+
+        // Media-heavy without `store_only`
+        let p = profile(&[], &media, 98 * GIB, 20, 240 * minute);
+        assert_eq!(p.size, 100 * GIB);
+        assert_eq!(p.store_only, 0);
+        match quality_advice(&p) {
+            Some(QualityAdvice::Lower {
+                quality,
+                extensions,
+                ..
+            }) => {
+                assert_eq!(quality, 6);
+                assert_eq!(extensions, ["mp4", "jpg"]);
+            }
+            res => panic!("Unexpected advice: {:?}", res),
+        }
+        assert!(quality_advice(&p).unwrap().to_string().contains("mp4, jpg"));
+        // Never when the media is already in `store_only`, or the quality is already low
+        let p = profile(&[".mp4", "JPG"], &media, 98 * GIB, 20, 240 * minute);
+        assert_eq!(p.store_only, 87 * GIB);
+        assert_eq!(quality_advice(&p), None);
+        let p = profile(&[], &media, 98 * GIB, 6, 240 * minute);
+        assert_eq!(quality_advice(&p), None);
+        let p = profile(&[], &media, 98 * GIB, 3, 10 * minute);
+        assert_eq!(quality_advice(&p), None);
+
+        // Text-heavy with a low quality, and time to spare
+        let p = profile(&["jpg"], &text, 30 * GIB, 9, 9 * minute);
+        match quality_advice(&p) {
+            Some(QualityAdvice::Raise { quality, saved, .. }) => {
+                assert_eq!(quality, 15);
+                assert!(saved > 0.7);
+            }
+            res => panic!("Unexpected advice: {:?}", res),
+        }
+        // Not when the compression was the bottleneck
+        let p = profile(&["jpg"], &text, 30 * GIB, 9, 60 * minute);
+        assert_eq!(quality_advice(&p), None);
+        // Not when the quality is already high
+        let p = profile(&["jpg"], &text, 30 * GIB, 19, 9 * minute);
+        assert_eq!(quality_advice(&p), None);
+
+        // Mixed data compresses reasonably
+        let mixed = [
+            ("a.mp4", 40 * GIB),
+            ("b.txt", 40 * GIB),
+            ("c.bin", 20 * GIB),
+        ];
+        let p = profile(&["mp4"], &mixed, 75 * GIB, 20, 120 * minute);
+        assert_eq!(quality_advice(&p), None);
+        let p = profile(&[], &mixed, 75 * GIB, 20, 120 * minute);
+        assert_eq!(quality_advice(&p), None);
+
+        // Small backups get no advice
+        let p = profile(&[], &[("a.mp4", 1 << 20)], 1 << 20, 20, minute);
+        assert_eq!(quality_advice(&p), None);
+    }
+}
@@ -0,0 +1,517 @@
+/// This module contains the write-ahead journal for operations that rename or delete several files
+/// (so that an interrupted operation can be completed or reverted the next time)
+use std::fmt::{Display, Formatter};
+use std::fs::{remove_file, rename, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// The file name prefix of the journals (followed by the operation id)
+pub const JOURNAL_PREFIX: &str = "backup_journal_";
+/// The file extension of the journals
+pub const JOURNAL_EXTENSION: &str = ".json";
+
+/// One change to the file system
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Step {
+    Rename { from: PathBuf, to: PathBuf },
+    Delete { path: PathBuf },
+}
+
+impl Step {
+    fn apply(&self) -> std::io::Result<()> {
+        match self {
+            Step::Rename { from, to } => rename(from, to),
+            Step::Delete { path } => remove_file(path),
+        }
+    }
+
+    /// Perform the step unless it has already been performed (returns whether anything was changed)
+    fn redo(&self) -> std::io::Result<bool> {
+        match self {
+            Step::Rename { from, .. } | Step::Delete { path: from } if !from.exists() => Ok(false),
+            _ => self.apply().map(|_| true),
+        }
+    }
+
+    /// Revert the step if it has been performed (returns whether anything was changed).
+    /// Deleted files cannot be restored, so they are returned as an error instead.
+    fn undo(&self) -> Result<bool, (PathBuf, Option<std::io::Error>)> {
+        match self {
+            Step::Rename { from, to } if to.exists() && !from.exists() => rename(to, from)
+                .map(|_| true)
+                .map_err(|e| (to.clone(), Some(e))),
+            Step::Rename { .. } => Ok(false),
+            Step::Delete { path } if path.exists() => Ok(false),
+            Step::Delete { path } => Err((path.clone(), None)),
+        }
+    }
+}
+
+/// Can the remaining steps be performed (all files that should be renamed are still present).
+/// The first remaining step might already have been performed (if interrupted before the journal was updated).
+fn can_complete(steps: &[Step]) -> bool {
+    steps.iter().enumerate().all(|(i, s)| match s {
+        Step::Rename { from, to } => from.exists() || (i == 0 && to.exists()),
+        Step::Delete { .. } => true,
+    })
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Step::Rename { from, to } => write!(
+                f,
+                "rename '{}' to '{}'",
+                from.to_string_lossy(),
+                to.to_string_lossy()
+            ),
+            Step::Delete { path } => write!(f, "delete '{}'", path.to_string_lossy()),
+        }
+    }
+}
+
+/// The recovery direction of an interrupted operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    /// The operation was interrupted while performing the steps
+    Pending,
+    /// The recovery completes the operation
+    Forward,
+    /// The recovery reverts the operation
+    Backward,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Record {
+    id: String,
+    direction: Direction,
+    /// The number of performed steps (when reverting: the number of steps that might still need to be reverted)
+    done: usize,
+    steps: Vec<Step>,
+}
+
+/// The failure of a [`Transaction`]
+#[derive(Debug)]
+pub enum TxnError {
+    /// The journal could not be written (no steps have been performed)
+    Journal(std::io::Error),
+    /// A step failed (the preceding steps have been performed)
+    Step(Step, std::io::Error),
+}
+
+impl Display for TxnError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            TxnError::Journal(e) => write!(f, "Could not write the journal: {}", e),
+            TxnError::Step(s, e) => write!(f, "Could not {}: {}", s, e),
+        }
+    }
+}
+
+impl std::error::Error for TxnError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            // The error is part of the message, so only the cause of it is the source
+            TxnError::Journal(e) | TxnError::Step(_, e) => e.source(),
+        }
+    }
+}
+
+impl From<TxnError> for std::io::Error {
+    fn from(e: TxnError) -> Self {
+        match e {
+            TxnError::Journal(e) | TxnError::Step(_, e) => e,
+        }
+    }
+}
+
+/// A list of renames and deletes that are performed together
+#[derive(Debug, Clone, Default)]
+pub struct Transaction {
+    steps: Vec<Step>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn rename<P: Into<PathBuf>, Q: Into<PathBuf>>(&mut self, from: P, to: Q) -> &mut Self {
+        self.steps.push(Step::Rename {
+            from: from.into(),
+            to: to.into(),
+        });
+        self
+    }
+
+    pub fn delete<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.steps.push(Step::Delete { path: path.into() });
+        self
+    }
+
+    #[allow(unused)]
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Perform the steps in order.
+    /// With a `journal` directory the steps are first written to a journal (which is removed when
+    /// all steps are complete), so that an interrupted operation can be recovered with [`recover`].
+    pub fn commit(self, journal: Option<&Path>) -> Result<(), TxnError> {
+        let dir = match journal {
+            Some(dir) if !self.steps.is_empty() => dir,
+            _ => {
+                return self
+                    .steps
+                    .into_iter()
+                    .try_for_each(|s| s.apply().map_err(|e| TxnError::Step(s, e)));
+            }
+        };
+        let mut record = Record {
+            id: operation_id(),
+            direction: Direction::Pending,
+            done: 0,
+            steps: self.steps,
+        };
+        let path = journal_path(dir, &record.id);
+        write_record(&path, &record).map_err(TxnError::Journal)?;
+        while record.done < record.steps.len() {
+            let step = &record.steps[record.done];
+            step.apply().map_err(|e| TxnError::Step(step.clone(), e))?;
+            record.done += 1;
+            if record.done < record.steps.len() {
+                write_record(&path, &record).map_err(TxnError::Journal)?;
+            }
+        }
+        // The journal is only removed after all steps are done
+        remove_file(path).map_err(TxnError::Journal)
+    }
+}
+
+/// A unique id for an operation (from the time and the process)
+fn operation_id() -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("{:x}-{:x}", time, std::process::id())
+}
+
+fn journal_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}{}{}", JOURNAL_PREFIX, id, JOURNAL_EXTENSION))
+}
+
+/// Write the journal atomically (through a temporary file)
+fn write_record(path: &Path, record: &Record) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut writer, record)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    std::mem::drop(writer);
+    rename(&tmp, path)
+}
+
+/// The outcome of recovering an interrupted operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recovery {
+    pub id: String,
+    /// Was the operation completed (or reverted)
+    pub forward: bool,
+    /// The number of steps that were performed (or reverted) during the recovery
+    pub changed: usize,
+    /// Files that could not be restored when reverting (because they were already deleted)
+    pub lost: Vec<PathBuf>,
+}
+
+/// Recover an interrupted operation from its journal.
+/// The operation is completed if possible (all files that have not yet been renamed are still
+/// present), otherwise the renamed files are restored to their original names.
+/// The decision is stored in the journal, so recovering again (after another interruption) is safe.
+pub fn recover_journal<P: AsRef<Path>>(path: P) -> std::io::Result<Recovery> {
+    let path = path.as_ref();
+    let mut record: Record = serde_json::from_reader(BufReader::new(File::open(path)?))
+        .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+    if record.direction == Direction::Pending {
+        let remaining = &record.steps[record.done.min(record.steps.len())..];
+        if can_complete(remaining) {
+            record.direction = Direction::Forward;
+        } else {
+            record.direction = Direction::Backward;
+            record.done = (record.done + 1).min(record.steps.len());
+        }
+        write_record(path, &record)?;
+    }
+    let mut changed = 0;
+    let mut lost = vec![];
+    if record.direction == Direction::Forward {
+        while record.done < record.steps.len() {
+            if record.steps[record.done].redo()? {
+                changed += 1;
+            }
+            record.done += 1;
+            write_record(path, &record)?;
+        }
+    } else {
+        while record.done > 0 {
+            match record.steps[record.done - 1].undo() {
+                Ok(true) => changed += 1,
+                Ok(false) => {}
+                Err((_, Some(e))) => return Err(e),
+                Err((p, None)) => lost.push(p),
+            }
+            record.done -= 1;
+            write_record(path, &record)?;
+        }
+    }
+    remove_file(path)?;
+    Ok(Recovery {
+        id: record.id,
+        forward: record.direction == Direction::Forward,
+        changed,
+        lost,
+    })
+}
+
+/// Recover all interrupted operations with a journal in the directory.
+/// Incomplete journals (the operation was interrupted before any step) are removed.
+pub fn recover<P: AsRef<Path>>(dir: P) -> std::io::Result<Vec<Recovery>> {
+    let dir = dir.as_ref();
+    let mut journals = vec![];
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(n) if n.starts_with(JOURNAL_PREFIX) => n,
+            _ => continue,
+        };
+        if name.ends_with(JOURNAL_EXTENSION) {
+            journals.push(entry.path());
+        } else if name.ends_with(".tmp") {
+            remove_file(entry.path())?;
+        }
+    }
+    journals.sort();
+    journals.iter().map(recover_journal).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_to_string, remove_file, write};
+    use std::path::{Path, PathBuf};
+
+    use super::{
+        journal_path, recover, recover_journal, write_record, Direction, Record, Step, Transaction,
+    };
+
+    /// Files `a` and `b` are moved to `a.old` and `b.old`, `c` is deleted, and `new` replaces `a`
+    fn setup(dir: &Path) -> Transaction {
+        for name in ["a", "b", "c", "new"] {
+            write(dir.join(name), name).unwrap();
+        }
+        let mut txn = Transaction::new();
+        txn.rename(dir.join("a"), dir.join("a.old"))
+            .rename(dir.join("b"), dir.join("b.old"))
+            .delete(dir.join("c"))
+            .rename(dir.join("new"), dir.join("a"));
+        txn
+    }
+
+    fn contents(dir: &Path) -> Vec<(String, String)> {
+        let mut files: Vec<(String, String)> = dir
+            .read_dir()
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .map(|p| {
+                (
+                    p.file_name().unwrap().to_string_lossy().to_string(),
+                    read_to_string(p).unwrap(),
+                )
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    fn expected(files: &[(&str, &str)]) -> Vec<(String, String)> {
+        files
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect()
+    }
+
+    const AFTER: [(&str, &str); 3] = [("a", "new"), ("a.old", "a"), ("b.old", "b")];
+    const BEFORE: [(&str, &str); 3] = [("a", "a"), ("b", "b"), ("c", "c")];
+
+    /// Write a journal (with `done` steps recorded) and perform the first `applied` steps
+    fn crash(
+        dir: &Path,
+        txn: &Transaction,
+        direction: Direction,
+        done: usize,
+        applied: usize,
+    ) -> PathBuf {
+        let record = Record {
+            id: format!("test{}", applied),
+            direction,
+            done,
+            steps: txn.steps().to_vec(),
+        };
+        let path = journal_path(dir, &record.id);
+        write_record(&path, &record).unwrap();
+        for s in txn.steps().iter().take(applied) {
+            s.apply().unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let txn = setup(dir.path());
+        txn.commit(Some(dir.path())).unwrap();
+        assert_eq!(contents(dir.path()), expected(&AFTER));
+
+        let dir = tempfile::tempdir().unwrap();
+        let txn = setup(dir.path());
+        txn.commit(None).unwrap();
+        assert_eq!(contents(dir.path()), expected(&AFTER));
+
+        let dir = tempfile::tempdir().unwrap();
+        let txn = setup(dir.path());
+        remove_file(dir.path().join("c")).unwrap();
+        assert!(txn.commit(Some(dir.path())).is_err());
+        // The journal is kept for recovery
+        let rec = recover(dir.path()).unwrap();
+        assert_eq!(rec.len(), 1);
+        assert!(rec[0].forward);
+        assert_eq!(rec[0].changed, 1);
+        assert_eq!(contents(dir.path()), expected(&AFTER));
+    }
+
+    #[test]
+    fn recover_forward() {
+        // Interrupted after each step (both before and after the journal is updated)
+        for applied in 0..=4usize {
+            for done in [applied, applied.saturating_sub(1)] {
+                let dir = tempfile::tempdir().unwrap();
+                let txn = setup(dir.path());
+                crash(dir.path(), &txn, Direction::Pending, done, applied);
+                let rec = recover(dir.path()).unwrap();
+                assert_eq!(rec.len(), 1);
+                assert!(rec[0].forward, "after {} steps", applied);
+                assert_eq!(rec[0].changed, 4 - applied);
+                assert!(rec[0].lost.is_empty());
+                assert_eq!(
+                    contents(dir.path()),
+                    expected(&AFTER),
+                    "after {} steps",
+                    applied
+                );
+                // Recovering again does nothing
+                assert!(recover(dir.path()).unwrap().is_empty());
+                assert_eq!(contents(dir.path()), expected(&AFTER));
+            }
+        }
+    }
+
+    #[test]
+    fn recover_backward() {
+        for applied in 0..=3usize {
+            for done in [applied, applied.saturating_sub(1)] {
+                let dir = tempfile::tempdir().unwrap();
+                let txn = setup(dir.path());
+                crash(dir.path(), &txn, Direction::Pending, done, applied);
+                // The replacement disappears (so the operation cannot be completed)
+                remove_file(dir.path().join("new")).unwrap();
+                let rec = recover(dir.path()).unwrap();
+                assert_eq!(rec.len(), 1);
+                assert!(!rec[0].forward, "after {} steps", applied);
+                assert_eq!(rec[0].changed, applied.min(2));
+                if applied < 3 {
+                    assert!(rec[0].lost.is_empty());
+                    assert_eq!(contents(dir.path()), expected(&BEFORE));
+                } else {
+                    assert_eq!(rec[0].lost, vec![dir.path().join("c")]);
+                    assert_eq!(contents(dir.path()), expected(&BEFORE[..2]));
+                }
+                assert!(recover(dir.path()).unwrap().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn recover_interrupted() {
+        // Interrupted while completing the operation (after each step)
+        for applied in 0..=4usize {
+            for done in [applied, applied.saturating_sub(1)] {
+                let dir = tempfile::tempdir().unwrap();
+                let txn = setup(dir.path());
+                let path = crash(dir.path(), &txn, Direction::Forward, done, applied);
+                let rec = recover_journal(path).unwrap();
+                assert!(rec.forward);
+                assert_eq!(rec.changed, 4 - applied);
+                assert_eq!(contents(dir.path()), expected(&AFTER));
+            }
+        }
+        // Interrupted while reverting the operation (after each step)
+        for reverted in 0..=2 {
+            for extra in [0, 1] {
+                let dir = tempfile::tempdir().unwrap();
+                let txn = setup(dir.path());
+                let remaining = 2 - reverted;
+                let path = crash(dir.path(), &txn, Direction::Backward, remaining + extra, 2);
+                for s in txn.steps()[remaining..2].iter().rev() {
+                    s.undo().unwrap();
+                }
+                // Reverting continues even if the operation could now be completed
+                let rec = recover_journal(path).unwrap();
+                assert!(!rec.forward);
+                assert_eq!(rec.changed, remaining);
+                assert!(rec.lost.is_empty());
+                assert_eq!(
+                    contents(dir.path()),
+                    expected(&[("a", "a"), ("b", "b"), ("c", "c"), ("new", "new")])
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn recover_incomplete_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let txn = setup(dir.path());
+        let path = crash(dir.path(), &txn, Direction::Pending, 0, 0);
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::rename(&path, &tmp).unwrap();
+        assert!(recover(dir.path()).unwrap().is_empty());
+        assert_eq!(
+            contents(dir.path()),
+            expected(&[("a", "a"), ("b", "b"), ("c", "c"), ("new", "new")])
+        );
+        assert!(recover(dir.path().join("missing")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn step_format() {
+        let step = Step::Delete {
+            path: PathBuf::from("x"),
+        };
+        let json = serde_json::to_string(&step).unwrap();
+        assert_eq!(json, r#"{"op":"delete","path":"x"}"#);
+        assert_eq!(serde_json::from_str::<Step>(&json).unwrap(), step);
+    }
+}
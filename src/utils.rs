@@ -1,14 +1,21 @@
 /// This module contains utility functions (such as getting backups and configs)
+use std::borrow::Cow;
 use std::cmp::PartialOrd;
 use std::ffi::{OsStr, OsString};
 use std::fs::ReadDir;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use chrono::NaiveDateTime;
 use number_prefix::NumberPrefix;
 
-use crate::backup::{BackupError, BackupReader, BACKUP_FILE_EXTENSION, CONFIG_FILE_EXTENSION};
+use crate::backup::{
+    BackupError, BackupReader, BACKUP_FILE_EXTENSION, CONFIG_FILE_EXTENSIONS, STREAM_PATH,
+};
+use crate::compression::{archive_exists, archive_metadata, is_multi_volume, volume_base};
 use crate::config::Config;
+use crate::files::FileInfo;
+use crate::parse_date;
 use crate::parse_date::parse_backup_file_name;
 
 macro_rules! try_some {
@@ -47,14 +54,150 @@ pub fn format_size(size: u64) -> String {
     }
 }
 
+/// Format a number with thousands separators (e.g. "12,345")
+pub fn format_count(count: u64) -> String {
+    let digits = count.to_string();
+    let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Format a duration compactly (e.g. "14m12s" or "1h02m03s")
+pub fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, _) => format!("{:.1}s", elapsed.as_secs_f64()),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+/// Format a time relative to now (e.g. "2 days ago" or "3 weeks ago")
+pub fn format_relative(time: NaiveDateTime) -> String {
+    format_relative_to(time, parse_date::naive_now())
+}
+
+/// Format a time relative to another time (see [`format_relative`])
+pub fn format_relative_to(time: NaiveDateTime, now: NaiveDateTime) -> String {
+    const DAY: i64 = 24 * 3600;
+    let (n, unit) = match (now - time).num_seconds() {
+        // Allow for some clock skew (e.g. a backup from another computer)
+        s if s < -60 => return String::from("in the future"),
+        s if s < 60 => return String::from("just now"),
+        s if s < 3600 => (s / 60, "minute"),
+        s if s < DAY => (s / 3600, "hour"),
+        s if s < 7 * DAY => (s / DAY, "day"),
+        s if s < 30 * DAY => (s / (7 * DAY), "week"),
+        s if s < 365 * DAY => (s / (30 * DAY), "month"),
+        s => (s / (365 * DAY), "year"),
+    };
+    format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+}
+
+/// Parse a size such as "4000M" (units: K, M, G, or T, as powers of 1024)
+pub fn parse_size(s: &str) -> Result<u64, &'static str> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let mult: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return Err("Unknown unit (use K, M, G, or T)"),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(mult))
+        .ok_or("Must be a size (e.g. 4000M)")
+}
+
 fn get_probable_time<P: AsRef<Path>>(path: P) -> Option<NaiveDateTime> {
     let path = path.as_ref();
     let s = path.file_name()?;
     if let Ok(ndt) = parse_backup_file_name(&s.to_string_lossy()) {
         return Some(ndt);
     }
-    let br = BackupReader::read_config_only(path.to_path_buf()).ok()?;
-    br.time
+    if let Some(time) = BackupReader::read_config_only(path.to_path_buf())
+        .ok()
+        .and_then(|c| c.time)
+    {
+        return Some(time);
+    }
+    // Fall back to the modification time for archives without any timestamps
+    let md = archive_metadata(path).ok()?;
+    Some(parse_date::system_to_naive(md.modified().ok()?))
+}
+
+/// The backup name if the path is the first volume (`*.tar.zst.001`) of a multi-volume backup
+pub fn first_volume_backup<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    volume_base(path).filter(|p| p.to_string_lossy().ends_with(BACKUP_FILE_EXTENSION))
+}
+
+/// Suffixes given to backups that have been replaced (e.g. `.old` after a merge)
+const SUPERSEDED_SUFFIXES: [&str; 2] = [".old", ".tmp"];
+
+/// Check if the file name is a backup followed by one or more `.old`/`.tmp` suffixes
+/// (these are readable, but not part of the incremental chain)
+pub fn is_superseded_backup<P: AsRef<Path>>(path: P) -> bool {
+    let name = match path.as_ref().file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => return false,
+    };
+    let mut name = name.as_ref();
+    let mut suffixed = false;
+    while let Some(s) = SUPERSEDED_SUFFIXES
+        .iter()
+        .find_map(|s| name.strip_suffix(s))
+    {
+        name = s;
+        suffixed = true;
+    }
+    suffixed && name.ends_with(BACKUP_FILE_EXTENSION)
+}
+
+/// Get the superseded backups in a directory
+pub fn get_superseded_backups<P: AsRef<Path>>(dir: P) -> std::io::Result<Vec<PathBuf>> {
+    let mut vec = vec![];
+    for entry in dir.as_ref().read_dir()? {
+        let path = entry?.path();
+        if is_superseded_backup(&path) && path.is_file() {
+            vec.push(path);
+        }
+    }
+    vec.sort();
+    Ok(vec)
+}
+
+/// Find the backup (in the same directory) that a superseded backup was merged into.
+/// Returns the merged backup and when it was written.
+pub fn find_superseding<P: AsRef<Path>>(path: P) -> Option<(PathBuf, NaiveDateTime)> {
+    let path = path.as_ref();
+    let name = path.file_name()?.to_string_lossy();
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let mut candidates: Vec<PathBuf> = BackupIterator::dir(dir).filter_map(|r| r.ok()).collect();
+    candidates.extend(get_superseded_backups(dir).ok()?);
+    for c in candidates {
+        if c.file_name() == path.file_name() {
+            continue;
+        }
+        if let Ok(conf) = BackupReader::read_config_only(c.clone()) {
+            if conf.merged.iter().any(|m| *m == name) {
+                let time = c.metadata().and_then(|md| md.modified()).ok()?;
+                return Some((c, parse_date::system_to_naive(time)));
+            }
+        }
+    }
+    None
 }
 
 pub struct BackupIterator {
@@ -64,9 +207,11 @@ pub struct BackupIterator {
 
 impl BackupIterator {
     /// Create an iterator over backups based on ONE specific backup
+    /// (multi-volume backups can be given by their first volume, `*.001`)
     pub fn file(path: PathBuf) -> Self {
+        let path = first_volume_backup(&path).unwrap_or(path);
         BackupIterator {
-            constant: Some(path.metadata().map(|_| path)),
+            constant: Some(archive_metadata(&path).map(|_| path)),
             dir: None,
         }
     }
@@ -92,7 +237,7 @@ impl BackupIterator {
         let iter = match ConfigPathType::parse(path)? {
             ConfigPathType::Dir(path) => BackupIterator::dir(path),
             ConfigPathType::Backup(path) => BackupIterator::file(path),
-            ConfigPathType::Config(path) => BackupIterator::path(Config::read_yaml(path)?.output)?,
+            ConfigPathType::Config(path) => BackupIterator::path(Config::read_file(path)?.output)?,
         };
         if let Some(Err(e)) = iter.constant {
             Err(BackupError::IOError(e))
@@ -123,6 +268,43 @@ impl BackupIterator {
             .map(|(p, _)| p)
     }
 
+    /// Get the next backup based on a file name
+    pub fn get_next(&mut self, path: &PathBuf) -> Option<PathBuf> {
+        let time = get_probable_time(path);
+        self.filter_map(|res| res.ok())
+            .filter_map(|p| {
+                let t2 = get_probable_time(&p);
+                if t2 > time {
+                    Some((p, t2))
+                } else {
+                    None
+                }
+            })
+            .min_by_key(|(_, t)| *t)
+            .map(|(p, _)| p)
+    }
+
+    /// Split the backups into those with a time in the range (`after <= time < before`) and those
+    /// outside it (both in chronological order)
+    pub fn split_range(
+        &mut self,
+        after: Option<NaiveDateTime>,
+        before: Option<NaiveDateTime>,
+    ) -> std::io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+        let mut vec = self
+            .map(|res| res.map(|p| (get_probable_time(&p), p)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        vec.sort_by_key(|(t, _)| *t);
+        let (inside, outside): (Vec<_>, Vec<_>) = vec.into_iter().partition(|(t, _)| match t {
+            Some(t) => after.is_none_or(|a| *t >= a) && before.is_none_or(|b| *t < b),
+            None => after.is_none() && before.is_none(),
+        });
+        Ok((
+            inside.into_iter().map(|(_, p)| p).collect(),
+            outside.into_iter().map(|(_, p)| p).collect(),
+        ))
+    }
+
     /// Get a vec of backups in chronological order
     #[allow(unused)]
     pub fn get_all(&mut self) -> std::io::Result<Vec<PathBuf>> {
@@ -149,6 +331,12 @@ impl Iterator for BackupIterator {
                     if s.ends_with(BACKUP_FILE_EXTENSION) {
                         return Some(Ok(path));
                     }
+                    // Multi-volume backups are listed once (by the name without the volume number)
+                    if let Some(base) = first_volume_backup(&path) {
+                        if !base.exists() {
+                            return Some(Ok(base));
+                        }
+                    }
                 }
             }
             None
@@ -158,7 +346,7 @@ impl Iterator for BackupIterator {
     }
 }
 
-enum ConfigPathType<P: AsRef<Path>> {
+pub(crate) enum ConfigPathType<P: AsRef<Path>> {
     Dir(P),
     Backup(P),
     Config(P),
@@ -167,15 +355,32 @@ enum ConfigPathType<P: AsRef<Path>> {
 impl<P: AsRef<Path>> ConfigPathType<P> {
     /// Parse a path to get how the config should be extracted
     pub fn parse(path: P) -> Result<Self, BackupError> {
+        Self::parse_inner(path, false)
+    }
+
+    /// Parse a path, also accepting superseded backups (see [`is_superseded_backup`]),
+    /// for uses that only read the backup
+    pub fn parse_read_only(path: P) -> Result<Self, BackupError> {
+        Self::parse_inner(path, true)
+    }
+
+    fn parse_inner(path: P, superseded: bool) -> Result<Self, BackupError> {
         let p = path.as_ref();
+        if is_multi_volume(p) && p.to_string_lossy().ends_with(BACKUP_FILE_EXTENSION) {
+            return Ok(Self::Backup(path));
+        }
         let md = p.metadata().map_err(BackupError::FileError)?;
         if md.is_dir() {
             return Ok(Self::Dir(path));
         } else if md.is_file() {
             let s = p.to_string_lossy();
-            if s.ends_with(CONFIG_FILE_EXTENSION) {
+            if CONFIG_FILE_EXTENSIONS.iter().any(|e| s.ends_with(e)) {
                 return Ok(Self::Config(path));
-            } else if s.ends_with(BACKUP_FILE_EXTENSION) {
+            } else if s.ends_with(BACKUP_FILE_EXTENSION)
+                || (superseded && is_superseded_backup(p))
+                || first_volume_backup(p).is_some()
+            {
+                // The first volume is mapped to the backup name when reading
                 return Ok(Self::Backup(path));
             }
         }
@@ -183,10 +388,17 @@ impl<P: AsRef<Path>> ConfigPathType<P> {
     }
 }
 
-/// Get a config based upon the path
+/// Get a config based upon the path (configs with errors are rejected, see [`Config::check`])
 pub fn get_config_from_path(path: PathBuf) -> Result<Config, BackupError> {
+    let config = read_config_from_path(path)?;
+    config.check()?;
+    Ok(config)
+}
+
+/// Get a config based upon the path, without checking it for errors (e.g. for fixing the config)
+pub fn read_config_from_path(path: PathBuf) -> Result<Config, BackupError> {
     match ConfigPathType::parse(path)? {
-        ConfigPathType::Config(path) => Config::read_yaml(path).map_err(BackupError::FileError),
+        ConfigPathType::Config(path) => Config::read_file(path).map_err(BackupError::FileError),
         ConfigPathType::Backup(path) => BackupReader::read_config_only(path),
         ConfigPathType::Dir(path) => match BackupIterator::dir(&path).get_latest() {
             None => Err(BackupError::NoBackup(path)),
@@ -195,10 +407,10 @@ pub fn get_config_from_path(path: PathBuf) -> Result<Config, BackupError> {
     }
 }
 
-/// Get a BackupReader based upon the path
+/// Get a BackupReader based upon the path (superseded backups are also accepted)
 pub fn get_backup_from_path(path: PathBuf) -> Result<BackupReader, BackupError> {
-    match ConfigPathType::parse(path)? {
-        ConfigPathType::Config(path) => Ok(BackupReader::from_config(Config::read_yaml(path)?)?),
+    match ConfigPathType::parse_read_only(path)? {
+        ConfigPathType::Config(path) => Ok(BackupReader::from_config(Config::read_file(path)?)?),
         ConfigPathType::Backup(path) => Ok(BackupReader::new(path)),
         ConfigPathType::Dir(path) => match BackupIterator::dir(&path).get_latest() {
             None => Err(BackupError::NoBackup(path)),
@@ -207,6 +419,190 @@ pub fn get_backup_from_path(path: PathBuf) -> Result<BackupReader, BackupError>
     }
 }
 
+/// Open a backup (see [`get_backup_from_path`]), or read it from stdin if the path is `-`
+pub fn get_backup_from_source(path: PathBuf) -> Result<BackupReader, BackupError> {
+    if path.as_os_str() == STREAM_PATH {
+        Ok(BackupReader::from_stream(std::io::stdin()))
+    } else {
+        get_backup_from_path(path)
+    }
+}
+
+/// How many paths [`sample_config_diff`] checks at most
+const SAMPLE_LIMIT: usize = 100_000;
+
+/// Walk a directory and check which paths the two configs classify differently
+/// (using the same classification as the backups, see [`crate::files::FileCrawler::check_path`]).
+/// Only the topmost differing path is reported (not all paths inside a differing directory),
+/// together with whether it is backed up by the first config.
+pub fn sample_config_diff(
+    first: &Config,
+    second: &Config,
+    dir: &Path,
+) -> Result<Vec<(PathBuf, bool)>, BackupError> {
+    let mut first = first.clone();
+    let mut second = second.clone();
+    first.read_pattern_files()?;
+    second.read_pattern_files()?;
+    let first = first.crawler()?;
+    let second = second.crawler()?;
+    let mut diff = vec![];
+    let root = (
+        first.check_path(&mut FileInfo::from(dir), None),
+        second.check_path(&mut FileInfo::from(dir), None),
+    );
+    if root.0 != root.1 {
+        diff.push((dir.to_path_buf(), root.0));
+    }
+    let mut stack = vec![(dir.to_path_buf(), root)];
+    let mut visited = 0;
+    'walk: while let Some((dir, parent)) = stack.pop() {
+        let entries = match std::fs::read_dir(long_path(&dir)) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            visited += 1;
+            if visited > SAMPLE_LIMIT {
+                break 'walk;
+            }
+            let path = dir.join(entry.file_name());
+            let inc = (
+                first.check_path(&mut FileInfo::from(path.as_path()), Some(parent.0)),
+                second.check_path(&mut FileInfo::from(path.as_path()), Some(parent.1)),
+            );
+            if inc.0 != inc.1 && inc != parent {
+                diff.push((path.clone(), inc.0));
+            }
+            // Do not follow symlinks (just like the crawler)
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                stack.push((path, inc));
+            }
+        }
+    }
+    diff.sort_unstable();
+    Ok(diff)
+}
+
+/// A (line-based) unified diff between two texts, with three lines of context around the changes
+/// (empty if the texts have the same lines)
+pub fn unified_diff(before: &str, after: &str) -> String {
+    const CONTEXT: usize = 3;
+    let a: Vec<&str> = before.lines().collect();
+    let b: Vec<&str> = after.lines().collect();
+    // Longest common subsequence (the texts are small, e.g. configs)
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    // The edit script as (tag, index in before, index in after)
+    let mut ops = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            ops.push((' ', i, j));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push(('-', i, j));
+            i += 1;
+        } else {
+            ops.push(('+', i, j));
+            j += 1;
+        }
+    }
+    let mut out = String::new();
+    let mut k = 0;
+    while let Some(first) = ops[k..].iter().position(|(t, _, _)| *t != ' ') {
+        // Extend the hunk until there are more than twice the context of unchanged lines
+        let start = (k + first).saturating_sub(CONTEXT);
+        let mut end = k + first;
+        let mut unchanged = 0;
+        for (n, (t, _, _)) in ops.iter().enumerate().skip(k + first) {
+            if *t == ' ' {
+                unchanged += 1;
+                if unchanged > 2 * CONTEXT {
+                    break;
+                }
+            } else {
+                unchanged = 0;
+                end = n;
+            }
+        }
+        let end = (end + 1 + CONTEXT).min(ops.len());
+        if out.is_empty() {
+            out.push_str("--- before\n+++ after\n");
+        }
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|(t, _, _)| *t != '+').count();
+        let new_len = hunk.iter().filter(|(t, _, _)| *t != '-').count();
+        // Empty ranges start at the line before (as in diff)
+        let old_start = hunk[0].1 + (old_len > 0) as usize;
+        let new_start = hunk[0].2 + (new_len > 0) as usize;
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start, old_len, new_start, new_len
+        ));
+        for (t, i, j) in hunk {
+            let line = if *t == '+' { b[*j] } else { a[*i] };
+            out.push(*t);
+            out.push_str(line);
+            out.push('\n');
+        }
+        k = end;
+    }
+    out
+}
+
+/// Use an extended-length path (`\\?\`) on Windows, so that paths longer than `MAX_PATH` work.
+/// The prefix is only for accessing the file system (it should never end up in a file list).
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    use std::path::{Component, Prefix};
+
+    use path_absolutize::Absolutize;
+    let absolute = match path.absolutize() {
+        Ok(p) => p,
+        Err(_) => return Cow::Borrowed(path),
+    };
+    let mut components = absolute.components();
+    let long = match components.next() {
+        Some(Component::Prefix(p)) => match p.kind() {
+            Prefix::Disk(_) => {
+                let mut s = OsString::from(r"\\?\");
+                s.push(absolute.as_os_str());
+                s
+            }
+            Prefix::UNC(server, share) => {
+                let mut s = OsString::from(r"\\?\UNC\");
+                s.push(server);
+                s.push(r"\");
+                s.push(share);
+                for c in components.filter(|c| !matches!(c, Component::RootDir)) {
+                    s.push(r"\");
+                    s.push(c.as_os_str());
+                }
+                s
+            }
+            // Already verbatim (or a device path)
+            _ => return absolute,
+        },
+        _ => return absolute,
+    };
+    Cow::Owned(PathBuf::from(long))
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
 pub fn strip_absolute_from_path(path: &str) -> String {
     let path = path.trim_start_matches('.');
     let path = path.trim_start_matches('/');
@@ -221,6 +617,81 @@ pub fn strip_absolute_from_path(path: &str) -> String {
     }
 }
 
+/// Replace the longest matching path prefix (from a list of `(from, to)`)
+pub fn remap_path(path: &str, map: &[(String, String)]) -> Option<String> {
+    map.iter()
+        .map(|(from, to)| (from.trim_end_matches('/'), to.trim_end_matches('/')))
+        .filter(|(from, _)| {
+            path.strip_prefix(from)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .max_by_key(|(from, _)| from.len())
+        .map(|(from, to)| to.to_string() + &path[from.len()..])
+}
+
+/// Remove a leading path (whole components) and return the rest (`None` if the path does not
+/// start with the prefix, or is the prefix)
+pub fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    path.strip_prefix(prefix.trim_end_matches('/'))
+        .and_then(|rest| rest.strip_prefix('/'))
+        .filter(|rest| !rest.is_empty())
+}
+
+/// Expand a leading `~` and environment variables (`$VAR` or `${VAR}`) in a path
+/// (unknown variables are left as is)
+pub fn expand_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    let mut rest = path;
+    if let Some(tail) = rest.strip_prefix('~') {
+        if tail.is_empty() || tail.starts_with('/') || tail.starts_with(std::path::MAIN_SEPARATOR) {
+            if let Some(home) = user_home() {
+                out.push_str(&home.to_string_lossy());
+                rest = tail;
+            }
+        }
+    }
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        match std::env::var(name) {
+            Ok(value) if !name.is_empty() => {
+                out.push_str(&value);
+                rest = &after[len..];
+            }
+            _ => {
+                out.push('$');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(feature = "dirs")]
+fn user_home() -> Option<PathBuf> {
+    dirs::home_dir()
+}
+
+#[cfg(not(feature = "dirs"))]
+fn user_home() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
 pub fn extend_pathbuf<S: AsRef<OsStr>>(mut path: PathBuf, extension: S) -> PathBuf {
     let mut p: OsString = path.into();
     p.push(extension);
@@ -228,6 +699,15 @@ pub fn extend_pathbuf<S: AsRef<OsStr>>(mut path: PathBuf, extension: S) -> PathB
     path
 }
 
+/// A free path for writing an archive before renaming it to `path` (by appending `.tmp` suffixes)
+pub fn temporary_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut path = extend_pathbuf(path.as_ref().to_path_buf(), ".tmp");
+    while archive_exists(&path) {
+        path = extend_pathbuf(path, ".tmp");
+    }
+    path
+}
+
 #[cfg(feature = "dirs")]
 pub fn default_dir() -> PathBuf {
     std::env::current_dir()
@@ -241,6 +721,18 @@ pub fn home_dir() -> PathBuf {
         .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")))
 }
 
+#[cfg(feature = "dirs")]
+#[allow(unused)]
+pub fn settings_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|p| p.join("simple_backup"))
+}
+
+#[cfg(not(feature = "dirs"))]
+#[allow(unused)]
+pub fn settings_dir() -> Option<PathBuf> {
+    None
+}
+
 #[cfg(not(feature = "dirs"))]
 pub fn default_dir() -> PathBuf {
     std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."))
@@ -260,19 +752,187 @@ pub fn default_dir_opt() -> Option<PathBuf> {
     std::env::current_dir().map(Some).unwrap_or_default()
 }
 
+/// The cpu priority ("nice" value) used in background mode (0 is normal and 19 is the lowest)
+pub const BACKGROUND_NICE: i32 = 10;
+/// In background mode the pause after each file is this fraction of the time spent on the file
+const BACKGROUND_PAUSE_DIVISOR: u32 = 4;
+const BACKGROUND_PAUSE_MIN: Duration = Duration::from_millis(1);
+const BACKGROUND_PAUSE_MAX: Duration = Duration::from_millis(100);
+
+/// Platform specific ways of lowering the priority of the current process (all best-effort).
+/// The changes also apply to threads that are started afterwards.
+pub trait Priority {
+    /// Set the cpu priority as a "nice" value (returns false if unsupported)
+    fn set_cpu(&mut self, nice: i32) -> bool;
+    /// Switch between idle and normal io priority (returns false if unsupported)
+    fn set_io(&mut self, idle: bool) -> bool;
+    /// Wait between files
+    fn pause(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// The priority of the current process (through the operating system)
+pub struct OsPriority;
+
+#[cfg(unix)]
+impl Priority for OsPriority {
+    fn set_cpu(&mut self, nice: i32) -> bool {
+        // SAFETY: setpriority has no memory safety requirements
+        unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) == 0 }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_io(&mut self, idle: bool) -> bool {
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        let prio = if idle {
+            3 << IOPRIO_CLASS_SHIFT // Idle class
+        } else {
+            (2 << IOPRIO_CLASS_SHIFT) | 4 // Default "best effort" class and level
+        };
+        // SAFETY: ioprio_set only takes integers
+        unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) == 0 }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn set_io(&mut self, _idle: bool) -> bool {
+        false
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Priority for OsPriority {
+    fn set_cpu(&mut self, nice: i32) -> bool {
+        const NORMAL_PRIORITY_CLASS: u32 = 0x0000_0020;
+        const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+        let class = if nice > 0 {
+            BELOW_NORMAL_PRIORITY_CLASS
+        } else {
+            NORMAL_PRIORITY_CLASS
+        };
+        // SAFETY: The pseudo handle for the current process is always valid
+        unsafe { windows::SetPriorityClass(windows::GetCurrentProcess(), class) != 0 }
+    }
+
+    fn set_io(&mut self, idle: bool) -> bool {
+        const PROCESS_MODE_BACKGROUND_BEGIN: u32 = 0x0010_0000;
+        const PROCESS_MODE_BACKGROUND_END: u32 = 0x0020_0000;
+        let mode = if idle {
+            PROCESS_MODE_BACKGROUND_BEGIN
+        } else {
+            PROCESS_MODE_BACKGROUND_END
+        };
+        // SAFETY: The pseudo handle for the current process is always valid
+        unsafe { windows::SetPriorityClass(windows::GetCurrentProcess(), mode) != 0 }
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod windows {
+    extern "system" {
+        pub fn GetCurrentProcess() -> *mut std::ffi::c_void;
+        pub fn SetPriorityClass(process: *mut std::ffi::c_void, class: u32) -> i32;
+    }
+}
+
+#[cfg(not(any(unix, target_os = "windows")))]
+impl Priority for OsPriority {
+    fn set_cpu(&mut self, _nice: i32) -> bool {
+        false
+    }
+
+    fn set_io(&mut self, _idle: bool) -> bool {
+        false
+    }
+}
+
+/// Background mode lowers the priority and pauses between files (even if the priority
+/// cannot be changed), so that other programs stay responsive
+pub struct Background<P: Priority> {
+    priority: P,
+    enabled: bool,
+    last: Instant,
+}
+
+impl<P: Priority> Background<P> {
+    pub fn new(priority: P, enabled: bool) -> Self {
+        let mut bg = Self {
+            priority,
+            enabled: false,
+            last: Instant::now(),
+        };
+        bg.set(enabled);
+        bg
+    }
+
+    /// Turn background mode on or off
+    pub fn set(&mut self, enabled: bool) {
+        if enabled != self.enabled {
+            self.enabled = enabled;
+            // Going back to a normal priority might fail without extra privileges
+            self.priority
+                .set_cpu(if enabled { BACKGROUND_NICE } else { 0 });
+            self.priority.set_io(enabled);
+            self.last = Instant::now();
+        }
+    }
+
+    /// Call this after every file, in background mode it pauses for a while
+    pub fn pace(&mut self) {
+        if self.enabled {
+            let pause = (self.last.elapsed() / BACKGROUND_PAUSE_DIVISOR)
+                .clamp(BACKGROUND_PAUSE_MIN, BACKGROUND_PAUSE_MAX);
+            self.priority.pause(pause);
+            self.last = Instant::now();
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
     use std::path::PathBuf;
+    use std::time::Duration;
 
     use tempfile::tempdir;
 
     use super::{
-        get_backup_from_path, get_config_from_path, strip_absolute_from_path, BackupIterator,
+        expand_path, format_count, format_elapsed, format_relative_to, get_backup_from_path,
+        get_config_from_path, is_superseded_backup, long_path, parse_backup_file_name, remap_path,
+        sample_config_diff, strip_absolute_from_path, strip_path_prefix, unified_diff, user_home,
+        Background, BackupIterator, ConfigPathType, Priority, BACKGROUND_NICE,
     };
     use crate::backup::BackupError;
+    use crate::config::ConfigBuilder;
+    use crate::parse_date::naive_now;
     use crate::Config;
 
+    #[test]
+    fn long_paths() {
+        use std::path::Path;
+        #[cfg(windows)]
+        {
+            assert_eq!(
+                long_path(Path::new(r"C:\data\..\deep\file.txt")).as_os_str(),
+                r"\\?\C:\deep\file.txt"
+            );
+            assert_eq!(
+                long_path(Path::new(r"\\server\share\deep\file.txt")).as_os_str(),
+                r"\\?\UNC\server\share\deep\file.txt"
+            );
+            assert_eq!(
+                long_path(Path::new(r"\\?\C:\file.txt")).as_os_str(),
+                r"\\?\C:\file.txt"
+            );
+        }
+        #[cfg(not(windows))]
+        assert_eq!(
+            long_path(Path::new("/data/../file.txt")).as_os_str(),
+            "/data/../file.txt"
+        );
+    }
+
     #[test]
     fn try_macros() {
         let try_some_ok: fn() -> Option<Result<i32, i32>> = || Some(Ok(try_some!(Ok(1))));
@@ -300,6 +960,15 @@ mod tests {
         assert_eq!(bi.get_latest().unwrap(), f4);
         let mut bi = BackupIterator::dir(dir.path());
         assert_eq!(bi.get_previous(&f4).unwrap(), f3);
+        let mut bi = BackupIterator::dir(dir.path());
+        assert_eq!(bi.get_next(&f2).unwrap(), f3);
+        assert!(BackupIterator::dir(dir.path()).get_next(&f4).is_none());
+        let (inside, outside) = BackupIterator::dir(dir.path()).split_range(
+            Some(parse_backup_file_name("_2020-03-01_00-00-00.tar.zst").unwrap()),
+            Some(parse_backup_file_name("_2020-04-24_22-20-20.tar.zst").unwrap()),
+        )?;
+        assert_eq!(inside, vec![f3.clone()]);
+        assert_eq!(outside, vec![f2.clone(), f4.clone()]);
         let mut bi = BackupIterator::file(f2.clone());
         assert_eq!(bi.next().unwrap()?, f2);
         assert!(bi.next().is_none());
@@ -314,6 +983,37 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn superseded() -> Result<(), BackupError> {
+        assert!(is_superseded_backup("dir/a.tar.zst.old"));
+        assert!(is_superseded_backup("a.tar.zst.old.old"));
+        assert!(is_superseded_backup("a.tar.zst.tmp.old"));
+        assert!(!is_superseded_backup("a.tar.zst"));
+        assert!(!is_superseded_backup("a.old"));
+        assert!(!is_superseded_backup("a.tar.zst.bak"));
+        assert!(!is_superseded_backup("a.tar.zst.old.bak"));
+        assert!(!is_superseded_backup("a.tar.zst/.old"));
+
+        let dir = tempdir()?;
+        let f1 = dir.path().join("backup_2020-02-20_20-20-20.tar.zst");
+        let f2 = dir.path().join("backup_2020-04-24_21-20-20.tar.zst.old");
+        let f3 = dir
+            .path()
+            .join("backup_2020-04-24_22-20-20.tar.zst.old.tmp");
+        File::create(&f1)?;
+        File::create(&f2)?;
+        File::create(&f3)?;
+        assert_eq!(BackupIterator::dir(dir.path()).get_all()?, vec![f1.clone()]);
+        assert_eq!(BackupIterator::dir(dir.path()).get_latest(), Some(f1));
+        assert!(ConfigPathType::parse(&f2).is_err());
+        assert!(matches!(
+            ConfigPathType::parse_read_only(&f3)?,
+            ConfigPathType::Backup(_)
+        ));
+        assert_eq!(*get_backup_from_path(f2.clone())?.path.get_path(), f2);
+        Ok(())
+    }
+
     #[test]
     fn from_path() -> std::io::Result<()> {
         let dir = tempdir()?;
@@ -323,9 +1023,25 @@ mod tests {
         File::create(&f1)?;
         File::create(&f2)?;
         let mut conf = Config::new();
+        conf.include = vec![dir.path().to_string_lossy().to_string()];
         conf.output = PathBuf::from("test");
         conf.write_yaml(&f3, true)?;
-        assert_eq!(get_config_from_path(f3).unwrap().output, conf.output);
+        assert_eq!(
+            get_config_from_path(f3.clone()).unwrap().output,
+            conf.output
+        );
+        for name in ["config.toml", "config.json"] {
+            let f4 = dir.path().join(name);
+            conf.write_file(&f4, true)?;
+            assert_eq!(get_config_from_path(f4).unwrap().output, conf.output);
+        }
+        // Configs with errors are rejected when loaded
+        conf.quality = 99;
+        conf.write_yaml(&f3, true)?;
+        assert!(matches!(
+            get_config_from_path(f3),
+            Err(BackupError::InvalidConfig(_))
+        ));
         assert_eq!(
             get_backup_from_path(dir.path().to_path_buf()).unwrap().path,
             f2.into()
@@ -344,4 +1060,192 @@ mod tests {
             assert_eq!("E\\path", strip_absolute_from_path("E:\\path"));
         }
     }
+
+    #[test]
+    fn remap() {
+        let map = vec![
+            ("/home".to_string(), "/mnt/home".to_string()),
+            ("/home/alice/".to_string(), "/home/bob".to_string()),
+        ];
+        assert_eq!(
+            remap_path("/home/alice/a.txt", &map).unwrap(),
+            "/home/bob/a.txt"
+        );
+        assert_eq!(remap_path("/home/alice", &map).unwrap(), "/home/bob");
+        assert_eq!(
+            remap_path("/home/alicia/a.txt", &map).unwrap(),
+            "/mnt/home/alicia/a.txt"
+        );
+        assert_eq!(remap_path("/homework/a.txt", &map), None);
+        let map = vec![("/".to_string(), "/mnt".to_string())];
+        assert_eq!(remap_path("/a/b.txt", &map).unwrap(), "/mnt/a/b.txt");
+    }
+
+    #[test]
+    fn strip_prefix() {
+        assert_eq!(
+            strip_path_prefix("/home/me/projects/a.txt", "/home/me"),
+            Some("projects/a.txt")
+        );
+        assert_eq!(
+            strip_path_prefix("/home/me/projects/a.txt", "/home/me/"),
+            Some("projects/a.txt")
+        );
+        assert_eq!(
+            strip_path_prefix("/home/me/a.txt", "/"),
+            Some("home/me/a.txt")
+        );
+        assert_eq!(strip_path_prefix("/home/mine/a.txt", "/home/me"), None);
+        assert_eq!(strip_path_prefix("/home/me", "/home/me"), None);
+        assert_eq!(strip_path_prefix("/srv/a.txt", "/home/me"), None);
+    }
+
+    #[test]
+    fn format_numbers() {
+        assert_eq!(format_count(0), "0");
+        assert_eq!(format_count(999), "999");
+        assert_eq!(format_count(12345), "12,345");
+        assert_eq!(format_count(1234567), "1,234,567");
+        assert_eq!(format_elapsed(Duration::from_millis(2500)), "2.5s");
+        assert_eq!(format_elapsed(Duration::from_secs(852)), "14m12s");
+        assert_eq!(format_elapsed(Duration::from_secs(3723)), "1h02m03s");
+    }
+
+    #[test]
+    fn format_relative_times() {
+        let now = naive_now();
+        let ago = |secs: i64| format_relative_to(now - chrono::TimeDelta::seconds(secs), now);
+        assert_eq!(ago(-10), "just now");
+        assert_eq!(ago(-3600), "in the future");
+        assert_eq!(ago(59), "just now");
+        assert_eq!(ago(60), "1 minute ago");
+        assert_eq!(ago(3 * 3600 + 59), "3 hours ago");
+        assert_eq!(ago(2 * 24 * 3600), "2 days ago");
+        assert_eq!(ago(22 * 24 * 3600), "3 weeks ago");
+        assert_eq!(ago(65 * 24 * 3600), "2 months ago");
+        assert_eq!(ago(800 * 24 * 3600), "2 years ago");
+    }
+
+    #[test]
+    fn expand() {
+        std::env::set_var("SIMPLE_BACKUP_EXPAND", "/data");
+        std::env::remove_var("SIMPLE_BACKUP_UNSET");
+        assert_eq!(expand_path("$SIMPLE_BACKUP_EXPAND/a"), "/data/a");
+        assert_eq!(expand_path("${SIMPLE_BACKUP_EXPAND}a/b"), "/dataa/b");
+        assert_eq!(expand_path("x/$SIMPLE_BACKUP_EXPAND"), "x//data");
+        assert_eq!(
+            expand_path("$SIMPLE_BACKUP_UNSET/a"),
+            "$SIMPLE_BACKUP_UNSET/a"
+        );
+        assert_eq!(
+            expand_path("${SIMPLE_BACKUP_EXPAND"),
+            "${SIMPLE_BACKUP_EXPAND"
+        );
+        assert_eq!(expand_path("a$/b"), "a$/b");
+        let home = user_home().unwrap();
+        assert_eq!(PathBuf::from(expand_path("~")), home);
+        assert_eq!(
+            PathBuf::from(expand_path("~/Pictures")),
+            home.join("Pictures")
+        );
+        assert_eq!(expand_path("a/~"), "a/~");
+        assert_eq!(expand_path("~user"), "~user");
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Cpu(i32),
+        Io(bool),
+        Pause(std::time::Duration),
+    }
+
+    /// Records the calls (and pretends the operating system does not support priorities)
+    #[derive(Default)]
+    struct Recorder(Vec<Call>);
+
+    impl Priority for Recorder {
+        fn set_cpu(&mut self, nice: i32) -> bool {
+            self.0.push(Call::Cpu(nice));
+            false
+        }
+        fn set_io(&mut self, idle: bool) -> bool {
+            self.0.push(Call::Io(idle));
+            false
+        }
+        fn pause(&mut self, duration: std::time::Duration) {
+            self.0.push(Call::Pause(duration));
+        }
+    }
+
+    #[test]
+    fn background() {
+        let mut bg = Background::new(Recorder::default(), false);
+        bg.pace();
+        assert!(bg.priority.0.is_empty());
+        bg.set(true);
+        assert!(bg.enabled);
+        assert_eq!(bg.priority.0.drain(..).collect::<Vec<_>>(), vec![
+            Call::Cpu(BACKGROUND_NICE),
+            Call::Io(true)
+        ]);
+        bg.pace();
+        match bg.priority.0.drain(..).collect::<Vec<_>>().as_slice() {
+            [Call::Pause(d)] => {
+                assert!(d.as_millis() >= 1);
+                assert!(d.as_millis() <= 100);
+            }
+            calls => panic!("Expected a single pause, got {:?}", calls),
+        }
+        bg.set(true);
+        assert!(bg.priority.0.is_empty());
+        bg.set(false);
+        bg.pace();
+        assert_eq!(bg.priority.0, vec![Call::Cpu(0), Call::Io(false)]);
+    }
+
+    #[test]
+    fn sample_diff() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        for d in ["a/x", "a/y", "b"] {
+            std::fs::create_dir_all(root.join(d)).unwrap();
+        }
+        for f in ["a/x/1.txt", "a/y/2.txt", "a/y/3.log", "b/4.txt"] {
+            File::create(root.join(f)).unwrap();
+        }
+        let path = |p: &str| root.join(p).to_string_lossy().to_string();
+        let first = ConfigBuilder::new()
+            .include([path("a"), path("b")])
+            .exclude([path("a/x")])
+            .build();
+        let second = ConfigBuilder::new()
+            .include([path("a")])
+            .regex([r"\.log$"])
+            .build();
+        let diff = sample_config_diff(&first, &second, &root).unwrap();
+        assert_eq!(diff, vec![
+            (root.join("a/x"), false),
+            (root.join("a/y/3.log"), true),
+            (root.join("b"), true),
+        ]);
+        assert!(sample_config_diff(&first, &first, &root)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn diff_lines() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n"), "");
+        let before = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let after = "0\n1\n2\n3\n4\n5\n6\n7\nx\n9\n10\n11\n12\n";
+        assert_eq!(
+            unified_diff(before, after),
+            "--- before\n+++ after\n@@ -1,3 +1,4 @@\n+0\n 1\n 2\n 3\n\
+             @@ -5,7 +6,7 @@\n 5\n 6\n 7\n-8\n+x\n 9\n 10\n 11\n"
+        );
+        assert_eq!(
+            unified_diff("a\n", "a\nb\n"),
+            "--- before\n+++ after\n@@ -1,1 +1,2 @@\n a\n+b\n"
+        );
+    }
 }
@@ -0,0 +1,257 @@
+/// This module contains the handling of (macOS) extended attributes, such as Finder tags and the quarantine flag
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// Extended attributes are only stored (and restored) on macOS
+pub const STORE_XATTRS: bool = cfg!(target_os = "macos");
+
+/// The (pax) prefix of the extended attributes in the archive
+/// (not the standard `SCHILY.xattr.`, since those would fail to unpack on other systems)
+const PAX_PREFIX: &str = "SIMPLE_BACKUP.xattr.";
+
+/// What to do with an extended attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum XattrPolicy {
+    /// Store the attribute and restore it
+    Restore,
+    /// Store the attribute, but do not restore it
+    Skip,
+    /// Do not store the attribute
+    Ignore,
+}
+
+/// The extended attributes that macOS uses for Finder metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KnownXattr {
+    /// Set on downloaded files (Gatekeeper asks before opening them)
+    Quarantine,
+    /// The Finder tags
+    Tags,
+    /// The Finder flags and the (legacy) color label
+    FinderInfo,
+    /// The resource fork (stored as a `._*` AppleDouble file on file systems without forks)
+    ResourceFork,
+}
+
+impl KnownXattr {
+    pub const ALL: [KnownXattr; 4] = [
+        KnownXattr::Quarantine,
+        KnownXattr::Tags,
+        KnownXattr::FinderInfo,
+        KnownXattr::ResourceFork,
+    ];
+
+    /// The name of the extended attribute
+    pub fn name(&self) -> &'static str {
+        match self {
+            KnownXattr::Quarantine => "com.apple.quarantine",
+            KnownXattr::Tags => "com.apple.metadata:_kMDItemUserTags",
+            KnownXattr::FinderInfo => "com.apple.FinderInfo",
+            KnownXattr::ResourceFork => "com.apple.ResourceFork",
+        }
+    }
+
+    /// Classify an extended attribute by name
+    pub fn classify(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.name() == name)
+    }
+}
+
+/// The policies for the extended attributes on macOS (in the config as
+/// `macos_xattrs: {quarantine: skip, tags: restore, ...}`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MacosXattrs {
+    pub quarantine: XattrPolicy,
+    pub tags: XattrPolicy,
+    pub finder_info: XattrPolicy,
+    pub resource_fork: XattrPolicy,
+    /// All other extended attributes
+    pub other: XattrPolicy,
+}
+
+impl Default for MacosXattrs {
+    fn default() -> Self {
+        Self {
+            quarantine: XattrPolicy::Skip,
+            tags: XattrPolicy::Restore,
+            finder_info: XattrPolicy::Restore,
+            resource_fork: XattrPolicy::Restore,
+            other: XattrPolicy::Ignore,
+        }
+    }
+}
+
+impl MacosXattrs {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The policy for an extended attribute
+    pub fn policy(&self, name: &str) -> XattrPolicy {
+        match KnownXattr::classify(name) {
+            Some(KnownXattr::Quarantine) => self.quarantine,
+            Some(KnownXattr::Tags) => self.tags,
+            Some(KnownXattr::FinderInfo) => self.finder_info,
+            Some(KnownXattr::ResourceFork) => self.resource_fork,
+            None => self.other,
+        }
+    }
+
+    /// The attributes to store in the backup
+    pub fn stored(&self, attrs: Vec<(String, Vec<u8>)>) -> Vec<(String, Vec<u8>)> {
+        attrs
+            .into_iter()
+            .filter(|(name, _)| self.policy(name) != XattrPolicy::Ignore)
+            .collect()
+    }
+
+    /// The stored attributes to restore
+    pub fn restored(&self, attrs: Vec<(String, Vec<u8>)>) -> Vec<(String, Vec<u8>)> {
+        attrs
+            .into_iter()
+            .filter(|(name, _)| self.policy(name) == XattrPolicy::Restore)
+            .collect()
+    }
+}
+
+/// Is the file an AppleDouble companion (`._*`, holding the metadata of another file on file
+/// systems that do not support extended attributes)
+#[allow(unused)]
+pub fn is_apple_double<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.len() > 2 && n.starts_with("._"))
+}
+
+/// The regex (for excludes) that matches AppleDouble companion files, see [`is_apple_double`]
+pub const APPLE_DOUBLE_REGEX: &str = r"[/\\]\._[^/\\]+$";
+
+/// The pax key for storing an extended attribute
+pub fn pax_key(name: &str) -> String {
+    format!("{}{}", PAX_PREFIX, name)
+}
+
+/// The name of the extended attribute stored with a pax key (`None` for other keys)
+pub fn from_pax_key(key: &str) -> Option<&str> {
+    key.strip_prefix(PAX_PREFIX).filter(|n| !n.is_empty())
+}
+
+/// Read the extended attributes of a file
+#[cfg(unix)]
+pub fn read(path: &Path) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    let mut attrs = vec![];
+    for name in xattr::list(path)? {
+        if let (Some(value), Some(name)) = (xattr::get(path, &name)?, name.to_str()) {
+            attrs.push((name.to_string(), value));
+        }
+    }
+    Ok(attrs)
+}
+
+#[cfg(not(unix))]
+pub fn read(_path: &Path) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    Ok(vec![])
+}
+
+/// Write extended attributes to a file
+#[cfg(unix)]
+pub fn write(path: &Path, attrs: &[(String, Vec<u8>)]) -> std::io::Result<()> {
+    attrs
+        .iter()
+        .try_for_each(|(name, value)| xattr::set(path, name, value))
+}
+
+#[cfg(not(unix))]
+pub fn write(_path: &Path, _attrs: &[(String, Vec<u8>)]) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        from_pax_key, is_apple_double, pax_key, KnownXattr, MacosXattrs, XattrPolicy,
+        APPLE_DOUBLE_REGEX,
+    };
+
+    #[test]
+    fn classify() {
+        for x in KnownXattr::ALL {
+            assert_eq!(KnownXattr::classify(x.name()), Some(x));
+        }
+        assert_eq!(KnownXattr::classify("com.apple.quarantine.x"), None);
+        assert_eq!(KnownXattr::classify("user.comment"), None);
+    }
+
+    #[test]
+    fn policy() {
+        let attrs = || {
+            vec![
+                ("com.apple.quarantine".to_string(), vec![1]),
+                ("com.apple.metadata:_kMDItemUserTags".to_string(), vec![2]),
+                ("user.comment".to_string(), vec![3]),
+            ]
+        };
+        let policy = MacosXattrs::default();
+        let stored = policy.stored(attrs());
+        assert_eq!(stored.len(), 2);
+        let restored = policy.restored(stored);
+        assert_eq!(restored, [(
+            "com.apple.metadata:_kMDItemUserTags".to_string(),
+            vec![2]
+        )]);
+
+        let policy = MacosXattrs {
+            tags: XattrPolicy::Ignore,
+            other: XattrPolicy::Restore,
+            ..Default::default()
+        };
+        assert_eq!(policy.stored(attrs()).len(), 2);
+        assert_eq!(policy.restored(attrs()), [(
+            "user.comment".to_string(),
+            vec![3]
+        )]);
+
+        let policy: MacosXattrs =
+            serde_yaml::from_str("{quarantine: restore, tags: skip}").unwrap();
+        assert_eq!(policy.quarantine, XattrPolicy::Restore);
+        assert_eq!(policy.tags, XattrPolicy::Skip);
+        assert_eq!(policy.finder_info, MacosXattrs::default().finder_info);
+    }
+
+    #[test]
+    fn apple_double() {
+        assert!(is_apple_double("dir/._photo.jpg"));
+        assert!(!is_apple_double("dir/._"));
+        assert!(!is_apple_double("dir/photo._jpg"));
+        assert!(!is_apple_double("._dir/photo.jpg"));
+        let regex = regex::Regex::new(APPLE_DOUBLE_REGEX).unwrap();
+        assert!(regex.is_match("/home/dir/._photo.jpg"));
+        assert!(regex.is_match(r"C:\dir\._photo.jpg"));
+        assert!(!regex.is_match("/home/._dir/photo.jpg"));
+    }
+
+    #[test]
+    fn pax_keys() {
+        let key = pax_key("com.apple.FinderInfo");
+        assert_eq!(from_pax_key(&key), Some("com.apple.FinderInfo"));
+        assert_eq!(from_pax_key("SIMPLE_BACKUP.duplicate"), None);
+        assert_eq!(from_pax_key("SCHILY.xattr.user.a"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file.txt");
+        std::fs::write(&file, "content").unwrap();
+        let attrs = vec![("user.simple_backup".to_string(), b"value".to_vec())];
+        // Not every file system supports extended attributes
+        if super::write(&file, &attrs).is_ok() {
+            assert_eq!(super::read(&file).unwrap(), attrs);
+        }
+    }
+}
@@ -1,14 +1,36 @@
 // This file contains integration tests for backups and restoring
 
-use std::fs::{remove_file, File};
-use std::path::PathBuf;
+use std::fs::{create_dir, remove_file, rename, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
 
 use path_absolutize::Absolutize;
-use simple_backup::backup::{BackupReader, BackupWriter};
-use simple_backup::cli::{backup, inspect, merge, restore};
-use simple_backup::config::Config;
-use simple_backup::parse_date::naive_now;
-use simple_backup::utils::{extend_pathbuf, get_backup_from_path, strip_absolute_from_path};
+use simple_backup::backup::{
+    BackupError, BackupMerger, BackupReader, BackupWriter, Overwrite, RequiredRoot, WriteStats,
+};
+use simple_backup::browse::Browser;
+use simple_backup::cli::{
+    backup_with, check, errors_path, extract_with, info, inspect, merge, overlap, prune_old_files,
+    restore_with, stats, usage as usage_cli,
+};
+use simple_backup::commands::{BackupCommand, METADATA_SIDECAR};
+use simple_backup::compression::{CompressionDecoder, CompressionEncoder};
+use simple_backup::config::{Config, ConfigBuilder};
+use simple_backup::file_types::FileType;
+use simple_backup::files::{path_to_string, ChangeDetection, FileInfo};
+use simple_backup::manifest::{manifest_path, read_manifest, RestoreOutcome, MANIFEST_FILE_NAME};
+use simple_backup::options::{BackupOptions, MergeOptions, RestoreOptions};
+use simple_backup::overlap::{find_config_overlaps, OverlapKind};
+use simple_backup::parse_date::{self, naive_now};
+use simple_backup::query::Query;
+use simple_backup::stats::{BackupInfo, BackupStats, ChainUsage, OutputFormat, StatsGroup};
+use simple_backup::txn::{Transaction, JOURNAL_PREFIX};
+use simple_backup::utils::{
+    extend_pathbuf, find_superseding, get_backup_from_path, get_config_from_path,
+    strip_absolute_from_path, BackupIterator,
+};
 use tempfile::tempdir;
 
 #[test]
@@ -25,22 +47,16 @@ fn cli_test() {
     File::create(&f3).unwrap();
     File::create(&f4).unwrap();
 
-    let config = Config {
-        include: vec![dir.path().to_string_lossy().to_string()],
-        exclude: vec![],
-        regex: vec![],
-        output: dir3,
-        incremental: true,
-        quality: 11,
-        threads: 1,
-        local: false,
-        time: None,
-        origin: PathBuf::new(),
-    };
+    let config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir3)
+        .quality(11)
+        .threads(1)
+        .build();
     let mut bw1 = BackupWriter::new(config).0;
     bw1.export_list(&f4, false).unwrap();
     bw1.export_list(&f3, true).unwrap();
-    bw1.write(|_, _| Ok(()), || ()).unwrap();
+    bw1.write(|_, _| Ok(()), |_| (), || ()).unwrap();
 
     remove_file(&f1).unwrap();
     remove_file(&f2).unwrap();
@@ -53,18 +69,12 @@ fn cli_test() {
     reader.get_list().unwrap();
     let _ = reader.get_meta().unwrap();
     reader.export_list(dir.path().join("files.txt")).unwrap();
-    restore::<PathBuf>(
-        reader,
-        None,
-        vec![f1.to_string_lossy().to_string()],
-        vec![],
-        false,
-        false,
-        false,
-        false,
-        false,
-        true,
-    );
+    restore_with(reader, &RestoreOptions {
+        include: vec![f1.to_string_lossy().to_string()],
+        quiet: true,
+        ..Default::default()
+    })
+    .unwrap();
 
     assert!(f1.exists());
     assert!(!f2.exists());
@@ -72,18 +82,15 @@ fn cli_test() {
     assert!(!f4.exists());
 
     let conf = Config::from_yaml(bw1.config.as_yaml().unwrap()).unwrap();
-    restore::<PathBuf>(
-        BackupReader::from_config(conf).unwrap(),
-        None,
-        vec![],
-        vec![f2.to_string_lossy().replace('\\', "/")],
-        false,
-        true,
-        true,
-        true,
-        false,
-        true,
-    );
+    restore_with(BackupReader::from_config(conf).unwrap(), &RestoreOptions {
+        regex: vec![f2.to_string_lossy().replace('\\', "/")],
+        only_this: true,
+        overwrite: Overwrite::Always,
+        verbose: true,
+        quiet: true,
+        ..Default::default()
+    })
+    .unwrap();
 
     assert!(f1.exists());
     assert!(f2.exists());
@@ -91,23 +98,56 @@ fn cli_test() {
     assert!(!f4.exists());
 
     let conf = Config::from_yaml(bw1.config.as_yaml().unwrap()).unwrap();
-    restore(
-        BackupReader::from_config(conf).unwrap(),
-        Some(&dir2),
+    restore_with(BackupReader::from_config(conf).unwrap(), &RestoreOptions {
+        output: Some(&dir2),
+        flatten: true,
+        only_this: true,
+        overwrite: Overwrite::Always,
+        quiet: true,
+        ..Default::default()
+    })
+    .unwrap();
+
+    assert!(dir2.join("a.txt").exists());
+    assert!(dir2.join("b.txt").exists());
+    assert!(dir2.join("c.txt").exists());
+    assert!(dir2.join("d.txt").exists());
+}
+
+#[test]
+#[allow(deprecated)]
+fn deprecated_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let f1 = dir.path().join("a.txt");
+    std::fs::write(&f1, "content")?;
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir.path().join("backup"))
+        .build();
+
+    // The signatures from before the options structs are kept
+    simple_backup::cli::backup(config.clone(), false, false, false, true);
+    let backup = config.get_backups().get_latest().unwrap();
+    remove_file(&f1)?;
+    simple_backup::cli::restore(
+        BackupReader::new(backup.clone()),
+        None::<&Path>,
         vec![],
         vec![],
-        true,
-        true,
-        true,
+        false,
+        false,
+        false,
         false,
         false,
         true,
     );
-
-    assert!(dir2.join("a.txt").exists());
-    assert!(dir2.join("b.txt").exists());
-    assert!(dir2.join("c.txt").exists());
-    assert!(dir2.join("d.txt").exists());
+    assert_eq!(std::fs::read_to_string(&f1)?, "content");
+    std::fs::write(&f1, "changed")?;
+    BackupReader::new(backup.clone()).restore_this(|fi| fi, |_| Ok(()), false)?;
+    assert_eq!(std::fs::read_to_string(&f1)?, "changed");
+    BackupReader::new(backup).restore_all(|fi| fi, |_| Ok(()), true)?;
+    assert_eq!(std::fs::read_to_string(&f1)?, "content");
+    Ok(())
 }
 
 #[test]
@@ -122,20 +162,15 @@ fn absolute_test() -> std::result::Result<(), Box<dyn std::error::Error>> {
     File::create(&f3)?;
     File::create(&f4)?;
 
-    let config = Config {
-        include: vec![dir.path().to_string_lossy().to_string()],
-        exclude: vec![],
-        regex: vec!["zst$".to_string()],
-        output: dir.path().to_path_buf(),
-        incremental: true,
-        quality: 11,
-        local: false,
-        threads: 1,
-        time: None,
-        origin: PathBuf::new(),
-    };
+    let config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .regex(vec!["zst$".to_string()])
+        .output(dir.path().to_path_buf())
+        .quality(11)
+        .threads(1)
+        .build();
     let mut bw1 = BackupWriter::new(config).0;
-    bw1.write(|_, _| Ok(()), || ())?;
+    bw1.write(|_, _| Ok(()), |_| (), || ())?;
 
     let f5 = dir.path().join("e.txt");
     let f6 = dir.path().join("f.txt");
@@ -145,7 +180,7 @@ fn absolute_test() -> std::result::Result<(), Box<dyn std::error::Error>> {
 
     let mut bw2 = BackupWriter::new(bw1.config).0;
     bw2.path = dir.path().join("b2.tar.zst");
-    bw2.write(|_, _| Ok(()), || ())?;
+    bw2.write(|_, _| Ok(()), |_| (), || ())?;
 
     remove_file(&f2)?;
     remove_file(&f5)?;
@@ -156,63 +191,88 @@ fn absolute_test() -> std::result::Result<(), Box<dyn std::error::Error>> {
     let mut br2 = BackupReader::from_config(bw2.config)?;
     let mut br1 = br2.get_previous()?.unwrap();
 
-    br2.restore_this(|fi| fi, |_| Ok(()), false)?;
+    let only_this = RestoreOptions {
+        only_this: true,
+        ..Default::default()
+    };
+    let files = listed_files(&mut br2)?;
+    br2.restore_with(files, |fi| fi, |_| Ok(()), |_| (), &only_this)?;
     assert!(!f2.exists());
     assert!(f5.exists());
 
     remove_file(&f5)?;
     assert!(!f5.exists());
 
-    br1.restore_this(|fi| fi, |_| Ok(()), false)?;
+    let files = listed_files(&mut br1)?;
+    br1.restore_with(files, |fi| fi, |_| Ok(()), |_| (), &only_this)?;
     assert!(f2.exists());
     assert!(!f5.exists());
 
     remove_file(&f2)?;
     assert!(!f2.exists());
 
-    br2.restore_this(|fi| fi, |_| Ok(()), true)?;
+    let files = listed_files(&mut br2)?;
+    let overwrite = RestoreOptions {
+        overwrite: Overwrite::Always,
+        ..only_this
+    };
+    br2.restore_with(files, |fi| fi, |_| Ok(()), |_| (), &overwrite)?;
     assert!(!f2.exists());
     assert!(f5.exists());
 
-    br2.restore_all(|fi| fi, |_| Ok(()), false)?;
+    let files = listed_files(&mut br2)?;
+    br2.restore_with(
+        files,
+        |fi| fi,
+        |_| Ok(()),
+        |_| (),
+        &RestoreOptions::default(),
+    )?;
     assert!(f2.exists());
 
     Ok(())
 }
 
+/// All the files in the list of the backup (to restore everything with `restore_with`)
+fn listed_files(reader: &mut BackupReader) -> Result<Vec<String>, BackupError> {
+    Ok(reader
+        .get_list()?
+        .iter()
+        .map(|(_, f)| f.to_string())
+        .collect())
+}
+
 #[test]
 fn local_test() -> Result<(), Box<dyn std::error::Error>> {
     let dir = tempdir().unwrap();
 
-    let mut config = Config {
-        include: vec![".".to_string()],
-        exclude: vec!["target".to_string(), ".git".to_string(), "src".to_string()],
-        regex: vec![".*.md".to_string()],
-        output: dir.path().to_path_buf(),
-        incremental: false,
-        quality: 11,
-        local: true,
-        threads: 1,
-        time: None,
-        origin: PathBuf::new(),
-    };
+    let mut config = ConfigBuilder::new()
+        .include(vec![".".to_string()])
+        .exclude(vec![
+            "target".to_string(),
+            ".git".to_string(),
+            "src".to_string(),
+        ])
+        .regex(vec![".*.md".to_string()])
+        .output(dir.path().to_path_buf())
+        .incremental(false)
+        .quality(11)
+        .threads(1)
+        .local(true)
+        .build();
 
     let conf = Config::from_yaml(config.as_yaml()?)?;
-    backup(conf, false, false, false, true);
+    backup_with(conf, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
 
     let reader = BackupReader::from_config(config)?;
-    restore(
-        reader,
-        Some(dir.path()),
-        vec![],
-        vec![],
-        false,
-        false,
-        false,
-        false,
-        false,
-        true,
-    );
+    restore_with(reader, &RestoreOptions {
+        output: Some(dir.path()),
+        quiet: true,
+        ..Default::default()
+    })?;
 
     assert!(dir.path().join("Cargo.toml").exists());
     assert!(!dir.path().join(".target").exists());
@@ -225,39 +285,32 @@ fn local_test() -> Result<(), Box<dyn std::error::Error>> {
 fn flatten_test() -> Result<(), Box<dyn std::error::Error>> {
     let dir = tempdir().unwrap();
 
-    let config = Config {
-        include: vec![
+    let config = ConfigBuilder::new()
+        .include(vec![
             "./src/lib.rs".to_string(),
             PathBuf::from("./src/cli.rs")
                 .absolutize()?
                 .to_string_lossy()
                 .to_string(),
-        ],
-        exclude: vec![],
-        regex: vec![],
-        output: dir.path().to_path_buf(),
-        incremental: false,
-        quality: 11,
-        local: true,
-        threads: 1,
-        time: None,
-        origin: PathBuf::new(),
-    };
-    backup(config.clone(), false, false, false, true);
+        ])
+        .output(dir.path().to_path_buf())
+        .incremental(false)
+        .quality(11)
+        .threads(1)
+        .local(true)
+        .build();
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
 
     let reader = BackupReader::from_config(config)?;
-    restore(
-        reader,
-        Some(&dir.path()),
-        vec![],
-        vec![],
-        true,
-        false,
-        false,
-        false,
-        false,
-        true,
-    );
+    restore_with(reader, &RestoreOptions {
+        output: Some(dir.path()),
+        flatten: true,
+        quiet: true,
+        ..Default::default()
+    })?;
 
     assert!(dir.path().join("cli.rs").exists());
     assert!(dir.path().join("lib.rs").exists());
@@ -275,37 +328,29 @@ fn extract_test() -> Result<(), Box<dyn std::error::Error>> {
             .to_string_lossy()
             .to_string(),
     ];
-    let mut config = Config {
-        include: inc.clone(),
-        exclude: vec![],
-        regex: vec![],
-        output: dir.path().to_path_buf(),
-        incremental: false,
-        quality: 11,
-        local: true,
-        threads: 1,
-        time: None,
-        origin: PathBuf::new(),
-    };
-    backup(config.clone(), false, false, false, true);
+    let mut config = ConfigBuilder::new()
+        .include(inc.clone())
+        .output(dir.path().to_path_buf())
+        .incremental(false)
+        .quality(11)
+        .threads(1)
+        .local(true)
+        .build();
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
 
     let reader = BackupReader::from_config(config.clone())?;
-    inspect(reader.clone(), false, false, true);
-    inspect(reader.clone(), false, true, true);
-    inspect(reader.clone(), true, false, true);
-    inspect(reader.clone(), true, true, true);
-    restore(
-        reader.clone(),
-        Some(&dir.path()),
-        vec![],
-        vec![],
-        false,
-        false,
-        false,
-        false,
-        false,
-        true,
-    );
+    inspect(reader.clone(), false, false, None, true)?;
+    inspect(reader.clone(), false, true, None, true)?;
+    inspect(reader.clone(), true, false, None, true)?;
+    inspect(reader.clone(), true, true, None, true)?;
+    restore_with(reader.clone(), &RestoreOptions {
+        output: Some(dir.path()),
+        quiet: true,
+        ..Default::default()
+    })?;
 
     for p in inc.iter() {
         assert!(dir.path().join(strip_absolute_from_path(p)).exists());
@@ -313,19 +358,16 @@ fn extract_test() -> Result<(), Box<dyn std::error::Error>> {
 
     let dir = dir.path().join("tmp");
     config.output = dir.clone();
-    backup(config, false, false, false, true);
-    restore(
-        reader,
-        Some(&dir),
-        vec![],
-        vec!["src".to_string()],
-        false,
-        false,
-        false,
-        false,
-        false,
-        true,
-    );
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    restore_with(reader, &RestoreOptions {
+        output: Some(&dir),
+        regex: vec!["src".to_string()],
+        quiet: true,
+        ..Default::default()
+    })?;
     for p in inc.iter() {
         assert!(dir.join(strip_absolute_from_path(p)).exists());
     }
@@ -344,55 +386,40 @@ fn time_test() -> Result<(), Box<dyn std::error::Error>> {
     File::create(&f2)?;
     std::thread::sleep(std::time::Duration::from_millis(20));
 
-    let config = Config {
-        include: vec![dir.path().to_string_lossy().to_string()],
-        exclude: vec![],
-        regex: vec![],
-        output: dir.path().to_path_buf(),
-        incremental: true,
-        quality: 11,
-        threads: 1,
-        local: false,
-        time: Some(naive_now()),
-        origin: PathBuf::new(),
-    };
+    let config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir.path().to_path_buf())
+        .quality(11)
+        .threads(1)
+        .time(Some(naive_now()))
+        .build();
 
     std::thread::sleep(std::time::Duration::from_millis(20));
     File::create(&f3)?;
     File::create(&f4)?;
 
-    backup(config, false, false, false, true);
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
 
     remove_file(&f1)?;
     remove_file(&f2)?;
     remove_file(&f3)?;
     remove_file(&f4)?;
 
-    let config = Config {
-        include: vec![dir.path().to_string_lossy().to_string()],
-        exclude: vec![],
-        regex: vec![],
-        output: dir.path().to_path_buf(),
-        incremental: true,
-        quality: 11,
-        threads: 1,
-        local: false,
-        time: Some(naive_now()),
-        origin: PathBuf::new(),
-    };
-
-    restore::<PathBuf>(
-        BackupReader::from_config(config)?,
-        None,
-        vec![],
-        vec![],
-        false,
-        false,
-        false,
-        false,
-        false,
-        true,
-    );
+    let config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir.path().to_path_buf())
+        .quality(11)
+        .threads(1)
+        .time(Some(naive_now()))
+        .build();
+
+    restore_with(BackupReader::from_config(config)?, &RestoreOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
 
     assert!(!f1.exists());
     assert!(!f2.exists());
@@ -408,37 +435,27 @@ fn longname_test() -> Result<(), Box<dyn std::error::Error>> {
     let f1 = dir.path().join(format!("{:50}.txt", 3));
     File::create(&f1)?;
 
-    let mut config = Config {
-        include: vec![f1.to_string_lossy().to_string()],
-        exclude: vec![],
-        regex: vec![],
-        output: dir.path().to_path_buf(),
-        incremental: false,
-        quality: 11,
-        local: false,
-        threads: 1,
-        time: None,
-        origin: PathBuf::new(),
-    };
+    let mut config = ConfigBuilder::new()
+        .include(vec![f1.to_string_lossy().to_string()])
+        .output(dir.path().to_path_buf())
+        .incremental(false)
+        .quality(11)
+        .threads(1)
+        .build();
 
     let conf = Config::from_yaml(config.as_yaml()?)?;
-    backup(conf, false, false, false, true);
+    backup_with(conf, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
 
     remove_file(&f1)?;
 
     let reader = BackupReader::from_config(config)?;
-    restore::<PathBuf>(
-        reader,
-        None,
-        vec![],
-        vec![],
-        false,
-        false,
-        false,
-        false,
-        false,
-        true,
-    );
+    restore_with(reader, &RestoreOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
 
     assert!(f1.exists());
     Ok(())
@@ -456,22 +473,19 @@ fn merge_test() -> Result<(), Box<dyn std::error::Error>> {
     let b3 = dir2.path().join("b3_2020-20-20_20-20-23.tar.zst");
     let b4 = dir2.path().join("b4_2020-20-20_20-20-24.tar.zst");
 
-    let mut config = Config {
-        include: vec![dir.path().to_string_lossy().to_string()],
-        exclude: vec![],
-        regex: vec![],
-        output: b1.clone(),
-        incremental: true,
-        quality: 11,
-        threads: 1,
-        local: false,
-        time: None,
-        origin: PathBuf::new(),
-    };
+    let mut config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(b1.clone())
+        .quality(11)
+        .threads(1)
+        .build();
 
     File::create(&f1)?;
 
-    backup(config.clone(), false, false, false, true);
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
     assert!(b1.exists());
     config.output = b2.clone();
     config.time = Some(naive_now());
@@ -480,7 +494,10 @@ fn merge_test() -> Result<(), Box<dyn std::error::Error>> {
     std::thread::sleep(std::time::Duration::from_millis(20));
     File::create(&f2)?;
 
-    backup(config.clone(), false, false, false, true);
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
     assert!(b2.exists());
     config.output = b3.clone();
     config.time = Some(naive_now());
@@ -488,24 +505,23 @@ fn merge_test() -> Result<(), Box<dyn std::error::Error>> {
     std::thread::sleep(std::time::Duration::from_millis(20));
     File::create(&f3)?;
 
-    backup(config, false, false, false, true);
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
     assert!(b3.exists());
 
     remove_file(&f2)?;
     remove_file(&f3)?;
 
-    merge(
-        vec![b1.clone(), b2.clone()],
-        None,
-        true,
-        true,
-        Some(1),
-        None,
-        false,
-        true,
-        false,
-        true,
-    );
+    merge(vec![b1.clone(), b2.clone()], &MergeOptions {
+        all: true,
+        delete: true,
+        quality: Some(1),
+        force: true,
+        quiet: true,
+        ..Default::default()
+    })?;
 
     assert_eq!(
         b3,
@@ -514,18 +530,13 @@ fn merge_test() -> Result<(), Box<dyn std::error::Error>> {
             .get_path()
     );
 
-    merge(
-        vec![dir2.path().to_path_buf()],
-        Some(b4.clone()),
-        false,
-        false,
-        None,
-        Some(1),
-        true,
-        false,
-        false,
-        true,
-    );
+    merge(vec![dir2.path().to_path_buf()], &MergeOptions {
+        output: Some(&b4),
+        threads: Some(1),
+        verbose: true,
+        quiet: true,
+        ..Default::default()
+    })?;
 
     std::thread::sleep(std::time::Duration::from_millis(20));
     assert!(!b1.exists());
@@ -552,36 +563,3638 @@ fn merge_test() -> Result<(), Box<dyn std::error::Error>> {
         vec![f2.to_string_lossy(), f3.to_string_lossy()]
     );
 
-    restore::<PathBuf>(
-        reader,
-        None,
-        vec![],
-        vec![],
-        false,
-        true,
-        false,
-        true,
-        false,
-        true,
-    );
+    restore_with(reader, &RestoreOptions {
+        only_this: true,
+        verbose: true,
+        quiet: true,
+        ..Default::default()
+    })?;
     assert!(!f1.exists());
     assert!(f2.exists());
     assert!(f3.exists());
 
-    restore::<PathBuf>(
-        BackupReader::new(b2),
-        None,
-        vec![],
-        vec![],
+    restore_with(BackupReader::new(b2), &RestoreOptions {
+        only_this: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(f1.exists());
+    assert!(f2.exists());
+    assert!(f3.exists());
+    Ok(())
+}
+
+#[test]
+fn merge_missing_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let f1 = dir.path().join("a.txt");
+    let f2 = dir.path().join("b.txt");
+    let f3 = dir.path().join("c.txt");
+    let b1 = dir2.path().join("b1_2020-20-20_20-20-21.tar.zst");
+    let b2 = dir2.path().join("b2_2020-20-20_20-20-22.tar.zst");
+    let b3 = dir2.path().join("b3_2020-20-20_20-20-23.tar.zst");
+    let b4 = dir2.path().join("b4_2020-20-20_20-20-24.tar.zst");
+
+    let mut config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(b1.clone())
+        .quality(1)
+        .threads(1)
+        .build();
+    File::create(&f1)?;
+    File::create(&f2)?;
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    config.output = b2.clone();
+    config.time = Some(naive_now());
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    File::create(&f3)?;
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+
+    // Rewrite the first backup without the data for "b.txt"
+    let tmp = extend_pathbuf(b1.clone(), ".tmp");
+    {
+        let mut decoder = CompressionDecoder::read(&b1, 1)?;
+        let mut encoder = CompressionEncoder::create(&tmp, 1, 1, None)?;
+        for entry in decoder.entries()? {
+            let (fi, entry) = entry?;
+            if !fi.copy_string().ends_with("b.txt") {
+                encoder.append_entry(entry, |_| ())?;
+            }
+        }
+        encoder.close()?;
+    }
+    rename(&tmp, &b1)?;
+
+    let readers = vec![BackupReader::new(b1.clone()), BackupReader::new(b2.clone())];
+    let mut merger = BackupMerger::new(
+        Some(b3.clone()),
+        readers,
         false,
-        true,
         false,
         false,
+        Some(1),
+        None,
         false,
-        true,
+    )
+    .map_err(|(_, e)| e)?;
+    let mut missing = vec![];
+    let stats = merger.write(
+        |_, res| {
+            if let Err(BackupError::MissingEntry(s)) = res {
+                missing.push(s);
+            }
+            Ok(())
+        },
+        |_| (),
+        || (),
+    )?;
+    assert_eq!(missing.len(), 1);
+    assert!(missing[0].ends_with("b.txt"));
+    assert_eq!(stats.files, 2);
+    assert_eq!(stats.compressed, Some(std::fs::metadata(&b3)?.len()));
+    assert_eq!(
+        BackupReader::new(b3.clone())
+            .get_list()?
+            .iter_included()
+            .count(),
+        3
     );
-    assert!(f1.exists());
-    assert!(f2.exists());
-    assert!(f3.exists());
+
+    let readers = merger.deconstruct();
+    let mut merger = BackupMerger::new(
+        Some(b4.clone()),
+        readers,
+        false,
+        true,
+        false,
+        Some(1),
+        None,
+        true,
+    )
+    .map_err(|(_, e)| e)?;
+    let mut missing = vec![];
+    merger.write(
+        |_, res| {
+            if let Err(BackupError::MissingEntry(s)) = res {
+                missing.push(s);
+            }
+            Ok(())
+        },
+        |_| (),
+        || (),
+    )?;
+    assert_eq!(missing.len(), 1);
+    let mut reader = BackupReader::new(b4);
+    let list = reader.get_list()?;
+    assert_eq!(list.iter().count(), 2);
+    assert!(list.iter().all(|(_, s)| !s.ends_with("b.txt")));
+    Ok(())
+}
+
+#[test]
+fn paranoid_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let sub = dir.path().join("sub");
+    create_dir(&sub)?;
+    let f1 = sub.join("a.txt");
+    let f2 = sub.join("b.txt");
+    let f3 = dir.path().join("c.txt");
+    File::create(&f1)?.write_all(b"aaaa")?;
+    File::create(&f2)?.write_all(b"bbbb")?;
+    File::create(&f3)?.write_all(b"cccc")?;
+    std::thread::sleep(std::time::Duration::from_millis(20));
+
+    let mut config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir2.path().to_path_buf())
+        .quality(1)
+        .threads(1)
+        .paranoid(true, 100.0, Some(42))
+        .build();
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-01.tar.zst");
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    assert!(bw.paranoid.is_none());
+
+    // Records the hashes of the unchanged files
+    config.time = Some(naive_now());
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-02.tar.zst");
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let report = bw.paranoid.unwrap();
+    assert_eq!(report.sampled, 3);
+    assert!(report.mismatched.is_empty());
+    assert!(bw.list.unwrap().iter().all(|(b, _)| !b));
+
+    // Silently change a file (same size and modification time)
+    let modified = f1.metadata()?.modified()?;
+    let mut file = OpenOptions::new().write(true).truncate(true).open(&f1)?;
+    file.write_all(b"AAAA")?;
+    file.set_modified(modified)?;
+    drop(file);
+
+    config.time = Some(naive_now());
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-03.tar.zst");
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let report = bw.paranoid.unwrap();
+    assert_eq!(report.sampled, 3);
+    assert_eq!(report.mismatched, vec![f1.to_string_lossy().to_string()]);
+    assert_eq!(report.escalated, vec![sub.clone()]);
+    let mut reader = BackupReader::new(bw.path);
+    let included = reader.get_list()?.iter_included().collect::<Vec<_>>();
+    #[cfg(target_os = "windows")]
+    assert_eq!(included, vec![
+        f1.to_string_lossy().replace('\\', "/"),
+        f2.to_string_lossy().replace('\\', "/")
+    ]);
+    #[cfg(not(target_os = "windows"))]
+    assert_eq!(included, vec![f1.to_string_lossy(), f2.to_string_lossy()]);
+
+    // Files with a changed size are included without hashing
+    File::create(&f3)?.write_all(b"c")?;
+    let modified = f2.metadata()?.modified()?;
+    File::options()
+        .write(true)
+        .open(&f3)?
+        .set_modified(modified)?;
+    config.time = Some(naive_now());
+    config.paranoid_sample = 0.0;
+    let mut bw = BackupWriter::new(config).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-04.tar.zst");
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let report = bw.paranoid.unwrap();
+    assert_eq!(report.sampled, 0);
+    assert_eq!(report.resized, 1);
+    Ok(())
+}
+
+#[test]
+fn restore_map_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let alice = dir.path().join("alice");
+    let bob = dir.path().join("bob");
+    create_dir(&alice)?;
+    let f1 = alice.join("a.txt");
+    let f2 = alice.join("b.txt");
+    let f3 = alice.join("c.txt");
+    let b1 = dir2.path().join("backup_2020-01-01_00-00-01.tar.zst");
+    let b2 = dir2.path().join("backup_2020-01-01_00-00-02.tar.zst");
+    File::create(&f1)?;
+    File::create(&f2)?;
+
+    let mut config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir2.path().to_path_buf())
+        .quality(1)
+        .threads(1)
+        .build();
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = b1;
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    config.time = Some(naive_now());
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    File::create(&f3)?;
+    let mut bw = BackupWriter::new(config).0;
+    bw.path = b2.clone();
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+
+    remove_file(&f1)?;
+    remove_file(&f2)?;
+    remove_file(&f3)?;
+
+    restore_with(BackupReader::new(b2), &RestoreOptions {
+        regex: vec!["[ac]\\.txt$".to_string()],
+        map: vec![
+            (
+                dir.path().to_string_lossy().to_string(),
+                dir2.path().to_string_lossy().to_string(),
+            ),
+            (
+                alice.to_string_lossy().to_string(),
+                bob.to_string_lossy().to_string(),
+            ),
+        ],
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(!f1.exists());
+    assert!(!f3.exists());
+    assert!(bob.join("a.txt").exists());
+    assert!(!bob.join("b.txt").exists());
+    assert!(bob.join("c.txt").exists());
+    assert!(!dir2.path().join("alice").exists());
+    Ok(())
+}
+
+#[test]
+fn strip_prefix_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    let projects = dir.path().join("projects");
+    create_dir(&projects)?;
+    File::create(projects.join("a.txt"))?;
+    File::create(dir.path().join("b.txt"))?;
+
+    let config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir2.path().to_path_buf())
+        .quality(1)
+        .threads(1)
+        .build();
+    let mut bw = BackupWriter::new(config).0;
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+
+    let prefix = dir.path().to_string_lossy().to_string();
+    restore_with(BackupReader::new(bw.path.clone()), &RestoreOptions {
+        output: Some(dir3.path()),
+        strip_prefix: Some(&prefix),
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(dir3.path().join("projects").join("a.txt").exists());
+    assert!(dir3.path().join("b.txt").exists());
+    assert_eq!(dir3.path().read_dir()?.count(), 2);
+
+    // Nothing is restored if a file is outside the prefix
+    let dir4 = tempdir()?;
+    let prefix = projects.to_string_lossy().to_string();
+    let res = restore_with(BackupReader::new(bw.path), &RestoreOptions {
+        output: Some(dir4.path()),
+        strip_prefix: Some(&prefix),
+        quiet: true,
+        ..Default::default()
+    });
+    assert!(matches!(res, Err(BackupError::OutsidePrefix(..))));
+    assert_eq!(dir4.path().read_dir()?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn stream_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    let mut f = File::create(dir.path().join("a.txt"))?;
+    f.write_all(b"streamed")?;
+    File::create(dir.path().join("b.txt"))?;
+
+    let config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(PathBuf::from("-"))
+        .quality(1)
+        .threads(1)
+        .build();
+    assert!(config.is_output_stream());
+    let archive = dir2.path().join("piped.tar.zst");
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.write_to(File::create(&archive)?, |_, _| Ok(()), |_| (), || ())?;
+
+    let mut br = BackupReader::from_stream(File::open(&archive)?);
+    assert!(br.is_stream());
+    assert_eq!(br.get_list()?.iter_included().count(), 2);
+    restore_with(br, &RestoreOptions {
+        output: Some(dir3.path()),
+        quiet: true,
+        ..Default::default()
+    })?;
+    let restored = dir3.path().join(strip_absolute_from_path(
+        &dir.path().join("a.txt").to_string_lossy(),
+    ));
+    assert_eq!(std::fs::read_to_string(restored)?, "streamed");
+
+    // Incremental backups need the previous backup, which cannot be found for a stream
+    let mut config = config;
+    config.incremental = true;
+    let mut bw = BackupWriter::new(config).0;
+    let res = bw.write(|_, _| Ok(()), |_| (), || ());
+    assert!(matches!(res, Err(BackupError::InvalidConfig(_))));
+    Ok(())
+}
+
+#[test]
+fn restore_type_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let files = [
+        "IMG_0001.JPG",
+        "scan.png",
+        "notes.txt",
+        "song.mp3",
+        "Makefile",
+    ]
+    .map(|f| dir.path().join(f));
+    for f in files.iter() {
+        File::create(f)?;
+    }
+    let config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir2.path().to_path_buf())
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .build();
+    let mut bw = BackupWriter::new(config).0;
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    for f in files.iter() {
+        remove_file(f)?;
+    }
+
+    restore_with(BackupReader::new(bw.path.clone()), &RestoreOptions {
+        types: vec![FileType::Photos],
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(files[0].exists());
+    assert!(files[1].exists());
+    assert!(files[2..].iter().all(|f| !f.exists()));
+
+    restore_with(BackupReader::new(bw.path), &RestoreOptions {
+        regex: vec!["notes".to_string(), "IMG".to_string()],
+        types: vec![FileType::Photos, FileType::Documents],
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(files[2].exists());
+    assert!(files[3..].iter().all(|f| !f.exists()));
+    Ok(())
+}
+
+#[test]
+fn extract_cli_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    let f1 = dir.path().join("a.txt");
+    let f2 = dir.path().join("b.txt");
+    File::create(&f1)?;
+    let config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir2.path().to_path_buf())
+        .quality(1)
+        .threads(1)
+        .build();
+    let mut bw1 = BackupWriter::new(config).0;
+    bw1.write(|_, _| Ok(()), |_| (), || ())?;
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    File::create(&f2)?;
+    let mut bw2 = BackupWriter::new(bw1.config).0;
+    bw2.path = dir2.path().join("b2.tar.zst");
+    bw2.write(|_, _| Ok(()), |_| (), || ())?;
+    remove_file(&f1)?;
+    remove_file(&f2)?;
+
+    // Only the selected backup, and never to the original location
+    let out = dir3.path().join("out");
+    extract_with(BackupReader::new(bw2.path.clone()), &RestoreOptions {
+        output: Some(&out),
+        only_this: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(!f1.exists());
+    assert!(!f2.exists());
+    assert!(!out
+        .join(strip_absolute_from_path(&f1.to_string_lossy()))
+        .exists());
+    assert!(out
+        .join(strip_absolute_from_path(&f2.to_string_lossy()))
+        .exists());
+
+    // Following the incremental chain
+    let out = dir3.path().join("flat");
+    extract_with(BackupReader::new(bw2.path), &RestoreOptions {
+        output: Some(&out),
+        regex: vec!["txt$".to_string()],
+        flatten: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(!f1.exists());
+    assert!(!f2.exists());
+    assert!(out.join("a.txt").exists());
+    assert!(out.join("b.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn stats_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    create_dir(dir.path().join("a"))?;
+    create_dir(dir.path().join("b"))?;
+    File::create(dir.path().join("a").join("x.txt"))?.write_all(&[b'x'; 1000])?;
+    File::create(dir.path().join("a").join("y.TXT"))?.write_all(&[b'y'; 10])?;
+    File::create(dir.path().join("b").join("z.bin"))?.write_all(&[b'z'; 100])?;
+    let config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir2.path().to_path_buf())
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .build();
+    let mut bw = BackupWriter::new(config).0;
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+
+    let mut reader = BackupReader::new(bw.path.clone());
+    let st = BackupStats::new(&mut reader, StatsGroup::Extension, 2)?;
+    assert_eq!(st.files, 3);
+    assert_eq!(st.listed, 3);
+    assert_eq!(st.unknown_size, 0);
+    assert_eq!(st.size, 1110);
+    assert!(st.compressed > 0);
+    assert_eq!(st.largest.len(), 2);
+    assert!(st.largest[0].path.ends_with("x.txt"));
+    assert_eq!(st.largest[1].size, 100);
+    assert_eq!(st.groups[0].name, "txt");
+    assert_eq!(st.groups[0].files, 2);
+    assert_eq!(st.groups[0].size, 1010);
+
+    let st = BackupStats::new(&mut reader, StatsGroup::Directory, 10)?;
+    assert_eq!(st.groups.len(), 2);
+    assert_eq!(PathBuf::from(&st.groups[0].name), dir.path().join("a"));
+    assert_eq!(st.groups[1].size, 100);
+
+    stats(reader, StatsGroup::Directory, 10, true, true)?;
+    Ok(())
+}
+
+#[test]
+fn required_roots_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let docs = dir.path().join("docs");
+    let empty = dir.path().join("empty");
+    let gone = dir.path().join("gone");
+    create_dir(&docs)?;
+    create_dir(&empty)?;
+    File::create(docs.join("a.txt"))?;
+    File::create(docs.join("b.txt"))?;
+    let path = |p: &PathBuf| p.to_string_lossy().to_string();
+    let writer = |required: &PathBuf| {
+        let config = ConfigBuilder::new()
+            .include([path(&docs), path(&empty)])
+            .required([path(required)])
+            .output(dir2.path())
+            .incremental(false)
+            .quality(1)
+            .threads(1)
+            .build();
+        BackupWriter::new(config).0
+    };
+
+    // Healthy
+    let mut bw = writer(&docs);
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    assert!(bw.path.exists());
+    assert_eq!(
+        bw.required,
+        Some(vec![RequiredRoot {
+            path: path(&docs),
+            files: 2
+        }])
+    );
+
+    // Missing and empty
+    for root in [&gone, &empty] {
+        let mut bw = writer(root);
+        match bw.foreach_file(true, |_| Ok(())) {
+            Err(BackupError::RequiredRootEmpty(p, _)) => assert_eq!(p, path(root)),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        match bw.write(|_, _| Ok(()), |_| (), || ()) {
+            Err(BackupError::RequiredRootEmpty(p, _)) => assert_eq!(p, path(root)),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        // Only the healthy backup remains (nothing partial is left behind)
+        assert_eq!(dir2.path().read_dir()?.count(), 1);
+    }
+
+    // Unreadable
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let locked = dir.path().join("locked");
+        create_dir(&locked)?;
+        File::create(locked.join("c.txt"))?;
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000))?;
+        // Permissions are not enforced for root
+        if locked.read_dir().is_err() {
+            let config = ConfigBuilder::new()
+                .required([path(&locked)])
+                .output(dir2.path())
+                .build();
+            let res = BackupWriter::new(config)
+                .0
+                .write(|_, _| Ok(()), |_| (), || ());
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755))?;
+            match res {
+                Err(BackupError::RequiredRootEmpty(p, reason)) => {
+                    assert_eq!(p, path(&locked));
+                    assert!(!reason.is_empty());
+                }
+                res => panic!("Unexpected result: {:?}", res),
+            }
+        } else {
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755))?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn skipped_files_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let sub = dir.path().join("sub");
+    create_dir(&sub)?;
+    let f1 = dir.path().join("a.txt");
+    let f2 = dir.path().join("b.txt");
+    let f3 = sub.join("c.txt");
+    File::create(&f1)?.write_all(b"a")?;
+    File::create(&f2)?.write_all(b"b")?;
+    File::create(&f3)?.write_all(b"c")?;
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .retries(40, 50)
+        .build();
+    let mut bw = BackupWriter::new(config).0;
+    bw.foreach_file(true, |_| Ok(()))?;
+
+    // A deleted file is skipped, and a temporarily unreadable file is retried
+    remove_file(&f2)?;
+    std::fs::remove_dir_all(&sub)?;
+    File::create(&sub)?;
+    let restorer = {
+        let (sub, f3) = (sub.clone(), f3.clone());
+        std::thread::spawn(move || -> std::io::Result<()> {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            remove_file(&sub)?;
+            create_dir(&sub)?;
+            File::create(&f3)?.write_all(b"c")
+        })
+    };
+    let mut errors = 0;
+    bw.write(
+        |_, res| {
+            if res.is_err() {
+                errors += 1;
+            }
+            Ok(())
+        },
+        |_| (),
+        || (),
+    )?;
+    restorer.join().unwrap()?;
+    assert_eq!(errors, 1);
+    assert_eq!(bw.skipped(), [f2.to_string_lossy().to_string()]);
+
+    let mut reader = BackupReader::new(bw.path.clone());
+    let listed: Vec<String> = reader
+        .get_list()?
+        .iter()
+        .map(|(_, f)| f.to_string())
+        .collect();
+    assert_eq!(listed, vec![
+        f1.to_string_lossy().to_string(),
+        f3.to_string_lossy().to_string()
+    ]);
+    remove_file(&f1)?;
+    remove_file(&f3)?;
+    let files = listed_files(&mut reader)?;
+    reader.restore_with(
+        files,
+        |fi| fi,
+        |_| Ok(()),
+        |_| (),
+        &RestoreOptions::default(),
+    )?;
+    assert!(f1.exists());
+    assert!(!f2.exists());
+    assert_eq!(std::fs::read(&f3)?, b"c");
+    Ok(())
+}
+
+#[test]
+fn cli_errors_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let file = dir.path().join("a.txt");
+    File::create(&file)?;
+    let config = ConfigBuilder::new()
+        .include([file.to_string_lossy()])
+        .output(dir.path().join("backup.tar.zst"))
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .build();
+    assert!(matches!(
+        backup_with(ConfigBuilder::new().build(), &BackupOptions {
+            quiet: true,
+            ..Default::default()
+        },),
+        Err(BackupError::InvalidConfig(_))
+    ));
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(matches!(
+        backup_with(config.clone(), &BackupOptions {
+            quiet: true,
+            ..Default::default()
+        },),
+        Err(BackupError::FileExists(_))
+    ));
+    let reader = BackupReader::new(config.output.clone());
+    let err = restore_with(reader.clone(), &RestoreOptions {
+        regex: vec!["(".to_string()],
+        dry: true,
+        quiet: true,
+        ..Default::default()
+    })
+    .unwrap_err();
+    assert!(matches!(err, BackupError::RegexError(_)));
+    message_chain(&err);
+    assert!(extract_with(reader, &RestoreOptions {
+        output: Some(&file),
+        only_this: true,
+        dry: true,
+        quiet: true,
+        ..Default::default()
+    },)
+    .is_err());
+    let err = inspect(
+        BackupReader::new(dir.path().join("missing.tar.zst")),
+        true,
+        true,
+        None,
+        true,
+    )
+    .unwrap_err();
+    message_chain(&err);
+    // Nested errors are only shown once
+    let cause = std::io::Error::other(BackupError::RegexError(regex::Error::CompiledTooBig(1)));
+    let err = BackupError::ArchiveError(std::io::Error::other(BackupError::IOError(cause)));
+    assert!(err
+        .to_string()
+        .starts_with("Could not read the backup: Could not parse regex: Compiled regex exceeds"));
+    assert_eq!(message_chain(&err).len(), 1);
+    // The causes that are not part of the message are still in the chain
+    let err = BackupError::WriteError(std::io::Error::other(Cause(std::io::Error::other(
+        "disk full",
+    ))));
+    assert_eq!(message_chain(&err), vec![
+        "Could not write the file: cause",
+        "disk full"
+    ]);
+    Ok(())
+}
+
+/// An error that only shows the cause as the source
+#[derive(Debug)]
+struct Cause(std::io::Error);
+
+impl std::fmt::Display for Cause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cause")
+    }
+}
+
+impl std::error::Error for Cause {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// The messages of the error and the chain of sources (checking that no message is repeated)
+fn message_chain(err: &dyn std::error::Error) -> Vec<String> {
+    let mut messages = vec![err.to_string()];
+    let mut source = err.source();
+    while let Some(e) = source {
+        let message = e.to_string();
+        assert!(
+            messages.iter().all(|m| !m.contains(&message)),
+            "'{}' is repeated in {:?}",
+            message,
+            messages
+        );
+        messages.push(message);
+        source = e.source();
+    }
+    messages
+}
+
+#[test]
+fn cancel_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let file = dir.path().join("a.txt");
+    File::create(&file)?.write_all(b"cancel")?;
+    let config = ConfigBuilder::new()
+        .include([file.to_string_lossy()])
+        .output(dir.path().join("backup.tar.zst"))
+        .incremental(false)
+        .build();
+    let cancel = AtomicBool::new(true);
+    assert!(matches!(
+        backup_with(config.clone(), &BackupOptions {
+            quiet: true,
+            cancel: Some(&cancel),
+            ..Default::default()
+        },),
+        Err(BackupError::Cancel)
+    ));
+    assert!(!config.output.exists());
+    // The options are also used when writing the backup directly
+    let mut bw = BackupWriter::new(config.clone()).0;
+    let options = BackupOptions {
+        cancel: Some(&cancel),
+        ..Default::default()
+    };
+    assert!(matches!(
+        bw.write_with(|_, _| Ok(()), |_| (), || (), &options),
+        Err(BackupError::Cancel)
+    ));
+    assert!(!config.output.exists());
+
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    std::fs::remove_file(&file)?;
+    assert!(matches!(
+        restore_with(BackupReader::new(config.output.clone()), &RestoreOptions {
+            quiet: true,
+            cancel: Some(&cancel),
+            ..Default::default()
+        },),
+        Err(BackupError::Cancel)
+    ));
+    Ok(())
+}
+
+#[test]
+fn interrupted_write_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let output = tempdir()?;
+    for name in ["a.txt", "b.txt", "c.txt"] {
+        File::create(dir.path().join(name))?.write_all(name.as_bytes())?;
+    }
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(output.path())
+        .incremental(false)
+        .build();
+    let listed =
+        || -> Result<Vec<PathBuf>, std::io::Error> { BackupIterator::dir(output.path()).collect() };
+
+    // The incomplete backup is never listed, and removed after the failure
+    let mut bw = BackupWriter::new(config.clone()).0;
+    let mut count = 0;
+    let res = bw.write(
+        |_, _| {
+            count += 1;
+            assert!(listed().unwrap().is_empty());
+            if count == 2 {
+                Err(BackupError::Cancel)
+            } else {
+                Ok(())
+            }
+        },
+        |_| (),
+        || (),
+    );
+    assert!(matches!(res, Err(BackupError::Cancel)));
+    assert_eq!(count, 2);
+    assert!(listed()?.is_empty());
+    assert_eq!(output.path().read_dir()?.count(), 0);
+
+    // A complete backup is renamed to the final name
+    let mut bw = BackupWriter::new(config).0;
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    assert_eq!(listed()?, vec![bw.path.clone()]);
+    assert_eq!(output.path().read_dir()?.count(), 1);
+    Ok(())
+}
+
+#[test]
+fn prune_old_files_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let b1 = dir2.path().join("b1_2020-20-20_20-20-21.tar.zst");
+    let b2 = dir2.path().join("b2_2020-20-20_20-20-22.tar.zst");
+    let b3 = dir2.path().join("b3_2020-20-20_20-20-23.tar.zst");
+    let mut config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(&b1)
+        .quality(1)
+        .threads(1)
+        .build();
+    File::create(dir.path().join("a.txt"))?;
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    for (b, f) in [(&b2, "b.txt"), (&b3, "c.txt")] {
+        config.output = b.clone();
+        config.time = Some(naive_now());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        File::create(dir.path().join(f))?;
+        backup_with(config.clone(), &BackupOptions {
+            quiet: true,
+            ..Default::default()
+        })?;
+    }
+
+    let merge_into = |backups: Vec<PathBuf>| {
+        merge(backups, &MergeOptions {
+            all: true,
+            quality: Some(1),
+            quiet: true,
+            ..Default::default()
+        })
+    };
+    merge_into(vec![b1.clone(), b2.clone()])?;
+    let b1_old = extend_pathbuf(b1.clone(), ".old");
+    let b2_old = extend_pathbuf(b2.clone(), ".old");
+    assert!(b1_old.exists() && b2_old.exists() && b2.exists());
+
+    // Superseded backups are readable, but not part of the chain
+    assert_eq!(BackupIterator::dir(dir2.path()).get_all()?, vec![
+        b2.clone(),
+        b3.clone()
+    ]);
+    assert!(get_backup_from_path(b1_old.clone())?.get_meta().is_ok());
+    inspect(
+        get_backup_from_path(b1_old.clone())?,
+        true,
+        true,
+        None,
+        true,
+    )?;
+    assert!(get_config_from_path(b1_old.clone()).is_err());
+    assert_eq!(find_superseding(&b1_old).map(|(p, _)| p), Some(b2.clone()));
+    assert_eq!(find_superseding(&b2_old).map(|(p, _)| p), Some(b2.clone()));
+    assert!(find_superseding(&b2).is_none());
+
+    // A merge of a merge
+    merge_into(vec![b2.clone(), b3.clone()])?;
+    let b2_old_old = extend_pathbuf(b2_old.clone(), ".old");
+    let b3_old = extend_pathbuf(b3.clone(), ".old");
+    assert_eq!(
+        find_superseding(&b2_old).map(|(p, _)| p),
+        Some(b2_old_old.clone())
+    );
+    assert_eq!(
+        find_superseding(&b2_old_old).map(|(p, _)| p),
+        Some(b3.clone())
+    );
+    let olds = [&b1_old, &b2_old, &b2_old_old, &b3_old];
+
+    // Too new
+    prune_old_files(
+        dir2.path().to_path_buf(),
+        Duration::from_secs(3600),
+        false,
+        false,
+        true,
+    )?;
+    assert!(olds.iter().all(|p| p.exists()));
+
+    // The merged backup is broken (only the config and list remain)
+    let tmp = extend_pathbuf(b3.clone(), ".bak");
+    rename(&b3, &tmp)?;
+    {
+        let mut decoder = CompressionDecoder::read(&tmp, 1)?;
+        let mut encoder = CompressionEncoder::create(&b3, 1, 1, None)?;
+        for entry in decoder.entries()?.take(2) {
+            encoder.append_entry(entry?.1, |_| ())?;
+        }
+        encoder.close()?;
+    }
+    prune_old_files(
+        dir2.path().to_path_buf(),
+        Duration::ZERO,
+        false,
+        false,
+        true,
+    )?;
+    assert!(olds.iter().all(|p| p.exists()));
+    rename(&tmp, &b3)?;
+
+    prune_old_files(b3.clone(), Duration::ZERO, false, true, true)?;
+    assert!(olds.iter().all(|p| p.exists()));
+    prune_old_files(b3.clone(), Duration::ZERO, false, false, true)?;
+    assert!(olds.iter().all(|p| !p.exists()));
+    assert!(b3.exists());
+    Ok(())
+}
+
+#[test]
+fn volume_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    let f1 = dir.path().join("a.bin");
+    let f2 = dir.path().join("b.bin");
+    let b1 = dir2.path().join("b1_2020-20-20_20-20-21.tar.zst");
+    let b2 = dir2.path().join("b2_2020-20-20_20-20-22.tar.zst");
+    // Pseudo-random content so that the backup does not compress well
+    let mut x = 7u32;
+    let mut random = |n: usize| -> Vec<u8> {
+        (0..n)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    };
+    let c1 = random(30_000);
+    let c2 = random(20_000);
+    File::create(&f1)?.write_all(&c1)?;
+    File::create(&f2)?.write_all(&c2)?;
+
+    let mut config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(b1.clone())
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .volume_size(Some(8_000))
+        .build();
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    let first = extend_pathbuf(b1.clone(), ".001");
+    assert!(!b1.exists());
+    assert!(first.exists());
+    assert!(extend_pathbuf(b1.clone(), ".007").exists());
+    assert_eq!(
+        BackupIterator::dir(dir2.path()).collect::<Result<Vec<_>, _>>()?,
+        vec![b1.clone()]
+    );
+    assert!(backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    },)
+    .is_err());
+
+    let mut reader = get_backup_from_path(first.clone())?;
+    assert_eq!(*reader.path.get_path(), b1);
+    assert_eq!(reader.get_list()?.iter().count(), 2);
+    let st = BackupStats::new(&mut reader, StatsGroup::Extension, 1)?;
+    assert!(st.compressed > 50_000);
+    restore_with(reader, &RestoreOptions {
+        output: Some(dir3.path()),
+        flatten: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert_eq!(std::fs::read(dir3.path().join("a.bin"))?, c1);
+    assert_eq!(std::fs::read(dir3.path().join("b.bin"))?, c2);
+
+    config.output = b2.clone();
+    config.volume_size = None;
+    config.time = Some(naive_now());
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(b2.exists());
+    let readers = vec![BackupReader::new(b1.clone()), BackupReader::new(b2.clone())];
+    match BackupMerger::new(None, readers, true, false, false, None, None, false) {
+        Err((_, e)) => assert!(e.to_string().contains("not yet supported")),
+        Ok(_) => panic!("Merging multi-volume backups should fail"),
+    }
+    assert!(first.exists());
+    assert!(b2.exists());
+    Ok(())
+}
+
+#[test]
+fn info_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let b1 = dir2.path().join("b1_2020-20-20_20-20-21.tar.zst");
+    let b2 = dir2.path().join("b2_2020-20-20_20-20-22.tar.zst");
+    let b3 = dir2.path().join("b3_2020-20-20_20-20-23.tar.zst");
+    let mut config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir2.path().to_path_buf())
+        .quality(1)
+        .threads(1)
+        .build();
+    for (i, b) in [&b1, &b2, &b3].into_iter().enumerate() {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(dir.path().join(format!("{}.txt", i)), "hello")?;
+        let mut bw = BackupWriter::new(config.clone()).0;
+        bw.path = b.clone();
+        let stats = bw.write(|_, _| Ok(()), |_| (), || ())?;
+        assert_eq!((stats.files, stats.size), (1, 5));
+        assert_eq!(stats.compressed, Some(std::fs::metadata(b)?.len()));
+        assert_eq!(bw.stats(), Some(stats));
+        config.time = Some(naive_now());
+    }
+
+    let stats = WriteStats {
+        files: 12345,
+        size: 1 << 30,
+        compressed: Some(1 << 29),
+        elapsed: Duration::from_secs(852),
+    };
+    assert_eq!(
+        stats.to_string(),
+        "12,345 files, 1.00 GiB -> 512.00 MiB (50%) in 14m12s (1.20 MiB/s)"
+    );
+    let stats = WriteStats {
+        elapsed: Duration::ZERO,
+        ..stats
+    };
+    assert!(stats.to_string().ends_with("in 0.0s"));
+
+    let info1 = BackupInfo::new(&mut BackupReader::new(b1.clone()))?;
+    assert_eq!((info1.files, info1.new), (1, 1));
+    assert_eq!(info1.size, Some(5));
+    assert!(info1.previous.is_none());
+    assert!(!info1.broken);
+    let info3 = BackupInfo::new(&mut BackupReader::new(b3.clone()))?;
+    assert_eq!((info3.files, info3.new), (3, 1));
+    assert!(info3.incremental);
+    assert!(info3.compressed > 0);
+    assert_eq!(info3.previous, Some(b2.to_string_lossy().to_string()));
+    assert!(!info3.broken);
+    info(dir2.path().to_path_buf(), false, false, true)?;
+    info(b3.clone(), true, false, true)?;
+
+    // Deleting a backup in the middle of the chain breaks the link
+    remove_file(&b2)?;
+    let info3 = BackupInfo::new(&mut BackupReader::new(b3.clone()))?;
+    assert_eq!(info3.previous, Some(b1.to_string_lossy().to_string()));
+    assert!(info3.broken);
+    info(dir2.path().to_path_buf(), false, false, true)?;
+    assert!(info(dir.path().to_path_buf(), false, false, true).is_err());
+    Ok(())
+}
+
+#[test]
+fn overlap_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let root = dir.path().join("a");
+    let pics = root.join("pics");
+    let docs = root.join("docs");
+    std::fs::create_dir_all(&pics)?;
+    std::fs::create_dir_all(&docs)?;
+    File::create(pics.join("1.jpg"))?.write_all(&[1; 100])?;
+    File::create(pics.join("2.jpg"))?.write_all(&[2; 200])?;
+    File::create(pics.join("skip.tmp"))?.write_all(&[3; 1000])?;
+    File::create(docs.join("a.txt"))?.write_all(&[4; 50])?;
+    let path = |p: &PathBuf| p.to_string_lossy().to_string();
+
+    let mut a = Config::new();
+    a.include = vec![path(&root)];
+    a.exclude = vec![path(&docs)];
+    let mut b = Config::new();
+    b.include = vec![path(&pics), path(&docs)];
+    b.regex = vec![String::from("\\.tmp$")];
+    let mut c = Config::new();
+    c.include = vec![path(&root)];
+
+    let overlaps = find_config_overlaps(&[a.clone(), b.clone(), c.clone()])?;
+    assert_eq!(overlaps.len(), 3);
+    let ab = &overlaps[0];
+    assert_eq!((ab.first, ab.second), (0, 1));
+    assert_eq!(ab.overlaps.len(), 1);
+    assert_eq!(ab.overlaps[0].path, pics);
+    assert_eq!(ab.overlaps[0].kind, OverlapKind::FirstContains);
+    assert_eq!(ab.overlaps[0].first, path(&root));
+    assert_eq!(ab.overlaps[0].second, path(&pics));
+    assert_eq!(ab.size, 300);
+    let ac = &overlaps[1];
+    assert_eq!((ac.first, ac.second), (0, 2));
+    assert_eq!(ac.overlaps[0].kind, OverlapKind::Shared);
+    assert_eq!(ac.size, 1300);
+    let bc = &overlaps[2];
+    assert_eq!((bc.first, bc.second), (1, 2));
+    assert_eq!(bc.overlaps.len(), 2);
+    assert_eq!(bc.overlaps[0].kind, OverlapKind::SecondContains);
+    assert_eq!(bc.size, 350);
+
+    let mut d = Config::new();
+    d.include = vec![path(&docs)];
+    assert!(find_config_overlaps(&[a.clone(), d])?.is_empty());
+
+    let fa = dir.path().join("a.yml");
+    let fb = dir.path().join("b.yml");
+    a.write_file(&fa, false)?;
+    b.write_file(&fb, false)?;
+    overlap(vec![fa, fb], true)?;
+    Ok(())
+}
+
+#[test]
+fn empty_dirs_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    let f1 = dir.path().join("a.txt");
+    let empty = dir.path().join("empty");
+    let excluded = dir.path().join("excluded");
+    let nested = dir.path().join("sub").join("inner");
+    File::create(&f1)?.write_all("test".as_bytes())?;
+    create_dir(&empty)?;
+    create_dir(&excluded)?;
+    File::create(excluded.join("b.tmp"))?.write_all("test".as_bytes())?;
+    std::fs::create_dir_all(&nested)?;
+
+    let mut config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .regex(vec![String::from(r"\.tmp$")])
+        .output(dir2.path().join("b1_2020-20-20_20-20-21.tar.zst"))
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .build();
+    let crawled = |config: &Config| -> Result<Vec<String>, BackupError> {
+        let mut files = vec![];
+        BackupWriter::new(config.clone())
+            .0
+            .foreach_file(true, |fi| {
+                files.push(fi.unwrap().copy_string().to_string());
+                Ok(())
+            })?;
+        Ok(files)
+    };
+    assert_eq!(crawled(&config)?, vec![f1.to_string_lossy().to_string()]);
+
+    config.include_empty_dirs = true;
+    let files = crawled(&config)?;
+    let expected: Vec<String> = [&f1, &empty, &excluded, &nested]
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    assert_eq!(files, expected);
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+
+    let reader = get_backup_from_path(config.output.clone())?;
+    restore_with(reader, &RestoreOptions {
+        output: Some(dir3.path()),
+        quiet: true,
+        ..Default::default()
+    })?;
+    let restored = |p: &PathBuf| {
+        dir3.path()
+            .join(strip_absolute_from_path(&p.to_string_lossy()))
+    };
+    assert!(restored(&f1).is_file());
+    assert!(restored(&empty).is_dir());
+    assert!(restored(&excluded).is_dir());
+    assert!(!restored(&excluded).join("b.tmp").exists());
+    assert!(restored(&nested).is_dir());
+    Ok(())
+}
+
+#[test]
+fn resume_scan_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    for d in ["a", "a/b", "c"] {
+        create_dir(dir.path().join(d))?;
+    }
+    for (i, f) in ["a/x.txt", "a/b/y.txt", "a/b/z.txt", "c/w.txt", "v.txt"]
+        .iter()
+        .enumerate()
+    {
+        File::create(dir.path().join(f))?.write_all(&vec![b'x'; i + 1])?;
+    }
+    let config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir2.path().to_path_buf())
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .build();
+    let checkpoint = config.get_scan_checkpoint();
+    let crawled = |bw: &mut BackupWriter| -> Result<Vec<(String, u64)>, BackupError> {
+        let mut files = vec![];
+        bw.foreach_file(true, |fi| {
+            let fi = fi?;
+            files.push((fi.copy_string().to_string(), fi.size));
+            Ok(())
+        })?;
+        files.sort();
+        Ok(files)
+    };
+
+    // Interrupt the crawl after two files
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.resume_scan();
+    let mut count = 0;
+    let res = bw.foreach_file(true, |_| {
+        count += 1;
+        if count == 2 {
+            Err(BackupError::Cancel)
+        } else {
+            Ok(())
+        }
+    });
+    assert!(matches!(res, Err(BackupError::Cancel)));
+    assert!(checkpoint.exists());
+
+    // Changes after the interruption are noticed when resuming
+    File::create(dir.path().join("a").join("new.txt"))?;
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.resume_scan();
+    let resumed = crawled(&mut bw)?;
+    let expected = crawled(&mut BackupWriter::new(config.clone()).0)?;
+    assert_eq!(expected.len(), 6);
+    assert_eq!(resumed, expected);
+
+    // The checkpoint is removed after a successful backup
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        resume_scan: true,
+        ..Default::default()
+    })?;
+    assert!(!checkpoint.exists());
+    let mut reader = get_backup_from_path(dir2.path().to_path_buf())?;
+    assert_eq!(reader.get_list()?.iter().count(), 6);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn hard_link_test() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let f1 = dir.path().join("a.bin");
+    let f2 = dir.path().join("b.bin");
+    let f3 = dir.path().join("sub").join("c.bin");
+    let b1 = dir2.path().join("b1_2020-20-20_20-20-21.tar.zst");
+    let b2 = dir2.path().join("b2_2020-20-20_20-20-22.tar.zst");
+    let b3 = dir2.path().join("b3_2020-20-20_20-20-23.tar.zst");
+    let c1 = vec![1u8; 10_000];
+    let c2 = vec![2u8; 10_000];
+    std::fs::write(&f1, &c1)?;
+    create_dir(dir.path().join("sub"))?;
+    std::fs::hard_link(&f1, &f2)?;
+    std::fs::hard_link(&f1, &f3)?;
+
+    let mut config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(b1.clone())
+        .quality(1)
+        .threads(1)
+        .build();
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+
+    // Only the first file stores the content
+    let mut decoder = CompressionDecoder::read(&b1, 1)?;
+    let mut links = vec![];
+    for entry in decoder.entries()?.skip(2) {
+        links.push(entry?.1.header().entry_type().is_hard_link());
+    }
+    assert_eq!(links, vec![false, true, true]);
+
+    let restore_to = |backup: &PathBuf,
+                      out: &Path,
+                      include: Vec<String>|
+     -> Result<(), Box<dyn std::error::Error>> {
+        restore_with(get_backup_from_path(backup.clone())?, &RestoreOptions {
+            output: Some(out),
+            include,
+            only_this: true,
+            quiet: true,
+            ..Default::default()
+        })?;
+        Ok(())
+    };
+    let restored = |out: &Path, p: &Path| out.join(strip_absolute_from_path(&p.to_string_lossy()));
+
+    // The links are recreated
+    let out = tempdir()?;
+    restore_to(&b1, out.path(), vec![])?;
+    let inode = std::fs::metadata(restored(out.path(), &f1))?.ino();
+    for f in [&f1, &f2, &f3] {
+        assert_eq!(std::fs::read(restored(out.path(), f))?, c1);
+        assert_eq!(std::fs::metadata(restored(out.path(), f))?.ino(), inode);
+    }
+
+    // Only the content of the first file is unpacked (for the progress)
+    let out = tempdir()?;
+    let mut reader = get_backup_from_path(b1.clone())?;
+    let files = listed_files(&mut reader)?;
+    let mut unpacked = 0;
+    reader.restore_with(
+        files,
+        |mut fi| FileInfo::from(restored(out.path(), fi.get_path())),
+        |res| res.map(|_| ()).map_err(BackupError::FileError),
+        |bytes| unpacked += bytes,
+        &RestoreOptions::default(),
+    )?;
+    assert_eq!(unpacked, c1.len() as u64);
+
+    // Restoring a link without the target still restores the content
+    let out = tempdir()?;
+    restore_to(&b1, out.path(), vec![f3.to_string_lossy().to_string()])?;
+    assert!(!restored(out.path(), &f1).exists());
+    assert_eq!(std::fs::read(restored(out.path(), &f3))?, c1);
+
+    // Merging keeps the content of links whose target is replaced by a newer backup
+    config.output = b2.clone();
+    config.time = Some(naive_now());
+    std::thread::sleep(Duration::from_millis(20));
+    remove_file(&f1)?;
+    std::fs::write(&f1, &c2)?;
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    let readers = vec![BackupReader::new(b1.clone()), BackupReader::new(b2.clone())];
+    let mut merger = BackupMerger::new(
+        Some(b3.clone()),
+        readers,
+        false,
+        false,
+        false,
+        Some(1),
+        None,
+        false,
+    )
+    .map_err(|(_, e)| e)?;
+    merger.write(|_, res| res, |_| (), || ())?;
+    let out = tempdir()?;
+    restore_to(&b3, out.path(), vec![])?;
+    assert_eq!(std::fs::read(restored(out.path(), &f1))?, c2);
+    assert_eq!(std::fs::read(restored(out.path(), &f2))?, c1);
+    assert_eq!(std::fs::read(restored(out.path(), &f3))?, c1);
+    Ok(())
+}
+
+#[test]
+fn journal_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let b1 = dir2.path().join("b1_2020-20-20_20-20-21.tar.zst");
+    let b2 = dir2.path().join("b2_2020-20-20_20-20-22.tar.zst");
+    let mut config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(&b1)
+        .quality(1)
+        .threads(1)
+        .journal(true)
+        .build();
+    File::create(dir.path().join("a.txt"))?;
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    config.output = b2.clone();
+    config.time = Some(naive_now());
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    File::create(dir.path().join("b.txt"))?;
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    let journals = || {
+        dir2.path()
+            .read_dir()
+            .unwrap()
+            .filter(|e| {
+                e.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(JOURNAL_PREFIX)
+            })
+            .count()
+    };
+
+    merge(vec![b1.clone(), b2.clone()], &MergeOptions {
+        all: true,
+        quality: Some(1),
+        journal: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(extend_pathbuf(b1.clone(), ".old").exists());
+    assert!(extend_pathbuf(b2.clone(), ".old").exists());
+    assert_eq!(
+        get_backup_from_path(b2.clone())?.get_list()?.iter().count(),
+        2
+    );
+    assert_eq!(journals(), 0);
+
+    // An interrupted operation is completed by the next command
+    let moved = dir2.path().join("b3_2020-20-20_20-20-23.tar.zst");
+    let mut txn = Transaction::new();
+    txn.rename(&b2, &moved).delete(dir2.path().join("missing"));
+    assert!(txn.commit(Some(dir2.path())).is_err());
+    assert_eq!(journals(), 1);
+    prune_old_files(
+        dir2.path().to_path_buf(),
+        Duration::from_secs(3600),
+        false,
+        false,
+        true,
+    )?;
+    assert_eq!(journals(), 0);
+    assert!(moved.exists() && !b2.exists());
+    Ok(())
+}
+
+#[test]
+fn dedup_test() -> Result<(), Box<dyn std::error::Error>> {
+    use std::path::Path;
+
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let f1 = dir.path().join("a").join("x.jpg");
+    let f2 = dir.path().join("b").join("y.jpg");
+    let f3 = dir.path().join("b").join("z.jpg");
+    let b1 = dir2.path().join("b1_2020-20-20_20-20-21.tar.zst");
+    let content = (0..20_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut other = content.clone();
+    other[10_000] = 0;
+    create_dir(dir.path().join("a"))?;
+    create_dir(dir.path().join("b"))?;
+    std::fs::write(&f1, &content)?;
+    std::fs::write(&f2, &content)?;
+    // Same size (and maybe the same hash), but a different content
+    std::fs::write(&f3, &other)?;
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(&b1)
+        .quality(1)
+        .threads(1)
+        .dedup(true)
+        .build();
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+
+    // The content is only stored once (and the duplicates share the hash in the list)
+    let mut decoder = CompressionDecoder::read(&b1, 1)?;
+    let mut sizes = vec![];
+    for entry in decoder.entries()?.skip(2) {
+        let (_, entry) = entry?;
+        sizes.push((entry.header().entry_type().is_hard_link(), entry.size()));
+    }
+    assert_eq!(sizes, vec![(false, 20_000), (true, 0), (false, 20_000)]);
+    let mut reader = get_backup_from_path(b1.clone())?;
+    let hashes: Vec<Option<u64>> = reader.get_list()?.iter_meta().map(|m| m.2).collect();
+    assert!(hashes[0].is_some());
+    assert_eq!(hashes[0], hashes[1]);
+    // The sizes are from the list (not the links) and the times from the archive
+    let entries = reader.entries()?.collect::<Vec<_>>();
+    assert_eq!(entries.len(), 3);
+    assert!(entries
+        .iter()
+        .all(|e| e.size == Some(20_000) && e.time.is_some()));
+
+    let restore_to = |out: &Path, include: Vec<String>| -> Result<(), BackupError> {
+        restore_with(get_backup_from_path(b1.clone())?, &RestoreOptions {
+            output: Some(out),
+            include,
+            only_this: true,
+            quiet: true,
+            ..Default::default()
+        })
+    };
+    let restored = |out: &Path, p: &Path| out.join(strip_absolute_from_path(&p.to_string_lossy()));
+
+    // Both copies are restored (as separate files)
+    let out = tempdir()?;
+    let out = out.path();
+    restore_to(out, vec![])?;
+    assert_eq!(std::fs::read(restored(out, &f1))?, content);
+    assert_eq!(std::fs::read(restored(out, &f2))?, content);
+    assert_eq!(std::fs::read(restored(out, &f3))?, other);
+    std::fs::write(restored(out, &f1), "changed")?;
+    assert_eq!(std::fs::read(restored(out, &f2))?, content);
+
+    // Only the duplicate
+    let out = tempdir()?;
+    let out = out.path();
+    restore_to(out, vec![f2.to_string_lossy().to_string()])?;
+    assert!(!restored(out, &f1).exists());
+    assert_eq!(std::fs::read(restored(out, &f2))?, content);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn change_detection_test() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let f1 = dir.path().join("a.txt");
+    let f2 = dir.path().join("b.txt");
+    let old = std::time::SystemTime::now() - Duration::from_secs(3600);
+    for f in [&f1, &f2] {
+        File::create(f)?.write_all(b"content")?;
+        File::options().write(true).open(f)?.set_modified(old)?;
+    }
+    // The changes are detected at second granularity (and the files were just created)
+    std::thread::sleep(Duration::from_millis(1100));
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .quality(1)
+        .threads(1)
+        .build();
+    let b1 = dir2.path().join("backup_2020-01-01_00-00-01.tar.zst");
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = b1.clone();
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+
+    // Only the status change time is updated
+    std::thread::sleep(Duration::from_millis(1100));
+    std::fs::set_permissions(&f1, std::fs::Permissions::from_mode(0o600))?;
+    let incremental = |detection: ChangeDetection, updates: bool| -> Result<_, BackupError> {
+        let output = dir2.path().join(format!("{:?}_{}", detection, updates));
+        create_dir(&output)?;
+        std::fs::copy(&b1, output.join("backup_2020-01-01_00-00-01.tar.zst"))?;
+        let mut config = config.clone();
+        config.output = output.clone();
+        config.change_detection = detection;
+        config.metadata_updates = updates;
+        let mut bw = BackupWriter::new(config).0;
+        bw.path = output.join("backup_2020-01-01_00-00-02.tar.zst");
+        bw.write(|_, _| Ok(()), |_| (), || ())?;
+        Ok(BackupReader::new(bw.path))
+    };
+    let included = |reader: &mut BackupReader| -> Result<Vec<String>, BackupError> {
+        Ok(reader
+            .get_list()?
+            .iter_included()
+            .map(String::from)
+            .collect())
+    };
+    let a = vec![f1.to_string_lossy().to_string()];
+    assert!(included(&mut incremental(ChangeDetection::Mtime, false)?)?.is_empty());
+    assert_eq!(
+        included(&mut incremental(ChangeDetection::Ctime, false)?)?,
+        a
+    );
+    assert_eq!(
+        included(&mut incremental(ChangeDetection::Either, false)?)?,
+        a
+    );
+    // Metadata updates are ignored when only checking the modification time
+    assert!(included(&mut incremental(ChangeDetection::Mtime, true)?)?.is_empty());
+
+    // Only the metadata is stored, and applied after the content is restored from the first backup
+    let mut reader = incremental(ChangeDetection::Ctime, true)?;
+    assert!(included(&mut reader)?.is_empty());
+    let updates: Vec<(u32, String)> = reader
+        .get_list()?
+        .iter_updates()
+        .map(|(u, s)| (u.mode, s.to_string()))
+        .collect();
+    assert_eq!(updates, vec![(0o600, a[0].clone())]);
+    let out = tempdir()?;
+    let files = listed_files(&mut reader)?;
+    reader.restore_with(
+        files,
+        |fi| {
+            FileInfo::from(
+                out.path()
+                    .join(strip_absolute_from_path(fi.copy_string().as_ref())),
+            )
+        },
+        |res| res.map(|_| ()).map_err(BackupError::FileError),
+        |_| (),
+        &RestoreOptions {
+            overwrite: Overwrite::Always,
+            ..Default::default()
+        },
+    )?;
+    let r1 = out
+        .path()
+        .join(strip_absolute_from_path(&f1.to_string_lossy()));
+    let r2 = out
+        .path()
+        .join(strip_absolute_from_path(&f2.to_string_lossy()));
+    assert_eq!(std::fs::read(&r1)?, b"content");
+    assert_eq!(std::fs::read(&r2)?, b"content");
+    assert_eq!(r1.metadata()?.permissions().mode() & 0o777, 0o600);
+    assert_ne!(r2.metadata()?.permissions().mode() & 0o777, 0o600);
+    Ok(())
+}
+
+#[test]
+fn windows_list_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let b1 = dir.path().join("b1_2020-20-20_20-20-21.tar.zst");
+    // A backup from an older Windows version (with `\` in the list, but `/` in the archive)
+    let mut config = Config::new();
+    config.time = Some(naive_now());
+    let mut encoder = CompressionEncoder::create(&b1, 1, 1, None)?;
+    encoder.append_data("config.yml", config.as_yaml()?)?;
+    encoder.append_data("files_v2.csv", "1,C:\\data\\a.txt\n1,C:\\data\\sub\\b.txt")?;
+    encoder.append_data("absC:/data/a.txt", "a")?;
+    encoder.append_data("absC:/data/sub/b.txt", "b")?;
+    encoder.close()?;
+
+    let out = tempdir()?;
+    restore_with(get_backup_from_path(b1.clone())?, &RestoreOptions {
+        output: Some(out.path()),
+        only_this: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    let restored = |out: &tempfile::TempDir, p: &str| {
+        std::fs::read_to_string(out.path().join(strip_absolute_from_path(p)))
+    };
+    assert_eq!(restored(&out, "C:/data/a.txt")?, "a");
+    assert_eq!(restored(&out, "C:/data/sub/b.txt")?, "b");
+
+    // Selecting a file with the Windows separator
+    let out = tempdir()?;
+    let mut reader = BackupReader::new(b1);
+    let mut count = 0;
+    reader.restore(
+        vec!["C:\\data\\sub\\b.txt"],
+        |mut fi| FileInfo::from(out.path().join(strip_absolute_from_path(fi.get_string()))),
+        |res| {
+            res?;
+            count += 1;
+            Ok(())
+        },
+        Overwrite::Never,
+        false,
+    )?;
+    assert_eq!(count, 1);
+    assert!(restored(&out, "C:/data/a.txt").is_err());
+    assert_eq!(restored(&out, "C:/data/sub/b.txt")?, "b");
+    Ok(())
+}
+
+#[test]
+fn unsorted_selection_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let b1 = dir2.path().join("b1_2020-20-20_20-20-21.tar.zst");
+    create_dir(dir.path().join("dir"))?;
+    create_dir(dir.path().join("sub"))?;
+    let names = ["b.txt", "dir.txt", "dir/a.txt", "sub/c.txt"];
+    for name in names {
+        std::fs::write(dir.path().join(name), name)?;
+    }
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(&b1)
+        .quality(1)
+        .threads(1)
+        .build();
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+
+    // A reversed selection (with a duplicate) is still restored completely
+    let out = tempdir()?;
+    let mut reader = get_backup_from_path(b1.clone())?;
+    let mut selection: Vec<String> = names.iter().rev().map(|n| path(n)).collect();
+    selection.push(path("dir.txt"));
+    let mut count = 0;
+    let mut size = 0;
+    reader.restore_with(
+        selection,
+        |mut fi| FileInfo::from(out.path().join(strip_absolute_from_path(fi.get_string()))),
+        |res| {
+            res?;
+            count += 1;
+            Ok(())
+        },
+        |bytes| size += bytes,
+        &RestoreOptions {
+            only_this: true,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(count, names.len());
+    // The unpacked bytes are the content of the restored files (the duplicate is not unpacked)
+    assert_eq!(size, names.iter().map(|n| n.len() as u64).sum::<u64>());
+    for name in names {
+        let restored = out.path().join(strip_absolute_from_path(&path(name)));
+        assert_eq!(std::fs::read_to_string(restored)?, name);
+    }
+
+    // Mixing includes and regex matches
+    let out = tempdir()?;
+    restore_with(get_backup_from_path(b1)?, &RestoreOptions {
+        output: Some(out.path()),
+        include: vec![path("sub/c.txt"), path("b.txt")],
+        regex: vec!["dir".to_string()],
+        only_this: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    for name in names {
+        let restored = out.path().join(strip_absolute_from_path(&path(name)));
+        assert_eq!(std::fs::read_to_string(restored)?, name);
+    }
+    Ok(())
+}
+
+#[test]
+fn dry_report_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    create_dir(&src)?;
+    std::fs::write(src.join("a.txt"), "report")?;
+    std::fs::write(src.join("b.txt"), "report")?;
+    let output = dir.path().join("backups");
+    let report = dir.path().join("report.csv");
+    let config = ConfigBuilder::new()
+        .include([src.to_string_lossy()])
+        .output(&output)
+        .build();
+    backup_with(config, &BackupOptions {
+        dry: true,
+        quiet: true,
+        report: Some(&report),
+        ..Default::default()
+    })?;
+    assert!(!output.exists() || std::fs::read_dir(&output)?.next().is_none());
+    let content = std::fs::read_to_string(&report)?;
+    let mut lines = content.lines();
+    assert!(lines.next().unwrap().ends_with("Path"));
+    let paths: Vec<&str> = lines.map(|l| l.rsplit(", ").next().unwrap()).collect();
+    assert_eq!(paths, [
+        src.join("a.txt").to_string_lossy(),
+        src.join("b.txt").to_string_lossy()
+    ]);
+    Ok(())
+}
+
+#[test]
+fn follow_origin_test() -> Result<(), Box<dyn std::error::Error>> {
+    let src = tempdir()?;
+    let origin = tempdir()?;
+    let copy = tempdir()?;
+    let f1 = src.path().join("a.txt");
+    let f2 = src.path().join("b.txt");
+    std::fs::write(&f1, "first")?;
+    std::fs::write(&f2, "first")?;
+    let mut config = ConfigBuilder::new()
+        .include([src.path().to_string_lossy()])
+        .output(origin.path())
+        .incremental(true)
+        .build();
+    backup_with(config.clone(), &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    let b1 = origin.path().join("backup_2020-01-01_00-00-00.tar.zst");
+    rename(config.get_backups().get_latest().unwrap(), &b1)?;
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    config.time = Some(naive_now());
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    std::fs::write(&f2, "second")?;
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    let mut backups = BackupIterator::dir(origin.path()).get_all()?;
+    assert_eq!(backups.len(), 2);
+    let b2 = backups.pop().unwrap();
+    for b in [&b1, &b2] {
+        std::fs::copy(b, copy.path().join(b.file_name().unwrap()))?;
+    }
+    let c2 = copy.path().join(b2.file_name().unwrap());
+    let restore_all = |path: &PathBuf, follow: bool| -> Result<Vec<String>, BackupError> {
+        let out = tempdir().unwrap();
+        let mut reader = BackupReader::new(path.clone()).with_follow_origin(follow);
+        let selection: Vec<String> = reader
+            .get_list()?
+            .iter()
+            .map(|(_, f)| f.to_string())
+            .collect();
+        let mut missing = vec![];
+        reader.restore(
+            selection,
+            |mut fi| FileInfo::from(out.path().join(strip_absolute_from_path(fi.get_string()))),
+            |res| {
+                if let Err(e) = res {
+                    missing.push(e.to_string());
+                }
+                Ok(())
+            },
+            Overwrite::Never,
+            true,
+        )?;
+        Ok(missing)
+    };
+
+    // The copied chain is used even if the original directory still exists
+    remove_file(&b1)?;
+    assert!(restore_all(&c2, false)?.is_empty());
+
+    // The chain does not reach back into the original directory by default
+    std::fs::copy(copy.path().join(b1.file_name().unwrap()), &b1)?;
+    remove_file(copy.path().join(b1.file_name().unwrap()))?;
+    let missing = restore_all(&c2, false)?;
+    assert_eq!(missing.len(), 1);
+    assert!(missing[0].contains("a.txt"));
+    assert!(restore_all(&c2, true)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn query_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    create_dir(&src)?;
+    create_dir(src.join("sub"))?;
+    for (name, size) in [
+        ("a.log", 2048),
+        ("b.log", 10),
+        ("c.txt", 2048),
+        ("sub/d.log", 4096),
+        ("sub/e.log", 4096),
+    ] {
+        std::fs::write(src.join(name), vec![b'x'; size])?;
+    }
+    let old = std::time::UNIX_EPOCH + Duration::from_secs(1_577_880_000);
+    File::options()
+        .write(true)
+        .open(src.join("sub/e.log"))?
+        .set_modified(old)?;
+    let output = dir.path().join("backup.tar.zst");
+    let config = ConfigBuilder::new()
+        .include([src.to_string_lossy()])
+        .output(&output)
+        .incremental(false)
+        .build();
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+
+    let restored = |query: &str| -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let out = tempdir()?;
+        let query = Query::parse(query)?;
+        restore_with(BackupReader::new(output.clone()), &RestoreOptions {
+            output: Some(out.path()),
+            query: Some(&query),
+            flatten: true,
+            quiet: true,
+            ..Default::default()
+        })?;
+        let mut names: Vec<String> = std::fs::read_dir(out.path())?
+            .map(|e| e.map(|e| e.file_name().to_string_lossy().to_string()))
+            .collect::<Result<_, _>>()?;
+        names.sort();
+        Ok(names)
+    };
+    assert_eq!(restored("size > 1K and path ~ '\\.log$'")?, [
+        "a.log", "d.log", "e.log"
+    ]);
+    assert_eq!(
+        restored("path ~ '\\.log$' and not (path ^= 'x' or size >= 4K)")?,
+        ["a.log", "b.log"]
+    );
+    assert_eq!(restored("size > 1K and mtime < 2021-01-01")?, ["e.log"]);
+    assert!(restored("size > 1M")?.is_empty());
+
+    // The query is an additional filter for the includes
+    let out = tempdir()?;
+    restore_with(BackupReader::new(output.clone()), &RestoreOptions {
+        output: Some(out.path()),
+        include: vec![
+            src.join("a.log").to_string_lossy().to_string(),
+            src.join("b.log").to_string_lossy().to_string(),
+        ],
+        query: Some(&Query::parse("size < 1K")?),
+        flatten: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(out.path().join("b.log").exists());
+    assert!(!out.path().join("a.log").exists());
+    Ok(())
+}
+
+#[test]
+fn store_only_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    create_dir(&src)?;
+    // Pseudo-random content (like an actual photo it does not compress)
+    let mut x = 7u32;
+    let photo: Vec<u8> = (0..300_000)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect();
+    let text = "All work and no play. ".repeat(10_000);
+    std::fs::write(src.join("a.txt"), &text)?;
+    std::fs::write(src.join("b.jpg"), &photo)?;
+    std::fs::write(src.join("c.txt"), &text)?;
+    let output = dir.path().join("backup.tar.zst");
+    let config = ConfigBuilder::new()
+        .include([src.to_string_lossy()])
+        .output(&output)
+        .incremental(false)
+        .quality(19)
+        .build();
+    assert!(config.store_only.iter().any(|e| e == "jpg"));
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+
+    let out = tempdir()?;
+    restore_with(BackupReader::new(output.clone()), &RestoreOptions {
+        output: Some(out.path()),
+        flatten: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert_eq!(std::fs::read(out.path().join("b.jpg"))?, photo);
+    assert_eq!(std::fs::read_to_string(out.path().join("a.txt"))?, text);
+    assert_eq!(std::fs::read_to_string(out.path().join("c.txt"))?, text);
+    Ok(())
+}
+
+#[test]
+fn apple_double_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    create_dir(&src)?;
+    std::fs::write(src.join("photo.jpg"), "photo")?;
+    std::fs::write(src.join("._photo.jpg"), "metadata")?;
+    let output = dir.path().join("backup.tar.zst");
+    let config = ConfigBuilder::new()
+        .include([src.to_string_lossy()])
+        .output(&output)
+        .incremental(false)
+        .skip_apple_double()
+        .build();
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+    let mut reader = BackupReader::new(output);
+    let files: Vec<String> = reader
+        .get_list()?
+        .iter_included()
+        .map(String::from)
+        .collect();
+    assert_eq!(files, [src.join("photo.jpg").to_string_lossy()]);
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+#[test]
+fn macos_xattrs_test() -> Result<(), Box<dyn std::error::Error>> {
+    use simple_backup::xattrs::{read, write};
+
+    const TAGS: &str = "com.apple.metadata:_kMDItemUserTags";
+    const QUARANTINE: &str = "com.apple.quarantine";
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    create_dir(&src)?;
+    let tagged = src.join("tagged.txt");
+    let quarantined = src.join("quarantined.txt");
+    std::fs::write(&tagged, "tagged")?;
+    std::fs::write(&quarantined, "quarantined")?;
+    let tags = b"bplist00\xa1\x01UGreen\n2\x08\x0a".to_vec();
+    write(&tagged, &[(TAGS.to_string(), tags.clone())])?;
+    write(&quarantined, &[(
+        QUARANTINE.to_string(),
+        b"0081;5f000000;Safari;".to_vec(),
+    )])?;
+    let output = dir.path().join("backup.tar.zst");
+    let config = ConfigBuilder::new()
+        .include([src.to_string_lossy()])
+        .output(&output)
+        .incremental(false)
+        .build();
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+
+    let out = tempdir()?;
+    restore_with(BackupReader::new(output.clone()), &RestoreOptions {
+        output: Some(out.path()),
+        flatten: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    let attrs = read(&out.path().join("tagged.txt"))?;
+    assert!(attrs.contains(&(TAGS.to_string(), tags)));
+    let attrs = read(&out.path().join("quarantined.txt"))?;
+    assert!(attrs.iter().all(|(name, _)| name != QUARANTINE));
+    assert_eq!(
+        std::fs::read_to_string(out.path().join("quarantined.txt"))?,
+        "quarantined"
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn write_errors_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    create_dir(&src)?;
+    std::fs::write(src.join("a.txt"), "a")?;
+    let looped = src.join("loop");
+    std::os::unix::fs::symlink(&src, &looped)?;
+    let out = dir.path().join("out");
+    create_dir(&out)?;
+    let config = ConfigBuilder::new()
+        .include([src.to_string_lossy()])
+        .output(&out)
+        .incremental(false)
+        .build();
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        write_errors: true,
+        ..Default::default()
+    })?;
+    let backup = std::fs::read_dir(&out)?
+        .map(|e| e.unwrap().path())
+        .find(|p| p.to_string_lossy().ends_with(".tar.zst"))
+        .unwrap();
+    let errors = std::fs::read_to_string(errors_path(&backup))?;
+    assert!(errors.contains(&*looped.to_string_lossy()));
+    assert!(!errors.contains("a.txt"));
+    let mut reader = BackupReader::new(backup);
+    let list: Vec<String> = reader
+        .get_list()?
+        .iter_included()
+        .map(|f| f.to_string())
+        .collect();
+    assert_eq!(list.len(), 1);
+    assert!(list[0].ends_with("a.txt"));
+    Ok(())
+}
+
+#[test]
+fn exclude_from_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    create_dir(&src)?;
+    for name in ["a.txt", "b.txt", "c.log", "d.txt"] {
+        std::fs::write(src.join(name), name)?;
+    }
+    let conf_dir = dir.path().join("conf");
+    create_dir(&conf_dir)?;
+    std::fs::write(
+        conf_dir.join("excludes.txt"),
+        format!("# Leave out b\n{}\n\n", src.join("b.txt").to_string_lossy()),
+    )?;
+    std::fs::write(conf_dir.join("regex.txt"), "\\.log$\n")?;
+    let config_path = conf_dir.join("config.yml");
+    ConfigBuilder::new()
+        .include([src.to_string_lossy()])
+        .exclude_from(Some("excludes.txt"))
+        .regex_from(Some("regex.txt"))
+        .output(dir.path().join("backup.tar.zst"))
+        .incremental(false)
+        .build()
+        .write_yaml(&config_path, false)?;
+
+    let config = Config::read_yaml(config_path)?;
+    let mut bw = BackupWriter::new(config).0;
+    let mut files = vec![];
+    bw.foreach_file(true, |fi| {
+        files.push(PathBuf::from(fi.unwrap().copy_string().to_string()));
+        Ok(())
+    })?;
+    files.sort();
+    assert_eq!(files, [src.join("a.txt"), src.join("d.txt")]);
+    assert!(bw
+        .config
+        .exclude
+        .contains(&src.join("b.txt").to_string_lossy().to_string()));
+
+    std::fs::remove_file(conf_dir.join("regex.txt"))?;
+    let config = Config::read_yaml(conf_dir.join("config.yml"))?;
+    assert!(matches!(
+        BackupWriter::new(config).0.foreach_file(true, |_| Ok(())),
+        Err(BackupError::FileError(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn usage_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    create_dir(&src)?;
+    let out = dir.path().join("out");
+    create_dir(&out)?;
+    let mut config = ConfigBuilder::new()
+        .include([src.to_string_lossy()])
+        .output(&out)
+        .build();
+    std::fs::write(src.join("a.txt"), "a")?;
+    std::fs::write(src.join("b.txt"), "bb")?;
+    let mut chain = vec![];
+    // The middle backup only stores a version of `a.txt` that is replaced by the last backup
+    for (i, content) in ["a1", "a22", "a333"].into_iter().enumerate() {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        std::fs::write(src.join("a.txt"), content)?;
+        let mut bw = BackupWriter::new(config.clone()).0;
+        bw.path = out.join(format!("backup_2020-01-01_00-00-0{}.tar.zst", i));
+        bw.write(|_, _| Ok(()), |_| (), || ())?;
+        chain.push(bw.path.clone());
+        config.time = Some(naive_now());
+    }
+
+    let usage = ChainUsage::new(BackupReader::new(chain[2].clone()))?;
+    assert_eq!(usage.files, 2);
+    assert_eq!(usage.missing, 0);
+    let counts: Vec<_> = usage
+        .backups
+        .iter()
+        .map(|b| (b.stored, b.unique, b.unique_size))
+        .collect();
+    assert_eq!(counts, [(2, 1, 2), (1, 0, 0), (1, 1, 4)]);
+    assert!(usage.backups[1].is_redundant());
+    assert!(!usage.consolidations.is_empty());
+    for c in usage.consolidations.iter() {
+        assert!(c.estimated < c.compressed);
+        assert_eq!(
+            c.backups.last(),
+            Some(&chain[2].to_string_lossy().to_string())
+        );
+    }
+    usage_cli(
+        BackupReader::new(chain[2].clone()),
+        OutputFormat::Json,
+        true,
+    )?;
+    usage_cli(
+        BackupReader::new(chain[2].clone()),
+        OutputFormat::Table,
+        true,
+    )?;
+    Ok(())
+}
+
+#[test]
+fn overwrite_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let src = dir.path().join("src");
+    create_dir(&src)?;
+    let older = src.join("older.txt");
+    let newer = src.join("newer.txt");
+    let hour = Duration::from_secs(3600);
+    let time = std::time::SystemTime::now() - hour;
+    for f in [&older, &newer] {
+        std::fs::write(f, "backup")?;
+        File::options().write(true).open(f)?.set_modified(time)?;
+    }
+    let output = dir.path().join("backup.tar.zst");
+    let config = ConfigBuilder::new()
+        .include([src.to_string_lossy()])
+        .output(&output)
+        .incremental(false)
+        .build();
+    backup_with(config, &BackupOptions {
+        quiet: true,
+        ..Default::default()
+    })?;
+
+    let out = dir.path().join("out");
+    let existing = |f: &PathBuf| out.join(strip_absolute_from_path(&f.to_string_lossy()));
+    let restore_with =
+        |overwrite: Overwrite| -> Result<(String, String), Box<dyn std::error::Error>> {
+            // One existing file is older than the backup and the other newer
+            for (f, t) in [(&older, time - hour), (&newer, time + hour)] {
+                std::fs::create_dir_all(existing(f).parent().unwrap())?;
+                std::fs::write(existing(f), "existing")?;
+                File::options()
+                    .write(true)
+                    .open(existing(f))?
+                    .set_modified(t)?;
+            }
+            restore_with(BackupReader::new(output.clone()), &RestoreOptions {
+                output: Some(&out),
+                overwrite,
+                quiet: true,
+                ..Default::default()
+            })?;
+            Ok((
+                std::fs::read_to_string(existing(&older))?,
+                std::fs::read_to_string(existing(&newer))?,
+            ))
+        };
+    assert_eq!(
+        restore_with(Overwrite::Never)?,
+        ("existing".into(), "existing".into())
+    );
+    assert_eq!(
+        restore_with(Overwrite::Always)?,
+        ("backup".into(), "backup".into())
+    );
+    assert_eq!(
+        restore_with(Overwrite::IfNewer)?,
+        ("backup".into(), "existing".into())
+    );
+
+    // The skipped files are reported with their own message
+    let mut errors = vec![];
+    let mut reader = BackupReader::new(output.clone());
+    let files = listed_files(&mut reader)?;
+    reader.restore_with(
+        files,
+        |mut fi| FileInfo::from(existing(fi.get_path())),
+        |res| {
+            if let Err(e) = res {
+                errors.push(e.to_string());
+            }
+            Ok(())
+        },
+        |_| (),
+        &RestoreOptions {
+            overwrite: Overwrite::IfNewer,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(errors.len(), 2);
+    assert!(errors
+        .iter()
+        .all(|e| e.contains("not older than the backup")));
+    Ok(())
+}
+
+#[test]
+fn base_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let f1 = dir.path().join("a.txt");
+    let f2 = dir.path().join("b.txt");
+    File::create(&f1)?.write_all(b"content")?;
+    File::create(&f2)?.write_all(b"content")?;
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .quality(1)
+        .threads(1)
+        .build();
+    std::thread::sleep(Duration::from_millis(20));
+    let b1 = dir2.path().join("backup_2020-01-01_00-00-01.tar.zst");
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = b1.clone();
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+
+    // Modified between the two backups
+    std::thread::sleep(Duration::from_millis(20));
+    File::create(&f1)?.write_all(b"changed")?;
+    std::thread::sleep(Duration::from_millis(20));
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-02.tar.zst");
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+
+    let crawled = |base: Option<&PathBuf>| -> Result<Vec<String>, BackupError> {
+        let mut config = config.clone();
+        config.base = base.cloned();
+        let (mut bw, error) = BackupWriter::new(config);
+        if let Some(e) = error {
+            return Err(e);
+        }
+        let mut files = vec![];
+        bw.foreach_file(false, |fi| {
+            files.push(fi.unwrap().copy_string().to_string());
+            Ok(())
+        })?;
+        Ok(files)
+    };
+    assert!(crawled(None)?.is_empty());
+    assert_eq!(crawled(Some(&b1))?, vec![f1.to_string_lossy().to_string()]);
+
+    // A missing base is an error instead of a full backup
+    let mut config = config.clone();
+    config.base = Some(dir2.path().join("missing.tar.zst"));
+    assert!(backup_with(config, &BackupOptions {
+        dry: true,
+        quiet: true,
+        ..Default::default()
+    })
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn merge_range_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .quality(1)
+        .threads(1)
+        .build();
+    let mut paths = vec![];
+    for i in 1..4 {
+        File::create(dir.path().join(format!("{}.txt", i)))?;
+        std::thread::sleep(Duration::from_millis(20));
+        let mut bw = BackupWriter::new(config.clone()).0;
+        bw.path = dir2
+            .path()
+            .join(format!("backup_2020-01-01_00-00-0{}.tar.zst", i));
+        bw.write(|_, _| Ok(()), |_| (), || ())?;
+        paths.push(bw.path);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let before = parse_date::try_parse("2020-01-01 00:00:03")?;
+    let merge_before = |output: Option<PathBuf>| {
+        merge(vec![dir2.path().to_path_buf()], &MergeOptions {
+            output: output.as_deref(),
+            before,
+            quiet: true,
+            ..Default::default()
+        })
+    };
+
+    // The third backup depends on the second, so the merge must keep its name
+    assert!(merge_before(Some(dir2.path().join("merged.tar.zst"))).is_err());
+    assert!(paths.iter().all(|p| p.exists()));
+    merge_before(None)?;
+    assert!(extend_pathbuf(paths[0].clone(), ".old").exists());
+    assert!(!extend_pathbuf(paths[2].clone(), ".old").exists());
+    let mut reader = BackupReader::new(paths[1].clone());
+    assert_eq!(reader.get_list()?.iter_included().count(), 2);
+    let mut reader = BackupReader::new(paths[2].clone());
+    assert_eq!(reader.get_previous()?.unwrap().path.clone_path(), paths[1]);
+    Ok(())
+}
+
+#[test]
+fn commands_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    File::create(dir.path().join("a.txt"))?.write_all(b"content")?;
+    #[cfg(target_os = "windows")]
+    let sleep = "ping -n 6 127.0.0.1";
+    #[cfg(not(target_os = "windows"))]
+    let sleep = "sleep 5";
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .quality(1)
+        .threads(1)
+        .command("hello", "echo hello", 0)
+        .command("fail", "exit 3", 0)
+        .command("slow", sleep, 1)
+        .build();
+    assert!(config.validate().iter().all(|i| !i.is_error()));
+
+    // Dry runs only list the commands
+    let marker = dir3.path().join("marker.txt");
+    let mut dry = config.clone();
+    dry.commands.push(BackupCommand::new(
+        "marker",
+        format!("echo x > \"{}\"", marker.to_string_lossy()),
+        0,
+    ));
+    backup_with(dry, &BackupOptions {
+        dry: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(!marker.exists());
+
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-01.tar.zst");
+    let mut errors = vec![];
+    bw.write(
+        |fi, res| {
+            if let Err(BackupError::CommandFailed(name, _)) = res {
+                errors.push((fi.copy_string().to_string(), name));
+            }
+            Ok(())
+        },
+        |_| (),
+        || (),
+    )?;
+    assert_eq!(errors, vec![
+        ("_commands/fail".to_string(), "fail".to_string()),
+        ("_commands/slow".to_string(), "slow".to_string())
+    ]);
+    assert_eq!(bw.failed_commands(), ["fail", "slow"]);
+    let mut reader = BackupReader::new(bw.path.clone());
+    let list = reader.get_list()?;
+    assert_eq!(list.iter_generated().collect::<Vec<_>>(), vec![
+        "_commands/hello"
+    ]);
+    assert_eq!(list.iter_included().count(), 2);
+    // No spooled outputs are left behind
+    assert_eq!(std::fs::read_dir(dir2.path())?.count(), 1);
+
+    // The commands are always run (even if no files have changed)
+    std::thread::sleep(Duration::from_millis(20));
+    let mut config = config.clone();
+    config.commands.truncate(1);
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-02.tar.zst");
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let mut reader = BackupReader::new(bw.path.clone());
+    assert_eq!(
+        reader.get_list()?.iter_included().collect::<Vec<_>>(),
+        vec!["_commands/hello"]
+    );
+    restore_with(reader, &RestoreOptions {
+        output: Some(dir3.path()),
+        quiet: true,
+        ..Default::default()
+    })?;
+    let restored = std::fs::read_to_string(dir3.path().join("_commands").join("hello"))?;
+    assert_eq!(restored.trim(), "hello");
+
+    // Failing commands fail the backup in strict mode
+    config
+        .commands
+        .push(BackupCommand::new("fail", "exit 3", 0));
+    let mut bw = BackupWriter::new(config).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-03.tar.zst");
+    bw.strict = true;
+    assert!(matches!(
+        bw.write(|_, _| Ok(()), |_| (), || ()),
+        Err(BackupError::CommandFailed(_, _))
+    ));
+    assert!(!bw.path.exists());
+    assert_eq!(std::fs::read_dir(dir2.path())?.count(), 2);
+    Ok(())
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn user_meta_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    let a = dir.path().join("a.txt");
+    let b = dir.path().join("x,y.txt");
+    File::create(&a)?;
+    File::create(&b)?;
+    let cmd = "while IFS= read -r p; do case \"$p\" in \
+        *a.txt) printf '%s\\towner=anna;note=a,b%%c\\n' \"$p\";; \
+        *) printf '%s\\towner=bob\\n' \"$p\";; esac; done";
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .quality(1)
+        .threads(1)
+        .metadata_command(Some(cmd))
+        .build();
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-01.tar.zst");
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let mut reader = BackupReader::new(bw.path.clone());
+    let mut meta: Vec<(String, String)> = reader
+        .get_list()?
+        .iter_user_meta()
+        .map(|(p, m)| (p.to_string(), m.to_string()))
+        .collect();
+    meta.sort();
+    assert_eq!(meta, vec![
+        (
+            a.to_string_lossy().to_string(),
+            "owner=anna;note=a,b%c".to_string()
+        ),
+        (b.to_string_lossy().to_string(), "owner=bob".to_string()),
+    ]);
+    let query = Query::parse("meta.owner == 'anna'")?;
+    let mask = query.filter_list(&mut reader)?;
+    let list = reader.get_list()?;
+    let matching: Vec<_> = list.iter().zip(mask).filter(|(_, m)| *m).collect();
+    assert_eq!(matching.len(), 1);
+    assert_eq!(matching[0].0 .1, a.to_string_lossy());
+
+    // The unchanged files of an incremental backup also get metadata
+    std::thread::sleep(Duration::from_millis(20));
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-02.tar.zst");
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let mut reader = BackupReader::new(bw.path.clone());
+    assert_eq!(reader.get_list()?.iter_included().count(), 0);
+    assert_eq!(reader.get_list()?.iter_user_meta().count(), 2);
+
+    restore_with(reader, &RestoreOptions {
+        output: Some(dir3.path()),
+        metadata_sidecar: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    let sidecar = std::fs::read_to_string(dir3.path().join(METADATA_SIDECAR))?;
+    assert_eq!(
+        sidecar,
+        format!(
+            "{}\towner=anna;note=a,b%c\n{}\towner=bob\n",
+            strip_absolute_from_path(&a.to_string_lossy()),
+            strip_absolute_from_path(&b.to_string_lossy())
+        )
+    );
+
+    // A failing command only leaves out the metadata
+    let mut config = config;
+    config.metadata_command = Some("cat > /dev/null; exit 3".to_string());
+    let mut bw = BackupWriter::new(config).0;
+    bw.path = dir2.path().join("backup_2020-01-01_00-00-03.tar.zst");
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let mut reader = BackupReader::new(bw.path.clone());
+    assert_eq!(reader.get_list()?.filename(), "files_v3.csv");
+    assert_eq!(reader.get_list()?.iter_user_meta().count(), 0);
+    Ok(())
+}
+
+#[test]
+fn output_unavailable_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let docs = dir.path().join("docs");
+    let mount = dir.path().join("mount");
+    let output = mount.join("backups");
+    create_dir(&docs)?;
+    File::create(docs.join("a.txt"))?;
+    let config = ConfigBuilder::new()
+        .include([docs.to_string_lossy()])
+        .output(&output)
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .require_output(true, None::<PathBuf>)
+        .build();
+    let options = BackupOptions {
+        quiet: true,
+        ..Default::default()
+    };
+
+    // Nothing is created when the output is missing
+    match backup_with(config.clone(), &options) {
+        Err(BackupError::OutputUnavailable(p)) => assert_eq!(p, output),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    let mut bw = BackupWriter::new(config.clone()).0;
+    assert!(matches!(
+        bw.write(|_, _| Ok(()), |_| (), || ()),
+        Err(BackupError::OutputUnavailable(_))
+    ));
+    assert!(!mount.exists());
+    let waiting = BackupOptions {
+        wait_for_output: Some(Duration::from_millis(300)),
+        ..options.clone()
+    };
+    assert!(matches!(
+        backup_with(config.clone(), &waiting),
+        Err(BackupError::OutputUnavailable(_))
+    ));
+
+    // The marker file must also exist
+    create_dir(&mount)?;
+    create_dir(&output)?;
+    let mut config = config;
+    config.marker_file = Some(PathBuf::from(".backup_drive"));
+    match backup_with(config.clone(), &options) {
+        Err(BackupError::OutputUnavailable(p)) => assert_eq!(p, output.join(".backup_drive")),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+    File::create(output.join(".backup_drive"))?;
+    backup_with(config, &waiting)?;
+    assert_eq!(BackupIterator::dir(&output).count(), 1);
+    Ok(())
+}
+
+#[test]
+fn restore_manifest_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let docs = dir.path().join("docs");
+    let output = dir.path().join("backups");
+    let restored = dir.path().join("restored");
+    create_dir(&docs)?;
+    File::create(docs.join("a.txt"))?.write_all(b"a")?;
+    std::thread::sleep(Duration::from_millis(1100));
+    let config = ConfigBuilder::new()
+        .include([docs.to_string_lossy()])
+        .output(&output)
+        .incremental(true)
+        .quality(1)
+        .threads(1)
+        .build();
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let first = bw.path.clone();
+    std::thread::sleep(Duration::from_millis(1100));
+    File::create(docs.join("b.txt"))?.write_all(b"b")?;
+    let mut bw = BackupWriter::new(config).0;
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let second = bw.path.clone();
+
+    let options = RestoreOptions {
+        output: Some(&restored),
+        flatten: true,
+        quiet: true,
+        record: Some(&restored),
+        ..Default::default()
+    };
+    restore_with(BackupReader::new(second.clone()), &options)?;
+    let manifest = manifest_path(&restored);
+    assert_eq!(manifest, restored.join(MANIFEST_FILE_NAME));
+    let records = read_manifest(&manifest)?;
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert!(!record.partial);
+    assert_eq!(record.backup, second.to_string_lossy());
+    assert!(record.parameters.flatten);
+    assert_eq!(record.count(RestoreOutcome::Restored), 2);
+    for f in record.files.iter() {
+        let expected = if f.entry.ends_with("a.txt") {
+            &first
+        } else {
+            &second
+        };
+        assert_eq!(f.source, expected.to_string_lossy());
+        assert!(f.mtime.is_some());
+        assert!(Path::new(&f.path).exists());
+    }
+
+    // The existing files are skipped (and the record is appended)
+    restore_with(BackupReader::new(second.clone()), &options)?;
+    let records = read_manifest(&manifest)?;
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1].count(RestoreOutcome::Skipped), 2);
+    assert!(records[1].files.iter().all(|f| f.error.is_some()));
+
+    // Dry runs are not recorded
+    let dry = RestoreOptions {
+        dry: true,
+        ..options.clone()
+    };
+    restore_with(BackupReader::new(second), &dry)?;
+    assert_eq!(read_manifest(&manifest)?.len(), 2);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn moved_output_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let docs = dir.path().join("docs");
+    let disk1 = dir.path().join("disk1");
+    let disk2 = dir.path().join("disk2");
+    let moved = dir.path().join("moved");
+    let link = dir.path().join("backups");
+    for d in [&docs, &disk1, &disk2, &moved] {
+        create_dir(d)?;
+    }
+    File::create(docs.join("a.txt"))?;
+    std::os::unix::fs::symlink(&disk1, &link)?;
+    let config = ConfigBuilder::new()
+        .include([docs.to_string_lossy()])
+        .output(&link)
+        .incremental(true)
+        .quality(1)
+        .threads(1)
+        .build();
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let first = bw.path.clone();
+    let embedded = BackupReader::read_config_only(first.clone())?;
+    assert_eq!(embedded.output, link);
+    assert_eq!(embedded.resolved_output, Some(disk1.canonicalize()?));
+    std::thread::sleep(Duration::from_millis(1100));
+    File::create(docs.join("b.txt"))?;
+    let (mut bw, error) = BackupWriter::new(config.clone());
+    assert!(error.is_none());
+    assert!(bw.prev_time.is_some());
+    bw.path = link.join("backup_2999-01-01_00-00-00.tar.zst");
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    let second = moved.join(bw.path.file_name().unwrap());
+    std::fs::copy(&bw.path, &second)?;
+
+    // Repoint the symlink to another disk (with a copy of the first backup)
+    std::fs::copy(&first, disk2.join(first.file_name().unwrap()))?;
+    remove_file(&link)?;
+    std::os::unix::fs::symlink(&disk2, &link)?;
+    let (bw, error) = BackupWriter::new(config.clone());
+    match error {
+        Some(BackupError::OutputMoved(prev, current)) => {
+            assert_eq!(prev, disk1.canonicalize()?);
+            assert_eq!(current, disk2.canonicalize()?);
+        }
+        e => panic!("Unexpected error: {:?}", e),
+    }
+    assert!(bw.prev_time.is_none());
+    let mut config = config;
+    config.chain_across_moves = true;
+    let (bw, error) = BackupWriter::new(config);
+    assert!(error.is_none());
+    assert!(bw.prev_time.is_some());
+
+    // Restoring follows the origin to where the backup was written (not where the link points)
+    let mut reader = BackupReader::new(second).with_follow_origin(true);
+    let previous = reader.get_previous()?.unwrap();
+    assert_eq!(
+        previous.path.clone_path().parent(),
+        Some(disk1.canonicalize()?.as_path())
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(not(target_os = "windows"))]
+fn control_chars_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    let a = dir.path().join("line\nbreak.txt");
+    let b = dir.path().join("tab\tand%0A.txt");
+    let c = dir.path().join("plain.txt");
+    std::fs::write(&a, "a")?;
+    std::fs::write(&b, "b")?;
+    std::fs::write(&c, "c")?;
+    let mut config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .quality(1)
+        .threads(1)
+        .build();
+    let b1 = dir2.path().join("backup_2020-01-01_00-00-01.tar.zst");
+    let mut bw = BackupWriter::new(config.clone()).0;
+    bw.path = b1.clone();
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+    config.time = Some(naive_now());
+    std::thread::sleep(Duration::from_millis(20));
+    std::fs::write(&a, "changed")?;
+    let b2 = dir2.path().join("backup_2020-01-01_00-00-02.tar.zst");
+    let mut bw = BackupWriter::new(config).0;
+    bw.path = b2.clone();
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+
+    let mut reader = BackupReader::new(b2.clone());
+    let list = reader.get_list()?;
+    assert_eq!(list.filename(), "files_v5.csv");
+    let mut files: Vec<_> = list.iter().collect();
+    files.sort();
+    assert_eq!(files, [
+        (false, c.to_str().unwrap()),
+        (false, b.to_str().unwrap()),
+        (true, a.to_str().unwrap()),
+    ]);
+    let export = dir3.path().join("list.csv");
+    reader.export_list(&export)?;
+    let export = std::fs::read_to_string(export)?;
+    assert_eq!(export.lines().count(), 3);
+    assert!(export.contains("line%0Abreak.txt"));
+    assert!(export.contains("tab%09and%250A.txt"));
+
+    merge(vec![b1, b2.clone()], &MergeOptions {
+        all: true,
+        delete: true,
+        quality: Some(1),
+        force: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    let mut reader = BackupReader::new(b2);
+    assert_eq!(reader.get_list()?.iter_included().count(), 3);
+    let query = Query::parse("path ~ '\\n'")?;
+    let mask = query.filter_list(&mut reader)?;
+    assert_eq!(mask.iter().filter(|m| **m).count(), 1);
+
+    let out = dir3.path().join("out");
+    restore_with(reader, &RestoreOptions {
+        output: Some(&out),
+        quiet: true,
+        ..Default::default()
+    })?;
+    let restored = |p: &Path| {
+        std::fs::read_to_string(out.join(strip_absolute_from_path(&p.to_string_lossy())))
+    };
+    assert_eq!(restored(&a)?, "changed");
+    assert_eq!(restored(&b)?, "b");
+    assert_eq!(restored(&c)?, "c");
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn non_utf8_test() -> Result<(), Box<dyn std::error::Error>> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    // Two names that would be the same after a lossy conversion, and a path over 300 bytes
+    let a = dir.path().join(OsStr::from_bytes(b"bad\xffname.txt"));
+    let b = dir.path().join(OsStr::from_bytes(b"bad\xfename.txt"));
+    let long = dir.path().join("d".repeat(100)).join("e".repeat(100));
+    create_dir(long.parent().unwrap())?;
+    create_dir(&long)?;
+    let c = long.join(OsStr::from_bytes(b"f\xe9".repeat(60).as_slice()));
+    // A valid name with the character that stands for the byte 0x80 (see `path_to_string`)
+    let d = dir.path().join("real\u{ef80}name.txt");
+    std::fs::write(&d, "d")?;
+    std::fs::write(&a, "a")?;
+    std::fs::write(&b, "b")?;
+    std::fs::write(&c, "c")?;
+    assert!(c.as_os_str().len() > 300);
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .build();
+    let b1 = dir2.path().join("backup_2020-01-01_00-00-01.tar.zst");
+    let mut bw = BackupWriter::new(config).0;
+    bw.path = b1.clone();
+    bw.write(|_, _| Ok(()), |_| (), || ())?;
+
+    let mut reader = BackupReader::new(b1.clone());
+    let list = reader.get_list()?;
+    assert_eq!(list.filename(), "files_v5.csv");
+    let mut files: Vec<String> = list.iter_included().map(String::from).collect();
+    files.sort();
+    let mut expected = [&a, &b, &c, &d].map(|p| path_to_string(p).into_owned());
+    expected.sort();
+    assert_eq!(files, expected);
+    let export = dir3.path().join("list.csv");
+    reader.export_list(&export)?;
+    let export = std::fs::read_to_string(export)?;
+    assert!(export.contains("bad%FFname.txt"));
+    assert!(export.contains("bad%FEname.txt"));
+    // The archive has the original bytes
+    let mut decoder = CompressionDecoder::read(&b1, 1)?;
+    let names: Vec<PathBuf> = decoder
+        .entries()?
+        .map(|e| e.map(|(mut fi, _)| fi.get_path().clone()))
+        .collect::<std::io::Result<_>>()?;
+    assert!(names.contains(&a));
+    assert!(names.contains(&c));
+
+    let out = dir3.path().join("out");
+    let restored = |p: &Path| out.join(p.strip_prefix("/").unwrap());
+    restore_with(BackupReader::new(b1.clone()), &RestoreOptions {
+        output: Some(&out),
+        include: vec![path_to_string(&a).into_owned()],
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert_eq!(std::fs::read_to_string(restored(&a))?, "a");
+    assert!(!restored(&b).exists());
+    restore_with(BackupReader::new(b1), &RestoreOptions {
+        output: Some(&out),
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert_eq!(std::fs::read_to_string(restored(&b))?, "b");
+    assert_eq!(std::fs::read_to_string(restored(&c))?, "c");
+    assert_eq!(std::fs::read_to_string(restored(&d))?, "d");
+    Ok(())
+}
+
+#[test]
+fn output_inside_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let output = dir.path().join("backups");
+    File::create(dir.path().join("a.txt"))?;
+    let mut config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(&output)
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .build();
+    let backup = |config: Config, name: &str| -> Result<Vec<String>, BackupError> {
+        let mut bw = BackupWriter::new(config).0;
+        bw.path = output.join(name);
+        bw.write(|_, _| Ok(()), |_| (), || ())?;
+        let mut reader = BackupReader::new(bw.path);
+        Ok(reader
+            .get_list()?
+            .iter_included()
+            .map(String::from)
+            .collect())
+    };
+
+    // The previous backups are left out
+    backup(config.clone(), "backup_2020-01-01_00-00-01.tar.zst")?;
+    let files = backup(config.clone(), "backup_2020-01-01_00-00-02.tar.zst")?;
+    assert_eq!(files.len(), 1);
+    assert!(!files.iter().any(|f| f.ends_with(".tar.zst")));
+
+    // Unless explicitly allowed
+    config.allow_output_inside = true;
+    let files = backup(config, "backup_2020-01-01_00-00-03.tar.zst")?;
+    assert_eq!(files.iter().filter(|f| f.ends_with(".tar.zst")).count(), 2);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn run_as_test() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+    use std::process::Command;
+
+    use simple_backup::privileges::{OsPrivileges, Privileges};
+
+    // Only root can switch to another user (the switch is done in a separate process)
+    let nobody = match OsPrivileges.lookup("nobody") {
+        Some(user) if OsPrivileges.euid() == 0 => user,
+        _ => return Ok(()),
+    };
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755))?;
+    let public = dir.path().join("public");
+    let secret = dir.path().join("secret");
+    create_dir(&public)?;
+    create_dir(&secret)?;
+    File::create(public.join("a.txt"))?.write_all(b"a")?;
+    File::create(secret.join("b.txt"))?.write_all(b"b")?;
+    std::fs::set_permissions(&public, std::fs::Permissions::from_mode(0o755))?;
+    std::fs::set_permissions(public.join("a.txt"), std::fs::Permissions::from_mode(0o644))?;
+    std::fs::set_permissions(&secret, std::fs::Permissions::from_mode(0o700))?;
+    chown(dir2.path(), Some(nobody.uid), Some(nobody.gid))?;
+    let path = |p: &PathBuf| p.to_string_lossy().to_string();
+    let config_path = dir.path().join("config.yml");
+    let mut config = ConfigBuilder::new()
+        .include([path(&public), path(&secret)])
+        .output(dir2.path())
+        .incremental(false)
+        .quality(1)
+        .threads(1)
+        .build();
+    config.write_yaml(&config_path, false)?;
+    let run = |user: &str| {
+        Command::new(env!("CARGO_BIN_EXE_simple_backup"))
+            .arg("backup")
+            .arg(&config_path)
+            .args(["--run-as", user])
+            .output()
+    };
+
+    // The unreadable include is reported, and the backup is owned by the user
+    let out = run("nobody")?;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(out.status.success(), "{}", stderr);
+    assert!(stderr.contains(&format!(
+        "'{}' cannot be read by the user 'nobody'",
+        path(&secret)
+    )));
+    let backup = dir2.path().read_dir()?.next().unwrap()?.path();
+    assert_eq!(backup.metadata()?.uid(), nobody.uid);
+    let mut reader = BackupReader::new(backup);
+    let files = reader
+        .get_list()?
+        .iter_included()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    assert_eq!(files, [path(&public.join("a.txt"))]);
+
+    // Refused
+    assert!(!run("root")?.status.success());
+    config.required = vec![path(&secret)];
+    config.write_yaml(&config_path, false)?;
+    let out = run("nobody")?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("The required path"));
+    config.required.clear();
+    config.write_yaml(&config_path, false)?;
+    chown(dir2.path(), Some(0), Some(0))?;
+    let out = run("nobody")?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cannot write to the output"));
+    Ok(())
+}
+
+#[test]
+fn legacy_test() -> Result<(), Box<dyn std::error::Error>> {
+    // Hand-built archives in the layout of the oldest versions: the configs use the old field
+    // names, the full backup has an unsorted `files.csv`, and the incremental backup stores the
+    // list before `config.yml`
+    let dir = tempdir()?;
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures");
+    let b1 = dir.path().join("backup_2021-03-01_10-00-00.tar.zst");
+    let b2 = dir.path().join("backup_2021-03-08_10-00-00.tar.zst");
+    let b3 = dir.path().join("backup_2021-03-09_10-00-00.tar.zst");
+    std::fs::copy(fixtures.join(b1.file_name().unwrap()), &b1)?;
+    std::fs::copy(fixtures.join(b2.file_name().unwrap()), &b2)?;
+
+    let mut reader = BackupReader::new(b2.clone());
+    let config = reader.get_config()?;
+    assert_eq!(config.include, vec!["/home/user/notes"]);
+    assert_eq!(config.quality, 21);
+    assert!(config.incremental);
+    assert!(config.migrations.iter().any(|m| m.contains("verbose")));
+    assert!(reader.is_legacy());
+    assert_eq!(
+        reader.get_list()?.iter_included().collect::<Vec<_>>(),
+        vec!["/home/user/notes/b.txt"]
+    );
+    assert_eq!(reader.get_previous()?.unwrap().path.get_path(), &b1);
+    assert!(BackupInfo::new(&mut BackupReader::new(b1.clone()))?.legacy);
+    assert!(BackupInfo::new(&mut reader)?.legacy);
+    assert_eq!(
+        *get_backup_from_path(dir.path().to_path_buf())?
+            .path
+            .get_path(),
+        b2
+    );
+
+    let out = dir.path().join("restored");
+    let restored = out.join("home").join("user").join("notes");
+    restore_with(reader, &RestoreOptions {
+        output: Some(&out),
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert_eq!(
+        std::fs::read_to_string(restored.join("a.txt"))?,
+        "first a\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(restored.join("b.txt"))?,
+        "second b\n"
+    );
+
+    merge(vec![b1, b2], &MergeOptions {
+        output: Some(&b3),
+        threads: Some(1),
+        quiet: true,
+        ..Default::default()
+    })?;
+    std::fs::remove_dir_all(&out)?;
+    let mut reader = BackupReader::new(b3);
+    assert!(!reader.is_legacy());
+    assert_eq!(reader.get_list()?.iter_included().count(), 2);
+    restore_with(reader, &RestoreOptions {
+        output: Some(&out),
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert_eq!(
+        std::fs::read_to_string(restored.join("a.txt"))?,
+        "first a\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(restored.join("b.txt"))?,
+        "second b\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn restore_dir_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    let photos = dir.path().join("photos");
+    create_dir(&photos)?;
+    create_dir(dir.path().join("photos2"))?;
+    File::create(photos.join("a.jpg"))?.write_all(b"a")?;
+    File::create(photos.join("b.jpg"))?.write_all(b"b")?;
+    File::create(dir.path().join("photos2").join("c.jpg"))?;
+    File::create(dir.path().join("notes.txt"))?;
+    File::create(dir.path().join("other.txt"))?;
+
+    let mut config = ConfigBuilder::new()
+        .include(vec![dir.path().to_string_lossy().to_string()])
+        .output(dir2.path())
+        .incremental(true)
+        .quality(1)
+        .threads(1)
+        .build();
+    let options = BackupOptions {
+        quiet: true,
+        ..Default::default()
+    };
+    backup_with(config.clone(), &options)?;
+    let b1 = dir2.path().join("backup_2020-01-01_00-00-00.tar.zst");
+    rename(config.get_backups().get_latest().unwrap(), &b1)?;
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    config.time = Some(naive_now());
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    File::create(photos.join("b.jpg"))?.write_all(b"new b")?;
+    backup_with(config.clone(), &options)?;
+    let b2 = config.get_backups().get_latest().unwrap();
+
+    // A directory (with files from both backups) and a single file, `photos` is not a prefix of
+    // `photos2`
+    let prefix = dir.path().to_string_lossy().to_string();
+    restore_with(BackupReader::new(b2.clone()), &RestoreOptions {
+        output: Some(dir3.path()),
+        strip_prefix: Some(&prefix),
+        include: vec![
+            photos.to_string_lossy().to_string(),
+            dir.path().join("notes.txt").to_string_lossy().to_string(),
+        ],
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert_eq!(
+        std::fs::read(dir3.path().join("photos").join("a.jpg"))?,
+        b"a"
+    );
+    assert_eq!(
+        std::fs::read(dir3.path().join("photos").join("b.jpg"))?,
+        b"new b"
+    );
+    assert!(dir3.path().join("notes.txt").exists());
+    assert!(!dir3.path().join("photos2").exists());
+    assert!(!dir3.path().join("other.txt").exists());
+
+    // Only the files stored in the latest backup
+    let mut reader = BackupReader::new(b2);
+    let selection = [photos.to_string_lossy().to_string()];
+    let expanded = reader.expand_selection(&selection, true)?.unwrap();
+    assert_eq!(expanded, [photos.join("b.jpg").to_string_lossy()]);
+    let expanded = reader.expand_selection(&selection, false)?.unwrap();
+    assert_eq!(expanded.len(), 2);
+    let missing = [dir.path().join("photo").to_string_lossy().to_string()];
+    assert_eq!(reader.expand_selection(&missing, false)?.unwrap(), missing);
+    let file = [dir.path().join("notes.txt").to_string_lossy().to_string()];
+    assert!(reader.expand_selection(&file, false)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn delta_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    const BLOCK: usize = 1 << 20;
+    // Pseudo-random content (so that the blocks differ and do not compress away)
+    let mut state = 0x9e3779b97f4a7c15u64;
+    let mut random = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    };
+    let disk = dir.path().join("disk.img");
+    let small = dir.path().join("small.img");
+    let mut content = random(BLOCK * 4 + 100);
+    std::fs::write(&disk, &content)?;
+    std::fs::write(&small, random(100))?;
+
+    let mut config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .incremental(true)
+        .delta_files([r"\.img$"], BLOCK as u64)
+        .quality(1)
+        .threads(1)
+        .build();
+    let options = BackupOptions {
+        quiet: true,
+        ..Default::default()
+    };
+    let backup = |config: &mut Config, name: &str| -> Result<PathBuf, BackupError> {
+        backup_with(config.clone(), &options)?;
+        let path = dir2.path().join(name);
+        rename(config.get_backups().get_latest().unwrap(), &path)?;
+        std::thread::sleep(Duration::from_millis(20));
+        config.time = Some(naive_now());
+        std::thread::sleep(Duration::from_millis(20));
+        Ok(path)
+    };
+    let stored_size = |backup: &PathBuf| -> Result<u64, BackupError> {
+        let headers = BackupReader::new(backup.clone()).read_headers()?;
+        Ok(headers[disk.to_string_lossy().as_ref()].1)
+    };
+    let restore = |backup: &PathBuf| -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let out = tempdir()?;
+        restore_with(BackupReader::new(backup.clone()), &RestoreOptions {
+            output: Some(out.path()),
+            quiet: true,
+            ..Default::default()
+        })?;
+        let restored = out
+            .path()
+            .join(strip_absolute_from_path(&disk.to_string_lossy()));
+        Ok(std::fs::read(restored)?)
+    };
+    let b1 = backup(&mut config, "backup_2020-01-01_00-00-00.tar.zst")?;
+    assert_eq!(stored_size(&b1)?, content.len() as u64);
+    let signatures = BackupReader::new(b1.clone()).read_signatures()?;
+    assert!(signatures.get(&disk.to_string_lossy()).is_some());
+    assert!(signatures.get(&small.to_string_lossy()).is_none());
+
+    // Changes at the block boundaries (the first and the last byte of blocks)
+    let v1 = content.clone();
+    content[BLOCK - 1] ^= 0xff;
+    content[BLOCK * 4] ^= 0xff;
+    std::fs::write(&disk, &content)?;
+    let b2 = backup(&mut config, "backup_2020-01-02_00-00-00.tar.zst")?;
+    assert_eq!(stored_size(&b2)?, BLOCK as u64 + 100);
+
+    // Growing the file adds blocks
+    let v2 = content.clone();
+    content[BLOCK * 2] ^= 0xff;
+    content.extend(random(BLOCK / 2));
+    std::fs::write(&disk, &content)?;
+    let b3 = backup(&mut config, "backup_2020-01-03_00-00-00.tar.zst")?;
+    assert_eq!(stored_size(&b3)?, (BLOCK + BLOCK / 2 + 100) as u64);
+    assert_eq!(restore(&b1)?, v1);
+    assert_eq!(restore(&b2)?, v2);
+    assert_eq!(restore(&b3)?, content);
+
+    // Shrinking the file (and changing most of the blocks) stores the whole file
+    let v3 = content.clone();
+    content.truncate(BLOCK * 3);
+    content[..BLOCK * 2].copy_from_slice(&random(BLOCK * 2));
+    std::fs::write(&disk, &content)?;
+    let b4 = backup(&mut config, "backup_2020-01-04_00-00-00.tar.zst")?;
+    assert_eq!(stored_size(&b4)?, content.len() as u64);
+    assert_eq!(restore(&b4)?, content);
+
+    // The merged backup has the whole files
+    let readers = vec![BackupReader::new(b2.clone()), BackupReader::new(b3.clone())];
+    let mut merger = BackupMerger::new(None, readers, true, true, false, Some(1), None, false)
+        .map_err(|(_, e)| e)?;
+    merger.write(|_, res| res, |_| (), || ())?;
+    assert!(!b2.exists());
+    assert_eq!(stored_size(&b3)?, v3.len() as u64);
+    assert_eq!(restore(&b3)?, v3);
+    assert_eq!(restore(&b4)?, content);
+    let signatures = BackupReader::new(b3.clone()).read_signatures()?;
+    assert!(signatures.get(&disk.to_string_lossy()).is_some());
+
+    // The next backup stores the changed blocks again (based on the signatures in the merged backup)
+    content[0] ^= 0xff;
+    std::fs::write(&disk, &content)?;
+    let b5 = backup(&mut config, "backup_2020-01-05_00-00-00.tar.zst")?;
+    assert_eq!(stored_size(&b5)?, BLOCK as u64);
+    assert_eq!(restore(&b5)?, content);
+
+    // A missing or different previous version is an error (instead of a corrupt file)
+    let count_errors = |backup: &PathBuf| -> Result<usize, BackupError> {
+        let out = tempdir()?;
+        let mut errors = 0;
+        let mut reader = BackupReader::new(backup.clone());
+        let files = listed_files(&mut reader)?;
+        reader.restore_with(
+            files,
+            |mut fi| FileInfo::from(out.path().join(strip_absolute_from_path(fi.get_string()))),
+            |res| {
+                errors += res.is_err() as usize;
+                Ok(())
+            },
+            |_| (),
+            &RestoreOptions {
+                overwrite: Overwrite::Always,
+                ..Default::default()
+            },
+        )?;
+        Ok(errors)
+    };
+    assert_eq!(count_errors(&b5)?, 0);
+    rename(&b4, extend_pathbuf(b4.clone(), ".bak"))?;
+    assert_eq!(count_errors(&b5)?, 1);
+    for b in [&b1, &b3] {
+        rename(b, extend_pathbuf(b.to_path_buf(), ".bak"))?;
+    }
+    // The unchanged file is also missing
+    assert_eq!(count_errors(&b5)?, 2);
+    Ok(())
+}
+
+#[test]
+fn browse_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let dir3 = tempdir()?;
+    create_dir(dir.path().join("sub"))?;
+    std::fs::write(dir.path().join("a.txt"), "first")?;
+    std::fs::write(dir.path().join("sub").join("b.txt"), "unchanged")?;
+
+    let mut config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .incremental(true)
+        .build();
+    let options = BackupOptions {
+        quiet: true,
+        ..Default::default()
+    };
+    backup_with(config.clone(), &options)?;
+    let first = dir2.path().join("backup_2020-01-01_00-00-00.tar.zst");
+    rename(config.get_backups().get_latest().unwrap(), &first)?;
+    std::thread::sleep(Duration::from_millis(20));
+    config.time = Some(naive_now());
+    std::thread::sleep(Duration::from_millis(20));
+    std::fs::write(dir.path().join("a.txt"), "second")?;
+    backup_with(config.clone(), &options)?;
+    let latest = config.get_backups().get_latest().unwrap();
+
+    let mut browser = Browser::new(BackupReader::new(latest))?;
+    let root = dir.path().to_string_lossy().replace('\\', "/");
+    assert_eq!(browser.cwd(), root);
+    assert_eq!(browser.num_files(), 2);
+    let mut run = |args: &[&str]| -> Result<String, BackupError> {
+        let mut out = vec![];
+        browser.run(args, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    };
+    let ls = run(&["ls"])?;
+    let lines: Vec<&str> = ls.lines().map(|l| l.trim()).collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with("a.txt"));
+    assert_eq!(lines[1], "sub/");
+    assert!(run(&["ls", "missing"]).is_err());
+    assert!(run(&["cd", "a.txt"]).is_err());
+    run(&["cd", "sub"])?;
+    assert_eq!(run(&["pwd"])?.trim(), format!("{}/sub", root));
+    // The unchanged file is read from the previous backup
+    assert_eq!(run(&["cat", "b.txt"])?, "unchanged");
+    assert_eq!(run(&["cat", "../a.txt"])?, "second");
+    assert_eq!(run(&["find", r"\.txt$"])?.lines().count(), 1);
+    run(&["cd", ".."])?;
+    assert_eq!(run(&["find", r"\.txt$"])?.lines().count(), 2);
+    run(&["get", "sub/b.txt", &dir3.path().to_string_lossy()])?;
+    assert_eq!(
+        std::fs::read_to_string(dir3.path().join("b.txt"))?,
+        "unchanged"
+    );
+    // Existing files are not overwritten
+    assert!(run(&["get", "a.txt", &dir3.path().join("b.txt").to_string_lossy()]).is_err());
+    assert!(run(&["unknown"]).is_err());
+    assert!(!browser.run(&["quit"], &mut std::io::sink())?);
+
+    // Without the previous backup the unchanged file cannot be read
+    remove_file(&first)?;
+    let mut browser = Browser::new(get_backup_from_path(dir2.path().to_path_buf())?)?;
+    assert!(browser
+        .run(&["cat", "sub/b.txt"], &mut std::io::sink())
+        .is_err());
+    let mut out = vec![];
+    browser.run(&["cat", "a.txt"], &mut out)?;
+    assert_eq!(out, b"second");
+    Ok(())
+}
+
+#[test]
+fn read_ahead_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    for i in 0..30 {
+        // Some files are larger than the limit, and some are duplicates
+        let content = vec![(i % 7) as u8; (i % 7) * 300_000];
+        std::fs::write(dir.path().join(format!("{:02}.bin", i)), content)?;
+    }
+    let write = |read_ahead: u64| -> Result<(Vec<String>, PathBuf), BackupError> {
+        let config = ConfigBuilder::new()
+            .include([dir.path().to_string_lossy()])
+            .output(dir2.path())
+            .incremental(false)
+            .dedup(true)
+            .read_ahead_mb(read_ahead)
+            .threads(3)
+            .build();
+        let mut bw = BackupWriter::new(config).0;
+        bw.path = dir2.path().join(format!("backup_{}.tar.zst", read_ahead));
+        let mut added = vec![];
+        bw.write(
+            |fi, res| {
+                res?;
+                added.push(fi.get_string().to_string());
+                Ok(())
+            },
+            |_| (),
+            || (),
+        )?;
+        Ok((added, bw.path))
+    };
+    let (direct, b1) = write(0)?;
+    let (ahead, b2) = write(1)?;
+    assert_eq!(direct.len(), 30);
+    assert_eq!(direct, ahead);
+    assert_eq!(
+        BackupReader::new(b1).read_headers()?,
+        BackupReader::new(b2.clone()).read_headers()?
+    );
+    let out = tempdir()?;
+    restore_with(BackupReader::new(b2), &RestoreOptions {
+        output: Some(out.path()),
+        quiet: true,
+        ..Default::default()
+    })?;
+    for i in 0..30 {
+        let name = dir.path().join(format!("{:02}.bin", i));
+        let restored = out
+            .path()
+            .join(strip_absolute_from_path(&name.to_string_lossy()));
+        assert_eq!(std::fs::read(restored)?, std::fs::read(name)?);
+    }
+
+    // Cancelling stops the reading
+    let config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .incremental(false)
+        .threads(2)
+        .build();
+    let mut bw = BackupWriter::new(config).0;
+    bw.path = dir2.path().join("cancelled.tar.zst");
+    let res = bw.write(|_, _| Err(BackupError::Cancel), |_| (), || ());
+    assert!(matches!(res, Err(BackupError::Cancel)));
+    assert!(!bw.path.exists());
+    Ok(())
+}
+
+#[test]
+fn mirror_test() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempdir()?;
+    let dir2 = tempdir()?;
+    let sub = dir.path().join("sub");
+    create_dir(&sub)?;
+    let files = ["a.txt", "b.txt", "sub/c.txt", "d.txt"].map(|f| dir.path().join(f));
+    for f in files.iter() {
+        std::fs::write(f, "content")?;
+    }
+    let mut config = ConfigBuilder::new()
+        .include([dir.path().to_string_lossy()])
+        .output(dir2.path())
+        .incremental(true)
+        .build();
+    let options = BackupOptions {
+        quiet: true,
+        ..Default::default()
+    };
+    let backup = |config: &mut Config, name: &str| -> Result<PathBuf, BackupError> {
+        backup_with(config.clone(), &options)?;
+        let path = dir2.path().join(name);
+        rename(config.get_backups().get_latest().unwrap(), &path)?;
+        std::thread::sleep(Duration::from_millis(20));
+        config.time = Some(naive_now());
+        std::thread::sleep(Duration::from_millis(20));
+        Ok(path)
+    };
+    let b1 = backup(&mut config, "backup_2020-01-01_00-00-00.tar.zst")?;
+    remove_file(&files[1])?;
+    remove_file(&files[2])?;
+    let expected = [&files[1], &files[2]].map(|f| f.to_string_lossy().to_string());
+    assert_eq!(BackupReader::new(b1.clone()).find_missing()?, expected);
+    check(BackupReader::new(b1.clone()), true)?;
+    let run_check = |b: &PathBuf| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_simple_backup"))
+            .arg("check")
+            .arg(b)
+            .output()
+    };
+    // The missing files are listed on stdout (and counted on stderr)
+    let out = run_check(&b1)?;
+    assert!(out.status.success());
+    let listed: Vec<String> = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(String::from)
+        .collect();
+    assert_eq!(listed, expected);
+    assert!(String::from_utf8_lossy(&out.stderr).starts_with("2 of 4 files in"));
+    // Deleted files are recorded, but not files that are only excluded
+    config.exclude.push(files[3].to_string_lossy().to_string());
+    let b2 = backup(&mut config, "backup_2020-01-02_00-00-00.tar.zst")?;
+    let deleted = |b: &PathBuf, chain: bool| BackupReader::new(b.clone()).get_deleted(chain);
+    assert_eq!(deleted(&b2, true)?, expected);
+    // Nothing is listed when all files still exist
+    let out = run_check(&b2)?;
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&out.stderr).starts_with("0 of 1 file in"));
+    // A deleted file that is added again is not deleted
+    std::fs::write(&files[1], "again")?;
+    let b3 = backup(&mut config, "backup_2020-01-03_00-00-00.tar.zst")?;
+    assert!(deleted(&b3, false)?.is_empty());
+    assert_eq!(deleted(&b3, true)?, expected[1..]);
+
+    let out = tempdir()?;
+    let restored = |f: &PathBuf| {
+        out.path()
+            .join(strip_absolute_from_path(&f.to_string_lossy()))
+    };
+    let restore = |mirror: bool, include: Vec<String>| {
+        restore_with(BackupReader::new(b3.clone()), &RestoreOptions {
+            output: Some(out.path()),
+            include,
+            mirror,
+            overwrite: Overwrite::Always,
+            quiet: true,
+            ..Default::default()
+        })
+    };
+    std::fs::create_dir_all(restored(&sub))?;
+    std::fs::write(restored(&files[2]), "old")?;
+    restore(false, vec![])?;
+    assert!(restored(&files[2]).exists());
+    // Only the selected deleted files are removed
+    restore(true, vec![files[0].to_string_lossy().to_string()])?;
+    assert!(restored(&files[2]).exists());
+    // Nothing is deleted when the restore fails (or is cancelled)
+    let cancel = AtomicBool::new(true);
+    let res = restore_with(BackupReader::new(b3.clone()), &RestoreOptions {
+        output: Some(out.path()),
+        mirror: true,
+        overwrite: Overwrite::Always,
+        quiet: true,
+        cancel: Some(&cancel),
+        ..Default::default()
+    });
+    assert!(matches!(res, Err(BackupError::Cancel)));
+    assert!(restored(&files[2]).exists());
+    restore(true, vec![])?;
+    assert!(!restored(&files[2]).exists());
+    assert_eq!(std::fs::read_to_string(restored(&files[1]))?, "again");
+    assert!(!restored(&files[3]).exists());
+
+    // Merging keeps the deleted files (that are not added again)
+    merge(vec![b2.clone(), b3.clone()], &MergeOptions {
+        delete: true,
+        quality: Some(1),
+        force: true,
+        quiet: true,
+        ..Default::default()
+    })?;
+    assert!(!b2.exists());
+    assert_eq!(deleted(&b3, true)?, expected[1..]);
     Ok(())
 }
@@ -0,0 +1,498 @@
+//! Randomised end-to-end tests: random directory trees and configs are backed up, mutated, backed
+//! up incrementally, merged, and restored, checking the invariants after every step.
+//! Failing cases are shrunk to a minimal tree before being reported.
+//!
+//! The number of cases can be increased with `SIMPLE_BACKUP_PROPERTY_CASES` (the default is kept
+//! small for CI), and a reported case can be repeated with `SIMPLE_BACKUP_PROPERTY_SEED`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use regex::Regex;
+use simple_backup::backup::{BackupMerger, BackupReader};
+use simple_backup::cli::{backup_with, restore_with};
+use simple_backup::compression::CompressionDecoder;
+use simple_backup::config::ConfigBuilder;
+use simple_backup::files::cmp_path_str;
+use simple_backup::options::{BackupOptions, RestoreOptions};
+use simple_backup::utils::strip_absolute_from_path;
+use tempfile::tempdir;
+
+const DEFAULT_CASES: u64 = 6;
+const DEFAULT_SEED: u64 = 0x2545f4914f6cdd1d;
+
+/// Names chosen so that string ordering and path ordering differ (and with non-ascii characters)
+const NAMES: [&str; 12] = [
+    "a",
+    "b",
+    "a b",
+    "a.b",
+    "a-b",
+    "ab",
+    "b.txt",
+    "c.tmp",
+    "ä",
+    "日本語",
+    "é😀",
+    "Z",
+];
+/// Sizes around the tar block size (and empty files)
+const SIZES: [usize; 9] = [0, 1, 511, 512, 513, 1024, 4095, 4096, 10_000];
+/// Valid regexes that only match the generated names (not the temporary directory)
+const REGEXES: [&str; 4] = [r"\.tmp$", r"[/\\]b[^/\\]*$", "ä", r"x{40}"];
+
+/// Simple seeded pseudo random number generator (xorshift)
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n.max(1) as u64) as usize
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+
+    fn name(&mut self) -> String {
+        if self.below(8) == 0 {
+            "x".repeat(20 + self.below(100))
+        } else {
+            self.pick(&NAMES).to_string()
+        }
+    }
+
+    fn content(&mut self) -> Vec<u8> {
+        let size = *self.pick(&SIZES);
+        (0..size).map(|_| self.below(256) as u8).collect()
+    }
+}
+
+/// Relative paths to the content of files (or `None` for directories)
+type Tree = BTreeMap<PathBuf, Option<Vec<u8>>>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Mutation {
+    Write(PathBuf, Vec<u8>),
+    Delete(PathBuf),
+    Rename(PathBuf, PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Case {
+    tree: Tree,
+    /// Relative includes (the empty path is the root of the tree)
+    include: Vec<PathBuf>,
+    exclude: Vec<PathBuf>,
+    regex: Vec<&'static str>,
+    /// The mutations before each incremental backup
+    steps: Vec<Vec<Mutation>>,
+}
+
+fn dirs(tree: &Tree) -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::new()];
+    dirs.extend(
+        tree.iter()
+            .filter(|(_, c)| c.is_none())
+            .map(|(p, _)| p.clone()),
+    );
+    dirs
+}
+
+fn files(tree: &Tree) -> Vec<PathBuf> {
+    tree.iter()
+        .filter(|(_, c)| c.is_some())
+        .map(|(p, _)| p.clone())
+        .collect()
+}
+
+impl Case {
+    fn generate(rng: &mut Rng) -> Self {
+        let mut tree = Tree::new();
+        for _ in 0..5 + rng.below(20) {
+            let parent = rng.pick(&dirs(&tree)).clone();
+            let path = parent.join(rng.name());
+            if tree.contains_key(&path) || parent.components().count() >= 4 {
+                continue;
+            }
+            let content = (rng.below(3) > 0).then(|| rng.content());
+            tree.insert(path, content);
+        }
+        let paths: Vec<PathBuf> = tree.keys().cloned().collect();
+        let mut include = vec![];
+        for _ in 0..rng.below(3) {
+            if !paths.is_empty() {
+                include.push(rng.pick(&paths).clone());
+            }
+        }
+        if include.is_empty() || rng.below(4) > 0 {
+            include.push(PathBuf::new());
+        }
+        let mut exclude = vec![];
+        for _ in 0..rng.below(3) {
+            if !paths.is_empty() {
+                exclude.push(rng.pick(&paths).clone());
+            }
+        }
+        let regex = (0..rng.below(3)).map(|_| *rng.pick(&REGEXES)).collect();
+        let mut state = tree.clone();
+        let steps = (0..1 + rng.below(3))
+            .map(|_| {
+                (0..rng.below(6))
+                    .filter_map(|_| {
+                        let files = files(&state);
+                        let mutation = match rng.below(4) {
+                            0 | 1 if !files.is_empty() => {
+                                Mutation::Write(rng.pick(&files).clone(), rng.content())
+                            }
+                            2 if !files.is_empty() => Mutation::Delete(rng.pick(&files).clone()),
+                            3 if !files.is_empty() => Mutation::Rename(
+                                rng.pick(&files).clone(),
+                                rng.pick(&dirs(&state)).join(rng.name()),
+                            ),
+                            _ => Mutation::Write(
+                                rng.pick(&dirs(&state)).join(rng.name()),
+                                rng.content(),
+                            ),
+                        };
+                        apply(&mut state, &mutation, None).then_some(mutation)
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            tree,
+            include,
+            exclude,
+            regex,
+            steps,
+        }
+    }
+
+    /// Smaller variants of the case (for shrinking)
+    fn shrink(&self) -> Vec<Case> {
+        let mut cases = vec![];
+        let mut with = |f: &dyn Fn(&mut Case)| {
+            let mut case = self.clone();
+            f(&mut case);
+            if case != *self {
+                cases.push(case);
+            }
+        };
+        for i in 0..self.steps.len() {
+            with(&|c| {
+                c.steps.remove(i);
+            });
+            for j in 0..self.steps[i].len() {
+                with(&|c| {
+                    c.steps[i].remove(j);
+                });
+            }
+        }
+        for path in self.tree.keys() {
+            with(&|c| c.tree.retain(|p, _| !p.starts_with(path)));
+        }
+        for (path, content) in self.tree.iter() {
+            if let Some(content) = content.as_ref().filter(|c| !c.is_empty()) {
+                let half = content[..content.len() / 2].to_vec();
+                with(&|c| {
+                    c.tree.insert(path.clone(), Some(half.clone()));
+                });
+            }
+        }
+        // A config without includes is invalid
+        for i in (0..self.include.len()).filter(|_| self.include.len() > 1) {
+            with(&|c| {
+                c.include.remove(i);
+            });
+        }
+        for i in 0..self.exclude.len() {
+            with(&|c| {
+                c.exclude.remove(i);
+            });
+        }
+        for i in 0..self.regex.len() {
+            with(&|c| {
+                c.regex.remove(i);
+            });
+        }
+        cases
+    }
+
+    /// Is the (relative) path selected by the includes, excludes, and regexes
+    fn selected(&self, root: &Path, regex: &[Regex], path: &Path) -> bool {
+        for p in path.ancestors() {
+            if self.include.iter().any(|i| i == p) {
+                return true;
+            }
+            let s = root.join(p).to_string_lossy().to_string();
+            if self.exclude.iter().any(|e| e == p) || regex.iter().any(|r| r.is_match(&s)) {
+                return false;
+            }
+        }
+        false
+    }
+}
+
+/// Apply a mutation to the tree (and the files under `root`), invalid mutations are skipped
+/// (e.g. after shrinking)
+fn apply(tree: &mut Tree, mutation: &Mutation, root: Option<&Path>) -> bool {
+    let has_parent = |tree: &Tree, p: &Path| {
+        let parent = p.parent().unwrap_or(Path::new(""));
+        parent.as_os_str().is_empty() || matches!(tree.get(parent), Some(None))
+    };
+    match mutation {
+        Mutation::Write(path, content) => {
+            if matches!(tree.get(path), Some(None)) || !has_parent(tree, path) {
+                return false;
+            }
+            if let Some(root) = root {
+                File::create(root.join(path))
+                    .and_then(|mut f| f.write_all(content))
+                    .unwrap();
+            }
+            tree.insert(path.clone(), Some(content.clone()));
+        }
+        Mutation::Delete(path) => {
+            if !matches!(tree.get(path), Some(Some(_))) {
+                return false;
+            }
+            if let Some(root) = root {
+                std::fs::remove_file(root.join(path)).unwrap();
+            }
+            tree.remove(path);
+        }
+        Mutation::Rename(from, to) => {
+            if !matches!(tree.get(from), Some(Some(_)))
+                || tree.contains_key(to)
+                || !has_parent(tree, to)
+            {
+                return false;
+            }
+            if let Some(root) = root {
+                std::fs::rename(root.join(from), root.join(to)).unwrap();
+            }
+            let content = tree.remove(from).unwrap();
+            tree.insert(to.clone(), content);
+        }
+    }
+    true
+}
+
+/// The files under a directory (relative paths to the content)
+fn read_tree(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    let mut stack = vec![dir.to_path_buf()];
+    while let Some(d) = stack.pop() {
+        for entry in d.read_dir().unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                stack.push(path);
+            } else {
+                let content = std::fs::read(&path).unwrap();
+                files.insert(path.strip_prefix(dir).unwrap().to_path_buf(), content);
+            }
+        }
+    }
+    files
+}
+
+/// Check that a restored backup contains exactly the expected files
+fn check_restored(
+    reader: BackupReader,
+    root: &Path,
+    expected: &BTreeMap<PathBuf, Vec<u8>>,
+) -> Result<(), String> {
+    let out = tempdir().unwrap();
+    restore_with(reader, &RestoreOptions {
+        output: Some(out.path()),
+        quiet: true,
+        ..Default::default()
+    })
+    .map_err(|e| format!("Restore failed: {}", e))?;
+    let prefix = PathBuf::from(strip_absolute_from_path(&root.to_string_lossy()));
+    let restored: BTreeMap<PathBuf, Vec<u8>> = read_tree(out.path())
+        .into_iter()
+        .map(|(p, c)| {
+            (
+                p.strip_prefix(&prefix).map(Path::to_path_buf).unwrap_or(p),
+                c,
+            )
+        })
+        .collect();
+    if &restored != expected {
+        let names = |m: &BTreeMap<PathBuf, Vec<u8>>| m.keys().cloned().collect::<Vec<_>>();
+        return Err(format!(
+            "Restored {:?}, expected {:?}",
+            names(&restored),
+            names(expected)
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the list is sorted and that the archive contains the included files (in order)
+fn check_list(path: &Path) -> Result<(), String> {
+    let mut reader = BackupReader::new(path.to_path_buf());
+    let list = reader.get_list().map_err(|e| e.to_string())?;
+    let all: Vec<&str> = list.iter().map(|(_, s)| s).collect();
+    if let Some(w) = all.windows(2).find(|w| cmp_path_str(w[0], w[1]).is_ge()) {
+        return Err(format!("The list is not sorted: {:?}", w));
+    }
+    let included: Vec<String> = list.iter_included().map(String::from).collect();
+    let mut decoder = CompressionDecoder::read(path, 1).map_err(|e| e.to_string())?;
+    let stored: Vec<String> = decoder
+        .entries()
+        .map_err(|e| e.to_string())?
+        .skip(2)
+        .map(|e| e.map(|(mut fi, _)| fi.move_string()))
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+    if stored != included {
+        return Err(format!("Stored {:?}, listed {:?}", stored, included));
+    }
+    Ok(())
+}
+
+fn run(case: &Case) -> Result<(), String> {
+    let src = tempdir().unwrap();
+    let out = tempdir().unwrap();
+    let merged = tempdir().unwrap();
+    let root = src.path().canonicalize().unwrap();
+    for (path, content) in case.tree.iter() {
+        match content {
+            None => std::fs::create_dir(root.join(path)).unwrap(),
+            Some(c) => std::fs::write(root.join(path), c).unwrap(),
+        }
+    }
+    let regex: Vec<Regex> = case.regex.iter().map(|r| Regex::new(r).unwrap()).collect();
+    let abs = |p: &PathBuf| root.join(p).to_string_lossy().to_string();
+    let mut config = ConfigBuilder::new()
+        .include(case.include.iter().map(abs))
+        .exclude(case.exclude.iter().map(abs))
+        .regex(case.regex.iter().copied())
+        .incremental(true)
+        .quality(1)
+        .threads(1)
+        .build();
+
+    let mut tree = case.tree.clone();
+    let mut backups = vec![];
+    for i in 0..=case.steps.len() {
+        if i > 0 {
+            std::thread::sleep(Duration::from_millis(20));
+            for m in case.steps[i - 1].iter() {
+                apply(&mut tree, m, Some(&root));
+            }
+        }
+        let expected: BTreeMap<PathBuf, Vec<u8>> = tree
+            .iter()
+            .filter_map(|(p, c)| Some((p.clone(), c.clone()?)))
+            .filter(|(p, _)| case.selected(&root, &regex, p))
+            .collect();
+        let path = out
+            .path()
+            .join(format!("backup_2020-01-01_00-00-{:02}.tar.zst", i));
+        config.output = path.clone();
+        backup_with(config.clone(), &BackupOptions {
+            quiet: true,
+            ..Default::default()
+        })
+        .map_err(|e| format!("Backup {} failed: {}", i, e))?;
+        if !path.exists() {
+            // Nothing to backup (no new or modified files)
+            continue;
+        }
+        check_list(&path).map_err(|e| format!("Backup {}: {}", i, e))?;
+        check_restored(BackupReader::new(path.clone()), &root, &expected)
+            .map_err(|e| format!("Backup {}: {}", i, e))?;
+        backups.push((path, expected));
+    }
+
+    // The merged backup restores the same files as the chain
+    if let Some((_, expected)) = backups.last() {
+        if backups.len() > 1 {
+            let path = merged.path().join("merged.tar.zst");
+            let readers = backups
+                .iter()
+                .map(|(p, _)| BackupReader::new(p.clone()))
+                .collect();
+            let mut merger = BackupMerger::new(
+                Some(path.clone()),
+                readers,
+                false,
+                false,
+                false,
+                None,
+                None,
+                false,
+            )
+            .map_err(|(_, e)| format!("Merge failed: {}", e))?;
+            merger
+                .write(|_, res| res, |_| (), || ())
+                .map_err(|e| format!("Merge failed: {}", e))?;
+            check_list(&path).map_err(|e| format!("Merged: {}", e))?;
+            check_restored(BackupReader::new(path), &root, expected)
+                .map_err(|e| format!("Merged: {}", e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Run the case, treating panics as failures
+fn check(case: &Case) -> Result<(), String> {
+    match catch_unwind(AssertUnwindSafe(|| run(case))) {
+        Ok(res) => res,
+        Err(e) => Err(e
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_else(|| "Panic".to_string())),
+    }
+}
+
+/// Shrink a failing case (greedily, as long as a smaller variant still fails)
+fn shrink(mut case: Case, mut error: String) -> (Case, String) {
+    'outer: loop {
+        for smaller in case.shrink() {
+            if let Err(e) = check(&smaller) {
+                case = smaller;
+                error = e;
+                continue 'outer;
+            }
+        }
+        return (case, error);
+    }
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    std::env::var(name).ok().and_then(|v| v.parse().ok())
+}
+
+#[test]
+fn backup_restore_merge_property() {
+    let (seed, cases) = match env_number("SIMPLE_BACKUP_PROPERTY_SEED") {
+        Some(seed) => (seed, 1),
+        None => (
+            DEFAULT_SEED,
+            env_number("SIMPLE_BACKUP_PROPERTY_CASES").unwrap_or(DEFAULT_CASES),
+        ),
+    };
+    for i in 0..cases {
+        let seed = seed.wrapping_add(i);
+        let case = Case::generate(&mut Rng(seed.max(1)));
+        if let Err(error) = check(&case) {
+            let (case, error) = shrink(case, error);
+            panic!(
+                "Failed with SIMPLE_BACKUP_PROPERTY_SEED={}: {}\nMinimal case: {:#?}",
+                seed, error, case
+            );
+        }
+    }
+}